@@ -0,0 +1,28 @@
+//! Benchmarks of the per-process computations run at each measurement, on the
+//! processes of the host running the benchmark.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use regex::Regex;
+use scaphandre::sensors::Topology;
+use std::collections::HashMap;
+
+fn process_tracker(c: &mut Criterion) {
+    let mut topology = Topology::new(HashMap::new());
+    // two refreshes so that every process has the two records a CPU usage needs
+    topology.refresh();
+    topology.refresh();
+    let tracker = &topology.proc_tracker;
+    let regex = Regex::new("^/usr/.*").unwrap();
+
+    c.bench_function("get_top_consumers(10)", |b| {
+        b.iter(|| tracker.get_top_consumers(black_box(10)))
+    });
+    c.bench_function("get_top_consumers(all)", |b| {
+        b.iter(|| tracker.get_top_consumers(black_box(u16::MAX)))
+    });
+    c.bench_function("get_filtered_processes", |b| {
+        b.iter(|| tracker.get_filtered_processes(black_box(&regex)))
+    });
+}
+
+criterion_group!(benches, process_tracker);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+//! Benchmarks of the work scaphandre does at each measurement: refreshing the
+//! topology, generating the metrics and rendering them for Prometheus.
+//!
+//! They run on the host running the benchmark, with 100, 1000 and 5000 idle
+//! `sleep` processes started on top of the existing ones, so that the per-process
+//! paths are measured at a known scale. No RAPL sensor is needed: the topology
+//! has no socket, which leaves the per-process work as the bulk of what is measured.
+//!
+//!     cargo bench --bench sampling
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use scaphandre::exporters::{prometheus::render_metrics, MetricGenerator};
+use scaphandre::sensors::Topology;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::process::{Child, Command};
+
+const PROCESS_COUNTS: [usize; 3] = [100, 1000, 5000];
+
+/// Idle processes, killed when dropped.
+struct Sleepers(Vec<Child>);
+
+impl Sleepers {
+    fn spawn(count: usize) -> Sleepers {
+        Sleepers(
+            (0..count)
+                .map(|_| Command::new("sleep").arg("3600").spawn().unwrap())
+                .collect(),
+        )
+    }
+}
+
+impl Drop for Sleepers {
+    fn drop(&mut self) {
+        for child in &mut self.0 {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Returns a topology refreshed twice, so that every process has the two
+/// records its CPU usage and power are computed from.
+fn refreshed_topology() -> Topology {
+    let mut topology = Topology::new(HashMap::new());
+    topology.refresh();
+    topology.refresh();
+    topology
+}
+
+fn sampling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sampling");
+    group.sample_size(10);
+    for count in PROCESS_COUNTS {
+        let _sleepers = Sleepers::spawn(count);
+
+        let mut topology = refreshed_topology();
+        group.bench_with_input(
+            BenchmarkId::new("topology_refresh", count),
+            &count,
+            |b, _| b.iter(|| topology.refresh()),
+        );
+
+        let mut generator =
+            MetricGenerator::new(refreshed_topology(), String::from("bench"), false, false);
+        group.bench_with_input(
+            BenchmarkId::new("gen_all_metrics", count),
+            &count,
+            |b, _| {
+                b.iter(|| {
+                    generator.gen_all_metrics();
+                    generator.pop_metrics()
+                })
+            },
+        );
+
+        // metrics are generated again before each render, outside of the measurement
+        let generator = RefCell::new(generator);
+        group.bench_with_input(
+            BenchmarkId::new("prometheus_render", count),
+            &count,
+            |b, _| {
+                b.iter_batched(
+                    || {
+                        let mut generator = generator.borrow_mut();
+                        generator.gen_all_metrics();
+                        generator.pop_metrics()
+                    },
+                    render_metrics,
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sampling);
+criterion_main!(benches);
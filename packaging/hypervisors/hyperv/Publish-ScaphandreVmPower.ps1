@@ -0,0 +1,54 @@
+# Publishes the power of the virtual machines of a Hyper-V host to their guests,
+# for scaphandre to read it with the hyperv sensor (scaphandre -s hyperv ...).
+#
+# Scaphandre has to run on the host with the prometheus exporter. The power of a
+# virtual machine is the power of its worker process (vmwp.exe), whose command
+# line holds the id of the virtual machine. It is sent as the scaphandre_power_uw
+# and scaphandre_timestamp KVP items.
+#
+# Run it as an Administrator, e.g. as a scheduled task started at boot:
+#
+#   powershell -ExecutionPolicy Bypass -File Publish-ScaphandreVmPower.ps1 -MetricsUrl http://localhost:8080/metrics
+#
+param(
+    [string]$MetricsUrl = "http://localhost:8080/metrics",
+    [int]$IntervalSeconds = 10
+)
+
+$namespace = "root\virtualization\v2"
+
+function Set-KvpItem($service, $vm, $name, $value) {
+    $item = ([WmiClass]"$namespace`:Msvm_KvpExchangeDataItem").CreateInstance()
+    $item.Name = $name
+    $item.Data = $value
+    # 0: data sent by the host to the guest
+    $item.Source = 0
+    $text = $item.PSBase.GetText(1)
+    $result = $service.ModifyKvpItems($vm, @($text))
+    if ($result.ReturnValue -ne 0 -and $result.ReturnValue -ne 4096) {
+        # the item doesn't exist yet
+        $service.AddKvpItems($vm, @($text)) | Out-Null
+    }
+}
+
+while ($true) {
+    try {
+        $lines = (Invoke-WebRequest -UseBasicParsing $MetricsUrl).Content -split "`n" |
+            Where-Object { $_ -like 'scaph_process_power_consumption_microwatts{*exe="vmwp.exe"*' }
+        $service = Get-WmiObject -Namespace $namespace -Class Msvm_VirtualSystemManagementService
+        $timestamp = [DateTimeOffset]::UtcNow.ToUnixTimeSeconds()
+        foreach ($vm in Get-WmiObject -Namespace $namespace -Class Msvm_ComputerSystem -Filter "Caption='Virtual Machine' AND EnabledState=2") {
+            $power = 0.0
+            foreach ($line in $lines) {
+                if ($line -match [Regex]::Escape($vm.Name) -and $line -match '\}\s+([0-9.eE+-]+)') {
+                    $power += [double]$Matches[1]
+                }
+            }
+            Set-KvpItem $service $vm "scaphandre_power_uw" ([string][long]$power)
+            Set-KvpItem $service $vm "scaphandre_timestamp" ([string]$timestamp)
+        }
+    } catch {
+        Write-Warning "Couldn't publish the power of the virtual machines: $_"
+    }
+    Start-Sleep -Seconds $IntervalSeconds
+}
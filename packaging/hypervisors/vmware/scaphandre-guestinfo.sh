@@ -0,0 +1,43 @@
+#!/bin/sh
+# Publishes the power of VMware virtual machines to their guests, for scaphandre
+# to read it with the vmware sensor (scaphandre -s vmware ...).
+#
+# ESXi measures the power of each virtual machine (power.power.average performance
+# counter, in watts). This script reads it through vCenter or ESXi with govc
+# (https://github.com/vmware/govmomi/tree/main/govc) and sets it, with the time of
+# the measurement, as the guestinfo.scaphandre.power_uw and
+# guestinfo.scaphandre.timestamp variables of each virtual machine.
+#
+# Configure govc with the GOVC_URL, GOVC_USERNAME and GOVC_PASSWORD environment
+# variables, then give the inventory paths of the virtual machines:
+#
+#   ./scaphandre-guestinfo.sh /dc1/vm/web-1 /dc1/vm/web-2
+#
+# The power counter needs the power management of the hosts to be enabled, and is
+# refreshed every 20 seconds.
+set -u
+
+INTERVAL="${INTERVAL:-20}"
+
+if [ "$#" -eq 0 ]; then
+    echo "usage: $0 VM_PATH..." >&2
+    exit 1
+fi
+
+while true; do
+    for vm in "$@"; do
+        watts=$(govc metric.sample -n 1 "$vm" power.power.average 2>/dev/null | awk '{print $NF}' | tail -n 1)
+        case "$watts" in
+            ''|*[!0-9.]*)
+                echo "Couldn't read the power of $vm" >&2
+                continue
+                ;;
+        esac
+        microwatts=$(awk -v w="$watts" 'BEGIN { printf "%d", w * 1000000 }')
+        govc vm.change -vm "$vm" \
+            -e "guestinfo.scaphandre.power_uw=$microwatts" \
+            -e "guestinfo.scaphandre.timestamp=$(date +%s)" \
+            || echo "Couldn't publish the power of $vm" >&2
+    done
+    sleep "$INTERVAL"
+done
@@ -0,0 +1,474 @@
+//! # Capabilities: what this build of scaphandre can do on this host
+//!
+//! Packaged builds don't all enable the same features, and a sensor or an
+//! exporter that is compiled in may still not work on a given host (no RAPL,
+//! missing kernel module, no Docker socket...). [detect] lists both, for
+//! `scaphandre --capabilities` to tell users why something is missing.
+use crate::sensors::cpu_support::CpuSupport;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Cargo features of scaphandre, and whether they are enabled in this build.
+pub const FEATURES: [(&str, bool); 10] = [
+    ("prometheus", cfg!(feature = "prometheus")),
+    ("prometheuspush", cfg!(feature = "prometheuspush")),
+    ("riemann", cfg!(feature = "riemann")),
+    ("warpten", cfg!(feature = "warpten")),
+    ("json", cfg!(feature = "json")),
+    ("containers", cfg!(feature = "containers")),
+    ("qemu", cfg!(feature = "qemu")),
+    ("smartplug", cfg!(feature = "smartplug")),
+    ("webhook", cfg!(feature = "webhook")),
+    ("signing", cfg!(feature = "signing")),
+];
+
+/// A sensor, an exporter or an integration, as available in this build and on
+/// this host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capability {
+    pub name: &'static str,
+    /// Part of this build (feature enabled, right operating system)
+    pub compiled: bool,
+    /// Compiled in, and its requirements on the host are met as far as can be
+    /// checked without configuration
+    pub usable: bool,
+    /// Why it isn't usable, or what it needs to be used
+    pub detail: String,
+}
+
+impl Capability {
+    fn new(name: &'static str, compiled: bool, requirement: &str) -> Capability {
+        Capability {
+            name,
+            compiled,
+            usable: compiled,
+            detail: String::from(requirement),
+        }
+    }
+
+    /// Marks the capability as unusable for `reason`, if it is compiled in.
+    fn check(mut self, check: impl FnOnce() -> Result<String, String>) -> Capability {
+        if self.compiled {
+            match check() {
+                Ok(detail) => self.detail = detail,
+                Err(reason) => {
+                    self.usable = false;
+                    self.detail = reason;
+                }
+            }
+        }
+        self
+    }
+
+    /// Sets the detail of a capability missing from this build.
+    fn missing(mut self, reason: &str) -> Capability {
+        if !self.compiled {
+            self.detail = String::from(reason);
+        }
+        self
+    }
+}
+
+/// Capabilities of this build of scaphandre on this host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub sensors: Vec<Capability>,
+    pub exporters: Vec<Capability>,
+    /// Sources of labels for the processes running in containers
+    pub containers: Vec<Capability>,
+    /// Whether the CPU of the host is known to be supported
+    pub cpu: Option<CpuSupport>,
+}
+
+/// Lists the sensors, exporters and container integrations, checking the
+/// requirements of those compiled in on this host.
+pub fn detect() -> Capabilities {
+    let sensors = vec![
+        Capability::new("powercap_rapl", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_powercap),
+        Capability::new("msr", cfg!(target_os = "windows"), "")
+            .missing("only available on Windows")
+            .check(check_msr_driver),
+        Capability::new("msr_linux", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_msr_devices),
+        Capability::new("powermetrics", cfg!(target_os = "macos"), "")
+            .missing("only available on macOS")
+            .check(check_powermetrics),
+        Capability::new(
+            "smartplug",
+            cfg!(feature = "smartplug"),
+            "needs --smartplug <kind>://<address>",
+        )
+        .missing("not compiled in, needs the smartplug feature"),
+        Capability::new("modbus", true, "needs --modbus-address"),
+        Capability::new("ipmi", true, "").check(check_ipmitool),
+        Capability::new("battery", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_battery),
+        Capability::new("ina", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_i2c_devices),
+        Capability::new("jetson", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_jetson_rails),
+        Capability::new("hyperv", true, HYPERV_REQUIREMENT).check(check_hyperv_pool),
+        Capability::new(
+            "vmware",
+            true,
+            "needs the power of the virtual machine published by the VMware host",
+        ),
+        Capability::new(
+            "shared",
+            cfg!(target_os = "linux"),
+            "needs --share-socket of a primary instance",
+        )
+        .missing("only available on Linux"),
+        Capability::new("wsl", cfg!(target_os = "linux"), "")
+            .missing("only available on Linux")
+            .check(check_wsl),
+    ];
+    let exporter = |name, feature: &str| {
+        Capability::new(name, FEATURES.contains(&(feature, true)), "")
+            .missing(&format!("not compiled in, needs the {feature} feature"))
+    };
+    let exporters = vec![
+        Capability::new("stdout", true, ""),
+        exporter("json", "json"),
+        exporter("prometheus", "prometheus"),
+        exporter("prometheus-push", "prometheuspush"),
+        exporter("riemann", "riemann"),
+        exporter("warpten", "warpten"),
+        Capability::new("etw", cfg!(target_os = "windows"), "")
+            .missing("only available on Windows"),
+        Capability::new(
+            "compare",
+            FEATURES.contains(&("prometheuspush", true))
+                || FEATURES.contains(&("warpten", true))
+                || FEATURES.contains(&("riemann", true)),
+            "",
+        )
+        .missing("not compiled in, needs the prometheuspush, warpten or riemann feature"),
+        exporter("qemu", "qemu").check(|| {
+            if Path::new("/var/lib/libvirt").is_dir() {
+                Ok(String::new())
+            } else {
+                Err(String::from(
+                    "/var/lib/libvirt not found, is libvirt installed?",
+                ))
+            }
+        }),
+    ];
+    let containers_compiled = cfg!(all(target_os = "linux", feature = "containers"));
+    let containers = vec![
+        Capability::new("docker", containers_compiled, "")
+            .missing("not compiled in, needs the containers feature on Linux")
+            .check(|| {
+                if Path::new("/var/run/docker.sock").exists() {
+                    Ok(String::new())
+                } else {
+                    Err(String::from("/var/run/docker.sock not found"))
+                }
+            }),
+        Capability::new("kubernetes", containers_compiled, "")
+            .missing("not compiled in, needs the containers feature on Linux")
+            .check(|| {
+                if Path::new("/root/.kube/config").exists() {
+                    Ok(String::new())
+                } else {
+                    Err(String::from("/root/.kube/config not found"))
+                }
+            }),
+    ];
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        sensors,
+        exporters,
+        containers,
+        cpu: CpuSupport::detect(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_powercap() -> Result<String, String> {
+    use crate::sensors::powercap_rapl::{PowercapRAPLSensor, POWERCAP_PATH};
+    if let Some(hwmon_path) = PowercapRAPLSensor::amd_energy_device(POWERCAP_PATH) {
+        let counter = format!("{hwmon_path}/energy1_input");
+        return match std::fs::read_to_string(&counter) {
+            Ok(_) => Ok(format!("reads the amd_energy counters of {hwmon_path}")),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Err(format!("{counter} is not readable, run as root"))
+            }
+            Err(e) => Err(format!("couldn't read {counter}: {e}")),
+        };
+    }
+    PowercapRAPLSensor::check_module()?;
+    let counter = "/sys/class/powercap/intel-rapl:0/energy_uj";
+    match std::fs::read_to_string(counter) {
+        Ok(_) => Ok(String::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(format!("{counter} is not readable, run as root"))
+        }
+        Err(e) => Err(format!("couldn't read {counter}: {e}")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_powercap() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn check_msr_devices() -> Result<String, String> {
+    use crate::sensors::msr_linux::{has_msr_devices, open_device, MSR_DEVICES_PATH};
+    if !has_msr_devices(MSR_DEVICES_PATH) {
+        return Err(format!(
+            "no msr device in {MSR_DEVICES_PATH}, needs the msr module (modprobe msr)"
+        ));
+    }
+    open_device(MSR_DEVICES_PATH, 0).map(|_| String::new())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_msr_devices() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn check_i2c_devices() -> Result<String, String> {
+    let has_i2c = std::fs::read_dir("/dev").is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|e| e.file_name().to_string_lossy().starts_with("i2c-"))
+    });
+    if has_i2c {
+        Ok(String::from("needs an INA219 or INA3221 on an I2C bus"))
+    } else {
+        Err(String::from(
+            "no I2C device in /dev, needs the i2c-dev module (modprobe i2c-dev)",
+        ))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_i2c_devices() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn check_jetson_rails() -> Result<String, String> {
+    use crate::sensors::jetson;
+    let rails = jetson::find_rails(jetson::DRIVERS_PATH);
+    if rails.is_empty() {
+        Err(String::from(
+            "no ina3221x or ina3221 rail, not an NVIDIA Jetson",
+        ))
+    } else {
+        let names: Vec<&str> = rails.iter().map(|r| r.name.as_str()).collect();
+        Ok(format!("rails {}", names.join(", ")))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_jetson_rails() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+#[cfg(target_os = "macos")]
+fn check_powermetrics() -> Result<String, String> {
+    use crate::sensors::powermetrics::POWERMETRICS;
+    // SAFETY: geteuid has no arguments and always succeeds.
+    let root = unsafe { libc::geteuid() } == 0;
+    if !Path::new("/usr/bin").join(POWERMETRICS).exists() {
+        Err(format!("/usr/bin/{POWERMETRICS} not found"))
+    } else if !root {
+        Err(format!("{POWERMETRICS} needs scaphandre to run as root"))
+    } else {
+        Ok(String::new())
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn check_powermetrics() -> Result<String, String> {
+    Err(String::from("only available on macOS"))
+}
+
+#[cfg(target_os = "linux")]
+fn check_battery() -> Result<String, String> {
+    use crate::sensors::battery;
+    battery::find_battery(battery::DEFAULT_POWER_SUPPLY_PATH, None)
+        .map(|_| String::from("only measures the host while it runs on battery"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_battery() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn check_wsl() -> Result<String, String> {
+    if crate::sensors::wsl::detect() {
+        Ok(String::from(
+            "reads scaphandre on the Windows host, or estimates the power from the CPU usage",
+        ))
+    } else {
+        Err(String::from("not running in WSL2"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_wsl() -> Result<String, String> {
+    Err(String::from("only available on Linux"))
+}
+
+fn check_ipmitool() -> Result<String, String> {
+    match std::process::Command::new("ipmitool").arg("-V").output() {
+        Ok(output) if output.status.success() => {
+            if Path::new("/dev/ipmi0").exists() {
+                Ok(String::new())
+            } else {
+                Ok(String::from(
+                    "no local BMC (/dev/ipmi0), needs --ipmi-host of a remote one",
+                ))
+            }
+        }
+        _ => Err(String::from("ipmitool not found")),
+    }
+}
+
+const HYPERV_REQUIREMENT: &str =
+    "needs the power of the virtual machine published by the Hyper-V host";
+
+#[cfg(not(target_os = "windows"))]
+fn check_hyperv_pool() -> Result<String, String> {
+    use crate::sensors::hypervisor::KVP_EXTERNAL_POOL;
+    if Path::new(KVP_EXTERNAL_POOL).exists() {
+        Ok(String::from(HYPERV_REQUIREMENT))
+    } else {
+        Err(format!(
+            "{KVP_EXTERNAL_POOL} not found, is hv_kvp_daemon running?"
+        ))
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn check_hyperv_pool() -> Result<String, String> {
+    Ok(String::from(HYPERV_REQUIREMENT))
+}
+
+#[cfg(target_os = "windows")]
+fn check_msr_driver() -> Result<String, String> {
+    if crate::sensors::windows_driver::is_running() {
+        Ok(String::new())
+    } else {
+        Err(format!(
+            "the {} driver is not running, see scaphandre driver install",
+            crate::sensors::windows_driver::SERVICE_NAME
+        ))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_msr_driver() -> Result<String, String> {
+    Err(String::from("only available on Windows"))
+}
+
+impl Capabilities {
+    /// Returns the capabilities as a JSON document.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"version\":{},\"features\":{{",
+            json_string(self.version)
+        );
+        let features: Vec<String> = FEATURES
+            .iter()
+            .map(|(name, enabled)| format!("{}:{enabled}", json_string(name)))
+            .collect();
+        json.push_str(&features.join(","));
+        json.push('}');
+        for (section, capabilities) in [
+            ("sensors", &self.sensors),
+            ("exporters", &self.exporters),
+            ("containers", &self.containers),
+        ] {
+            let items: Vec<String> = capabilities
+                .iter()
+                .map(|c| {
+                    format!(
+                        "{{\"name\":{},\"compiled\":{},\"usable\":{},\"detail\":{}}}",
+                        json_string(c.name),
+                        c.compiled,
+                        c.usable,
+                        json_string(&c.detail)
+                    )
+                })
+                .collect();
+            let _ = write!(json, ",\"{section}\":[{}]", items.join(","));
+        }
+        if let Some(cpu) = &self.cpu {
+            let _ = write!(
+                json,
+                ",\"cpu\":{{\"vendor\":{},\"family\":{},\"model\":{},\"model_name\":{},\"supported\":{},\"reason\":{}}}",
+                json_string(&cpu.vendor),
+                cpu.family,
+                cpu.model,
+                json_string(&cpu.model_name),
+                cpu.supported,
+                json_string(&cpu.reason)
+            );
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Returns `s` as a JSON string literal.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(res, "\\u{:04x}", c as u32);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_json() {
+        let capabilities = detect();
+        let stdout = &capabilities.exporters[0];
+        assert_eq!(stdout.name, "stdout");
+        assert!(stdout.compiled && stdout.usable);
+        let json = capabilities.to_json();
+        assert!(json.starts_with("{\"version\":\""));
+        assert!(json.contains("\"name\":\"modbus\",\"compiled\":true"));
+        assert_eq!(json_string("a \"b\"\n\\"), "\"a \\\"b\\\"\\n\\\\\"");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
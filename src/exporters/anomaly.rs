@@ -0,0 +1,236 @@
+//! # Anomaly: detection of unusual power draws
+//!
+//! [AnomalyDetector] keeps an exponentially weighted mean and variance of the
+//! power of each monitored scope (the host, each container) and flags a
+//! measurement as anomalous when its z-score, its distance to the mean in
+//! standard deviations, goes over a threshold. This is meant to spot a
+//! cryptominer or a runaway workload from the agent itself, without a
+//! dedicated analytics stack.
+//!
+//! Anomalous measurements are learned with a lower weight: a spike doesn't
+//! inflate the variance enough to hide the measurements that follow it, while a
+//! lasting change of workload still becomes the new normal after a few hundred
+//! measurements.
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Weight of the last measurement in the mean and variance.
+const ALPHA: f64 = 0.1;
+/// Weight of the last measurement when it is anomalous.
+const ANOMALOUS_ALPHA: f64 = 0.01;
+/// Number of measurements of a scope before its z-score is meaningful.
+const WARMUP_MEASUREMENTS: u32 = 10;
+/// Standard deviation under which a scope is considered constant, in microwatts,
+/// so that tiny variations of a very stable power aren't reported.
+const MIN_STANDARD_DEVIATION: f64 = 1000.0;
+
+/// Exponentially weighted statistics of the power of a scope.
+#[derive(Debug, Clone, Default)]
+struct PowerStats {
+    mean: f64,
+    variance: f64,
+    count: u32,
+    anomalous: bool,
+}
+
+/// Result of the evaluation of a measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    pub zscore: f64,
+    /// Mean power before this measurement, in microwatts
+    pub expected: f64,
+    pub anomalous: bool,
+    /// True if the scope wasn't anomalous at the previous measurement
+    pub started: bool,
+}
+
+/// Detects anomalies in the power of several scopes, identified by a key.
+#[derive(Debug, Clone)]
+pub struct AnomalyDetector {
+    /// z-score over which a measurement is anomalous
+    pub threshold: f64,
+    /// URL to POST a notification to when an anomaly starts
+    pub webhook: Option<String>,
+    stats: HashMap<String, PowerStats>,
+}
+
+impl AnomalyDetector {
+    pub fn new(threshold: f64, webhook: Option<String>) -> AnomalyDetector {
+        AnomalyDetector {
+            threshold,
+            webhook,
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Learns the power `microwatts` of the scope `key` and returns whether it
+    /// is anomalous, or None while the scope is warming up.
+    pub fn evaluate(&mut self, key: &str, microwatts: f64) -> Option<Evaluation> {
+        let stats = self.stats.entry(String::from(key)).or_default();
+        if stats.count == 0 {
+            stats.mean = microwatts;
+            stats.count = 1;
+            return None;
+        }
+        let deviation = microwatts - stats.mean;
+        let standard_deviation = stats.variance.sqrt().max(MIN_STANDARD_DEVIATION);
+        let evaluation = if stats.count >= WARMUP_MEASUREMENTS {
+            let zscore = deviation / standard_deviation;
+            let anomalous = zscore.abs() > self.threshold;
+            let started = anomalous && !stats.anomalous;
+            stats.anomalous = anomalous;
+            Some(Evaluation {
+                zscore,
+                expected: stats.mean,
+                anomalous,
+                started,
+            })
+        } else {
+            None
+        };
+        let alpha = if stats.anomalous {
+            ANOMALOUS_ALPHA
+        } else {
+            ALPHA
+        };
+        stats.mean += alpha * deviation;
+        stats.variance = (1.0 - alpha) * (stats.variance + alpha * deviation * deviation);
+        stats.count = stats.count.saturating_add(1);
+        evaluation
+    }
+
+    /// Forgets the statistics of the scopes whose key doesn't match `keep`,
+    /// such as containers that are gone.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.stats.retain(|key, _| keep(key));
+    }
+}
+
+/// Returns the JSON body of the notification of an anomaly.
+pub fn notification_payload(
+    hostname: &str,
+    scope: &str,
+    name: &str,
+    microwatts: f64,
+    evaluation: &Evaluation,
+    timestamp: Duration,
+) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    format!(
+        "{{\"host\":\"{}\",\"scope\":\"{}\",\"name\":\"{}\",\"power_microwatts\":{},\"expected_power_microwatts\":{},\"zscore\":{:.2},\"timestamp\":{}}}",
+        escape(hostname),
+        escape(scope),
+        escape(name),
+        microwatts as u64,
+        evaluation.expected as u64,
+        evaluation.zscore,
+        timestamp.as_secs()
+    )
+}
+
+/// Sends `payload` to the webhook at `url`, from a background thread so that a
+/// slow endpoint doesn't delay the measurements. Also used to notify power
+/// budget violations.
+#[cfg(feature = "webhook")]
+pub fn notify(url: &str, payload: String) {
+    use isahc::config::Configurable;
+    use isahc::RequestExt;
+    let url = String::from(url);
+    std::thread::spawn(move || {
+        let response = isahc::Request::post(&url)
+            .header("Content-Type", "application/json")
+            .timeout(Duration::from_secs(10))
+            .body(payload)
+            .map_err(|e| e.to_string())
+            .and_then(|request| request.send().map_err(|e| e.to_string()));
+        match response {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Webhook {} answered with status {}", url, response.status())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Couldn't notify webhook {}: {}", url, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_anomalies() {
+        let mut detector = AnomalyDetector::new(3.0, None);
+        for i in 0..WARMUP_MEASUREMENTS {
+            assert_eq!(detector.evaluate("host", 50e6 + (i % 2) as f64 * 2e6), None);
+        }
+        let normal = detector.evaluate("host", 51e6).unwrap();
+        assert!(!normal.anomalous);
+
+        let spike = detector.evaluate("host", 150e6).unwrap();
+        assert!(spike.anomalous);
+        assert!(spike.started);
+        assert!(spike.zscore > 3.0);
+        assert!((spike.expected - 51e6).abs() < 2e6);
+        let lasting = detector.evaluate("host", 150e6).unwrap();
+        assert!(lasting.anomalous);
+        assert!(!lasting.started);
+
+        // the new power becomes the normal one
+        for _ in 0..500 {
+            detector.evaluate("host", 150e6);
+        }
+        assert!(!detector.evaluate("host", 150e6).unwrap().anomalous);
+
+        detector.retain(|key| key != "host");
+        assert_eq!(detector.evaluate("host", 150e6), None);
+    }
+
+    #[test]
+    fn stable_power_is_not_anomalous() {
+        let mut detector = AnomalyDetector::new(3.0, None);
+        for _ in 0..20 {
+            detector.evaluate("container:db", 10e6);
+        }
+        assert!(
+            !detector
+                .evaluate("container:db", 10e6 + 500.0)
+                .unwrap()
+                .anomalous
+        );
+    }
+
+    #[test]
+    fn format_notification() {
+        let evaluation = Evaluation {
+            zscore: 4.256,
+            expected: 50e6,
+            anomalous: true,
+            started: true,
+        };
+        assert_eq!(
+            notification_payload(
+                "node1",
+                "container",
+                "mi\"ner",
+                150e6,
+                &evaluation,
+                Duration::from_secs(1700000000)
+            ),
+            "{\"host\":\"node1\",\"scope\":\"container\",\"name\":\"mi\\\"ner\",\"power_microwatts\":150000000,\"expected_power_microwatts\":50000000,\"zscore\":4.26,\"timestamp\":1700000000}"
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
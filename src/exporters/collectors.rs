@@ -0,0 +1,214 @@
+//! # Collectors: subsets of the metrics a scrape can ask for
+//!
+//! As with the `collect[]` parameter of the node exporter, a scrape of the
+//! prometheus exporter can ask for some groups of metrics only, so that
+//! several Prometheus jobs scrape one scaphandre at different intervals: the
+//! host power every 5 seconds, the processes every minute...
+//!
+//! ```text
+//! /metrics?collect[]=host&collect[]=socket
+//! /metrics?exclude[]=process
+//! ```
+//!
+//! The metrics are measured and generated as for any scrape, then filtered,
+//! so that a scrape asking for some collectors doesn't change what the others
+//! get. `scope` is accepted as well as `collect[]`.
+use super::Metric;
+
+/// Collectors and the prefixes of the names of their metrics, the first
+/// matching prefix giving the collector of a metric. Metrics matching none
+/// are derived metrics.
+pub const COLLECTORS: [(&str, &[&str]); 7] = [
+    (
+        "system",
+        &[
+            "scaph_host_load_avg_",
+            "scaph_host_cpu_frequency",
+            "scaph_host_memory_",
+            "scaph_host_swap_",
+            "scaph_host_disk_",
+            "scaph_host_network_",
+            "scaph_host_component_temperature_",
+            "scaph_host_fan_",
+            "scaph_host_pressure_",
+            "scaph_host_boot_time_",
+            "scaph_forks_",
+            "scaph_processes_",
+            "scaph_context_switches_",
+        ],
+    ),
+    (
+        "host",
+        &[
+            "scaph_host_",
+            "scaph_kernel_",
+            "scaph_power_anomaly",
+            "scaph_cpu_supported",
+            "scaph_topology_",
+            "kepler_node_platform_",
+        ],
+    ),
+    (
+        "socket",
+        &["scaph_socket_", "scaph_igpu_", "kepler_node_package_"],
+    ),
+    (
+        "domain",
+        &[
+            "scaph_domain_",
+            "scaph_rapl_zone_",
+            "kepler_node_core_",
+            "kepler_node_uncore_",
+            "kepler_node_dram_",
+        ],
+    ),
+    ("process", &["scaph_process_", "kepler_process_"]),
+    (
+        "container",
+        &["scaph_container_", "scaph_kubernetes_", "scaph_job_"],
+    ),
+    ("self", &["scaph_self_", "scaph_peer_"]),
+];
+
+/// Collector of the metrics matching no prefix of [COLLECTORS].
+pub const DERIVED_COLLECTOR: &str = "derived";
+
+/// Returns the collector of the metric `name`.
+pub fn collector_of(name: &str) -> &'static str {
+    COLLECTORS
+        .iter()
+        .find(|(_, prefixes)| prefixes.iter().any(|prefix| name.starts_with(prefix)))
+        .map_or(DERIVED_COLLECTOR, |(collector, _)| collector)
+}
+
+/// Collectors asked for by a scrape.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    /// Collectors to publish, all of them if empty
+    collect: Vec<String>,
+    /// Collectors to leave out
+    exclude: Vec<String>,
+}
+
+impl Selection {
+    /// Reads the `collect[]`, `scope` and `exclude[]` parameters of the query
+    /// string of a scrape. Other parameters are ignored.
+    pub fn from_query(query: &str) -> Result<Selection, String> {
+        let mut selection = Selection::default();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let list = match percent_decode(key).as_str() {
+                "collect[]" | "scope" => &mut selection.collect,
+                "exclude[]" => &mut selection.exclude,
+                _ => continue,
+            };
+            let collector = percent_decode(value);
+            let known = COLLECTORS.iter().any(|(name, _)| *name == collector)
+                || collector == DERIVED_COLLECTOR;
+            if !known {
+                return Err(format!(
+                    "unknown collector {collector:?}, expected one of {}, {DERIVED_COLLECTOR}",
+                    COLLECTORS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            list.push(collector);
+        }
+        Ok(selection)
+    }
+
+    /// Tells if all the metrics are asked for.
+    pub fn is_all(&self) -> bool {
+        self.collect.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Tells if the metrics of `collector` are asked for.
+    pub fn includes(&self, collector: &str) -> bool {
+        (self.collect.is_empty() || self.collect.iter().any(|c| c == collector))
+            && !self.exclude.iter().any(|c| c == collector)
+    }
+
+    /// Keeps the metrics asked for.
+    pub fn filter(&self, metrics: Vec<Metric>) -> Vec<Metric> {
+        if self.is_all() {
+            return metrics;
+        }
+        metrics
+            .into_iter()
+            .filter(|metric| self.includes(collector_of(&metric.name)))
+            .collect()
+    }
+}
+
+/// Decodes the %XX escapes of a query string parameter, `collect%5B%5D` being
+/// how most clients send `collect[]`.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::stability::METRICS;
+
+    #[test]
+    fn select_collectors() {
+        assert_eq!(collector_of("scaph_host_power_microwatts"), "host");
+        assert_eq!(collector_of("scaph_host_memory_free_bytes"), "system");
+        assert_eq!(collector_of("scaph_domain_energy_microjoules"), "domain");
+        assert_eq!(collector_of("kepler_process_joules_total"), "process");
+        assert_eq!(collector_of("efficiency"), DERIVED_COLLECTOR);
+        // all the metrics of scaphandre have a collector
+        for (name, _) in METRICS {
+            assert_ne!(collector_of(name), DERIVED_COLLECTOR, "{name}");
+        }
+
+        let selection = Selection::from_query("collect%5B%5D=host&collect[]=socket").unwrap();
+        assert!(selection.includes("host") && selection.includes("socket"));
+        assert!(!selection.includes("process"));
+        let selection = Selection::from_query("exclude[]=process&other=1").unwrap();
+        assert!(selection.includes("host") && !selection.includes("process"));
+        assert_eq!(
+            Selection::from_query("scope=host"),
+            Selection::from_query("collect[]=host")
+        );
+        assert!(Selection::from_query("").unwrap().is_all());
+        assert!(Selection::from_query("collect[]=gpu").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
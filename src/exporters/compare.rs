@@ -0,0 +1,541 @@
+//! # Compare exporter
+//!
+//! The compare exporter sends each batch of metrics to two backends, a primary
+//! and a secondary one, and counts the batches each of them accepted or
+//! rejected. It is meant for migrations between backends (from Riemann to a
+//! Prometheus push gateway, for instance): the new pipeline can be checked
+//! against the old one before the latter is switched off.
+
+use super::utils::{
+    get_hostname, wait_for_next_step, HttpClientArgs, MetricGeneratorArgs, MetricNamingArgs,
+    StepArgs,
+};
+use crate::exporters::{Exporter, Metric, MetricGenerator, MetricValueType};
+use crate::sensors::{Sensor, Topology};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Holds the arguments for a CompareExporter.
+#[derive(clap::Args, Debug)]
+pub struct ExporterArgs {
+    /// Backend the metrics are sent to first, as <kind>://<host>:<port>, kind being
+    /// prometheuspush, warpten or riemann. Append +https to the kind for HTTPS
+    #[arg(long, value_name = "BACKEND")]
+    pub primary: BackendSpec,
+
+    /// Backend the same metrics are sent to afterwards, in the same format as --primary
+    #[arg(long, value_name = "BACKEND")]
+    pub secondary: BackendSpec,
+
+    /// Interval between two measurements, in seconds
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 30)]
+    pub step: u64,
+
+    /// Apply labels to metrics of processes looking like a Qemu/KVM virtual machine
+    #[arg(long)]
+    pub qemu: bool,
+
+    /// Apply labels to metrics of processes running as containers
+    #[arg(long)]
+    pub containers: bool,
+
+    #[command(flatten)]
+    pub generator: MetricGeneratorArgs,
+
+    #[command(flatten)]
+    pub naming_args: MetricNamingArgs,
+
+    /// Don't verify remote TLS certificate (works with +https backends)
+    #[arg(long)]
+    pub no_tls_check: bool,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
+}
+
+/// Kind of backend a batch can be sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    PrometheusPush,
+    Warpten,
+    Riemann,
+}
+
+/// A backend given on the command line, as `<kind>[+https]://<host>:<port>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendSpec {
+    pub kind: BackendKind,
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl BackendSpec {
+    #[cfg(any(feature = "prometheuspush", feature = "warpten"))]
+    fn scheme(&self) -> &'static str {
+        match self.https {
+            true => "https",
+            false => "http",
+        }
+    }
+}
+
+impl FromStr for BackendSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, address) = s
+            .split_once("://")
+            .ok_or_else(|| format!("{s} should look like <kind>://<host>:<port>"))?;
+        let (kind, https) = match kind.strip_suffix("+https") {
+            Some(kind) => (kind, true),
+            None => (kind, false),
+        };
+        let kind = match kind {
+            "prometheuspush" => BackendKind::PrometheusPush,
+            "warpten" => BackendKind::Warpten,
+            "riemann" => BackendKind::Riemann,
+            _ => {
+                return Err(format!(
+                    "unknown backend {kind}, expected prometheuspush, warpten or riemann"
+                ))
+            }
+        };
+        if https && kind == BackendKind::Riemann {
+            return Err(String::from(
+                "riemann backends are only reached through TCP",
+            ));
+        }
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| format!("{address} should include a port"))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port {port} in {address}"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("{address} should include a host"));
+        }
+        Ok(BackendSpec {
+            kind,
+            https,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// A backend the compare exporter sends the batches to.
+trait Backend {
+    /// Sends a batch, returns an error if the backend couldn't be reached or
+    /// rejected some of the metrics.
+    fn send(&mut self, metrics: &[Metric]) -> Result<(), String>;
+
+    /// Checks that the backend can be reached, before the first batch.
+    fn validate(&mut self) -> Result<String, String>;
+}
+
+#[cfg(feature = "prometheuspush")]
+struct PrometheusPushBackend {
+    http_client: isahc::HttpClient,
+    base_uri: String,
+    uri: String,
+    no_tls_check: bool,
+}
+
+#[cfg(feature = "prometheuspush")]
+impl Backend for PrometheusPushBackend {
+    fn send(&mut self, metrics: &[Metric]) -> Result<(), String> {
+        let body = super::prometheuspush::format_push_body(metrics);
+        super::prometheuspush::push(&self.http_client, &self.uri, body, self.no_tls_check)
+    }
+
+    fn validate(&mut self) -> Result<String, String> {
+        super::prometheuspush::check_ready(&self.http_client, &self.base_uri, self.no_tls_check)
+    }
+}
+
+#[cfg(feature = "warpten")]
+struct WarptenBackend {
+    client: warp10::Client,
+    http_client: isahc::HttpClient,
+    write_token: String,
+}
+
+#[cfg(feature = "warpten")]
+impl Backend for WarptenBackend {
+    fn send(&mut self, metrics: &[Metric]) -> Result<(), String> {
+        let data = super::warpten::to_warp10_data(metrics);
+        super::warpten::post(&self.client, &self.http_client, &self.write_token, data)
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    fn validate(&mut self) -> Result<String, String> {
+        let uri = self.client.update_uri();
+        super::warpten::post(&self.client, &self.http_client, &self.write_token, vec![])
+            .map(|_| format!("write token accepted by {uri}"))
+            .map_err(|e| format!("update rejected by {uri}: {e:?}"))
+    }
+}
+
+#[cfg(feature = "riemann")]
+struct RiemannBackend {
+    client: super::riemann::RiemannClient,
+    address: String,
+}
+
+#[cfg(feature = "riemann")]
+impl Backend for RiemannBackend {
+    fn send(&mut self, metrics: &[Metric]) -> Result<(), String> {
+        let mut errors = metrics
+            .iter()
+            .filter_map(|metric| self.client.try_send_metric(metric).err());
+        match errors.next() {
+            Some(first) => Err(format!(
+                "{} metrics rejected by {}, first error: {first}",
+                errors.count() + 1,
+                self.address
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn validate(&mut self) -> Result<String, String> {
+        Ok(format!("connected to Riemann at {}", self.address))
+    }
+}
+
+/// Returns the backend described by `spec`, or an error if it can't be built,
+/// either because it isn't compiled in or because the client can't be set up.
+fn build_backend(
+    spec: &BackendSpec,
+    hostname: &str,
+    no_tls_check: bool,
+    http_client_args: &HttpClientArgs,
+) -> Result<Box<dyn Backend>, String> {
+    let _ = (hostname, no_tls_check, http_client_args);
+    match spec.kind {
+        #[cfg(feature = "prometheuspush")]
+        BackendKind::PrometheusPush => {
+            let http_client = super::utils::get_http_client(http_client_args, spec.scheme())?;
+            let base_uri = format!("{}://{}:{}", spec.scheme(), spec.host, spec.port);
+            let uri = format!("{base_uri}/metrics/job/scaphandre/instance/{hostname}");
+            Ok(Box::new(PrometheusPushBackend {
+                http_client,
+                base_uri,
+                uri,
+                no_tls_check,
+            }))
+        }
+        #[cfg(feature = "warpten")]
+        BackendKind::Warpten => {
+            let client =
+                warp10::Client::new(&format!("{}://{}:{}", spec.scheme(), spec.host, spec.port))
+                    .map_err(|e| format!("invalid Warp10 address: {e:?}"))?;
+            let http_client = super::utils::get_http_client(http_client_args, spec.scheme())?;
+            let token_var = super::warpten::TOKEN_ENV_VAR;
+            let write_token = std::env::var(token_var)
+                .map_err(|_| format!("the env var {token_var} should hold the write token"))?;
+            Ok(Box::new(WarptenBackend {
+                client,
+                http_client,
+                write_token,
+            }))
+        }
+        #[cfg(feature = "riemann")]
+        BackendKind::Riemann => {
+            let client = super::riemann::RiemannClient::connect(&spec.host, spec.port)?;
+            Ok(Box::new(RiemannBackend {
+                client,
+                address: format!("{}:{}", spec.host, spec.port),
+            }))
+        }
+        #[allow(unreachable_patterns)]
+        kind => Err(format!("the {kind:?} backend is not compiled in")),
+    }
+}
+
+/// Which of the two backends a result is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Side {
+    Primary,
+    Secondary,
+}
+
+impl Side {
+    fn name(&self) -> &'static str {
+        match self {
+            Side::Primary => "primary",
+            Side::Secondary => "secondary",
+        }
+    }
+}
+
+/// Counts the batches accepted and rejected by each backend, and the batches
+/// that only one of them accepted.
+#[derive(Debug, Default)]
+struct CompareStats {
+    /// Batches per backend and whether they were accepted.
+    batches: HashMap<(Side, bool), u64>,
+    divergent_batches: u64,
+}
+
+impl CompareStats {
+    /// Records the results of a batch sent to both backends, returns true if
+    /// they diverged.
+    fn record(&mut self, primary: &Result<(), String>, secondary: &Result<(), String>) -> bool {
+        *self
+            .batches
+            .entry((Side::Primary, primary.is_ok()))
+            .or_default() += 1;
+        *self
+            .batches
+            .entry((Side::Secondary, secondary.is_ok()))
+            .or_default() += 1;
+        let divergent = primary.is_ok() != secondary.is_ok();
+        if divergent {
+            self.divergent_batches += 1;
+        }
+        divergent
+    }
+
+    /// Returns the counters as metrics, for both backends to receive them with
+    /// the next batch.
+    fn metrics(&self, hostname: &str, timestamp: Duration) -> Vec<Metric> {
+        let mut metrics = vec![];
+        for side in [Side::Primary, Side::Secondary] {
+            for ok in [true, false] {
+                let count = self.batches.get(&(side, ok)).copied().unwrap_or(0);
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("backend"), side.name().into());
+                attributes.insert(
+                    String::from("result"),
+                    (if ok { "ok" } else { "error" }).into(),
+                );
+                metrics.push(Metric {
+                    name: String::from("scaph_self_compare_batches_total"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: hostname.to_string(),
+                    state: String::from("ok"),
+                    tags: vec![String::from("scaphandre")],
+                    attributes,
+                    description: String::from(
+                        "Batches of metrics sent by the compare exporter to each backend, by result.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(count),
+                });
+            }
+        }
+        metrics.push(Metric {
+            name: String::from("scaph_self_compare_divergent_batches_total"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp,
+            hostname: hostname.to_string(),
+            state: String::from("ok"),
+            tags: vec![String::from("scaphandre")],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Batches of metrics accepted by one backend of the compare exporter and rejected by the other.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(self.divergent_batches),
+        });
+        metrics
+    }
+}
+
+/// An exporter sending the same metrics to two backends, to compare them.
+pub struct CompareExporter {
+    topo: Topology,
+    hostname: String,
+    args: ExporterArgs,
+    primary: Box<dyn Backend>,
+    secondary: Box<dyn Backend>,
+}
+
+impl CompareExporter {
+    pub fn new(
+        sensor: &dyn Sensor,
+        args: ExporterArgs,
+        http_client_args: &HttpClientArgs,
+    ) -> CompareExporter {
+        let topo = sensor
+            .get_topology()
+            .expect("sensor topology should be available");
+        let hostname = get_hostname();
+        let primary = build_backend(
+            &args.primary,
+            &hostname,
+            args.no_tls_check,
+            http_client_args,
+        )
+        .unwrap_or_else(|e| panic!("primary backend could not be created: {e}"));
+        let secondary = build_backend(
+            &args.secondary,
+            &hostname,
+            args.no_tls_check,
+            http_client_args,
+        )
+        .unwrap_or_else(|e| panic!("secondary backend could not be created: {e}"));
+        CompareExporter {
+            topo,
+            hostname,
+            args,
+            primary,
+            secondary,
+        }
+    }
+}
+
+impl Exporter for CompareExporter {
+    fn run(&mut self) {
+        info!(
+            "{}: Starting compare exporter",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+
+        let mut metric_generator = MetricGenerator::new(
+            self.topo.clone(),
+            self.hostname.clone(),
+            self.args.qemu,
+            self.args.containers,
+        );
+        self.args.generator.apply(&mut metric_generator);
+        metric_generator.set_metric_naming(self.args.naming_args.metric_naming);
+        let mut stats = CompareStats::default();
+
+        loop {
+            metric_generator.topology.refresh();
+            metric_generator.gen_all_metrics();
+            let mut metrics = metric_generator.pop_metrics();
+            let timestamp = crate::sensors::utils::current_system_time_since_epoch();
+            metrics.extend(stats.metrics(&self.hostname, timestamp));
+
+            let primary = self.primary.send(&metrics);
+            let secondary = self.secondary.send(&metrics);
+            for (side, result) in [(Side::Primary, &primary), (Side::Secondary, &secondary)] {
+                if let Err(e) = result {
+                    debug!("{} backend: {e}", side.name());
+                }
+            }
+            if stats.record(&primary, &secondary) {
+                let (ok, failed, error) = match (&primary, &secondary) {
+                    (Err(e), _) => (Side::Secondary, Side::Primary, e),
+                    (_, Err(e)) => (Side::Primary, Side::Secondary, e),
+                    _ => unreachable!("diverging batches have exactly one error"),
+                };
+                warn!(
+                    "batch of {} metrics accepted by the {} backend but not by the {} one: {error}",
+                    metrics.len(),
+                    ok.name(),
+                    failed.name()
+                );
+            } else if let (Err(e), Err(_)) = (&primary, &secondary) {
+                warn!("batch rejected by both backends, primary: {e}");
+            }
+
+            wait_for_next_step(
+                Duration::new(self.args.step, 0),
+                self.args.step_args.align_timestamps,
+            );
+        }
+    }
+
+    fn kind(&self) -> &str {
+        "compare"
+    }
+
+    /// Checks both backends, so that a migration isn't validated against a
+    /// backend that can't be reached.
+    fn validate(&mut self) -> Result<String, String> {
+        let primary = self
+            .primary
+            .validate()
+            .map_err(|e| format!("primary: {e}"))?;
+        let secondary = self
+            .secondary
+            .validate()
+            .map_err(|e| format!("secondary: {e}"))?;
+        Ok(format!("primary: {primary}, secondary: {secondary}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_backend_spec() {
+        assert_eq!(
+            "prometheuspush+https://gateway.local:9091".parse(),
+            Ok(BackendSpec {
+                kind: BackendKind::PrometheusPush,
+                https: true,
+                host: String::from("gateway.local"),
+                port: 9091,
+            })
+        );
+        assert_eq!(
+            "riemann://[::1]:5555".parse(),
+            Ok(BackendSpec {
+                kind: BackendKind::Riemann,
+                https: false,
+                host: String::from("::1"),
+                port: 5555,
+            })
+        );
+        assert!("riemann+https://localhost:5555"
+            .parse::<BackendSpec>()
+            .is_err());
+        assert!("influxdb://localhost:8086".parse::<BackendSpec>().is_err());
+        assert!("warpten://localhost".parse::<BackendSpec>().is_err());
+        assert!("localhost:8080".parse::<BackendSpec>().is_err());
+    }
+
+    #[test]
+    fn compare_stats() {
+        let mut stats = CompareStats::default();
+        assert!(!stats.record(&Ok(()), &Ok(())));
+        assert!(stats.record(&Ok(()), &Err(String::from("timeout"))));
+        assert!(!stats.record(&Err(String::from("down")), &Err(String::from("down"))));
+
+        let metrics = stats.metrics("host", Duration::from_secs(1));
+        let value = |backend: &str, result: &str| {
+            metrics
+                .iter()
+                .find(|m| {
+                    m.attributes.get("backend").map(|v| v.to_string()) == Some(backend.into())
+                        && m.attributes.get("result").map(|v| v.to_string()) == Some(result.into())
+                })
+                .map(|m| m.metric_value.to_string())
+        };
+        assert_eq!(value("primary", "ok"), Some(String::from("2")));
+        assert_eq!(value("primary", "error"), Some(String::from("1")));
+        assert_eq!(value("secondary", "ok"), Some(String::from("1")));
+        assert_eq!(value("secondary", "error"), Some(String::from("2")));
+        let divergent = metrics
+            .iter()
+            .find(|m| m.name == "scaph_self_compare_divergent_batches_total")
+            .unwrap();
+        assert_eq!(divergent.metric_value.to_string(), "1");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
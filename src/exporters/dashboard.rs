@@ -0,0 +1,221 @@
+//! # Dashboard: Grafana dashboard for scaphandre metrics
+//!
+//! `scaphandre generate-dashboard` prints a Grafana dashboard, in JSON, querying
+//! the metrics of this version through a Prometheus datasource. Panels of
+//! metrics that aren't published on this host are left out: the integrated GPU
+//! panel when there is no integrated GPU, and the Kubernetes panels unless
+//! `--containers` is given.
+use crate::capabilities::json_string;
+use crate::sensors::utils::{has_integrated_gpu, PCI_DEVICES_PATH};
+use std::fmt::Write;
+
+/// Holds the arguments of `scaphandre generate-dashboard`.
+#[derive(clap::Args, Debug)]
+pub struct DashboardArgs {
+    /// Exporter the metrics are collected from, which sets the datasource of the panels
+    #[arg(long, default_value = "prometheus", value_parser = ["prometheus"])]
+    pub exporter: String,
+
+    /// Add panels of the power of Kubernetes namespaces and pods (for metrics
+    /// exposed with --containers)
+    #[arg(long)]
+    pub containers: bool,
+
+    /// Title of the dashboard
+    #[arg(long, default_value = "Scaphandre")]
+    pub title: String,
+}
+
+/// A timeseries panel, with one query.
+struct Panel {
+    title: &'static str,
+    expr: &'static str,
+    legend: &'static str,
+    unit: &'static str,
+}
+
+/// Returns the rows of the dashboard, as their title and panels.
+fn rows(containers: bool, gpu: bool) -> Vec<(&'static str, Vec<Panel>)> {
+    let mut rows = vec![
+        (
+            "Hosts",
+            vec![
+                Panel {
+                    title: "Host power",
+                    expr: "(sum by (instance) (scaph_host_power_microwatts{instance=~\"$instance\", value_source=~\".*psys\"}) or sum by (instance) (scaph_host_power_microwatts{instance=~\"$instance\"})) / 1e6",
+                    legend: "{{instance}}",
+                    unit: "watt",
+                },
+                Panel {
+                    title: "Socket power",
+                    expr: "scaph_socket_power_microwatts{instance=~\"$instance\"} / 1e6",
+                    legend: "{{instance}} socket {{socket_id}}",
+                    unit: "watt",
+                },
+                Panel {
+                    title: "Host energy over the last hour",
+                    expr: "sum by (instance) (increase(scaph_host_energy_microjoules{instance=~\"$instance\"}[1h])) / 3.6e9",
+                    legend: "{{instance}}",
+                    unit: "kwatth",
+                },
+                Panel {
+                    title: "CPU load",
+                    expr: "scaph_host_load_avg_one{instance=~\"$instance\"}",
+                    legend: "{{instance}}",
+                    unit: "short",
+                },
+            ],
+        ),
+        (
+            "Processes",
+            vec![
+                Panel {
+                    title: "Top 10 processes",
+                    expr: "topk(10, scaph_process_power_consumption_microwatts{instance=~\"$instance\"}) / 1e6",
+                    legend: "{{instance}} {{exe}} ({{pid}})",
+                    unit: "watt",
+                },
+                Panel {
+                    title: "Top 10 executables",
+                    expr: "topk(10, sum by (instance, exe) (scaph_process_power_consumption_microwatts{instance=~\"$instance\"})) / 1e6",
+                    legend: "{{instance}} {{exe}}",
+                    unit: "watt",
+                },
+            ],
+        ),
+    ];
+    if gpu {
+        rows.push((
+            "Integrated GPU",
+            vec![Panel {
+                title: "Integrated GPU power",
+                expr: "scaph_igpu_power_microwatts{instance=~\"$instance\"} / 1e6",
+                legend: "{{instance}} socket {{socket_id}}",
+                unit: "watt",
+            }],
+        ));
+    }
+    if containers {
+        rows.push((
+            "Kubernetes",
+            vec![
+                Panel {
+                    title: "Power by namespace",
+                    expr: "sum by (kubernetes_pod_namespace) (scaph_process_power_consumption_microwatts{instance=~\"$instance\", kubernetes_pod_namespace!=\"\"}) / 1e6",
+                    legend: "{{kubernetes_pod_namespace}}",
+                    unit: "watt",
+                },
+                Panel {
+                    title: "Top 10 pods",
+                    expr: "topk(10, sum by (kubernetes_pod_namespace, kubernetes_pod_name) (scaph_process_power_consumption_microwatts{instance=~\"$instance\", kubernetes_pod_name!=\"\"})) / 1e6",
+                    legend: "{{kubernetes_pod_namespace}}/{{kubernetes_pod_name}}",
+                    unit: "watt",
+                },
+            ],
+        ));
+    }
+    rows
+}
+
+/// Returns the dashboard, in JSON, for the integrated GPU of this host if any.
+pub fn generate(args: &DashboardArgs) -> String {
+    dashboard(args, has_integrated_gpu(PCI_DEVICES_PATH))
+}
+
+fn dashboard(args: &DashboardArgs, gpu: bool) -> String {
+    let datasource = "{\"type\":\"prometheus\",\"uid\":\"${datasource}\"}";
+    let mut panels = vec![];
+    let mut id = 1;
+    let mut y = 0;
+    for (title, row) in rows(args.containers, gpu) {
+        panels.push(format!(
+            "{{\"id\":{id},\"type\":\"row\",\"title\":{},\"collapsed\":false,\"gridPos\":{{\"x\":0,\"y\":{y},\"w\":24,\"h\":1}},\"panels\":[]}}",
+            json_string(title)
+        ));
+        id += 1;
+        y += 1;
+        for (i, panel) in row.iter().enumerate() {
+            let mut json = String::new();
+            let _ = write!(
+                json,
+                "{{\"id\":{id},\"type\":\"timeseries\",\"title\":{},\"datasource\":{datasource},\"gridPos\":{{\"x\":{},\"y\":{},\"w\":12,\"h\":8}},",
+                json_string(panel.title),
+                (i % 2) * 12,
+                y + (i / 2) * 8
+            );
+            let _ = write!(
+                json,
+                "\"fieldConfig\":{{\"defaults\":{{\"unit\":{}}},\"overrides\":[]}},\"targets\":[{{\"refId\":\"A\",\"datasource\":{datasource},\"expr\":{},\"legendFormat\":{}}}]}}",
+                json_string(panel.unit),
+                json_string(panel.expr),
+                json_string(panel.legend)
+            );
+            panels.push(json);
+            id += 1;
+        }
+        y += row.len().div_ceil(2) * 8;
+    }
+    format!(
+        "{{\"title\":{},\"uid\":\"scaphandre\",\"tags\":[\"scaphandre\"],\"schemaVersion\":38,\"editable\":true,\"refresh\":\"30s\",\"time\":{{\"from\":\"now-6h\",\"to\":\"now\"}},\"templating\":{{\"list\":[{{\"name\":\"datasource\",\"label\":\"Data source\",\"type\":\"datasource\",\"query\":{}}},{{\"name\":\"instance\",\"label\":\"Instance\",\"type\":\"query\",\"datasource\":{datasource},\"query\":\"label_values(scaph_host_power_microwatts, instance)\",\"refresh\":2,\"multi\":true,\"includeAll\":true,\"current\":{{\"text\":\"All\",\"value\":\"$__all\"}}}}]}},\"panels\":[{}]}}\n",
+        json_string(&args.title),
+        json_string(&args.exporter),
+        panels.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dashboard_panels() {
+        let mut args = DashboardArgs {
+            exporter: String::from("prometheus"),
+            containers: false,
+            title: String::from("Scaphandre"),
+        };
+        let json = dashboard(&args, false);
+        assert!(json.contains("\"title\":\"Top 10 processes\""));
+        assert!(!json.contains("scaph_igpu_power_microwatts"));
+        assert!(!json.contains("kubernetes_pod_namespace"));
+        args.containers = true;
+        let json = dashboard(&args, true);
+        assert!(json.contains("scaph_igpu_power_microwatts"));
+        assert!(json.contains("\"title\":\"Power by namespace\""));
+        #[cfg(feature = "json")]
+        {
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["panels"].as_array().unwrap().len(), 13);
+        }
+
+        // the metrics have to exist in this version
+        let sources = [include_str!("mod.rs"), include_str!("../sensors/mod.rs")];
+        for metric in [
+            "scaph_host_power_microwatts",
+            "scaph_socket_power_microwatts",
+            "scaph_host_energy_microjoules",
+            "scaph_host_load_avg_one",
+            "scaph_process_power_consumption_microwatts",
+            "scaph_igpu_power_microwatts",
+        ] {
+            assert!(
+                sources.iter().any(|s| s.contains(&format!("\"{metric}\""))),
+                "{metric} is not generated anymore"
+            );
+        }
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,462 @@
+//! # Derived metrics: site-specific metrics computed from the others
+//!
+//! A derived metrics file defines metrics computed from the values of other
+//! metrics each time they are generated, for the KPIs of a site (energy per
+//! request, share of the host power going to a service...). Each non-empty
+//! line that doesn't start with `#` defines one metric:
+//!
+//! ```text
+//! # name = expression
+//! host_power_watts = scaph_host_power_microwatts / 1000000
+//! nginx_power_share = scaph_process_power_consumption_microwatts{exe="nginx"} / scaph_host_power_microwatts
+//! efficiency = scaph_host_power_microwatts / http_requests_rate
+//! ```
+//!
+//! Expressions are made of numbers, metric names, `+ - * /` and parentheses. A
+//! metric name stands for the sum of the metrics of that name, restricted to
+//! those whose labels have the values given between braces, if any. Names that
+//! aren't generated by scaphandre (`http_requests_rate` above) are values
+//! pushed by other programs, with `PUT /values/<name>` on the prometheus
+//! exporter, and forgotten after [EXTERNAL_VALUE_TTL].
+//!
+//! A derived metric isn't published when one of its operands has no value or
+//! when it divides by zero. Derived metrics can't refer to each other.
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+
+/// How long a pushed value is used for, if not pushed again.
+pub const EXTERNAL_VALUE_TTL: Duration = Duration::from_secs(300);
+
+/// Binary operator of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// Parsed expression of a derived metric.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    /// Sum of the metrics named `name` whose labels have the given values
+    Selector {
+        name: String,
+        labels: Vec<(String, String)>,
+    },
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, `value` giving the value of a selector.
+    /// Returns None if a selector has no value or the result isn't finite.
+    pub fn eval<F>(&self, value: &F) -> Option<f64>
+    where
+        F: Fn(&str, &[(String, String)]) -> Option<f64>,
+    {
+        let result = match self {
+            Expr::Number(number) => *number,
+            Expr::Selector { name, labels } => value(name, labels)?,
+            Expr::Neg(expr) => -expr.eval(value)?,
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (left.eval(value)?, right.eval(value)?);
+                match op {
+                    Op::Add => left + right,
+                    Op::Sub => left - right,
+                    Op::Mul => left * right,
+                    Op::Div => left / right,
+                }
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    /// Calls `f` with the name of each selector of the expression.
+    fn visit_names<'a>(&'a self, f: &mut impl FnMut(&'a str)) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Selector { name, .. } => f(name),
+            Expr::Neg(expr) => expr.visit_names(f),
+            Expr::Binary(left, _, right) => {
+                left.visit_names(f);
+                right.visit_names(f);
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let expr = parser.expr()?;
+        parser.skip_whitespaces();
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(format!("unexpected '{c}' at column {}", parser.pos + 1)),
+        }
+    }
+}
+
+/// Recursive descent parser of expressions.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':'
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start(c) || c.is_ascii_digit()
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `c`, after whitespaces, if it comes next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespaces();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected '{c}' at column {}", self.pos + 1))
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
+        }
+    }
+
+    /// term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.factor()?));
+        }
+    }
+
+    /// factor := '-' factor | '(' expr ')' | number | selector
+    fn factor(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.factor()?)));
+        }
+        if self.eat('(') {
+            let expr = self.expr()?;
+            self.expect(')')?;
+            return Ok(expr);
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.' || c == 'e');
+                number
+                    .parse::<f64>()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("invalid number {number}"))
+            }
+            Some(c) if is_name_start(c) => self.selector(),
+            Some(c) => Err(format!("unexpected '{c}' at column {}", self.pos + 1)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    /// selector := name ('{' label '=' '"' value '"' (',' label '=' '"' value '"')* '}')?
+    fn selector(&mut self) -> Result<Expr, String> {
+        let name = self.take_while(is_name_char);
+        let mut labels = vec![];
+        if self.eat('{') {
+            loop {
+                self.skip_whitespaces();
+                let label = self.take_while(is_name_char);
+                if label.is_empty() {
+                    return Err(format!("expected a label at column {}", self.pos + 1));
+                }
+                self.expect('=')?;
+                self.expect('"')?;
+                let value = self.take_while(|c| c != '"');
+                self.expect('"')?;
+                labels.push((label, value));
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect('}')?;
+        }
+        Ok(Expr::Selector { name, labels })
+    }
+}
+
+/// A metric defined in a derived metrics file.
+#[derive(Debug, Clone)]
+pub struct DerivedMetric {
+    pub name: String,
+    pub expr: Expr,
+    /// The expression as written, for the description of the metric
+    pub source: String,
+}
+
+/// The derived metrics of a file, and the values pushed for them.
+#[derive(Debug, Clone, Default)]
+pub struct DerivedMetrics {
+    pub metrics: Vec<DerivedMetric>,
+    /// Last value pushed for each external name, and when it was pushed
+    external: HashMap<String, (f64, Duration)>,
+}
+
+impl DerivedMetrics {
+    /// Reads and parses the derived metrics file at `path`.
+    pub fn from_file(path: &str) -> Result<DerivedMetrics, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read derived metrics file {path}: {e}"))?;
+        DerivedMetrics::parse(&content).map_err(|e| format!("{path}: {e}"))
+    }
+
+    /// Parses the content of a derived metrics file.
+    pub fn parse(content: &str) -> Result<DerivedMetrics, String> {
+        let mut metrics: Vec<DerivedMetric> = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, source) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected <name> = <expression>", i + 1))?;
+            let (name, source) = (name.trim(), source.trim());
+            if name.is_empty()
+                || !name.starts_with(is_name_start)
+                || !name.chars().all(is_name_char)
+            {
+                return Err(format!("line {}: invalid metric name {name}", i + 1));
+            }
+            if name.starts_with("scaph_") {
+                return Err(format!(
+                    "line {}: {name}: the scaph_ prefix is reserved to the metrics of scaphandre",
+                    i + 1
+                ));
+            }
+            if metrics.iter().any(|m| m.name == name) {
+                return Err(format!("line {}: {name} is defined twice", i + 1));
+            }
+            let expr = source
+                .parse::<Expr>()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            metrics.push(DerivedMetric {
+                name: String::from(name),
+                expr,
+                source: String::from(source),
+            });
+        }
+        let res = DerivedMetrics {
+            metrics,
+            external: HashMap::new(),
+        };
+        for metric in &res.metrics {
+            let mut error = None;
+            metric.expr.visit_names(&mut |name| {
+                if res.is_derived(name) {
+                    error = Some(format!(
+                        "{} refers to the derived metric {name}",
+                        metric.name
+                    ));
+                }
+            });
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Tells if `name` is the name of a derived metric.
+    pub fn is_derived(&self, name: &str) -> bool {
+        self.metrics.iter().any(|m| m.name == name)
+    }
+
+    /// Tells if a value can be pushed for `name`: the expressions use it, and
+    /// it isn't a metric of scaphandre.
+    pub fn is_external(&self, name: &str) -> bool {
+        let mut found = false;
+        for metric in &self.metrics {
+            metric.expr.visit_names(&mut |n| found |= n == name);
+        }
+        found && !name.starts_with("scaph_")
+    }
+
+    /// Stores `value`, pushed at `now` for the external name `name`.
+    pub fn set_external_value(
+        &mut self,
+        name: &str,
+        value: f64,
+        now: Duration,
+    ) -> Result<(), String> {
+        if !self.is_external(name) {
+            return Err(format!("{name} isn't used by any derived metric"));
+        }
+        if !value.is_finite() {
+            return Err(format!("invalid value {value}"));
+        }
+        self.external.insert(String::from(name), (value, now));
+        Ok(())
+    }
+
+    /// Returns the value pushed for `name`, unless it is older than [EXTERNAL_VALUE_TTL].
+    pub fn external_value(&self, name: &str, now: Duration) -> Option<f64> {
+        let (value, pushed) = self.external.get(name)?;
+        (now.saturating_sub(*pushed) <= EXTERNAL_VALUE_TTL).then_some(*value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_evaluate() {
+        let derived = DerivedMetrics::parse(
+            "# KPIs\n\
+             \n\
+             share = scaph_process_power_consumption_microwatts{exe=\"nginx\", cgroup=\"/\"} / scaph_host_power_microwatts\n\
+             efficiency = (scaph_host_power_microwatts - 2e6) / -http_requests_rate * 100\n",
+        )
+        .unwrap();
+        assert_eq!(derived.metrics.len(), 2);
+        assert_eq!(
+            derived.metrics[0].expr,
+            Expr::Binary(
+                Box::new(Expr::Selector {
+                    name: String::from("scaph_process_power_consumption_microwatts"),
+                    labels: vec![
+                        (String::from("exe"), String::from("nginx")),
+                        (String::from("cgroup"), String::from("/"))
+                    ],
+                }),
+                Op::Div,
+                Box::new(Expr::Selector {
+                    name: String::from("scaph_host_power_microwatts"),
+                    labels: vec![],
+                })
+            )
+        );
+
+        let value = |name: &str, labels: &[(String, String)]| match name {
+            "scaph_host_power_microwatts" => Some(10e6),
+            "scaph_process_power_consumption_microwatts" if !labels.is_empty() => Some(2.5e6),
+            "http_requests_rate" => Some(-4.0),
+            _ => None,
+        };
+        assert_eq!(derived.metrics[0].expr.eval(&value), Some(0.25));
+        assert_eq!(derived.metrics[1].expr.eval(&value), Some(200e6));
+        assert_eq!(derived.metrics[1].expr.eval(&|_, _| None), None);
+        assert_eq!("1 / (2 - 2)".parse::<Expr>().unwrap().eval(&value), None);
+
+        for invalid in [
+            "a = 1 +",
+            "a = (1",
+            "a = b{c=d}",
+            "a = 1 2",
+            "a",
+            "scaph_x = 1",
+            "a = 1\na = 2",
+            "a = 1\nb = a * 2",
+        ] {
+            assert!(DerivedMetrics::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn external_values() {
+        let mut derived =
+            DerivedMetrics::parse("efficiency = scaph_host_power_microwatts / http_requests_rate")
+                .unwrap();
+        assert!(derived.is_external("http_requests_rate"));
+        assert!(!derived.is_external("scaph_host_power_microwatts"));
+        assert!(!derived.is_external("efficiency"));
+        assert!(derived
+            .set_external_value("other", 1.0, Duration::ZERO)
+            .is_err());
+        assert!(derived
+            .set_external_value("http_requests_rate", f64::NAN, Duration::ZERO)
+            .is_err());
+
+        let pushed = Duration::from_secs(1000);
+        derived
+            .set_external_value("http_requests_rate", 42.0, pushed)
+            .unwrap();
+        assert_eq!(
+            derived.external_value("http_requests_rate", pushed + EXTERNAL_VALUE_TTL),
+            Some(42.0)
+        );
+        assert_eq!(
+            derived.external_value(
+                "http_requests_rate",
+                pushed + EXTERNAL_VALUE_TTL + Duration::from_secs(1)
+            ),
+            None
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,367 @@
+//! # Energy totals: cumulative energy counters kept by scaphandre
+//!
+//! Hardware energy counters wrap around and don't exist per process, so
+//! [EnergyTotals] integrates the host and process powers computed by scaphandre
+//! into counters that only go up. With a state file, the counters are saved
+//! periodically and restored when scaphandre starts, so that a restart or an
+//! upgrade of the agent doesn't look like a counter reset to dashboards.
+//!
+//! The state file is a text file with one counter per line:
+//!
+//! ```text
+//! created <seconds since the epoch>
+//! host <microjoules>
+//! process <pid> <microjoules> <created> <exe>
+//! ```
+//!
+//! The creation times of the counters are exposed along them (see
+//! [EnergyTotals::created]), so that consumers such as Prometheus know when
+//! they really started from 0.
+//!
+//! Restored process counters are only kept if a process with the same pid and
+//! executable is still alive.
+//!
+//! [EnergySinceStart] integrates the host power as well, but always starts
+//! from 0 with the agent, and extrapolates it to the time since the host booted.
+use crate::sensors::utils::current_system_time_since_epoch;
+use std::collections::HashMap;
+use std::fs;
+use std::time::Duration;
+use sysinfo::{Pid, PidExt};
+
+/// Cumulative energy of a process.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessEnergy {
+    pub exe: String,
+    /// Energy in microjoules
+    pub energy: f64,
+    /// Time since the epoch the counter started at
+    pub created: Duration,
+}
+
+/// Cumulative energy of the host and of the processes, in microjoules.
+#[derive(Debug, Clone, Default)]
+pub struct EnergyTotals {
+    pub host: f64,
+    /// Time since the epoch the host counter started at
+    pub created: Duration,
+    /// Energy of each process, by pid
+    pub processes: HashMap<Pid, ProcessEnergy>,
+    /// Timestamp of the last host power added
+    last_timestamp: Option<Duration>,
+    /// Time elapsed between the last two host powers added, applied to process powers
+    interval: Duration,
+    state_file: Option<String>,
+    save_interval: Duration,
+    last_save: Option<Duration>,
+}
+
+impl EnergyTotals {
+    /// Returns counters starting at 0, or restored from `state_file` if it is set and
+    /// readable, which will be saved to `state_file` at most every `save_interval`.
+    pub fn new(state_file: Option<String>, save_interval: Duration) -> EnergyTotals {
+        let mut totals = match &state_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => match EnergyTotals::parse(&content) {
+                    Ok(totals) => {
+                        info!("Restored energy totals from {}", path);
+                        totals
+                    }
+                    Err(e) => {
+                        warn!("Ignoring invalid state file {}: {}", path, e);
+                        EnergyTotals::default()
+                    }
+                },
+                Err(e) => {
+                    info!("No energy totals restored from {}: {}", path, e);
+                    EnergyTotals::default()
+                }
+            },
+            None => EnergyTotals::default(),
+        };
+        if totals.created.is_zero() {
+            totals.created = Duration::from_secs(current_system_time_since_epoch().as_secs());
+        }
+        totals.state_file = state_file;
+        totals.save_interval = save_interval;
+        totals
+    }
+
+    fn parse(content: &str) -> Result<EnergyTotals, String> {
+        let mut totals = EnergyTotals::default();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let parse_energy = |value: &str| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite() && *v >= 0.0)
+                    .ok_or_else(|| format!("invalid energy {value} in line '{line}'"))
+            };
+            let parse_time = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .map_err(|e| format!("invalid time {value} in line '{line}': {e}"))
+            };
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            match fields.as_slice() {
+                ["created", created] => totals.created = parse_time(created)?,
+                ["host", energy] => totals.host = parse_energy(energy)?,
+                ["process", pid, energy, created, exe] => {
+                    let pid = pid
+                        .parse::<u32>()
+                        .map(Pid::from_u32)
+                        .map_err(|e| format!("invalid pid {pid} in line '{line}': {e}"))?;
+                    totals.processes.insert(
+                        pid,
+                        ProcessEnergy {
+                            exe: String::from(*exe),
+                            energy: parse_energy(energy)?,
+                            created: parse_time(created)?,
+                        },
+                    );
+                }
+                _ => return Err(format!("invalid line '{line}'")),
+            }
+        }
+        Ok(totals)
+    }
+
+    fn serialize(&self) -> String {
+        let mut content = format!("created {}\nhost {}\n", self.created.as_secs(), self.host);
+        for (pid, process) in &self.processes {
+            content.push_str(&format!(
+                "process {pid} {} {} {}\n",
+                process.energy,
+                process.created.as_secs(),
+                process.exe
+            ));
+        }
+        content
+    }
+
+    /// Adds the energy of the host, consumed at `microwatts` since the previous
+    /// call. A timestamp that is not newer than the previous one adds nothing.
+    pub fn add_host_power(&mut self, timestamp: Duration, microwatts: f64) {
+        self.interval = match self.last_timestamp {
+            Some(last) if timestamp <= last => Duration::ZERO,
+            Some(last) => timestamp - last,
+            // nothing is known of the time before the first measurement
+            None => Duration::ZERO,
+        };
+        if !self.interval.is_zero() || self.last_timestamp.is_none() {
+            self.last_timestamp = Some(timestamp);
+        }
+        self.host += microwatts * self.interval.as_secs_f64();
+    }
+
+    /// Adds the energy of the process `pid`, consumed at `microwatts` during the
+    /// last host interval, and returns its total. A process with the same pid but
+    /// another executable starts from 0.
+    pub fn add_process_power(&mut self, pid: Pid, exe: &str, microwatts: f64) -> f64 {
+        let interval = self.interval.as_secs_f64();
+        let new_counter = ProcessEnergy {
+            exe: String::from(exe),
+            energy: 0.0,
+            created: self.last_timestamp.unwrap_or(self.created),
+        };
+        let entry = self
+            .processes
+            .entry(pid)
+            .or_insert_with(|| new_counter.clone());
+        if entry.exe != exe {
+            *entry = new_counter;
+        }
+        entry.energy += microwatts * interval;
+        entry.energy
+    }
+
+    /// Forgets the processes that are not in `alive`.
+    pub fn retain_processes(&mut self, alive: &[Pid]) {
+        self.processes.retain(|pid, _| alive.contains(pid));
+    }
+
+    /// Writes the counters to the state file if the last save is older than the
+    /// save interval, `now` being the time since the epoch.
+    pub fn save_if_due(&mut self, now: Duration) {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return,
+        };
+        if self
+            .last_save
+            .is_some_and(|last| now.saturating_sub(last) < self.save_interval)
+        {
+            return;
+        }
+        // write then rename, so that a crash doesn't leave a truncated file
+        let tmp_path = format!("{path}.tmp");
+        match fs::write(&tmp_path, self.serialize()).and_then(|_| fs::rename(&tmp_path, path)) {
+            Ok(_) => self.last_save = Some(now),
+            Err(e) => warn!("Couldn't save energy totals to {}: {}", path, e),
+        }
+    }
+}
+
+/// Energy consumed by the host since scaphandre started, never restored from a
+/// state file.
+#[derive(Debug, Clone)]
+pub struct EnergySinceStart {
+    /// Energy in microjoules
+    pub energy: f64,
+    /// Time since the epoch scaphandre started at
+    pub started: Duration,
+    /// Timestamps of the first and last host powers added
+    first_timestamp: Option<Duration>,
+    last_timestamp: Option<Duration>,
+}
+
+impl EnergySinceStart {
+    /// Returns a counter starting at 0, `started` being the time since the epoch
+    /// scaphandre started at.
+    pub fn new(started: Duration) -> EnergySinceStart {
+        EnergySinceStart {
+            energy: 0.0,
+            started,
+            first_timestamp: None,
+            last_timestamp: None,
+        }
+    }
+
+    /// Adds the energy of the host, consumed at `microwatts` since the previous
+    /// call. A timestamp that is not newer than the previous one adds nothing.
+    pub fn add_host_power(&mut self, timestamp: Duration, microwatts: f64) {
+        match self.last_timestamp {
+            Some(last) if timestamp <= last => return,
+            Some(last) => self.energy += microwatts * (timestamp - last).as_secs_f64(),
+            None => self.first_timestamp = Some(timestamp),
+        }
+        self.last_timestamp = Some(timestamp);
+    }
+
+    /// Returns an estimate of the energy consumed by the host since it booted at
+    /// `boot_time` (time since the epoch): the energy measured, plus the mean
+    /// power measured applied to the time between the boot and the first
+    /// measurement. None until two measurements were added.
+    pub fn since_boot(&self, boot_time: Duration) -> Option<f64> {
+        let first = self.first_timestamp?;
+        let measured = self.last_timestamp?.checked_sub(first)?;
+        if measured.is_zero() || boot_time.is_zero() || boot_time > first {
+            return None;
+        }
+        let mean_microwatts = self.energy / measured.as_secs_f64();
+        Some(self.energy + mean_microwatts * (first - boot_time).as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn energy_since_start_and_boot() {
+        let s = Duration::from_secs;
+        let mut since_start = EnergySinceStart::new(s(1000));
+        since_start.add_host_power(s(1001), 10e6);
+        assert_eq!(since_start.energy, 0.0);
+        assert_eq!(since_start.since_boot(s(500)), None);
+        since_start.add_host_power(s(1011), 10e6);
+        since_start.add_host_power(s(1011), 50e6);
+        since_start.add_host_power(s(1021), 30e6);
+        assert_eq!(since_start.energy, 400e6);
+        // 20 W on average, over the 501 seconds between the boot and the first measurement
+        assert_eq!(since_start.since_boot(s(500)), Some(400e6 + 20e6 * 501.0));
+        // unknown or inconsistent boot time
+        assert_eq!(since_start.since_boot(Duration::ZERO), None);
+        assert_eq!(since_start.since_boot(s(2000)), None);
+    }
+
+    #[test]
+    fn integrate_power() {
+        let mut totals = EnergyTotals::default();
+        totals.add_host_power(Duration::from_secs(100), 10e6);
+        assert_eq!(totals.host, 0.0);
+        totals.add_host_power(Duration::from_secs(102), 10e6);
+        assert_eq!(totals.host, 20e6);
+        assert_eq!(
+            totals.add_process_power(Pid::from_u32(42), "nginx", 1e6),
+            2e6
+        );
+        // same measurement, nothing new
+        totals.add_host_power(Duration::from_secs(102), 10e6);
+        assert_eq!(totals.host, 20e6);
+        assert_eq!(
+            totals.add_process_power(Pid::from_u32(42), "nginx", 1e6),
+            2e6
+        );
+        totals.add_host_power(Duration::from_secs(103), 10e6);
+        assert_eq!(
+            totals.add_process_power(Pid::from_u32(42), "nginx", 1e6),
+            3e6
+        );
+        // pid reused by another program
+        assert_eq!(
+            totals.add_process_power(Pid::from_u32(42), "bash", 1e6),
+            1e6
+        );
+        assert_eq!(
+            totals.processes[&Pid::from_u32(42)].created,
+            Duration::from_secs(103)
+        );
+        totals.retain_processes(&[Pid::from_u32(1)]);
+        assert!(totals.processes.is_empty());
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let path = std::env::temp_dir().join(format!(
+            "scaphandre-test-energy-totals-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap().to_string();
+        let mut totals = EnergyTotals::new(Some(path.clone()), Duration::from_secs(60));
+        totals.host = 1234.5;
+        totals.processes.insert(
+            Pid::from_u32(42),
+            ProcessEnergy {
+                exe: String::from("my program"),
+                energy: 12.0,
+                created: Duration::from_secs(900),
+            },
+        );
+        totals.save_if_due(Duration::from_secs(1000));
+        totals.host = 2000.0;
+        // too early for a new save
+        totals.save_if_due(Duration::from_secs(1030));
+
+        let restored = EnergyTotals::new(Some(path.clone()), Duration::from_secs(60));
+        assert_eq!(restored.host, 1234.5);
+        assert_eq!(restored.created, totals.created);
+        assert_eq!(
+            restored.processes.get(&Pid::from_u32(42)),
+            Some(&ProcessEnergy {
+                exe: String::from("my program"),
+                energy: 12.0,
+                created: Duration::from_secs(900),
+            })
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(EnergyTotals::parse("host -3").is_err());
+        assert!(EnergyTotals::parse("process 1 2 3").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,392 @@
+//! # ETWExporter
+//!
+//! `ETWExporter` registers the `Scaphandre` provider of Event Tracing for
+//! Windows and writes each metric as a TraceLogging event, so that the energy
+//! of the host and its processes ends up in the traces captured by WPR, xperf
+//! or any ETW session, next to their CPU samples and context switches.
+//!
+//! Events are self-describing: the name of an event is the name of the metric,
+//! its first field is `value` (a double) and the next ones are the labels of
+//! the metric, as UTF-8 strings. Tools decode them without a manifest.
+//!
+//! Metrics are only computed while a session listens to the provider.
+use crate::exporters::*;
+use crate::sensors::Sensor;
+use std::ffi::c_void;
+use std::time::Duration;
+
+/// Name of the ETW provider.
+pub const PROVIDER_NAME: &str = "Scaphandre";
+
+/// GUID of the provider, derived from its name the way TraceLogging and
+/// EventSource do it, so that tools also take `*Scaphandre` in place of it.
+pub const PROVIDER_GUID: Guid = Guid {
+    data1: 0x54bd6040,
+    data2: 0xbb08,
+    data3: 0x565f,
+    data4: [0xf9, 0xc5, 0x87, 0xe2, 0x4c, 0x08, 0x9c, 0x4a],
+};
+
+/// Channel of TraceLogging events.
+const CHANNEL_TRACELOGGING: u8 = 11;
+/// Level of the metric events (information).
+const LEVEL_INFORMATION: u8 = 4;
+/// Keyword of the metric events, sessions enabling the provider with a
+/// keyword mask may keep them out.
+pub const KEYWORD_METRICS: u64 = 0x1;
+
+/// `EVENT_INFO_CLASS` values given to `EventSetInformation`.
+const EVENT_PROVIDER_SET_TRAITS: u32 = 2;
+const EVENT_PROVIDER_USE_DESCRIPTOR_TYPE: u32 = 3;
+
+/// Types of `EVENT_DATA_DESCRIPTOR`.
+const DESCRIPTOR_TYPE_EVENT_METADATA: u8 = 1;
+const DESCRIPTOR_TYPE_PROVIDER_METADATA: u8 = 2;
+
+/// TraceLogging field types.
+const IN_TYPE_ANSI_STRING: u8 = 2;
+const IN_TYPE_DOUBLE: u8 = 12;
+const IN_TYPE_CHAIN: u8 = 0x80;
+const OUT_TYPE_UTF8: u8 = 35;
+
+/// A Windows GUID.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data4;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+#[repr(C)]
+struct EventDescriptor {
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+}
+
+#[repr(C)]
+struct EventDataDescriptor {
+    ptr: u64,
+    size: u32,
+    kind: u8,
+    reserved1: u8,
+    reserved2: u16,
+}
+
+impl EventDataDescriptor {
+    fn new(data: &[u8], kind: u8) -> EventDataDescriptor {
+        EventDataDescriptor {
+            ptr: data.as_ptr() as u64,
+            size: data.len() as u32,
+            kind,
+            reserved1: 0,
+            reserved2: 0,
+        }
+    }
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(
+        provider_id: *const Guid,
+        enable_callback: *const c_void,
+        callback_context: *const c_void,
+        reg_handle: *mut u64,
+    ) -> u32;
+    fn EventUnregister(reg_handle: u64) -> u32;
+    fn EventSetInformation(
+        reg_handle: u64,
+        information_class: u32,
+        information: *const c_void,
+        information_length: u32,
+    ) -> u32;
+    fn EventProviderEnabled(reg_handle: u64, level: u8, keyword: u64) -> u8;
+    fn EventWriteTransfer(
+        reg_handle: u64,
+        event_descriptor: *const EventDescriptor,
+        activity_id: *const Guid,
+        related_activity_id: *const Guid,
+        user_data_count: u32,
+        user_data: *const EventDataDescriptor,
+    ) -> u32;
+}
+
+/// Appends `text` to `buffer` as a nul-terminated UTF-8 string.
+fn push_string(buffer: &mut Vec<u8>, text: &str) {
+    buffer.extend(text.bytes().filter(|b| *b != 0));
+    buffer.push(0);
+}
+
+/// Writes the size of a metadata blob in its first two bytes.
+fn with_size(mut blob: Vec<u8>) -> Vec<u8> {
+    let size = (blob.len() as u16).to_le_bytes();
+    blob[..2].copy_from_slice(&size);
+    blob
+}
+
+/// Returns the provider metadata (traits) of the provider `name`.
+pub fn provider_metadata(name: &str) -> Vec<u8> {
+    let mut blob = vec![0, 0];
+    push_string(&mut blob, name);
+    with_size(blob)
+}
+
+/// Returns the metadata of the event of `metric`, then its payload.
+pub fn event_data(metric: &Metric) -> Option<(Vec<u8>, Vec<u8>)> {
+    let value = metric.metric_value.to_string().trim().parse::<f64>().ok()?;
+    // size, then no tags
+    let mut metadata = vec![0, 0, 0];
+    push_string(&mut metadata, &metric.name);
+    push_string(&mut metadata, "value");
+    metadata.push(IN_TYPE_DOUBLE);
+    let mut payload = value.to_le_bytes().to_vec();
+    for (label, label_value) in metric.sorted_attributes() {
+        push_string(&mut metadata, label);
+        metadata.extend([IN_TYPE_ANSI_STRING | IN_TYPE_CHAIN, OUT_TYPE_UTF8]);
+        push_string(&mut payload, &label_value.to_string());
+    }
+    Some((with_size(metadata), payload))
+}
+
+/// A registered ETW provider.
+pub struct Provider {
+    handle: u64,
+    metadata: Vec<u8>,
+}
+
+impl Provider {
+    /// Registers the provider `name` under `guid`.
+    pub fn register(guid: &Guid, name: &str) -> Result<Provider, String> {
+        let mut handle = 0u64;
+        let status =
+            unsafe { EventRegister(guid, std::ptr::null(), std::ptr::null(), &mut handle) };
+        if status != 0 {
+            return Err(format!(
+                "couldn't register the ETW provider {name} ({guid}), error {status}"
+            ));
+        }
+        let metadata = provider_metadata(name);
+        let use_descriptor_type = 1u8;
+        unsafe {
+            EventSetInformation(
+                handle,
+                EVENT_PROVIDER_SET_TRAITS,
+                metadata.as_ptr() as *const c_void,
+                metadata.len() as u32,
+            );
+            EventSetInformation(
+                handle,
+                EVENT_PROVIDER_USE_DESCRIPTOR_TYPE,
+                &use_descriptor_type as *const u8 as *const c_void,
+                1,
+            );
+        }
+        Ok(Provider { handle, metadata })
+    }
+
+    /// Tells if a session listens to the metric events.
+    pub fn enabled(&self) -> bool {
+        unsafe { EventProviderEnabled(self.handle, LEVEL_INFORMATION, KEYWORD_METRICS) != 0 }
+    }
+
+    /// Writes `metric` as an event. Metrics whose value isn't a number are
+    /// left out.
+    pub fn write(&self, metric: &Metric) -> Result<(), String> {
+        let Some((metadata, payload)) = event_data(metric) else {
+            return Ok(());
+        };
+        let descriptor = EventDescriptor {
+            id: 0,
+            version: 0,
+            channel: CHANNEL_TRACELOGGING,
+            level: LEVEL_INFORMATION,
+            opcode: 0,
+            task: 0,
+            keyword: KEYWORD_METRICS,
+        };
+        let data = [
+            EventDataDescriptor::new(&self.metadata, DESCRIPTOR_TYPE_PROVIDER_METADATA),
+            EventDataDescriptor::new(&metadata, DESCRIPTOR_TYPE_EVENT_METADATA),
+            EventDataDescriptor::new(&payload, 0),
+        ];
+        let status = unsafe {
+            EventWriteTransfer(
+                self.handle,
+                &descriptor,
+                std::ptr::null(),
+                std::ptr::null(),
+                data.len() as u32,
+                data.as_ptr(),
+            )
+        };
+        match status {
+            0 => Ok(()),
+            status => Err(format!("couldn't write {}, error {status}", metric.name)),
+        }
+    }
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        unsafe {
+            EventUnregister(self.handle);
+        }
+    }
+}
+
+/// An exporter that writes the metrics as events of an ETW provider.
+pub struct ETWExporter {
+    metric_generator: MetricGenerator,
+    provider: Provider,
+    args: ExporterArgs,
+}
+
+/// Holds the arguments for an ETWExporter.
+#[derive(clap::Args, Debug)]
+pub struct ExporterArgs {
+    /// Interval between two measurements, in seconds
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 2)]
+    pub step: u64,
+
+    /// Apply labels to metrics of processes running as containers
+    #[arg(long)]
+    pub containers: bool,
+
+    #[command(flatten)]
+    pub generator: utils::MetricGeneratorArgs,
+
+    #[command(flatten)]
+    pub step_args: utils::StepArgs,
+}
+
+impl Exporter for ETWExporter {
+    /// Runs [iterate()] every [step] until interrupted.
+    fn run(&mut self) {
+        info!(
+            "Writing the metrics as events of the ETW provider {PROVIDER_NAME} ({PROVIDER_GUID})"
+        );
+        let step = Duration::from_secs(self.args.step);
+        let mut listened = false;
+        loop {
+            let enabled = self.provider.enabled();
+            if enabled != listened {
+                info!(
+                    "{}",
+                    if enabled {
+                        "An ETW session listens to the provider, writing events"
+                    } else {
+                        "No ETW session listens to the provider anymore"
+                    }
+                );
+                listened = enabled;
+            }
+            self.iterate(enabled);
+            utils::wait_for_next_step(step, self.args.step_args.align_timestamps);
+        }
+    }
+
+    fn kind(&self) -> &str {
+        "etw"
+    }
+}
+
+impl ETWExporter {
+    /// Instantiates and returns a new ETWExporter, registering its provider.
+    pub fn new(sensor: &dyn Sensor, args: ExporterArgs) -> ETWExporter {
+        let topology = sensor
+            .get_topology()
+            .expect("sensor topology should be available");
+        let mut metric_generator =
+            MetricGenerator::new(topology, utils::get_hostname(), false, args.containers);
+        args.generator.apply(&mut metric_generator);
+        let provider =
+            Provider::register(&PROVIDER_GUID, PROVIDER_NAME).unwrap_or_else(|e| panic!("{e}"));
+        ETWExporter {
+            metric_generator,
+            provider,
+            args,
+        }
+    }
+
+    /// Refreshes the topology and, when a session listens, writes the metrics.
+    fn iterate(&mut self, enabled: bool) {
+        self.metric_generator.topology.refresh();
+        if !enabled {
+            return;
+        }
+        self.metric_generator.gen_all_metrics();
+        for metric in self.metric_generator.pop_metrics() {
+            if let Err(e) = self.provider.write(&metric) {
+                warn!("{e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tracelogging_metadata() {
+        assert_eq!(
+            PROVIDER_GUID.to_string(),
+            "54bd6040-bb08-565f-f9c5-87e24c089c4a"
+        );
+        assert_eq!(provider_metadata("Scaphandre"), b"\x0d\x00Scaphandre\x00");
+
+        let metric = Metric {
+            name: String::from("scaph_host_power_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::ZERO,
+            hostname: String::from("node1"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: HashMap::from([(String::from("value_source"), "powercap_rapl".into())]),
+            description: String::from("Power of the host, in microwatts."),
+            metric_value: MetricValueType::IntUnsigned(12_000_000),
+        };
+        let (metadata, payload) = event_data(&metric).unwrap();
+        let mut expected = vec![0, 0, 0];
+        expected.extend(b"scaph_host_power_microwatts\x00value\x00\x0c");
+        expected.extend(b"value_source\x00\x82\x23");
+        expected[0] = expected.len() as u8;
+        assert_eq!(metadata, expected);
+        let mut expected = 12e6f64.to_le_bytes().to_vec();
+        expected.extend(b"powercap_rapl\x00");
+        assert_eq!(payload, expected);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,175 @@
+//! # Forecast: short-term predictions of the host power
+//!
+//! [PowerForecaster] follows the host power with a damped Holt model (exponential
+//! smoothing of a level and a trend, the non-seasonal form of Holt-Winters) and
+//! predicts its value at the configured horizons. The trend is damped so that
+//! predictions far ahead converge instead of extrapolating the last variations
+//! forever: the model is meant to give a cheap hint of where consumption is
+//! heading, not to replace a proper capacity planning pipeline.
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Smoothing factor of the level (weight of the last measurement).
+const ALPHA: f64 = 0.3;
+/// Smoothing factor of the trend.
+const BETA: f64 = 0.1;
+/// Damping of the trend, per measurement interval.
+const PHI: f64 = 0.98;
+
+/// A time ahead of the last measurement to forecast the power at, such as `1h`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Horizon {
+    /// Horizon as given by the user, used as metric label
+    pub label: String,
+    pub duration: Duration,
+}
+
+impl FromStr for Horizon {
+    type Err = String;
+
+    /// Parses a number followed by a unit: s, m, h or d.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unit_index = s
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| format!("horizon {s} has no unit (s, m, h or d)"))?;
+        let (value, unit) = s.split_at(unit_index);
+        let value = value
+            .parse::<u64>()
+            .map_err(|e| format!("invalid horizon {s}: {e}"))?;
+        let seconds = match unit {
+            "s" => value,
+            "m" => value * 60,
+            "h" => value * 3600,
+            "d" => value * 86400,
+            _ => {
+                return Err(format!(
+                    "invalid unit in horizon {s}, expected s, m, h or d"
+                ))
+            }
+        };
+        if seconds == 0 {
+            return Err(String::from("horizon should be greater than 0"));
+        }
+        Ok(Horizon {
+            label: String::from(s),
+            duration: Duration::from_secs(seconds),
+        })
+    }
+}
+
+/// Damped Holt model of the host power.
+#[derive(Debug, Clone)]
+pub struct PowerForecaster {
+    pub horizons: Vec<Horizon>,
+    level: Option<f64>,
+    /// Variation of the level per measurement interval
+    trend: f64,
+    /// Smoothed time between two measurements, in seconds
+    step: Option<f64>,
+    last_timestamp: Option<Duration>,
+}
+
+impl PowerForecaster {
+    pub fn new(horizons: Vec<Horizon>) -> PowerForecaster {
+        PowerForecaster {
+            horizons,
+            level: None,
+            trend: 0.0,
+            step: None,
+            last_timestamp: None,
+        }
+    }
+
+    /// Updates the model with the power measured at `timestamp` (since the epoch).
+    /// Measurements that are not newer than the last one are ignored.
+    pub fn update(&mut self, timestamp: Duration, value: f64) {
+        let (level, last_timestamp) = match (self.level, self.last_timestamp) {
+            (Some(level), Some(last_timestamp)) => (level, last_timestamp),
+            _ => {
+                self.level = Some(value);
+                self.last_timestamp = Some(timestamp);
+                return;
+            }
+        };
+        if timestamp <= last_timestamp {
+            return;
+        }
+        let interval = (timestamp - last_timestamp).as_secs_f64();
+        self.step = Some(match self.step {
+            Some(step) => step + ALPHA * (interval - step),
+            None => interval,
+        });
+        let new_level = ALPHA * value + (1.0 - ALPHA) * (level + PHI * self.trend);
+        self.trend = BETA * (new_level - level) + (1.0 - BETA) * PHI * self.trend;
+        self.level = Some(new_level);
+        self.last_timestamp = Some(timestamp);
+    }
+
+    /// Returns the power expected `horizon` after the last measurement, or None
+    /// until the model got two measurements.
+    pub fn forecast(&self, horizon: Duration) -> Option<f64> {
+        let level = self.level?;
+        let step = self.step?;
+        let steps = horizon.as_secs_f64() / step;
+        // sum of PHI^i for i in 1..=steps
+        let damped_steps = PHI * (1.0 - PHI.powf(steps)) / (1.0 - PHI);
+        Some((level + self.trend * damped_steps).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_horizons() {
+        let horizon = "1h".parse::<Horizon>().unwrap();
+        assert_eq!(horizon.label, "1h");
+        assert_eq!(horizon.duration, Duration::from_secs(3600));
+        assert_eq!(
+            "15m".parse::<Horizon>().unwrap().duration,
+            Duration::from_secs(900)
+        );
+        assert!("1".parse::<Horizon>().is_err());
+        assert!("0s".parse::<Horizon>().is_err());
+        assert!("2w".parse::<Horizon>().is_err());
+        assert!("h".parse::<Horizon>().is_err());
+    }
+
+    #[test]
+    fn forecast_power() {
+        let mut forecaster = PowerForecaster::new(vec![]);
+        forecaster.update(Duration::from_secs(100), 50.0);
+        assert_eq!(forecaster.forecast(Duration::from_secs(3600)), None);
+        for i in 1..100 {
+            forecaster.update(Duration::from_secs(100 + i * 10), 50.0);
+        }
+        let constant = forecaster.forecast(Duration::from_secs(3600)).unwrap();
+        assert!((constant - 50.0).abs() < 1e-6);
+
+        // a rising power is forecast higher, within the bounds set by the damping
+        for i in 100..200 {
+            forecaster.update(Duration::from_secs(100 + i * 10), 50.0 + (i - 100) as f64);
+        }
+        let short = forecaster.forecast(Duration::from_secs(60)).unwrap();
+        let long = forecaster.forecast(Duration::from_secs(3600)).unwrap();
+        assert!(short > 140.0);
+        assert!(long > short);
+        assert!(long < 200.0);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
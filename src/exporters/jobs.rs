@@ -0,0 +1,280 @@
+//! # Jobs: power and energy of the jobs of HPC batch schedulers
+//!
+//! SLURM and PBS run the processes of each job in a dedicated cgroup, whose
+//! path holds the job id. [Jobs] sums the power of the processes by job and
+//! integrates it into an energy counter per job, so that the energy of a job
+//! can be read at its end without joining process metrics by hand.
+//!
+//! Cgroups of the jobs, as found in /proc/PID/cgroup:
+//!
+//! ```text
+//! /slurm/uid_1000/job_1234/step_0/task_0                          SLURM, cgroup v1
+//! /system.slice/slurmstepd.scope/job_1234/step_0/user/task_0      SLURM, cgroup v2
+//! /pbs_jobs.service/jobid/1234.server                             PBS Pro
+//! /pbspro/1234.server                                             PBS Pro (older versions)
+//! /torque/1234.server                                             Torque
+//! ```
+//!
+//! Jobs stay published for [RETENTION] after their last process exited, for
+//! their final energy to be scraped.
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Time a job is still published for after its last process exited.
+pub const RETENTION: Duration = Duration::from_secs(600);
+
+/// Batch job a process belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId {
+    /// `slurm` or `pbs`
+    pub scheduler: &'static str,
+    pub id: String,
+}
+
+/// Returns the job a process belongs to, from the paths of its `cgroups`.
+pub fn job_from_cgroups(cgroups: &[String]) -> Option<JobId> {
+    cgroups.iter().find_map(|path| job_from_cgroup(path))
+}
+
+fn job_from_cgroup(path: &str) -> Option<JobId> {
+    let components = path.split('/').collect::<Vec<_>>();
+    if components.iter().any(|c| c.contains("slurm")) {
+        let id = components.iter().find_map(|c| c.strip_prefix("job_"))?;
+        if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+            return Some(JobId {
+                scheduler: "slurm",
+                id: id.to_string(),
+            });
+        }
+        return None;
+    }
+    let id = components.windows(2).find_map(|pair| match pair {
+        ["jobid", id] if components.contains(&"pbs_jobs.service") => Some(*id),
+        ["pbspro" | "torque", id] => Some(*id),
+        _ => None,
+    })?;
+    if id.is_empty() {
+        return None;
+    }
+    Some(JobId {
+        scheduler: "pbs",
+        id: id.to_string(),
+    })
+}
+
+/// Power and energy of a job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// Name of the user running the job, or its uid if it has no name
+    pub user: String,
+    /// Power of the job at the last measurement, in microwatts (0 once it ended)
+    pub power: f64,
+    /// Energy consumed since the job was first seen, in microjoules
+    pub energy: f64,
+    pub timestamp: Duration,
+    /// Time since the epoch the energy counter started at
+    pub created: Duration,
+}
+
+/// Aggregation of the power of processes by batch job.
+#[derive(Debug, Clone, Default)]
+pub struct Jobs {
+    jobs: HashMap<JobId, Job>,
+    /// Power and user of the jobs during the current measurement
+    current: HashMap<JobId, (f64, Duration, Option<u32>)>,
+    /// Names of the users, by uid
+    user_names: HashMap<u32, String>,
+}
+
+impl Jobs {
+    /// Adds the power of a process of `job`, running as `uid`.
+    pub fn add_process_power(
+        &mut self,
+        job: &JobId,
+        uid: Option<u32>,
+        microwatts: f64,
+        timestamp: Duration,
+    ) {
+        let entry = self
+            .current
+            .entry(job.clone())
+            .or_insert((0.0, timestamp, uid));
+        entry.0 += microwatts;
+        entry.1 = entry.1.max(timestamp);
+        // the job step daemon runs as root, its tasks as the user
+        if entry.2.is_none() || entry.2 == Some(0) {
+            entry.2 = uid;
+        }
+    }
+
+    /// Ends the current measurement at `now`: integrates the power of each job
+    /// into its energy, forgets the jobs ended for longer than [RETENTION], and
+    /// returns the jobs sorted by id.
+    pub fn flush(&mut self, now: Duration) -> Vec<(JobId, Job)> {
+        let current = std::mem::take(&mut self.current);
+        for job in self.jobs.values_mut() {
+            // only jobs with processes in this measurement get a power below
+            job.power = 0.0;
+        }
+        for (id, (power, timestamp, uid)) in current {
+            let user = match uid {
+                Some(uid) => self
+                    .user_names
+                    .entry(uid)
+                    .or_insert_with(|| user_name(uid))
+                    .clone(),
+                None => String::new(),
+            };
+            let job = self.jobs.entry(id).or_insert(Job {
+                user: user.clone(),
+                power,
+                energy: 0.0,
+                timestamp,
+                created: Duration::from_secs(now.as_secs()),
+            });
+            if timestamp > job.timestamp {
+                job.energy += power * (timestamp - job.timestamp).as_secs_f64();
+            }
+            if job.user.is_empty() {
+                job.user = user;
+            }
+            job.power = power;
+            job.timestamp = timestamp;
+        }
+        self.jobs
+            .retain(|_, job| now.saturating_sub(job.timestamp) <= RETENTION);
+        let mut result = self
+            .jobs
+            .iter()
+            .map(|(id, job)| (id.clone(), job.clone()))
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    /// Returns the time the energy counter of the job `id` of `scheduler` started at.
+    pub fn created(&self, scheduler: &str, id: &str) -> Option<Duration> {
+        self.jobs
+            .iter()
+            .find(|(job, _)| job.scheduler == scheduler && job.id == id)
+            .map(|(_, job)| job.created)
+    }
+}
+
+/// Returns the name of the user `uid`, through the name service of the host
+/// (/etc/passwd, LDAP...), or the uid if it has none.
+#[cfg(target_os = "linux")]
+fn user_name(uid: u32) -> String {
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return uid.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn user_name(uid: u32) -> String {
+    uid.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgroups(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn jobs_from_cgroups() {
+        let slurm = |id: &str| {
+            Some(JobId {
+                scheduler: "slurm",
+                id: id.to_string(),
+            })
+        };
+        let pbs = |id: &str| {
+            Some(JobId {
+                scheduler: "pbs",
+                id: id.to_string(),
+            })
+        };
+        assert_eq!(
+            job_from_cgroups(&cgroups(&["/slurm/uid_1000/job_1234/step_0/task_0"])),
+            slurm("1234")
+        );
+        assert_eq!(
+            job_from_cgroups(&cgroups(&[
+                "/system.slice/node1_slurmstepd.scope/job_42/step_batch/user/task_0"
+            ])),
+            slurm("42")
+        );
+        assert_eq!(
+            job_from_cgroups(&cgroups(&["/system.slice/slurmd.service"])),
+            None
+        );
+        assert_eq!(
+            job_from_cgroups(&cgroups(&["/", "/pbs_jobs.service/jobid/1234.server"])),
+            pbs("1234.server")
+        );
+        assert_eq!(
+            job_from_cgroups(&cgroups(&["/torque/77.head"])),
+            pbs("77.head")
+        );
+        assert_eq!(
+            job_from_cgroups(&cgroups(&["/user.slice/user-1000.slice"])),
+            None
+        );
+    }
+
+    #[test]
+    fn aggregate_by_job() {
+        let s = Duration::from_secs;
+        let job = JobId {
+            scheduler: "slurm",
+            id: String::from("1234"),
+        };
+        let mut jobs = Jobs::default();
+        jobs.user_names.insert(1000, String::from("alice"));
+        jobs.add_process_power(&job, Some(0), 1e6, s(100));
+        jobs.add_process_power(&job, Some(1000), 2e6, s(100));
+        let result = jobs.flush(s(100));
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].1.user, "alice");
+        assert_eq!(result[0].1.power, 3e6);
+        jobs.add_process_power(&job, Some(1000), 4e6, s(110));
+        assert_eq!(jobs.flush(s(110))[0].1.energy, 4e7);
+        assert_eq!(jobs.created("slurm", "1234"), Some(s(100)));
+        // the job ended: its energy stays published for RETENTION
+        let result = jobs.flush(s(120));
+        assert_eq!((result[0].1.power, result[0].1.energy), (0.0, 4e7));
+        assert!(jobs.flush(s(110) + RETENTION + s(1)).is_empty());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -1,12 +1,13 @@
+use crate::exporters::utils::{EnergyTotal, StepArgs, TimestampFormat, TimestampZone};
 use crate::exporters::*;
-use crate::sensors::Sensor;
+use crate::sensors::units::{self, Measure, NumberFormat, Unit};
+use crate::sensors::{utils::IProcess, Sensor};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
     io::{BufWriter, Write},
     path::{Path, PathBuf},
-    thread,
     time::{Duration, Instant},
 };
 
@@ -19,10 +20,22 @@ pub struct JsonExporter {
     time_limit: Option<Duration>,
     max_top_consumers: u16,
     out_writer: BufWriter<Box<dyn Write>>,
-    process_regex: Option<Regex>,
-    container_regex: Option<Regex>,
+    /// True if processes are filtered with --process-regex or --container-regex,
+    /// in which case all the tracked processes are reported
+    filter_processes: bool,
     monitor_resources: bool,
     watch_containers: bool,
+    align_timestamps: bool,
+    power_unit: Unit,
+    /// Number of decimals of the human readable consumptions, if --human is set
+    human_precision: Option<usize>,
+    timestamp_format: TimestampFormat,
+    timezone: TimestampZone,
+    /// Energy consumed by the host during the run
+    energy: EnergyTotal,
+    max_energy_joules: Option<f64>,
+    max_average_power_watts: Option<f64>,
+    summary_file: Option<String>,
 }
 
 // Note: clap::Args automatically generate Args for the fields of this struct,
@@ -73,40 +86,161 @@ pub struct ExporterArgs {
     /// Monitor and incude CPU, RAM and Disk usage per process
     #[arg(long)]
     pub resources: bool,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
+
+    /// Unit of the power consumptions in the report: MW, kW, W, mW or uW
+    #[arg(long, value_name = "UNIT", default_value = "uW", value_parser = units::parse_power_unit)]
+    pub power_unit: Unit,
+
+    /// Add the consumptions in their most readable unit (e.g. "35.71 W") as consumption_human
+    /// fields, next to the numeric ones
+    #[arg(long)]
+    pub human: bool,
+
+    /// Number of decimals of the consumption_human fields (2 by default)
+    #[arg(long, value_name = "DECIMALS", requires = "human")]
+    pub precision: Option<usize>,
+
+    /// Format of the timestamps in the report: seconds since the epoch, or RFC 3339 dates and
+    /// times (e.g. 2023-11-14T22:13:20.500Z) that other logs can be merged with
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = TimestampFormat::Epoch)]
+    pub timestamp_format: TimestampFormat,
+
+    /// Time zone of the RFC 3339 timestamps: UTC, local (the one of the host) or an offset
+    /// such as +02:00
+    #[arg(long, value_name = "ZONE", default_value = "UTC", value_parser = utils::parse_timestamp_zone)]
+    pub timezone: TimestampZone,
+
+    /// Exit with the code 3 if the host consumed more than JOULES during the run,
+    /// so that CI jobs fail on energy regressions
+    #[arg(long, value_name = "JOULES", requires = "timeout")]
+    pub fail_if_energy_above: Option<f64>,
+
+    /// Exit with the code 3 if the average power of the host during the run was
+    /// above WATTS
+    #[arg(long, value_name = "WATTS", requires = "timeout")]
+    pub fail_if_avg_power_above: Option<f64>,
+
+    /// Write a summary of the run (energy, average power, thresholds) as JSON to this file
+    /// once the timeout is reached
+    #[arg(long, value_name = "PATH", requires = "timeout")]
+    pub summary_file: Option<String>,
     // TODO uncomment this option once we display something interesting about it
     // /// Apply labels to metrics of processes looking like a Qemu/KVM virtual machine
     // #[arg(short, long)]
     // pub qemu: bool
 }
 
+/// Version of the report format, published as a JSON Schema in
+/// docs_src/references/exporter-json-report-v1.schema.json. It must be
+/// incremented, along with a new schema file, whenever a field of the report
+/// is renamed or removed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Exit code of the JSON exporter when the run exceeded one of the
+/// --fail-if-* thresholds. It differs from the code of errors (1), of invalid
+/// arguments (2) and of panics (101), so that CI jobs can tell them apart.
+pub const THRESHOLD_EXIT_CODE: i32 = 3;
+
+/// Summary of a run with a timeout, written to --summary-file and checked
+/// against the --fail-if-* thresholds.
+#[derive(Serialize, Debug)]
+pub struct RunSummary {
+    duration_seconds: f64,
+    measurements: u64,
+    energy_joules: f64,
+    average_power_watts: Option<f64>,
+    max_energy_joules: Option<f64>,
+    max_average_power_watts: Option<f64>,
+    /// Thresholds that were exceeded, or "no_measurement" if thresholds were
+    /// given but the run was too short to measure any energy
+    exceeded: Vec<&'static str>,
+    passed: bool,
+}
+
+impl RunSummary {
+    fn new(
+        energy: &EnergyTotal,
+        max_energy_joules: Option<f64>,
+        max_average_power_watts: Option<f64>,
+    ) -> RunSummary {
+        let energy_joules = energy.microjoules / 1_000_000.0;
+        let average_power_watts = energy.average_microwatts().map(|uw| uw / 1_000_000.0);
+        let mut exceeded = vec![];
+        if max_energy_joules.is_some() || max_average_power_watts.is_some() {
+            match average_power_watts {
+                Some(average) => {
+                    if max_energy_joules.is_some_and(|max| energy_joules > max) {
+                        exceeded.push("energy");
+                    }
+                    if max_average_power_watts.is_some_and(|max| average > max) {
+                        exceeded.push("average_power");
+                    }
+                }
+                None => exceeded.push("no_measurement"),
+            }
+        }
+        RunSummary {
+            duration_seconds: energy.span().as_secs_f64(),
+            measurements: energy.measurements,
+            energy_joules,
+            average_power_watts,
+            max_energy_joules,
+            max_average_power_watts,
+            passed: exceeded.is_empty(),
+            exceeded,
+        }
+    }
+}
+
 // Below are the structures that will store the reports.
 
-#[derive(Serialize, Deserialize)]
+/// Time of a measurement: seconds since the epoch, or an RFC 3339 date and
+/// time with --timestamp-format rfc3339.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum Timestamp {
+    Epoch(f64),
+    Rfc3339(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Domain {
     name: String,
     consumption: f32,
-    timestamp: f64,
+    /// Consumption in its most readable unit, with --human
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumption_human: Option<String>,
+    timestamp: Timestamp,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Socket {
     id: u16,
     consumption: f32,
+    /// Consumption in its most readable unit, with --human
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumption_human: Option<String>,
     domains: Vec<Domain>,
-    timestamp: f64,
+    timestamp: Timestamp,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Consumer {
     exe: PathBuf,
     cmdline: String,
     pid: i32,
     resources_usage: Option<ResourcesUsage>,
     consumption: f32,
-    timestamp: f64,
+    /// Consumption in its most readable unit, with --human
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumption_human: Option<String>,
+    timestamp: Timestamp,
     container: Option<Container>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct ResourcesUsage {
     cpu_usage: String,
     cpu_usage_unit: String,
@@ -120,14 +254,14 @@ struct ResourcesUsage {
     disk_usage_read_unit: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Container {
     name: String,
     id: String,
     runtime: String,
     scheduler: String,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Disk {
     disk_type: String,
     disk_mount_point: String,
@@ -137,18 +271,44 @@ struct Disk {
     disk_available_bytes: String,
     disk_name: String,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Memory {
+    memory_total_bytes: String,
+    memory_available_bytes: String,
+    memory_free_bytes: String,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Temperature {
+    component_label: String,
+    temperature_celsius: String,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Fan {
+    fan_label: String,
+    fan_speed_rpm: String,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Components {
     disks: Option<Vec<Disk>>,
+    memory: Option<Memory>,
+    temperatures: Option<Vec<Temperature>>,
+    fans: Option<Vec<Fan>>,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Host {
     consumption: f32,
-    timestamp: f64,
+    /// Consumption in its most readable unit, with --human
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    consumption_human: Option<String>,
+    timestamp: Timestamp,
     components: Components,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct Report {
+    schema_version: u32,
+    /// Unit of the consumptions, microwatts if absent (reports written before it was added)
+    #[serde(default)]
+    power_unit: Option<String>,
     host: Host,
     consumers: Vec<Consumer>,
     sockets: Vec<Socket>,
@@ -164,12 +324,13 @@ impl Exporter for JsonExporter {
             let t0 = Instant::now();
             while t0.elapsed() <= timeout {
                 self.iterate();
-                thread::sleep(self.time_step);
+                utils::wait_for_next_step(self.time_step, self.align_timestamps);
             }
+            self.finish();
         } else {
             loop {
                 self.iterate();
-                thread::sleep(self.time_step);
+                utils::wait_for_next_step(self.time_step, self.align_timestamps);
             }
         }
     }
@@ -186,8 +347,28 @@ impl JsonExporter {
         let topo = sensor
             .get_topology()
             .expect("sensor topology should be available");
-        let metric_generator =
+        let mut metric_generator =
             MetricGenerator::new(topo, utils::get_hostname(), false, args.containers);
+        // Filtered out processes are not even tracked, see ProcessFilters
+        #[cfg(feature = "containers")]
+        let filter_processes = args.process_regex.is_some() || args.container_regex.is_some();
+        #[cfg(not(feature = "containers"))]
+        let filter_processes = args.process_regex.is_some();
+        let filters = &mut metric_generator.topology.proc_tracker.filters;
+        if let Some(regex) = args.process_regex {
+            if filters.include.is_some() {
+                warn!("--process-regex replaces the regular expression given with --include-processes");
+            }
+            filters.include = Some(regex);
+        }
+        #[cfg(feature = "containers")]
+        {
+            filters.container = args.container_regex;
+        }
+        #[cfg(not(feature = "containers"))]
+        if args.container_regex.is_some() {
+            warn!("--container-regex needs scaphandre to be built with the containers feature");
+        }
 
         // Extract the parameters we need to run the exporter
         let time_step = Duration::new(args.step, args.step_nano);
@@ -198,8 +379,6 @@ impl JsonExporter {
             time_limit = None
         };
         let max_top_consumers = args.max_top_consumers;
-        let process_regex = args.process_regex;
-        let container_regex = args.container_regex;
         let monitor_resources = args.resources;
 
         // Prepare the output (either stdout or a file)
@@ -217,18 +396,84 @@ impl JsonExporter {
             time_limit,
             max_top_consumers,
             out_writer,
-            process_regex,
-            container_regex,
+            filter_processes,
             monitor_resources,
             watch_containers: args.containers,
+            align_timestamps: args.step_args.align_timestamps,
+            power_unit: args.power_unit,
+            human_precision: args
+                .human
+                .then(|| args.precision.unwrap_or(units::HUMAN_PRECISION)),
+            timestamp_format: args.timestamp_format,
+            timezone: args.timezone,
+            energy: EnergyTotal::default(),
+            max_energy_joules: args.fail_if_energy_above,
+            max_average_power_watts: args.fail_if_avg_power_above,
+            summary_file: args.summary_file,
+        }
+    }
+
+    /// Ends a run with a timeout: writes the summary of the run if asked to, and exits
+    /// with [THRESHOLD_EXIT_CODE] if it exceeded one of the thresholds.
+    fn finish(&mut self) {
+        if let Err(e) = self.out_writer.flush() {
+            warn!("Failed to flush the reports: {e}");
+        }
+        let summary = RunSummary::new(
+            &self.energy,
+            self.max_energy_joules,
+            self.max_average_power_watts,
+        );
+        if let Some(path) = &self.summary_file {
+            let json = serde_json::to_string_pretty(&summary)
+                .expect("summary should be serializable to JSON");
+            if let Err(e) = std::fs::write(path, json + "\n") {
+                eprintln!("Error: failed to write the summary to {path}: {e}");
+                std::process::exit(1);
+            }
+        }
+        if !summary.passed {
+            eprintln!(
+                "Thresholds exceeded ({}): {:.3} J consumed, {} on average",
+                summary.exceeded.join(", "),
+                summary.energy_joules,
+                summary
+                    .average_power_watts
+                    .map_or(String::from("no power measured"), |w| format!("{w:.3} W"))
+            );
+            std::process::exit(THRESHOLD_EXIT_CODE);
+        }
+    }
+
+    /// Converts a power consumption from microwatts to the unit given by --power-unit.
+    fn convert_power(&self, microwatts: f32) -> f32 {
+        Unit::to(microwatts as f64, &Unit::MicroWatt, &self.power_unit)
+            .expect("power unit should be a unit of power") as f32
+    }
+
+    /// Formats a power consumption given in microwatts in its most readable unit, if --human is set.
+    fn human_power(&self, microwatts: f32) -> Option<String> {
+        self.human_precision.map(|precision| {
+            Measure::new(microwatts as f64, Unit::MicroWatt)
+                .human(Some(precision), &NumberFormat::default())
+        })
+    }
+
+    /// Writes the time of a measurement in the format given by --timestamp-format.
+    fn timestamp(&self, timestamp: Duration) -> Timestamp {
+        match self.timestamp_format {
+            TimestampFormat::Epoch => Timestamp::Epoch(timestamp.as_secs_f64()),
+            TimestampFormat::Rfc3339 => {
+                Timestamp::Rfc3339(utils::format_rfc3339(timestamp, self.timezone))
+            }
         }
     }
 
     fn gen_disks_report(&self, metrics: &Vec<&Metric>) -> Vec<Disk> {
         let mut res: Vec<Disk> = vec![];
         for m in metrics {
-            let metric_disk_name = m.attributes.get("disk_name").unwrap();
-            if let Some(disk) = res.iter_mut().find(|x| metric_disk_name == &x.disk_name) {
+            let metric_disk_name = m.attributes.get("disk_name").unwrap().to_string();
+            if let Some(disk) = res.iter_mut().find(|x| metric_disk_name == x.disk_name) {
                 info!("editing disk");
                 disk.disk_name = metric_disk_name.clone();
                 if m.name == "scaph_host_disk_available_bytes" {
@@ -250,24 +495,20 @@ impl JsonExporter {
                     disk_file_system: {
                         if let Some(metric_disk_file_system) = m.attributes.get("disk_file_system")
                         {
-                            metric_disk_file_system.clone()
+                            metric_disk_file_system.to_string()
                         } else {
                             String::from("")
                         }
                     },
-                    disk_is_removable: {
-                        if let Some(metric_disk_is_removable) =
-                            m.attributes.get("disk_is_removable")
-                        {
-                            metric_disk_is_removable.parse::<bool>().unwrap()
-                        } else {
-                            false
-                        }
-                    },
+                    disk_is_removable: m
+                        .attributes
+                        .get("disk_is_removable")
+                        .and_then(AttributeValue::as_bool)
+                        .unwrap_or(false),
                     disk_mount_point: {
                         if let Some(metric_disk_mount_point) = m.attributes.get("disk_mount_point")
                         {
-                            metric_disk_mount_point.clone()
+                            metric_disk_mount_point.to_string()
                         } else {
                             String::from("")
                         }
@@ -281,7 +522,7 @@ impl JsonExporter {
                     },
                     disk_type: {
                         if let Some(metric_disk_type) = m.attributes.get("disk_type") {
-                            metric_disk_type.clone()
+                            metric_disk_type.to_string()
                         } else {
                             String::from("")
                         }
@@ -292,6 +533,21 @@ impl JsonExporter {
         res
     }
 
+    fn gen_memory_report(&self, metrics: &[Metric]) -> Memory {
+        let value_of = |name: &str| {
+            metrics
+                .iter()
+                .find(|x| x.name == name)
+                .map(|x| x.metric_value.to_string())
+                .unwrap_or_default()
+        };
+        Memory {
+            memory_total_bytes: value_of("scaph_host_memory_total_bytes"),
+            memory_available_bytes: value_of("scaph_host_memory_available_bytes"),
+            memory_free_bytes: value_of("scaph_host_memory_free_bytes"),
+        }
+    }
+
     fn iterate(&mut self) {
         self.metric_generator.topology.refresh();
         self.retrieve_metrics();
@@ -301,12 +557,15 @@ impl JsonExporter {
         self.metric_generator.gen_all_metrics();
 
         let metrics = self.metric_generator.pop_metrics();
-        let mut metrics_iter = metrics.iter();
-        let socket_metrics_res = metrics_iter.find(|x| x.name == "scaph_socket_power_microwatts");
-        //TODO: fix for multiple sockets
+        // empty on hosts without sockets, measured at host level only
+        let socket_metrics: Vec<&Metric> = metrics
+            .iter()
+            .filter(|x| x.name == "scaph_socket_power_microwatts")
+            .collect();
         let mut host_report: Option<Host> = None;
         let disks = self.gen_disks_report(
-            &metrics_iter
+            &metrics
+                .iter()
                 .filter(|x| x.name.starts_with("scaph_host_disk_"))
                 .collect(),
         );
@@ -317,10 +576,17 @@ impl JsonExporter {
             let host_power_string = format!("{}", host_metric.metric_value);
             let host_power_f32 = host_power_string.parse::<f32>().unwrap();
             if host_power_f32 > 0.0 {
+                self.energy.add(host_power_f32 as f64);
                 host_report = Some(Host {
-                    consumption: host_power_f32,
-                    timestamp: host_metric.timestamp.as_secs_f64(),
-                    components: Components { disks: None },
+                    consumption: self.convert_power(host_power_f32),
+                    consumption_human: self.human_power(host_power_f32),
+                    timestamp: self.timestamp(host_metric.timestamp),
+                    components: Components {
+                        disks: None,
+                        memory: None,
+                        temperatures: None,
+                        fans: None,
+                    },
                 });
             }
         } else {
@@ -330,30 +596,35 @@ impl JsonExporter {
 
         if let Some(host) = &mut host_report {
             host.components.disks = Some(disks);
+            host.components.memory = Some(self.gen_memory_report(&metrics));
+            host.components.temperatures = Some(
+                metrics
+                    .iter()
+                    .filter(|x| x.name == "scaph_host_component_temperature_celsius")
+                    .map(|x| Temperature {
+                        component_label: x.attributes.get("component_label").unwrap().to_string(),
+                        temperature_celsius: x.metric_value.to_string(),
+                    })
+                    .collect(),
+            );
+            host.components.fans = Some(
+                metrics
+                    .iter()
+                    .filter(|x| x.name == "scaph_host_fan_speed_rpm")
+                    .map(|x| Fan {
+                        fan_label: x.attributes.get("fan_label").unwrap().to_string(),
+                        fan_speed_rpm: x.metric_value.to_string(),
+                    })
+                    .collect(),
+            );
         }
 
         let max_top = self.max_top_consumers;
-        let consumers: Vec<(IProcess, f64)> = if let Some(regex_filter) = &self.process_regex {
-            debug!("Processes filtered by '{}':", regex_filter.as_str());
+        let consumers: Vec<(IProcess, f64)> = if self.filter_processes {
             self.metric_generator
                 .topology
                 .proc_tracker
-                .get_filtered_processes(regex_filter)
-        } else if let Some(regex_filter) = &self.container_regex {
-            debug!("Processes filtered by '{}':", regex_filter.as_str());
-            #[cfg(feature = "containers")]
-            {
-                self.metric_generator
-                    .get_processes_filtered_by_container_name(regex_filter)
-            }
-
-            #[cfg(not(feature = "containers"))]
-            {
-                self.metric_generator
-                    .topology
-                    .proc_tracker
-                    .get_top_consumers(max_top)
-            }
+                .get_top_consumers(u16::MAX)
         } else {
             self.metric_generator
                 .topology
@@ -368,39 +639,41 @@ impl JsonExporter {
                     .iter()
                     .find(|x| {
                         x.name == "scaph_process_power_consumption_microwatts"
-                            && &process.pid.to_string() == x.attributes.get("pid").unwrap()
+                            && x.attributes.get("pid").unwrap() == &process.pid.to_string()
                     })
-                    .map(|metric| Consumer {
-                        exe: PathBuf::from(metric.attributes.get("exe").unwrap()),
-                        cmdline: metric.attributes.get("cmdline").unwrap().clone(),
+                    .map(|metric| {
+                        let microwatts = format!("{}", metric.metric_value).parse::<f32>().unwrap();
+                        (metric, microwatts)
+                    })
+                    .map(|(metric, microwatts)| Consumer {
+                        exe: PathBuf::from(metric.attributes.get("exe").unwrap().to_string()),
+                        cmdline: metric.attributes.get("cmdline").unwrap().to_string(),
                         pid: process.pid.to_string().parse::<i32>().unwrap(),
-                        consumption: format!("{}", metric.metric_value).parse::<f32>().unwrap(),
+                        consumption: self.convert_power(microwatts),
+                        consumption_human: self.human_power(microwatts),
                         resources_usage: None,
-                        timestamp: metric.timestamp.as_secs_f64(),
+                        timestamp: self.timestamp(metric.timestamp),
                         container: if self.watch_containers {
                             metric
                                 .attributes
                                 .get("container_id")
                                 .map(|container_id| Container {
-                                    id: String::from(container_id),
-                                    name: String::from(
-                                        metric
-                                            .attributes
-                                            .get("container_names")
-                                            .unwrap_or(&String::from("unknown")),
-                                    ),
-                                    runtime: String::from(
-                                        metric
-                                            .attributes
-                                            .get("container_runtime")
-                                            .unwrap_or(&String::from("unknown")),
-                                    ),
-                                    scheduler: String::from(
-                                        metric
-                                            .attributes
-                                            .get("container_scheduler")
-                                            .unwrap_or(&String::from("unknown")),
-                                    ),
+                                    id: container_id.to_string(),
+                                    name: metric
+                                        .attributes
+                                        .get("container_names")
+                                        .map(ToString::to_string)
+                                        .unwrap_or_else(|| String::from("unknown")),
+                                    runtime: metric
+                                        .attributes
+                                        .get("container_runtime")
+                                        .map(ToString::to_string)
+                                        .unwrap_or_else(|| String::from("unknown")),
+                                    scheduler: metric
+                                        .attributes
+                                        .get("container_scheduler")
+                                        .map(ToString::to_string)
+                                        .unwrap_or_else(|| String::from("unknown")),
                                 })
                         } else {
                             None
@@ -462,13 +735,8 @@ impl JsonExporter {
             .get_sockets_passive()
             .iter()
             .filter_map(|socket| {
-                if let Some(metric) = socket_metrics_res.iter().find(|x| {
-                    socket.id
-                        == x.attributes
-                            .get("socket_id")
-                            .unwrap()
-                            .parse::<u16>()
-                            .unwrap()
+                if let Some(metric) = socket_metrics.iter().find(|x| {
+                    x.attributes.get("socket_id").unwrap().as_i64() == Some(socket.id.into())
                 }) {
                     let socket_power = format!("{}", metric.metric_value).parse::<f32>().unwrap();
 
@@ -476,25 +744,27 @@ impl JsonExporter {
                         .iter()
                         .filter(|x| {
                             x.name == "scaph_domain_power_microwatts"
-                                && x.attributes
-                                    .get("socket_id")
-                                    .unwrap()
-                                    .parse::<u16>()
-                                    .unwrap()
-                                    == socket.id
+                                && x.attributes.get("socket_id").unwrap().as_i64()
+                                    == Some(socket.id.into())
+                        })
+                        .map(|d| {
+                            let microwatts = format!("{}", d.metric_value).parse::<f32>().unwrap();
+                            (d, microwatts)
                         })
-                        .map(|d| Domain {
-                            name: d.attributes.get("domain_name").unwrap().clone(),
-                            consumption: format!("{}", d.metric_value).parse::<f32>().unwrap(),
-                            timestamp: d.timestamp.as_secs_f64(),
+                        .map(|(d, microwatts)| Domain {
+                            name: d.attributes.get("domain_name").unwrap().to_string(),
+                            consumption: self.convert_power(microwatts),
+                            consumption_human: self.human_power(microwatts),
+                            timestamp: self.timestamp(d.timestamp),
                         })
                         .collect::<Vec<_>>();
 
                     Some(Socket {
                         id: socket.id,
-                        consumption: socket_power,
+                        consumption: self.convert_power(socket_power),
+                        consumption_human: self.human_power(socket_power),
                         domains,
-                        timestamp: metric.timestamp.as_secs_f64(),
+                        timestamp: self.timestamp(metric.timestamp),
                     })
                 } else {
                     None
@@ -505,6 +775,8 @@ impl JsonExporter {
         match host_report {
             Some(host) => {
                 let report = Report {
+                    schema_version: SCHEMA_VERSION,
+                    power_unit: Some(String::from(self.power_unit.symbol())),
                     host,
                     consumers: top_consumers,
                     sockets: all_sockets,
@@ -523,8 +795,306 @@ impl JsonExporter {
 
 #[cfg(test)]
 mod tests {
-    //#[test]
-    //fn get_cons_socket0() {}
+    use super::*;
+    use serde_json::Value;
+
+    const SCHEMA: &str =
+        include_str!("../../docs_src/references/exporter-json-report-v1.schema.json");
+
+    fn sample_report(with_options: bool) -> Report {
+        let timestamp = || match with_options {
+            true => Timestamp::Rfc3339(String::from("2023-11-14T22:13:20.500Z")),
+            false => Timestamp::Epoch(1700000000.5),
+        };
+        Report {
+            schema_version: SCHEMA_VERSION,
+            power_unit: with_options.then(|| String::from("uW")),
+            host: Host {
+                consumption: 12345678.0,
+                consumption_human: with_options.then(|| String::from("12.35 W")),
+                timestamp: timestamp(),
+                components: Components {
+                    disks: with_options.then(|| {
+                        vec![Disk {
+                            disk_type: String::from("SSD"),
+                            disk_mount_point: String::from("/"),
+                            disk_is_removable: false,
+                            disk_file_system: String::from("ext4"),
+                            disk_total_bytes: String::from("512110190592"),
+                            disk_available_bytes: String::from("112110190592"),
+                            disk_name: String::from("/dev/nvme0n1p2"),
+                        }]
+                    }),
+                    memory: with_options.then(|| Memory {
+                        memory_total_bytes: String::from("16595976192"),
+                        memory_available_bytes: String::from("9834725376"),
+                        memory_free_bytes: String::from("3217092608"),
+                    }),
+                    temperatures: with_options.then(|| {
+                        vec![Temperature {
+                            component_label: String::from("coretemp Package id 0"),
+                            temperature_celsius: String::from("54"),
+                        }]
+                    }),
+                    fans: with_options.then(|| {
+                        vec![Fan {
+                            fan_label: String::from("thinkpad/fan1"),
+                            fan_speed_rpm: String::from("2900"),
+                        }]
+                    }),
+                },
+            },
+            consumers: vec![Consumer {
+                exe: PathBuf::from("/usr/bin/postgres"),
+                cmdline: String::from("/usr/bin/postgres-D/var/lib/postgresql"),
+                pid: 1234,
+                resources_usage: with_options.then(|| ResourcesUsage {
+                    cpu_usage: String::from("3.5"),
+                    cpu_usage_unit: String::from("%"),
+                    memory_usage: String::from("104857600"),
+                    memory_usage_unit: String::from("Bytes"),
+                    memory_virtual_usage: String::from("209715200"),
+                    memory_virtual_usage_unit: String::from("Bytes"),
+                    disk_usage_write: String::from("4096"),
+                    disk_usage_write_unit: String::from("Bytes"),
+                    disk_usage_read: String::from("8192"),
+                    disk_usage_read_unit: String::from("Bytes"),
+                }),
+                consumption: 456789.0,
+                consumption_human: with_options.then(|| String::from("456.79 mW")),
+                timestamp: timestamp(),
+                container: with_options.then(|| Container {
+                    name: String::from("db"),
+                    id: String::from("0123456789ab"),
+                    runtime: String::from("docker"),
+                    scheduler: String::from("docker"),
+                }),
+            }],
+            sockets: vec![Socket {
+                id: 0,
+                consumption: 10000000.0,
+                consumption_human: with_options.then(|| String::from("10.00 W")),
+                domains: vec![Domain {
+                    name: String::from("dram"),
+                    consumption: 1500000.0,
+                    consumption_human: with_options.then(|| String::from("1.50 W")),
+                    timestamp: timestamp(),
+                }],
+                timestamp: timestamp(),
+            }],
+        }
+    }
+
+    /// Checks `value` against the subset of JSON Schema used by the report schema,
+    /// returning the path of the first mismatch.
+    fn validate(value: &Value, schema: &Value, root: &Value, path: &str) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/$defs/");
+            return validate(value, &root["$defs"][name], root, path);
+        }
+        if let Some(alternatives) = schema["oneOf"].as_array() {
+            return match alternatives
+                .iter()
+                .filter(|s| validate(value, s, root, path).is_ok())
+                .count()
+            {
+                1 => Ok(()),
+                _ => Err(format!("{path}: should match exactly one alternative")),
+            };
+        }
+        if !schema["const"].is_null() && value != &schema["const"] {
+            return Err(format!("{path}: should be {}", schema["const"]));
+        }
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(t) => t.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        let type_ok = types.iter().any(|t| match *t {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => false,
+        });
+        if !types.is_empty() && !type_ok {
+            return Err(format!("{path}: should be of type {}", schema["type"]));
+        }
+        if let Some(object) = value.as_object() {
+            for required in schema["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap();
+                if !object.contains_key(required) {
+                    return Err(format!("{path}: missing field {required}"));
+                }
+            }
+            for (key, field) in object {
+                match schema["properties"].get(key) {
+                    Some(field_schema) => {
+                        validate(field, field_schema, root, &format!("{path}.{key}"))?
+                    }
+                    None => return Err(format!("{path}: field {key} is not in the schema")),
+                }
+            }
+        }
+        if let Some(items) = value.as_array() {
+            for (i, item) in items.iter().enumerate() {
+                validate(item, &schema["items"], root, &format!("{path}[{i}]"))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn report_round_trip() {
+        for with_options in [true, false] {
+            let report = sample_report(with_options);
+            let json = serde_json::to_string(&report).unwrap();
+            let parsed: Report = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, report);
+        }
+    }
+
+    #[test]
+    fn report_matches_schema() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        for with_options in [true, false] {
+            let report = serde_json::to_value(sample_report(with_options)).unwrap();
+            validate(&report, &schema, &schema, "report").unwrap();
+        }
+    }
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ExporterArgs,
+    }
+
+    fn exporter(options: &[&str]) -> JsonExporter {
+        use crate::sensors::{StaticSensor, Topology};
+        use clap::Parser;
+        use std::collections::HashMap;
+
+        let args = Cli::parse_from([&["json"], options].concat()).args;
+        JsonExporter::new(&StaticSensor(Topology::new(HashMap::new())), args)
+    }
+
+    #[test]
+    fn process_regex_filters_tracked_processes() {
+        use sysinfo::{ProcessExt, SystemExt};
+        let exe = std::env::current_exe().unwrap();
+        let name = regex::escape(exe.file_name().unwrap().to_str().unwrap());
+        let mut json = exporter(&["--process-regex", &name]);
+        assert!(json.filter_processes);
+        let topology = &mut json.metric_generator.topology;
+        topology.refresh();
+        let tracker = &topology.proc_tracker;
+        let regex = Regex::new(&name).unwrap();
+        let pids = tracker.get_all_pids();
+        assert!(pids.contains(&sysinfo::get_current_pid().unwrap()));
+        for pid in pids {
+            let process = tracker.sysinfo.process(pid).unwrap();
+            assert!(
+                regex.is_match(process.exe().to_str().unwrap())
+                    || regex.is_match(&process.cmd().concat())
+            );
+        }
+        assert!(!exporter(&[]).filter_processes);
+    }
+
+    #[cfg(feature = "containers")]
+    #[test]
+    fn container_regex_filters_tracked_processes() {
+        let mut json = exporter(&["--container-regex", "^web"]);
+        assert!(json.filter_processes);
+        let topology = &mut json.metric_generator.topology;
+        assert!(topology.proc_tracker.filters.container.is_some());
+        // the tests don't run in a Docker container named web...
+        topology.refresh();
+        assert!(topology.proc_tracker.procs.is_empty());
+    }
+
+    #[test]
+    fn report_without_sockets() {
+        use crate::sensors::{units::Unit, Record};
+        let path =
+            std::env::temp_dir().join(format!("scaphandre-test-json-{}.json", std::process::id()));
+        let mut json = exporter(&["--file", path.to_str().unwrap()]);
+        json.metric_generator.topology.record_buffer = [1_000_000, 3_000_000]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Record::new(
+                    Duration::from_secs(1_700_000_000 + i as u64),
+                    value.to_string(),
+                    Unit::MicroJoule,
+                )
+            })
+            .collect();
+        json.retrieve_metrics();
+        json.out_writer.flush().unwrap();
+
+        let report: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report["host"]["consumption"], 2_000_000.0);
+        assert_eq!(report["sockets"], Value::Array(vec![]));
+        assert!(report["host"]["components"]["disks"].is_array());
+    }
+
+    #[test]
+    fn run_summary_thresholds() {
+        let mut energy = EnergyTotal::default();
+        assert!(RunSummary::new(&energy, None, None).passed);
+        let summary = RunSummary::new(&energy, Some(100.0), None);
+        assert_eq!(summary.exceeded, vec!["no_measurement"]);
+        assert!(!summary.passed);
+
+        // 20 W during 10 s
+        let start = Instant::now();
+        energy.add_at(start, 20_000_000.0);
+        energy.add_at(start + Duration::from_secs(10), 20_000_000.0);
+        let summary = RunSummary::new(&energy, Some(250.0), Some(25.0));
+        assert_eq!(summary.energy_joules, 200.0);
+        assert_eq!(summary.average_power_watts, Some(20.0));
+        assert!(summary.passed);
+        let summary = RunSummary::new(&energy, Some(150.0), Some(15.0));
+        assert_eq!(summary.exceeded, vec!["energy", "average_power"]);
+        assert!(!summary.passed);
+        let summary = RunSummary::new(&energy, None, Some(15.0));
+        assert_eq!(summary.exceeded, vec!["average_power"]);
+    }
+
+    #[test]
+    fn parse_v1_report() {
+        // Report written by a v1 exporter: it must keep being readable as long as
+        // SCHEMA_VERSION is 1.
+        let v1 = r#"{"schema_version":1,"host":{"consumption":9821430.0,"timestamp":1700000000.5,
+            "components":{"disks":null}},"consumers":[{"exe":"/usr/bin/firefox","cmdline":"/usr/bin/firefox",
+            "pid":4242,"resources_usage":null,"consumption":512000.0,"timestamp":1700000000.5,"container":null}],
+            "sockets":[{"id":0,"consumption":8000000.0,"domains":[{"name":"core","consumption":6000000.0,
+            "timestamp":1700000000.5}],"timestamp":1700000000.5}]}"#;
+        let report: Report = serde_json::from_str(v1).unwrap();
+        assert_eq!(report.schema_version, 1);
+        assert_eq!(report.consumers[0].pid, 4242);
+        assert_eq!(report.sockets[0].domains[0].name, "core");
+        assert_eq!(report.host.components.temperatures, None);
+        assert_eq!(report.power_unit, None);
+        assert_eq!(report.host.timestamp, Timestamp::Epoch(1700000000.5));
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        validate(
+            &serde_json::from_str(v1).unwrap(),
+            &schema,
+            &schema,
+            "report",
+        )
+        .unwrap();
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -0,0 +1,187 @@
+//! # Label groups: power and energy of pods aggregated by Kubernetes labels
+//!
+//! [LabelGroups] sums the power of the processes running in Kubernetes pods by
+//! the values of a few pod labels chosen by the user (`app`, `team`...), and
+//! integrates it into an energy counter per combination of values. This gives
+//! series that can be used for chargeback as they are, without joining the
+//! process metrics with kube-state-metrics.
+//!
+//! Pods that have none of the labels are not part of any group; a label missing
+//! from a pod that has some of the others gets an empty value.
+use super::AttributeValue;
+use crate::sensors::utils::current_system_time_since_epoch;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Prefix of the process attributes holding the labels of its pod.
+pub const POD_LABEL_PREFIX: &str = "kubernetes_pod_label_";
+
+/// Returns `label` with the characters that can't be part of a metric label
+/// name (`.`, `/`, `-`...) replaced by `_`.
+pub fn sanitize_label(label: &str) -> String {
+    label.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_")
+}
+
+/// Power and energy of a group of processes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelGroup {
+    /// Power of the group at the last measurement, in microwatts
+    pub power: f64,
+    /// Energy consumed since the group was first seen, in microjoules
+    pub energy: f64,
+    pub timestamp: Duration,
+    /// Time since the epoch the energy counter started at
+    pub created: Duration,
+}
+
+/// Aggregation of the power of processes by the values of pod labels.
+#[derive(Debug, Clone)]
+pub struct LabelGroups {
+    /// Sanitized names of the labels to aggregate by
+    pub labels: Vec<String>,
+    /// Groups by label values, in the order of `labels`
+    groups: HashMap<Vec<String>, LabelGroup>,
+    /// Power of the groups during the current measurement
+    current: HashMap<Vec<String>, (f64, Duration)>,
+}
+
+impl LabelGroups {
+    pub fn new(labels: &[String]) -> LabelGroups {
+        LabelGroups {
+            labels: labels.iter().map(|l| sanitize_label(l)).collect(),
+            groups: HashMap::new(),
+            current: HashMap::new(),
+        }
+    }
+
+    /// Adds the power of a process, given its attributes, to the group of its
+    /// pod. Processes out of any group are ignored.
+    pub fn add_process_power(
+        &mut self,
+        attributes: &HashMap<String, AttributeValue>,
+        microwatts: f64,
+        timestamp: Duration,
+    ) {
+        let values: Vec<Option<String>> = self
+            .labels
+            .iter()
+            .map(|label| {
+                attributes
+                    .get(&format!("{POD_LABEL_PREFIX}{label}"))
+                    .map(ToString::to_string)
+            })
+            .collect();
+        if values.iter().all(Option::is_none) {
+            return;
+        }
+        let key = values.into_iter().map(Option::unwrap_or_default).collect();
+        let entry = self.current.entry(key).or_insert((0.0, timestamp));
+        entry.0 += microwatts;
+        entry.1 = entry.1.max(timestamp);
+    }
+
+    /// Ends the current measurement: integrates the power of each group into
+    /// its energy, forgets the groups that have no process anymore, and returns
+    /// the groups with their label values, sorted by these values.
+    pub fn flush(&mut self) -> Vec<(Vec<(String, String)>, LabelGroup)> {
+        let current = std::mem::take(&mut self.current);
+        self.groups.retain(|key, _| current.contains_key(key));
+        let mut result = vec![];
+        for (key, (power, timestamp)) in current {
+            let group = self.groups.entry(key.clone()).or_insert(LabelGroup {
+                power,
+                energy: 0.0,
+                timestamp,
+                created: Duration::from_secs(current_system_time_since_epoch().as_secs()),
+            });
+            if timestamp > group.timestamp {
+                group.energy += power * (timestamp - group.timestamp).as_secs_f64();
+            }
+            group.power = power;
+            group.timestamp = timestamp;
+            let labels: Vec<(String, String)> = self.labels.iter().cloned().zip(key).collect();
+            result.push((labels, group.clone()));
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    /// Returns the time the energy counter of the group with these label values
+    /// started at.
+    pub fn created(&self, attributes: &HashMap<String, AttributeValue>) -> Option<Duration> {
+        let key = self
+            .labels
+            .iter()
+            .map(|label| attributes.get(label).map(ToString::to_string))
+            .collect::<Option<Vec<String>>>()?;
+        self.groups.get(&key).map(|group| group.created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(labels: &[(&str, &str)]) -> HashMap<String, AttributeValue> {
+        labels
+            .iter()
+            .map(|(k, v)| (format!("{POD_LABEL_PREFIX}{k}"), AttributeValue::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn aggregate_by_labels() {
+        let mut groups = LabelGroups::new(&[String::from("app"), String::from("team")]);
+        let web = pod(&[("app", "web"), ("team", "front")]);
+        let ts = Duration::from_secs(100);
+        groups.add_process_power(&web, 1e6, ts);
+        groups.add_process_power(&web, 2e6, ts);
+        groups.add_process_power(&pod(&[("app", "db")]), 5e6, ts);
+        groups.add_process_power(&pod(&[("tier", "1")]), 7e6, ts);
+        let result = groups.flush();
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result[0].0,
+            vec![
+                (String::from("app"), String::from("db")),
+                (String::from("team"), String::new())
+            ]
+        );
+        assert_eq!(result[1].1.power, 3e6);
+        assert_eq!(result[1].1.energy, 0.0);
+
+        groups.add_process_power(&web, 4e6, Duration::from_secs(102));
+        let result = groups.flush();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].1.energy, 8e6);
+        let mut labels = HashMap::new();
+        labels.insert(String::from("app"), AttributeValue::from("web"));
+        labels.insert(String::from("team"), AttributeValue::from("front"));
+        assert!(groups.created(&labels).is_some());
+        labels.insert(String::from("app"), AttributeValue::from("db"));
+        assert_eq!(groups.created(&labels), None);
+    }
+
+    #[test]
+    fn sanitize_label_names() {
+        assert_eq!(
+            sanitize_label("app.kubernetes.io/name"),
+            "app_kubernetes_io_name"
+        );
+        assert_eq!(sanitize_label("team"), "team");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -2,31 +2,75 @@
 //!
 //! `Exporter` is the root for all exporters. It defines the [Exporter] trait
 //! needed to implement an exporter.
+pub mod anomaly;
+pub mod collectors;
+#[cfg(any(feature = "prometheuspush", feature = "warpten", feature = "riemann"))]
+pub mod compare;
+pub mod dashboard;
+pub mod derived;
+pub mod energy_totals;
+#[cfg(target_os = "windows")]
+pub mod etw;
+pub mod forecast;
+pub mod jobs;
 #[cfg(feature = "json")]
 pub mod json;
+pub mod label_groups;
+pub mod naming;
+pub mod nic_energy;
+pub mod node_labels;
+pub mod power_budget;
+pub mod power_slope;
 #[cfg(feature = "prometheus")]
 pub mod prometheus;
 #[cfg(feature = "prometheuspush")]
 pub mod prometheuspush;
 #[cfg(target_os = "linux")]
 pub mod qemu;
+pub mod request_energy;
+pub mod restarts;
 #[cfg(feature = "riemann")]
 pub mod riemann;
+pub mod rules;
+pub mod service_mapping;
+pub mod stability;
 pub mod stdout;
 pub mod utils;
 #[cfg(feature = "warpten")]
 pub mod warpten;
+#[cfg(feature = "smartplug")]
+use crate::sensors::smartplug::SmartPlug;
 use crate::sensors::{
-    utils::{current_system_time_since_epoch, IProcess},
-    RecordGenerator, Topology,
+    cpu_support::CpuSupport,
+    diskstats::DiskIo,
+    netstats::NetIo,
+    utils::{cgroup_leaf, current_system_time_since_epoch, is_container_cgroup},
+    EnergyReading, Record, Topology,
 };
+use anomaly::AnomalyDetector;
 use chrono::Utc;
-use std::collections::HashMap;
+use derived::DerivedMetrics;
+use energy_totals::{EnergySinceStart, EnergyTotals};
+use forecast::{Horizon, PowerForecaster};
+use jobs::Jobs;
+use label_groups::LabelGroups;
+use naming::MetricNaming;
+use nic_energy::NicEnergyModel;
+use node_labels::NodeLabels;
+use power_budget::PowerBudgets;
+use power_slope::PowerSlopes;
+use request_energy::{ProcessPowerHistory, WindowEnergy};
+use restarts::RestartTracker;
+use service_mapping::ServiceMapping;
+use stability::Stability;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::time::Duration;
+use sysinfo::{Pid, PidExt};
 use utils::get_scaphandre_version;
 #[cfg(feature = "containers")]
 use {
+    crate::sensors::utils::IProcess,
     docker_sync::{container::Container, Docker},
     k8s_sync::kubernetes::Kubernetes,
     k8s_sync::Pod,
@@ -41,11 +85,19 @@ pub struct Metric {
     /// `name` is the metric name, it will be used as service field for Riemann.
     name: String, // Will be used as service for Riemann
     /// `metric_type` mostly used by Prometheus, define is it is a gauge, counter...
+    #[cfg_attr(
+        not(any(feature = "prometheus", feature = "prometheuspush")),
+        allow(dead_code)
+    )]
     metric_type: String,
     /// `ttl` time to live for this metric used by Riemann.
     #[allow(dead_code)]
     ttl: f32,
     /// `hostname` host that provides the metric.
+    #[cfg_attr(
+        not(any(feature = "prometheuspush", feature = "riemann")),
+        allow(dead_code)
+    )]
     hostname: String,
     /// `state` used by Riemann, define a state like Ok or Ko regarding this metric.
     #[allow(dead_code)]
@@ -55,7 +107,9 @@ pub struct Metric {
     tags: Vec<String>,
     /// `attributes` used by exporters to better qualify the metric. In Prometheus context
     /// this is used as a metric tag (socket_id) : `scaph_self_socket_stats_nb{socket_id="0"} 2`.
-    attributes: HashMap<String, String>,
+    /// Values are typed (see [AttributeValue]) for the backends that tell numeric
+    /// fields from text tags.
+    attributes: HashMap<String, AttributeValue>,
     /// `description` metric description and units used.
     description: String,
     /// `metric_value` the value of the metric. This is possible to pass different types using
@@ -66,6 +120,16 @@ pub struct Metric {
     timestamp: Duration,
 }
 
+impl Metric {
+    /// Returns the attributes sorted by name, for exporters to send them in the
+    /// same order at each measurement.
+    fn sorted_attributes(&self) -> Vec<(&String, &AttributeValue)> {
+        let mut attributes: Vec<_> = self.attributes.iter().collect();
+        attributes.sort_by(|a, b| a.0.cmp(b.0));
+        attributes
+    }
+}
+
 enum MetricValueType {
     // IntSigned(i64),
     // Float(f32),
@@ -98,6 +162,107 @@ impl fmt::Debug for MetricValueType {
     }
 }
 
+/// Value of a metric attribute. Exporters whose labels are text (Prometheus,
+/// Warp10, Riemann) use its [Display](fmt::Display) form, others may keep the type.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(untagged))]
+pub enum AttributeValue {
+    Text(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl AttributeValue {
+    /// Returns the value as an integer, parsing it if it is a text.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            AttributeValue::Int(value) => Some(*value),
+            AttributeValue::Text(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a boolean, parsing it if it is a text.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            AttributeValue::Bool(value) => Some(*value),
+            AttributeValue::Text(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::Text(text) => write!(f, "{text}"),
+            AttributeValue::Int(value) => write!(f, "{value}"),
+            AttributeValue::Float(value) => write!(f, "{value}"),
+            AttributeValue::Bool(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(text: String) -> Self {
+        AttributeValue::Text(text)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(text: &str) -> Self {
+        AttributeValue::Text(String::from(text))
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<u32> for AttributeValue {
+    fn from(value: u32) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        AttributeValue::Float(value)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+impl PartialEq<str> for AttributeValue {
+    fn eq(&self, other: &str) -> bool {
+        match self {
+            AttributeValue::Text(text) => text == other,
+            AttributeValue::Int(value) => other.parse() == Ok(*value),
+            AttributeValue::Float(value) => other.parse() == Ok(*value),
+            AttributeValue::Bool(value) => other.parse() == Ok(*value),
+        }
+    }
+}
+
+impl PartialEq<String> for AttributeValue {
+    fn eq(&self, other: &String) -> bool {
+        self == other.as_str()
+    }
+}
+
 /// An Exporter is what tells scaphandre when to collect metrics and how to export
 /// or expose them.
 /// Its basic role is to instanciate a Sensor, get the data the sensor has to offer
@@ -112,8 +277,31 @@ pub trait Exporter {
 
     /// The name of the kind of the exporter, for example "json".
     fn kind(&self) -> &str;
+
+    /// Checks the configuration of the exporter without running it (connectivity,
+    /// credentials...) and returns a description of what was checked.
+    fn validate(&mut self) -> Result<String, String> {
+        Ok(String::from("nothing to check"))
+    }
 }
 
+/// Time after which the wall power of an external reference meter, read from
+/// a file or pushed, isn't compared with the host power anymore.
+const REFERENCE_POWER_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Power metrics computed from the difference between two energy counters,
+/// which get `interval_seconds` when the interval attribute is enabled.
+const DELTA_POWER_METRICS: [&str; 8] = [
+    "scaph_host_power_microwatts",
+    "scaph_kernel_power_microwatts",
+    "scaph_socket_power_microwatts",
+    "scaph_domain_power_microwatts",
+    "scaph_igpu_power_microwatts",
+    "scaph_process_power_consumption_microwatts",
+    "scaph_process_domain_power_microwatts",
+    "scaph_kubernetes_group_power_microwatts",
+];
+
 /// MetricGenerator is an exporter helper structure to collect Scaphandre metrics.
 /// The goal is to provide a standard Vec\<Metric\> that can be used by exporters
 /// to avoid code duplication.
@@ -158,6 +346,64 @@ pub struct MetricGenerator {
     ///
     #[cfg(feature = "containers")]
     pods_last_check: String,
+    /// Rules applying user-defined labels to process metrics, see [service_mapping]
+    service_mapping: Option<ServiceMapping>,
+    /// File holding the wall power measured by an external meter, in watts
+    reference_power_file: Option<String>,
+    /// Last wall power pushed by an external meter, in microwatts, and when it was pushed
+    pushed_reference_power: Option<(Duration, f64)>,
+    /// Smart plug measuring the wall power of the host, used as reference meter
+    #[cfg(feature = "smartplug")]
+    reference_smartplug: Option<SmartPlug>,
+    /// Split the power of core, uncore and dram domains between processes
+    domain_attribution: bool,
+    /// Power of the storage devices in microwatts, and weight of the block I/O
+    /// wait in its split between processes
+    storage_attribution: Option<(f64, f64)>,
+    /// Energy per gigabyte of the network interfaces, if enabled
+    nic_energy_model: Option<NicEnergyModel>,
+    /// Model of the host power, to publish its forecast at given horizons
+    power_forecaster: Option<PowerForecaster>,
+    /// Detector of unusual power draws of the host and containers
+    anomaly_detector: Option<AnomalyDetector>,
+    /// Cumulative energy of the host and processes, possibly persisted
+    energy_totals: Option<EnergyTotals>,
+    /// Energy of the host since scaphandre started
+    energy_since_start: EnergySinceStart,
+    /// Power and energy of the pods aggregated by label values, if enabled
+    label_groups: Option<LabelGroups>,
+    /// Rolling average power of the pods and containers with a power budget
+    power_budgets: Option<PowerBudgets>,
+    /// Power the host should stay under, in microwatts, to publish its headroom
+    power_cap: Option<f64>,
+    /// Recent power of the host and sockets, to publish its rate of change
+    power_slopes: Option<PowerSlopes>,
+    /// Recent power of the processes, to compute the energy of their requests
+    process_power_history: Option<ProcessPowerHistory>,
+    /// Add the interval power is computed over to the power metrics
+    interval_attribute: bool,
+    /// Tell containerized processes from their cgroups on process metrics
+    cgroup_attributes: bool,
+    /// Maximum length of the cmdline attribute of process metrics, in characters
+    cmdline_max_length: usize,
+    /// Publish the metrics whose stability is experimental
+    include_experimental: bool,
+    /// Publish the context switches of each process
+    context_switches: bool,
+    /// Counts the restarts of the processes, if enabled
+    restart_tracker: Option<RestartTracker>,
+    /// Power and energy of the batch jobs, if enabled
+    jobs: Option<Jobs>,
+    /// Labels of the Kubernetes node added to all metrics, if enabled
+    node_labels: Option<NodeLabels>,
+    /// Name of the LXD container scaphandre runs in, added to all metrics
+    lxc_container: Option<String>,
+    /// Whether the CPU of the host is known to be supported, checked at startup
+    cpu_support: Option<CpuSupport>,
+    /// Metrics computed from the others, defined by the user, if any
+    derived_metrics: Option<DerivedMetrics>,
+    /// Names under which the metrics are published
+    metric_naming: MetricNaming,
 }
 
 /// This is not mandatory to use MetricGenerator methods. Exporter can use dedicated
@@ -173,6 +419,14 @@ impl MetricGenerator {
         _watch_containers: bool,
     ) -> MetricGenerator {
         let data = Vec::new();
+        let cpu_support = CpuSupport::detect();
+        if let Some(cpu_support) = &cpu_support {
+            cpu_support.log();
+        }
+        #[cfg(target_os = "linux")]
+        let lxc_container = crate::sensors::lxc::container_name();
+        #[cfg(not(target_os = "linux"))]
+        let lxc_container = None;
         #[cfg(feature = "containers")]
         {
             let containers = vec![];
@@ -218,6 +472,35 @@ impl MetricGenerator {
                 pods,
                 pods_last_check: String::from(""),
                 //kubernetes_version,
+                service_mapping: None,
+                reference_power_file: None,
+                pushed_reference_power: None,
+                #[cfg(feature = "smartplug")]
+                reference_smartplug: None,
+                domain_attribution: false,
+                storage_attribution: None,
+                nic_energy_model: None,
+                power_forecaster: None,
+                anomaly_detector: None,
+                energy_totals: None,
+                energy_since_start: EnergySinceStart::new(current_system_time_since_epoch()),
+                label_groups: None,
+                power_budgets: None,
+                power_cap: None,
+                power_slopes: None,
+                process_power_history: None,
+                interval_attribute: false,
+                cgroup_attributes: false,
+                cmdline_max_length: utils::DEFAULT_CMDLINE_MAX_LENGTH,
+                include_experimental: false,
+                context_switches: false,
+                restart_tracker: None,
+                jobs: None,
+                node_labels: None,
+                lxc_container,
+                cpu_support,
+                derived_metrics: None,
+                metric_naming: MetricNaming::Scaphandre,
             }
         }
         #[cfg(not(feature = "containers"))]
@@ -227,9 +510,227 @@ impl MetricGenerator {
             hostname,
             #[cfg(target_os = "linux")]
             qemu: _qemu,
+            service_mapping: None,
+            reference_power_file: None,
+            pushed_reference_power: None,
+            #[cfg(feature = "smartplug")]
+            reference_smartplug: None,
+            domain_attribution: false,
+            storage_attribution: None,
+            nic_energy_model: None,
+            power_forecaster: None,
+            anomaly_detector: None,
+            energy_totals: None,
+            energy_since_start: EnergySinceStart::new(current_system_time_since_epoch()),
+            label_groups: None,
+            power_budgets: None,
+            power_cap: None,
+            power_slopes: None,
+            process_power_history: None,
+            interval_attribute: false,
+            cgroup_attributes: false,
+            cmdline_max_length: utils::DEFAULT_CMDLINE_MAX_LENGTH,
+            include_experimental: false,
+            context_switches: false,
+            restart_tracker: None,
+            jobs: None,
+            node_labels: None,
+            lxc_container,
+            cpu_support,
+            derived_metrics: None,
+            metric_naming: MetricNaming::Scaphandre,
+        }
+    }
+
+    /// Loads the service mapping file at `path`, whose rules will add labels
+    /// to the metrics of the matching processes.
+    pub fn set_service_mapping(&mut self, path: &str) -> Result<(), String> {
+        self.service_mapping = Some(ServiceMapping::from_file(path)?);
+        Ok(())
+    }
+
+    /// Sets the file where an external tool writes the wall power measured by a
+    /// reference meter, to be compared with the host power computed by scaphandre.
+    pub fn set_reference_power_file(&mut self, path: &str) {
+        self.reference_power_file = Some(String::from(path));
+    }
+
+    /// Stores the wall power of the host, in watts, pushed by an external
+    /// reference meter. It is compared with the host power computed by
+    /// scaphandre until it gets older than [REFERENCE_POWER_MAX_AGE].
+    pub fn set_reference_power(&mut self, watts: f64) -> Result<(), String> {
+        if !watts.is_finite() || watts < 0.0 {
+            return Err(format!("{watts} isn't a power in watts"));
+        }
+        self.pushed_reference_power =
+            Some((current_system_time_since_epoch(), watts * 1_000_000.0));
+        Ok(())
+    }
+
+    /// Enables the `in_container` and `cgroup` attributes on process metrics,
+    /// found from the cgroups of the processes.
+    pub fn set_cgroup_attributes(&mut self, enabled: bool) {
+        self.cgroup_attributes = enabled;
+    }
+
+    /// Sets the maximum length, in characters, of the cmdline attribute of
+    /// process metrics.
+    pub fn set_cmdline_max_length(&mut self, max_length: usize) {
+        self.cmdline_max_length = max_length;
+    }
+
+    /// Publishes the metrics under the names of `naming`.
+    pub fn set_metric_naming(&mut self, naming: MetricNaming) {
+        self.metric_naming = naming;
+    }
+
+    /// Publishes the metrics whose stability is experimental, left out by default.
+    pub fn set_include_experimental(&mut self, enabled: bool) {
+        self.include_experimental = enabled;
+    }
+
+    /// Enables scaph_process_context_switches_total, read from the status of
+    /// each thread of the processes.
+    pub fn set_context_switches(&mut self, enabled: bool) {
+        self.context_switches = enabled;
+    }
+
+    /// Enables scaph_process_start_time_seconds and scaph_process_restarts_total.
+    pub fn set_process_restarts(&mut self, enabled: bool) {
+        self.restart_tracker = enabled.then(RestartTracker::default);
+    }
+
+    /// Enables scaph_job_power_microwatts and scaph_job_energy_microjoules_total.
+    pub fn set_jobs(&mut self, enabled: bool) {
+        self.jobs = enabled.then(Jobs::default);
+    }
+
+    /// Loads the derived metrics file at `path`, whose metrics will be computed
+    /// from the others at each generation.
+    pub fn set_derived_metrics(&mut self, path: &str) -> Result<(), String> {
+        self.derived_metrics = Some(DerivedMetrics::from_file(path)?);
+        Ok(())
+    }
+
+    /// Stores `value`, pushed by another program for the external operand
+    /// `name` of the derived metrics.
+    pub fn set_external_value(&mut self, name: &str, value: f64) -> Result<(), String> {
+        match &mut self.derived_metrics {
+            Some(derived_metrics) => {
+                derived_metrics.set_external_value(name, value, current_system_time_since_epoch())
+            }
+            None => Err(String::from("no derived metrics are defined")),
         }
     }
 
+    /// Adds the node labels of `allowed` (the default allow-list if empty),
+    /// read from the file at `path`, and the node name to all metrics.
+    pub fn set_node_labels(&mut self, path: &str, allowed: &[String]) {
+        self.node_labels = Some(NodeLabels::new(path, allowed));
+    }
+
+    /// Enables the `interval_seconds` attribute on the power metrics computed
+    /// from the difference of two energy counters.
+    pub fn set_interval_attribute(&mut self, enabled: bool) {
+        self.interval_attribute = enabled;
+    }
+
+    /// Enables the attribution of the core, uncore and dram domains power to
+    /// processes, published as scaph_process_domain_power_microwatts.
+    pub fn set_domain_attribution(&mut self, enabled: bool) {
+        self.domain_attribution = enabled;
+    }
+
+    /// Splits `watts`, the power of the storage devices of the host, between
+    /// processes as scaph_process_storage_power_microwatts: `iowait_weight` of
+    /// it on their block I/O wait, the rest on the bytes they read and wrote.
+    pub fn set_storage_attribution(&mut self, watts: Option<f64>, iowait_weight: f64) {
+        self.storage_attribution = watts.map(|watts| (watts * 1e6, iowait_weight));
+    }
+
+    /// Enables scaph_host_network_power_microwatts, estimated from the traffic
+    /// of the network interfaces with `model`.
+    pub fn set_nic_energy_model(&mut self, model: Option<NicEnergyModel>) {
+        self.nic_energy_model = model;
+    }
+
+    /// Enables the forecast of the host power at each of the `horizons`, published
+    /// as scaph_host_power_forecast_microwatts. No forecast is made if it is empty.
+    pub fn set_forecast_horizons(&mut self, horizons: &[Horizon]) {
+        self.power_forecaster = if horizons.is_empty() {
+            None
+        } else {
+            Some(PowerForecaster::new(horizons.to_vec()))
+        };
+    }
+
+    /// Enables the detection of anomalies in the power of the host and containers,
+    /// published as scaph_power_anomaly. When `webhook` is set, a notification
+    /// is sent to it each time an anomaly starts.
+    pub fn set_anomaly_detection(&mut self, threshold: f64, webhook: Option<String>) {
+        self.anomaly_detector = Some(AnomalyDetector::new(threshold, webhook));
+    }
+
+    /// Enables the cumulative energy counters of the host and processes. When
+    /// `state_file` is set, they are restored from it and saved to it every `save_interval`.
+    pub fn set_energy_totals(&mut self, state_file: Option<&str>, save_interval: Duration) {
+        self.energy_totals = Some(EnergyTotals::new(
+            state_file.map(String::from),
+            save_interval,
+        ));
+    }
+
+    /// Enables the power and energy of the processes in Kubernetes pods,
+    /// aggregated by the values of the pod `labels`.
+    pub fn set_label_groups(&mut self, labels: &[String]) {
+        self.label_groups = if labels.is_empty() {
+            None
+        } else {
+            Some(LabelGroups::new(labels))
+        };
+    }
+
+    /// Enables the comparison of the average power of the pods and containers
+    /// over `window` with their power budget annotation, published as
+    /// scaph_container_power_budget_violation. When `webhook` is set, a
+    /// notification is sent to it each time a violation starts.
+    pub fn set_power_budgets(&mut self, window: Duration, webhook: Option<String>) {
+        self.power_budgets = Some(PowerBudgets::new(window, webhook));
+    }
+
+    /// Sets the power the host should stay under, in watts, publishing the
+    /// difference with its power as scaph_host_power_headroom_microwatts.
+    pub fn set_power_cap(&mut self, watts: Option<f64>) {
+        self.power_cap = watts.map(|watts| watts * 1e6);
+    }
+
+    /// Publishes the rate of change of the power of the host and sockets over
+    /// `window`, as scaph_host_power_slope_microwatts_per_second and
+    /// scaph_socket_power_slope_microwatts_per_second.
+    pub fn set_power_slope_window(&mut self, window: Option<Duration>) {
+        self.power_slopes = window.map(PowerSlopes::new);
+    }
+
+    /// Keeps the power of the processes over the last `retention`, for
+    /// [MetricGenerator::process_energy].
+    pub fn set_process_power_history(&mut self, retention: Duration) {
+        self.process_power_history = Some(ProcessPowerHistory::new(retention));
+    }
+
+    /// Returns the energy consumed by the process `pid` between `start` and
+    /// `end` (times since the epoch), if the power history is enabled.
+    pub fn process_energy(&self, pid: u32, start: Duration, end: Duration) -> Option<WindowEnergy> {
+        self.process_power_history.as_ref()?.energy(pid, start, end)
+    }
+
+    /// Sets the smart plug (designated as `<kind>://<address>`) measuring the wall
+    /// power of the host, to be compared with the host power computed by scaphandre.
+    #[cfg(feature = "smartplug")]
+    pub fn set_reference_smartplug(&mut self, spec: &str) -> Result<(), String> {
+        self.reference_smartplug = Some(SmartPlug::parse(spec)?);
+        Ok(())
+    }
+
     #[cfg(feature = "containers")]
     pub fn get_processes_filtered_by_container_name(
         &self,
@@ -274,9 +775,124 @@ impl MetricGenerator {
         result
     }
 
+    /// Updates the model of the host power with `power` and generates the
+    /// forecasts at the configured horizons.
+    fn gen_forecast_metrics(&mut self, power: &Record) {
+        let forecaster = match self.power_forecaster.as_mut() {
+            Some(forecaster) => forecaster,
+            None => return,
+        };
+        match power.value.trim().parse::<f64>() {
+            Ok(microwatts) => forecaster.update(power.timestamp, microwatts),
+            Err(e) => {
+                warn!(
+                    "Couldn't parse host power {} for forecast: {}",
+                    power.value, e
+                );
+                return;
+            }
+        }
+        let forecasts: Vec<(String, f64)> = forecaster
+            .horizons
+            .iter()
+            .filter_map(|h| Some((h.label.clone(), forecaster.forecast(h.duration)?)))
+            .collect();
+        for (horizon, forecast) in forecasts {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("horizon"), horizon.into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_power_forecast_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Host power expected after the given horizon, forecast from the past measurements, in microwatts",
+                ),
+                metric_value: MetricValueType::Text((forecast as u64).to_string()),
+            });
+        }
+    }
+
+    /// Evaluates the power `microwatts` of the `name` item of `scope` (host or
+    /// container) and generates scaph_power_anomaly, notifying the webhook when
+    /// an anomaly starts.
+    fn gen_anomaly_metric(
+        &mut self,
+        scope: &str,
+        name: &str,
+        microwatts: f64,
+        timestamp: Duration,
+    ) {
+        let detector = match self.anomaly_detector.as_mut() {
+            Some(detector) => detector,
+            None => return,
+        };
+        let evaluation = match detector.evaluate(&format!("{scope}:{name}"), microwatts) {
+            Some(evaluation) => evaluation,
+            None => return,
+        };
+        if evaluation.started {
+            warn!(
+                "Power anomaly on {} {}: {} W instead of {} W (z-score {:.2})",
+                scope,
+                name,
+                microwatts / 1e6,
+                evaluation.expected / 1e6,
+                evaluation.zscore
+            );
+            #[cfg(feature = "webhook")]
+            if let Some(url) = &detector.webhook {
+                anomaly::notify(
+                    url,
+                    anomaly::notification_payload(
+                        &self.hostname,
+                        scope,
+                        name,
+                        microwatts,
+                        &evaluation,
+                        timestamp,
+                    ),
+                );
+            }
+        }
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("scope"), scope.into());
+        if !name.is_empty() {
+            attributes.insert(String::from("container_name"), name.into());
+        }
+        self.data.push(Metric {
+            name: String::from("scaph_power_anomaly"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp,
+            hostname: self.hostname.clone(),
+            state: if evaluation.anomalous {
+                String::from("warning")
+            } else {
+                String::from("ok")
+            },
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description: String::from(
+                "1 if the power of the host or container is unusually high or low compared to its recent history, 0 otherwise",
+            ),
+            metric_value: MetricValueType::Text(String::from(if evaluation.anomalous {
+                "1"
+            } else {
+                "0"
+            })),
+        });
+    }
+
     /// Generate all scaphandre internal metrics.
     fn gen_self_metrics(&mut self) {
-        let myself = IProcess::myself(self.topology.get_proc_tracker()).unwrap();
+        // scaphandre's own process is only tracked like any other one, its
+        // metrics are missing when process tracking is disabled
+        let self_pid = Pid::from_u32(std::process::id());
 
         let default_timestamp = current_system_time_since_epoch();
         self.data.push(Metric {
@@ -292,7 +908,69 @@ impl MetricGenerator {
             metric_value: MetricValueType::Text(get_scaphandre_version()),
         });
 
-        if let Some(metric_value) = self.topology.get_process_cpu_usage_percentage(myself.pid) {
+        if let Some(cpu_support) = &self.cpu_support {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("vendor"), cpu_support.vendor.as_str().into());
+            attributes.insert(String::from("family"), cpu_support.family.into());
+            attributes.insert(String::from("model"), cpu_support.model.into());
+            attributes.insert(
+                String::from("model_name"),
+                cpu_support.model_name.as_str().into(),
+            );
+            attributes.insert(String::from("reason"), cpu_support.reason.as_str().into());
+            self.data.push(Metric {
+                name: String::from("scaph_cpu_supported"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                timestamp: default_timestamp,
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "1 if the CPU is known to be supported by scaphandre, 0 otherwise, with the reason.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(cpu_support.supported as u64),
+            });
+        }
+
+        for warning in &self.topology.warnings {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("type"), warning.name().into());
+            self.data.push(Metric {
+                name: String::from("scaph_topology_warnings"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                timestamp: default_timestamp,
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "1 for each degraded mode the sensor fell into while discovering the host.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(1),
+            });
+        }
+
+        if let Some(start_time) = self.topology.proc_tracker.get_process_start_time(self_pid) {
+            self.data.push(Metric {
+                name: String::from("scaph_self_start_time_seconds"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                timestamp: default_timestamp,
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Time scaphandre started at, in seconds since the epoch.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(start_time),
+            });
+        }
+
+        if let Some(metric_value) = self.topology.get_process_cpu_usage_percentage(self_pid) {
             self.data.push(Metric {
                 name: String::from("scaph_self_cpu_usage_percent"),
                 metric_type: String::from("gauge"),
@@ -307,7 +985,7 @@ impl MetricGenerator {
             });
         }
 
-        if let Some(metric_value) = self.topology.get_process_memory_virtual_bytes(myself.pid) {
+        if let Some(metric_value) = self.topology.get_process_memory_virtual_bytes(self_pid) {
             self.data.push(Metric {
                 name: String::from("scaph_self_memory_virtual_bytes"),
                 metric_type: String::from("gauge"),
@@ -324,7 +1002,7 @@ impl MetricGenerator {
             });
         }
 
-        if let Some(metric_value) = self.topology.get_process_memory_bytes(myself.pid) {
+        if let Some(metric_value) = self.topology.get_process_memory_bytes(self_pid) {
             self.data.push(Metric {
                 name: String::from("scaph_self_memory_bytes"),
                 metric_type: String::from("gauge"),
@@ -341,6 +1019,29 @@ impl MetricGenerator {
             });
         }
 
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        if let Some((profile, allowed)) = crate::seccomp::applied() {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("profile"), profile.into());
+            self.data.push(Metric {
+                name: String::from("scaph_self_seccomp_allowed_syscalls"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                timestamp: default_timestamp,
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Number of system calls allowed by the seccomp profile applied to scaphandre.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(allowed as u64),
+            });
+        }
+
         let topo_stat_buffer_len = self.topology.stat_buffer.len();
         let topo_record_buffer_len = self.topology.record_buffer.len();
         let topo_procs_len = self.topology.proc_tracker.procs.len();
@@ -384,9 +1085,41 @@ impl MetricGenerator {
             metric_value: MetricValueType::IntUnsigned(topo_procs_len as u64),
         });
 
+        self.data.push(Metric {
+            name: String::from("scaph_self_evicted_processes_total"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp: default_timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Number of processes no longer tracked because too many processes were tracked at once.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(self.topology.proc_tracker.evicted_pids),
+        });
+
+        if let Some(ratio) = self.topology.proc_tracker.tasks_cpu_usage_ratio() {
+            self.data.push(Metric {
+                name: String::from("scaph_self_tasks_cpu_usage_ratio"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: default_timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "CPU usage of the processes summed over their threads, divided by the one computed by sysinfo.",
+                ),
+                metric_value: MetricValueType::Text(ratio.to_string()),
+            });
+        }
+
         for socket in &self.topology.sockets {
             let mut attributes = HashMap::new();
-            attributes.insert("socket_id".to_string(), socket.id.to_string());
+            attributes.insert("socket_id".to_string(), socket.id.into());
 
             self.data.push(Metric {
                 name: String::from("scaph_self_socket_stats_nb"),
@@ -417,7 +1150,7 @@ impl MetricGenerator {
             });
 
             for domain in &socket.domains {
-                attributes.insert("rapl_domain_name".to_string(), domain.name.to_string());
+                attributes.insert("rapl_domain_name".to_string(), domain.name.as_str().into());
 
                 self.data.push(Metric {
                     name: String::from("scaph_self_domain_records_nb"),
@@ -439,28 +1172,16 @@ impl MetricGenerator {
 
     /// Generate host metrics.
     fn gen_host_metrics(&mut self) {
-        let records = self.topology.get_records_passive();
-
-        // metrics
-        if !records.is_empty() {
-            let record = records.last().unwrap();
+        // the counter and the power come from the same records, so that they share their timestamp
+        if let Some(EnergyReading {
+            counter: record,
+            power,
+        }) = self.topology.get_energy_reading()
+        {
             let host_energy_microjoules = record.value.clone();
             let mut attributes = HashMap::new();
-            if self.topology._sensor_data.contains_key("psys") {
-                attributes.insert(
-                    String::from("value_source"),
-                    String::from("powercap_rapl_psys"),
-                );
-            } else if self.topology._sensor_data.contains_key("source_file") {
-                attributes.insert(
-                    String::from("value_source"),
-                    String::from("powercap_rapl_pkg"),
-                );
-            } else if self.topology._sensor_data.contains_key("DRIVER_NAME") {
-                attributes.insert(
-                    String::from("value_source"),
-                    String::from("scaphandredrv_rapl_pkg"),
-                );
+            if let Some(source) = record.source {
+                attributes.insert(String::from("value_source"), source.value_source().into());
             }
 
             self.data.push(Metric {
@@ -478,7 +1199,32 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(host_energy_microjoules),
                 });
 
-            if let Some(power) = self.topology.get_records_diff_power_microwatts() {
+            if let Some(power) = power {
+                self.gen_reference_power_metrics(&power);
+                self.gen_forecast_metrics(&power);
+                if let Ok(microwatts) = power.value.trim().parse::<f64>() {
+                    self.gen_anomaly_metric("host", "", microwatts, power.timestamp);
+                    self.gen_headroom_metrics(microwatts, power.timestamp);
+                    self.gen_host_power_slope_metric(microwatts, power.timestamp);
+                    self.gen_energy_since_start_metrics(microwatts, power.timestamp);
+                    if let Some(totals) = self.energy_totals.as_mut() {
+                        totals.add_host_power(power.timestamp, microwatts);
+                        self.data.push(Metric {
+                            name: String::from("scaph_host_energy_total_microjoules"),
+                            metric_type: String::from("counter"),
+                            ttl: 60.0,
+                            timestamp: power.timestamp,
+                            hostname: self.hostname.clone(),
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes: HashMap::new(),
+                            description: String::from(
+                                "Energy consumed by the host, integrated from its power by scaphandre, in microjoules.",
+                            ),
+                            metric_value: MetricValueType::Text((totals.host as u64).to_string()),
+                        });
+                    }
+                }
                 self.data.push(Metric {
                     name: String::from("scaph_host_power_microwatts"),
                     metric_type: String::from("gauge"),
@@ -491,14 +1237,112 @@ impl MetricGenerator {
                     description: String::from("Power measurement on the whole host, in microwatts"),
                     metric_value: MetricValueType::Text(power.value),
                 });
+                // with PSYS, the sum of the PKG and DRAM domains is published as well,
+                // right after it so that both series stay in the same family
+                if self.topology.has_psys() {
+                    if let Some(pkg_power) = self.topology.get_pkg_dram_power_microwatts() {
+                        let mut attributes = HashMap::new();
+                        if let Some(source) = pkg_power.source {
+                            attributes
+                                .insert(String::from("value_source"), source.value_source().into());
+                        }
+                        self.data.push(Metric {
+                            name: String::from("scaph_host_power_microwatts"),
+                            metric_type: String::from("gauge"),
+                            ttl: 60.0,
+                            timestamp: pkg_power.timestamp,
+                            hostname: self.hostname.clone(),
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes,
+                            description: String::from(
+                                "Power measurement on the whole host, in microwatts",
+                            ),
+                            metric_value: MetricValueType::Text(pkg_power.value),
+                        });
+                    }
+                }
             }
-        }
-        if let Some(metric_value) = self.topology.get_load_avg() {
-            self.data.push(Metric {
-                name: String::from("scaph_host_load_avg_one"),
-                metric_type: String::from("gauge"),
-                ttl: 60.0,
-                timestamp: metric_value[0].timestamp,
+
+            #[cfg(target_os = "linux")]
+            for (zone, record) in self.topology.get_rapl_psys_zones_energy_microjoules() {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("psys_zone"), zone.into());
+                self.data.push(Metric {
+                    name: String::from("scaph_host_rapl_psys_microjoules"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: record.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Raw energy counter of a RAPL PSYS zone, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::Text(record.value),
+                });
+            }
+
+            if let Some(ratio) = self.topology.get_measurement_coverage_ratio() {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_measurement_coverage_ratio"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: ratio.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from(
+                        "Share of the host power (PSYS) that is observed through PKG and DRAM RAPL domains, between 0 and 1.",
+                    ),
+                    metric_value: MetricValueType::Text(ratio.value),
+                });
+            }
+
+            if let Some(period) = self.topology.counter_update_period {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_rapl_update_period_seconds"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: current_system_time_since_epoch(),
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from(
+                        "Time between two updates of the RAPL energy counters, as detected at startup, in seconds.",
+                    ),
+                    metric_value: MetricValueType::Text(period.as_secs_f64().to_string()),
+                });
+            }
+
+            for (activity, power) in self.topology.get_kernel_power_microwatts() {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("kernel_activity"), activity.into());
+                self.data.push(Metric {
+                    name: String::from("scaph_kernel_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Power attributed to the kernel (irq, softirq or kernel threads), in microwatts",
+                    ),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+        }
+        if let Some(metric_value) = self.topology.get_load_avg() {
+            self.data.push(Metric {
+                name: String::from("scaph_host_load_avg_one"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: metric_value[0].timestamp,
                 hostname: self.hostname.clone(),
                 state: String::from("ok"),
                 tags: vec!["scaphandre".to_string()],
@@ -531,6 +1375,25 @@ impl MetricGenerator {
                 metric_value: MetricValueType::Text(metric_value[2].value.clone()),
             });
         }
+        let timestamp = current_system_time_since_epoch();
+        for (mode, ratio) in self.topology.get_cpu_time_ratios() {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("mode"), mode.into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_cpu_time_ratio"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Share of the CPU time of the host spent in each mode of /proc/stat since the last measurement, from 0 to 1.",
+                ),
+                metric_value: MetricValueType::Text(format!("{ratio:.4}")),
+            });
+        }
         let freq = self.topology.get_cpu_frequency();
         self.data.push(Metric {
             name: String::from("scaph_host_cpu_frequency"),
@@ -554,11 +1417,165 @@ impl MetricGenerator {
                 hostname: self.hostname.clone(),
                 state: String::from("ok"),
                 tags: vec!["scaphandre".to_string()],
-                attributes: metric.1,
+                attributes: metric
+                    .1
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let value = match (k.as_str(), v.parse::<bool>()) {
+                            ("disk_is_removable", Ok(removable)) => removable.into(),
+                            _ => v.into(),
+                        };
+                        (k, value)
+                    })
+                    .collect(),
                 description: metric.0,
                 metric_value: MetricValueType::Text(metric.2.value),
             });
         }
+        // one family after the other, for the prometheus exporter to group them
+        type DiskIoMetric = (&'static str, &'static str, fn(&DiskIo) -> String);
+        let disk_io_metrics: [DiskIoMetric; 4] = [
+            (
+                "scaph_host_disk_read_bytes",
+                "Bytes read from the disk since the previous measurement.",
+                |io| io.read_bytes.to_string(),
+            ),
+            (
+                "scaph_host_disk_written_bytes",
+                "Bytes written to the disk since the previous measurement.",
+                |io| io.written_bytes.to_string(),
+            ),
+            (
+                "scaph_host_disk_read_iops",
+                "Read operations per second on the disk since the previous measurement.",
+                |io| format!("{:.2}", io.read_iops),
+            ),
+            (
+                "scaph_host_disk_write_iops",
+                "Write operations per second on the disk since the previous measurement.",
+                |io| format!("{:.2}", io.write_iops),
+            ),
+        ];
+        for (name, description, value) in disk_io_metrics {
+            for io in &self.topology.disk_io {
+                let mut attributes = HashMap::new();
+                attributes.insert(
+                    String::from("disk_name"),
+                    format!("/dev/{}", io.name).into(),
+                );
+                self.data.push(Metric {
+                    name: String::from(name),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(description),
+                    metric_value: MetricValueType::Text(value(io)),
+                });
+            }
+        }
+        self.gen_network_metrics(timestamp);
+
+        for (label, record) in self.topology.get_components_temperatures() {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("component_label"), label.into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_component_temperature_celsius"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: record.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from("Temperature of a hardware component, in Celsius."),
+                metric_value: MetricValueType::Text(record.value),
+            });
+        }
+
+        for (label, record) in self.topology.get_fans_speed() {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("fan_label"), label.into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_fan_speed_rpm"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: record.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from("Speed of a fan, in revolutions per minute."),
+                metric_value: MetricValueType::Text(record.value),
+            });
+        }
+
+        if let Some((battery, online)) = self.topology.get_ac_online() {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("battery"), battery.into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_ac_online"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: current_system_time_since_epoch(),
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "1 if the host is plugged into AC power, 0 if it runs on its battery.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(online as u64),
+            });
+        }
+
+        let timestamp = current_system_time_since_epoch();
+        for pressure in &self.topology.pressure {
+            for (window, avg) in [
+                ("10", pressure.avg10),
+                ("60", pressure.avg60),
+                ("300", pressure.avg300),
+            ] {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("resource"), pressure.resource.as_str().into());
+                attributes.insert(String::from("kind"), pressure.kind.as_str().into());
+                attributes.insert(String::from("window_seconds"), window.into());
+                self.data.push(Metric {
+                    name: String::from("scaph_host_pressure_ratio"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Share of time tasks were stalled waiting for the resource (PSI), from 0 to 1.",
+                    ),
+                    metric_value: MetricValueType::Text(avg.to_string()),
+                });
+            }
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("resource"), pressure.resource.as_str().into());
+            attributes.insert(String::from("kind"), pressure.kind.as_str().into());
+            self.data.push(Metric {
+                name: String::from("scaph_host_pressure_stall_microseconds"),
+                metric_type: String::from("counter"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Total time tasks were stalled waiting for the resource (PSI), in microseconds.",
+                ),
+                metric_value: MetricValueType::Text(pressure.total.to_string()),
+            });
+        }
 
         let ram_attributes = HashMap::new();
         let metric_value = self.topology.get_total_memory_bytes();
@@ -632,15 +1649,78 @@ impl MetricGenerator {
         });
     }
 
+    /// Generates the state of the `enabled` attribute of the RAPL zones of the
+    /// sockets, domains and PSYS, for those having one.
+    fn gen_rapl_zone_metrics(&mut self) {
+        let mut zones = vec![];
+        for socket in &self.topology.sockets {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("socket_id"), socket.id.into());
+            zones.push((socket.enabled, socket.max_energy_range_uj, attributes));
+            for domain in &socket.domains {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("domain_name"), domain.name.as_str().into());
+                attributes.insert(String::from("domain_id"), domain.id.into());
+                attributes.insert(String::from("socket_id"), socket.id.into());
+                zones.push((domain.enabled, domain.max_energy_range_uj, attributes));
+            }
+        }
+        for zone in &self.topology.psys_zones {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("psys_zone"), zone.name.as_str().into());
+            zones.push((zone.enabled, zone.max_energy_range_uj, attributes));
+        }
+        let timestamp = current_system_time_since_epoch();
+        for (enabled, _, attributes) in &zones {
+            if let Some(enabled) = enabled {
+                self.data.push(Metric {
+                    name: String::from("scaph_rapl_zone_enabled"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "1 if the RAPL zone is enabled, 0 if it is disabled and its energy counter doesn't advance.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned((*enabled).into()),
+                });
+            }
+        }
+        // after the enabled state of every zone, so that each family stays grouped
+        for (_, max_energy_range, attributes) in zones {
+            if let Some(max_energy_range) = max_energy_range {
+                self.data.push(Metric {
+                    name: String::from("scaph_rapl_zone_max_energy_range_microjoules"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Value after which the energy counter of the RAPL zone wraps around to 0, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(max_energy_range),
+                });
+            }
+        }
+    }
+
     /// Generate socket metrics.
     fn gen_socket_metrics(&mut self) {
         let sockets = self.topology.get_sockets_passive();
         for socket in sockets {
-            let records = socket.get_records_passive();
             let mut attributes = HashMap::new();
-            attributes.insert("socket_id".to_string(), socket.id.to_string());
-            if !records.is_empty() {
-                let metric = records.last().unwrap();
+            attributes.insert("socket_id".to_string(), socket.id.into());
+            if let Some(EnergyReading {
+                counter: metric,
+                power,
+            }) = socket.get_energy_reading()
+            {
                 let metric_value = metric.value.clone();
                 let metric_timestamp = metric.timestamp;
 
@@ -657,7 +1737,8 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(metric_value.clone()),
                 });
 
-                if let Some(power) = socket.get_records_diff_power_microwatts() {
+                // a disabled zone has a flat counter, not a null power
+                if let Some(power) = power.filter(|_| socket.is_enabled()) {
                     let socket_power_microwatts = &power.value;
 
                     self.data.push(Metric {
@@ -694,16 +1775,21 @@ impl MetricGenerator {
                 });
             }
             for domain in socket.get_domains_passive() {
-                let records = domain.get_records_passive();
-                if !records.is_empty() {
-                    let metric = records.last().unwrap();
+                if let Some(EnergyReading {
+                    counter: metric,
+                    power,
+                }) = domain.get_energy_reading()
+                {
                     let metric_value = metric.value.clone();
                     let metric_timestamp = metric.timestamp;
 
                     let mut attributes = HashMap::new();
-                    attributes.insert("domain_name".to_string(), domain.name.clone());
-                    attributes.insert("domain_id".to_string(), domain.id.to_string());
-                    attributes.insert("socket_id".to_string(), socket.id.to_string());
+                    attributes.insert("domain_name".to_string(), domain.name.as_str().into());
+                    attributes.insert("domain_id".to_string(), domain.id.into());
+                    attributes.insert("socket_id".to_string(), socket.id.into());
+                    if domain.igpu {
+                        attributes.insert("domain".to_string(), "igpu".into());
+                    }
 
                     self.data.push(Metric {
                         name: String::from("scaph_domain_energy_microjoules"),
@@ -720,7 +1806,7 @@ impl MetricGenerator {
                         metric_value: MetricValueType::Text(metric_value.clone()),
                     });
 
-                    if let Some(power) = domain.get_records_diff_power_microwatts() {
+                    if let Some(power) = power.filter(|_| domain.is_enabled()) {
                         let domain_power_microwatts = &power.value;
                         self.data.push(Metric {
                             name: String::from("scaph_domain_power_microwatts"),
@@ -736,13 +1822,69 @@ impl MetricGenerator {
                             ),
                             metric_value: MetricValueType::Text(domain_power_microwatts.clone()),
                         });
+
+                        if domain.igpu {
+                            let mut igpu_attributes = HashMap::new();
+                            igpu_attributes.insert("domain".to_string(), "igpu".into());
+                            igpu_attributes.insert("socket_id".to_string(), socket.id.into());
+                            self.data.push(Metric {
+                                name: String::from("scaph_igpu_power_microwatts"),
+                                metric_type: String::from("gauge"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: power.timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes: igpu_attributes,
+                                description: String::from(
+                                    "Power of the integrated GPU, measured by the uncore RAPL domain, in microwatts",
+                                ),
+                                metric_value: MetricValueType::Text(domain_power_microwatts.clone()),
+                            });
+                        }
+                    }
+                    // companions of the energy of the domain, to compute work per joule
+                    if domain.name == "core" {
+                        if let Some(usage) = socket.get_cpu_usage_percentage() {
+                            self.data.push(Metric {
+                                name: String::from("scaph_domain_cpu_usage_percent"),
+                                metric_type: String::from("gauge"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: metric_timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes: attributes.clone(),
+                                description: String::from(
+                                    "Share of time the CPU cores of the socket were busy, in percent",
+                                ),
+                                metric_value: MetricValueType::Text(format!("{usage:.2}")),
+                            });
+                        }
+                    } else if domain.name == "dram" {
+                        if let Some(bytes) = self.topology.memory_traffic.get(&socket.id) {
+                            self.data.push(Metric {
+                                name: String::from("scaph_domain_memory_traffic_bytes"),
+                                metric_type: String::from("counter"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: metric_timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes: attributes.clone(),
+                                description: String::from(
+                                    "Bytes read from and written to memory by the memory controllers of the socket",
+                                ),
+                                metric_value: MetricValueType::IntUnsigned(*bytes),
+                            });
+                        }
                     }
-                    let mut mmio_attributes = attributes.clone();
-                    mmio_attributes.insert(
-                        String::from("value_source"),
-                        String::from("powercap_rapl_mmio"),
-                    );
                     if let Some(mmio) = domain.get_rapl_mmio_energy_microjoules() {
+                        let mut mmio_attributes = attributes.clone();
+                        if let Some(source) = mmio.source {
+                            mmio_attributes
+                                .insert(String::from("value_source"), source.value_source().into());
+                        }
                         self.data.push(Metric {
                             name: String::from("scaph_domain_rapl_mmio_energy_microjoules"),
                             metric_type: String::from("counter"),
@@ -763,9 +1905,100 @@ impl MetricGenerator {
         }
     }
 
+    /// Publishes the traffic and link speed of the network interfaces, and
+    /// their power if a NIC energy model is set.
+    fn gen_network_metrics(&mut self, timestamp: Duration) {
+        let net_io = self.topology.net_io.clone();
+        let interface_attributes = |io: &NetIo| {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("interface"), io.interface.as_str().into());
+            attributes
+        };
+        type NetIoMetric = (&'static str, &'static str, fn(&NetIo) -> u64);
+        let net_io_metrics: [NetIoMetric; 2] = [
+            (
+                "scaph_host_network_received_bytes",
+                "Bytes received by the network interface since the previous measurement.",
+                |io| io.received_bytes,
+            ),
+            (
+                "scaph_host_network_transmitted_bytes",
+                "Bytes transmitted by the network interface since the previous measurement.",
+                |io| io.transmitted_bytes,
+            ),
+        ];
+        for (name, description, bytes) in net_io_metrics {
+            for io in &net_io {
+                self.data.push(Metric {
+                    name: String::from(name),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: interface_attributes(io),
+                    description: String::from(description),
+                    metric_value: MetricValueType::IntUnsigned(bytes(io)),
+                });
+            }
+        }
+        for io in &net_io {
+            if let Some(speed) = io.speed_mbps {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_network_link_speed_mbps"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: interface_attributes(io),
+                    description: String::from(
+                        "Speed of the link of the network interface, in Mb/s.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(speed),
+                });
+            }
+        }
+        if let Some(model) = &self.nic_energy_model {
+            for io in &net_io {
+                if let Some(microwatts) = model.power_microwatts(io) {
+                    self.data.push(Metric {
+                        name: String::from("scaph_host_network_power_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: interface_attributes(io),
+                        description: String::from(
+                            "Power of the network interface, estimated from its traffic and the energy per GB of its speed class, in microwatts.",
+                        ),
+                        metric_value: MetricValueType::Text(format!("{microwatts:.0}")),
+                    });
+                }
+            }
+        }
+    }
+
     /// Generate system metrics.
     fn gen_system_metrics(&mut self) {
         let default_timestamp = current_system_time_since_epoch();
+        self.data.push(Metric {
+            name: String::from("scaph_host_boot_time_seconds"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: default_timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from("Time the host booted at, in seconds since the epoch."),
+            metric_value: MetricValueType::IntUnsigned(self.topology.proc_tracker.get_boot_time()),
+        });
+
         if let Some(metric_value) = self.topology.read_nb_process_total_count() {
             self.data.push(Metric {
                 name: String::from("scaph_forks_since_boot_total"),
@@ -838,6 +2071,9 @@ impl MetricGenerator {
             if let Some(docker) = self.docker_client.as_mut() {
                 if let Ok(containers_result) = docker.get_containers(false) {
                     self.containers = containers_result;
+                    self.topology
+                        .proc_tracker
+                        .set_container_names(&self.containers);
                     self.containers_last_check =
                         current_system_time_since_epoch().as_secs().to_string();
                 }
@@ -924,11 +2160,41 @@ impl MetricGenerator {
         }
         debug!("Before loop.");
 
+        let mut domain_powers = if self.domain_attribution {
+            self.topology.get_processes_domain_power_microwatts()
+        } else {
+            HashMap::new()
+        };
+
+        let mut storage_powers = match self.storage_attribution {
+            Some((microwatts, iowait_weight)) => self
+                .topology
+                .get_processes_storage_power_microwatts(microwatts, iowait_weight),
+            None => HashMap::new(),
+        };
+
+        #[cfg(feature = "containers")]
+        let pod_budgets = if self.power_budgets.is_some() {
+            power_budget::pod_budgets(&self.pods)
+        } else {
+            HashMap::new()
+        };
+        #[cfg(not(feature = "containers"))]
+        let pod_budgets = HashMap::new();
+
+        let records_interval = self.topology.get_records_interval();
+
+        // power of each container, for anomaly detection
+        let mut containers_power: BTreeMap<String, (f64, Duration)> = BTreeMap::new();
+
+        // (exe, cgroup, pid) of each process, for restarts
+        let mut restart_groups = vec![];
+
         for pid in self.topology.proc_tracker.get_alive_pids() {
             let exe = self.topology.proc_tracker.get_process_name(pid);
             let cmdline = self.topology.proc_tracker.get_process_cmdline(pid);
 
-            let mut attributes = HashMap::new();
+            let mut attributes: HashMap<String, AttributeValue> = HashMap::new();
             debug!("Working on {}: {}", pid, exe);
 
             #[cfg(feature = "containers")]
@@ -946,28 +2212,118 @@ impl MetricGenerator {
 
                 if !container_data.is_empty() {
                     for (k, v) in container_data.iter() {
-                        attributes.insert(String::from(k), String::from(v));
+                        attributes.insert(String::from(k), v.as_str().into());
                     }
                 }
             }
 
-            attributes.insert("pid".to_string(), pid.to_string());
+            attributes.insert("pid".to_string(), pid.as_u32().into());
+
+            attributes.insert("exe".to_string(), exe.as_str().into());
 
-            attributes.insert("exe".to_string(), exe.clone());
+            let mut job = None;
+            if self.service_mapping.is_some()
+                || self.cgroup_attributes
+                || self.restart_tracker.is_some()
+                || self.jobs.is_some()
+            {
+                let cgroups = self.topology.proc_tracker.get_process_cgroups(pid);
+                if self.jobs.is_some() {
+                    job = jobs::job_from_cgroups(&cgroups);
+                }
+                if self.restart_tracker.is_some() {
+                    restart_groups.push((
+                        exe.clone(),
+                        restarts::restart_cgroup(&cgroups),
+                        pid.as_u32(),
+                    ));
+                }
+                if self.cgroup_attributes {
+                    let in_container = cgroups.iter().any(|c| is_container_cgroup(c));
+                    attributes.insert(String::from("in_container"), in_container.into());
+                    if let Some(leaf) = cgroup_leaf(&cgroups) {
+                        attributes.insert(String::from("cgroup"), leaf.into());
+                    }
+                }
+                if let Some(mapping) = &self.service_mapping {
+                    for (k, v) in mapping.labels_for(&exe, cmdline.as_deref(), &cgroups) {
+                        attributes.entry(k).or_insert(v.into());
+                    }
+                }
+            }
 
             if let Some(cmdline_str) = cmdline {
-                attributes.insert("cmdline".to_string(), utils::filter_cmdline(&cmdline_str));
+                attributes.insert(
+                    "cmdline".to_string(),
+                    utils::filter_cmdline(&cmdline_str, self.cmdline_max_length).into(),
+                );
 
                 #[cfg(target_os = "linux")]
                 if self.qemu {
                     if let Some(vmname) = utils::filter_qemu_cmdline(&cmdline_str) {
-                        attributes.insert("vmname".to_string(), vmname);
+                        attributes.insert("vmname".to_string(), vmname.into());
                     }
                 }
             }
 
             if let Some(metrics) = self.topology.get_all_per_process(pid) {
                 for (k, v) in metrics {
+                    if k == "scaph_process_power_consumption_microwatts" {
+                        if let Ok(microwatts) = v.1.value.trim().parse::<f64>() {
+                            if let Some(container) = attributes.get("container_names") {
+                                let entry = containers_power
+                                    .entry(container.to_string())
+                                    .or_insert((0.0, v.1.timestamp));
+                                entry.0 += microwatts;
+                            }
+                            if let Some(groups) = self.label_groups.as_mut() {
+                                groups.add_process_power(&attributes, microwatts, v.1.timestamp);
+                            }
+                            if let (Some(jobs), Some(job)) = (self.jobs.as_mut(), &job) {
+                                let uid = self.topology.proc_tracker.get_process_user_id(pid);
+                                jobs.add_process_power(job, uid, microwatts, v.1.timestamp);
+                            }
+                            if let (Some(history), Some(interval)) =
+                                (self.process_power_history.as_mut(), records_interval)
+                            {
+                                history.add(
+                                    pid.as_u32(),
+                                    v.1.timestamp.saturating_sub(interval),
+                                    v.1.timestamp,
+                                    microwatts,
+                                );
+                            }
+                            if let Some(budgets) = self.power_budgets.as_mut() {
+                                if let Some((scope, budget)) =
+                                    power_budget::budget_scope(&attributes, &pod_budgets)
+                                {
+                                    budgets.add_process_power(
+                                        scope,
+                                        budget,
+                                        microwatts,
+                                        v.1.timestamp,
+                                    );
+                                }
+                            }
+                            if let Some(totals) = self.energy_totals.as_mut() {
+                                let energy = totals.add_process_power(pid, &exe, microwatts);
+                                self.data.push(Metric {
+                                    name: String::from("scaph_process_energy_total_microjoules"),
+                                    metric_type: String::from("counter"),
+                                    ttl: 60.0,
+                                    timestamp: v.1.timestamp,
+                                    hostname: self.hostname.clone(),
+                                    state: String::from("ok"),
+                                    tags: vec!["scaphandre".to_string()],
+                                    attributes: attributes.clone(),
+                                    description: String::from(
+                                        "Energy consumed by the process since scaphandre started to monitor it, in microjoules.",
+                                    ),
+                                    metric_value: MetricValueType::Text((energy as u64).to_string()),
+                                });
+                            }
+                        }
+                    }
                     self.data.push(Metric {
                         name: k,
                         metric_type: String::from("gauge"),
@@ -982,11 +2338,532 @@ impl MetricGenerator {
                     })
                 }
             }
-        }
+
+            if self.restart_tracker.is_some() {
+                if let Some(start_time) = self.topology.proc_tracker.get_process_start_time(pid) {
+                    self.data.push(Metric {
+                        name: String::from("scaph_process_start_time_seconds"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: current_system_time_since_epoch(),
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Time the process started at, in seconds since the epoch.",
+                        ),
+                        metric_value: MetricValueType::IntUnsigned(start_time),
+                    });
+                }
+            }
+
+            if self.context_switches {
+                if let Some((voluntary, nonvoluntary)) =
+                    self.topology.proc_tracker.get_process_context_switches(pid)
+                {
+                    for (kind, count) in [("voluntary", voluntary), ("nonvoluntary", nonvoluntary)]
+                    {
+                        let mut attributes = attributes.clone();
+                        attributes.insert(String::from("kind"), kind.into());
+                        self.data.push(Metric {
+                            name: String::from("scaph_process_context_switches_total"),
+                            metric_type: String::from("counter"),
+                            ttl: 60.0,
+                            timestamp: current_system_time_since_epoch(),
+                            hostname: self.hostname.clone(),
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes,
+                            description: String::from(
+                                "Context switches of the threads of the process: voluntary ones when they wait (sleep, I/O), after which they are woken up, nonvoluntary ones when they are preempted.",
+                            ),
+                            metric_value: MetricValueType::Text(count.to_string()),
+                        });
+                    }
+                }
+            }
+
+            if let Some(domains) = domain_powers.remove(&pid) {
+                for (domain, record) in domains {
+                    let mut attributes = attributes.clone();
+                    attributes.insert("domain".to_string(), domain.into());
+                    self.data.push(Metric {
+                        name: String::from("scaph_process_domain_power_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: record.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes,
+                        description: String::from(
+                            "Power of a RAPL domain (core, uncore or dram) attributed to the process, in microwatts.",
+                        ),
+                        metric_value: MetricValueType::Text(record.value),
+                    })
+                }
+            }
+            if let Some(record) = storage_powers.remove(&pid) {
+                self.data.push(Metric {
+                    name: String::from("scaph_process_storage_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: record.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "Power of the storage devices attributed to the process, on its block I/O wait and bytes transferred, in microwatts.",
+                    ),
+                    metric_value: MetricValueType::Text(record.value),
+                });
+            }
+        }
+
+        if let Some(history) = self.process_power_history.as_mut() {
+            let pids: Vec<u32> = self
+                .topology
+                .proc_tracker
+                .get_alive_pids()
+                .iter()
+                .map(|pid| pid.as_u32())
+                .collect();
+            history.retain(&pids);
+        }
+
+        if let Some(jobs) = self.jobs.as_mut() {
+            for (id, job) in jobs.flush(current_system_time_since_epoch()) {
+                let mut attributes: HashMap<String, AttributeValue> = HashMap::new();
+                attributes.insert(String::from("scheduler"), id.scheduler.into());
+                attributes.insert(String::from("job_id"), id.id.into());
+                attributes.insert(String::from("user"), job.user.into());
+                self.data.push(Metric {
+                    name: String::from("scaph_job_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: job.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "Power of the processes of the batch job on this host, in microwatts.",
+                    ),
+                    metric_value: MetricValueType::Text((job.power as u64).to_string()),
+                });
+                self.data.push(Metric {
+                    name: String::from("scaph_job_energy_microjoules_total"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: job.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Energy consumed by the processes of the batch job on this host since it started, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::Text((job.energy as u64).to_string()),
+                });
+            }
+        }
+
+        if let Some(tracker) = self.restart_tracker.as_mut() {
+            let now = current_system_time_since_epoch();
+            tracker.update(&restart_groups, now);
+            for (exe, cgroup, restarts) in tracker.restarts() {
+                let mut attributes: HashMap<String, AttributeValue> = HashMap::new();
+                attributes.insert(String::from("exe"), exe.into());
+                attributes.insert(String::from("cgroup"), cgroup.into());
+                self.data.push(Metric {
+                    name: String::from("scaph_process_restarts_total"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: now,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Times a process of this executable and cgroup exited and another one was started in its place.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(restarts),
+                });
+            }
+        }
+
+        if let Some(totals) = self.energy_totals.as_mut() {
+            totals.retain_processes(&self.topology.proc_tracker.get_alive_pids());
+            totals.save_if_due(current_system_time_since_epoch());
+        }
+
+        if let Some(detector) = self.anomaly_detector.as_mut() {
+            detector.retain(|key| {
+                key == "host:"
+                    || key
+                        .strip_prefix("container:")
+                        .is_some_and(|name| containers_power.contains_key(name))
+            });
+        }
+        for (container, (microwatts, timestamp)) in containers_power {
+            self.gen_anomaly_metric("container", &container, microwatts, timestamp);
+        }
+
+        self.gen_power_budget_metrics();
+
+        if let Some(groups) = self.label_groups.as_mut() {
+            for (labels, group) in groups.flush() {
+                let attributes: HashMap<String, AttributeValue> = labels
+                    .into_iter()
+                    .map(|(label, value)| (label, value.into()))
+                    .collect();
+                self.data.push(Metric {
+                    name: String::from("scaph_kubernetes_group_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: group.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: attributes.clone(),
+                    description: String::from(
+                        "Power of the processes of the pods sharing these label values, in microwatts.",
+                    ),
+                    metric_value: MetricValueType::Text((group.power as u64).to_string()),
+                });
+                self.data.push(Metric {
+                    name: String::from("scaph_kubernetes_group_energy_microjoules"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp: group.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Energy consumed by the processes of the pods sharing these label values, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::Text((group.energy as u64).to_string()),
+                });
+            }
+        }
+    }
+
+    /// Generates the average power and the budget of each pod or container that
+    /// has a power budget, and whether it is over it, notifying the webhook when
+    /// a violation starts.
+    fn gen_power_budget_metrics(&mut self) {
+        let budgets = match self.power_budgets.as_mut() {
+            Some(budgets) => budgets,
+            None => return,
+        };
+        let window = budgets.window.as_secs();
+        for (scope, evaluation) in budgets.flush() {
+            let attributes: HashMap<String, AttributeValue> = scope
+                .iter()
+                .map(|(k, v)| (k.clone(), v.as_str().into()))
+                .collect();
+            if evaluation.started {
+                let names: Vec<&str> = scope.iter().map(|(_, v)| v.as_str()).collect();
+                warn!(
+                    "Power budget exceeded by {}: {} W on average over {}s, for a budget of {} W",
+                    names.join("/"),
+                    evaluation.average / 1e6,
+                    window,
+                    evaluation.budget / 1e6
+                );
+                #[cfg(feature = "webhook")]
+                if let Some(url) = &budgets.webhook {
+                    anomaly::notify(
+                        url,
+                        power_budget::notification_payload(&self.hostname, &scope, &evaluation),
+                    );
+                }
+            }
+            self.data.push(Metric {
+                name: String::from("scaph_container_power_budget_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: evaluation.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: attributes.clone(),
+                description: String::from(
+                    "Power budget of the pod or container, from its scaphandre.io/power-budget-watts annotation, in microwatts.",
+                ),
+                metric_value: MetricValueType::Text((evaluation.budget as u64).to_string()),
+            });
+            let mut average_attributes = attributes.clone();
+            average_attributes.insert(String::from("window_seconds"), (window as i64).into());
+            self.data.push(Metric {
+                name: String::from("scaph_container_power_average_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: evaluation.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: average_attributes,
+                description: String::from(
+                    "Average power of the pod or container over the budget window, in microwatts.",
+                ),
+                metric_value: MetricValueType::Text((evaluation.average as u64).to_string()),
+            });
+            self.data.push(Metric {
+                name: String::from("scaph_container_power_budget_violation"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: evaluation.timestamp,
+                hostname: self.hostname.clone(),
+                state: if evaluation.violating {
+                    String::from("warning")
+                } else {
+                    String::from("ok")
+                },
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "1 if the average power of the pod or container is over its budget, 0 otherwise.",
+                ),
+                metric_value: MetricValueType::Text(String::from(if evaluation.violating {
+                    "1"
+                } else {
+                    "0"
+                })),
+            });
+        }
+    }
+
+    /// Generates the power cap of the host and the headroom left under it, which
+    /// is negative when the host is over its cap.
+    fn gen_headroom_metrics(&mut self, microwatts: f64, timestamp: Duration) {
+        let cap = match self.power_cap {
+            Some(cap) => cap,
+            None => return,
+        };
+        self.data.push(Metric {
+            name: String::from("scaph_host_power_cap_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from("Power the host should stay under, in microwatts."),
+            metric_value: MetricValueType::Text((cap as u64).to_string()),
+        });
+        self.data.push(Metric {
+            name: String::from("scaph_host_power_headroom_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp,
+            hostname: self.hostname.clone(),
+            state: if microwatts > cap {
+                String::from("warning")
+            } else {
+                String::from("ok")
+            },
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Power left to the host under its cap, negative when over it, in microwatts.",
+            ),
+            metric_value: MetricValueType::Text(((cap - microwatts) as i64).to_string()),
+        });
+    }
+
+    /// Generates the rate of change of the host power over the slope window.
+    fn gen_host_power_slope_metric(&mut self, microwatts: f64, timestamp: Duration) {
+        let slope = match self.power_slopes.as_mut() {
+            Some(slopes) => slopes.add("host", timestamp, microwatts),
+            None => return,
+        };
+        if let Some(slope) = slope {
+            self.data.push(Metric {
+                name: String::from("scaph_host_power_slope_microwatts_per_second"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Rate of change of the host power over the slope window, in microwatts per second.",
+                ),
+                metric_value: MetricValueType::Text((slope as i64).to_string()),
+            });
+        }
+    }
+
+    /// Generates the size of the memory behind each socket, with its NUMA nodes
+    /// and memory channels as attributes, to relate the energy of the dram
+    /// domain to it.
+    fn gen_socket_memory_metrics(&mut self) {
+        for socket in self.topology.get_sockets_passive() {
+            let memory_bytes = match socket.memory_bytes {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let mut attributes = HashMap::new();
+            attributes.insert("socket_id".to_string(), socket.id.into());
+            attributes.insert(
+                "numa_nodes".to_string(),
+                socket
+                    .numa_nodes
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+                    .into(),
+            );
+            if let Some(channels) = socket.memory_channels {
+                attributes.insert("memory_channels".to_string(), channels.into());
+            }
+            self.data.push(Metric {
+                name: String::from("scaph_socket_memory_bytes"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: current_system_time_since_epoch(),
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Size of the memory of the NUMA nodes of the socket, in bytes.",
+                ),
+                metric_value: MetricValueType::Text(memory_bytes.to_string()),
+            });
+        }
+    }
+
+    /// Generates the rate of change of the power of each socket over the slope
+    /// window, after the other socket metrics so that they stay grouped.
+    fn gen_socket_power_slope_metrics(&mut self) {
+        let slopes = match self.power_slopes.as_mut() {
+            Some(slopes) => slopes,
+            None => return,
+        };
+        let mut socket_slopes = vec![];
+        for socket in self.topology.get_sockets_passive() {
+            let power = match socket.get_energy_reading().and_then(|r| r.power) {
+                Some(power) if socket.is_enabled() => power,
+                _ => continue,
+            };
+            if let Ok(microwatts) = power.value.trim().parse::<f64>() {
+                if let Some(slope) = slopes.add(
+                    &format!("socket:{}", socket.id),
+                    power.timestamp,
+                    microwatts,
+                ) {
+                    socket_slopes.push((socket.id, power.timestamp, slope));
+                }
+            }
+        }
+        for (socket_id, timestamp, slope) in socket_slopes {
+            let mut attributes = HashMap::new();
+            attributes.insert("socket_id".to_string(), socket_id.into());
+            self.data.push(Metric {
+                name: String::from("scaph_socket_power_slope_microwatts_per_second"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Rate of change of the socket power over the slope window, in microwatts per second.",
+                ),
+                metric_value: MetricValueType::Text((slope as i64).to_string()),
+            });
+        }
+    }
+
+    /// Generate metrics comparing the host power computed by scaphandre with
+    /// the wall power measured by an external reference meter.
+    fn gen_reference_power_metrics(&mut self, power: &Record) {
+        let host_microwatts = match power.value.parse::<f64>() {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        let mut reference = self.pushed_reference_power.filter(|(timestamp, _)| {
+            current_system_time_since_epoch().saturating_sub(*timestamp) <= REFERENCE_POWER_MAX_AGE
+        });
+        if let Some(path) = &self.reference_power_file {
+            reference = utils::read_reference_power_microwatts(path, REFERENCE_POWER_MAX_AGE);
+        }
+        #[cfg(feature = "smartplug")]
+        if let Some(plug) = &self.reference_smartplug {
+            match plug.read_power_watts() {
+                Ok(watts) => {
+                    reference = Some((current_system_time_since_epoch(), watts * 1_000_000.0))
+                }
+                Err(e) => warn!("Couldn't read reference power from smart plug: {}", e),
+            }
+        }
+        if let Some((timestamp, reference_microwatts)) = reference {
+            self.data.push(Metric {
+                name: String::from("scaph_host_reference_power_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Wall power of the host measured by an external reference meter, in microwatts",
+                ),
+                metric_value: MetricValueType::Text((reference_microwatts as u64).to_string()),
+            });
+            if reference_microwatts > 0.0 {
+                let error_ratio = (host_microwatts - reference_microwatts) / reference_microwatts;
+                self.data.push(Metric {
+                    name: String::from("scaph_host_power_error_ratio"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from(
+                        "Relative error of scaph_host_power_microwatts compared to the reference meter (negative when underestimated)",
+                    ),
+                    metric_value: MetricValueType::Text(format!("{error_ratio:.4}")),
+                });
+            }
+        }
+    }
+
+    /// Adds the time between the last two measurements, over which power is
+    /// computed, to the power metrics as `interval_seconds`. It is rounded to a
+    /// tenth of a second so that jitter doesn't create new series at each step.
+    fn add_interval_attribute(&mut self) {
+        let interval = match self.topology.get_records_interval() {
+            Some(interval) => (interval.as_secs_f64() * 10.0).round() / 10.0,
+            None => return,
+        };
+        for metric in self.data.iter_mut() {
+            if DELTA_POWER_METRICS.contains(&metric.name.as_str()) {
+                metric
+                    .attributes
+                    .insert(String::from("interval_seconds"), interval.into());
+            }
+        }
     }
 
     /// Generate all metrics provided by Scaphandre agent.
-    fn gen_all_metrics(&mut self) {
+    ///
+    /// The metrics of the host, its sockets, the system and the processes are
+    /// computed from the last refresh of the topology, to be taken with
+    /// pop_metrics.
+    pub fn gen_all_metrics(&mut self) {
         info!(
             "{}: Get self metrics",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
@@ -1002,6 +2879,9 @@ impl MetricGenerator {
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
         self.gen_socket_metrics();
+        self.gen_socket_memory_metrics();
+        self.gen_socket_power_slope_metrics();
+        self.gen_rapl_zone_metrics();
         info!(
             "{}: Get system metrics",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
@@ -1012,18 +2892,308 @@ impl MetricGenerator {
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
         self.gen_process_metrics();
+        if self.interval_attribute {
+            self.add_interval_attribute();
+        }
+        self.gen_derived_metrics();
+        if let Some(reading) = self.topology.get_energy_reading() {
+            crate::watchdog::notify_alive(reading.counter.timestamp);
+        }
+        if let Some(node_labels) = &self.node_labels {
+            let attributes = node_labels.attributes();
+            for metric in self.data.iter_mut() {
+                for (key, value) in &attributes {
+                    metric.attributes.insert(key.clone(), value.as_str().into());
+                }
+            }
+        }
+        if let Some(container) = &self.lxc_container {
+            for metric in self.data.iter_mut() {
+                metric
+                    .attributes
+                    .insert(String::from("lxc_container"), container.as_str().into());
+            }
+        }
         trace!("self_metrics: {:#?}", self.data);
     }
 
+    /// Evaluates the derived metrics over the metrics generated so far, and the
+    /// values pushed for their external operands.
+    fn gen_derived_metrics(&mut self) {
+        let derived_metrics = match &self.derived_metrics {
+            Some(derived_metrics) => derived_metrics,
+            None => return,
+        };
+        let timestamp = current_system_time_since_epoch();
+        let value = |name: &str, labels: &[(String, String)]| {
+            let values: Vec<f64> = self
+                .data
+                .iter()
+                .filter(|metric| {
+                    metric.name == name
+                        && labels.iter().all(|(label, value)| {
+                            metric
+                                .attributes
+                                .get(label)
+                                .is_some_and(|v| format!("{v}") == *value)
+                        })
+                })
+                .filter_map(|metric| match &metric.metric_value {
+                    MetricValueType::Text(text) => text.parse::<f64>().ok(),
+                    MetricValueType::IntUnsigned(value) => Some(*value as f64),
+                })
+                .collect();
+            if values.is_empty() {
+                derived_metrics.external_value(name, timestamp)
+            } else {
+                Some(values.iter().sum())
+            }
+        };
+        let mut metrics = vec![];
+        for derived in &derived_metrics.metrics {
+            if let Some(result) = derived.expr.eval(&value) {
+                metrics.push(Metric {
+                    name: derived.name.clone(),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: format!("Derived metric: {}", derived.source),
+                    metric_value: MetricValueType::Text(result.to_string()),
+                });
+            }
+        }
+        self.data.extend(metrics);
+    }
+
+    /// Adds the host power to the energy since scaphandre started, and publishes
+    /// it along its extrapolation to the time since the host booted.
+    fn gen_energy_since_start_metrics(&mut self, microwatts: f64, timestamp: Duration) {
+        self.energy_since_start
+            .add_host_power(timestamp, microwatts);
+        self.data.push(Metric {
+            name: String::from("scaph_host_energy_since_start_microjoules"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Energy consumed by the host since scaphandre started, integrated from its power, in microjoules.",
+            ),
+            metric_value: MetricValueType::Text(
+                (self.energy_since_start.energy as u64).to_string(),
+            ),
+        });
+        let boot_time = Duration::from_secs(self.topology.proc_tracker.get_boot_time());
+        if let Some(energy) = self.energy_since_start.since_boot(boot_time) {
+            self.data.push(Metric {
+                name: String::from("scaph_host_energy_since_boot_microjoules"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Estimate of the energy consumed by the host since it booted, the time before scaphandre started being estimated from its mean power since then, in microjoules.",
+                ),
+                metric_value: MetricValueType::Text((energy as u64).to_string()),
+            });
+        }
+    }
+
+    /// Returns the time since the epoch `metric` started counting from 0 at, if
+    /// it is a counter that never resets or wraps in between. Exporters may send
+    /// it along the counter so that its first value isn't mistaken for a reset.
+    pub fn counter_created_time(&self, metric: &Metric) -> Option<Duration> {
+        match naming::scaphandre_name(&metric.name) {
+            "scaph_forks_since_boot_total" | "scaph_host_pressure_stall_microseconds" => Some(
+                Duration::from_secs(self.topology.proc_tracker.get_boot_time()),
+            ),
+            "scaph_host_energy_since_start_microjoules" => Some(self.energy_since_start.started),
+            "scaph_host_energy_total_microjoules" => {
+                self.energy_totals.as_ref().map(|totals| totals.created)
+            }
+            "scaph_process_energy_total_microjoules" => {
+                let pid = u32::try_from(metric.attributes.get("pid")?.as_i64()?).ok()?;
+                let totals = self.energy_totals.as_ref()?;
+                Some(totals.processes.get(&Pid::from_u32(pid))?.created)
+            }
+            "scaph_process_context_switches_total" => {
+                let pid = u32::try_from(metric.attributes.get("pid")?.as_i64()?).ok()?;
+                let start_time = self
+                    .topology
+                    .proc_tracker
+                    .get_process_start_time(Pid::from_u32(pid))?;
+                Some(Duration::from_secs(start_time))
+            }
+            "scaph_kubernetes_group_energy_microjoules" => {
+                self.label_groups.as_ref()?.created(&metric.attributes)
+            }
+            "scaph_job_energy_microjoules_total" => self.jobs.as_ref()?.created(
+                &metric.attributes.get("scheduler")?.to_string(),
+                &metric.attributes.get("job_id")?.to_string(),
+            ),
+            "scaph_process_restarts_total" => self.restart_tracker.as_ref()?.created(
+                &metric.attributes.get("exe")?.to_string(),
+                &metric.attributes.get("cgroup")?.to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Returns the metrics generated since the last call, without the
+    /// experimental ones unless they are enabled. The stability of metrics that
+    /// aren't stable is appended to their description.
     pub fn pop_metrics(&mut self) -> Vec<Metric> {
         let mut res = vec![];
-        while !&self.data.is_empty() {
-            res.push(self.data.pop().unwrap())
+        while let Some(mut metric) = self.data.pop() {
+            // derived metrics are the user's own, their stability is theirs to manage
+            if self
+                .derived_metrics
+                .as_ref()
+                .is_some_and(|derived| derived.is_derived(&metric.name))
+            {
+                res.push(metric);
+                continue;
+            }
+            let stability = stability::of(&metric.name);
+            if stability == Stability::Experimental && !self.include_experimental {
+                continue;
+            }
+            if stability != Stability::Stable {
+                metric.description = format!("{} ({})", metric.description, stability.name());
+            }
+            self.metric_naming.apply(&mut metric);
+            res.push(metric)
         }
         res
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::units::Unit;
+
+    /// Two energy records of `microjoules`, one second apart.
+    fn records(microjoules: [u64; 2]) -> Vec<Record> {
+        microjoules
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Record::new(
+                    Duration::from_secs(1_700_000_000 + i as u64),
+                    value.to_string(),
+                    Unit::MicroJoule,
+                )
+            })
+            .collect()
+    }
+
+    fn generator(topology: Topology) -> MetricGenerator {
+        MetricGenerator::new(topology, String::from("test"), false, false)
+    }
+
+    #[test]
+    fn metrics_without_sockets() {
+        let mut topology = Topology::new(HashMap::new());
+        topology.add_cpu_cores();
+        topology.record_buffer = records([1_000_000, 3_000_000]);
+        let mut generator = generator(topology);
+        generator.gen_all_metrics();
+        let metrics = generator.pop_metrics();
+
+        let host = metrics
+            .iter()
+            .find(|m| m.name == "scaph_host_power_microwatts")
+            .unwrap();
+        assert_eq!(host.metric_value.to_string(), "2000000");
+        assert!(metrics
+            .iter()
+            .any(|m| m.name == "scaph_host_energy_microjoules"));
+        assert!(!metrics
+            .iter()
+            .any(|m| m.name.starts_with("scaph_socket_") || m.name.starts_with("scaph_domain_")));
+    }
+
+    #[test]
+    fn pushed_reference_power() {
+        let mut generator = generator(Topology::new(HashMap::new()));
+        assert!(generator.set_reference_power(f64::NAN).is_err());
+        generator.set_reference_power(100.0).unwrap();
+        let host = Record::new(
+            current_system_time_since_epoch(),
+            String::from("110000000"),
+            Unit::MicroWatt,
+        );
+        generator.gen_reference_power_metrics(&host);
+        let metrics = generator.pop_metrics();
+        let value = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m.name == name)
+                .map(|m| m.metric_value.to_string())
+        };
+        assert_eq!(
+            value("scaph_host_reference_power_microwatts").as_deref(),
+            Some("100000000")
+        );
+        assert_eq!(
+            value("scaph_host_power_error_ratio").as_deref(),
+            Some("0.1000")
+        );
+
+        // the meter stopped pushing
+        generator.pushed_reference_power = generator
+            .pushed_reference_power
+            .map(|(timestamp, microwatts)| (timestamp - Duration::from_secs(61), microwatts));
+        generator.gen_reference_power_metrics(&host);
+        assert!(generator.pop_metrics().is_empty());
+    }
+
+    #[test]
+    fn igpu_domain_label() {
+        let mut topology = Topology::new(HashMap::new());
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        for (id, name) in [(0, "core"), (1, "uncore")] {
+            topology.safe_add_domain_to_socket(0, id, name, "", 1, HashMap::new());
+        }
+        for domain in topology.sockets[0].domains.iter_mut() {
+            domain.record_buffer = records([1_000_000, 3_000_000]);
+        }
+        topology.sockets[0].domains[1].igpu = true;
+        let mut generator = generator(topology);
+        generator.gen_socket_metrics();
+        let metrics = generator.pop_metrics();
+
+        let domain = |metric: &Metric| metric.attributes.get("domain").map(|v| v.to_string());
+        let power: Vec<&Metric> = metrics
+            .iter()
+            .filter(|m| m.name == "scaph_domain_power_microwatts")
+            .collect();
+        assert_eq!(power.len(), 2);
+        for metric in power {
+            let uncore = metric.attributes["domain_name"].to_string() == "uncore";
+            assert_eq!(domain(metric).is_some(), uncore);
+        }
+        let igpu: Vec<&Metric> = metrics
+            .iter()
+            .filter(|m| m.name == "scaph_igpu_power_microwatts")
+            .collect();
+        assert_eq!(igpu.len(), 1);
+        assert_eq!(domain(igpu[0]), Some(String::from("igpu")));
+        assert_eq!(igpu[0].metric_value.to_string(), "2000000");
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -0,0 +1,218 @@
+//! # Naming: names of the published metrics, for dashboards of other tools
+//!
+//! Clusters moving between scaphandre and [Kepler](https://github.com/sustainable-computing-io/kepler),
+//! or running both, can keep their dashboards with `--metric-naming kepler`:
+//! the energy counters that have a Kepler equivalent are published under the
+//! Kepler name, in joules, and the labels of sockets and containers get the
+//! Kepler names as well:
+//!
+//! ```text
+//! scaph_socket_energy_microjoules{socket_id="0"} 123456789
+//! kepler_node_package_joules_total{package="0"} 123.456789
+//! ```
+//!
+//! Metrics without an equivalent keep their name, so that nothing is lost.
+//! The mapping is applied last, when the metrics are taken from
+//! [MetricGenerator](super::MetricGenerator), everything before working on the
+//! scaphandre names.
+use super::{Metric, MetricValueType};
+
+/// Naming scheme of the published metrics.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetricNaming {
+    /// Names of scaphandre (scaph_*)
+    #[default]
+    Scaphandre,
+    /// Names of Kepler (kepler_*) for the metrics it has, in joules
+    Kepler,
+}
+
+/// Energy counters in microjoules with a Kepler equivalent in joules, as
+/// (scaphandre name, domain_name label, Kepler name).
+pub const KEPLER_METRICS: [(&str, Option<&str>, &str); 6] = [
+    (
+        "scaph_host_energy_microjoules",
+        None,
+        "kepler_node_platform_joules_total",
+    ),
+    (
+        "scaph_socket_energy_microjoules",
+        None,
+        "kepler_node_package_joules_total",
+    ),
+    (
+        "scaph_domain_energy_microjoules",
+        Some("core"),
+        "kepler_node_core_joules_total",
+    ),
+    (
+        "scaph_domain_energy_microjoules",
+        Some("uncore"),
+        "kepler_node_uncore_joules_total",
+    ),
+    (
+        "scaph_domain_energy_microjoules",
+        Some("dram"),
+        "kepler_node_dram_joules_total",
+    ),
+    (
+        "scaph_process_energy_total_microjoules",
+        None,
+        "kepler_process_joules_total",
+    ),
+];
+
+/// Labels renamed on all metrics, as (scaphandre label, Kepler label).
+pub const KEPLER_LABELS: [(&str, &str); 4] = [
+    ("socket_id", "package"),
+    ("container_names", "container_name"),
+    ("kubernetes_pod_name", "pod_name"),
+    ("kubernetes_pod_namespace", "container_namespace"),
+];
+
+impl MetricNaming {
+    /// Renames `metric` and its labels, converting its value if needed.
+    pub fn apply(self, metric: &mut Metric) {
+        if self == MetricNaming::Scaphandre {
+            return;
+        }
+        let domain = metric.attributes.get("domain_name").map(|d| d.to_string());
+        let kepler_name = KEPLER_METRICS.iter().find(|(name, domain_name, _)| {
+            *name == metric.name && (domain_name.is_none() || *domain_name == domain.as_deref())
+        });
+        if let Some((_, _, kepler_name)) = kepler_name {
+            let microjoules = match &metric.metric_value {
+                MetricValueType::Text(text) => text.parse::<f64>().ok(),
+                MetricValueType::IntUnsigned(value) => Some(*value as f64),
+            };
+            if let Some(microjoules) = microjoules {
+                metric.name = String::from(*kepler_name);
+                metric.metric_value = MetricValueType::Text((microjoules / 1e6).to_string());
+                metric.description = metric.description.replace("microjoules", "joules");
+                if metric.name == "kepler_process_joules_total" {
+                    // Kepler's command is the name of the executable, not its path
+                    if let Some(exe) = metric.attributes.get("exe").map(|e| e.to_string()) {
+                        let command = exe.rsplit('/').next().unwrap_or_default().to_string();
+                        metric
+                            .attributes
+                            .insert(String::from("command"), command.into());
+                    }
+                }
+            }
+        }
+        for (label, kepler_label) in KEPLER_LABELS {
+            if let Some(value) = metric.attributes.remove(label) {
+                metric.attributes.insert(String::from(kepler_label), value);
+            }
+        }
+    }
+}
+
+/// Returns the scaphandre name of a metric named `name` under any naming.
+pub fn scaphandre_name(name: &str) -> &str {
+    KEPLER_METRICS
+        .iter()
+        .find(|(_, _, kepler_name)| *kepler_name == name)
+        .map_or(name, |(scaphandre_name, _, _)| scaphandre_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn metric(name: &str, value: u64, attributes: &[(&str, &str)]) -> Metric {
+        Metric {
+            name: String::from(name),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp: Duration::ZERO,
+            hostname: String::from("node1"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), (*v).into()))
+                .collect::<HashMap<_, _>>(),
+            description: String::from("Energy, in microjoules."),
+            metric_value: MetricValueType::IntUnsigned(value),
+        }
+    }
+
+    #[test]
+    fn kepler_names() {
+        let mut socket = metric(
+            "scaph_socket_energy_microjoules",
+            2_500_000,
+            &[("socket_id", "0")],
+        );
+        MetricNaming::Scaphandre.apply(&mut socket);
+        assert_eq!(socket.name, "scaph_socket_energy_microjoules");
+        MetricNaming::Kepler.apply(&mut socket);
+        assert_eq!(socket.name, "kepler_node_package_joules_total");
+        assert_eq!(socket.metric_value.to_string(), "2.5");
+        assert_eq!(socket.description, "Energy, in joules.");
+        assert_eq!(socket.attributes.get("package").unwrap().to_string(), "0");
+        assert!(!socket.attributes.contains_key("socket_id"));
+
+        let mut dram = metric(
+            "scaph_domain_energy_microjoules",
+            1,
+            &[("domain_name", "dram")],
+        );
+        MetricNaming::Kepler.apply(&mut dram);
+        assert_eq!(dram.name, "kepler_node_dram_joules_total");
+        let mut psys = metric(
+            "scaph_domain_energy_microjoules",
+            1,
+            &[("domain_name", "psys")],
+        );
+        MetricNaming::Kepler.apply(&mut psys);
+        assert_eq!(psys.name, "scaph_domain_energy_microjoules");
+
+        let mut process = metric(
+            "scaph_process_energy_total_microjoules",
+            1_000_000,
+            &[("exe", "/usr/sbin/nginx"), ("kubernetes_pod_name", "web-1")],
+        );
+        MetricNaming::Kepler.apply(&mut process);
+        assert_eq!(process.name, "kepler_process_joules_total");
+        assert_eq!(
+            process.attributes.get("command").unwrap().to_string(),
+            "nginx"
+        );
+        assert_eq!(
+            process.attributes.get("pod_name").unwrap().to_string(),
+            "web-1"
+        );
+
+        let mut power = metric("scaph_host_power_microwatts", 1, &[]);
+        MetricNaming::Kepler.apply(&mut power);
+        assert_eq!(power.name, "scaph_host_power_microwatts");
+        assert_eq!(power.metric_value.to_string(), "1");
+
+        assert_eq!(
+            scaphandre_name("kepler_process_joules_total"),
+            "scaph_process_energy_total_microjoules"
+        );
+        assert_eq!(
+            scaphandre_name("scaph_host_power_microwatts"),
+            "scaph_host_power_microwatts"
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,123 @@
+//! # NIC energy: power of the network interfaces from their traffic
+//!
+//! The power drawn by a network interface card barely shows in RAPL, while
+//! proxies and storage nodes move enough data for it to matter. Without a
+//! meter on the NIC itself, [NicEnergyModel] estimates it from the traffic of
+//! each interface, with an energy per gigabyte that depends on the speed class
+//! of its link, given as `<speed in Mb/s>=<joules per GB>` pairs:
+//!
+//! ```text
+//! --nic-joules-per-gb 1000=2.0,10000=0.8,25000=0.5
+//! ```
+//!
+//! An interface uses the coefficient of the fastest class its link reaches (a
+//! 40 Gb/s link uses the 25000 one above). Interfaces whose speed is unknown,
+//! or slower than all the classes, get no estimation. The coefficients are
+//! left to the user, from the datasheets of their NICs or their own
+//! measurements.
+use crate::sensors::netstats::NetIo;
+use std::str::FromStr;
+
+/// Energy per gigabyte transferred, by speed class of the link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NicEnergyModel {
+    /// Speed of the class in Mb/s, and its joules per gigabyte, sorted by speed
+    classes: Vec<(u64, f64)>,
+}
+
+impl FromStr for NicEnergyModel {
+    type Err = String;
+
+    /// Parses comma separated `<speed in Mb/s>=<joules per GB>` pairs.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut classes = s
+            .split(',')
+            .map(|class| {
+                let (speed, joules) = class
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid class {class}, expected <Mb/s>=<J/GB>"))?;
+                let speed = speed
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| format!("invalid speed in {class}: {e}"))?;
+                let joules = joules
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|e| format!("invalid energy in {class}: {e}"))?;
+                if joules < 0.0 {
+                    return Err(format!("negative energy in {class}"));
+                }
+                Ok((speed, joules))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        classes.sort_by_key(|(speed, _)| *speed);
+        Ok(NicEnergyModel { classes })
+    }
+}
+
+impl NicEnergyModel {
+    /// Returns the joules per gigabyte of a link at `speed_mbps`.
+    pub fn joules_per_gb(&self, speed_mbps: Option<u64>) -> Option<f64> {
+        let speed = speed_mbps?;
+        self.classes
+            .iter()
+            .rev()
+            .find(|(class, _)| *class <= speed)
+            .map(|(_, joules)| *joules)
+    }
+
+    /// Returns the power of the interface over the interval of `io`, in microwatts.
+    pub fn power_microwatts(&self, io: &NetIo) -> Option<f64> {
+        let joules_per_gb = self.joules_per_gb(io.speed_mbps)?;
+        let seconds = io.interval.as_secs_f64();
+        if seconds <= 0.0 {
+            return None;
+        }
+        let gigabytes = (io.received_bytes + io.transmitted_bytes) as f64 / 1e9;
+        Some(gigabytes * joules_per_gb / seconds * 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn nic_power_from_traffic() {
+        let model = "10000=0.8, 1000=2".parse::<NicEnergyModel>().unwrap();
+        assert_eq!(model.joules_per_gb(Some(1000)), Some(2.0));
+        assert_eq!(model.joules_per_gb(Some(40000)), Some(0.8));
+        assert_eq!(model.joules_per_gb(Some(100)), None);
+        assert_eq!(model.joules_per_gb(None), None);
+
+        let io = NetIo {
+            interface: String::from("eth0"),
+            received_bytes: 3_000_000_000,
+            transmitted_bytes: 1_000_000_000,
+            speed_mbps: Some(25000),
+            interval: Duration::from_secs(10),
+        };
+        // 4 GB at 0.8 J/GB over 10 s
+        let microwatts = model.power_microwatts(&io).unwrap();
+        assert!((microwatts - 320_000.0).abs() < 1e-3, "{microwatts}");
+
+        assert!("1000".parse::<NicEnergyModel>().is_err());
+        assert!("1000=-1".parse::<NicEnergyModel>().is_err());
+        assert!("fast=1".parse::<NicEnergyModel>().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
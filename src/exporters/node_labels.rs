@@ -0,0 +1,180 @@
+//! # Node labels: labels of the Kubernetes node on all metrics
+//!
+//! Node labels can't be exposed to a pod through the Downward API, so the Helm
+//! chart copies them, with an init container, to a file in the same format as a
+//! Downward API volume: one `key="value"` line per label. [NodeLabels] reads
+//! this file and adds the labels of an allow-list (zone, instance type, node
+//! pool...) to every metric, so that energy can be aggregated by zone without
+//! joining with kube-state-metrics.
+//!
+//! The name of the node is added as well when the `NODE_NAME` environment
+//! variable is set, usually from `spec.nodeName`.
+use std::collections::HashMap;
+use std::fs;
+
+/// Node labels added when no allow-list is given: zone, region, instance type
+/// and node pool, as set by Kubernetes and the main managed offerings.
+pub const DEFAULT_NODE_LABELS: [&str; 7] = [
+    "topology.kubernetes.io/zone",
+    "topology.kubernetes.io/region",
+    "node.kubernetes.io/instance-type",
+    "karpenter.sh/nodepool",
+    "cloud.google.com/gke-nodepool",
+    "eks.amazonaws.com/nodegroup",
+    "kubernetes.azure.com/agentpool",
+];
+
+/// Environment variable holding the name of the node.
+pub const NODE_NAME_VARIABLE: &str = "NODE_NAME";
+
+/// Parses a file in the format of Downward API volumes: one `key="value"`
+/// line per label, the value being quoted and escaped as a Go string.
+pub fn parse_labels(content: &str) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    for line in content.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => unescape(quoted),
+                None => String::from(value),
+            };
+            res.insert(String::from(key.trim()), value);
+        }
+    }
+    res
+}
+
+fn unescape(s: &str) -> String {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some(c) => res.push(c),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+/// Returns the attribute holding the node label `label`, e.g.
+/// node_label_topology_kubernetes_io_zone.
+pub fn attribute_name(label: &str) -> String {
+    let sanitized: String = label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("node_label_{sanitized}")
+}
+
+/// Labels of the node to add to the metrics.
+#[derive(Debug, Clone)]
+pub struct NodeLabels {
+    /// File holding the labels of the node
+    pub path: String,
+    /// Labels to add, the other ones are ignored
+    pub allowed: Vec<String>,
+    /// Name of the node, from [NODE_NAME_VARIABLE]
+    pub node_name: Option<String>,
+}
+
+impl NodeLabels {
+    pub fn new(path: &str, allowed: &[String]) -> NodeLabels {
+        NodeLabels {
+            path: String::from(path),
+            allowed: if allowed.is_empty() {
+                DEFAULT_NODE_LABELS
+                    .iter()
+                    .map(|l| String::from(*l))
+                    .collect()
+            } else {
+                allowed.to_vec()
+            },
+            node_name: std::env::var(NODE_NAME_VARIABLE)
+                .ok()
+                .filter(|name| !name.is_empty()),
+        }
+    }
+
+    /// Returns the attributes to add to the metrics. The file is read each
+    /// time, as the labels of the node may change while scaphandre runs.
+    pub fn attributes(&self) -> Vec<(String, String)> {
+        let mut res = vec![];
+        if let Some(name) = &self.node_name {
+            res.push((String::from("kubernetes_node_name"), name.clone()));
+        }
+        match fs::read_to_string(&self.path) {
+            Ok(content) => {
+                let labels = parse_labels(&content);
+                for label in &self.allowed {
+                    if let Some(value) = labels.get(label) {
+                        res.push((attribute_name(label), value.clone()));
+                    }
+                }
+            }
+            Err(e) => warn!("Couldn't read node labels from {}: {}", self.path, e),
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_labels_file() {
+        let content = "kubernetes.io/os=\"linux\"\ntopology.kubernetes.io/zone=\"eu-west-3a\"\nnote=\"say \\\"hi\\\"\"\n";
+        let labels = parse_labels(content);
+        assert_eq!(labels["topology.kubernetes.io/zone"], "eu-west-3a");
+        assert_eq!(labels["note"], "say \"hi\"");
+        assert_eq!(
+            attribute_name("topology.kubernetes.io/zone"),
+            "node_label_topology_kubernetes_io_zone"
+        );
+
+        let path = std::env::temp_dir().join("scaphandre_node_labels_test");
+        fs::write(&path, content).unwrap();
+        let mut node_labels = NodeLabels::new(path.to_str().unwrap(), &[]);
+        node_labels.node_name = Some(String::from("node-1"));
+        assert_eq!(
+            node_labels.attributes(),
+            vec![
+                (String::from("kubernetes_node_name"), String::from("node-1")),
+                (
+                    String::from("node_label_topology_kubernetes_io_zone"),
+                    String::from("eu-west-3a")
+                ),
+            ]
+        );
+        node_labels.allowed = vec![String::from("kubernetes.io/os")];
+        node_labels.node_name = None;
+        assert_eq!(
+            node_labels.attributes(),
+            vec![(
+                String::from("node_label_kubernetes_io_os"),
+                String::from("linux")
+            )]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
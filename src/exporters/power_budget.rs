@@ -0,0 +1,275 @@
+//! # Power budget: advisory power limits of pods and containers
+//!
+//! A pod can be given a power budget with the `scaphandre.io/power-budget-watts`
+//! annotation (a Docker container with a label of the same name).
+//! [PowerBudgets] compares the average power of each pod or container over a
+//! rolling window with its budget, and reports the ones that go over it.
+//!
+//! Scaphandre doesn't throttle anything: violations are published as metrics,
+//! and possibly sent to a webhook, for an external controller to enforce its own
+//! policy (evict the pod, lower its CPU quota...).
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+#[cfg(feature = "containers")]
+use k8s_sync::Pod;
+
+/// Pod annotation, or Docker container label, holding the power budget in watts.
+pub const BUDGET_ANNOTATION: &str = "scaphandre.io/power-budget-watts";
+/// Process attribute holding the budget label of its Docker container.
+pub const BUDGET_LABEL_ATTRIBUTE: &str = "container_label_scaphandre_io/power_budget_watts";
+
+/// Labels identifying a pod (namespace and name) or a container (name).
+pub type Scope = Vec<(String, String)>;
+
+/// Parses a budget in watts, which has to be a positive number.
+pub fn parse_budget_watts(value: &str) -> Option<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(watts) if watts.is_finite() && watts > 0.0 => Some(watts),
+        _ => None,
+    }
+}
+
+/// Returns the budgets of the pods that have the budget annotation, in watts,
+/// by namespace and name.
+#[cfg(feature = "containers")]
+pub fn pod_budgets(pods: &[Pod]) -> HashMap<(String, String), f64> {
+    let mut res = HashMap::new();
+    for pod in pods {
+        let budget = pod
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(BUDGET_ANNOTATION))
+            .and_then(|value| parse_budget_watts(value));
+        if let (Some(budget), Some(namespace), Some(name)) =
+            (budget, &pod.metadata.namespace, &pod.metadata.name)
+        {
+            res.insert((namespace.clone(), name.clone()), budget);
+        }
+    }
+    res
+}
+
+/// Returns the scope a process is accounted in and its budget in watts, given
+/// its attributes: its pod if it has a budget, else its Docker container if it
+/// has one.
+pub fn budget_scope<V: ToString>(
+    attributes: &HashMap<String, V>,
+    pod_budgets: &HashMap<(String, String), f64>,
+) -> Option<(Scope, f64)> {
+    let get = |key: &str| attributes.get(key).map(ToString::to_string);
+    if let (Some(namespace), Some(name)) =
+        (get("kubernetes_pod_namespace"), get("kubernetes_pod_name"))
+    {
+        if let Some(budget) = pod_budgets.get(&(namespace.clone(), name.clone())) {
+            let scope = vec![
+                (String::from("kubernetes_pod_namespace"), namespace),
+                (String::from("kubernetes_pod_name"), name),
+            ];
+            return Some((scope, *budget));
+        }
+    }
+    let budget = get(BUDGET_LABEL_ATTRIBUTE).and_then(|value| parse_budget_watts(&value))?;
+    let name = get("container_names")?;
+    Some((vec![(String::from("container_name"), name)], budget))
+}
+
+/// Average power of a scope compared with its budget.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetEvaluation {
+    /// Average power over the window, in microwatts
+    pub average: f64,
+    /// Budget, in microwatts
+    pub budget: f64,
+    pub timestamp: Duration,
+    pub violating: bool,
+    /// True if the scope wasn't over its budget at the previous measurement
+    pub started: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ScopeHistory {
+    samples: VecDeque<(Duration, f64)>,
+    violating: bool,
+}
+
+/// Rolling average power of the pods and containers that have a budget.
+#[derive(Debug, Clone)]
+pub struct PowerBudgets {
+    /// Duration power is averaged over
+    pub window: Duration,
+    /// URL to POST a notification to when a violation starts
+    pub webhook: Option<String>,
+    history: BTreeMap<Scope, ScopeHistory>,
+    /// Power and budget of the scopes during the current measurement
+    current: BTreeMap<Scope, (f64, f64, Duration)>,
+}
+
+impl PowerBudgets {
+    pub fn new(window: Duration, webhook: Option<String>) -> PowerBudgets {
+        PowerBudgets {
+            window,
+            webhook,
+            history: BTreeMap::new(),
+            current: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the power of a process to its scope, whose budget is `budget_watts`.
+    pub fn add_process_power(
+        &mut self,
+        scope: Scope,
+        budget_watts: f64,
+        microwatts: f64,
+        timestamp: Duration,
+    ) {
+        let entry = self
+            .current
+            .entry(scope)
+            .or_insert((0.0, budget_watts * 1e6, timestamp));
+        entry.0 += microwatts;
+        entry.2 = entry.2.max(timestamp);
+    }
+
+    /// Ends the current measurement: adds the power of each scope to its
+    /// window, forgets the scopes that have no process anymore, and returns the
+    /// evaluation of each scope.
+    pub fn flush(&mut self) -> Vec<(Scope, BudgetEvaluation)> {
+        let current = std::mem::take(&mut self.current);
+        self.history.retain(|scope, _| current.contains_key(scope));
+        let mut res = vec![];
+        for (scope, (power, budget, timestamp)) in current {
+            let history = self.history.entry(scope.clone()).or_default();
+            history.samples.push_back((timestamp, power));
+            while history
+                .samples
+                .front()
+                .is_some_and(|(t, _)| *t + self.window < timestamp)
+            {
+                history.samples.pop_front();
+            }
+            let average =
+                history.samples.iter().map(|(_, p)| p).sum::<f64>() / history.samples.len() as f64;
+            let violating = average > budget;
+            let started = violating && !history.violating;
+            history.violating = violating;
+            res.push((
+                scope,
+                BudgetEvaluation {
+                    average,
+                    budget,
+                    timestamp,
+                    violating,
+                    started,
+                },
+            ));
+        }
+        res
+    }
+}
+
+/// Returns the JSON body of the notification of a budget violation.
+pub fn notification_payload(
+    hostname: &str,
+    scope: &Scope,
+    evaluation: &BudgetEvaluation,
+) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let scope: Vec<String> = scope
+        .iter()
+        .map(|(k, v)| format!("\"{}\":\"{}\"", escape(k), escape(v)))
+        .collect();
+    format!(
+        "{{\"host\":\"{}\",\"scope\":{{{}}},\"average_power_microwatts\":{},\"budget_microwatts\":{},\"timestamp\":{}}}",
+        escape(hostname),
+        scope.join(","),
+        evaluation.average as u64,
+        evaluation.budget as u64,
+        evaluation.timestamp.as_secs()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_violations() {
+        let mut budgets = PowerBudgets::new(Duration::from_secs(20), None);
+        let db = vec![(String::from("container_name"), String::from("db"))];
+        let mut evaluate = |secs: u64, microwatts: f64| {
+            budgets.add_process_power(
+                db.clone(),
+                10.0,
+                microwatts / 2.0,
+                Duration::from_secs(secs),
+            );
+            budgets.add_process_power(
+                db.clone(),
+                10.0,
+                microwatts / 2.0,
+                Duration::from_secs(secs),
+            );
+            budgets.flush().remove(0).1
+        };
+        assert!(!evaluate(0, 8e6).violating);
+        // a spike is averaged with the window
+        let spike = evaluate(10, 11e6);
+        assert_eq!(spike.average, 9.5e6);
+        assert!(!spike.violating);
+        let over = evaluate(20, 14e6);
+        assert!(over.violating && over.started);
+        // the first sample is out of the window
+        let still = evaluate(30, 11e6);
+        assert_eq!(still.average, 12e6);
+        assert!(still.violating && !still.started);
+        assert_eq!(still.budget, 10e6);
+    }
+
+    #[test]
+    fn scopes_of_processes() {
+        let mut pod_budgets = HashMap::new();
+        pod_budgets.insert((String::from("prod"), String::from("api-1")), 25.0);
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("container_names"), String::from("k8s_api"));
+        attributes.insert(
+            String::from("kubernetes_pod_namespace"),
+            String::from("prod"),
+        );
+        attributes.insert(String::from("kubernetes_pod_name"), String::from("api-1"));
+        let (scope, budget) = budget_scope(&attributes, &pod_budgets).unwrap();
+        assert_eq!(
+            scope[1],
+            (String::from("kubernetes_pod_name"), String::from("api-1"))
+        );
+        assert_eq!(budget, 25.0);
+
+        attributes.insert(String::from("kubernetes_pod_name"), String::from("api-2"));
+        assert_eq!(budget_scope(&attributes, &pod_budgets), None);
+        attributes.insert(String::from(BUDGET_LABEL_ATTRIBUTE), String::from("5"));
+        let (scope, budget) = budget_scope(&attributes, &pod_budgets).unwrap();
+        assert_eq!(
+            scope,
+            vec![(String::from("container_name"), String::from("k8s_api"))]
+        );
+        assert_eq!(budget, 5.0);
+
+        assert_eq!(parse_budget_watts("-3"), None);
+        assert_eq!(parse_budget_watts(" 12.5 "), Some(12.5));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,125 @@
+//! # Power slope: rate of change of the power of the host and sockets
+//!
+//! [PowerSlopes] keeps the power measured for each scope (the host, each
+//! socket) over a sliding window and computes its slope, the least squares
+//! fit of the power over time, in microwatts per second. Fitting every
+//! measurement of the window rather than comparing its ends means that a
+//! single spike barely moves the slope, while a sustained increase such as a
+//! failing fan or a runaway process shows up as a steadily positive value that
+//! a simple threshold alert can catch.
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Power of several scopes over a sliding window, identified by a key.
+#[derive(Debug, Clone)]
+pub struct PowerSlopes {
+    window: Duration,
+    samples: HashMap<String, VecDeque<(Duration, f64)>>,
+}
+
+impl PowerSlopes {
+    pub fn new(window: Duration) -> PowerSlopes {
+        PowerSlopes {
+            window,
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Adds the power of the scope `key`, in microwatts, measured at
+    /// `timestamp` (time since the epoch), and returns its slope over the
+    /// window in microwatts per second. The slope is only returned once the
+    /// measurements of the scope cover at least half of the window, so that it
+    /// isn't computed from two close measurements right after start.
+    pub fn add(&mut self, key: &str, timestamp: Duration, microwatts: f64) -> Option<f64> {
+        let samples = self.samples.entry(key.to_string()).or_default();
+        if samples.back().is_some_and(|(last, _)| *last >= timestamp) {
+            // the power didn't get a new measurement since the last call
+            return slope(samples);
+        }
+        samples.push_back((timestamp, microwatts));
+        while samples
+            .front()
+            .is_some_and(|(first, _)| timestamp.saturating_sub(*first) > self.window)
+        {
+            samples.pop_front();
+        }
+        let covered = timestamp.saturating_sub(samples.front()?.0);
+        if covered < self.window / 2 {
+            return None;
+        }
+        slope(samples)
+    }
+}
+
+/// Returns the least squares slope of the samples, in microwatts per second.
+fn slope(samples: &VecDeque<(Duration, f64)>) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let origin = samples.front()?.0;
+    let n = samples.len() as f64;
+    let points = samples
+        .iter()
+        .map(|(t, p)| ((*t - origin).as_secs_f64(), *p));
+    let (sum_t, sum_p) = points
+        .clone()
+        .fold((0.0, 0.0), |(st, sp), (t, p)| (st + t, sp + p));
+    let (mean_t, mean_p) = (sum_t / n, sum_p / n);
+    let (covariance, variance) = points.fold((0.0, 0.0), |(c, v), (t, p)| {
+        (c + (t - mean_t) * (p - mean_p), v + (t - mean_t).powi(2))
+    });
+    if variance == 0.0 {
+        return None;
+    }
+    Some(covariance / variance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slope_over_window() {
+        let mut slopes = PowerSlopes::new(Duration::from_secs(10));
+        let at = |s: u64| Duration::from_secs(1_700_000_000 + s);
+        assert_eq!(slopes.add("host", at(0), 50e6), None);
+        assert_eq!(slopes.add("host", at(2), 52e6), None);
+        // 1 W per second, once half of the window is covered
+        let slope = slopes.add("host", at(5), 55e6).unwrap();
+        assert!((slope - 1e6).abs() < 1.0, "{slope}");
+        // a repeated measurement doesn't count twice
+        assert_eq!(slopes.add("host", at(5), 55e6), Some(slope));
+        // scopes are independent
+        assert_eq!(slopes.add("socket:0", at(5), 20e6), None);
+
+        // a lone spike barely moves the slope of a flat power
+        let mut slopes = PowerSlopes::new(Duration::from_secs(10));
+        for s in 0..10 {
+            let power = if s == 5 { 80e6 } else { 50e6 };
+            slopes.add("host", at(s), power);
+        }
+        let flat = slopes.add("host", at(10), 50e6).unwrap();
+        assert!(flat.abs() < 1e6, "{flat}");
+
+        // older measurements leave the window
+        for s in 11..=20 {
+            slopes.add("host", at(s), 50e6 + (s - 10) as f64 * 3e6);
+        }
+        let rising = slopes.add("host", at(21), 83e6).unwrap();
+        assert!((rising - 3e6).abs() < 1e5, "{rising}");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
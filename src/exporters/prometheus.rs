@@ -5,17 +5,26 @@
 //! [scrape](https://prometheus.io/docs/prometheus/latest/getting_started).
 
 use super::utils;
-use crate::exporters::{Exporter, MetricGenerator, MetricValueType};
+use crate::exporters::collectors::{collector_of, Selection};
+use crate::exporters::request_energy;
+use crate::exporters::{AttributeValue, Exporter, Metric, MetricGenerator, MetricValueType};
 use crate::sensors::utils::current_system_time_since_epoch;
 use crate::sensors::{Sensor, Topology};
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
+use flate2::{write::GzEncoder, Compression};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE,
+};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Client, Request, Response, Server, Uri};
+use protobuf::{CodedOutputStream, ProtobufResult};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::convert::Infallible;
 use std::{
     collections::HashMap,
     fmt::Write,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    io::{self, Write as _},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -23,6 +32,29 @@ use std::{
 /// Default ipv4/ipv6 address to expose the service is any
 const DEFAULT_IP_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
 
+/// Maximum number of pending connections on the metrics endpoint
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Content type of the text exposition format
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Media type of the protobuf exposition format, delimited MetricFamily messages
+/// of the [Prometheus client model](https://github.com/prometheus/client_model)
+const PROTOBUF_MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// Path of the custom metrics API, as served by a Kubernetes metrics adapter
+const CUSTOM_METRICS_PATH: &str = "/apis/custom.metrics.k8s.io/v1beta1";
+
+/// Metrics served on the custom metrics API, by name on the API, from the
+/// metrics in microwatts of the same name.
+const CUSTOM_METRICS: [&str; 3] = [
+    "scaph_host_power",
+    "scaph_host_power_cap",
+    "scaph_host_power_headroom",
+];
+
 /// Exporter that exposes metrics to an HTTP endpoint
 /// matching the Prometheus.io metrics format.
 pub struct PrometheusExporter {
@@ -42,9 +74,43 @@ pub struct ExporterArgs {
     #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
 
+    /// Let several processes listen on the same port (SO_REUSEPORT), so that a new
+    /// scaphandre can be started before the old one is stopped
+    #[cfg(unix)]
+    #[arg(long)]
+    pub reuse_port: bool,
+
+    /// Close HTTP connections after each response instead of keeping them alive
+    #[arg(long)]
+    pub no_keepalive: bool,
+
+    /// Interval of the TCP keepalive probes on connections to the metrics endpoint,
+    /// in seconds (no probes if not set)
+    #[arg(long, value_name = "SECONDS")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Time given to clients to send the headers of a request, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub header_read_timeout: u64,
+
     #[arg(short, long, default_value_t = String::from("metrics"))]
     pub suffix: String,
 
+    /// Measure again on each scrape whose last measurement is older than --min-refresh-interval,
+    /// instead of when the previous request is more than 2 seconds old
+    #[arg(long)]
+    pub refresh_on_scrape: bool,
+
+    /// Minimum time between two measurements with --refresh-on-scrape, in seconds: scrapes
+    /// coming sooner get metrics from the last measurement
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 2,
+        requires = "refresh_on_scrape"
+    )]
+    pub min_refresh_interval: u64,
+
     /// Apply labels to metrics of processes that look like a Qemu/KVM virtual machine
     #[arg(long)]
     pub qemu: bool,
@@ -52,6 +118,41 @@ pub struct ExporterArgs {
     /// Apply labels to metrics of processes running as containers
     #[arg(long)]
     pub containers: bool,
+
+    #[command(flatten)]
+    pub generator: utils::MetricGeneratorArgs,
+
+    /// Accept the wall power of the host in watts, pushed by an external meter with
+    /// PUT /reference-power, to publish the error of the computed host power
+    #[arg(long, conflicts_with = "reference_power_file")]
+    #[cfg_attr(feature = "smartplug", arg(conflicts_with = "reference_smartplug"))]
+    pub reference_power_push: bool,
+
+    #[command(flatten)]
+    pub naming_args: utils::MetricNamingArgs,
+
+    /// Metrics endpoint of another scaphandre to scrape and expose along the local metrics,
+    /// with a host label telling them apart (e.g. http://node2:8080/metrics). Can be repeated
+    #[arg(long = "peer", value_name = "URL")]
+    pub peers: Vec<String>,
+
+    /// Maximum time to wait for a peer to answer, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    pub peer_timeout: u64,
+
+    /// Also serve the power, cap and headroom of the node in the format of the Kubernetes
+    /// custom metrics API, under /apis/custom.metrics.k8s.io/v1beta1/nodes/NODE/
+    #[arg(long)]
+    pub custom_metrics: bool,
+
+    /// Name of the node on the custom metrics API (the hostname by default)
+    #[arg(long, value_name = "NAME", requires = "custom_metrics")]
+    pub node_name: Option<String>,
+
+    /// Serve the energy consumed by a process during a request on /requests, given
+    /// pid, start and end (seconds since the epoch) query parameters
+    #[arg(long)]
+    pub request_energy: bool,
 }
 
 impl PrometheusExporter {
@@ -79,36 +180,183 @@ impl Exporter for PrometheusExporter {
         );
         println!("Press CTRL-C to stop scaphandre");
         let socket_addr = SocketAddr::new(self.args.address, self.args.port);
-        let metric_generator = MetricGenerator::new(
+        #[cfg(unix)]
+        let reuse_port = self.args.reuse_port;
+        #[cfg(not(unix))]
+        let reuse_port = false;
+        let listener = match bind_listener(socket_addr, reuse_port) {
+            Ok(listener) => listener,
+            Err(e) => panic!("Couldn't listen on {socket_addr}: {e}"),
+        };
+        let mut metric_generator = MetricGenerator::new(
             self.topo.clone(), // improvement possible here: avoid cloning by adding a lifetime param to MetricGenerator
             self.hostname.clone(),
             self.args.qemu,
             self.args.containers,
         );
-        run_server(socket_addr, metric_generator, &self.args.suffix);
+        self.args.generator.apply(&mut metric_generator);
+        metric_generator.set_metric_naming(self.args.naming_args.metric_naming);
+        if self.args.request_energy {
+            metric_generator.set_process_power_history(request_energy::DEFAULT_RETENTION);
+        }
+        let peers = self
+            .args
+            .peers
+            .iter()
+            .map(|peer| {
+                peer.parse::<Uri>()
+                    .unwrap_or_else(|e| panic!("Invalid peer URL {peer}: {e}"))
+            })
+            .collect();
+        run_server(
+            listener,
+            metric_generator,
+            &self.args.suffix,
+            RefreshPolicy::from_args(&self.args),
+            &self.args,
+            peers,
+            self.hostname.clone(),
+        );
     }
 
     fn kind(&self) -> &str {
         "prometheus"
     }
+
+    /// Checks that the metrics endpoint can listen on its address and that the
+    /// peer URLs are valid.
+    fn validate(&mut self) -> Result<String, String> {
+        for peer in &self.args.peers {
+            peer.parse::<Uri>()
+                .map_err(|e| format!("invalid peer URL {peer}: {e}"))?;
+        }
+        let socket_addr = SocketAddr::new(self.args.address, self.args.port);
+        #[cfg(unix)]
+        let reuse_port = self.args.reuse_port;
+        #[cfg(not(unix))]
+        let reuse_port = false;
+        bind_listener(socket_addr, reuse_port)
+            .map(|_| format!("can listen on {socket_addr}"))
+            .map_err(|e| format!("can't listen on {socket_addr}: {e}"))
+    }
+}
+
+/// Time after which a request measures again if no other request came in between.
+const IDLE_REFRESH_PERIOD: Duration = Duration::from_secs(2);
+
+/// When a request to the metrics endpoint measures again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RefreshPolicy {
+    /// When the previous request, which measured or not, is more than
+    /// [IDLE_REFRESH_PERIOD] old
+    AfterIdle,
+    /// When the last measurement is at least this old (--refresh-on-scrape)
+    OnScrape(Duration),
+}
+
+impl RefreshPolicy {
+    fn from_args(args: &ExporterArgs) -> RefreshPolicy {
+        match args.refresh_on_scrape {
+            true => RefreshPolicy::OnScrape(Duration::from_secs(args.min_refresh_interval)),
+            false => RefreshPolicy::AfterIdle,
+        }
+    }
+
+    /// Returns true if a request coming at `now` should measure again, `last`
+    /// being the time of the previous request or measurement, which it updates.
+    fn should_refresh(&self, last: &mut Duration, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(*last);
+        match self {
+            RefreshPolicy::AfterIdle => {
+                *last = now;
+                elapsed > IDLE_REFRESH_PERIOD
+            }
+            RefreshPolicy::OnScrape(min_interval) => {
+                let refresh = elapsed >= *min_interval;
+                if refresh {
+                    *last = now;
+                }
+                refresh
+            }
+        }
+    }
 }
 
 /// Contains a mutex holding a MetricGenerator.
 /// Used to pass the topology data from one http worker to another.
 struct PowerMetrics {
+    /// Time of the last request or measurement, see [RefreshPolicy]
     last_request: Mutex<Duration>,
     metric_generator: Mutex<MetricGenerator>,
+    refresh_policy: RefreshPolicy,
+    /// Metrics endpoints of other scaphandre instances to aggregate
+    peers: Vec<Uri>,
+    peer_timeout: Duration,
+    hostname: String,
+    /// Name of the node on the custom metrics API, if served
+    custom_metrics_node: Option<String>,
+    /// Serve the energy of requests on /requests
+    request_energy: bool,
+    /// Accept the values of the external operands of derived metrics on /values/
+    derived_metrics: bool,
+    /// Accept the wall power measured by an external meter on /reference-power
+    reference_power_push: bool,
+}
+
+/// Opens the listening socket of the metrics endpoint.
+///
+/// Binding the unspecified IPv6 address (`::`) accepts IPv4 connections too,
+/// whatever the net.ipv6.bindv6only setting of the host.
+fn bind_listener(socket_addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(socket_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if socket_addr.is_ipv6() {
+        socket.set_only_v6(!socket_addr.ip().is_unspecified())?;
+    }
+    // same as std and tokio listeners, so that a restart doesn't wait for TIME_WAIT connections
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        warn!("SO_REUSEPORT is not available on this platform, ignoring it");
+    }
+    socket.bind(&socket_addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
 }
 
 #[tokio::main]
 async fn run_server(
-    socket_addr: SocketAddr,
+    listener: TcpListener,
     metric_generator: MetricGenerator,
     endpoint_suffix: &str,
+    refresh_policy: RefreshPolicy,
+    args: &ExporterArgs,
+    peers: Vec<Uri>,
+    hostname: String,
 ) {
     let power_metrics = PowerMetrics {
         last_request: Mutex::new(Duration::new(0, 0)),
         metric_generator: Mutex::new(metric_generator),
+        refresh_policy,
+        peers,
+        peer_timeout: Duration::from_secs(args.peer_timeout),
+        custom_metrics_node: if args.custom_metrics {
+            Some(args.node_name.clone().unwrap_or_else(|| hostname.clone()))
+        } else {
+            None
+        },
+        hostname,
+        request_energy: args.request_energy,
+        derived_metrics: args.generator.derived_metrics.is_some(),
+        reference_power_push: args.reference_power_push,
     };
     let context = Arc::new(power_metrics);
     let make_svc = make_service_fn(move |_| {
@@ -120,8 +368,18 @@ async fn run_server(
             }))
         }
     });
-    let server = Server::bind(&socket_addr);
-    let res = server.serve(make_svc);
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("server error: {}", e);
+            return;
+        }
+    };
+    let res = server
+        .http1_keepalive(!args.no_keepalive)
+        .http1_header_read_timeout(Duration::from_secs(args.header_read_timeout))
+        .tcp_keepalive(args.tcp_keepalive.map(Duration::from_secs))
+        .serve(make_svc);
     let (tx, rx) = tokio::sync::oneshot::channel::<()>();
     let graceful = res.with_graceful_shutdown(async {
         rx.await.ok();
@@ -150,6 +408,256 @@ fn push_metric(
     body
 }
 
+/// Renders metrics in the Prometheus exposition format, with the HELP and TYPE
+/// lines of each metric family once. The text format has no creation time: the
+/// one of the counters is only sent in the protobuf format.
+pub fn render_metrics(metrics: Vec<Metric>) -> String {
+    let mut body = String::new();
+    let mut metrics_pushed: Vec<String> = vec![];
+
+    // Send all data
+    for msg in metrics {
+        let mut attributes: Option<&HashMap<String, AttributeValue>> = None;
+        if !msg.attributes.is_empty() {
+            attributes = Some(&msg.attributes);
+        }
+
+        let value = match msg.metric_value {
+            // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
+            // MetricValueType::Float(value) => event.set_metric_f(value),
+            //MetricValueType::FloatDouble(value) => value.to_string(),
+            MetricValueType::IntUnsigned(value) => value.to_string(),
+            MetricValueType::Text(ref value) => value.to_string(),
+        };
+
+        let mut should_i_add_help = true;
+
+        if metrics_pushed.contains(&msg.name) {
+            should_i_add_help = false;
+        } else {
+            metrics_pushed.insert(0, msg.name.clone());
+        }
+
+        body = push_metric(
+            body,
+            msg.description.clone(),
+            msg.metric_type.clone(),
+            msg.name.clone(),
+            utils::format_prometheus_metric(&msg.name, &value, attributes),
+            should_i_add_help,
+        );
+    }
+    body
+}
+
+/// Appends the protobuf fields written by `write` to `message`.
+fn write_message(
+    message: &mut Vec<u8>,
+    write: impl FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>,
+) {
+    let mut os = CodedOutputStream::vec(message);
+    write(&mut os)
+        .and_then(|_| os.flush())
+        .expect("writing a protobuf message to a vector shouldn't fail");
+}
+
+/// Returns the protobuf message written by `write`.
+fn encode_message(write: impl FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>) -> Vec<u8> {
+    let mut message = vec![];
+    write_message(&mut message, write);
+    message
+}
+
+/// Encodes a metric as a Metric message of the Prometheus client model.
+fn encode_metric(metric: &Metric, value: f64, created: Option<Duration>) -> Vec<u8> {
+    encode_message(|os| {
+        for (name, label_value) in metric.sorted_attributes() {
+            let label = encode_message(|os| {
+                os.write_string(1, name)?;
+                os.write_string(2, &label_value.to_string())
+            });
+            os.write_bytes(1, &label)?;
+        }
+        match metric.metric_type.as_str() {
+            "counter" => {
+                let counter = encode_message(|os| {
+                    os.write_double(1, value)?;
+                    if let Some(created) = created {
+                        let timestamp = encode_message(|os| {
+                            os.write_int64(1, created.as_secs() as i64)?;
+                            os.write_int32(2, created.subsec_nanos() as i32)
+                        });
+                        os.write_bytes(3, &timestamp)?;
+                    }
+                    Ok(())
+                });
+                os.write_bytes(3, &counter)
+            }
+            "gauge" => os.write_bytes(2, &encode_message(|os| os.write_double(1, value))),
+            _ => os.write_bytes(5, &encode_message(|os| os.write_double(1, value))),
+        }
+    })
+}
+
+/// Renders metrics in the protobuf exposition format: a MetricFamily message
+/// per metric name, each one preceded by its length.
+pub fn render_metrics_protobuf(
+    metrics: Vec<Metric>,
+    metric_generator: &MetricGenerator,
+) -> Vec<u8> {
+    let mut families: Vec<Vec<u8>> = vec![];
+    let mut family_index: HashMap<String, usize> = HashMap::new();
+    for metric in &metrics {
+        let Ok(value) = metric.metric_value.to_string().trim().parse::<f64>() else {
+            continue;
+        };
+        let index = *family_index.entry(metric.name.clone()).or_insert_with(|| {
+            families.push(encode_message(|os| {
+                os.write_string(1, &metric.name)?;
+                os.write_string(2, &metric.description)?;
+                let metric_type = match metric.metric_type.as_str() {
+                    "counter" => 0,
+                    "gauge" => 1,
+                    _ => 3,
+                };
+                os.write_enum(3, metric_type)
+            }));
+            families.len() - 1
+        });
+        let encoded = encode_metric(metric, value, metric_generator.counter_created_time(metric));
+        write_message(&mut families[index], |os| os.write_bytes(4, &encoded));
+    }
+    encode_message(|os| {
+        for family in &families {
+            os.write_raw_varint32(family.len() as u32)?;
+            os.write_raw_bytes(family)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns the highest quality given by `header`, an Accept or Accept-Encoding
+/// header, to the values for which `matches` is true (0 if there is none).
+fn accepted_quality(
+    header: Option<&HeaderValue>,
+    matches: impl Fn(&str, &[(&str, &str)]) -> bool,
+) -> f32 {
+    let Some(header) = header.and_then(|h| h.to_str().ok()) else {
+        return 0.0;
+    };
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let value = parts.next()?;
+            let params: Vec<(&str, &str)> = parts
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (k.trim(), v.trim().trim_matches('"')))
+                .collect();
+            if !matches(value, &params) {
+                return None;
+            }
+            Some(
+                params
+                    .iter()
+                    .find(|(k, _)| *k == "q")
+                    .map_or(1.0, |(_, q)| q.parse().unwrap_or(0.0)),
+            )
+        })
+        .fold(0.0, f32::max)
+}
+
+/// Tells if the scraper prefers the protobuf exposition format to the text one.
+fn accepts_protobuf(headers: &HeaderMap) -> bool {
+    let protobuf = accepted_quality(headers.get(ACCEPT), |value, params| {
+        value.eq_ignore_ascii_case(PROTOBUF_MEDIA_TYPE)
+            && params.contains(&("proto", "io.prometheus.client.MetricFamily"))
+            && params.contains(&("encoding", "delimited"))
+    });
+    let text = accepted_quality(headers.get(ACCEPT), |value, _| {
+        ["text/plain", "text/*", "*/*"].contains(&value)
+    });
+    protobuf > 0.0 && protobuf >= text
+}
+
+/// Returns the response to a scrape, gzipped if the scraper accepts it.
+fn metrics_response(
+    body: Vec<u8>,
+    content_type: &'static str,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let gzip = accepted_quality(headers.get(ACCEPT_ENCODING), |value, _| {
+        value.eq_ignore_ascii_case("gzip")
+    }) > 0.0;
+    let gzipped = if gzip {
+        let mut encoder = GzEncoder::new(vec![], Compression::fast());
+        encoder.write_all(&body).and_then(|_| encoder.finish()).ok()
+    } else {
+        None
+    };
+    let mut response = match gzipped {
+        Some(gzipped) => {
+            let mut response = Response::new(Body::from(gzipped));
+            response
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            response
+        }
+        None => Response::new(Body::from(body)),
+    };
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Measures again if the last measurement is old enough, generates the metrics
+/// and returns what `f` makes of them, or None if the generator is unavailable.
+fn with_metrics<T>(
+    context: &PowerMetrics,
+    f: impl FnOnce(Vec<Metric>, &MetricGenerator) -> T,
+) -> Option<T> {
+    let now = current_system_time_since_epoch();
+    match context.last_request.lock() {
+        Ok(mut last_request) => {
+            match context.metric_generator.lock() {
+                Ok(mut metric_generator) => {
+                    // Only measure again if the last measurement is old enough, so that
+                    // close scrapes (several Prometheus servers for instance) don't
+                    // produce measurements over a too short period.
+                    if context
+                        .refresh_policy
+                        .should_refresh(&mut last_request, now)
+                    {
+                        info!(
+                            "{}: Refresh topology",
+                            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+                        );
+                        metric_generator.topology.refresh();
+                    }
+
+                    info!("{}: Refresh data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
+
+                    metric_generator.gen_all_metrics();
+
+                    let metrics = metric_generator.pop_metrics();
+                    Some(f(metrics, &metric_generator))
+                }
+                Err(e) => {
+                    error!("Error while locking metric_generator: {e:?}");
+                    error!("Error while locking metric_generator: {}", e.to_string());
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error in show_metrics : {e:?}");
+            error!("Error details : {}", e.to_string());
+            None
+        }
+    }
+}
+
 /// Handles requests and returns data formated for Prometheus.
 async fn show_metrics(
     req: Request<Body>,
@@ -159,82 +667,722 @@ async fn show_metrics(
     trace!("{}", req.uri());
     let mut body = String::new();
     if req.uri().path() == format!("/{}", &suffix) {
-        let now = current_system_time_since_epoch();
-        match context.last_request.lock() {
-            Ok(mut last_request) => {
-                match context.metric_generator.lock() {
-                    Ok(mut metric_generator) => {
-                        if now - (*last_request) > Duration::from_secs(2) {
-                            {
-                                info!(
-                                    "{}: Refresh topology",
-                                    Utc::now().format("%Y-%m-%dT%H:%M:%S")
-                                );
-                                metric_generator
-                                    .topology
-                                    .proc_tracker
-                                    .clean_terminated_process_records_vectors();
-                                metric_generator.topology.refresh();
-                            }
-                        }
-                        *last_request = now;
-
-                        info!("{}: Refresh data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
-
-                        metric_generator.gen_all_metrics();
-
-                        let mut metrics_pushed: Vec<String> = vec![];
-
-                        // Send all data
-                        for msg in metric_generator.pop_metrics() {
-                            let mut attributes: Option<&HashMap<String, String>> = None;
-                            if !msg.attributes.is_empty() {
-                                attributes = Some(&msg.attributes);
-                            }
-
-                            let value = match msg.metric_value {
-                                // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
-                                // MetricValueType::Float(value) => event.set_metric_f(value),
-                                //MetricValueType::FloatDouble(value) => value.to_string(),
-                                MetricValueType::IntUnsigned(value) => value.to_string(),
-                                MetricValueType::Text(ref value) => value.to_string(),
-                            };
-
-                            let mut should_i_add_help = true;
-
-                            if metrics_pushed.contains(&msg.name) {
-                                should_i_add_help = false;
-                            } else {
-                                metrics_pushed.insert(0, msg.name.clone());
-                            }
-
-                            body = push_metric(
-                                body,
-                                msg.description.clone(),
-                                msg.metric_type.clone(),
-                                msg.name.clone(),
-                                utils::format_prometheus_metric(&msg.name, &value, attributes),
-                                should_i_add_help,
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error while locking metric_generator: {e:?}");
-                        error!("Error while locking metric_generator: {}", e.to_string());
-                    }
-                }
-            }
+        let selection = match Selection::from_query(req.uri().query().unwrap_or("")) {
+            Ok(selection) => selection,
             Err(e) => {
-                error!("Error in show_metrics : {e:?}");
-                error!("Error details : {}", e.to_string());
+                let mut response = Response::new(Body::from(e));
+                *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+                return Ok(response);
             }
+        };
+        // expositions of peers are merged as text
+        if context.peers.is_empty() && accepts_protobuf(req.headers()) {
+            let body = with_metrics(&context, |metrics, metric_generator| {
+                render_metrics_protobuf(selection.filter(metrics), metric_generator)
+            })
+            .unwrap_or_default();
+            return Ok(metrics_response(body, PROTOBUF_CONTENT_TYPE, req.headers()));
+        }
+        if let Some(metrics) = with_metrics(&context, |metrics, _| {
+            render_metrics(selection.filter(metrics))
+        }) {
+            body = metrics;
         }
+        if !context.peers.is_empty() {
+            body = aggregate_peers(body, req.uri().query(), &selection, &context).await;
+        }
+        return Ok(metrics_response(
+            body.into_bytes(),
+            TEXT_CONTENT_TYPE,
+            req.headers(),
+        ));
+    } else if let (Some(node), Some(path)) = (
+        &context.custom_metrics_node,
+        req.uri().path().strip_prefix(CUSTOM_METRICS_PATH),
+    ) {
+        return Ok(custom_metrics_response(path, node, &context));
+    } else if let (true, Some(name)) = (
+        context.derived_metrics
+            && (req.method() == hyper::Method::PUT || req.method() == hyper::Method::POST),
+        req.uri().path().strip_prefix("/values/"),
+    ) {
+        let name = name.to_string();
+        let value = hyper::body::to_bytes(req.into_body()).await;
+        return Ok(pushed_value_response(
+            value.ok().as_deref(),
+            &context,
+            |metric_generator, value| metric_generator.set_external_value(&name, value),
+        ));
+    } else if context.reference_power_push
+        && (req.method() == hyper::Method::PUT || req.method() == hyper::Method::POST)
+        && req.uri().path() == "/reference-power"
+    {
+        let watts = hyper::body::to_bytes(req.into_body()).await;
+        return Ok(pushed_value_response(
+            watts.ok().as_deref(),
+            &context,
+            MetricGenerator::set_reference_power,
+        ));
+    } else if context.request_energy && req.uri().path() == "/requests" {
+        return Ok(request_energy_response(
+            req.uri().query().unwrap_or(""),
+            &context,
+        ));
     } else {
         let _ = write!(body, "<a href=\"https://github.com/hubblo-org/scaphandre/\">Scaphandre's</a> prometheus exporter here. Metrics available on <a href=\"/{suffix}\">/{suffix}</a>");
     }
     Ok(Response::new(body.into()))
 }
 
+/// Answers a request to the custom metrics API, `path` being relative to its
+/// root: the list of the metrics, or the value of one of them for the node.
+fn custom_metrics_response(path: &str, node: &str, context: &PowerMetrics) -> Response<Body> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let body = match segments.as_slice() {
+        [] => Some(custom_metrics_resource_list()),
+        ["nodes", name, metric] if *name == node || *name == "*" => {
+            with_metrics(context, |metrics, _| {
+                custom_metric_value_list(node, metric, &metrics)
+            })
+            .flatten()
+        }
+        _ => None,
+    };
+    match body {
+        Some(body) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        None => {
+            let mut response = Response::new(Body::from("not found"));
+            *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+/// Stores a value pushed as the `body` of the request, the value of an external
+/// operand of the derived metrics or the power of the reference meter, with `set`.
+fn pushed_value_response(
+    body: Option<&[u8]>,
+    context: &PowerMetrics,
+    set: impl FnOnce(&mut MetricGenerator, f64) -> Result<(), String>,
+) -> Response<Body> {
+    let value = body
+        .and_then(|body| std::str::from_utf8(body).ok())
+        .and_then(|body| body.trim().parse::<f64>().ok());
+    let result = match (value, context.metric_generator.lock()) {
+        (Some(value), Ok(mut metric_generator)) => set(&mut metric_generator, value),
+        (None, _) => Err(String::from("the body should be a number")),
+        (_, Err(e)) => Err(format!("couldn't lock metric_generator: {e}")),
+    };
+    match result {
+        Ok(()) => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = hyper::StatusCode::NO_CONTENT;
+            response
+        }
+        Err(e) => {
+            let mut response = Response::new(Body::from(e));
+            *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+            response
+        }
+    }
+}
+
+/// Answers a request for the energy of a process during a window, given by the
+/// pid, start and end parameters of `query`. The trace_id parameter, if any, is
+/// returned as is so that the answer can be matched with the request.
+fn request_energy_response(query: &str, context: &PowerMetrics) -> Response<Body> {
+    let params: HashMap<&str, &str> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect();
+    let pid = params.get("pid").and_then(|pid| pid.parse::<u32>().ok());
+    let start = params
+        .get("start")
+        .and_then(|t| request_energy::parse_timestamp(t));
+    let end = params
+        .get("end")
+        .and_then(|t| request_energy::parse_timestamp(t));
+    let (pid, start, end) = match (pid, start, end) {
+        (Some(pid), Some(start), Some(end)) => (pid, start, end),
+        _ => {
+            let mut response = Response::new(Body::from(
+                "pid, start and end (seconds since the epoch) parameters are required",
+            ));
+            *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+    // measure now if the window ended after the last measurement
+    let energy = with_metrics(context, |_, metric_generator| {
+        metric_generator.process_energy(pid, start, end)
+    })
+    .flatten();
+    match energy {
+        Some(energy) => {
+            let trace_id = params
+                .get("trace_id")
+                .map(|id| format!(",\"trace_id\":\"{}\"", id.replace(['\\', '"'], "")))
+                .unwrap_or_default();
+            let body = format!(
+                "{{\"pid\":{pid}{trace_id},\"start\":{},\"end\":{},\"energy_microjoules\":{},\"coverage_ratio\":{:.3}}}",
+                start.as_secs_f64(),
+                end.as_secs_f64(),
+                energy.energy_microjoules as u64,
+                energy.coverage_ratio
+            );
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        None => {
+            let mut response = Response::new(Body::from("no power measured for this process"));
+            *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+            response
+        }
+    }
+}
+
+/// Returns the APIResourceList of the custom metrics API, listing the metrics
+/// served for nodes.
+fn custom_metrics_resource_list() -> String {
+    let resources: Vec<String> = CUSTOM_METRICS
+        .iter()
+        .map(|metric| {
+            format!(
+                "{{\"name\":\"nodes/{metric}\",\"singularName\":\"\",\"namespaced\":false,\"kind\":\"MetricValueList\",\"verbs\":[\"get\"]}}"
+            )
+        })
+        .collect();
+    format!(
+        "{{\"kind\":\"APIResourceList\",\"apiVersion\":\"v1\",\"groupVersion\":\"custom.metrics.k8s.io/v1beta1\",\"resources\":[{}]}}",
+        resources.join(",")
+    )
+}
+
+/// Returns the MetricValueList of `metric` for the node, in watts with
+/// milliwatt precision, or None if it isn't one of [CUSTOM_METRICS] or hasn't
+/// been measured.
+fn custom_metric_value_list(node: &str, metric: &str, metrics: &[Metric]) -> Option<String> {
+    if !CUSTOM_METRICS.contains(&metric) {
+        return None;
+    }
+    let name = format!("{metric}_microwatts");
+    // the host power comes first, before the PKG+DRAM one when PSYS is used
+    let sample = metrics.iter().find(|m| m.name == name)?;
+    let microwatts = match &sample.metric_value {
+        MetricValueType::Text(value) => value.trim().parse::<f64>().ok()?,
+        MetricValueType::IntUnsigned(value) => *value as f64,
+    };
+    let timestamp = Utc
+        .timestamp_opt(sample.timestamp.as_secs() as i64, 0)
+        .single()?
+        .format("%Y-%m-%dT%H:%M:%SZ");
+    Some(format!(
+        "{{\"kind\":\"MetricValueList\",\"apiVersion\":\"custom.metrics.k8s.io/v1beta1\",\"metadata\":{{}},\"items\":[{{\"describedObject\":{{\"kind\":\"Node\",\"apiVersion\":\"/v1\",\"name\":\"{}\"}},\"metricName\":\"{metric}\",\"timestamp\":\"{timestamp}\",\"value\":\"{}m\"}}]}}",
+        node.replace('\\', "\\\\").replace('"', "\\\""),
+        (microwatts / 1000.0).round() as i64
+    ))
+}
+
+/// Gets the metrics exposed by the scaphandre at `uri`.
+async fn fetch_peer(uri: &Uri, timeout: Duration) -> Result<String, String> {
+    let request = Client::new().get(uri.clone());
+    let response = match tokio::time::timeout(timeout, request).await {
+        Ok(response) => response.map_err(|e| e.to_string())?,
+        Err(_) => return Err(String::from("timed out")),
+    };
+    if !response.status().is_success() {
+        return Err(format!("got HTTP status {}", response.status()));
+    }
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(body.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Scrapes the peers and returns the local metrics in `body` merged with theirs,
+/// each sample labeled with the host it comes from. The peers are given the
+/// `query` of the scrape, to publish the same collectors.
+async fn aggregate_peers(
+    body: String,
+    query: Option<&str>,
+    selection: &Selection,
+    context: &PowerMetrics,
+) -> String {
+    let mut peers_up = String::from(
+        "# HELP scaph_peer_up 1 if the last scrape of the peer succeeded, 0 otherwise\n\
+         # TYPE scaph_peer_up gauge\n",
+    );
+    let mut sources = vec![(context.hostname.clone(), body)];
+    for peer in &context.peers {
+        let host = peer.host().unwrap_or_default().to_string();
+        let mut labels = HashMap::new();
+        labels.insert(String::from("host"), host.clone());
+        let uri = match query {
+            Some(query) => with_query(peer, query),
+            None => peer.clone(),
+        };
+        match fetch_peer(&uri, context.peer_timeout).await {
+            Ok(text) => {
+                peers_up.push_str(&utils::format_prometheus_metric(
+                    "scaph_peer_up",
+                    "1",
+                    Some(&labels),
+                ));
+                sources.push((host, text));
+            }
+            Err(e) => {
+                warn!("Couldn't scrape peer {peer}: {e}");
+                peers_up.push_str(&utils::format_prometheus_metric(
+                    "scaph_peer_up",
+                    "0",
+                    Some(&labels),
+                ));
+            }
+        }
+    }
+    if selection.includes(collector_of("scaph_peer_up")) {
+        sources.push((context.hostname.clone(), peers_up));
+    }
+    merge_expositions(&sources)
+}
+
+/// Returns `uri` with `query` added to its own query string.
+fn with_query(uri: &Uri, query: &str) -> Uri {
+    let path = uri.path();
+    let path_and_query = match uri.query() {
+        Some(own) => format!("{path}?{own}&{query}"),
+        None => format!("{path}?{query}"),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+/// Merges metrics in the Prometheus text format coming from several hosts, given as
+/// (host, metrics) pairs. Samples get a host label and are grouped by metric, so
+/// that each metric has one HELP and TYPE line.
+fn merge_expositions(sources: &[(String, String)]) -> String {
+    // metric name, HELP and TYPE lines, samples
+    let mut families: Vec<(String, Vec<String>, Vec<String>)> = vec![];
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (host, text) in sources {
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (name, line, is_meta) = if let Some(comment) = line.strip_prefix('#') {
+                let mut fields = comment.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some("HELP" | "TYPE"), Some(name)) => {
+                        (name.to_string(), line.to_string(), true)
+                    }
+                    _ => continue,
+                }
+            } else {
+                match relabel_sample(line, host) {
+                    Some((name, line)) => (name, line, false),
+                    None => {
+                        debug!("Ignoring invalid metric line from {host}: {line}");
+                        continue;
+                    }
+                }
+            };
+            let i = *index.entry(name.clone()).or_insert_with(|| {
+                families.push((name, vec![], vec![]));
+                families.len() - 1
+            });
+            if !is_meta {
+                families[i].2.push(line);
+            } else if !families[i]
+                .1
+                .iter()
+                .any(|meta| meta.split_whitespace().nth(1) == line.split_whitespace().nth(1))
+            {
+                families[i].1.push(line);
+            }
+        }
+    }
+    let mut result = String::new();
+    for (_, meta, samples) in families {
+        for line in meta.iter().chain(samples.iter()) {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// Returns the metric name of a sample line and the line with its host label
+/// set to `host`, or None if the line is not a valid sample.
+fn relabel_sample(line: &str, host: &str) -> Option<(String, String)> {
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = &line[..name_end];
+    let mut labels = vec![format!(
+        "host=\"{}\"",
+        host.replace('\\', "\\\\").replace('"', "\\\"")
+    )];
+    let mut rest = &line[name_end..];
+    if let Some(inner) = rest.strip_prefix('{') {
+        // label values may hold commas, braces and escaped quotes
+        let mut in_quotes = false;
+        let mut escaped = false;
+        let mut start = 0;
+        let mut end = None;
+        for (i, c) in inner.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_quotes = !in_quotes;
+            } else if !in_quotes && (c == ',' || c == '}') {
+                let label = inner[start..i].trim();
+                if !label.is_empty() && label.split('=').next()?.trim() != "host" {
+                    labels.push(label.to_string());
+                }
+                start = i + 1;
+                if c == '}' {
+                    end = Some(i);
+                    break;
+                }
+            }
+        }
+        rest = &inner[end? + 1..];
+    }
+    let value = rest.trim();
+    if name.is_empty() || value.is_empty() {
+        return None;
+    }
+    Some((
+        name.to_string(),
+        format!("{name}{{{}}} {value}", labels.join(",")),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_reused_port() {
+        let first = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let socket_addr = first.local_addr().unwrap();
+        assert!(bind_listener(socket_addr, true).is_ok());
+        assert!(bind_listener(socket_addr, false).is_err());
+    }
+
+    #[test]
+    fn bind_dual_stack() {
+        // hosts without IPv6 can't run this test
+        if let Ok(listener) = bind_listener("[::]:0".parse().unwrap(), false) {
+            let port = listener.local_addr().unwrap().port();
+            assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_ok());
+        }
+    }
+
+    #[test]
+    fn refresh_policies() {
+        let at = Duration::from_millis;
+        let mut last = Duration::ZERO;
+        let idle = RefreshPolicy::AfterIdle;
+        assert!(idle.should_refresh(&mut last, at(10_000)));
+        assert!(!idle.should_refresh(&mut last, at(11_000)));
+        // requests every 1.5s never wait for 2s, as in earlier versions
+        assert!(!idle.should_refresh(&mut last, at(12_500)));
+        assert!(idle.should_refresh(&mut last, at(15_000)));
+
+        let mut last = Duration::ZERO;
+        let on_scrape = RefreshPolicy::OnScrape(Duration::from_secs(2));
+        assert!(on_scrape.should_refresh(&mut last, at(10_000)));
+        assert!(!on_scrape.should_refresh(&mut last, at(11_000)));
+        assert!(!on_scrape.should_refresh(&mut last, at(11_500)));
+        assert!(on_scrape.should_refresh(&mut last, at(12_000)));
+        assert_eq!(last, at(12_000));
+    }
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ExporterArgs,
+    }
+
+    #[test]
+    fn refresh_on_scrape_args() {
+        use clap::Parser;
+        let policy = |options: &[&str]| {
+            Cli::try_parse_from([&["prometheus"], options].concat())
+                .map(|cli| RefreshPolicy::from_args(&cli.args))
+        };
+        assert_eq!(policy(&[]).unwrap(), RefreshPolicy::AfterIdle);
+        assert_eq!(
+            policy(&["--refresh-on-scrape"]).unwrap(),
+            RefreshPolicy::OnScrape(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy(&["--refresh-on-scrape", "--min-refresh-interval", "15"]).unwrap(),
+            RefreshPolicy::OnScrape(Duration::from_secs(15))
+        );
+        assert!(policy(&["--min-refresh-interval", "15"]).is_err());
+    }
+
+    #[test]
+    fn scrape_within_interval_reuses_measurement() {
+        let topology = Topology::new(HashMap::new());
+        let context = PowerMetrics {
+            last_request: Mutex::new(Duration::ZERO),
+            metric_generator: Mutex::new(MetricGenerator::new(
+                topology,
+                String::from("node1"),
+                false,
+                false,
+            )),
+            refresh_policy: RefreshPolicy::OnScrape(Duration::from_secs(60)),
+            peers: vec![],
+            peer_timeout: Duration::from_secs(1),
+            hostname: String::from("node1"),
+            custom_metrics_node: None,
+            request_energy: false,
+            derived_metrics: false,
+            reference_power_push: false,
+        };
+        let pid = sysinfo::get_current_pid().unwrap();
+        let records = |_: Vec<Metric>, metric_generator: &MetricGenerator| {
+            metric_generator
+                .topology
+                .proc_tracker
+                .find_records(pid)
+                .map_or(0, |records| records.len())
+        };
+        assert_eq!(with_metrics(&context, records), Some(1));
+        // the second scrape comes within the minimum interval: no new measurement
+        assert_eq!(with_metrics(&context, records), Some(1));
+        *context.last_request.lock().unwrap() = Duration::ZERO;
+        assert_eq!(with_metrics(&context, records), Some(2));
+    }
+
+    #[tokio::test]
+    async fn push_reference_power() {
+        let context = Arc::new(PowerMetrics {
+            last_request: Mutex::new(Duration::ZERO),
+            metric_generator: Mutex::new(MetricGenerator::new(
+                Topology::new(HashMap::new()),
+                String::from("node1"),
+                false,
+                false,
+            )),
+            refresh_policy: RefreshPolicy::AfterIdle,
+            peers: vec![],
+            peer_timeout: Duration::from_secs(1),
+            hostname: String::from("node1"),
+            custom_metrics_node: None,
+            request_energy: false,
+            derived_metrics: false,
+            reference_power_push: true,
+        });
+        let push = |watts: &'static str| {
+            let req = Request::put("/reference-power")
+                .body(Body::from(watts))
+                .unwrap();
+            show_metrics(req, context.clone(), String::from("metrics"))
+        };
+        let status = push("42.5\n").await.unwrap().status();
+        assert_eq!(status, hyper::StatusCode::NO_CONTENT);
+        let pushed = context
+            .metric_generator
+            .lock()
+            .unwrap()
+            .pushed_reference_power;
+        assert_eq!(pushed.map(|(_, microwatts)| microwatts), Some(42_500_000.0));
+        let status = push("-3").await.unwrap().status();
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        let status = push("a lot").await.unwrap().status();
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+
+        use clap::Parser;
+        let args = |options: &[&str]| Cli::try_parse_from([&["prometheus"], options].concat());
+        assert!(
+            args(&["--reference-power-push"])
+                .unwrap()
+                .args
+                .reference_power_push
+        );
+        assert!(args(&["--reference-power-push", "--reference-power-file", "f"]).is_err());
+    }
+
+    #[test]
+    fn custom_metric_values() {
+        let metric = Metric {
+            name: String::from("scaph_host_power_headroom_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::from_secs(1700000000),
+            hostname: String::from("node1"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: HashMap::new(),
+            description: String::new(),
+            metric_value: MetricValueType::Text(String::from("-12345678")),
+        };
+        let list =
+            custom_metric_value_list("node1", "scaph_host_power_headroom", &[metric]).unwrap();
+        assert!(list.contains(
+            "\"describedObject\":{\"kind\":\"Node\",\"apiVersion\":\"/v1\",\"name\":\"node1\"}"
+        ));
+        assert!(list.contains("\"timestamp\":\"2023-11-14T22:13:20Z\""));
+        assert!(list.contains("\"value\":\"-12346m\""));
+        assert_eq!(
+            custom_metric_value_list("node1", "scaph_host_power", &[]),
+            None
+        );
+        assert_eq!(
+            custom_metric_value_list("node1", "scaph_process_power", &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn relabel_samples() {
+        assert_eq!(
+            relabel_sample("scaph_host_power_microwatts 42", "node1"),
+            Some((
+                String::from("scaph_host_power_microwatts"),
+                String::from("scaph_host_power_microwatts{host=\"node1\"} 42")
+            ))
+        );
+        let (_, line) = relabel_sample(
+            "scaph_process_power_consumption_microwatts{cmdline=\"sh -c \\\"a,b}\\\"\",host=\"old\",pid=\"12\"} 7",
+            "node2",
+        )
+        .unwrap();
+        assert_eq!(
+            line,
+            "scaph_process_power_consumption_microwatts{host=\"node2\",cmdline=\"sh -c \\\"a,b}\\\"\",pid=\"12\"} 7"
+        );
+        assert_eq!(
+            relabel_sample("scaph_host_power_microwatts{pid=\"1\" 4", "h"),
+            None
+        );
+        assert_eq!(relabel_sample("scaph_host_power_microwatts", "h"), None);
+    }
+
+    #[test]
+    fn negotiate_exposition() {
+        let headers = |accept: &str, encoding: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+            headers
+        };
+        let prometheus = headers(
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.6,\
+             application/openmetrics-text;version=1.0.0;q=0.5,text/plain;version=0.0.4;q=0.4,*/*;q=0.1",
+            "gzip",
+        );
+        assert!(accepts_protobuf(&prometheus));
+        let text_first = headers(
+            "text/plain;version=0.0.4;q=0.5,application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.2",
+            "identity",
+        );
+        assert!(!accepts_protobuf(&text_first));
+        assert!(!accepts_protobuf(&headers(
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text",
+            ""
+        )));
+        assert!(!accepts_protobuf(&HeaderMap::new()));
+
+        let response = metrics_response(b"a 1\n".to_vec(), TEXT_CONTENT_TYPE, &prometheus);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let response = metrics_response(b"a 1\n".to_vec(), TEXT_CONTENT_TYPE, &text_first);
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.headers()[CONTENT_TYPE], TEXT_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn encode_protobuf_metrics() {
+        let metric = Metric {
+            name: String::from("scaph_host_energy_microjoules"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp: Duration::ZERO,
+            hostname: String::from("node1"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: HashMap::from([(String::from("host"), "a".into())]),
+            description: String::from("Energy"),
+            metric_value: MetricValueType::IntUnsigned(2),
+        };
+        let mut expected = vec![0x0a, 9, 0x0a, 4];
+        expected.extend(b"host\x12\x01a");
+        // Counter, value and created timestamp
+        expected.extend([0x1a, 15, 0x09]);
+        expected.extend(2.0f64.to_le_bytes());
+        expected.extend([0x1a, 4, 0x08, 10, 0x10, 5]);
+        assert_eq!(
+            encode_metric(&metric, 2.0, Some(Duration::new(10, 5))),
+            expected
+        );
+
+        let gauge = Metric {
+            metric_type: String::from("gauge"),
+            attributes: HashMap::new(),
+            ..metric
+        };
+        let mut expected = vec![0x12, 9, 0x09];
+        expected.extend(1.5f64.to_le_bytes());
+        assert_eq!(encode_metric(&gauge, 1.5, None), expected);
+    }
+
+    #[test]
+    fn merge_metrics_of_hosts() {
+        let local = "# HELP scaph_host_power_microwatts Power\n\
+                     # TYPE scaph_host_power_microwatts gauge\n\
+                     scaph_host_power_microwatts 10\n\
+                     # HELP scaph_host_load_avg_one Load\n\
+                     # TYPE scaph_host_load_avg_one gauge\n\
+                     scaph_host_load_avg_one 1.5\n";
+        let peer = "# HELP scaph_host_power_microwatts Power\n\
+                    # TYPE scaph_host_power_microwatts gauge\n\
+                    scaph_host_power_microwatts 20\n";
+        let merged = merge_expositions(&[
+            (String::from("a"), String::from(local)),
+            (String::from("b"), String::from(peer)),
+        ]);
+        assert_eq!(
+            merged,
+            "# HELP scaph_host_power_microwatts Power\n\
+             # TYPE scaph_host_power_microwatts gauge\n\
+             scaph_host_power_microwatts{host=\"a\"} 10\n\
+             scaph_host_power_microwatts{host=\"b\"} 20\n\
+             # HELP scaph_host_load_avg_one Load\n\
+             # TYPE scaph_host_load_avg_one gauge\n\
+             scaph_host_load_avg_one{host=\"a\"} 1.5\n"
+        );
+    }
+
+    #[test]
+    fn forward_query_to_peers() {
+        let peer = "http://node2:8080/metrics".parse::<Uri>().unwrap();
+        assert_eq!(
+            with_query(&peer, "collect[]=host").to_string(),
+            "http://node2:8080/metrics?collect[]=host"
+        );
+        let peer = "http://node2:8080/metrics?token=1".parse::<Uri>().unwrap();
+        assert_eq!(
+            with_query(&peer, "exclude%5B%5D=process").to_string(),
+            "http://node2:8080/metrics?token=1&exclude%5B%5D=process"
+        );
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
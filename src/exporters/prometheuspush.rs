@@ -4,20 +4,24 @@
 //! a [Prometheus](https://prometheus.io/) pushgateway.
 //!
 
-use super::utils::{format_prometheus_metric, get_hostname};
-use crate::exporters::{Exporter, MetricGenerator};
+use super::utils::{
+    format_prometheus_metric, get_hostname, get_http_client, wait_for_next_step, HttpClientArgs,
+    MetricGeneratorArgs, MetricNamingArgs, StepArgs,
+};
+use crate::exporters::{Exporter, Metric, MetricGenerator};
 use crate::sensors::{Sensor, Topology};
 use chrono::Utc;
 use isahc::config::SslOption;
-use isahc::{prelude::*, Request};
+use isahc::http::request::Builder;
+use isahc::{prelude::*, HttpClient, Request};
 use std::fmt::Write;
-use std::thread;
 use std::time::Duration;
 
 pub struct PrometheusPushExporter {
     topo: Topology,
     hostname: String,
     args: ExporterArgs,
+    http_client: HttpClient,
 }
 
 /// Hold the arguments for a PrometheusExporter.
@@ -48,6 +52,12 @@ pub struct ExporterArgs {
     #[arg(long)]
     pub containers: bool,
 
+    #[command(flatten)]
+    pub generator: MetricGeneratorArgs,
+
+    #[command(flatten)]
+    pub naming_args: MetricNamingArgs,
+
     /// Job name to apply as a label for pushed metrics
     #[arg(short, long, default_value_t = String::from("scaphandre"))]
     pub job: String,
@@ -55,18 +65,28 @@ pub struct ExporterArgs {
     /// Don't verify remote TLS certificate (works with --scheme="https")
     #[arg(long)]
     pub no_tls_check: bool,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
 }
 
 impl PrometheusPushExporter {
-    pub fn new(sensor: &dyn Sensor, args: ExporterArgs) -> PrometheusPushExporter {
+    pub fn new(
+        sensor: &dyn Sensor,
+        args: ExporterArgs,
+        http_client_args: &HttpClientArgs,
+    ) -> PrometheusPushExporter {
         let topo = sensor
             .get_topology()
             .expect("sensor topology should be available");
         let hostname = get_hostname();
+        let http_client = get_http_client(http_client_args, &args.scheme)
+            .expect("HTTP client for the push gateway could not be created");
         PrometheusPushExporter {
             topo,
             hostname,
             args,
+            http_client,
         }
     }
 }
@@ -94,71 +114,132 @@ impl Exporter for PrometheusPushExporter {
             self.args.qemu,
             self.args.containers,
         );
+        self.args.generator.apply(&mut metric_generator);
+        metric_generator.set_metric_naming(self.args.naming_args.metric_naming);
 
         loop {
             metric_generator.topology.refresh();
             metric_generator.gen_all_metrics();
-            let mut body = String::from("");
-            let mut metrics_pushed: Vec<String> = vec![];
-            //let mut counter = 0;
-            for mut m in metric_generator.pop_metrics() {
-                let mut should_i_add_help = true;
-
-                if metrics_pushed.contains(&m.name) {
-                    should_i_add_help = false;
-                } else {
-                    metrics_pushed.insert(0, m.name.clone());
-                }
-
-                if should_i_add_help {
-                    let _ = write!(body, "# HELP {} {}", m.name, m.description);
-                    let _ = write!(body, "\n# TYPE {} {}\n", m.name, m.metric_type);
-                }
-                if !&m.attributes.contains_key("instance") {
-                    m.attributes
-                        .insert(String::from("instance"), m.hostname.clone());
-                }
-                if !&m.attributes.contains_key("hostname") {
-                    m.attributes
-                        .insert(String::from("hostname"), m.hostname.clone());
-                }
-                let attributes = Some(&m.attributes);
-
-                let _ = write!(
-                    body,
-                    "{}",
-                    format_prometheus_metric(&m.name, &m.metric_value.to_string(), attributes)
-                );
+            let metrics = metric_generator.pop_metrics();
+            let body = format_push_body(&metrics);
+            if let Err(e) = push(&self.http_client, &uri, body, self.args.no_tls_check) {
+                warn!("{e}");
             }
 
-            let pre_request = Request::post(uri.clone())
-                .timeout(Duration::from_secs(5))
-                .header("Content-Type", "text/plain");
-            let final_request = match self.args.no_tls_check {
-                true => pre_request.ssl_options(
-                    SslOption::DANGER_ACCEPT_INVALID_CERTS
-                        | SslOption::DANGER_ACCEPT_REVOKED_CERTS
-                        | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
-                ),
-                false => pre_request,
-            };
-            if let Ok(request) = final_request.body(body) {
-                match request.send() {
-                    Ok(mut response) => {
-                        debug!("Got {:?}", response);
-                        debug!("Response Text {:?}", response.text());
-                    }
-                    Err(err) => {
-                        warn!("Got error : {:?}", err)
-                    }
-                }
-            }
-
-            thread::sleep(Duration::new(self.args.step, 0));
+            wait_for_next_step(
+                Duration::new(self.args.step, 0),
+                self.args.step_args.align_timestamps,
+            );
         }
     }
 
     fn kind(&self) -> &str {
         "prometheuspush"
     }
+
+    /// Checks that the push gateway answers on its readiness endpoint.
+    fn validate(&mut self) -> Result<String, String> {
+        check_ready(
+            &self.http_client,
+            &format!(
+                "{}://{}:{}",
+                self.args.scheme, self.args.host, self.args.port
+            ),
+            self.args.no_tls_check,
+        )
+    }
+}
+
+/// Formats `metrics` in the Prometheus text format for the push gateway, with
+/// `instance` and `hostname` labels.
+pub fn format_push_body(metrics: &[Metric]) -> String {
+    let mut body = String::new();
+    let mut metrics_pushed: Vec<&str> = vec![];
+    for m in metrics {
+        if !metrics_pushed.contains(&m.name.as_str()) {
+            metrics_pushed.push(&m.name);
+            let _ = write!(body, "# HELP {} {}", m.name, m.description);
+            let _ = write!(body, "\n# TYPE {} {}\n", m.name, m.metric_type);
+        }
+        let mut attributes = m.attributes.clone();
+        attributes
+            .entry(String::from("instance"))
+            .or_insert_with(|| m.hostname.as_str().into());
+        attributes
+            .entry(String::from("hostname"))
+            .or_insert_with(|| m.hostname.as_str().into());
+        let attributes = Some(&attributes);
+
+        let _ = write!(
+            body,
+            "{}",
+            format_prometheus_metric(&m.name, &m.metric_value.to_string(), attributes)
+        );
+    }
+    body
+}
+
+/// Skips the verification of the certificate of the push gateway if `no_tls_check` is set.
+fn with_tls_check(builder: Builder, no_tls_check: bool) -> Builder {
+    match no_tls_check {
+        true => builder.ssl_options(
+            SslOption::DANGER_ACCEPT_INVALID_CERTS
+                | SslOption::DANGER_ACCEPT_REVOKED_CERTS
+                | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+        ),
+        false => builder,
+    }
+}
+
+/// Sends `body` to the push gateway at `uri`. Returns an error if it can't be
+/// reached or doesn't accept the metrics.
+pub fn push(
+    http_client: &HttpClient,
+    uri: &str,
+    body: String,
+    no_tls_check: bool,
+) -> Result<(), String> {
+    let pre_request = Request::post(uri)
+        .timeout(Duration::from_secs(5))
+        .header("Content-Type", "text/plain");
+    let request = with_tls_check(pre_request, no_tls_check)
+        .body(body)
+        .map_err(|e| format!("invalid push gateway URL {uri}: {e}"))?;
+    match http_client.send(request) {
+        Ok(mut response) if response.status().is_success() => {
+            debug!("Got {:?}", response);
+            debug!("Response Text {:?}", response.text());
+            Ok(())
+        }
+        Ok(mut response) => Err(format!(
+            "push gateway answered {} at {uri}: {}",
+            response.status(),
+            response.text().unwrap_or_default().trim()
+        )),
+        Err(e) => Err(format!("can't reach the push gateway at {uri}: {e}")),
+    }
+}
+
+/// Checks that the push gateway at `base_uri` (scheme, host and port) answers
+/// on its readiness endpoint.
+pub fn check_ready(
+    http_client: &HttpClient,
+    base_uri: &str,
+    no_tls_check: bool,
+) -> Result<String, String> {
+    let uri = format!("{base_uri}/-/ready");
+    let pre_request = Request::get(uri.clone()).timeout(Duration::from_secs(5));
+    let request = with_tls_check(pre_request, no_tls_check)
+        .body(())
+        .map_err(|e| format!("invalid push gateway URL {uri}: {e}"))?;
+    match http_client.send(request) {
+        Ok(response) if response.status().is_success() => {
+            Ok(format!("push gateway is ready at {uri}"))
+        }
+        Ok(response) => Err(format!(
+            "push gateway answered {} at {uri}",
+            response.status()
+        )),
+        Err(e) => Err(format!("can't reach the push gateway at {uri}: {e}")),
+    }
 }
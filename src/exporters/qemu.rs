@@ -20,20 +20,9 @@ impl Exporter for QemuExporter {
     fn run(&mut self) {
         info!("Starting qemu exporter");
         let path = "/var/lib/libvirt/scaphandre";
-        let cleaner_step = 120;
-        let mut timer = time::Duration::from_secs(cleaner_step);
         loop {
             self.iterate(String::from(path));
-            let step = time::Duration::from_secs(5);
-            thread::sleep(step);
-            if timer - step > time::Duration::from_millis(0) {
-                timer -= step;
-            } else {
-                self.topology
-                    .proc_tracker
-                    .clean_terminated_process_records_vectors();
-                timer = time::Duration::from_secs(cleaner_step);
-            }
+            thread::sleep(time::Duration::from_secs(5));
         }
     }
 
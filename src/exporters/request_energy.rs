@@ -0,0 +1,144 @@
+//! # Request energy: energy of a process during a time window
+//!
+//! [ProcessPowerHistory] keeps the recent power measurements of each process,
+//! each one being the average power over the interval since the previous
+//! measurement. An instrumented application gives the start and end of a
+//! request it served (and its trace id), and gets back the energy its process
+//! consumed meanwhile, to attach to the span of the request.
+//!
+//! Power being measured over the whole interval between two measurements, the
+//! energy of a request shorter than this interval is the share of the average
+//! power of the process during the interval, not the energy of the request alone.
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// How long power measurements are kept, so that requests can be asked for
+/// after they ended.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(600);
+
+/// Energy of a process during a window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowEnergy {
+    pub energy_microjoules: f64,
+    /// Share of the window covered by power measurements, from 0 to 1. It is
+    /// below 1 when the end of the window hasn't been measured yet.
+    pub coverage_ratio: f64,
+}
+
+/// Recent power of the processes, as (start, end, microwatts) intervals.
+#[derive(Debug, Clone)]
+pub struct ProcessPowerHistory {
+    pub retention: Duration,
+    intervals: HashMap<u32, VecDeque<(Duration, Duration, f64)>>,
+}
+
+impl ProcessPowerHistory {
+    pub fn new(retention: Duration) -> ProcessPowerHistory {
+        ProcessPowerHistory {
+            retention,
+            intervals: HashMap::new(),
+        }
+    }
+
+    /// Records the average power of `pid` between `start` and `end` (times
+    /// since the epoch), forgetting its measurements older than the retention.
+    pub fn add(&mut self, pid: u32, start: Duration, end: Duration, microwatts: f64) {
+        let intervals = self.intervals.entry(pid).or_default();
+        intervals.push_back((start, end, microwatts));
+        while intervals
+            .front()
+            .is_some_and(|(_, e, _)| *e + self.retention < end)
+        {
+            intervals.pop_front();
+        }
+    }
+
+    /// Forgets the processes not in `pids`.
+    pub fn retain(&mut self, pids: &[u32]) {
+        self.intervals.retain(|pid, _| pids.contains(pid));
+    }
+
+    /// Returns the energy consumed by `pid` between `start` and `end`, or None
+    /// if the process is unknown or the window is empty.
+    pub fn energy(&self, pid: u32, start: Duration, end: Duration) -> Option<WindowEnergy> {
+        if end <= start {
+            return None;
+        }
+        let intervals = self.intervals.get(&pid)?;
+        let mut energy = 0.0;
+        let mut covered = 0.0;
+        for (s, e, microwatts) in intervals {
+            let overlap = (*e).min(end).saturating_sub((*s).max(start)).as_secs_f64();
+            energy += microwatts * overlap;
+            covered += overlap;
+        }
+        Some(WindowEnergy {
+            energy_microjoules: energy,
+            coverage_ratio: (covered / (end - start).as_secs_f64()).min(1.0),
+        })
+    }
+}
+
+/// Parses a time since the epoch in seconds, possibly fractional.
+pub fn parse_timestamp(value: &str) -> Option<Duration> {
+    let seconds = value.parse::<f64>().ok()?;
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_energy() {
+        let secs = Duration::from_secs;
+        let mut history = ProcessPowerHistory::new(secs(60));
+        history.add(42, secs(100), secs(110), 2e6);
+        history.add(42, secs(110), secs(120), 4e6);
+        // 5s at 2W and 5s at 4W
+        let energy = history.energy(42, secs(105), secs(115)).unwrap();
+        assert_eq!(energy.energy_microjoules, 30e6);
+        assert_eq!(energy.coverage_ratio, 1.0);
+        // the end of the window isn't measured yet
+        let partial = history.energy(42, secs(115), secs(125)).unwrap();
+        assert_eq!(partial.energy_microjoules, 20e6);
+        assert_eq!(partial.coverage_ratio, 0.5);
+        assert_eq!(history.energy(42, secs(115), secs(115)), None);
+        assert_eq!(history.energy(7, secs(100), secs(110)), None);
+
+        history.add(42, secs(180), secs(190), 1e6);
+        assert_eq!(
+            history
+                .energy(42, secs(100), secs(110))
+                .unwrap()
+                .energy_microjoules,
+            0.0
+        );
+        history.retain(&[7]);
+        assert_eq!(history.energy(42, secs(180), secs(190)), None);
+
+        assert_eq!(
+            parse_timestamp("1700000000.25"),
+            Some(Duration::from_millis(1700000000250))
+        );
+        assert_eq!(parse_timestamp("-1"), None);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,225 @@
+//! # Restarts: processes that exit and are started again
+//!
+//! A process restarted by its supervisor (systemd, a container runtime...)
+//! gets a new pid, so that its metrics start a new series and a crash loop
+//! looks like many short-lived processes. [RestartTracker] groups the
+//! processes by executable and cgroup: when a process of a group exits and a
+//! new one appears in the same group within [RESTART_WINDOW], the group is
+//! counted as restarted.
+//!
+//! A new process is only a restart if it replaces one that exited, so that
+//! groups of several processes (e.g. the workers of a web server) scaling up
+//! aren't counted as restarting.
+//!
+//! A restarted container gets a new id and cgroup, so the cgroup of a group is
+//! the parent of the container cgroup, e.g. the cgroup of the pod (see
+//! [restart_cgroup]).
+use crate::sensors::utils::is_container_cgroup;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::Duration;
+
+/// Maximum time between the exit of a process and the start of the one
+/// replacing it, longer than the back-off of systemd or Kubernetes (5 minutes).
+pub const RESTART_WINDOW: Duration = Duration::from_secs(600);
+
+/// Time after which a group without any process is forgotten.
+pub const RETENTION: Duration = Duration::from_secs(3600);
+
+/// Processes sharing an executable and a cgroup.
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    /// Pids alive at the last update
+    pids: BTreeSet<u32>,
+    /// Times since the epoch processes of the group exited at, not replaced yet
+    exits: VecDeque<Duration>,
+    restarts: u64,
+    /// Time since the epoch the group was first seen at
+    created: Duration,
+    /// Time since the epoch a process of the group was last seen at
+    last_seen: Duration,
+}
+
+/// Counts the restarts of the groups of processes sharing an executable and a cgroup.
+#[derive(Debug, Clone, Default)]
+pub struct RestartTracker {
+    groups: HashMap<(String, String), Group>,
+}
+
+impl RestartTracker {
+    /// Updates the groups with the processes alive at `now` (time since the
+    /// epoch), given as (executable, cgroup, pid).
+    pub fn update(&mut self, processes: &[(String, String, u32)], now: Duration) {
+        let mut alive: HashMap<(String, String), BTreeSet<u32>> = HashMap::new();
+        for (exe, cgroup, pid) in processes {
+            alive
+                .entry((exe.clone(), cgroup.clone()))
+                .or_default()
+                .insert(*pid);
+        }
+        for (key, group) in self.groups.iter_mut() {
+            let pids = alive.remove(key).unwrap_or_default();
+            for _ in group.pids.difference(&pids) {
+                group.exits.push_back(now);
+            }
+            while group
+                .exits
+                .front()
+                .is_some_and(|exit| now.saturating_sub(*exit) > RESTART_WINDOW)
+            {
+                group.exits.pop_front();
+            }
+            for _ in pids.difference(&group.pids) {
+                if group.exits.pop_front().is_some() {
+                    group.restarts += 1;
+                }
+            }
+            if !pids.is_empty() {
+                group.last_seen = now;
+            }
+            group.pids = pids;
+        }
+        for (key, pids) in alive {
+            self.groups.insert(
+                key,
+                Group {
+                    pids,
+                    exits: VecDeque::new(),
+                    restarts: 0,
+                    created: now,
+                    last_seen: now,
+                },
+            );
+        }
+        self.groups
+            .retain(|_, group| now.saturating_sub(group.last_seen) <= RETENTION);
+    }
+
+    /// Returns the number of restarts of each group, as (executable, cgroup, restarts).
+    pub fn restarts(&self) -> Vec<(&str, &str, u64)> {
+        let mut restarts = self
+            .groups
+            .iter()
+            .map(|((exe, cgroup), group)| (exe.as_str(), cgroup.as_str(), group.restarts))
+            .collect::<Vec<_>>();
+        restarts.sort();
+        restarts
+    }
+
+    /// Returns the time since the epoch the restarts of the group of `exe` and
+    /// `cgroup` are counted from.
+    pub fn created(&self, exe: &str, cgroup: &str) -> Option<Duration> {
+        self.groups
+            .get(&(exe.to_string(), cgroup.to_string()))
+            .map(|group| group.created)
+    }
+}
+
+/// Returns the cgroup identifying the group of a process among its `cgroups`:
+/// the parent of its container cgroup if any, else its first cgroup that isn't
+/// the root one.
+pub fn restart_cgroup(cgroups: &[String]) -> String {
+    if let Some(container) = cgroups.iter().find(|c| is_container_cgroup(c)) {
+        return match container.rsplit_once('/') {
+            Some(("", _)) | None => String::from("/"),
+            Some((parent, _)) => parent.to_string(),
+        };
+    }
+    cgroups
+        .iter()
+        .find(|c| c.as_str() != "/")
+        .cloned()
+        .unwrap_or_else(|| String::from("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processes(pids: &[(&str, u32)]) -> Vec<(String, String, u32)> {
+        pids.iter()
+            .map(|(exe, pid)| {
+                (
+                    exe.to_string(),
+                    String::from("/system.slice/app.service"),
+                    *pid,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restarts() {
+        let s = Duration::from_secs;
+        let mut tracker = RestartTracker::default();
+        tracker.update(
+            &processes(&[("app", 10), ("nginx", 20), ("nginx", 21)]),
+            s(0),
+        );
+        // a crash and its restart, seen at once or with a gap
+        tracker.update(
+            &processes(&[("app", 11), ("nginx", 20), ("nginx", 21)]),
+            s(10),
+        );
+        tracker.update(&processes(&[("nginx", 20), ("nginx", 21)]), s(20));
+        tracker.update(
+            &processes(&[("app", 12), ("nginx", 20), ("nginx", 21)]),
+            s(30),
+        );
+        // scaling up isn't a restart
+        tracker.update(
+            &processes(&[("app", 12), ("nginx", 20), ("nginx", 21), ("nginx", 22)]),
+            s(40),
+        );
+        assert_eq!(
+            tracker.restarts(),
+            vec![
+                ("app", "/system.slice/app.service", 2),
+                ("nginx", "/system.slice/app.service", 0)
+            ]
+        );
+        assert_eq!(
+            tracker.created("app", "/system.slice/app.service"),
+            Some(s(0))
+        );
+        // an exit is only replaced within RESTART_WINDOW
+        tracker.update(
+            &processes(&[("nginx", 20), ("nginx", 21), ("nginx", 22)]),
+            s(50),
+        );
+        let later = s(50) + RESTART_WINDOW + s(10);
+        tracker.update(&processes(&[("app", 13)]), later);
+        assert_eq!(tracker.restarts()[0].2, 2);
+        // groups without processes are forgotten after RETENTION
+        tracker.update(&processes(&[("app", 13)]), later + RETENTION);
+        assert_eq!(tracker.restarts().len(), 1);
+    }
+
+    #[test]
+    fn restart_cgroups() {
+        let cgroups = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            restart_cgroup(&cgroups(&["/kubepods/burstable/pod1234/0123abcd"])),
+            "/kubepods/burstable/pod1234"
+        );
+        assert_eq!(
+            restart_cgroup(&cgroups(&["/", "/system.slice/nginx.service"])),
+            "/system.slice/nginx.service"
+        );
+        assert_eq!(restart_cgroup(&cgroups(&["/docker-0123abcd.scope"])), "/");
+        assert_eq!(restart_cgroup(&[]), "/");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -2,7 +2,7 @@
 //!
 //! The Riemann exporter sends metrics to a [Riemann](https://riemann.io/) server.
 
-use crate::exporters::utils::get_hostname;
+use crate::exporters::utils::{get_hostname, MetricGeneratorArgs, MetricNamingArgs, StepArgs};
 use crate::exporters::*;
 use crate::sensors::Sensor;
 use chrono::Utc;
@@ -11,6 +11,7 @@ use riemann_client::Client;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sysinfo::PidExt;
 
 /// Riemann server default ipv4/ipv6 address
 const DEFAULT_IP_ADDRESS: &str = "localhost";
@@ -22,20 +23,35 @@ const DEFAULT_PORT: u16 = 5555;
 /// [rust-riemann_client](https://github.com/borntyping/rust-riemann_client) library.
 ///
 /// It allows to connect to a Riemann server and send metrics.
-struct RiemannClient {
+pub(crate) struct RiemannClient {
     client: Client,
 }
 
 impl RiemannClient {
+    /// Connects to the Riemann server at `address` and `port`, through raw TCP.
+    pub(crate) fn connect(address: &str, port: u16) -> Result<RiemannClient, String> {
+        Client::connect(&(address, port))
+            .map(|client| RiemannClient { client })
+            .map_err(|e| format!("can't connect to Riemann at {address}:{port}: {e:?}"))
+    }
+
     /// Send metrics to the server.
     fn send_metric(&mut self, metric: &Metric) {
+        if let Err(e) = self.try_send_metric(metric) {
+            panic!("Fail to send metric to Riemann: {e}");
+        }
+    }
+
+    /// Sends a metric to the server, returns an error if its value isn't a
+    /// number or the server didn't accept it.
+    pub(crate) fn try_send_metric(&mut self, metric: &Metric) -> Result<(), String> {
         let mut event = Event::new();
 
         let mut attributes: Vec<Attribute> = vec![];
-        for (key, value) in &metric.attributes {
+        for (key, value) in metric.sorted_attributes() {
             let mut attribute = Attribute::new();
             attribute.set_key(key.clone());
-            attribute.set_value(value.clone());
+            attribute.set_value(value.to_string());
             attributes.push(attribute);
         }
 
@@ -54,32 +70,42 @@ impl RiemannClient {
             event.set_attributes(protobuf::RepeatedField::from_vec(attributes));
         }
         event.set_description(metric.description.to_string());
-
-        match metric.metric_value {
-            // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
-            // MetricValueType::Float(value) => event.set_metric_f(value),
-            //MetricValueType::FloatDouble(value) => event.set_metric_d(value),
-            MetricValueType::IntUnsigned(value) => event.set_metric_sint64(
-                i64::try_from(value).expect("Metric cannot be converted to signed integer."),
-            ),
-            MetricValueType::Text(ref value) => {
-                let value = value.replace(',', ".").replace('\n', "");
-                if value.contains('.') {
-                    event.set_metric_d(value.parse::<f64>().expect("Cannot parse metric value."));
-                } else {
-                    event.set_metric_sint64(
-                        value.parse::<i64>().expect("Cannot parse metric value."),
-                    );
-                }
-            }
-        }
+        set_metric_value(&mut event, metric)?;
 
         self.client
             .event(event)
-            .expect("Fail to send metric to Riemann");
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
     }
 }
 
+/// Sets the value of the metric on the event: a double for decimal values,
+/// a signed integer otherwise.
+fn set_metric_value(event: &mut Event, metric: &Metric) -> Result<(), String> {
+    match metric.metric_value {
+        // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
+        // MetricValueType::Float(value) => event.set_metric_f(value),
+        //MetricValueType::FloatDouble(value) => event.set_metric_d(value),
+        MetricValueType::IntUnsigned(value) => event.set_metric_sint64(
+            i64::try_from(value)
+                .map_err(|_| String::from("Metric cannot be converted to signed integer."))?,
+        ),
+        MetricValueType::Text(ref value) => {
+            let value = value.replace(',', ".").replace('\n', "");
+            if value.contains('.') {
+                event.set_metric_d(value.parse::<f64>().map_err(|e| {
+                    format!("Cannot parse metric value {value} of {}: {e}", metric.name)
+                })?);
+            } else {
+                event.set_metric_sint64(value.parse::<i64>().map_err(|e| {
+                    format!("Cannot parse metric value {value} of {}: {e}", metric.name)
+                })?);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// An exporter that sends metrics to a Riemann server.
 pub struct RiemannExporter {
     metric_generator: MetricGenerator,
@@ -106,6 +132,12 @@ pub struct ExporterArgs {
     #[arg(short, long)]
     pub qemu: bool,
 
+    #[command(flatten)]
+    pub generator: MetricGeneratorArgs,
+
+    #[command(flatten)]
+    pub naming_args: MetricNamingArgs,
+
     /// Monitor and apply labels for processes running as containers
     #[arg(long)]
     pub containers: bool,
@@ -131,6 +163,9 @@ pub struct ExporterArgs {
     /// Client RSA key file
     #[arg(long = "key", requires = "mtls")]
     pub key_file: Option<String>,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
 }
 
 impl RiemannExporter {
@@ -140,8 +175,10 @@ impl RiemannExporter {
         let topo = sensor
             .get_topology()
             .expect("sensor topology should be available");
-        let metric_generator =
+        let mut metric_generator =
             MetricGenerator::new(topo, utils::get_hostname(), args.qemu, args.containers);
+        args.generator.apply(&mut metric_generator);
+        metric_generator.set_metric_naming(args.naming_args.metric_naming);
 
         // Initialize the connection to the Riemann server
         let client = if args.mtls {
@@ -184,11 +221,6 @@ impl Exporter for RiemannExporter {
                 Utc::now().format("%Y-%m-%dT%H:%M:%S")
             );
 
-            self.metric_generator
-                .topology
-                .proc_tracker
-                .clean_terminated_process_records_vectors();
-
             info!(
                 "{}: Refresh topology",
                 Utc::now().format("%Y-%m-%dT%H:%M:%S")
@@ -209,17 +241,21 @@ impl Exporter for RiemannExporter {
                 let exe = processes_tracker.get_process_name(pid);
                 let cmdline = processes_tracker.get_process_cmdline(pid);
 
-                let mut attributes = HashMap::new();
-                attributes.insert("pid".to_string(), pid.to_string());
+                let mut attributes: HashMap<String, AttributeValue> = HashMap::new();
+                attributes.insert("pid".to_string(), pid.as_u32().into());
 
-                attributes.insert("exe".to_string(), exe.clone());
+                attributes.insert("exe".to_string(), exe.as_str().into());
 
                 if let Some(cmdline_str) = cmdline {
-                    attributes.insert("cmdline".to_string(), cmdline_str.replace('\"', "\\\""));
+                    attributes.insert(
+                        "cmdline".to_string(),
+                        utils::filter_cmdline(&cmdline_str, self.args.generator.max_cmdline_length)
+                            .into(),
+                    );
 
                     if self.args.qemu {
                         if let Some(vmname) = utils::filter_qemu_cmdline(&cmdline_str) {
-                            attributes.insert("vmname".to_string(), vmname);
+                            attributes.insert("vmname".to_string(), vmname.into());
                         }
                     }
                 }
@@ -259,13 +295,82 @@ impl Exporter for RiemannExporter {
             }
 
             // Pause for some time
-            std::thread::sleep(dispatch_interval);
+            utils::wait_for_next_step(dispatch_interval, self.args.step_args.align_timestamps);
         }
     }
 
     fn kind(&self) -> &str {
         "riemann"
     }
+
+    /// The connection to the server is made when the exporter is created, so
+    /// there is nothing left to check once it exists.
+    fn validate(&mut self) -> Result<String, String> {
+        Ok(format!(
+            "connected to {}:{}",
+            self.args.address, self.args.port
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(value: MetricValueType) -> Metric {
+        Metric {
+            name: String::from("scaph_host_power_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::from_secs(1700000000),
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![String::from("scaphandre")],
+            attributes: HashMap::new(),
+            description: String::from("Power measurement on the whole host, in microwatts"),
+            metric_value: value,
+        }
+    }
+
+    #[test]
+    fn metric_values() {
+        let mut event = Event::new();
+        set_metric_value(
+            &mut event,
+            &metric(MetricValueType::Text(String::from("12,5"))),
+        )
+        .unwrap();
+        assert_eq!(event.get_metric_d(), 12.5);
+        assert!(!event.has_metric_sint64());
+
+        let mut event = Event::new();
+        set_metric_value(
+            &mut event,
+            &metric(MetricValueType::Text(String::from("42\n"))),
+        )
+        .unwrap();
+        assert_eq!(event.get_metric_sint64(), 42);
+        assert!(!event.has_metric_d());
+
+        let mut event = Event::new();
+        set_metric_value(&mut event, &metric(MetricValueType::IntUnsigned(7))).unwrap();
+        assert_eq!(event.get_metric_sint64(), 7);
+
+        let mut event = Event::new();
+        assert!(set_metric_value(
+            &mut event,
+            &metric(MetricValueType::Text(String::from("1.2.3")))
+        )
+        .is_err());
+        assert!(set_metric_value(
+            &mut event,
+            &metric(MetricValueType::Text(String::from("n/a")))
+        )
+        .is_err());
+        assert!(
+            set_metric_value(&mut event, &metric(MetricValueType::IntUnsigned(u64::MAX))).is_err()
+        );
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
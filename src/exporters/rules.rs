@@ -0,0 +1,239 @@
+//! # Rules: Prometheus recording and alerting rules for scaphandre metrics
+//!
+//! `scaphandre generate-rules` prints a rules file for Prometheus, written
+//! against the metric names of this version, so that dashboards and alerts can
+//! rely on pre-aggregated series instead of summing the high cardinality
+//! `scaph_process_*` metrics at query time.
+//!
+//! The rules follow the `level:metric:operations` naming convention of
+//! Prometheus, and convert microwatts to watts.
+use std::fmt::Write;
+
+/// Metrics the rules are written against.
+pub const RULE_METRICS: [&str; 6] = [
+    "scaph_host_power_microwatts",
+    "scaph_process_power_consumption_microwatts",
+    "scaph_host_energy_microjoules",
+    "scaph_host_power_headroom_microwatts",
+    "scaph_container_power_budget_violation",
+    "scaph_power_anomaly",
+];
+
+/// Holds the arguments of `scaphandre generate-rules`.
+#[derive(clap::Args, Debug)]
+pub struct RulesArgs {
+    /// Add rules aggregating the power of processes by Kubernetes namespace and
+    /// workload (for metrics exposed with --containers)
+    #[arg(long)]
+    pub containers: bool,
+
+    /// Add alerting rules (power cap, power budgets, anomalies, stuck energy counter)
+    #[arg(long)]
+    pub alerts: bool,
+
+    /// Evaluation interval of the rule groups (e.g. 30s, 1m)
+    #[arg(long, value_name = "DURATION", default_value = "1m", value_parser = parse_interval)]
+    pub interval: String,
+}
+
+/// Checks that `value` is a Prometheus duration made of a number and a unit.
+fn parse_interval(value: &str) -> Result<String, String> {
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    if !number.is_empty() && ["ms", "s", "m", "h", "d"].contains(&unit) {
+        Ok(String::from(value))
+    } else {
+        Err(String::from(
+            "expected a number followed by ms, s, m, h or d (e.g. 1m)",
+        ))
+    }
+}
+
+/// Power of the host: PSYS based when it is published, so that hosts exposing
+/// both values aren't counted twice.
+const HOST_POWER: &str = "(sum by (instance) (scaph_host_power_microwatts{value_source=~\".*psys\"}) or sum by (instance) (scaph_host_power_microwatts))";
+
+/// Process power with a `workload` label guessed from the pod name: the name of
+/// the Deployment (`<name>-<replicaset hash>-<suffix>`), of the StatefulSet
+/// (`<name>-<ordinal>`) or of the DaemonSet (`<name>-<suffix>`), or the pod
+/// name itself for standalone pods.
+const WORKLOAD_POWER: &str = "label_replace(label_replace(label_replace(label_replace(scaph_process_power_consumption_microwatts{kubernetes_pod_name!=\"\"}, \"workload\", \"$1\", \"kubernetes_pod_name\", \"(.+)\"), \"workload\", \"$1\", \"kubernetes_pod_name\", \"(.+)-[0-9]+\"), \"workload\", \"$1\", \"kubernetes_pod_name\", \"(.+)-[a-z0-9]{5}\"), \"workload\", \"$1\", \"kubernetes_pod_name\", \"(.+)-[a-z0-9]{6,10}-[a-z0-9]{5}\")";
+
+/// A rule of a group: a recording rule, or an alert with its duration,
+/// severity and summary.
+enum Rule<'a> {
+    Record(&'a str, String),
+    Alert(&'a str, String, &'a str, &'a str, &'a str),
+}
+
+/// Returns the rules file, in YAML.
+pub fn generate(args: &RulesArgs) -> String {
+    let mut groups: Vec<(&str, Vec<Rule>)> = vec![(
+        "scaphandre.rules",
+        vec![
+            Rule::Record(
+                "instance:scaph_host_power_watts:sum",
+                format!("{HOST_POWER} / 1e6"),
+            ),
+            Rule::Record(
+                "instance:scaph_process_power_watts:sum",
+                String::from(
+                    "sum by (instance) (scaph_process_power_consumption_microwatts) / 1e6",
+                ),
+            ),
+            Rule::Record(
+                "instance:scaph_unattributed_power_watts:sum",
+                String::from("clamp_min(instance:scaph_host_power_watts:sum - instance:scaph_process_power_watts:sum, 0)"),
+            ),
+            Rule::Record(
+                "instance_exe:scaph_process_power_watts:sum",
+                String::from(
+                    "sum by (instance, exe) (scaph_process_power_consumption_microwatts) / 1e6",
+                ),
+            ),
+            Rule::Record(
+                "cluster:scaph_host_power_watts:sum",
+                String::from("sum(instance:scaph_host_power_watts:sum)"),
+            ),
+        ],
+    )];
+    if args.containers {
+        groups.push((
+            "scaphandre.kubernetes.rules",
+            vec![
+                Rule::Record(
+                    "namespace:scaph_process_power_watts:sum",
+                    String::from("sum by (kubernetes_pod_namespace) (scaph_process_power_consumption_microwatts{kubernetes_pod_namespace!=\"\"}) / 1e6"),
+                ),
+                Rule::Record(
+                    "namespace_workload:scaph_process_power_watts:sum",
+                    format!("sum by (kubernetes_pod_namespace, workload) ({WORKLOAD_POWER}) / 1e6"),
+                ),
+            ],
+        ));
+    }
+    if args.alerts {
+        groups.push((
+            "scaphandre.alerts",
+            vec![
+                Rule::Alert(
+                    "ScaphandreNodeOverPowerCap",
+                    String::from("scaph_host_power_headroom_microwatts < 0"),
+                    "10m",
+                    "warning",
+                    "{{ $labels.instance }} is over its power cap",
+                ),
+                Rule::Alert(
+                    "ScaphandrePowerBudgetExceeded",
+                    String::from("scaph_container_power_budget_violation == 1"),
+                    "5m",
+                    "warning",
+                    "A pod or container of {{ $labels.instance }} is over its power budget",
+                ),
+                Rule::Alert(
+                    "ScaphandrePowerAnomaly",
+                    String::from("scaph_power_anomaly == 1"),
+                    "5m",
+                    "info",
+                    "Unusual power draw on {{ $labels.instance }}",
+                ),
+                Rule::Alert(
+                    "ScaphandreEnergyCounterStuck",
+                    String::from("changes(scaph_host_energy_microjoules[10m]) == 0"),
+                    "5m",
+                    "warning",
+                    "The energy counter of {{ $labels.instance }} doesn't change, check the sensor",
+                ),
+            ],
+        ));
+    }
+    let mut yaml = format!(
+        "# Generated by scaphandre {} generate-rules\ngroups:\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for (name, rules) in groups {
+        let _ = write!(
+            yaml,
+            "  - name: {name}\n    interval: {}\n    rules:\n",
+            args.interval
+        );
+        for rule in rules {
+            match rule {
+                Rule::Record(record, expr) => {
+                    let _ = write!(
+                        yaml,
+                        "      - record: {record}\n        expr: {}\n",
+                        quote(&expr)
+                    );
+                }
+                Rule::Alert(alert, expr, duration, severity, summary) => {
+                    let _ = write!(
+                        yaml,
+                        "      - alert: {alert}\n        expr: {}\n        for: {duration}\n        labels:\n          severity: {severity}\n        annotations:\n          summary: {}\n",
+                        quote(&expr),
+                        quote(summary)
+                    );
+                }
+            }
+        }
+    }
+    yaml
+}
+
+/// Returns `s` as a single-quoted YAML string.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_generated() {
+        let mut args = RulesArgs {
+            containers: false,
+            alerts: false,
+            interval: String::from("30s"),
+        };
+        let yaml = generate(&args);
+        assert!(yaml.contains("  - name: scaphandre.rules\n    interval: 30s\n"));
+        assert!(yaml.contains("      - record: cluster:scaph_host_power_watts:sum\n        expr: 'sum(instance:scaph_host_power_watts:sum)'\n"));
+        assert!(!yaml.contains("namespace"));
+        args.containers = true;
+        args.alerts = true;
+        let yaml = generate(&args);
+        assert!(yaml.contains("namespace_workload:scaph_process_power_watts:sum"));
+        assert!(yaml.contains("      - alert: ScaphandreNodeOverPowerCap\n"));
+
+        // the metrics have to exist in this version
+        let sources = [include_str!("mod.rs"), include_str!("../sensors/mod.rs")];
+        for metric in RULE_METRICS {
+            assert!(
+                sources.iter().any(|s| s.contains(&format!("\"{metric}\""))),
+                "{metric} is not generated anymore"
+            );
+        }
+
+        assert!(parse_interval("1m").is_ok());
+        assert!(parse_interval("500ms").is_ok());
+        assert!(parse_interval("m").is_err());
+        assert!(parse_interval("1 minute").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
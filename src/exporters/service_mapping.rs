@@ -0,0 +1,183 @@
+//! # Service mapping: business-level labels for process metrics
+//!
+//! A service mapping file lets users attach their own labels (service, team,
+//! cost center...) to the metrics of the processes matching a regular
+//! expression. Each non-empty line that doesn't start with `#` is a rule made of
+//! three whitespace-separated columns:
+//!
+//! ```text
+//! # field   regex                labels
+//! exe       ^nginx$              service=frontend,team=web
+//! cmdline   java.*checkout\.jar  service=checkout,team=checkout,cost_center=cc-42
+//! cgroup    ^/system\.slice/     team=platform
+//! ```
+//!
+//! `field` is either `exe`, `cmdline` or `cgroup`. As columns are separated by
+//! whitespaces, use `\s` to match a whitespace in the regex. Rules are evaluated
+//! in order and, when several rules set the same label, the first one wins.
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+
+/// The process property a [MappingRule] is matched against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchField {
+    Exe,
+    Cmdline,
+    Cgroup,
+}
+
+/// A regex on a process property, and the labels to apply when it matches.
+#[derive(Debug, Clone)]
+pub struct MappingRule {
+    pub field: MatchField,
+    pub regex: Regex,
+    pub labels: Vec<(String, String)>,
+}
+
+/// Ordered list of rules loaded from a service mapping file.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceMapping {
+    pub rules: Vec<MappingRule>,
+}
+
+impl ServiceMapping {
+    /// Reads and parses the service mapping file at `path`.
+    pub fn from_file(path: &str) -> Result<ServiceMapping, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read service mapping file {path}: {e}"))?;
+        ServiceMapping::parse(&content).map_err(|e| format!("{path}: {e}"))
+    }
+
+    /// Parses the content of a service mapping file.
+    pub fn parse(content: &str) -> Result<ServiceMapping, String> {
+        let label_name = Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+        let mut rules = vec![];
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() != 3 {
+                return Err(format!(
+                    "line {}: expected 3 columns (field, regex, labels), got {}",
+                    i + 1,
+                    columns.len()
+                ));
+            }
+            let field = match columns[0] {
+                "exe" => MatchField::Exe,
+                "cmdline" => MatchField::Cmdline,
+                "cgroup" => MatchField::Cgroup,
+                other => {
+                    return Err(format!(
+                        "line {}: unknown field '{other}', expected exe, cmdline or cgroup",
+                        i + 1
+                    ))
+                }
+            };
+            let regex = Regex::new(columns[1])
+                .map_err(|e| format!("line {}: invalid regex: {e}", i + 1))?;
+            let mut labels = vec![];
+            for pair in columns[2].split(',') {
+                match pair.split_once('=') {
+                    Some((k, v)) if label_name.is_match(k) => {
+                        labels.push((k.to_string(), v.to_string()));
+                    }
+                    _ => {
+                        return Err(format!(
+                            "line {}: invalid label '{pair}', expected name=value",
+                            i + 1
+                        ))
+                    }
+                }
+            }
+            rules.push(MappingRule {
+                field,
+                regex,
+                labels,
+            });
+        }
+        Ok(ServiceMapping { rules })
+    }
+
+    /// Returns the labels of all the rules matching the given process properties.
+    pub fn labels_for(
+        &self,
+        exe: &str,
+        cmdline: Option<&str>,
+        cgroups: &[String],
+    ) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        for rule in &self.rules {
+            let matched = match rule.field {
+                MatchField::Exe => rule.regex.is_match(exe),
+                MatchField::Cmdline => cmdline.is_some_and(|c| rule.regex.is_match(c)),
+                MatchField::Cgroup => cgroups.iter().any(|c| rule.regex.is_match(c)),
+            };
+            if matched {
+                for (k, v) in &rule.labels {
+                    labels.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_for() {
+        let mapping = ServiceMapping::parse(
+            "# comment\n\
+             exe ^nginx$ service=frontend,team=web\n\
+             \n\
+             cmdline checkout\\.jar service=checkout,team=checkout,cost_center=cc-42\n\
+             cgroup ^/system\\.slice/ team=platform\n",
+        )
+        .unwrap();
+        assert_eq!(mapping.rules.len(), 3);
+
+        let labels = mapping.labels_for("nginx", None, &[]);
+        assert_eq!(labels.get("service").unwrap(), "frontend");
+        assert_eq!(labels.get("team").unwrap(), "web");
+
+        let cgroups = vec![String::from("/system.slice/java.service")];
+        let labels = mapping.labels_for("java", Some("java-jarcheckout.jar"), &cgroups);
+        assert_eq!(labels.get("team").unwrap(), "checkout");
+        assert_eq!(labels.get("cost_center").unwrap(), "cc-42");
+
+        let labels = mapping.labels_for("java", None, &cgroups);
+        assert_eq!(labels.get("team").unwrap(), "platform");
+        assert!(!labels.contains_key("service"));
+
+        assert!(mapping.labels_for("bash", Some("bash"), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ServiceMapping::parse("exe ^nginx$").is_err());
+        assert!(ServiceMapping::parse("pid ^1$ service=init").is_err());
+        assert!(ServiceMapping::parse("exe ( service=broken").is_err());
+        assert!(ServiceMapping::parse("exe ^nginx$ service").is_err());
+        assert!(ServiceMapping::parse("exe ^nginx$ my-team=web").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
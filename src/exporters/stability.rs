@@ -0,0 +1,257 @@
+//! # Stability: how much dashboards can rely on each metric
+//!
+//! Each metric has a stability level:
+//!
+//! - `stable`: its name, labels and unit only change with a major version,
+//! - `beta`: it may still change, with a note in the changelog,
+//! - `experimental`: it may change or disappear in any version, and is only
+//!   published with `--include-experimental`.
+//!
+//! New metrics are beta. Only the ones whose value or labels aren't settled
+//! yet, such as estimations, are experimental, so that they can be iterated on
+//! without breaking the dashboards built on the others. Metrics missing from
+//! [METRICS] are experimental as well.
+use std::fmt::Write;
+
+/// Stability level of a metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Stability {
+    Stable,
+    Beta,
+    Experimental,
+}
+
+impl Stability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stability::Stable => "stable",
+            Stability::Beta => "beta",
+            Stability::Experimental => "experimental",
+        }
+    }
+}
+
+/// Metrics published by this version and their stability.
+pub const METRICS: [(&str, Stability); 99] = [
+    ("scaph_host_power_microwatts", Stability::Stable),
+    ("scaph_host_energy_microjoules", Stability::Stable),
+    ("scaph_host_energy_total_microjoules", Stability::Beta),
+    ("scaph_host_energy_since_start_microjoules", Stability::Beta),
+    ("scaph_host_energy_since_boot_microjoules", Stability::Beta),
+    ("scaph_host_rapl_psys_microjoules", Stability::Stable),
+    ("scaph_host_measurement_coverage_ratio", Stability::Beta),
+    ("scaph_host_cpu_time_ratio", Stability::Beta),
+    (
+        "scaph_host_rapl_update_period_seconds",
+        Stability::Experimental,
+    ),
+    ("scaph_host_reference_power_microwatts", Stability::Beta),
+    ("scaph_host_power_error_ratio", Stability::Beta),
+    ("scaph_host_power_forecast_microwatts", Stability::Beta),
+    ("scaph_host_power_cap_microwatts", Stability::Beta),
+    ("scaph_host_power_headroom_microwatts", Stability::Beta),
+    (
+        "scaph_host_power_slope_microwatts_per_second",
+        Stability::Beta,
+    ),
+    ("scaph_kernel_power_microwatts", Stability::Beta),
+    ("scaph_power_anomaly", Stability::Beta),
+    ("scaph_cpu_supported", Stability::Beta),
+    ("scaph_topology_warnings", Stability::Experimental),
+    ("scaph_socket_power_microwatts", Stability::Stable),
+    ("scaph_socket_energy_microjoules", Stability::Stable),
+    ("scaph_socket_memory_bytes", Stability::Beta),
+    (
+        "scaph_socket_power_slope_microwatts_per_second",
+        Stability::Beta,
+    ),
+    (
+        "scaph_socket_rapl_mmio_energy_microjoules",
+        Stability::Stable,
+    ),
+    ("scaph_domain_power_microwatts", Stability::Stable),
+    ("scaph_domain_energy_microjoules", Stability::Stable),
+    (
+        "scaph_domain_rapl_mmio_energy_microjoules",
+        Stability::Stable,
+    ),
+    ("scaph_rapl_zone_enabled", Stability::Beta),
+    (
+        "scaph_rapl_zone_max_energy_range_microjoules",
+        Stability::Beta,
+    ),
+    ("scaph_domain_cpu_usage_percent", Stability::Experimental),
+    ("scaph_domain_memory_traffic_bytes", Stability::Experimental),
+    ("scaph_igpu_power_microwatts", Stability::Beta),
+    ("scaph_host_load_avg_one", Stability::Stable),
+    ("scaph_host_load_avg_five", Stability::Stable),
+    ("scaph_host_load_avg_fifteen", Stability::Stable),
+    ("scaph_host_cpu_frequency", Stability::Stable),
+    ("scaph_host_memory_total_bytes", Stability::Stable),
+    ("scaph_host_memory_available_bytes", Stability::Stable),
+    ("scaph_host_memory_free_bytes", Stability::Stable),
+    ("scaph_host_swap_total_bytes", Stability::Stable),
+    ("scaph_host_swap_free_bytes", Stability::Stable),
+    ("scaph_host_disk_total_bytes", Stability::Stable),
+    ("scaph_host_disk_available_bytes", Stability::Stable),
+    ("scaph_host_disk_read_bytes", Stability::Beta),
+    ("scaph_host_disk_written_bytes", Stability::Beta),
+    ("scaph_host_disk_read_iops", Stability::Beta),
+    ("scaph_host_disk_write_iops", Stability::Beta),
+    ("scaph_host_network_received_bytes", Stability::Beta),
+    ("scaph_host_network_transmitted_bytes", Stability::Beta),
+    ("scaph_host_network_link_speed_mbps", Stability::Beta),
+    (
+        "scaph_host_network_power_microwatts",
+        Stability::Experimental,
+    ),
+    ("scaph_host_component_temperature_celsius", Stability::Beta),
+    ("scaph_host_fan_speed_rpm", Stability::Beta),
+    ("scaph_host_ac_online", Stability::Beta),
+    ("scaph_host_pressure_ratio", Stability::Beta),
+    ("scaph_host_pressure_stall_microseconds", Stability::Beta),
+    ("scaph_host_boot_time_seconds", Stability::Beta),
+    ("scaph_forks_since_boot_total", Stability::Stable),
+    ("scaph_processes_running_current", Stability::Stable),
+    ("scaph_processes_blocked_current", Stability::Stable),
+    ("scaph_context_switches_total", Stability::Stable),
+    (
+        "scaph_process_power_consumption_microwatts",
+        Stability::Stable,
+    ),
+    ("scaph_process_energy_total_microjoules", Stability::Beta),
+    ("scaph_process_domain_power_microwatts", Stability::Beta),
+    ("scaph_process_storage_power_microwatts", Stability::Beta),
+    ("scaph_process_cpu_usage_percentage", Stability::Stable),
+    ("scaph_process_memory_bytes", Stability::Stable),
+    ("scaph_process_memory_virtual_bytes", Stability::Stable),
+    ("scaph_process_disk_read_bytes", Stability::Stable),
+    ("scaph_process_disk_write_bytes", Stability::Stable),
+    ("scaph_process_disk_total_read_bytes", Stability::Stable),
+    ("scaph_process_disk_total_write_bytes", Stability::Stable),
+    ("scaph_process_context_switches_total", Stability::Beta),
+    ("scaph_process_start_time_seconds", Stability::Beta),
+    ("scaph_process_restarts_total", Stability::Beta),
+    ("scaph_job_power_microwatts", Stability::Beta),
+    ("scaph_job_energy_microjoules_total", Stability::Beta),
+    ("scaph_kubernetes_group_power_microwatts", Stability::Beta),
+    ("scaph_kubernetes_group_energy_microjoules", Stability::Beta),
+    ("scaph_container_power_budget_microwatts", Stability::Beta),
+    ("scaph_container_power_average_microwatts", Stability::Beta),
+    ("scaph_container_power_budget_violation", Stability::Beta),
+    ("scaph_self_version", Stability::Stable),
+    ("scaph_self_start_time_seconds", Stability::Beta),
+    ("scaph_self_cpu_usage_percent", Stability::Stable),
+    ("scaph_self_memory_bytes", Stability::Stable),
+    ("scaph_self_memory_virtual_bytes", Stability::Stable),
+    ("scaph_self_seccomp_allowed_syscalls", Stability::Beta),
+    ("scaph_self_topo_stats_nb", Stability::Stable),
+    ("scaph_self_topo_records_nb", Stability::Stable),
+    ("scaph_self_topo_procs_nb", Stability::Stable),
+    ("scaph_self_evicted_processes_total", Stability::Beta),
+    ("scaph_self_tasks_cpu_usage_ratio", Stability::Experimental),
+    ("scaph_self_compare_batches_total", Stability::Beta),
+    (
+        "scaph_self_compare_divergent_batches_total",
+        Stability::Beta,
+    ),
+    ("scaph_self_socket_stats_nb", Stability::Stable),
+    ("scaph_self_socket_records_nb", Stability::Stable),
+    ("scaph_self_domain_records_nb", Stability::Stable),
+    ("scaph_peer_up", Stability::Beta),
+];
+
+/// Returns the stability of the metric `name`. Metrics missing from [METRICS]
+/// are experimental.
+pub fn of(name: &str) -> Stability {
+    METRICS
+        .iter()
+        .find(|(metric, _)| *metric == name)
+        .map(|(_, stability)| *stability)
+        .unwrap_or(Stability::Experimental)
+}
+
+/// Holds the arguments of `scaphandre metrics`.
+#[derive(clap::Subcommand, Debug)]
+pub enum MetricsCommand {
+    /// List the metrics of this version and their stability
+    List {
+        /// Only list the metrics of this stability
+        #[arg(long, value_enum)]
+        stability: Option<Stability>,
+    },
+}
+
+/// Runs a `scaphandre metrics` subcommand and returns what it prints.
+pub fn run(command: &MetricsCommand) -> String {
+    match command {
+        MetricsCommand::List { stability } => {
+            let mut metrics = METRICS
+                .iter()
+                .filter(|(_, s)| stability.is_none() || *stability == Some(*s))
+                .collect::<Vec<_>>();
+            metrics.sort_by_key(|(name, s)| (*s, *name));
+            let mut result = String::new();
+            for (name, stability) in metrics {
+                let _ = writeln!(result, "{name:<50} {}", stability.name());
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stability() {
+        assert_eq!(of("scaph_host_power_microwatts"), Stability::Stable);
+        assert_eq!(
+            of("scaph_domain_cpu_usage_percent"),
+            Stability::Experimental
+        );
+        assert_eq!(of("scaph_not_a_metric"), Stability::Experimental);
+        let mut names = METRICS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), METRICS.len());
+        let list = run(&MetricsCommand::List {
+            stability: Some(Stability::Experimental),
+        });
+        assert!(list.contains("scaph_domain_memory_traffic_bytes"));
+        assert!(!list.contains("scaph_host_power_microwatts "));
+    }
+
+    #[test]
+    fn all_metrics_have_a_stability() {
+        // metrics generated by MetricGenerator missing from METRICS would be
+        // experimental, and disappear without --include-experimental
+        let source = include_str!("mod.rs");
+        for name in source
+            .split("String::from(\"")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter(|name| name.starts_with("scaph_"))
+        {
+            assert!(
+                METRICS.iter().any(|(metric, _)| *metric == name),
+                "{name} is missing from METRICS"
+            );
+        }
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
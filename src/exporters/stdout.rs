@@ -1,8 +1,10 @@
+use crate::exporters::utils::{MetricGeneratorArgs, StepArgs, TimestampFormat, TimestampZone};
 use crate::exporters::*;
+use crate::sensors::units::{self, DisplayUnit, Measure, NumberFormat, Unit};
 use crate::sensors::{utils::current_system_time_since_epoch, utils::IProcess, Sensor};
 use regex::Regex;
+use std::collections::HashMap;
 use std::fmt::Write;
-use std::thread;
 use std::time::{Duration, Instant};
 
 /// An Exporter that displays power consumption data of the host
@@ -10,6 +12,12 @@ use std::time::{Duration, Instant};
 pub struct StdoutExporter {
     metric_generator: MetricGenerator,
     args: ExporterArgs,
+    /// Energy consumed by the host since the first measurement
+    host_energy: utils::EnergyTotal,
+    /// Last displayed power of each line (host, sockets, processes), in watts
+    last_displayed: HashMap<String, f64>,
+    /// Separators of the displayed numbers
+    number_format: NumberFormat,
 }
 
 /// Holds the arguments for a StdoutExporter.
@@ -46,9 +54,80 @@ pub struct ExporterArgs {
     #[arg(short, long)]
     pub qemu: bool,
 
+    #[command(flatten)]
+    pub generator: MetricGeneratorArgs,
+
     /// Display metrics with their names
     #[arg(long)]
     pub raw_metrics: bool,
+
+    /// Display metrics as CSV lines: timestamp, name, value and labels (as name=value pairs
+    /// separated by ;)
+    #[arg(long, conflicts_with = "raw_metrics")]
+    pub csv: bool,
+
+    /// Format of the timestamps of the CSV lines: seconds since the epoch, or RFC 3339 dates
+    /// and times (e.g. 2023-11-14T22:13:20.500Z)
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = TimestampFormat::Epoch, requires = "csv")]
+    pub timestamp_format: TimestampFormat,
+
+    /// Time zone of the RFC 3339 timestamps: UTC, local (the one of the host) or an offset
+    /// such as +02:00
+    #[arg(long, value_name = "ZONE", default_value = "UTC", value_parser = utils::parse_timestamp_zone)]
+    pub timezone: TimestampZone,
+
+    /// Hide the power of each CPU socket (and of its RAPL domains)
+    #[arg(long)]
+    pub no_sockets: bool,
+
+    /// Hide the power of each RAPL domain, shown in columns of the sockets lines
+    #[arg(long)]
+    pub no_domains: bool,
+
+    /// Print the total energy consumed by the host during the run, once the timeout is reached
+    #[arg(long)]
+    pub summary_on_exit: bool,
+
+    /// Only display a line (host, socket or process) when its power changed by more
+    /// than this number of watts since it was last displayed
+    #[arg(long, value_name = "WATTS")]
+    pub delta_threshold: Option<f64>,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
+
+    /// Unit of the displayed powers: MW, kW, W, mW or uW, or auto for the most readable
+    /// one for each value
+    #[arg(long, value_name = "UNIT", default_value = "W", value_parser = units::parse_display_power_unit)]
+    pub power_unit: DisplayUnit,
+
+    /// Unit of the energy displayed with --summary-on-exit: kWh, Wh, J, mJ or uJ
+    /// (if unset, the energy is displayed in J and Wh)
+    #[arg(long, value_name = "UNIT", value_parser = units::parse_energy_unit)]
+    pub energy_unit: Option<Unit>,
+
+    /// Number of decimals of the displayed values (all significant decimals if unset,
+    /// 2 with --human)
+    #[arg(long, value_name = "DECIMALS")]
+    pub precision: Option<usize>,
+
+    /// Display powers and energies in their most readable unit (e.g. 1.23 kW, 350 mW, 2.4 kWh),
+    /// with 2 decimals unless --precision is given
+    #[arg(long, conflicts_with_all = ["power_unit", "energy_unit"])]
+    pub human: bool,
+
+    /// Display numbers with the decimal and thousands separators of this locale (e.g. fr_FR,
+    /// de_DE), or of the LC_ALL, LC_NUMERIC or LANG environment variables with "env"
+    #[arg(long, value_name = "LOCALE")]
+    pub locale: Option<String>,
+
+    /// Decimal separator of the displayed numbers, overriding the one of --locale
+    #[arg(long, value_name = "CHAR")]
+    pub decimal_separator: Option<char>,
+
+    /// Thousands separator of the displayed numbers, overriding the one of --locale
+    #[arg(long, value_name = "CHAR")]
+    pub thousands_separator: Option<char>,
 }
 
 impl Exporter for StdoutExporter {
@@ -61,17 +140,24 @@ impl Exporter for StdoutExporter {
             Some(Duration::from_secs(self.args.timeout.unsigned_abs()))
         };
 
-        println!("Measurement step is: {time_step:?}");
+        if self.args.csv {
+            println!("timestamp,name,value,labels");
+        } else {
+            println!("Measurement step is: {time_step:?}");
+        }
         if let Some(timeout) = time_limit {
             let t0 = Instant::now();
             while t0.elapsed() <= timeout {
                 self.iterate();
-                thread::sleep(time_step);
+                utils::wait_for_next_step(time_step, self.args.step_args.align_timestamps);
+            }
+            if self.args.summary_on_exit {
+                self.print_summary(t0.elapsed());
             }
         } else {
             loop {
                 self.iterate();
-                thread::sleep(time_step);
+                utils::wait_for_next_step(time_step, self.args.step_args.align_timestamps);
             }
         }
     }
@@ -89,51 +175,131 @@ impl StdoutExporter {
             .get_topology()
             .expect("sensor topology should be available");
 
-        let metric_generator =
+        let mut metric_generator =
             MetricGenerator::new(topo, utils::get_hostname(), args.qemu, args.containers);
+        // Filtered out processes are not even tracked, see ProcessFilters
+        if let Some(regex) = &args.regex_filter {
+            let filters = &mut metric_generator.topology.proc_tracker.filters;
+            if filters.include.is_some() {
+                warn!(
+                    "--regex-filter replaces the regular expression given with --include-processes"
+                );
+            }
+            filters.include = Some(regex.clone());
+        }
+        args.generator.apply(&mut metric_generator);
+
+        let mut number_format = match args.locale.as_deref() {
+            None => NumberFormat::default(),
+            Some("env") => NumberFormat::from_env(),
+            Some(locale) => NumberFormat::for_locale(locale).unwrap_or_else(|| {
+                panic!("unknown locale {locale}, use --decimal-separator and --thousands-separator")
+            }),
+        };
+        if let Some(separator) = args.decimal_separator {
+            number_format.decimal_separator = separator;
+        }
+        if args.thousands_separator.is_some() {
+            number_format.thousands_separator = args.thousands_separator;
+        }
 
         StdoutExporter {
             metric_generator,
             args,
+            host_energy: utils::EnergyTotal::default(),
+            last_displayed: HashMap::new(),
+            number_format,
+        }
+    }
+
+    /// Formats `value`, given in `unit`, in `dest_unit` with the precision given by --precision
+    /// and the separators given by --locale.
+    fn format_measure(&self, value: f64, unit: Unit, dest_unit: DisplayUnit) -> String {
+        let measure = Measure::new(value, unit);
+        let measure = match dest_unit {
+            DisplayUnit::Auto => measure.auto_scaled(),
+            DisplayUnit::Fixed(dest_unit) => {
+                measure.to(dest_unit).expect("units should be compatible")
+            }
+        };
+        measure.format(self.args.precision, &self.number_format)
+    }
+
+    /// Formats a power given in microwatts in the unit given by --power-unit, or the most
+    /// readable one with --human.
+    fn format_power(&self, microwatts: f64) -> String {
+        if self.args.human {
+            return Measure::new(microwatts, Unit::MicroWatt)
+                .human(self.args.precision, &self.number_format);
+        }
+        self.format_measure(microwatts, Unit::MicroWatt, self.args.power_unit)
+    }
+
+    fn print_summary(&self, duration: Duration) {
+        let energy = self.host_energy.microjoules;
+        println!("Summary:");
+        if self.args.human {
+            println!(
+                "Host consumed {} in {:?}",
+                Measure::new(energy, Unit::MicroJoule)
+                    .human(self.args.precision, &self.number_format),
+                duration
+            );
+            return;
         }
+        match self.args.energy_unit {
+            Some(unit) => println!(
+                "Host consumed {} in {:?}",
+                self.format_measure(energy, Unit::MicroJoule, DisplayUnit::Fixed(unit)),
+                duration
+            ),
+            None => println!(
+                "Host consumed {} ({}) in {:?}",
+                self.format_measure(energy, Unit::MicroJoule, DisplayUnit::Fixed(Unit::Joule)),
+                self.format_measure(energy, Unit::MicroJoule, DisplayUnit::Fixed(Unit::WattHour)),
+                duration
+            ),
+        }
+    }
+
+    /// Returns true if the line identified by `key` should be displayed with this
+    /// power value, according to --delta-threshold, and remembers it if so.
+    fn should_display(&mut self, key: &str, watts: f64) -> bool {
+        if let Some(threshold) = self.args.delta_threshold {
+            if let Some(last) = self.last_displayed.get(key) {
+                if (watts - last).abs() <= threshold {
+                    return false;
+                }
+            }
+        }
+        self.last_displayed.insert(String::from(key), watts);
+        true
     }
 
     fn iterate(&mut self) {
-        self.metric_generator
-            .topology
-            .proc_tracker
-            .clean_terminated_process_records_vectors();
         self.metric_generator.topology.refresh();
         self.show_metrics();
     }
 
-    fn summarized_view(&mut self, metrics: Vec<Metric>) {
-        let mut metrics_iter = metrics.iter();
-        let none_value = MetricValueType::Text("0".to_string());
-        let mut host_power_source = String::from("");
-        let host_power = match metrics_iter.find(|x| x.name == "scaph_host_power_microwatts") {
-            Some(m) => {
-                if let Some(src) = &m.attributes.get("value_source") {
-                    host_power_source = src.to_string()
-                }
-                &m.metric_value
-            }
-            None => &none_value,
-        };
+    /// Returns the lines showing the power of the sockets and of their domains.
+    fn sockets_view(&mut self, metrics: &[Metric]) -> Vec<String> {
+        let show_sockets = !self.args.no_sockets;
+        let show_domains = show_sockets && !self.args.no_domains;
+        let domain_names = self.metric_generator.topology.domains_names.clone();
+        let domain_names = domain_names.as_ref();
+        let mut lines = vec![];
 
-        let domain_names = self.metric_generator.topology.domains_names.as_ref();
-        if domain_names.is_some() {
-            info!("domain_names: {:?}", domain_names.unwrap());
+        if show_sockets && self.metric_generator.topology.sockets.is_empty() {
+            lines.push(String::from(
+                "No socket: the sensor only measures the whole host",
+            ));
         }
 
-        println!(
-            "Host:\t{} W from {}",
-            (format!("{host_power}").parse::<f64>().unwrap() / 1000000.0),
-            host_power_source
-        );
-
-        if domain_names.is_some() {
-            println!("\tpackage \t{}", domain_names.unwrap().join("\t\t"));
+        if show_domains && domain_names.is_some() {
+            lines.push(format!(
+                "\tpackage \t{}",
+                domain_names.unwrap().join("\t\t")
+            ));
         }
 
         for s in metrics
@@ -141,14 +307,22 @@ impl StdoutExporter {
             .filter(|x| x.name == "scaph_socket_power_microwatts")
         {
             debug!("✅ Found socket power metric !");
-            let power = format!("{}", s.metric_value).parse::<f32>().unwrap() / 1000000.0;
+            let microwatts = format!("{}", s.metric_value).parse::<f64>().unwrap();
+            let power = microwatts / 1000000.0;
             let mut power_str = String::from("----");
             if power > 0.0 {
-                power_str = power.to_string();
+                power_str = self.format_power(microwatts);
             }
             let socket_id = s.attributes.get("socket_id").unwrap().clone();
+            if !show_sockets || !self.should_display(&format!("socket{socket_id}"), power) {
+                continue;
+            }
 
-            let mut to_print = format!("Socket{socket_id}\t{power_str} W |\t");
+            let mut to_print = format!("Socket{socket_id}\t{power_str} |\t");
+            if !show_domains {
+                lines.push(format!("{to_print}\n"));
+                continue;
+            }
 
             let domains = metrics.iter().filter(|x| {
                 x.name == "scaph_domain_power_microwatts"
@@ -171,22 +345,59 @@ impl StdoutExporter {
                     }) {
                         let _ = write!(
                             to_print,
-                            "{} W\t",
-                            current_domain
-                                .metric_value
-                                .to_string()
-                                .parse::<f32>()
-                                .unwrap()
-                                / 1000000.0
+                            "{}\t",
+                            self.format_power(
+                                current_domain
+                                    .metric_value
+                                    .to_string()
+                                    .parse::<f64>()
+                                    .unwrap()
+                            )
                         );
                     } else {
                         to_print.push_str("---");
                     }
                 }
-                println!("{to_print}\n");
+                lines.push(format!("{to_print}\n"));
             } else {
-                println!("{to_print} Could'nt get per-domain metrics.\n");
+                lines.push(format!("{to_print} Could'nt get per-domain metrics.\n"));
+            }
+        }
+        lines
+    }
+
+    fn summarized_view(&mut self, metrics: Vec<Metric>) {
+        let mut metrics_iter = metrics.iter();
+        let none_value = MetricValueType::Text("0".to_string());
+        let mut host_power_source = String::from("");
+        let host_power = match metrics_iter.find(|x| x.name == "scaph_host_power_microwatts") {
+            Some(m) => {
+                if let Some(src) = &m.attributes.get("value_source") {
+                    host_power_source = src.to_string()
+                }
+                &m.metric_value
             }
+            None => &none_value,
+        };
+
+        let domain_names = self.metric_generator.topology.domains_names.clone();
+        let domain_names = domain_names.as_ref();
+        if domain_names.is_some() {
+            info!("domain_names: {:?}", domain_names.unwrap());
+        }
+
+        let host_microwatts = format!("{host_power}").parse::<f64>().unwrap();
+        self.host_energy.add(host_microwatts);
+        if self.should_display("host", host_microwatts / 1000000.0) {
+            println!(
+                "Host:\t{} from {}",
+                self.format_power(host_microwatts),
+                host_power_source
+            );
+        }
+
+        for line in self.sockets_view(&metrics) {
+            println!("{line}");
         }
 
         let consumers: Vec<(IProcess, f64)>;
@@ -196,7 +407,7 @@ impl StdoutExporter {
                 .metric_generator
                 .topology
                 .proc_tracker
-                .get_filtered_processes(regex);
+                .get_top_consumers(u16::MAX);
         } else {
             let n = self.args.processes;
             println!("Top {n} consumers:");
@@ -221,12 +432,16 @@ impl StdoutExporter {
                         false
                     }
                 }) {
-                    println!(
-                        "{} W\t{}\t{:?}",
-                        format!("{}", process.metric_value).parse::<f32>().unwrap() / 1000000.0,
-                        process.attributes.get("pid").unwrap(),
-                        process.attributes.get("exe").unwrap()
-                    );
+                    let microwatts = format!("{}", process.metric_value).parse::<f64>().unwrap();
+                    let pid = process.attributes.get("pid").unwrap();
+                    if self.should_display(&format!("pid{pid}"), microwatts / 1000000.0) {
+                        println!(
+                            "{}\t{}\t{:?}",
+                            self.format_power(microwatts),
+                            pid,
+                            process.attributes.get("exe").unwrap()
+                        );
+                    }
                 }
             }
         }
@@ -236,7 +451,21 @@ impl StdoutExporter {
     fn raw_metrics_view(&mut self, metrics: Vec<Metric>) {
         println!("## At {}", current_system_time_since_epoch().as_secs());
         for m in metrics {
+            #[cfg(feature = "json")]
             let serialized_data = serde_json::to_string(&m.attributes).unwrap();
+            // same object as serde_json would give, quoting strings the Rust way
+            #[cfg(not(feature = "json"))]
+            let serialized_data = format!(
+                "{{{}}}",
+                m.sorted_attributes()
+                    .iter()
+                    .map(|(name, value)| match value {
+                        AttributeValue::Text(text) => format!("{name:?}:{text:?}"),
+                        _ => format!("{name:?}:{value}"),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
             println!(
                 "{} = {} {} # {}",
                 m.name, m.metric_value, serialized_data, m.description
@@ -244,6 +473,27 @@ impl StdoutExporter {
         }
     }
 
+    fn csv_view(&mut self, metrics: Vec<Metric>) {
+        for m in metrics {
+            let labels = m
+                .sorted_attributes()
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>();
+            let timestamp = match self.args.timestamp_format {
+                TimestampFormat::Epoch => m.timestamp.as_secs_f64().to_string(),
+                TimestampFormat::Rfc3339 => utils::format_rfc3339(m.timestamp, self.args.timezone),
+            };
+            println!(
+                "{},{},{},{}",
+                timestamp,
+                csv_field(&m.name),
+                csv_field(&m.metric_value.to_string()),
+                csv_field(&labels.join(";"))
+            );
+        }
+    }
+
     fn show_metrics(&mut self) {
         self.metric_generator.gen_all_metrics();
 
@@ -251,16 +501,145 @@ impl StdoutExporter {
 
         if self.args.raw_metrics {
             self.raw_metrics_view(metrics);
+        } else if self.args.csv {
+            self.csv_view(metrics);
         } else {
             self.summarized_view(metrics);
         }
     }
 }
 
+/// Quotes `field` if it contains a comma, a quote or a line break, doubling its quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     //#[test]
     //fn get_cons_socket0() {}
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ExporterArgs,
+    }
+
+    /// Returns an exporter with these options, on a topology of one socket with
+    /// two domains if `with_sockets`.
+    fn exporter(options: &[&str], with_sockets: bool) -> StdoutExporter {
+        use crate::sensors::{StaticSensor, Topology};
+        use clap::Parser;
+
+        let mut topology = Topology::new(HashMap::new());
+        if with_sockets {
+            topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+            for (id, name) in [(0, "core"), (1, "dram")] {
+                topology.safe_add_domain_to_socket(0, id, name, "", 1, HashMap::new());
+            }
+        }
+        let args = Cli::parse_from([&["stdout"], options].concat()).args;
+        StdoutExporter::new(&StaticSensor(topology), args)
+    }
+
+    fn power_metric(name: &str, attributes: &[(&str, &str)], microwatts: u64) -> Metric {
+        Metric {
+            name: String::from(name),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::from_secs(1700000000),
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![String::from("scaphandre")],
+            attributes: attributes
+                .iter()
+                .map(|(key, value)| (key.to_string(), (*value).into()))
+                .collect(),
+            description: String::new(),
+            metric_value: MetricValueType::IntUnsigned(microwatts),
+        }
+    }
+
+    #[test]
+    fn regex_filter_filters_tracked_processes() {
+        use sysinfo::{ProcessExt, SystemExt};
+        let exe = std::env::current_exe().unwrap();
+        let name = regex::escape(exe.file_name().unwrap().to_str().unwrap());
+        let mut stdout = exporter(&["-r", &name], false);
+        let topology = &mut stdout.metric_generator.topology;
+        topology.refresh();
+        let tracker = &topology.proc_tracker;
+        let regex = Regex::new(&name).unwrap();
+        let pids = tracker.get_all_pids();
+        assert!(pids.contains(&sysinfo::get_current_pid().unwrap()));
+        for pid in pids {
+            let process = tracker.sysinfo.process(pid).unwrap();
+            assert!(
+                regex.is_match(process.exe().to_str().unwrap())
+                    || regex.is_match(&process.cmd().concat())
+            );
+        }
+    }
+
+    #[test]
+    fn sockets_view() {
+        let metrics = vec![
+            power_metric(
+                "scaph_socket_power_microwatts",
+                &[("socket_id", "0")],
+                30_000_000,
+            ),
+            power_metric(
+                "scaph_domain_power_microwatts",
+                &[("socket_id", "0"), ("domain_name", "core")],
+                20_000_000,
+            ),
+            power_metric(
+                "scaph_domain_power_microwatts",
+                &[("socket_id", "0"), ("domain_name", "dram")],
+                5_000_000,
+            ),
+        ];
+        // sockets and their domains are displayed by default
+        let mut stdout = exporter(&[], true);
+        let (socket, core, dram) = (
+            stdout.format_power(30_000_000.0),
+            stdout.format_power(20_000_000.0),
+            stdout.format_power(5_000_000.0),
+        );
+        assert_eq!(
+            stdout.sockets_view(&metrics),
+            vec![
+                String::from("\tpackage \tcore\t\tdram"),
+                format!("Socket0\t{socket} |\t{core}\t{dram}\t\n"),
+            ]
+        );
+        assert_eq!(
+            exporter(&["--no-domains"], true).sockets_view(&metrics),
+            vec![format!("Socket0\t{socket} |\t\n")]
+        );
+        assert!(exporter(&["--no-sockets"], true)
+            .sockets_view(&metrics)
+            .is_empty());
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(
+            csv_field("scaph_host_power_microwatts"),
+            "scaph_host_power_microwatts"
+        );
+        assert_eq!(
+            csv_field("cmdline=nginx -g daemon off;, \"worker\""),
+            "\"cmdline=nginx -g daemon off;, \"\"worker\"\"\""
+        );
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -1,9 +1,19 @@
 //! # utils
 //!
 //! The utils module provides common functions used by the exporters.
+use super::forecast::Horizon;
+use super::naming::MetricNaming;
+use super::nic_energy::NicEnergyModel;
+use super::MetricGenerator;
 use clap::crate_version;
+#[cfg(any(feature = "prometheuspush", feature = "warpten"))]
+use isahc::{
+    config::{CaCertificate, ClientCertificate, Configurable, PrivateKey},
+    HttpClient,
+};
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
 #[cfg(feature = "containers")]
 use {
     docker_sync::Docker,
@@ -13,32 +23,354 @@ use {
 /// Default ipv4/ipv6 address to expose the service is any
 pub const DEFAULT_IP_ADDRESS: &str = "::";
 
+/// Holds the HTTP(s) client options shared by all exporters pushing data over HTTP.
+///
+/// Those are global options: they can be given before or after the exporter subcommand.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct HttpClientArgs {
+    /// Proxy to use for the HTTP requests sent by the exporters (example: http://proxy.local:3128)
+    #[arg(long, global = true, value_name = "URL")]
+    pub http_proxy: Option<String>,
+
+    /// Proxy to use for the HTTPS requests sent by the exporters (example: http://proxy.local:3128)
+    #[arg(long, global = true, value_name = "URL")]
+    pub https_proxy: Option<String>,
+
+    /// CA certificate file (.pem format) to trust when sending HTTPS requests
+    #[arg(long, global = true, value_name = "FILE")]
+    pub ca_cert: Option<String>,
+
+    /// Client certificate file (.pem format) presented when sending HTTPS requests,
+    /// for servers requiring mutual TLS
+    #[arg(long, global = true, value_name = "FILE", requires = "client_key")]
+    pub client_cert: Option<String>,
+
+    /// Private key file (.pem format) of the client certificate
+    #[arg(long, global = true, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<String>,
+}
+
+impl HttpClientArgs {
+    /// Returns the proxy to use to reach a destination using `scheme` ("http" or "https"), if any.
+    pub fn proxy_for_scheme(&self, scheme: &str) -> Option<&str> {
+        if scheme.eq_ignore_ascii_case("https") {
+            self.https_proxy.as_deref()
+        } else {
+            self.http_proxy.as_deref()
+        }
+    }
+}
+
+/// Holds the options of the exporters that publish the metrics of a [MetricGenerator],
+/// set on it with [apply](MetricGeneratorArgs::apply).
+#[derive(clap::Args, Clone, Debug)]
+pub struct MetricGeneratorArgs {
+    /// File of rules adding labels (service, team...) to the metrics of matching processes.
+    /// Each line holds a field (exe, cmdline or cgroup), a regex and comma-separated name=value labels
+    #[arg(long, value_name = "FILE")]
+    pub service_mapping: Option<String>,
+
+    /// File holding the wall power of the host in watts, kept up to date by an external meter
+    /// (smart plug, USB power meter...), to publish the error of the computed host power
+    #[arg(long, value_name = "FILE")]
+    pub reference_power_file: Option<String>,
+
+    /// Smart plug measuring the wall power of the host, as <kind>://<address> (kind being shelly,
+    /// tasmota or kasa), to publish the error of the computed host power
+    #[cfg(feature = "smartplug")]
+    #[arg(long, value_name = "PLUG", conflicts_with = "reference_power_file")]
+    pub reference_smartplug: Option<String>,
+
+    /// Split the power of the core, uncore and dram RAPL domains between processes,
+    /// on CPU time, resident memory and evenly, respectively
+    #[arg(long)]
+    pub domain_attribution: bool,
+
+    /// Publish a forecast of the host power at this horizon (e.g. 15m, 1h, 1d) as
+    /// scaph_host_power_forecast_microwatts. Can be repeated
+    #[arg(long = "forecast-horizon", value_name = "HORIZON")]
+    pub forecast_horizons: Vec<Horizon>,
+
+    /// Detect anomalies in the power of the host and containers, publishing
+    /// scaph_power_anomaly when the z-score of a measurement is over this value (3 is a good start)
+    #[arg(long, value_name = "ZSCORE")]
+    pub anomaly_threshold: Option<f64>,
+
+    /// URL to POST a JSON notification to when an anomaly starts
+    #[cfg(feature = "webhook")]
+    #[arg(long, value_name = "URL", requires = "anomaly_threshold")]
+    pub anomaly_webhook: Option<String>,
+
+    /// Publish the energy consumed by the host and each process, integrated from their power
+    #[arg(long)]
+    pub energy_totals: bool,
+
+    /// File where the energy totals are saved, and restored from at start, so that
+    /// restarting scaphandre doesn't reset them (implies --energy-totals)
+    #[arg(long, value_name = "FILE")]
+    pub state_file: Option<String>,
+
+    /// Time between two saves of the state file, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub state_save_interval: u64,
+
+    /// Publish the power and energy of the pods aggregated by the values of these
+    /// pod labels (e.g. app,team), requires --containers
+    #[arg(long, value_name = "LABEL", value_delimiter = ',')]
+    pub aggregate_by_label: Vec<String>,
+
+    /// Add the time over which power metrics are computed, in seconds, as an
+    /// interval_seconds label
+    #[arg(long)]
+    pub interval_attribute: bool,
+
+    /// Add in_container and cgroup labels to process metrics, found from /proc/PID/cgroup
+    /// (works without --containers)
+    #[arg(long)]
+    pub cgroup_attributes: bool,
+
+    /// Compare the average power of the pods and containers over this many seconds with
+    /// their scaphandre.io/power-budget-watts annotation or label, publishing
+    /// scaph_container_power_budget_violation, requires --containers
+    #[arg(long, value_name = "SECONDS")]
+    pub power_budget_window: Option<u64>,
+
+    /// URL to POST a JSON notification to when a pod or container goes over its power budget
+    #[cfg(feature = "webhook")]
+    #[arg(long, value_name = "URL", requires = "power_budget_window")]
+    pub power_budget_webhook: Option<String>,
+
+    /// Power the host should stay under, in watts, to publish the headroom left under it
+    /// as scaph_host_power_headroom_microwatts
+    #[arg(long, value_name = "WATTS")]
+    pub power_cap: Option<f64>,
+
+    /// Publish the voluntary and nonvoluntary context switches of each process, frequent
+    /// wakeups keeping the CPU out of its idle states (Linux only)
+    #[arg(long)]
+    pub context_switches: bool,
+
+    /// File holding the labels of the Kubernetes node, one key="value" line per label
+    /// as in a Downward API volume: the allowed labels, and the node name from the
+    /// NODE_NAME environment variable, are added to all metrics
+    #[arg(long, value_name = "PATH")]
+    pub node_labels_file: Option<String>,
+
+    /// Node labels to add to the metrics (zone, region, instance type and node pool
+    /// labels by default)
+    #[arg(
+        long,
+        value_name = "LABEL",
+        value_delimiter = ',',
+        requires = "node_labels_file"
+    )]
+    pub node_labels: Vec<String>,
+
+    /// Maximum length of the cmdline label of process metrics, in characters
+    #[arg(long, value_name = "CHARS", default_value_t = DEFAULT_CMDLINE_MAX_LENGTH)]
+    pub max_cmdline_length: usize,
+
+    /// Also publish the metrics whose stability is experimental (see `scaphandre metrics list`)
+    #[arg(long)]
+    pub include_experimental: bool,
+
+    /// Publish the start time of each process and how many times the processes of an
+    /// executable and a cgroup exited and were started again (crash loops)
+    #[arg(long)]
+    pub process_restarts: bool,
+
+    /// Publish the power and energy of each SLURM or PBS job running on the host, found
+    /// from the cgroups of its processes (Linux only)
+    #[arg(long)]
+    pub jobs: bool,
+
+    /// Publish the rate of change of the host and socket power, fitted over this
+    /// window in seconds, as scaph_host_power_slope_microwatts_per_second and
+    /// scaph_socket_power_slope_microwatts_per_second
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(2..))]
+    pub power_slope_window: Option<u64>,
+
+    /// Power of the storage devices of the host, in watts, to split between processes
+    /// as scaph_process_storage_power_microwatts
+    #[arg(long, value_name = "WATTS")]
+    pub storage_power: Option<f64>,
+
+    /// Share of the storage power, from 0 to 1, split on the time processes wait
+    /// for block I/O, the rest being split on the bytes they read and write
+    #[arg(
+        long,
+        value_name = "WEIGHT",
+        default_value_t = 1.0,
+        requires = "storage_power"
+    )]
+    pub iowait_weight: f64,
+
+    /// Energy per gigabyte transferred by the network interfaces, by speed class of their
+    /// link, as <Mb/s>=<J/GB> pairs (e.g. 1000=2,10000=0.8), to publish
+    /// scaph_host_network_power_microwatts
+    #[arg(long, value_name = "CLASSES")]
+    pub nic_joules_per_gb: Option<NicEnergyModel>,
+
+    /// File defining metrics computed from the others, one <name> = <expression> line per
+    /// metric (e.g. efficiency = scaph_host_power_microwatts / http_requests_rate), the prometheus
+    /// exporter taking the values of the names that aren't metrics with PUT /values/<name>
+    #[arg(long, value_name = "FILE")]
+    pub derived_metrics: Option<String>,
+}
+
+impl MetricGeneratorArgs {
+    /// Sets these options on `metric_generator`.
+    pub fn apply(&self, metric_generator: &mut MetricGenerator) {
+        if let Some(path) = &self.service_mapping {
+            metric_generator
+                .set_service_mapping(path)
+                .expect("service mapping file should be valid");
+        }
+        if let Some(path) = &self.reference_power_file {
+            metric_generator.set_reference_power_file(path);
+        }
+        #[cfg(feature = "smartplug")]
+        if let Some(plug) = &self.reference_smartplug {
+            metric_generator
+                .set_reference_smartplug(plug)
+                .expect("reference smart plug should be valid");
+        }
+        metric_generator.set_domain_attribution(self.domain_attribution);
+        metric_generator.set_forecast_horizons(&self.forecast_horizons);
+        if let Some(threshold) = self.anomaly_threshold {
+            #[cfg(feature = "webhook")]
+            let webhook = self.anomaly_webhook.clone();
+            #[cfg(not(feature = "webhook"))]
+            let webhook = None;
+            metric_generator.set_anomaly_detection(threshold, webhook);
+        }
+        if self.energy_totals || self.state_file.is_some() {
+            metric_generator.set_energy_totals(
+                self.state_file.as_deref(),
+                Duration::from_secs(self.state_save_interval),
+            );
+        }
+        metric_generator.set_label_groups(&self.aggregate_by_label);
+        metric_generator.set_interval_attribute(self.interval_attribute);
+        metric_generator.set_cgroup_attributes(self.cgroup_attributes);
+        if let Some(window) = self.power_budget_window {
+            #[cfg(feature = "webhook")]
+            let webhook = self.power_budget_webhook.clone();
+            #[cfg(not(feature = "webhook"))]
+            let webhook = None;
+            metric_generator.set_power_budgets(Duration::from_secs(window), webhook);
+        }
+        metric_generator.set_power_cap(self.power_cap);
+        metric_generator.set_context_switches(self.context_switches);
+        if let Some(path) = &self.node_labels_file {
+            metric_generator.set_node_labels(path, &self.node_labels);
+        }
+        metric_generator.set_cmdline_max_length(self.max_cmdline_length);
+        metric_generator.set_include_experimental(self.include_experimental);
+        metric_generator.set_process_restarts(self.process_restarts);
+        metric_generator.set_jobs(self.jobs);
+        metric_generator.set_power_slope_window(self.power_slope_window.map(Duration::from_secs));
+        metric_generator.set_storage_attribution(self.storage_power, self.iowait_weight);
+        metric_generator.set_nic_energy_model(self.nic_joules_per_gb.clone());
+        if let Some(path) = &self.derived_metrics {
+            metric_generator
+                .set_derived_metrics(path)
+                .expect("derived metrics file should be valid");
+        }
+    }
+}
+
+/// Holds the naming option of the exporters publishing the metrics under their names
+/// (the stdout exporter finds the metrics it displays by their scaphandre names).
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct MetricNamingArgs {
+    /// Names of the published metrics: scaphandre, or kepler to publish the energy counters
+    /// under the names of Kepler, in joules, and reuse its dashboards
+    #[arg(long, value_enum, value_name = "NAMING", default_value_t = MetricNaming::Scaphandre)]
+    pub metric_naming: MetricNaming,
+}
+
+/// Holds the options of the exporters measuring at a fixed step.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct StepArgs {
+    /// Align measurements on wall-clock multiples of the step (e.g. :00, :15, :30 and :45 with a 15 seconds step),
+    /// so that time series from several hosts can be joined on their timestamps
+    #[arg(long)]
+    pub align_timestamps: bool,
+}
+
+/// Returns an HTTP client configured with the proxy, CA and client certificate
+/// options in `args`, for a destination reached with `scheme`.
+///
+/// When no proxy is given, the usual http_proxy/https_proxy environment variables
+/// are still honored by the underlying client.
+#[cfg(any(feature = "prometheuspush", feature = "warpten"))]
+pub fn get_http_client(args: &HttpClientArgs, scheme: &str) -> Result<HttpClient, String> {
+    let mut builder = HttpClient::builder();
+    if let Some(proxy) = args.proxy_for_scheme(scheme) {
+        match proxy.parse() {
+            Ok(uri) => {
+                debug!("Using proxy {} for {} requests", proxy, scheme);
+                builder = builder.proxy(Some(uri));
+            }
+            Err(e) => return Err(format!("Invalid proxy URL {proxy}: {e}")),
+        }
+    }
+    if let Some(ca_cert) = &args.ca_cert {
+        builder = builder.ssl_ca_certificate(CaCertificate::file(ca_cert));
+    }
+    if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+        // the files are read for each new connection, so they can be renewed in place
+        builder = builder.ssl_client_certificate(ClientCertificate::pem_file(
+            cert,
+            PrivateKey::pem_file(key, None),
+        ));
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Couldn't build HTTP client: {e}"))
+}
+
+/// Default maximum length of the cmdline attribute, in characters.
+pub const DEFAULT_CMDLINE_MAX_LENGTH: usize = 256;
+
 /// Returns a cmdline String filtered from potential characters that
 /// could break exporters output.
 ///
-/// Here we replace:
-/// 1. Double quote by backslash double quote.
-/// 2. Remove carriage return.
-pub fn filter_cmdline(cmdline: &str) -> String {
-    cmdline.replace('\"', "\\\"").replace('\n', "")
+/// Control characters (carriage returns, tabulations...) are removed, and the
+/// result is cut to `max_length` characters: command lines such as Java
+/// classpaths can be megabytes long.
+pub fn filter_cmdline(cmdline: &str, max_length: usize) -> String {
+    cmdline
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_length)
+        .collect()
+}
+
+/// Escapes the backslashes, double quotes and line feeds of a label value,
+/// as the Prometheus exposition format requires.
+pub fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
 }
 
 /// Returns a well formatted Prometheus metric string.
-pub fn format_prometheus_metric(
+pub fn format_prometheus_metric<V: fmt::Display>(
     key: &str,
     value: &str,
-    labels: Option<&HashMap<String, String>>,
+    labels: Option<&HashMap<String, V>>,
 ) -> String {
     let mut result = key.to_string();
     if let Some(labels) = labels {
         result.push('{');
-        for (k, v) in labels.iter() {
-            let _ = write!(
-                result,
-                "{}=\"{}\",",
-                k,
-                v.replace('\"', "_").replace('\\', "")
-            );
+        // sorted, so that a series is rendered the same way at each scrape
+        let mut labels: Vec<_> = labels.iter().collect();
+        labels.sort_by(|a, b| a.0.cmp(b.0));
+        for (k, v) in labels {
+            let _ = write!(result, "{}=\"{}\",", k, escape_label_value(&v.to_string()));
         }
         result.remove(result.len() - 1);
         result.push('}');
@@ -47,6 +379,172 @@ pub fn format_prometheus_metric(
     result
 }
 
+/// Returns the time to wait, from `now` (a duration since the epoch), to reach the next
+/// wall-clock boundary that is a multiple of `step`.
+///
+/// For example, with a 15 seconds step, boundaries are :00, :15, :30 and :45 of each minute,
+/// so that several hosts produce samples at the same timestamps.
+pub fn duration_until_next_boundary(now: Duration, step: Duration) -> Duration {
+    let step_nanos = step.as_nanos();
+    if step_nanos == 0 {
+        return Duration::ZERO;
+    }
+    let elapsed_in_step = now.as_nanos() % step_nanos;
+    Duration::from_nanos((step_nanos - elapsed_in_step) as u64)
+}
+
+/// Sleeps for `step`, or until the next wall-clock multiple of `step` if `align` is true.
+pub fn wait_for_next_step(step: Duration, align: bool) {
+    if align {
+        let now = crate::sensors::utils::current_system_time_since_epoch();
+        std::thread::sleep(duration_until_next_boundary(now, step));
+    } else {
+        std::thread::sleep(step);
+    }
+}
+
+/// Energy consumed by the host during a run, integrated from the power of the
+/// host at each measurement, assuming it stayed at the average of two
+/// successive measurements.
+#[derive(Debug, Clone, Default)]
+pub struct EnergyTotal {
+    /// Energy consumed since the first measurement, in microjoules
+    pub microjoules: f64,
+    /// Number of measurements
+    pub measurements: u64,
+    first: Option<Instant>,
+    last: Option<(Instant, f64)>,
+}
+
+impl EnergyTotal {
+    /// Adds a measurement of the power of the host, in microwatts, taken now.
+    pub fn add(&mut self, microwatts: f64) {
+        self.add_at(Instant::now(), microwatts);
+    }
+
+    /// Adds a measurement of the power of the host, in microwatts, taken at `now`.
+    pub(crate) fn add_at(&mut self, now: Instant, microwatts: f64) {
+        if let Some((previous_time, previous_microwatts)) = self.last {
+            let elapsed = now.duration_since(previous_time).as_secs_f64();
+            self.microjoules += (previous_microwatts + microwatts) / 2.0 * elapsed;
+        }
+        self.first.get_or_insert(now);
+        self.last = Some((now, microwatts));
+        self.measurements += 1;
+    }
+
+    /// Returns the time between the first and the last measurement.
+    pub fn span(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some((last, _))) => last.duration_since(first),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Returns the average power of the host between the first and the last
+    /// measurement, in microwatts, if there were at least two of them.
+    pub fn average_microwatts(&self) -> Option<f64> {
+        let span = self.span().as_secs_f64();
+        (span > 0.0).then(|| self.microjoules / span)
+    }
+}
+
+/// How the exporters writing files (JSON reports, CSV lines) write timestamps.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// Seconds since the epoch, with their decimals (e.g. 1700000000.5)
+    #[default]
+    Epoch,
+    /// RFC 3339 date and time, with milliseconds, in the --timezone (e.g. 2023-11-14T22:13:20.500Z)
+    Rfc3339,
+}
+
+/// Time zone of the RFC 3339 timestamps: UTC, the one of the host, or a fixed offset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampZone {
+    #[default]
+    Utc,
+    Local,
+    Offset(chrono::FixedOffset),
+}
+
+/// Parses a --timezone option: "UTC" (or "Z"), "local", or an offset such as "+02:00" or "-0530".
+///
+/// Named zones such as Europe/Paris would need a time zone database: on a host set to such a
+/// zone, "local" follows its daylight saving time changes.
+pub fn parse_timestamp_zone(value: &str) -> Result<TimestampZone, String> {
+    if value.eq_ignore_ascii_case("utc") || value == "Z" {
+        return Ok(TimestampZone::Utc);
+    }
+    if value.eq_ignore_ascii_case("local") {
+        return Ok(TimestampZone::Local);
+    }
+    let invalid =
+        || format!("invalid time zone {value:?}, expected UTC, local or an offset such as +02:00");
+    let (sign, offset) = if let Some(offset) = value.strip_prefix('+') {
+        (1, offset)
+    } else if let Some(offset) = value.strip_prefix('-') {
+        (-1, offset)
+    } else {
+        return Err(invalid());
+    };
+    let digits = offset.replacen(':', "", 1);
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+    let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+        .map(TimestampZone::Offset)
+        .ok_or_else(invalid)
+}
+
+/// Formats `timestamp`, a duration since the epoch, as an RFC 3339 date and time in `zone`.
+pub fn format_rfc3339(timestamp: Duration, zone: TimestampZone) -> String {
+    use chrono::{SecondsFormat, TimeZone};
+    let utc = chrono::Utc
+        .timestamp_opt(timestamp.as_secs() as i64, timestamp.subsec_nanos())
+        .single()
+        .unwrap_or_default();
+    match zone {
+        TimestampZone::Utc => utc.to_rfc3339_opts(SecondsFormat::Millis, true),
+        TimestampZone::Local => utc
+            .with_timezone(&chrono::Local)
+            .to_rfc3339_opts(SecondsFormat::Millis, false),
+        TimestampZone::Offset(offset) => utc
+            .with_timezone(&offset)
+            .to_rfc3339_opts(SecondsFormat::Millis, false),
+    }
+}
+
+/// Reads a reference wall power measurement, in watts, from a file kept up to date by
+/// an external tool (smart plug poller, serial power meter reader...).
+///
+/// Returns the power in microwatts and the modification time of the file, or None if the
+/// file can't be read, doesn't contain a number or hasn't been updated for `max_age`.
+pub fn read_reference_power_microwatts(path: &str, max_age: Duration) -> Option<(Duration, f64)> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    let now = crate::sensors::utils::current_system_time_since_epoch();
+    if now.saturating_sub(modified) > max_age {
+        debug!("Reference power file {} is outdated, ignoring it.", path);
+        return None;
+    }
+    match std::fs::read_to_string(path).ok()?.trim().parse::<f64>() {
+        Ok(watts) => Some((modified, watts * 1_000_000.0)),
+        Err(e) => {
+            warn!("Couldn't parse reference power from {}: {}", path, e);
+            None
+        }
+    }
+}
+
 /// Returns an Option containing the VM name of a qemu process.
 ///
 /// Then VM name is extracted from the command line.
@@ -88,6 +586,169 @@ pub fn get_hostname() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_proxy_for_scheme() {
+        let args = HttpClientArgs {
+            http_proxy: Some(String::from("http://proxy:3128")),
+            https_proxy: Some(String::from("http://secureproxy:3128")),
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+        };
+        assert_eq!(args.proxy_for_scheme("http"), Some("http://proxy:3128"));
+        assert_eq!(
+            args.proxy_for_scheme("https"),
+            Some("http://secureproxy:3128")
+        );
+        assert_eq!(HttpClientArgs::default().proxy_for_scheme("https"), None);
+    }
+
+    #[test]
+    fn test_energy_total() {
+        let mut total = EnergyTotal::default();
+        assert_eq!(total.average_microwatts(), None);
+        let start = Instant::now();
+        total.add_at(start, 10_000_000.0);
+        total.add_at(start + Duration::from_secs(2), 20_000_000.0);
+        total.add_at(start + Duration::from_secs(3), 20_000_000.0);
+        // 15 W during 2 s, then 20 W during 1 s
+        assert_eq!(total.microjoules, 50_000_000.0);
+        assert_eq!(total.measurements, 3);
+        assert_eq!(total.span(), Duration::from_secs(3));
+        assert_eq!(total.average_microwatts(), Some(50_000_000.0 / 3.0));
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        let timestamp = Duration::from_millis(1_700_000_000_500);
+        assert_eq!(
+            format_rfc3339(timestamp, parse_timestamp_zone("UTC").unwrap()),
+            "2023-11-14T22:13:20.500Z"
+        );
+        assert_eq!(
+            format_rfc3339(timestamp, parse_timestamp_zone("+02:00").unwrap()),
+            "2023-11-15T00:13:20.500+02:00"
+        );
+        assert_eq!(
+            format_rfc3339(timestamp, parse_timestamp_zone("-0530").unwrap()),
+            "2023-11-14T16:43:20.500-05:30"
+        );
+        assert_eq!(parse_timestamp_zone("Local"), Ok(TimestampZone::Local));
+        for invalid in ["Europe/Paris", "+2", "+24:00", "-01:60", "02:00"] {
+            assert!(parse_timestamp_zone(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_format_typed_labels() {
+        use crate::exporters::AttributeValue;
+        let mut labels = HashMap::new();
+        labels.insert(String::from("pid"), AttributeValue::from(42_u32));
+        assert_eq!(
+            format_prometheus_metric("scaph_process_power", "12", Some(&labels)),
+            "scaph_process_power{pid=\"42\"} 12\n"
+        );
+        labels.insert(String::from("pid"), AttributeValue::from(true));
+        assert_eq!(labels["pid"].as_bool(), Some(true));
+        assert_eq!(labels["pid"].as_i64(), None);
+        let pid = AttributeValue::from(42_u32);
+        assert!(pid == *"42");
+        assert_eq!(AttributeValue::from("42").as_i64(), Some(42));
+    }
+
+    #[test]
+    fn test_format_escaped_labels() {
+        let labels: HashMap<String, &str> =
+            HashMap::from([(String::from("cmdline"), "sh-cecho \"a\\b\"\nc")]);
+        assert_eq!(
+            format_prometheus_metric("m", "1", Some(&labels)),
+            "m{cmdline=\"sh-cecho \\\"a\\\\b\\\"\\nc\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn test_format_sorted_labels() {
+        let labels: HashMap<String, &str> = ["socket_id", "exe", "pid", "cmdline"]
+            .iter()
+            .map(|k| (k.to_string(), "x"))
+            .collect();
+        assert_eq!(
+            format_prometheus_metric("m", "1", Some(&labels)),
+            "m{cmdline=\"x\",exe=\"x\",pid=\"x\",socket_id=\"x\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn test_duration_until_next_boundary() {
+        let step = Duration::from_secs(15);
+        assert_eq!(
+            duration_until_next_boundary(Duration::from_secs(1_700_000_002), step),
+            Duration::from_secs(8)
+        );
+        assert_eq!(
+            duration_until_next_boundary(Duration::from_millis(1_700_000_009_500), step),
+            Duration::from_millis(500)
+        );
+        // already on a boundary: wait for the next one
+        assert_eq!(
+            duration_until_next_boundary(Duration::from_secs(1_700_000_010), step),
+            step
+        );
+        assert_eq!(
+            duration_until_next_boundary(Duration::from_secs(42), Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_read_reference_power_microwatts() {
+        let path = std::env::temp_dir().join("scaphandre_test_reference_power");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "42.5\n").unwrap();
+        let (_, microwatts) =
+            read_reference_power_microwatts(path, Duration::from_secs(60)).unwrap();
+        assert_eq!(microwatts, 42_500_000.0);
+        std::fs::write(path, "n/a").unwrap();
+        assert!(read_reference_power_microwatts(path, Duration::from_secs(60)).is_none());
+        std::fs::remove_file(path).unwrap();
+        assert!(read_reference_power_microwatts(path, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_shared_exporter_args() {
+        use clap::Parser;
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            generator: MetricGeneratorArgs,
+            #[command(flatten)]
+            step_args: StepArgs,
+        }
+        let cli = Cli::parse_from([
+            "exporter",
+            "--reference-power-file",
+            "/run/wall_power",
+            "--domain-attribution",
+            "--align-timestamps",
+        ]);
+        assert_eq!(
+            cli.generator.reference_power_file.as_deref(),
+            Some("/run/wall_power")
+        );
+        assert!(cli.generator.domain_attribution);
+        assert!(cli.generator.service_mapping.is_none());
+        assert!(cli.step_args.align_timestamps);
+        #[cfg(feature = "smartplug")]
+        assert!(Cli::try_parse_from([
+            "exporter",
+            "--reference-power-file",
+            "/run/wall_power",
+            "--reference-smartplug",
+            "shelly://10.0.0.2",
+        ])
+        .is_err());
+    }
+
     #[test]
     fn test_filter_qemu_cmdline_ok() {
         let cmdline = "file=/var/lib/libvirt/qemu/domain-1-fedora33/master-key.aes-object-Sguest=fedora33,debug-threads=on-name/usr/bin/qemu-system-x86_64";
@@ -156,11 +817,19 @@ pub fn get_kubernetes_client() -> Result<Kubernetes, KubernetesError> {
 fn test_filter_cmdline_with_carriage_return() {
     let cmdline = "bash-csleep infinity;\n> echo plop";
     assert_eq!(
-        filter_cmdline(cmdline),
+        filter_cmdline(cmdline, DEFAULT_CMDLINE_MAX_LENGTH),
         String::from("bash-csleep infinity;> echo plop")
     );
 }
 
+#[test]
+fn test_filter_cmdline_max_length() {
+    let classpath = format!("java-cp{}", "/opt/lib/é.jar:".repeat(100_000));
+    let filtered = filter_cmdline(&classpath, DEFAULT_CMDLINE_MAX_LENGTH);
+    assert_eq!(filtered.chars().count(), DEFAULT_CMDLINE_MAX_LENGTH);
+    assert!(filtered.starts_with("java-cp/opt/lib/é.jar:"));
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -1,7 +1,11 @@
-use super::utils::get_hostname;
+use super::utils::{
+    get_hostname, get_http_client, HttpClientArgs, MetricGeneratorArgs, MetricNamingArgs, StepArgs,
+};
 use crate::exporters::*;
 use crate::sensors::Sensor;
+use isahc::{http::StatusCode, prelude::*, HttpClient, Request};
 use std::time::Duration;
+use warp10::Warp10Serializable;
 
 /// An exporter that sends power consumption data of the host and its processes to
 /// a [Warp10](https://warp10.io) instance through **HTTP(s)**
@@ -10,10 +14,14 @@ pub struct Warp10Exporter {
     metric_generator: MetricGenerator,
     /// Warp10 client
     client: warp10::Client,
+    /// HTTP client used to send the data to Warp10
+    http_client: HttpClient,
     /// Warp10 auth token
     write_token: String,
 
     step: Duration,
+    /// Wait for wall-clock multiples of step between two measurements
+    align_timestamps: bool,
 }
 
 /// Holds the arguments for a Warp10Exporter.
@@ -43,9 +51,18 @@ pub struct ExporterArgs {
     /// Apply labels to metrics of processes looking like a Qemu/KVM virtual machine
     #[arg(short, long)]
     pub qemu: bool,
+
+    #[command(flatten)]
+    pub generator: MetricGeneratorArgs,
+
+    #[command(flatten)]
+    pub naming_args: MetricNamingArgs,
+
+    #[command(flatten)]
+    pub step_args: StepArgs,
 }
 
-const TOKEN_ENV_VAR: &str = "SCAPH_WARP10_WRITE_TOKEN";
+pub const TOKEN_ENV_VAR: &str = "SCAPH_WARP10_WRITE_TOKEN";
 
 impl Exporter for Warp10Exporter {
     /// Control loop for self.iterate()
@@ -55,23 +72,37 @@ impl Exporter for Warp10Exporter {
                 Ok(res) => debug!("Result: {:?}", res),
                 Err(err) => error!("Failed ! {:?}", err),
             }
-            std::thread::sleep(self.step);
+            utils::wait_for_next_step(self.step, self.align_timestamps);
         }
     }
 
     fn kind(&self) -> &str {
         "warp10"
     }
+
+    /// Sends an empty update, which Warp10 accepts only with a valid write token.
+    fn validate(&mut self) -> Result<String, String> {
+        let uri = self.client.update_uri();
+        post(&self.client, &self.http_client, &self.write_token, vec![])
+            .map(|_| format!("write token accepted by {uri}"))
+            .map_err(|e| format!("update rejected by {uri}: {e:?}"))
+    }
 }
 
 impl Warp10Exporter {
     /// Instantiates and returns a new Warp10Exporter
-    pub fn new(sensor: &dyn Sensor, args: ExporterArgs) -> Warp10Exporter {
+    pub fn new(
+        sensor: &dyn Sensor,
+        args: ExporterArgs,
+        http_client_args: &HttpClientArgs,
+    ) -> Warp10Exporter {
         // Prepare for measurement
         let topology = sensor
             .get_topology()
             .expect("sensor topology should be available");
-        let metric_generator = MetricGenerator::new(topology, get_hostname(), args.qemu, false);
+        let mut metric_generator = MetricGenerator::new(topology, get_hostname(), args.qemu, false);
+        args.generator.apply(&mut metric_generator);
+        metric_generator.set_metric_naming(args.naming_args.metric_naming);
 
         // Prepare for sending data to Warp10
         let scheme = args.scheme;
@@ -79,6 +110,8 @@ impl Warp10Exporter {
         let port = args.port;
         let client = warp10::Client::new(&format!("{scheme}://{host}:{port}"))
             .expect("warp10 Client could not be created");
+        let http_client = get_http_client(http_client_args, &scheme)
+            .expect("HTTP client for Warp10 could not be created");
         let write_token = args.write_token.unwrap_or_else(|| {
             std::env::var(TOKEN_ENV_VAR).unwrap_or_else(|_| panic!("No token found, you must provide either --write-token or the env var {TOKEN_ENV_VAR}"))
         });
@@ -86,8 +119,10 @@ impl Warp10Exporter {
         Warp10Exporter {
             metric_generator,
             client,
+            http_client,
             write_token,
             step: Duration::from_secs(args.step),
+            align_timestamps: args.step_args.align_timestamps,
         }
     }
 
@@ -95,36 +130,19 @@ impl Warp10Exporter {
     /// metric itself and some labels attaches, stores them in a vector and sends it
     /// to Warp10
     pub fn iterate(&mut self) -> Result<Vec<warp10::Warp10Response>, warp10::Error> {
-        let writer = self.client.get_writer(self.write_token.clone());
-        self.metric_generator
-            .topology
-            .proc_tracker
-            .clean_terminated_process_records_vectors();
-
         debug!("Refreshing topology.");
         self.metric_generator.topology.refresh();
 
         self.metric_generator.gen_all_metrics();
 
-        let mut process_data: Vec<warp10::Data> = vec![];
-
-        for metric in self.metric_generator.pop_metrics() {
-            let mut labels = vec![];
-
-            for (k, v) in &metric.attributes {
-                labels.push(warp10::Label::new(k, v));
-            }
-
-            process_data.push(warp10::Data::new(
-                time::OffsetDateTime::now_utc(),
-                None,
-                metric.name,
-                labels,
-                warp10::Value::String(metric.metric_value.to_string().replace('`', "")),
-            ));
-        }
+        let process_data = to_warp10_data(&self.metric_generator.pop_metrics());
 
-        let res = writer.post_sync(process_data)?;
+        let res = post(
+            &self.client,
+            &self.http_client,
+            &self.write_token,
+            process_data,
+        )?;
 
         let results = vec![res];
 
@@ -148,6 +166,61 @@ impl Warp10Exporter {
     }
 }
 
+/// Converts `metrics` to Warp10 data points, labelled with their attributes.
+pub fn to_warp10_data(metrics: &[Metric]) -> Vec<warp10::Data> {
+    metrics
+        .iter()
+        .map(|metric| {
+            let labels = metric
+                .sorted_attributes()
+                .into_iter()
+                .map(|(k, v)| warp10::Label::new(k, &v.to_string()))
+                .collect();
+            warp10::Data::new(
+                time::OffsetDateTime::now_utc(),
+                None,
+                metric.name.clone(),
+                labels,
+                warp10::Value::String(metric.metric_value.to_string().replace('`', "")),
+            )
+        })
+        .collect()
+}
+
+/// Sends `data` to the update endpoint of the Warp10 instance of `client`,
+/// authenticated by `write_token`.
+///
+/// The request goes through `http_client`, returned by [get_http_client], rather
+/// than the writer of the warp10 crate, so that the proxy and certificate options
+/// apply as they do for the other exporters.
+pub fn post(
+    client: &warp10::Client,
+    http_client: &HttpClient,
+    write_token: &str,
+    data: Vec<warp10::Data>,
+) -> Result<warp10::Warp10Response, warp10::Error> {
+    let body = data
+        .iter()
+        .map(|d| d.warp10_serialize())
+        .collect::<Vec<String>>()
+        .join("\n");
+    let mut request = Request::post(client.update_uri()).body(body)?;
+    warp10::Token::new(client, write_token.to_string()).set_headers(request.headers_mut());
+    let mut response = http_client.send(request)?;
+    let status = response.status();
+    let error_message = response
+        .headers()
+        .get("X-Warp10-Error-Message")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let payload = response.text()?;
+    let response = warp10::Warp10Response::new(status, payload);
+    match status {
+        StatusCode::OK => Ok(response),
+        _ => Err(warp10::Error::api_error(response, error_message)),
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
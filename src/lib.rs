@@ -5,8 +5,21 @@
 //! Final monitoring data is sent to or exposed for monitoring tools thanks to *exporters*.
 #[macro_use]
 extern crate log;
+pub mod capabilities;
 pub mod exporters;
+#[cfg(target_os = "linux")]
+pub mod privileges;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod seccomp;
+pub mod self_limits;
+pub mod selftest;
 pub mod sensors;
+#[cfg(target_os = "windows")]
+pub mod service;
+pub mod watchdog;
 
 #[cfg(target_os = "windows")]
 use sensors::msr_rapl;
@@ -14,6 +27,9 @@ use sensors::msr_rapl;
 #[cfg(target_os = "linux")]
 use sensors::powercap_rapl;
 
+#[cfg(target_os = "macos")]
+use sensors::powermetrics;
+
 /// Create a new [`Sensor`] instance with the default sensor available,
 /// with its default options.
 pub fn get_default_sensor() -> impl sensors::Sensor {
@@ -26,6 +42,13 @@ pub fn get_default_sensor() -> impl sensors::Sensor {
 
     #[cfg(target_os = "windows")]
     return msr_rapl::MsrRAPLSensor::new();
+
+    #[cfg(target_os = "macos")]
+    return powermetrics::PowermetricsSensor::new(
+        powermetrics::DEFAULT_SAMPLE_INTERVAL,
+        powermetrics::DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES,
+        powermetrics::DEFAULT_BUFFER_PER_DOMAIN_MAX_KBYTES,
+    );
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -1,14 +1,47 @@
 //! Generic sensor and transmission agent for energy consumption related metrics.
 
-use clap::{command, ArgAction, Parser, Subcommand};
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand};
 use colored::Colorize;
-use scaphandre::{exporters, sensors::Sensor};
+use regex::Regex;
+use scaphandre::{
+    capabilities, exporters,
+    self_limits::{self, SelfLimits},
+    selftest,
+    sensors::{
+        hypervisor::{Hypervisor, HypervisorSensor},
+        ipmi::IpmiSensor,
+        modbus, raw_reads,
+        utils::{Lockfile, ProcessCpuSource, ProcessFilters},
+        FilteredSensor, RecordGenerator, Sensor,
+    },
+    watchdog,
+};
 
 #[cfg(target_os = "linux")]
-use scaphandre::sensors::powercap_rapl;
+use scaphandre::{
+    privileges,
+    sensors::{
+        battery, counter_file::ReadMode, ina, jetson, lxc, msr_linux, powercap_rapl, shared, wsl,
+    },
+};
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use scaphandre::seccomp;
 
 #[cfg(target_os = "windows")]
-use scaphandre::sensors::msr_rapl;
+use scaphandre::{
+    sensors::{msr_rapl, windows_driver},
+    service,
+};
+
+#[cfg(target_os = "macos")]
+use scaphandre::sensors::powermetrics;
+
+#[cfg(feature = "smartplug")]
+use scaphandre::sensors::smartplug;
 
 #[cfg(target_os = "windows")]
 use windows_service::{
@@ -42,7 +75,7 @@ use std::ffi::OsString;
 struct Cli {
     /// The exporter module to use to output the energy consumption metrics
     #[command(subcommand)]
-    exporter: ExporterChoice,
+    exporter: Option<ExporterChoice>,
 
     /// Increase the verbosity level
     #[arg(short, action = ArgAction::Count, default_value_t = 0)]
@@ -52,6 +85,16 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     no_header: bool,
 
+    /// Check the sensor and the configuration of the exporter (connectivity, credentials...)
+    /// instead of running it, then exit with status 0 if they are valid, 1 otherwise
+    #[arg(long, global = true)]
+    validate: bool,
+
+    /// Print, in JSON, the sensors and exporters compiled in this build and whether
+    /// they are usable on this host, then exit
+    #[arg(long)]
+    capabilities: bool,
+
     /// Tell Scaphandre that it's running in a virtual machine.
     /// You should have another instance of Scaphandre running on the hypervisor (see docs).
     #[arg(long, default_value_t = false)]
@@ -61,6 +104,199 @@ struct Cli {
     #[arg(short, long)]
     sensor: Option<String>,
 
+    /// Smart plug the host is plugged into, as <kind>://<address> with kind being shelly, tasmota or kasa,
+    /// or as mqtt://<broker>/<topic> for a plug publishing on an MQTT broker.
+    /// Only available for the smartplug sensor.
+    #[cfg(feature = "smartplug")]
+    #[arg(long, value_name = "PLUG")]
+    smartplug: Option<String>,
+
+    /// Address (host or host:port) of the meter to read over Modbus/TCP.
+    /// Only available for the modbus sensor.
+    #[arg(long, value_name = "ADDRESS")]
+    modbus_address: Option<String>,
+
+    /// Modbus unit identifier of the meter.
+    /// Only available for the modbus sensor.
+    #[arg(long, default_value_t = 1)]
+    modbus_unit_id: u8,
+
+    /// File describing the registers holding the power and energy of the meter.
+    /// Only available for the modbus sensor.
+    #[arg(long, value_name = "FILE")]
+    modbus_register_map: Option<String>,
+
+    /// Address (host:port) of the prometheus exporter of scaphandre on the Windows host.
+    /// Only available for the wsl sensor, which estimates the power when it doesn't answer.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "ADDRESS", default_value = wsl::DEFAULT_COMPANION)]
+    wsl_companion: String,
+
+    /// Power of the host when idle, in watts, used to estimate its power from the CPU usage.
+    /// Only available for the wsl sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "WATTS", default_value_t = wsl::DEFAULT_IDLE_WATTS)]
+    wsl_idle_watts: f64,
+
+    /// Power of the host with all its CPUs busy, in watts, used to estimate its power from
+    /// the CPU usage. Only available for the wsl sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "WATTS", default_value_t = wsl::DEFAULT_MAX_WATTS)]
+    wsl_max_watts: f64,
+
+    /// Battery to read the power from (BAT0, BAT1... in /sys/class/power_supply, or the path of
+    /// its folder), instead of the first one. Only available for the battery sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "NAME")]
+    battery: Option<String>,
+
+    /// Power monitor to read: ina219 or ina3221. Only available for the ina sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "CHIP", default_value = "ina219")]
+    ina_chip: ina::Chip,
+
+    /// I2C bus of the power monitor, read from /dev/i2c-BUS.
+    /// Only available for the ina sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "BUS", default_value_t = ina::DEFAULT_BUS)]
+    ina_bus: u8,
+
+    /// I2C address of the power monitor, in hexadecimal or decimal.
+    /// Only available for the ina sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "ADDRESS", default_value = ina::DEFAULT_ADDRESS)]
+    ina_address: String,
+
+    /// Value of the shunt resistor of each channel to read, in ohms, comma separated,
+    /// or a single value for all the channels. Only available for the ina sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "OHMS", default_value = ina::DEFAULT_SHUNT_OHMS)]
+    ina_shunt_ohms: String,
+
+    /// Names of the domains of the channels, comma separated (channel1, channel2... by default).
+    /// Only available for the ina sensor.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "NAMES")]
+    ina_channel_names: Option<String>,
+
+    /// Interval between two samples of powermetrics, in milliseconds.
+    /// Only available for the powermetrics sensor (on macOS).
+    #[cfg(target_os = "macos")]
+    #[arg(long, value_name = "MILLISECONDS",
+        default_value_t = powermetrics::DEFAULT_SAMPLE_INTERVAL.as_millis() as u64)]
+    powermetrics_interval: u64,
+
+    /// Host of the BMC to read the power from over the network (lanplus), instead of the local one.
+    /// Only available for the ipmi sensor.
+    #[arg(long, value_name = "HOST")]
+    ipmi_host: Option<String>,
+
+    /// User of the BMC given with --ipmi-host
+    #[arg(long, value_name = "USER", requires = "ipmi_host")]
+    ipmi_user: Option<String>,
+
+    /// File holding the password of the BMC user given with --ipmi-user
+    #[arg(long, value_name = "FILE", requires = "ipmi_user")]
+    ipmi_password_file: Option<String>,
+
+    /// Unix socket on which the energy counters are published for secondary instances.
+    /// With the shared sensor, the socket to read the counters from, instead of reading RAPL.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH")]
+    share_socket: Option<String>,
+
+    /// Secret key the energy counters published with --share-socket are signed with,
+    /// reloaded when the file changes (see `scaphandre generate-signing-key`)
+    #[cfg(all(target_os = "linux", feature = "signing"))]
+    #[arg(long, value_name = "FILE", requires = "share_socket")]
+    share_signing_key: Option<String>,
+
+    /// With the shared sensor, only accept energy counters signed by one of these public
+    /// keys: a .pub file, or a directory of them
+    #[cfg(all(target_os = "linux", feature = "signing"))]
+    #[arg(long, value_name = "PATH", requires = "share_socket")]
+    share_trusted_keys: Option<String>,
+
+    /// Write each read of an energy counter (path or MSR address, raw value, timestamp)
+    /// as a JSON line to DESTINATION, "stderr" or a file appended to, for debugging
+    #[arg(long, value_name = "DESTINATION")]
+    debug_raw: Option<String>,
+
+    /// How long the energy counter reads are written for, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = raw_reads::DEFAULT_DURATION_SECONDS,
+        requires = "debug_raw")]
+    debug_raw_duration: u64,
+
+    /// File locked while this instance reads the sensors, so that a second instance
+    /// reading them at the same time fails to start (not needed with the shared sensor)
+    #[arg(long, value_name = "PATH")]
+    lockfile: Option<String>,
+
+    /// Notify systemd when started and after each new measurement (service with Type=notify
+    /// and WatchdogSec=), so that it restarts scaphandre if it hangs. Only available on Linux.
+    #[arg(long)]
+    watchdog: bool,
+
+    /// CPUs scaphandre is allowed to run on, e.g. a housekeeping core, to keep it away
+    /// from latency-critical workloads. Only available on Linux.
+    #[arg(long, value_name = "CPU,...", value_delimiter = ',')]
+    self_cpu_affinity: Vec<usize>,
+
+    /// Nice value of scaphandre, from -20 to 19 (lowest priority).
+    /// On Windows, it is mapped to a priority class (idle from 15).
+    #[arg(long, value_name = "NICE", allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19))]
+    self_nice: Option<i32>,
+
+    /// I/O scheduling class of scaphandre (best-effort is used at its lowest priority).
+    /// Only available on Linux.
+    #[arg(long, value_name = "CLASS", value_parser = self_limits::IONICE_CLASSES)]
+    self_ionice: Option<String>,
+
+    /// Drop all the capabilities scaphandre was started with but the ones it needs
+    /// (CAP_DAC_READ_SEARCH to read RAPL, CAP_SYS_PTRACE to read the processes of other
+    /// users...), and forbid gaining new ones.
+    /// Only available on Linux.
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    drop_capabilities: bool,
+
+    /// Other capabilities to keep with --drop-capabilities, e.g. CAP_NET_BIND_SERVICE to
+    /// listen on a port below 1024
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "CAP,...", value_delimiter = ',',
+        value_parser = privileges::parse_capability, requires = "drop_capabilities")]
+    keep_capabilities: Vec<u32>,
+
+    /// Change the root directory to DIR at startup, which must hold the proc and sys folders
+    /// (bind mounted read-only), the paths given in other options being then inside DIR.
+    /// Only available on Linux.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "DIR")]
+    chroot: Option<String>,
+
+    /// Don't restrict the system calls scaphandre can make once started.
+    /// Only available on Linux (x86_64 and aarch64).
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[arg(long)]
+    no_seccomp: bool,
+
+    /// Other system calls to allow, by name or number, on top of the ones needed by the exporter
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[arg(
+        long,
+        value_name = "SYSCALL,...",
+        value_delimiter = ',',
+        conflicts_with = "no_seccomp"
+    )]
+    seccomp_allow: Vec<String>,
+
     /// Maximum memory size allowed, in KiloBytes, for storing energy consumption of each **domain**.
     /// Only available for the RAPL sensor (on Linux).
     #[cfg(target_os = "linux")]
@@ -72,6 +308,49 @@ struct Cli {
     #[cfg(target_os = "linux")]
     #[arg(long, default_value_t = powercap_rapl::DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES)]
     sensor_buffer_per_socket_max_kb: u16,
+
+    /// How the RAPL energy counters are read: reopening their sysfs file at each
+    /// measurement (sysfs) or reading files kept open with pread (pread).
+    /// Only available for the RAPL sensor (on Linux).
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "MODE", default_value = "pread")]
+    powercap_read_mode: ReadMode,
+
+    /// Folder of the powercap tree, such as the tree of the host passed through to an
+    /// LXC container. Defaults to /sys/class/powercap, or /var/scaphandre with --vm.
+    /// Only available for the RAPL sensor (on Linux).
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH")]
+    powercap_path: Option<String>,
+
+    /// Only track the processes whose executable or command line matches this regular expression.
+    /// Other processes are ignored by all exporters.
+    #[arg(long, value_name = "REGEX")]
+    include_processes: Option<Regex>,
+
+    /// Don't track the processes whose executable or command line matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    exclude_processes: Option<Regex>,
+
+    /// Don't track processes at all, to keep the memory used by scaphandre low on small
+    /// hosts: only the host, socket and domain metrics are exported
+    #[arg(long, conflicts_with_all = ["include_processes", "exclude_processes"])]
+    low_memory: bool,
+
+    /// Where the CPU usage of the processes, which their power is computed from, comes from:
+    /// sysinfo, or the CPU time of their threads summed from /proc/PID/task (Linux only), for
+    /// heavily threaded services sysinfo may undercount
+    #[arg(
+        long,
+        value_name = "SOURCE",
+        default_value = "sysinfo",
+        conflicts_with = "low_memory"
+    )]
+    process_cpu_source: ProcessCpuSource,
+
+    /// HTTP client options used by the exporters sending data over HTTP
+    #[command(flatten)]
+    http_client: exporters::utils::HttpClientArgs,
 }
 
 /// Defines the possible subcommands, one per exporter.
@@ -108,6 +387,53 @@ enum ExporterChoice {
     /// Push metrics to Prometheus Push Gateway
     #[cfg(feature = "prometheuspush")]
     PrometheusPush(exporters::prometheuspush::ExporterArgs),
+
+    /// Write the metrics as events of the Scaphandre ETW provider, for Windows
+    /// Performance Analyzer and ETW sessions
+    #[cfg(target_os = "windows")]
+    Etw(exporters::etw::ExporterArgs),
+
+    /// Send the same metrics to two backends and count the batches each of them rejected
+    #[cfg(any(feature = "prometheuspush", feature = "warpten", feature = "riemann"))]
+    Compare(exporters::compare::ExporterArgs),
+
+    /// Print Prometheus recording and alerting rules for the metrics of this version
+    GenerateRules(exporters::rules::RulesArgs),
+
+    /// Print a Grafana dashboard for the metrics available on this host
+    GenerateDashboard(exporters::dashboard::DashboardArgs),
+
+    /// Generate a key pair to sign the energy counters published with --share-socket:
+    /// the secret key is written to PATH, the public key to PATH.pub
+    #[cfg(feature = "signing")]
+    GenerateSigningKey {
+        #[arg(value_name = "PATH")]
+        path: String,
+    },
+
+    /// Measure the host idle, then with one core kept busy, and check that the
+    /// measured energy rises accordingly
+    Selftest(selftest::SelftestArgs),
+
+    /// List the metrics of this version and their stability
+    Metrics {
+        #[command(subcommand)]
+        command: exporters::stability::MetricsCommand,
+    },
+
+    /// Install, inspect or remove the RAPL driver needed by the msr sensor
+    #[cfg(target_os = "windows")]
+    Driver {
+        #[command(subcommand)]
+        command: windows_driver::DriverCommand,
+    },
+
+    /// Create, update or delete the Windows service running scaphandre in the background
+    #[cfg(target_os = "windows")]
+    Service {
+        #[command(subcommand)]
+        command: service::ServiceCommand,
+    },
 }
 
 #[cfg(target_os = "windows")]
@@ -229,19 +555,275 @@ fn main() {
 }
 
 fn parse_cli_and_run_exporter() {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
     loggerv::init_with_verbosity(cli.verbose.into()).expect("unable to initialize the logger");
 
-    let sensor = build_sensor(&cli);
-    let mut exporter = build_exporter(cli.exporter, &sensor);
+    if cli.capabilities {
+        println!("{}", capabilities::detect().to_json());
+        return;
+    }
+    let exporter = match cli.exporter.take() {
+        Some(exporter) => exporter,
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingSubcommand,
+                "an exporter subcommand is required, unless --capabilities is given",
+            )
+            .exit(),
+    };
+
+    match &exporter {
+        ExporterChoice::GenerateRules(args) => {
+            print!("{}", exporters::rules::generate(args));
+            return;
+        }
+        ExporterChoice::GenerateDashboard(args) => {
+            print!("{}", exporters::dashboard::generate(args));
+            return;
+        }
+        ExporterChoice::Metrics { command } => {
+            print!("{}", exporters::stability::run(command));
+            return;
+        }
+        #[cfg(feature = "signing")]
+        ExporterChoice::GenerateSigningKey { path } => {
+            match scaphandre::sensors::signing::generate_key(path) {
+                Ok(id) => println!("Generated the key {id} in {path} and {path}.pub"),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    // managing the driver or the service doesn't need any sensor
+    #[cfg(target_os = "windows")]
+    if matches!(
+        exporter,
+        ExporterChoice::Driver { .. } | ExporterChoice::Service { .. }
+    ) {
+        let result = match exporter {
+            ExporterChoice::Driver { command } => windows_driver::run(command),
+            ExporterChoice::Service { command } => service::run(command),
+            _ => unreachable!(),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if cli.validate {
+        let valid = validate(cli, exporter);
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    let _lockfile = match &cli.lockfile {
+        Some(path) if cli.sensor.as_deref() != Some("shared") => {
+            Some(Lockfile::acquire(path).unwrap_or_else(|e| panic!("Couldn't start: {e}")))
+        }
+        _ => None,
+    };
+
+    let self_limits = SelfLimits {
+        cpu_affinity: cli.self_cpu_affinity.clone(),
+        nice: cli.self_nice,
+        ionice: cli.self_ionice.clone(),
+    };
+    if !self_limits.is_empty() {
+        self_limits
+            .apply()
+            .unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+    }
+
+    if let Some(destination) = &cli.debug_raw {
+        raw_reads::enable(
+            destination,
+            std::time::Duration::from_secs(cli.debug_raw_duration),
+        )
+        .unwrap_or_else(|e| panic!("Couldn't dump raw counter reads: {e}"));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // before the sensor starts its threads, which inherit the capabilities
+        if let Some(dir) = &cli.chroot {
+            privileges::chroot(dir).unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+        }
+        if cli.drop_capabilities {
+            let sensor = if falls_back_on_msr(&cli) {
+                Some("msr_linux")
+            } else {
+                cli.sensor.as_deref()
+            };
+            let mut keep =
+                privileges::needed_capabilities(sensor, !cli.low_memory, cli.ipmi_host.is_none());
+            keep.extend(&cli.keep_capabilities);
+            privileges::drop_capabilities(&keep).unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+        }
+        if !cli.low_memory {
+            privileges::warn_if_processes_hidden();
+        }
+    }
+
+    if let ExporterChoice::Selftest(args) = &exporter {
+        let sensor = build_sensor(&cli);
+        println!(
+            "Measuring the host idle, then with one core busy, for {} s each...",
+            args.duration
+        );
+        match selftest::run(sensor.as_ref(), args) {
+            Ok(report) => {
+                println!("{report}");
+                if report.passed() {
+                    println!("selftest: {}", "passed".green());
+                } else {
+                    println!("selftest: {}", "failed".red());
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                println!("selftest: {} {e}", "failed".red());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut sensor = build_sensor(&cli);
+    #[cfg(target_os = "linux")]
+    if let Some(path) = &cli.share_socket {
+        if cli.sensor.as_deref() != Some("shared") {
+            let topology = (*sensor.get_topology()).expect("sensor topology should be available");
+            #[cfg(feature = "signing")]
+            let published = match &cli.share_signing_key {
+                Some(key) => {
+                    let signer = scaphandre::sensors::signing::Signer::load(key)
+                        .unwrap_or_else(|e| panic!("Invalid signing key: {e}"));
+                    shared::publish_signed(topology, path, signer)
+                }
+                None => shared::publish(topology, path),
+            };
+            #[cfg(not(feature = "signing"))]
+            let published = shared::publish(topology, path);
+            published.unwrap_or_else(|e| panic!("Couldn't publish energy counters on {path}: {e}"));
+        }
+    }
+    if cli.include_processes.is_some()
+        || cli.exclude_processes.is_some()
+        || cli.low_memory
+        || cli.process_cpu_source != ProcessCpuSource::Sysinfo
+    {
+        let filters = ProcessFilters {
+            include: cli.include_processes.clone(),
+            exclude: cli.exclude_processes.clone(),
+            // set from --container-regex by the json exporter
+            #[cfg(feature = "containers")]
+            container: None,
+            disabled: cli.low_memory,
+        };
+        sensor =
+            Box::new(FilteredSensor::new(sensor, filters).with_cpu_source(cli.process_cpu_source));
+    }
+    let mut exporter = build_exporter(exporter, sensor.as_ref(), &cli.http_client);
     if !cli.no_header {
         print_scaphandre_header(exporter.kind());
     }
+    if cli.watchdog {
+        match watchdog::enable() {
+            Ok(()) => watchdog::notify_ready(),
+            Err(e) => eprintln!("Warning: watchdog disabled, {e}"),
+        }
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    if !cli.no_seccomp {
+        let listens = exporter.kind() == "prometheus"
+            || (cli.share_socket.is_some() && cli.sensor.as_deref() != Some("shared"));
+        let mut profile = seccomp::Profile::new(exporter.kind(), listens, sensor.runs_commands());
+        for syscall in &cli.seccomp_allow {
+            profile
+                .allow(syscall)
+                .unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+        }
+        profile
+            .apply()
+            .unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+    }
 
     exporter.run();
 }
 
-fn build_exporter(choice: ExporterChoice, sensor: &dyn Sensor) -> Box<dyn exporters::Exporter> {
+/// Checks the sensor, then the exporter, printing the result of each check.
+/// Returns true if both are valid.
+fn validate(cli: Cli, exporter: ExporterChoice) -> bool {
+    // sensors and exporters panic on invalid options, turn that into a failed check
+    let checked = |name: &str, check: &mut dyn FnMut() -> Result<String, String>| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)) {
+            Ok(Ok(description)) => {
+                println!("{name}: {} {description}", "ok".green());
+                true
+            }
+            Ok(Err(e)) => {
+                println!("{name}: {} {e}", "failed".red());
+                false
+            }
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("invalid configuration");
+                println!("{name}: {} {message}", "failed".red());
+                false
+            }
+        }
+    };
+    let sensor = build_sensor(&cli);
+    if !checked("sensor", &mut || validate_sensor(sensor.as_ref())) {
+        return false;
+    }
+    let mut choice = Some(exporter);
+    checked("exporter", &mut || {
+        let choice = choice.take().expect("the exporter should be checked once");
+        build_exporter(choice, sensor.as_ref(), &cli.http_client).validate()
+    })
+}
+
+/// Checks that the sensor provides a topology and an energy record.
+fn validate_sensor(sensor: &dyn Sensor) -> Result<String, String> {
+    let mut topology =
+        (*sensor.get_topology()).ok_or_else(|| String::from("the sensor provides no topology"))?;
+    topology.refresh();
+    match topology.get_records_passive().last() {
+        Some(record) if topology.sockets.is_empty() => Ok(format!(
+            "no socket (host-level measurements only), host energy counter {record}"
+        )),
+        Some(record) => Ok(format!(
+            "{} socket(s), host energy counter {}",
+            topology.sockets.len(),
+            record
+        )),
+        None => Err(String::from("couldn't read the host energy counter")),
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "prometheuspush", feature = "warpten", feature = "riemann")),
+    allow(unused_variables)
+)]
+fn build_exporter(
+    choice: ExporterChoice,
+    sensor: &dyn Sensor,
+    http_client_args: &exporters::utils::HttpClientArgs,
+) -> Box<dyn exporters::Exporter> {
     match choice {
         ExporterChoice::Stdout(args) => {
             Box::new(exporters::stdout::StdoutExporter::new(sensor, args))
@@ -263,13 +845,41 @@ fn build_exporter(choice: ExporterChoice, sensor: &dyn Sensor) -> Box<dyn export
             Box::new(exporters::riemann::RiemannExporter::new(sensor, args))
         }
         #[cfg(feature = "warpten")]
-        ExporterChoice::Warpten(args) => {
-            Box::new(exporters::warpten::Warp10Exporter::new(sensor, args))
-        }
+        ExporterChoice::Warpten(args) => Box::new(exporters::warpten::Warp10Exporter::new(
+            sensor,
+            args,
+            http_client_args,
+        )),
         #[cfg(feature = "prometheuspush")]
         ExporterChoice::PrometheusPush(args) => Box::new(
-            exporters::prometheuspush::PrometheusPushExporter::new(sensor, args),
+            exporters::prometheuspush::PrometheusPushExporter::new(sensor, args, http_client_args),
         ),
+        #[cfg(target_os = "windows")]
+        ExporterChoice::Etw(args) => {
+            Box::new(exporters::etw::ETWExporter::new(sensor, args)) // keep this in braces
+        }
+        #[cfg(any(feature = "prometheuspush", feature = "warpten", feature = "riemann"))]
+        ExporterChoice::Compare(args) => Box::new(exporters::compare::CompareExporter::new(
+            sensor,
+            args,
+            http_client_args,
+        )),
+        ExporterChoice::GenerateRules(_)
+        | ExporterChoice::GenerateDashboard(_)
+        | ExporterChoice::Metrics { .. } => {
+            unreachable!("rules, dashboards and metrics are printed before building an exporter")
+        }
+        #[cfg(feature = "signing")]
+        ExporterChoice::GenerateSigningKey { .. } => {
+            unreachable!("signing keys are generated before building an exporter")
+        }
+        ExporterChoice::Selftest(_) => {
+            unreachable!("the selftest is run before building an exporter")
+        }
+        #[cfg(target_os = "windows")]
+        ExporterChoice::Driver { .. } | ExporterChoice::Service { .. } => {
+            unreachable!("driver and service subcommands are run before building an exporter")
+        }
     }
     // Note that invalid choices are automatically turned into errors by `parse()` before the Cli is populated,
     // that's why they don't appear in this function.
@@ -278,24 +888,54 @@ fn build_exporter(choice: ExporterChoice, sensor: &dyn Sensor) -> Box<dyn export
 /// Returns the sensor to use, given the command-line arguments.
 /// Unless sensor-specific options are provided, this should return
 /// the same thing as [`scaphandre::get_default_sensor`].
-fn build_sensor(cli: &Cli) -> impl Sensor {
+fn build_sensor(cli: &Cli) -> Box<dyn Sensor> {
     #[cfg(target_os = "linux")]
     let rapl_sensor = || {
-        powercap_rapl::PowercapRAPLSensor::new(
+        let mut sensor = powercap_rapl::PowercapRAPLSensor::new(
             cli.sensor_buffer_per_socket_max_kb,
             cli.sensor_buffer_per_domain_max_kb,
             cli.vm,
+        );
+        sensor.set_read_mode(cli.powercap_read_mode);
+        match &cli.powercap_path {
+            Some(path) => sensor.set_base_path(path),
+            None if !cli.vm => lxc::warn_if_no_passthrough(powercap_rapl::POWERCAP_PATH),
+            None => {}
+        }
+        privileges::check_powercap(sensor.base_path())
+            .unwrap_or_else(|e| panic!("Couldn't start: {e}"));
+        sensor
+    };
+
+    #[cfg(target_os = "linux")]
+    let msr_linux_sensor = || {
+        msr_linux::MsrLinuxSensor::new(
+            cli.sensor_buffer_per_socket_max_kb,
+            cli.sensor_buffer_per_domain_max_kb,
         )
     };
 
+    #[cfg(target_os = "linux")]
+    let wsl_sensor =
+        || wsl::WslSensor::new(&cli.wsl_companion, cli.wsl_idle_watts, cli.wsl_max_watts);
+
     #[cfg(target_os = "windows")]
     let msr_sensor_win = msr_rapl::MsrRAPLSensor::new;
 
+    #[cfg(target_os = "macos")]
+    let powermetrics_sensor = || {
+        powermetrics::PowermetricsSensor::new(
+            std::time::Duration::from_millis(cli.powermetrics_interval),
+            powermetrics::DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES,
+            powermetrics::DEFAULT_BUFFER_PER_DOMAIN_MAX_KBYTES,
+        )
+    };
+
     match cli.sensor.as_deref() {
         Some("powercap_rapl") => {
             #[cfg(target_os = "linux")]
             {
-                rapl_sensor()
+                Box::new(rapl_sensor())
             }
             #[cfg(not(target_os = "linux"))]
             panic!("Invalid sensor: Scaphandre's powercap_rapl only works on Linux")
@@ -303,25 +943,175 @@ fn build_sensor(cli: &Cli) -> impl Sensor {
         Some("msr") => {
             #[cfg(target_os = "windows")]
             {
-                msr_sensor_win()
+                Box::new(msr_sensor_win())
             }
             #[cfg(not(target_os = "windows"))]
             panic!("Invalid sensor: Scaphandre's msr only works on Windows")
         }
+        Some("msr_linux") => {
+            #[cfg(target_os = "linux")]
+            {
+                Box::new(msr_linux_sensor())
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's msr_linux only works on Linux")
+        }
+        Some("powermetrics") => {
+            #[cfg(target_os = "macos")]
+            {
+                Box::new(powermetrics_sensor())
+            }
+            #[cfg(not(target_os = "macos"))]
+            panic!("Invalid sensor: Scaphandre's powermetrics sensor only works on macOS")
+        }
+        #[cfg(feature = "smartplug")]
+        Some("smartplug") => {
+            let plug = cli
+                .smartplug
+                .as_deref()
+                .expect("the smartplug sensor needs --smartplug <kind>://<address>");
+            match smartplug::SmartPlugSensor::new(plug) {
+                Ok(sensor) => Box::new(sensor),
+                Err(e) => panic!("Invalid smart plug: {e}"),
+            }
+        }
+        Some("shared") => {
+            #[cfg(target_os = "linux")]
+            {
+                let path = cli
+                    .share_socket
+                    .as_deref()
+                    .expect("the shared sensor needs --share-socket <path>");
+                let sensor = shared::SharedSensor::new(
+                    path,
+                    cli.sensor_buffer_per_socket_max_kb,
+                    cli.sensor_buffer_per_domain_max_kb,
+                );
+                #[cfg(feature = "signing")]
+                let sensor = match &cli.share_trusted_keys {
+                    Some(keys) => sensor.with_trusted_keys(keys),
+                    None => sensor,
+                };
+                Box::new(sensor)
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's shared sensor only works on Linux")
+        }
+        Some("modbus") => {
+            let address = cli
+                .modbus_address
+                .as_deref()
+                .expect("the modbus sensor needs --modbus-address");
+            let register_map = cli
+                .modbus_register_map
+                .as_deref()
+                .expect("the modbus sensor needs --modbus-register-map");
+            match modbus::ModbusSensor::new(address, cli.modbus_unit_id, register_map) {
+                Ok(sensor) => Box::new(sensor),
+                Err(e) => panic!("Invalid modbus sensor configuration: {e}"),
+            }
+        }
+        Some("wsl") => {
+            #[cfg(target_os = "linux")]
+            {
+                Box::new(wsl_sensor())
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's wsl sensor only works on Linux")
+        }
+        Some("battery") => {
+            #[cfg(target_os = "linux")]
+            match battery::BatterySensor::new(
+                battery::DEFAULT_POWER_SUPPLY_PATH,
+                cli.battery.as_deref(),
+            ) {
+                Ok(sensor) => Box::new(sensor),
+                Err(e) => panic!("Invalid battery sensor: {e}"),
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's battery sensor only works on Linux")
+        }
+        Some("ina") => {
+            #[cfg(target_os = "linux")]
+            match ina::InaSensor::new(
+                cli.ina_chip,
+                cli.ina_bus,
+                &cli.ina_address,
+                &cli.ina_shunt_ohms,
+                cli.ina_channel_names.as_deref(),
+                cli.sensor_buffer_per_socket_max_kb,
+                cli.sensor_buffer_per_domain_max_kb,
+            ) {
+                Ok(sensor) => Box::new(sensor),
+                Err(e) => panic!("Invalid ina sensor configuration: {e}"),
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's ina sensor only works on Linux")
+        }
+        Some("jetson") => {
+            #[cfg(target_os = "linux")]
+            match jetson::JetsonSensor::new(
+                jetson::DRIVERS_PATH,
+                cli.sensor_buffer_per_socket_max_kb,
+                cli.sensor_buffer_per_domain_max_kb,
+            ) {
+                Ok(sensor) => Box::new(sensor),
+                Err(e) => panic!("Invalid jetson sensor: {e}"),
+            }
+            #[cfg(not(target_os = "linux"))]
+            panic!("Invalid sensor: Scaphandre's jetson sensor only works on Linux")
+        }
+        Some("ipmi") => Box::new(IpmiSensor::new(
+            cli.ipmi_host.as_deref(),
+            cli.ipmi_user.as_deref(),
+            cli.ipmi_password_file.as_deref(),
+        )),
+        Some(name @ ("hyperv" | "vmware")) => Box::new(HypervisorSensor::new(
+            Hypervisor::from_name(name).expect("known hypervisor"),
+        )),
         Some(s) => panic!("Unknown sensor type {}", s),
         None => {
             #[cfg(target_os = "linux")]
-            return rapl_sensor();
+            if !cli.vm && cli.powercap_path.is_none() && wsl::detect() {
+                return Box::new(wsl_sensor());
+            }
+            #[cfg(target_os = "linux")]
+            if falls_back_on_msr(cli) {
+                log::warn!(
+                    "No RAPL zone in {}, reading the RAPL counters from the msr devices",
+                    powercap_rapl::POWERCAP_PATH
+                );
+                return Box::new(msr_linux_sensor());
+            }
+            #[cfg(target_os = "linux")]
+            return Box::new(rapl_sensor());
 
             #[cfg(target_os = "windows")]
-            return msr_sensor_win();
+            return Box::new(msr_sensor_win());
+
+            #[cfg(target_os = "macos")]
+            return Box::new(powermetrics_sensor());
 
-            #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+            #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
             compile_error!("Unsupported target OS")
         }
     }
 }
 
+/// Tells if the default sensor reads the msr devices, when powercap has no
+/// RAPL zone (no intel_rapl driver), nor the amd_energy driver on AMD CPUs,
+/// but the msr module is loaded.
+#[cfg(target_os = "linux")]
+fn falls_back_on_msr(cli: &Cli) -> bool {
+    cli.sensor.is_none()
+        && !cli.vm
+        && cli.powercap_path.is_none()
+        && !powercap_rapl::PowercapRAPLSensor::has_rapl_zones(powercap_rapl::POWERCAP_PATH)
+        && powercap_rapl::PowercapRAPLSensor::amd_energy_device(powercap_rapl::POWERCAP_PATH)
+            .is_none()
+        && msr_linux::has_msr_devices(msr_linux::MSR_DEVICES_PATH)
+}
+
 fn print_scaphandre_header(exporter_name: &str) {
     let title = format!("Scaphandre {exporter_name} exporter");
     println!("{}", title.red().bold());
@@ -344,6 +1134,16 @@ mod test {
         "warpten",
         #[cfg(feature = "qemu")]
         "qemu",
+        #[cfg(feature = "prometheuspush")]
+        "prometheus-push",
+        #[cfg(any(feature = "prometheuspush", feature = "warpten", feature = "riemann"))]
+        "compare",
+        "generate-rules",
+        "generate-dashboard",
+        #[cfg(feature = "signing")]
+        "generate-signing-key",
+        "selftest",
+        "metrics",
     ];
 
     /// Test that `--help` works for Scaphandre _and_ for each subcommand.
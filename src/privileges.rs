@@ -0,0 +1,356 @@
+//! # Privileges: running scaphandre without being root
+//!
+//! Since CVE-2020-8694, the RAPL energy counters (`energy_uj` files of the
+//! powercap tree) are readable by root only. Rather than running as root,
+//! scaphandre can run as a dedicated user with the few capabilities it needs,
+//! given as ambient capabilities by its systemd unit
+//! (`AmbientCapabilities=CAP_DAC_READ_SEARCH CAP_SYS_PTRACE`) or with
+//! `setcap` on the binary:
+//!
+//! - `CAP_DAC_READ_SEARCH` to read the energy counters, unless the user can
+//!   read them otherwise (ACL set with `setfacl` at boot, group...),
+//! - `CAP_SYS_PTRACE` to read the executable and the I/O of the processes of
+//!   other users, which are missing from the process metrics otherwise,
+//! - `CAP_PERFMON` to count the memory traffic of the sockets with the uncore
+//!   perf events, which is left out otherwise,
+//! - `CAP_DAC_OVERRIDE` for `ipmitool` to open `/dev/ipmi0` with the ipmi
+//!   sensor reading the local BMC,
+//! - `CAP_SYS_RAWIO` to open the `/dev/cpu/N/msr` devices with the msr_linux
+//!   sensor.
+//!
+//! [check_powercap] tells at startup which of them is missing. With
+//! `--drop-capabilities`, scaphandre gives up all the other capabilities it
+//! was started with, from its bounding set as well, and can't gain new ones
+//! (`no_new_privs`). With `--chroot`, it only sees the files under a folder,
+//! which must hold `/proc` and `/sys` (bind mounted read-only). Both are done
+//! at startup, before any thread is started: capabilities are per thread in
+//! Linux, and threads keep the ones they were started with.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Capabilities that can be kept, by name, with their number in linux/capability.h.
+pub const CAPABILITIES: [(&str, u32); 12] = [
+    ("CAP_CHOWN", 0),
+    ("CAP_DAC_OVERRIDE", 1),
+    ("CAP_DAC_READ_SEARCH", 2),
+    ("CAP_SETPCAP", 8),
+    ("CAP_NET_BIND_SERVICE", 10),
+    ("CAP_NET_RAW", 13),
+    ("CAP_SYS_RAWIO", 17),
+    ("CAP_SYS_CHROOT", 18),
+    ("CAP_SYS_PTRACE", 19),
+    ("CAP_SYS_ADMIN", 21),
+    ("CAP_PERFMON", 38),
+    ("CAP_BPF", 39),
+];
+
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_SETPCAP: u32 = 8;
+const CAP_SYS_RAWIO: u32 = 17;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_PERFMON: u32 = 38;
+
+const CAP_LAST_CAP: &str = "/proc/sys/kernel/cap_last_cap";
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Returns the number of the capability `name`, case insensitive and with or
+/// without the `CAP_` prefix (`cap_net_bind_service`, `NET_BIND_SERVICE`).
+pub fn parse_capability(name: &str) -> Result<u32, String> {
+    let upper = name.trim().to_uppercase();
+    let upper = if upper.starts_with("CAP_") {
+        upper
+    } else {
+        format!("CAP_{upper}")
+    };
+    CAPABILITIES
+        .iter()
+        .find(|(cap, _)| *cap == upper)
+        .map(|(_, number)| *number)
+        .ok_or_else(|| format!("unknown capability {name}"))
+}
+
+fn capability_name(number: u32) -> String {
+    CAPABILITIES.iter().find(|(_, n)| *n == number).map_or_else(
+        || format!("capability {number}"),
+        |(name, _)| name.to_string(),
+    )
+}
+
+/// Returns the capabilities scaphandre needs to keep: `CAP_DAC_READ_SEARCH`
+/// and `CAP_PERFMON` for the RAPL sensors, `CAP_SYS_PTRACE` if it tracks
+/// processes, `CAP_DAC_OVERRIDE` to read the local BMC, `CAP_SYS_RAWIO` to
+/// open the msr devices.
+pub fn needed_capabilities(
+    sensor: Option<&str>,
+    track_processes: bool,
+    local_bmc: bool,
+) -> Vec<u32> {
+    let mut needed = vec![];
+    if matches!(sensor, None | Some("powercap_rapl") | Some("shared")) {
+        needed.push(CAP_DAC_READ_SEARCH);
+        needed.push(CAP_PERFMON);
+    }
+    if sensor == Some("msr_linux") {
+        needed.push(CAP_DAC_READ_SEARCH);
+        needed.push(CAP_SYS_RAWIO);
+    }
+    if track_processes {
+        needed.push(CAP_SYS_PTRACE);
+    }
+    if sensor == Some("ipmi") && local_bmc {
+        needed.push(CAP_DAC_OVERRIDE);
+    }
+    needed
+}
+
+/// Explains how to let scaphandre read `path`, denied because of `error`.
+fn read_denied(path: &str, error: &io::Error) -> String {
+    // SAFETY: getuid has no arguments and always succeeds.
+    let uid = unsafe { libc::getuid() };
+    format!(
+        "couldn't read {path} ({error}): run scaphandre as root, give it CAP_DAC_READ_SEARCH \
+        (AmbientCapabilities=CAP_DAC_READ_SEARCH in its systemd unit, or \
+        setcap cap_dac_read_search+ep on its binary), or let its user read the file \
+        (setfacl -m u:{uid}:r {path})"
+    )
+}
+
+/// Checks that the energy counters of the powercap tree at `base_path` can be
+/// read, and tells which permission is missing otherwise. Missing counters are
+/// left to the sensor to report.
+pub fn check_powercap(base_path: &str) -> Result<(), String> {
+    let entries = match fs::read_dir(base_path) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    let mut counters: Vec<String> = entries
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("intel-rapl"))
+        .map(|e| format!("{}/energy_uj", e.path().display()))
+        .filter(|counter| Path::new(counter).exists())
+        .collect();
+    counters.sort();
+    for counter in counters {
+        if let Err(e) = fs::read_to_string(&counter) {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                return Err(read_denied(&counter, &e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Warns when the executable of processes of other users can't be read, which
+/// means their exe label and I/O metrics will be missing.
+pub fn warn_if_processes_hidden() {
+    if let Err(e) = fs::read_link("/proc/1/exe") {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            warn!(
+                "Couldn't read the executable of the processes of other users ({e}): give \
+                scaphandre CAP_SYS_PTRACE, or their exe label and I/O metrics will be missing"
+            );
+        }
+    }
+}
+
+/// Changes the root directory of scaphandre to `dir`, which must hold the
+/// `proc` and `sys` folders it reads.
+pub fn chroot(dir: &str) -> Result<(), String> {
+    for needed in ["proc", "sys"] {
+        if !Path::new(dir).join(needed).join(".").exists() {
+            warn!("{dir}/{needed} doesn't exist, scaphandre won't be able to read it");
+        }
+    }
+    std::os::unix::fs::chroot(dir).map_err(|e| {
+        let hint = if e.kind() == io::ErrorKind::PermissionDenied {
+            " (it needs CAP_SYS_CHROOT)"
+        } else {
+            ""
+        };
+        format!("couldn't chroot to {dir}: {e}{hint}")
+    })?;
+    std::env::set_current_dir("/").map_err(|e| format!("couldn't enter {dir}: {e}"))
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Capability sets of the current thread, one bit per capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilitySets {
+    pub effective: u64,
+    pub permitted: u64,
+    pub inheritable: u64,
+}
+
+impl CapabilitySets {
+    /// Reads the capability sets of the current thread.
+    pub fn current() -> Result<CapabilitySets, String> {
+        let mut header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        // SAFETY: capget fills the two data structs of version 3.
+        if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+            return Err(format!(
+                "couldn't read the capabilities: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        let join = |low: u32, high: u32| u64::from(low) | (u64::from(high) << 32);
+        Ok(CapabilitySets {
+            effective: join(data[0].effective, data[1].effective),
+            permitted: join(data[0].permitted, data[1].permitted),
+            inheritable: join(data[0].inheritable, data[1].inheritable),
+        })
+    }
+
+    fn apply(&self) -> Result<(), String> {
+        let mut header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let split = |set: u64, high: bool| if high { (set >> 32) as u32 } else { set as u32 };
+        let data = [false, true].map(|high| CapData {
+            effective: split(self.effective, high),
+            permitted: split(self.permitted, high),
+            inheritable: split(self.inheritable, high),
+        });
+        // SAFETY: capset only reads the two data structs of version 3.
+        if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+            return Err(format!(
+                "couldn't set the capabilities: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Drops all the capabilities of the current thread but `keep`, and forbids
+/// gaining new ones. The capabilities of `keep` are ambient, for the commands
+/// run by the sensors (ipmitool) to have them as well.
+pub fn drop_capabilities(keep: &[u32]) -> Result<(), String> {
+    let current = CapabilitySets::current()?;
+    let keep_mask = keep.iter().fold(0u64, |mask, cap| mask | (1 << cap)) & current.permitted;
+    for cap in keep {
+        if keep_mask & (1 << cap) == 0 {
+            warn!(
+                "{} is needed but scaphandre wasn't started with it",
+                capability_name(*cap)
+            );
+        }
+    }
+    // the bounding set can only be lowered with CAP_SETPCAP, which
+    // no_new_privs makes pointless to keep anyway
+    let last_cap = fs::read_to_string(CAP_LAST_CAP)
+        .ok()
+        .and_then(|last| last.trim().parse::<u32>().ok())
+        .unwrap_or(40);
+    if current.effective & (1 << CAP_SETPCAP) != 0 {
+        for cap in (0..=last_cap).filter(|cap| keep_mask & (1 << cap) == 0) {
+            // SAFETY: prctl only reads its integer arguments.
+            unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+        }
+    }
+    // SAFETY: prctl only reads its integer arguments.
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+            0,
+            0,
+            0,
+        )
+    };
+    CapabilitySets {
+        effective: keep_mask,
+        permitted: keep_mask,
+        inheritable: keep_mask,
+    }
+    .apply()?;
+    for cap in (0..64).filter(|cap| keep_mask & (1 << cap) != 0) {
+        // SAFETY: prctl only reads its integer arguments.
+        unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                cap as libc::c_ulong,
+                0,
+                0,
+            )
+        };
+    }
+    // SAFETY: prctl only reads its integer arguments.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!(
+            "couldn't set no_new_privs: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let kept: Vec<String> = (0..64)
+        .filter(|cap| keep_mask & (1 << cap) != 0)
+        .map(capability_name)
+        .collect();
+    info!("Dropped all capabilities but {kept:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_needed() {
+        assert_eq!(parse_capability("cap_net_bind_service"), Ok(10));
+        assert_eq!(parse_capability("SYS_PTRACE"), Ok(19));
+        assert!(parse_capability("CAP_FLY").is_err());
+        assert_eq!(capability_name(2), "CAP_DAC_READ_SEARCH");
+
+        assert_eq!(needed_capabilities(None, true, false), vec![2, 38, 19]);
+        assert!(needed_capabilities(Some("smartplug"), false, false).is_empty());
+        assert_eq!(needed_capabilities(Some("ipmi"), false, true), vec![1]);
+        assert_eq!(
+            needed_capabilities(Some("msr_linux"), false, false),
+            vec![2, 17]
+        );
+        assert!(needed_capabilities(Some("ipmi"), false, false).is_empty());
+
+        assert!(CapabilitySets::current().is_ok());
+        assert!(check_powercap("/nonexistent").is_ok());
+        let denied = read_denied(
+            "/sys/class/powercap/intel-rapl:0/energy_uj",
+            &io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        assert!(denied.contains("CAP_DAC_READ_SEARCH"));
+        assert!(denied.ends_with(":r /sys/class/powercap/intel-rapl:0/energy_uj)"));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,438 @@
+//! # Seccomp: restricting the system calls of scaphandre
+//!
+//! Scaphandre runs for months, often as root or with capabilities letting it
+//! read any file. Once it is started, it only needs a small set of system
+//! calls: reading files of `/proc` and `/sys`, managing its memory and threads,
+//! and talking on the network. At startup, after the sensor and the exporter
+//! are built, a seccomp filter allowing only those is applied to all its
+//! threads, so that a flaw in scaphandre or one of its dependencies can't be
+//! turned into running arbitrary commands or loading kernel modules.
+//!
+//! The allowed system calls depend on the exporter: only the ones serving
+//! metrics (prometheus) can accept connections, and only the ipmi sensor,
+//! which runs `ipmitool`, can start commands. Other ones are added with
+//! `--seccomp-allow`, the filter is disabled with `--no-seccomp`. Denied calls
+//! fail with `EPERM` ("Operation not permitted") rather than killing
+//! scaphandre.
+//!
+//! The filter is only available on x86_64 and aarch64.
+use std::io;
+use std::sync::Mutex;
+
+/// Lists system calls as (name, number) pairs.
+macro_rules! syscalls {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), sys::$name)),*]
+    };
+}
+
+/// Numbers of the system calls, named as in the kernel.
+mod sys {
+    pub use libc::{
+        SYS_accept as accept, SYS_accept4 as accept4, SYS_bind as bind, SYS_brk as brk,
+        SYS_capget as capget, SYS_chdir as chdir, SYS_clock_getres as clock_getres,
+        SYS_clock_gettime as clock_gettime, SYS_clock_nanosleep as clock_nanosleep,
+        SYS_clone as clone, SYS_clone3 as clone3, SYS_close as close, SYS_connect as connect,
+        SYS_dup as dup, SYS_dup3 as dup3, SYS_epoll_create1 as epoll_create1,
+        SYS_epoll_ctl as epoll_ctl, SYS_epoll_pwait as epoll_pwait, SYS_eventfd2 as eventfd2,
+        SYS_execve as execve, SYS_exit as exit, SYS_exit_group as exit_group,
+        SYS_faccessat as faccessat, SYS_faccessat2 as faccessat2, SYS_fcntl as fcntl,
+        SYS_fdatasync as fdatasync, SYS_flock as flock, SYS_fstat as fstat, SYS_fstatfs as fstatfs,
+        SYS_fsync as fsync, SYS_ftruncate as ftruncate, SYS_futex as futex, SYS_getcwd as getcwd,
+        SYS_getdents64 as getdents64, SYS_getegid as getegid, SYS_geteuid as geteuid,
+        SYS_getgid as getgid, SYS_getpeername as getpeername, SYS_getpid as getpid,
+        SYS_getppid as getppid, SYS_getpriority as getpriority, SYS_getrandom as getrandom,
+        SYS_getrusage as getrusage, SYS_getsockname as getsockname, SYS_getsockopt as getsockopt,
+        SYS_gettid as gettid, SYS_gettimeofday as gettimeofday, SYS_getuid as getuid,
+        SYS_ioctl as ioctl, SYS_kill as kill, SYS_listen as listen, SYS_lseek as lseek,
+        SYS_madvise as madvise, SYS_membarrier as membarrier, SYS_mkdirat as mkdirat,
+        SYS_mmap as mmap, SYS_mprotect as mprotect, SYS_mremap as mremap, SYS_munmap as munmap,
+        SYS_nanosleep as nanosleep, SYS_newfstatat as newfstatat, SYS_openat as openat,
+        SYS_perf_event_open as perf_event_open, SYS_pipe2 as pipe2, SYS_ppoll as ppoll,
+        SYS_prctl as prctl, SYS_pread64 as pread64, SYS_prlimit64 as prlimit64,
+        SYS_pselect6 as pselect6, SYS_pwrite64 as pwrite64, SYS_read as read,
+        SYS_readlinkat as readlinkat, SYS_readv as readv, SYS_recvfrom as recvfrom,
+        SYS_recvmmsg as recvmmsg, SYS_recvmsg as recvmsg, SYS_renameat as renameat,
+        SYS_renameat2 as renameat2, SYS_rseq as rseq, SYS_rt_sigaction as rt_sigaction,
+        SYS_rt_sigprocmask as rt_sigprocmask, SYS_rt_sigreturn as rt_sigreturn,
+        SYS_sched_getaffinity as sched_getaffinity, SYS_sched_yield as sched_yield,
+        SYS_sendmmsg as sendmmsg, SYS_sendmsg as sendmsg, SYS_sendto as sendto,
+        SYS_set_robust_list as set_robust_list, SYS_set_tid_address as set_tid_address,
+        SYS_setsockopt as setsockopt, SYS_shutdown as shutdown, SYS_sigaltstack as sigaltstack,
+        SYS_socket as socket, SYS_socketpair as socketpair, SYS_statfs as statfs,
+        SYS_statx as statx, SYS_sysinfo as sysinfo, SYS_tgkill as tgkill,
+        SYS_timerfd_create as timerfd_create, SYS_timerfd_settime as timerfd_settime,
+        SYS_uname as uname, SYS_unlinkat as unlinkat, SYS_wait4 as wait4, SYS_waitid as waitid,
+        SYS_write as write, SYS_writev as writev,
+    };
+    #[cfg(target_arch = "x86_64")]
+    pub use libc::{
+        SYS_access as access, SYS_arch_prctl as arch_prctl, SYS_dup2 as dup2,
+        SYS_epoll_wait as epoll_wait, SYS_getdents as getdents, SYS_lstat as lstat,
+        SYS_mkdir as mkdir, SYS_open as open, SYS_pipe as pipe, SYS_poll as poll,
+        SYS_readlink as readlink, SYS_rename as rename, SYS_select as select, SYS_stat as stat,
+        SYS_unlink as unlink, SYS_vfork as vfork,
+    };
+}
+
+/// System calls needed by all exporters: files, memory, threads, time,
+/// signals, and network clients (containers runtimes, webhooks, smart plugs,
+/// push gateways...). HTTP clients bind a local UDP socket to wake their
+/// agent up, which is why bind is there.
+const BASE: &[(&str, libc::c_long)] = syscalls![
+    read,
+    write,
+    readv,
+    writev,
+    pread64,
+    pwrite64,
+    openat,
+    close,
+    lseek,
+    fstat,
+    newfstatat,
+    statx,
+    statfs,
+    fstatfs,
+    getdents64,
+    readlinkat,
+    faccessat,
+    faccessat2,
+    fcntl,
+    flock,
+    ioctl,
+    dup,
+    dup3,
+    pipe2,
+    mkdirat,
+    renameat,
+    renameat2,
+    unlinkat,
+    ftruncate,
+    fsync,
+    fdatasync,
+    getcwd,
+    chdir,
+    mmap,
+    munmap,
+    mremap,
+    mprotect,
+    madvise,
+    brk,
+    membarrier,
+    clone,
+    clone3,
+    exit,
+    exit_group,
+    futex,
+    set_robust_list,
+    set_tid_address,
+    rseq,
+    sched_yield,
+    sched_getaffinity,
+    nanosleep,
+    clock_nanosleep,
+    clock_gettime,
+    clock_getres,
+    gettimeofday,
+    timerfd_create,
+    timerfd_settime,
+    getpid,
+    gettid,
+    getppid,
+    getuid,
+    geteuid,
+    getgid,
+    getegid,
+    getrandom,
+    getrusage,
+    getpriority,
+    prlimit64,
+    sysinfo,
+    uname,
+    capget,
+    prctl,
+    perf_event_open,
+    rt_sigaction,
+    rt_sigprocmask,
+    rt_sigreturn,
+    sigaltstack,
+    tgkill,
+    epoll_create1,
+    epoll_ctl,
+    epoll_pwait,
+    eventfd2,
+    ppoll,
+    pselect6,
+    socket,
+    socketpair,
+    connect,
+    sendto,
+    recvfrom,
+    sendmsg,
+    recvmsg,
+    sendmmsg,
+    recvmmsg,
+    getsockopt,
+    setsockopt,
+    getsockname,
+    getpeername,
+    shutdown,
+    bind,
+];
+
+/// Older system calls of x86_64, still used by some libraries.
+#[cfg(target_arch = "x86_64")]
+const BASE_X86_64: &[(&str, libc::c_long)] = syscalls![
+    open, stat, lstat, access, readlink, getdents, mkdir, rename, unlink, pipe, dup2, poll, select,
+    epoll_wait, arch_prctl,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const BASE_X86_64: &[(&str, libc::c_long)] = &[];
+
+/// System calls needed to accept connections.
+const SERVER: &[(&str, libc::c_long)] = syscalls![listen, accept, accept4];
+
+/// System calls needed to run commands and wait for them.
+const COMMANDS: &[(&str, libc::c_long)] = syscalls![execve, wait4, waitid, kill];
+#[cfg(target_arch = "x86_64")]
+const COMMANDS_X86_64: &[(&str, libc::c_long)] = syscalls![vfork];
+#[cfg(not(target_arch = "x86_64"))]
+const COMMANDS_X86_64: &[(&str, libc::c_long)] = &[];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+// offsets of the fields of struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+/// Name and number of allowed system calls of the applied profile.
+static APPLIED: Mutex<Option<(String, usize)>> = Mutex::new(None);
+
+/// System calls allowed by the filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Name of the profile, the exporter it was made for.
+    pub name: String,
+    syscalls: Vec<libc::c_long>,
+}
+
+impl Profile {
+    /// Returns the profile of `exporter`, allowed to accept connections if
+    /// `listens`, and to run commands if `runs_commands`.
+    pub fn new(exporter: &str, listens: bool, runs_commands: bool) -> Profile {
+        let mut profile = Profile {
+            name: String::from(exporter),
+            syscalls: vec![],
+        };
+        profile.extend(BASE);
+        profile.extend(BASE_X86_64);
+        if listens {
+            profile.extend(SERVER);
+        }
+        if runs_commands {
+            profile.extend(COMMANDS);
+            profile.extend(COMMANDS_X86_64);
+        }
+        profile
+    }
+
+    fn extend(&mut self, syscalls: &[(&str, libc::c_long)]) {
+        for (_, number) in syscalls {
+            if !self.syscalls.contains(number) {
+                self.syscalls.push(*number);
+            }
+        }
+    }
+
+    /// Allows `syscall` as well, given by its name or number.
+    pub fn allow(&mut self, syscall: &str) -> Result<(), String> {
+        let known = [BASE, BASE_X86_64, SERVER, COMMANDS, COMMANDS_X86_64]
+            .into_iter()
+            .flatten()
+            .find(|(name, _)| *name == syscall)
+            .map(|(_, number)| *number);
+        let number = match known {
+            Some(number) => number,
+            None => syscall.parse::<libc::c_long>().map_err(|_| {
+                format!("unknown system call {syscall}, give it by its number instead")
+            })?,
+        };
+        self.extend(&[(syscall, number)]);
+        Ok(())
+    }
+
+    /// Number of allowed system calls.
+    pub fn len(&self) -> usize {
+        self.syscalls.len()
+    }
+
+    /// Tells if no system call is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.syscalls.is_empty()
+    }
+
+    /// Compiles the profile to a BPF program: calls of another architecture
+    /// kill the process, allowed ones go through, the others fail with EPERM.
+    fn program(&self) -> Vec<libc::sock_filter> {
+        let statement = |code: u32, k: u32| libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump_if_equal = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            jt,
+            jf,
+            k,
+        };
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let ret = libc::BPF_RET | libc::BPF_K;
+        let mut program = vec![
+            statement(load, SECCOMP_DATA_ARCH),
+            jump_if_equal(AUDIT_ARCH, 1, 0),
+            statement(ret, SECCOMP_RET_KILL_PROCESS),
+            statement(load, SECCOMP_DATA_NR),
+        ];
+        // each comparison jumps to the final ALLOW when it matches
+        let count = self.syscalls.len();
+        for (i, number) in self.syscalls.iter().enumerate() {
+            program.push(jump_if_equal(*number as u32, (count - i) as u8, 0));
+        }
+        program.push(statement(ret, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        program.push(statement(ret, SECCOMP_RET_ALLOW));
+        program
+    }
+
+    /// Applies the filter to all the threads of scaphandre. It can't be
+    /// removed afterwards.
+    pub fn apply(&self) -> Result<(), String> {
+        if self.syscalls.len() > u8::MAX as usize {
+            return Err(format!(
+                "too many system calls in the {} profile",
+                self.name
+            ));
+        }
+        let program = self.program();
+        let fprog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: prctl only reads its integer arguments.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(format!(
+                "couldn't set no_new_privs: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: seccomp only reads the program, which outlives the call.
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog as *const libc::sock_fprog,
+            )
+        };
+        match result {
+            0 => {}
+            -1 => {
+                return Err(format!(
+                    "couldn't apply the seccomp filter: {}",
+                    io::Error::last_os_error()
+                ))
+            }
+            thread => {
+                return Err(format!(
+                    "couldn't apply the seccomp filter to thread {thread}"
+                ))
+            }
+        }
+        info!(
+            "Applied the {} seccomp profile, allowing {} system calls",
+            self.name,
+            self.syscalls.len()
+        );
+        *APPLIED.lock().unwrap() = Some((self.name.clone(), self.syscalls.len()));
+        Ok(())
+    }
+}
+
+/// Returns the name and number of allowed system calls of the applied
+/// profile, if any.
+pub fn applied() -> Option<(String, usize)> {
+    APPLIED.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let stdout = Profile::new("stdout", false, false);
+        let prometheus = Profile::new("prometheus", true, false);
+        assert_eq!(prometheus.len(), stdout.len() + SERVER.len());
+        assert!(!stdout.syscalls.contains(&libc::SYS_listen));
+        assert!(!stdout.syscalls.contains(&libc::SYS_execve));
+        assert!(Profile::new("stdout", false, true)
+            .syscalls
+            .contains(&libc::SYS_execve));
+
+        let mut json = Profile::new("json", false, false);
+        json.allow("listen").unwrap();
+        json.allow("listen").unwrap();
+        json.allow("999").unwrap();
+        assert_eq!(json.len(), stdout.len() + 2);
+        assert!(json.allow("not_a_syscall").is_err());
+
+        let program = stdout.program();
+        assert_eq!(program.len(), stdout.len() + 6);
+        // the last comparison jumps over the EPERM return to the ALLOW one
+        assert_eq!(program[program.len() - 3].jt, 1);
+        assert_eq!(program.last().unwrap().k, SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn sensors_running_commands() {
+        use crate::sensors::hypervisor::{Hypervisor, HypervisorSensor};
+        use crate::sensors::ipmi::IpmiSensor;
+        use crate::sensors::utils::ProcessFilters;
+        use crate::sensors::{FilteredSensor, Sensor};
+
+        let profile = |sensor: &dyn Sensor| Profile::new("stdout", false, sensor.runs_commands());
+        let vmware = HypervisorSensor::new(Hypervisor::Vmware);
+        assert!(profile(&vmware).syscalls.contains(&libc::SYS_execve));
+        let ipmi = IpmiSensor::new(Some("bmc.local"), Some("admin"), None);
+        assert!(profile(&ipmi).syscalls.contains(&libc::SYS_execve));
+        let filtered = FilteredSensor::new(Box::new(vmware), ProcessFilters::default());
+        assert!(profile(&filtered).syscalls.contains(&libc::SYS_execve));
+
+        let hyperv = HypervisorSensor::new(Hypervisor::HyperV);
+        assert!(!profile(&hyperv).syscalls.contains(&libc::SYS_execve));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
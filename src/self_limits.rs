@@ -0,0 +1,205 @@
+//! # Self limits: keeping scaphandre away from the workloads it measures
+//!
+//! The `--self-*` options pin scaphandre to some CPUs (e.g. a housekeeping core)
+//! and lower its CPU and I/O priorities. They are applied at startup, before the
+//! sensor and the exporter start their threads, which inherit them.
+//!
+//! On Linux they rely on `sched_setaffinity(2)`, `setpriority(2)` and
+//! `ioprio_set(2)`. On Windows the nice value is mapped to a priority class
+//! (`SetPriorityClass`); the CPU affinity and the I/O priority aren't supported
+//! there, as the MSR sensor has to run on a core of each socket.
+
+/// I/O scheduling classes usable with `--self-ionice`.
+pub const IONICE_CLASSES: [&str; 2] = ["idle", "best-effort"];
+
+/// Limits applied to the scaphandre process itself.
+#[derive(Debug, Clone, Default)]
+pub struct SelfLimits {
+    /// CPUs scaphandre is allowed to run on, all of them if empty
+    pub cpu_affinity: Vec<usize>,
+    /// Nice value, from -20 (highest priority) to 19 (lowest priority)
+    pub nice: Option<i32>,
+    /// I/O scheduling class, one of [IONICE_CLASSES]
+    pub ionice: Option<String>,
+}
+
+impl SelfLimits {
+    /// Returns true if no limit has to be applied.
+    pub fn is_empty(&self) -> bool {
+        self.cpu_affinity.is_empty() && self.nice.is_none() && self.ionice.is_none()
+    }
+
+    /// Applies the limits to the current process, stopping at the first one
+    /// that fails.
+    pub fn apply(&self) -> Result<(), String> {
+        if !self.cpu_affinity.is_empty() {
+            set_cpu_affinity(&self.cpu_affinity)?;
+            info!("Pinned scaphandre to CPUs {:?}", self.cpu_affinity);
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("nice value {nice} isn't between -20 and 19"));
+            }
+            set_nice(nice)?;
+            info!("Set the nice value of scaphandre to {nice}");
+        }
+        if let Some(class) = &self.ionice {
+            set_ionice(class)?;
+            info!("Set the I/O scheduling class of scaphandre to {class}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpus: &[usize]) -> Result<(), String> {
+    if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+        return Err(format!("CPU {cpu} doesn't exist"));
+    }
+    // SAFETY: cpu_set_t is a plain bitmask, zeroed before setting the CPUs
+    // to allow, and only read by sched_setaffinity.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(format!(
+                "couldn't set the CPU affinity: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> Result<(), String> {
+    // SAFETY: setpriority only reads its integer arguments.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(format!(
+            "couldn't set the nice value: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_ionice(class: &str) -> Result<(), String> {
+    // from linux/ioprio.h, best-effort is used at its lowest level
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let prio = match class {
+        "idle" => 3 << IOPRIO_CLASS_SHIFT,
+        "best-effort" => (2 << IOPRIO_CLASS_SHIFT) | 7,
+        _ => return Err(format!("unknown I/O scheduling class {class}")),
+    };
+    // SAFETY: ioprio_set only reads its integer arguments.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        return Err(format!(
+            "couldn't set the I/O scheduling class: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn set_cpu_affinity(_cpus: &[usize]) -> Result<(), String> {
+    Err(String::from(
+        "the CPU affinity isn't supported on Windows, the sensor has to run on each socket",
+    ))
+}
+
+#[cfg(target_os = "windows")]
+fn set_nice(nice: i32) -> Result<(), String> {
+    use windows::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, ABOVE_NORMAL_PRIORITY_CLASS,
+        BELOW_NORMAL_PRIORITY_CLASS, HIGH_PRIORITY_CLASS, IDLE_PRIORITY_CLASS,
+        NORMAL_PRIORITY_CLASS,
+    };
+    let class = match nice {
+        15..=19 => IDLE_PRIORITY_CLASS,
+        1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        -14..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        _ => HIGH_PRIORITY_CLASS,
+    };
+    // SAFETY: the pseudo handle of the current process doesn't need to be closed.
+    if !unsafe { SetPriorityClass(GetCurrentProcess(), class) }.as_bool() {
+        return Err(format!(
+            "couldn't set the priority class: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn set_ionice(_class: &str) -> Result<(), String> {
+    Err(String::from(
+        "the I/O scheduling class isn't supported on Windows",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_cpu_affinity(_cpus: &[usize]) -> Result<(), String> {
+    Err(String::from(
+        "the CPU affinity isn't supported on this operating system",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_nice(nice: i32) -> Result<(), String> {
+    // SAFETY: setpriority only reads its integer arguments.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(format!(
+            "couldn't set the nice value: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn set_ionice(_class: &str) -> Result<(), String> {
+    Err(String::from(
+        "the I/O scheduling class isn't supported on this operating system",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_limits_checked() {
+        assert!(SelfLimits::default().is_empty());
+        assert!(SelfLimits::default().apply().is_ok());
+        let limits = SelfLimits {
+            cpu_affinity: vec![usize::MAX],
+            ..Default::default()
+        };
+        assert!(limits.apply().is_err());
+        let limits = SelfLimits {
+            nice: Some(20),
+            ..Default::default()
+        };
+        assert!(limits.apply().unwrap_err().contains("between -20 and 19"));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,225 @@
+//! # Selftest: sanity check of the measurements with a known load
+//!
+//! `scaphandre selftest` measures the energy of the host while it is idle,
+//! then while a spin loop keeps one CPU core busy, for the same duration. A
+//! busy core always draws more power than an idle one, so the measurement
+//! passes when the energy of the loaded phase is higher than the energy of the
+//! idle phase by at least a minimum power (1 W by default) over its duration.
+//!
+//! This doesn't check the accuracy of the measurements, only that they follow
+//! the load of the host: a counter that doesn't advance, or doesn't react to a
+//! busy core (a virtual machine without the qemu exporter on its hypervisor, a
+//! disabled RAPL zone...), fails it. Other workloads of the host make the
+//! result less reliable, it is meant to be run on a quiet host.
+use crate::sensors::{RecordGenerator, Sensor, Topology};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Length of a chunk of the spin loop, after which the loop checks if it
+/// should stop.
+const CHUNK_DURATION: Duration = Duration::from_millis(1);
+
+/// Options of the selftest.
+#[derive(clap::Args, Debug)]
+pub struct SelftestArgs {
+    /// Duration of each phase (idle, then one core busy), in seconds
+    #[arg(short, long, default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    pub duration: u64,
+
+    /// Minimum increase of the host power, in watts, when a core is busy
+    #[arg(long, value_name = "WATTS", default_value_t = 1.0)]
+    pub min_increase: f64,
+}
+
+/// Energy of the host measured over a phase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub microjoules: u64,
+    pub duration: Duration,
+}
+
+impl Phase {
+    pub fn watts(&self) -> f64 {
+        self.microjoules as f64 / 1e6 / self.duration.as_secs_f64()
+    }
+}
+
+/// Result of the selftest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub idle: Phase,
+    pub load: Phase,
+    /// Iterations of the spin loop per millisecond
+    pub iterations_per_ms: u64,
+    pub min_increase_watts: f64,
+}
+
+impl Report {
+    /// Increase of the host power when a core is busy, in watts.
+    pub fn increase_watts(&self) -> f64 {
+        self.load.watts() - self.idle.watts()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.idle.microjoules > 0 && self.increase_watts() >= self.min_increase_watts
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "idle:      {:.3} J in {:.1} s ({:.2} W)",
+            self.idle.microjoules as f64 / 1e6,
+            self.idle.duration.as_secs_f64(),
+            self.idle.watts()
+        )?;
+        writeln!(
+            f,
+            "1 core:    {:.3} J in {:.1} s ({:.2} W, spin loop at {} iterations/ms)",
+            self.load.microjoules as f64 / 1e6,
+            self.load.duration.as_secs_f64(),
+            self.load.watts(),
+            self.iterations_per_ms
+        )?;
+        write!(
+            f,
+            "increase:  {:.2} W (expected at least {:.2} W)",
+            self.increase_watts(),
+            self.min_increase_watts
+        )
+    }
+}
+
+/// Runs `iterations` of a computation the compiler can't optimize away.
+fn spin(iterations: u64) -> u64 {
+    let mut x = 0x9e37_79b9_7f4a_7c15_u64;
+    for i in 0..iterations {
+        x = std::hint::black_box(x.rotate_left(5) ^ i).wrapping_mul(0x0100_0000_01b3);
+    }
+    x
+}
+
+/// Returns the number of iterations of the spin loop that run in a millisecond.
+fn calibrate() -> u64 {
+    let mut iterations = 1000;
+    loop {
+        let start = Instant::now();
+        spin(iterations);
+        let elapsed = start.elapsed();
+        if elapsed >= Duration::from_millis(50) {
+            return ((iterations as f64 / elapsed.as_secs_f64() / 1000.0) as u64).max(1);
+        }
+        iterations *= 2;
+    }
+}
+
+/// Returns the value of the host energy counter, in microjoules, and the time it was read.
+fn read_energy(topology: &mut Topology) -> Result<(u64, Duration), String> {
+    topology.refresh();
+    let record = topology
+        .get_records_passive()
+        .pop()
+        .ok_or("couldn't read the host energy counter")?;
+    let microjoules = record
+        .value
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid host energy counter '{}': {e}", record.value))?;
+    Ok((microjoules, record.timestamp))
+}
+
+/// Measures the energy of the host while `during` runs.
+fn measure(topology: &mut Topology, during: impl FnOnce()) -> Result<Phase, String> {
+    let (start, start_time) = read_energy(topology)?;
+    during();
+    let (end, end_time) = read_energy(topology)?;
+    if end < start {
+        return Err(String::from(
+            "the host energy counter wrapped around during the test, run it again",
+        ));
+    }
+    let duration = end_time
+        .checked_sub(start_time)
+        .filter(|d| !d.is_zero())
+        .ok_or("the host energy counter wasn't read again during the test")?;
+    Ok(Phase {
+        microjoules: end - start,
+        duration,
+    })
+}
+
+/// Measures the host idle, then with one core busy, for `args.duration` each.
+pub fn run(sensor: &dyn Sensor, args: &SelftestArgs) -> Result<Report, String> {
+    let mut topology =
+        (*sensor.get_topology()).ok_or_else(|| String::from("the sensor provides no topology"))?;
+    let duration = Duration::from_secs(args.duration);
+    let iterations_per_ms = calibrate();
+    let chunk = iterations_per_ms * CHUNK_DURATION.as_millis() as u64;
+
+    let idle = measure(&mut topology, || thread::sleep(duration))?;
+    let load = measure(&mut topology, || {
+        let stop = Arc::new(AtomicBool::new(false));
+        let spinning = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    spin(chunk);
+                }
+            })
+        };
+        thread::sleep(duration);
+        stop.store(true, Ordering::Relaxed);
+        let _ = spinning.join();
+    })?;
+    Ok(Report {
+        idle,
+        load,
+        iterations_per_ms,
+        min_increase_watts: args.min_increase,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_verdict() {
+        let phase = |joules: u64| Phase {
+            microjoules: joules * 1_000_000,
+            duration: Duration::from_secs(10),
+        };
+        let report = |idle, load| Report {
+            idle: phase(idle),
+            load: phase(load),
+            iterations_per_ms: 1000,
+            min_increase_watts: 1.0,
+        };
+        assert!(report(50, 110).passed());
+        assert_eq!(report(50, 110).increase_watts(), 6.0);
+        assert!(!report(50, 55).passed(), "0.5 W isn't enough");
+        assert!(!report(0, 0).passed(), "a flat counter fails");
+        assert!(report(50, 110).to_string().contains("6.00 W"));
+
+        assert!(calibrate() > 0);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
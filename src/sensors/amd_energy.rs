@@ -0,0 +1,264 @@
+//! # amd_energy: RAPL counters of AMD CPUs from hwmon
+//!
+//! Before Linux 5.8 (5.11 for the core domain), powercap has no RAPL zone on
+//! AMD CPUs. The `amd_energy` hwmon driver reads the same counters, the
+//! `MSR_AMD_PKG_ENERGY_STATUS` and `MSR_AMD_CORE_ENERGY_STATUS` registers of
+//! Zen CPUs, and exposes them in `/sys/class/hwmon/hwmonN`:
+//!
+//! - `energyN_input` files labelled `EsocketS` are the packages,
+//! - `energyN_input` files labelled `EcoreC` are the cores, C being the
+//!   number of a CPU. Hyperthreads of a core share its counter.
+//!
+//! The driver accumulates the 32 bits registers in 64 bits counters, in
+//! microjoules, so that they don't wrap around. Since CVE-2020-12912, the
+//! files are readable by root only.
+//!
+//! The powercap sensor reads them when the CPU is an AMD one and powercap has
+//! no RAPL zone: each package is a socket, and its cores, one thread each,
+//! are summed into its `core` domain.
+use crate::sensors::utils::{current_system_time_since_epoch, read_trimmed};
+use crate::sensors::{counter_file::CounterFiles, Record, RecordSource, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use super::units::Unit::MicroJoule;
+
+/// Folder of the hwmon devices of the host.
+pub const HWMON_PATH: &str = "/sys/class/hwmon";
+
+/// Folder of the CPUs of the host, to know their package and core.
+pub const CPU_TOPOLOGY_PATH: &str = "/sys/devices/system/cpu";
+
+/// Name of the hwmon device of the driver.
+pub const DRIVER_NAME: &str = "amd_energy";
+
+/// Key of the hwmon device in the sensor data of the sockets.
+pub const SENSOR_DATA_KEY: &str = "amd_energy";
+/// Key of the counter files of the cores, comma separated, in the sensor data
+/// of the `core` domains.
+pub const SENSOR_DATA_CORE_FILES_KEY: &str = "amd_energy_core_files";
+
+/// Counters exposed by the amd_energy driver.
+#[derive(Debug, Default, PartialEq)]
+pub struct Channels {
+    /// Counter file of each package, by socket id
+    pub sockets: Vec<(u16, String)>,
+    /// Counter files of the cores of each package, one thread per core
+    pub cores: HashMap<u16, Vec<String>>,
+}
+
+impl Channels {
+    /// Lists the counters of the hwmon device at `hwmon_path`, reading the
+    /// package and core of each CPU from `cpu_path`.
+    pub fn read(hwmon_path: &str, cpu_path: &str) -> Channels {
+        let mut channels = Channels::default();
+        let Ok(entries) = fs::read_dir(hwmon_path) else {
+            return channels;
+        };
+        let mut labels: Vec<(String, String)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let channel = file_name.strip_suffix("_label")?.to_string();
+                let label = read_trimmed(&entry.path())?;
+                Some((label, format!("{hwmon_path}/{channel}_input")))
+            })
+            .collect();
+        // read_dir gives the files in no order, Ecore000 comes before Ecore001
+        labels.sort();
+        let mut seen_cores: Vec<(u16, String)> = vec![];
+        for (label, input) in labels {
+            if let Some(socket) = label.strip_prefix("Esocket") {
+                if let Ok(socket) = socket.parse::<u16>() {
+                    channels.sockets.push((socket, input));
+                }
+            } else if let Some(cpu) = label.strip_prefix("Ecore") {
+                let Ok(cpu) = cpu.parse::<u32>() else {
+                    continue;
+                };
+                let topology = Path::new(cpu_path).join(format!("cpu{cpu}/topology"));
+                let field = |name: &str| read_trimmed(&topology.join(name));
+                let (Some(package), Some(core)) = (
+                    field("physical_package_id").and_then(|id| id.parse::<u16>().ok()),
+                    field("core_id"),
+                ) else {
+                    // offline CPUs have no topology
+                    continue;
+                };
+                // hyperthreads of a core read the same counter
+                if seen_cores.contains(&(package, core.clone())) {
+                    continue;
+                }
+                seen_cores.push((package, core));
+                channels.cores.entry(package).or_default().push(input);
+            }
+        }
+        channels
+    }
+}
+
+/// Returns the folder of the hwmon device of the amd_energy driver in
+/// `base_path`, if it is loaded.
+pub fn find(base_path: &str) -> Option<String> {
+    let mut devices: Vec<String> = fs::read_dir(base_path)
+        .ok()?
+        .flatten()
+        .filter(|entry| read_trimmed(&entry.path().join("name")).as_deref() == Some(DRIVER_NAME))
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .collect();
+    devices.sort();
+    devices.into_iter().next()
+}
+
+/// Adds the packages of the amd_energy device at `hwmon_path` to `topo` as
+/// sockets, with their cores as a `core` domain.
+pub fn add_to_topology(
+    topo: &mut Topology,
+    hwmon_path: &str,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+) -> Result<(), String> {
+    let channels = Channels::read(hwmon_path, CPU_TOPOLOGY_PATH);
+    if channels.sockets.is_empty() {
+        return Err(format!("no Esocket counter in {hwmon_path}"));
+    }
+    let (_, first) = &channels.sockets[0];
+    fs::read_to_string(first).map_err(|e| {
+        format!("couldn't read {first} ({e}), the amd_energy counters are readable by root only")
+    })?;
+    for (socket_id, input) in &channels.sockets {
+        let sensor_data = HashMap::from([
+            (String::from("source_file"), input.clone()),
+            (String::from(SENSOR_DATA_KEY), String::from(hwmon_path)),
+        ]);
+        topo.safe_add_socket(
+            *socket_id,
+            vec![],
+            vec![],
+            input.clone(),
+            buffer_per_socket_max_kbytes,
+            sensor_data,
+        );
+        let Some(cores) = channels.cores.get(socket_id) else {
+            continue;
+        };
+        let core_files = cores.join(",");
+        topo.safe_add_domain_to_socket(
+            *socket_id,
+            0,
+            "core",
+            &core_files,
+            buffer_per_domain_max_kbytes,
+            HashMap::from([(String::from(SENSOR_DATA_CORE_FILES_KEY), core_files.clone())]),
+        );
+    }
+    Ok(())
+}
+
+/// Tells whether the sensor data of a `core` domain is the one of the sum of
+/// the amd_energy core counters.
+pub fn is_core_sum(sensor_data: &HashMap<String, String>) -> bool {
+    sensor_data.contains_key(SENSOR_DATA_CORE_FILES_KEY)
+}
+
+/// Reads the energy of a `core` domain, summing the counters of its cores.
+pub fn read_core_record(
+    sensor_data: &HashMap<String, String>,
+    counter_files: &CounterFiles,
+) -> Result<Record, Box<dyn Error>> {
+    let files = sensor_data
+        .get(SENSOR_DATA_CORE_FILES_KEY)
+        .ok_or("no core counter in sensor data")?;
+    let mut total: u64 = 0;
+    for path in files.split(',') {
+        total += counter_files.read(path, path)?.trim().parse::<u64>()?;
+    }
+    Ok(Record::new(
+        current_system_time_since_epoch(),
+        total.to_string(),
+        MicroJoule,
+    )
+    .with_source(Some(RecordSource::AmdEnergy)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::utils::write_sysfs_attribute;
+
+    #[test]
+    fn amd_energy_channels() {
+        let base = std::env::temp_dir().join(format!("scaph-amd-energy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        write_sysfs_attribute(&base, "hwmon/hwmon0/name", "nvme");
+        let hwmon = base.join("hwmon/hwmon3");
+        write_sysfs_attribute(&hwmon, "name", DRIVER_NAME);
+        // 2 cores of 2 threads on socket 0, cpu 3 being offline
+        for (channel, label, energy) in [
+            (1, "Ecore000", 1000),
+            (2, "Ecore001", 2000),
+            (3, "Ecore002", 1000),
+            (4, "Ecore003", 2000),
+            (5, "Esocket0", 9000),
+        ] {
+            write_sysfs_attribute(&hwmon, &format!("energy{channel}_label"), label);
+            write_sysfs_attribute(
+                &hwmon,
+                &format!("energy{channel}_input"),
+                &energy.to_string(),
+            );
+        }
+        let cpus = base.join("cpu");
+        for (cpu, core) in [(0, 0), (1, 1), (2, 0)] {
+            write_sysfs_attribute(
+                &cpus,
+                &format!("cpu{cpu}/topology/physical_package_id"),
+                "0",
+            );
+            write_sysfs_attribute(
+                &cpus,
+                &format!("cpu{cpu}/topology/core_id"),
+                &core.to_string(),
+            );
+        }
+
+        let base_path = base.join("hwmon").to_string_lossy().to_string();
+        let hwmon_path = find(&base_path).unwrap();
+        assert!(hwmon_path.ends_with("hwmon3"));
+        let channels = Channels::read(&hwmon_path, &cpus.to_string_lossy());
+        assert_eq!(
+            channels.sockets,
+            vec![(0, format!("{hwmon_path}/energy5_input"))]
+        );
+        let cores = channels.cores[&0].join(",");
+        assert_eq!(
+            cores,
+            format!("{hwmon_path}/energy1_input,{hwmon_path}/energy2_input")
+        );
+
+        let sensor_data = HashMap::from([(String::from(SENSOR_DATA_CORE_FILES_KEY), cores)]);
+        assert!(is_core_sum(&sensor_data));
+        let record = read_core_record(&sensor_data, &CounterFiles::default()).unwrap();
+        assert_eq!(record.value, "3000");
+        assert_eq!(record.source, Some(RecordSource::AmdEnergy));
+
+        fs::remove_dir_all(&base).unwrap();
+        assert!(find(&base_path).is_none());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,313 @@
+//! # Battery sensor: power of a laptop running on battery
+//!
+//! Laptops whose RAPL counters aren't readable by the user (since
+//! CVE-2020-8694, they are readable by root only) still expose their battery
+//! in `/sys/class/power_supply`, readable by everyone. While the laptop runs
+//! on battery, the power drawn from it is the power of the whole host:
+//!
+//! - `power_now`, in microwatts, when the battery gives it,
+//! - `voltage_now` times `current_now` otherwise, in microvolts and
+//!   microamperes,
+//! - the decrease of `energy_now` (or `charge_now` times `voltage_now`)
+//!   between two measurements when neither is given. Batteries refresh it
+//!   every few seconds to a minute, the power is then coarse.
+//!
+//! On AC power, the battery is charging or idle and says nothing of the power
+//! of the host: there is no host record until the laptop runs on battery
+//! again, the energy counter of the host starting from 0 then. Whether it
+//! runs on AC is published as `scaph_host_ac_online`.
+use crate::sensors::utils::{
+    accumulate_power_as_energy, current_system_time_since_epoch, read_trimmed,
+};
+use crate::sensors::{units, Record, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Key of the topology sensor data holding the folder of the battery.
+pub const SENSOR_DATA_KEY: &str = "battery";
+
+/// Folder of the power supplies of the host.
+pub const DEFAULT_POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Energy left in the battery at the last measurement, in microwatt-hours if
+/// the battery gives it. None when the last measurement was on AC power.
+static LAST_ENERGY: Mutex<Option<Option<f64>>> = Mutex::new(None);
+
+/// Error of a measurement made while the laptop runs on AC power.
+#[derive(Debug)]
+pub struct OnAcPower;
+
+impl fmt::Display for OnAcPower {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the host runs on AC power, its battery doesn't give its power"
+        )
+    }
+}
+
+impl Error for OnAcPower {}
+
+/// What the battery measures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryReading {
+    /// Whether the battery powers the host.
+    pub discharging: bool,
+    /// Power drawn from the battery, in watts, if it gives it.
+    pub watts: Option<f64>,
+    /// Energy left in the battery, in microwatt-hours, if it gives it.
+    pub energy_microwatt_hours: Option<f64>,
+}
+
+impl BatteryReading {
+    /// Reads the battery whose folder is `path`.
+    pub fn read(path: &str) -> Result<BatteryReading, Box<dyn Error>> {
+        let path = Path::new(path);
+        let status = fs::read_to_string(path.join("status"))
+            .map_err(|e| format!("couldn't read the status of {}: {e}", path.display()))?;
+        let field = |name: &str| {
+            fs::read_to_string(path.join(name))
+                .ok()
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        let voltage = field("voltage_now");
+        // some drivers give a negative current when discharging
+        let watts = field("power_now")
+            .map(|microwatts| microwatts.abs() / 1e6)
+            .or_else(|| Some(voltage? * field("current_now")?.abs() / 1e12));
+        let energy_microwatt_hours =
+            field("energy_now").or_else(|| Some(field("charge_now")? * voltage? / 1e6));
+        Ok(BatteryReading {
+            discharging: status.trim() == "Discharging",
+            watts,
+            energy_microwatt_hours,
+        })
+    }
+}
+
+/// Returns the folder of the battery `name` in `base_path`, or of the first
+/// battery of the host if None. Batteries of peripherals (mice, keyboards...)
+/// are left out.
+pub fn find_battery(base_path: &str, name: Option<&str>) -> Result<String, String> {
+    if let Some(name) = name {
+        let path = Path::new(base_path).join(name);
+        return if path.join("status").exists() {
+            Ok(path.to_string_lossy().to_string())
+        } else {
+            Err(format!("no battery {name} in {base_path}"))
+        };
+    }
+    let mut batteries: Vec<String> = power_supplies(base_path)
+        .into_iter()
+        .filter(|(path, kind)| {
+            kind == "Battery"
+                && read_trimmed(&Path::new(path).join("scope")) != Some("Device".into())
+        })
+        .map(|(path, _)| path)
+        .collect();
+    batteries.sort();
+    batteries
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("no battery found in {base_path}"))
+}
+
+/// Tells if the host is plugged into AC power, from the mains and USB power
+/// supplies next to `battery_path`. None if the host has none.
+pub fn ac_online(battery_path: &str) -> Option<bool> {
+    let base_path = Path::new(battery_path)
+        .parent()?
+        .to_string_lossy()
+        .to_string();
+    let adapters: Vec<bool> = power_supplies(&base_path)
+        .into_iter()
+        .filter(|(_, kind)| kind == "Mains" || kind == "USB")
+        .map(|(path, _)| read_trimmed(&Path::new(&path).join("online")) == Some("1".into()))
+        .collect();
+    if adapters.is_empty() {
+        None
+    } else {
+        Some(adapters.contains(&true))
+    }
+}
+
+/// Lists the power supplies of `base_path`, as (folder, type).
+fn power_supplies(base_path: &str) -> Vec<(String, String)> {
+    let Ok(entries) = fs::read_dir(base_path) else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let kind = read_trimmed(&entry.path().join("type"))?;
+            Some((entry.path().to_string_lossy().to_string(), kind))
+        })
+        .collect()
+}
+
+/// Returns a new energy Record for a topology whose sensor is a battery.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    let path = topology
+        ._sensor_data
+        .get(SENSOR_DATA_KEY)
+        .ok_or("no battery in topology sensor data")?;
+    let reading = BatteryReading::read(path)?;
+    let mut last_energy = LAST_ENERGY.lock().unwrap();
+    if !reading.discharging {
+        if last_energy.take().is_some() {
+            info!("The host runs on AC power, its power is unknown until it runs on battery");
+        }
+        return Err(Box::new(OnAcPower));
+    }
+    let previous = topology.record_buffer.last();
+    let record = match (last_energy.take(), reading.watts) {
+        // first measurement on battery: the counter starts from there,
+        // without counting the time spent on AC power
+        (None, _) => {
+            info!("The host runs on battery, measuring its power");
+            Record::new(
+                current_system_time_since_epoch(),
+                previous.map_or_else(|| String::from("0"), |p| p.value.clone()),
+                units::Unit::MicroJoule,
+            )
+        }
+        (Some(_), Some(watts)) => accumulate_power_as_energy(previous, watts)?,
+        (Some(last), None) => {
+            let (last, energy) = last
+                .zip(reading.energy_microwatt_hours)
+                .ok_or("the battery gives neither its power nor its energy")?;
+            let previous_microjoules = match previous {
+                Some(previous) => previous.value.trim().parse::<u64>()?,
+                None => 0,
+            };
+            // 1 microwatt-hour is 3600 microjoules
+            let spent = ((last - energy).max(0.0) * 3600.0) as u64;
+            Record::new(
+                current_system_time_since_epoch(),
+                (previous_microjoules + spent).to_string(),
+                units::Unit::MicroJoule,
+            )
+        }
+    };
+    *last_energy = Some(reading.energy_microwatt_hours);
+    Ok(record)
+}
+
+/// This is a Sensor type that reads the power of a laptop from its battery.
+pub struct BatterySensor {
+    path: String,
+}
+
+impl BatterySensor {
+    /// Instantiates and returns an instance of BatterySensor, reading the
+    /// battery `name` of `base_path`, or its first battery if None.
+    pub fn new(base_path: &str, name: Option<&str>) -> Result<BatterySensor, String> {
+        Ok(BatterySensor {
+            path: find_battery(base_path, name)?,
+        })
+    }
+}
+
+impl Sensor for BatterySensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from(SENSOR_DATA_KEY), self.path.clone());
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::utils::write_sysfs_attribute;
+
+    fn power_supply(base: &Path, name: &str, files: &[(&str, &str)]) {
+        for (file, value) in files {
+            write_sysfs_attribute(&base.join(name), file, value);
+        }
+    }
+
+    #[test]
+    fn battery_readings() {
+        let base = std::env::temp_dir().join(format!("scaph-battery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        power_supply(&base, "AC", &[("type", "Mains"), ("online", "0")]);
+        power_supply(
+            &base,
+            "hidpp_battery_0",
+            &[
+                ("type", "Battery"),
+                ("scope", "Device"),
+                ("status", "Discharging"),
+            ],
+        );
+        power_supply(
+            &base,
+            "BAT1",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("voltage_now", "12000000"),
+                ("current_now", "-1500000"),
+                ("charge_now", "3000000"),
+            ],
+        );
+        power_supply(
+            &base,
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Full"),
+                ("power_now", "0"),
+                ("energy_now", "45000000"),
+            ],
+        );
+        let base_path = base.to_string_lossy().to_string();
+
+        let bat0 = find_battery(&base_path, None).unwrap();
+        assert!(bat0.ends_with("BAT0"));
+        let reading = BatteryReading::read(&bat0).unwrap();
+        assert!(!reading.discharging);
+        assert_eq!(reading.energy_microwatt_hours, Some(45000000.0));
+
+        let bat1 = find_battery(&base_path, Some("BAT1")).unwrap();
+        let reading = BatteryReading::read(&bat1).unwrap();
+        assert!(reading.discharging);
+        assert_eq!(reading.watts, Some(18.0));
+        assert_eq!(reading.energy_microwatt_hours, Some(36000000.0));
+        assert!(find_battery(&base_path, Some("BAT2")).is_err());
+
+        assert_eq!(ac_online(&bat0), Some(false));
+        power_supply(&base, "AC", &[("online", "1")]);
+        assert_eq!(ac_online(&bat0), Some(true));
+
+        fs::remove_dir_all(&base).unwrap();
+        assert!(find_battery(&base_path, None).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,198 @@
+//! # Counter files: energy counters read from files kept open
+//!
+//! Powercap exposes each RAPL counter as a small sysfs text file. Reading it
+//! with `fs::read_to_string` costs an open, a stat, reads and a close at every
+//! measurement. A [CounterFile] opens the file once and reads it again from
+//! offset 0 with a single positioned read (`pread`), which also keeps working
+//! when the directory is no longer reachable, for example after dropping
+//! privileges or in a container with a narrowed mount.
+//!
+//! Sysfs attributes are regenerated on each read from offset 0, so the file
+//! doesn't need to be reopened to see new values.
+//!
+//! The kernel has no powercap character device nor netlink interface to read
+//! energy counters from, so positioned reads on sysfs are the cheapest path it
+//! offers.
+//!
+//! Sockets, domains and the topology hold their open files in [CounterFiles],
+//! by the key of the path in their sensor data (`source_file`, `mmio`), or
+//! `psys:<zone>` for the PSYS zones. The msr devices read by the msr_linux
+//! sensor are kept open the same way, the registers being read at the offset
+//! of their address.
+use super::raw_reads;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Size of the buffer counters are read into: an energy counter is at most
+/// 20 digits long, plus a new line.
+const COUNTER_BUFFER_SIZE: usize = 32;
+
+/// How the energy counter files are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Open, read and close the file at each measurement.
+    Sysfs,
+    /// Keep the file open and read it with a positioned read.
+    #[default]
+    Pread,
+}
+
+impl FromStr for ReadMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sysfs" => Ok(ReadMode::Sysfs),
+            "pread" => Ok(ReadMode::Pread),
+            _ => Err(format!("invalid read mode {s}, expected sysfs or pread")),
+        }
+    }
+}
+
+/// A counter file kept open between reads.
+#[derive(Debug)]
+pub struct CounterFile {
+    path: String,
+    file: File,
+}
+
+impl CounterFile {
+    /// Opens the counter file at `path`.
+    pub fn open(path: &str) -> io::Result<CounterFile> {
+        Ok(CounterFile {
+            path: String::from(path),
+            file: File::open(path)?,
+        })
+    }
+
+    /// Reads the whole content of the file, as `fs::read_to_string` would.
+    pub fn read_value(&self) -> io::Result<String> {
+        let mut buffer = [0_u8; COUNTER_BUFFER_SIZE];
+        let length = self.read_at_start(&mut buffer)?;
+        if length == buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is too long for an energy counter", self.path),
+            ));
+        }
+        std::str::from_utf8(&buffer[..length])
+            .map(String::from)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads the 8 bytes at `offset` as an integer, such as the model-specific
+    /// register at this address of an msr device.
+    #[cfg(unix)]
+    pub fn read_u64_at(&self, offset: u64) -> io::Result<u64> {
+        use std::os::unix::fs::FileExt;
+        let mut buffer = [0_u8; 8];
+        self.file.read_exact_at(&mut buffer, offset)?;
+        Ok(u64::from_ne_bytes(buffer))
+    }
+
+    #[cfg(unix)]
+    fn read_at_start(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_at(buffer, 0)
+    }
+
+    #[cfg(windows)]
+    fn read_at_start(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+        self.file.seek_read(buffer, 0)
+    }
+}
+
+/// Counter files kept open by a socket, a domain or the topology, by key.
+/// Cloning it shares the open files.
+#[derive(Debug, Clone, Default)]
+pub struct CounterFiles {
+    files: HashMap<String, Arc<CounterFile>>,
+}
+
+impl CounterFiles {
+    /// Opens the counter file at `path` and keeps it under `key`. On failure the
+    /// counter will be read from `path` at each measurement instead.
+    pub fn open(&mut self, key: &str, path: &str) {
+        match CounterFile::open(path) {
+            Ok(counter) => {
+                self.files.insert(String::from(key), Arc::new(counter));
+            }
+            Err(e) => warn!("Couldn't keep {path} open, it will be reopened at each read: {e}"),
+        }
+    }
+
+    /// Reads the counter kept under `key`, or the file at `path` if it isn't open.
+    pub fn read(&self, key: &str, path: &str) -> io::Result<String> {
+        let value = match self.files.get(key) {
+            Some(counter) => counter.read_value(),
+            None => fs::read_to_string(path),
+        };
+        if let Ok(value) = &value {
+            raw_reads::record(path, value);
+        }
+        value
+    }
+
+    /// Reads the register at `address` of the msr device kept under `key`, or
+    /// opened from `path` if it isn't open.
+    #[cfg(unix)]
+    pub fn read_register(&self, key: &str, path: &str, address: u64) -> io::Result<u64> {
+        let value = match self.files.get(key) {
+            Some(device) => device.read_u64_at(address),
+            None => CounterFile::open(path)?.read_u64_at(address),
+        };
+        if let Ok(value) = &value {
+            raw_reads::record(&format!("{path}:{address:#x}"), &value.to_string());
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn read_counter_again() {
+        let path = std::env::temp_dir().join(format!(
+            "scaphandre-test-counter-file-{}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "123456\n").unwrap();
+        let counter = CounterFile::open(&path).unwrap();
+        assert_eq!(counter.read_value().unwrap(), "123456\n");
+        // rewritten in place, as sysfs does
+        fs::write(&path, "123789\n").unwrap();
+        assert_eq!(counter.read_value().unwrap(), "123789\n");
+        fs::write(&path, "1".repeat(COUNTER_BUFFER_SIZE)).unwrap();
+        assert!(counter.read_value().is_err());
+        fs::remove_file(&path).unwrap();
+
+        let mut files = CounterFiles::default();
+        files.open("source_file", "/nonexistent/energy_uj");
+        assert!(files.read("source_file", &path).is_err());
+
+        assert_eq!("pread".parse::<ReadMode>(), Ok(ReadMode::Pread));
+        assert!("netlink".parse::<ReadMode>().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
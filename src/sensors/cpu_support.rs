@@ -0,0 +1,223 @@
+//! # CPU support: is this CPU known to work with scaphandre ?
+//!
+//! Whether energy metrics are available mostly depends on the CPU: its vendor,
+//! family and model tell if it has a RAPL interface, which domains it exposes
+//! and which kernel is needed to read it. [CpuSupport::detect] looks the CPU
+//! of the host up in [COMPATIBILITY], so that scaphandre can tell by itself
+//! why it doesn't publish energy metrics.
+
+/// Vendor, range of families, range of models, whether the CPUs are supported,
+/// and the reason, known issues or requirements. The first matching entry applies.
+pub type CompatibilityEntry = (&'static str, (u32, u32), (u32, u32), bool, &'static str);
+
+/// CPUs known to work, or not, with the RAPL sensors.
+pub const COMPATIBILITY: [CompatibilityEntry; 8] = [
+    (
+        "GenuineIntel",
+        (6, 6),
+        (0x2a, 0xff),
+        true,
+        "RAPL available since Sandy Bridge, the DRAM domain is mostly found on server models",
+    ),
+    (
+        "GenuineIntel",
+        (0, 15),
+        (0, 0xff),
+        false,
+        "Intel CPUs older than Sandy Bridge have no RAPL interface",
+    ),
+    (
+        "AuthenticAMD",
+        (0x17, 0x17),
+        (0, 0xff),
+        true,
+        "Zen and Zen 2 have RAPL PKG and core domains, read through powercap since Linux 5.11, but no DRAM domain",
+    ),
+    (
+        "AuthenticAMD",
+        (0x19, 0x19),
+        (0, 0xff),
+        true,
+        "Zen 3 and Zen 4 have RAPL PKG and core domains, read through powercap since Linux 5.11, but no DRAM domain",
+    ),
+    (
+        "AuthenticAMD",
+        (0x1a, 0x1a),
+        (0, 0xff),
+        true,
+        "Zen 5 has RAPL PKG and core domains, read through powercap on recent kernels only, but no DRAM domain",
+    ),
+    (
+        "AuthenticAMD",
+        (0, 0x16),
+        (0, 0xff),
+        false,
+        "AMD CPUs older than Zen have no RAPL interface",
+    ),
+    (
+        "HygonGenuine",
+        (0x18, 0x18),
+        (0, 0xff),
+        true,
+        "Hygon Dhyana has RAPL PKG and core domains, read through powercap on recent kernels",
+    ),
+    (
+        "",
+        (0, u32::MAX),
+        (0, u32::MAX),
+        false,
+        "no RAPL interface on this architecture, use a power meter (modbus or smartplug sensors)",
+    ),
+];
+
+/// The CPU of the host and whether it is known to be supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuSupport {
+    /// Vendor id, e.g. GenuineIntel, empty on CPUs which don't report it (ARM)
+    pub vendor: String,
+    pub family: u32,
+    pub model: u32,
+    /// Model name, as displayed by lscpu
+    pub model_name: String,
+    /// True if the CPU is listed as supported in [COMPATIBILITY]
+    pub supported: bool,
+    /// Why it is or isn't supported, and its known issues
+    pub reason: String,
+}
+
+impl CpuSupport {
+    /// Looks the CPU up in [COMPATIBILITY]. `hypervisor` tells the CPU is a
+    /// virtual one, whose RAPL counters are usually not exposed by the hypervisor.
+    pub fn new(
+        vendor: &str,
+        family: u32,
+        model: u32,
+        model_name: &str,
+        hypervisor: bool,
+    ) -> CpuSupport {
+        let entry = COMPATIBILITY.iter().find(|(v, families, models, _, _)| {
+            *v == vendor
+                && (families.0..=families.1).contains(&family)
+                && (models.0..=models.1).contains(&model)
+        });
+        let (supported, mut reason) = match entry {
+            Some((_, _, _, supported, reason)) => (*supported, String::from(*reason)),
+            None => (
+                false,
+                format!(
+                    "{vendor} family {family:#x} model {model:#x} is not in the compatibility table, please report whether it works"
+                ),
+            ),
+        };
+        if hypervisor {
+            reason.push_str(
+                "; running in a virtual machine, RAPL counters have to be exposed by the hypervisor (see --vm)",
+            );
+        }
+        CpuSupport {
+            vendor: String::from(vendor),
+            family,
+            model,
+            model_name: String::from(model_name),
+            supported,
+            reason,
+        }
+    }
+
+    /// Returns the support of the first CPU of the host, from /proc/cpuinfo.
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Option<CpuSupport> {
+        let cpuinfo = procfs::CpuInfo::new().ok()?;
+        let field = |name| cpuinfo.get_field(0, name).unwrap_or_default();
+        let number = |name| field(name).trim().parse::<u32>().unwrap_or_default();
+        Some(CpuSupport::new(
+            field("vendor_id"),
+            number("cpu family"),
+            number("model"),
+            field("model name"),
+            field("flags").split_whitespace().any(|f| f == "hypervisor"),
+        ))
+    }
+
+    /// Returns the support of the CPU of the host, from CPUID.
+    #[cfg(target_os = "windows")]
+    pub fn detect() -> Option<CpuSupport> {
+        let cpuid = raw_cpuid::CpuId::new();
+        let vendor = cpuid.get_vendor_info()?;
+        let features = cpuid.get_feature_info()?;
+        let model_name = cpuid
+            .get_processor_brand_string()
+            .map(|brand| String::from(brand.as_str().trim()))
+            .unwrap_or_default();
+        Some(CpuSupport::new(
+            vendor.as_str(),
+            features.family_id() as u32,
+            features.model_id() as u32,
+            &model_name,
+            features.has_hypervisor(),
+        ))
+    }
+
+    /// RAPL support isn't known on other operating systems, their sensors
+    /// don't rely on it.
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    pub fn detect() -> Option<CpuSupport> {
+        None
+    }
+
+    /// Tells if the CPU is an AMD one (or a Hygon one, made under AMD
+    /// license), whose RAPL counters are at other addresses than Intel's.
+    pub fn is_amd(&self) -> bool {
+        self.vendor == "AuthenticAMD" || self.vendor == "HygonGenuine"
+    }
+
+    /// Logs whether the CPU is supported, as a warning if it isn't.
+    pub fn log(&self) {
+        if self.supported {
+            info!(
+                "CPU {} ({} family {:#x} model {:#x}) is supported: {}",
+                self.model_name, self.vendor, self.family, self.model, self.reason
+            );
+        } else {
+            warn!(
+                "CPU {} ({} family {:#x} model {:#x}) may not be supported: {}",
+                self.model_name, self.vendor, self.family, self.model, self.reason
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_compatibility() {
+        let skylake = CpuSupport::new("GenuineIntel", 6, 0x55, "Xeon Gold", false);
+        assert!(skylake.supported);
+        let nehalem = CpuSupport::new("GenuineIntel", 6, 0x1a, "Core i7", false);
+        assert!(!nehalem.supported);
+        assert!(nehalem.reason.contains("Sandy Bridge"));
+        let zen3 = CpuSupport::new("AuthenticAMD", 0x19, 0x01, "EPYC", true);
+        assert!(zen3.supported);
+        assert!(zen3.reason.contains("5.11") && zen3.reason.contains("virtual machine"));
+        assert!(!CpuSupport::new("", 0, 0, "", false).supported);
+        let unknown = CpuSupport::new("CentaurHauls", 7, 0x1b, "", false);
+        assert!(!unknown.supported);
+        assert!(unknown.reason.contains("not in the compatibility table"));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
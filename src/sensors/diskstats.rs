@@ -0,0 +1,168 @@
+//! # Diskstats: I/O activity of the disks of the host
+//!
+//! The capacity metrics of the disks, from sysinfo, don't tell whether a disk
+//! is busy. `/proc/diskstats` has one line of cumulated counters per block
+//! device:
+//!
+//! ```text
+//!  259       0 nvme0n1 81253 21340 7062512 21562 143220 90151 9913834 171410 0 139620 206710 ...
+//! ```
+//!
+//! the fields after the name being the reads completed, reads merged, sectors
+//! read, time spent reading, then the same for writes. Sectors are always 512
+//! bytes there, whatever the sector size of the device. Only whole disks
+//! (those in `/sys/block`) are kept, partitions being counted in their disk,
+//! as well as loop and ram devices, which don't reach any hardware.
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const DISKSTATS: &str = "/proc/diskstats";
+const BLOCK_DEVICES: &str = "/sys/block";
+const SECTOR_SIZE: u64 = 512;
+
+/// Cumulated I/O counters of a disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskStat {
+    /// Name of the device (sda, nvme0n1...)
+    pub name: String,
+    pub reads: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub written_bytes: u64,
+}
+
+/// I/O of a disk between two reads of its counters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskIo {
+    pub name: String,
+    /// Bytes read and written over the interval
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    /// Read and write operations per second over the interval
+    pub read_iops: f64,
+    pub write_iops: f64,
+    pub interval: Duration,
+}
+
+/// Parses the content of `/proc/diskstats`, keeping the devices for which
+/// `keep` returns true. Malformed lines are skipped.
+pub fn parse_diskstats(content: &str, keep: impl Fn(&str) -> bool) -> Vec<DiskStat> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let name = *fields.get(2)?;
+            if !keep(name) {
+                return None;
+            }
+            let field = |i: usize| fields.get(i)?.parse::<u64>().ok();
+            Some(DiskStat {
+                name: String::from(name),
+                reads: field(3)?,
+                read_bytes: field(5)? * SECTOR_SIZE,
+                writes: field(7)?,
+                written_bytes: field(9)? * SECTOR_SIZE,
+            })
+        })
+        .collect()
+}
+
+/// Tells if `name` is a whole disk backed by hardware.
+fn is_disk(name: &str) -> bool {
+    !name.starts_with("loop")
+        && !name.starts_with("ram")
+        && !name.starts_with("zram")
+        && Path::new(BLOCK_DEVICES).join(name).exists()
+}
+
+/// Reads the I/O counters of the disks of the host. Returns an empty vector
+/// when `/proc/diskstats` can't be read.
+pub fn read_diskstats() -> Vec<DiskStat> {
+    match fs::read_to_string(DISKSTATS) {
+        Ok(content) => parse_diskstats(&content, is_disk),
+        Err(e) => {
+            debug!("Couldn't read {DISKSTATS}: {e}");
+            vec![]
+        }
+    }
+}
+
+/// Returns the I/O of each disk of `current` between `previous` and
+/// `current`, read `interval` apart. Disks missing from `previous`, or whose
+/// counters went backwards (device removed and added again), are skipped.
+pub fn disks_io(previous: &[DiskStat], current: &[DiskStat], interval: Duration) -> Vec<DiskIo> {
+    let seconds = interval.as_secs_f64();
+    if seconds <= 0.0 {
+        return vec![];
+    }
+    current
+        .iter()
+        .filter_map(|disk| {
+            let before = previous.iter().find(|p| p.name == disk.name)?;
+            let reads = disk.reads.checked_sub(before.reads)?;
+            let writes = disk.writes.checked_sub(before.writes)?;
+            Some(DiskIo {
+                name: disk.name.clone(),
+                read_bytes: disk.read_bytes.checked_sub(before.read_bytes)?,
+                written_bytes: disk.written_bytes.checked_sub(before.written_bytes)?,
+                read_iops: reads as f64 / seconds,
+                write_iops: writes as f64 / seconds,
+                interval,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_io_between_reads() {
+        let before = "   7       0 loop0 10 0 80 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n\
+             259       0 nvme0n1 1000 0 2000 0 500 0 4000 0 0 0 0\n\
+             259       1 nvme0n1p1 900 0 1800 0 500 0 4000 0 0 0 0\n\
+             8       0 sda 10 0\n";
+        let after = " 259       0 nvme0n1 1100 0 2400 0 700 0 6000 0 0 0 0\n";
+        let keep = |name: &str| name.starts_with("nvme") && !name.contains('p');
+        let previous = parse_diskstats(before, keep);
+        assert_eq!(
+            previous,
+            vec![DiskStat {
+                name: String::from("nvme0n1"),
+                reads: 1000,
+                read_bytes: 1_024_000,
+                writes: 500,
+                written_bytes: 2_048_000,
+            }]
+        );
+        assert_eq!(parse_diskstats(before, |_| true).len(), 3);
+
+        let current = parse_diskstats(after, keep);
+        let io = disks_io(&previous, &current, Duration::from_secs(10));
+        assert_eq!(io.len(), 1);
+        assert_eq!(io[0].read_bytes, 204_800);
+        assert_eq!(io[0].written_bytes, 1_024_000);
+        assert_eq!(io[0].read_iops, 10.0);
+        assert_eq!(io[0].write_iops, 20.0);
+
+        // a device added again starts over
+        assert!(disks_io(&current, &previous, Duration::from_secs(10)).is_empty());
+        assert!(disks_io(&previous, &current, Duration::ZERO).is_empty());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,284 @@
+//! # Hypervisor sensors: power of the virtual machine given by Hyper-V or VMware
+//!
+//! On Qemu/KVM, the qemu exporter of the hypervisor writes the energy of each
+//! virtual machine to files that the guest reads with `--vm`. On Hyper-V and
+//! VMware the guest can't share a folder with the host that easily, so a
+//! companion on the host publishes the power of each virtual machine through
+//! the channel the hypervisor already has to send data to its guests:
+//!
+//! - Hyper-V: key-value pairs of the KVP exchange, read in the pool of external
+//!   items (`/var/lib/hyperv/.kvp_pool_0`, written by `hv_kvp_daemon`, on Linux,
+//!   `HKLM\SOFTWARE\Microsoft\Virtual Machine\External` on Windows),
+//! - VMware: `guestinfo` variables of the virtual machine, read with the
+//!   `rpctool` of VMware Tools.
+//!
+//! The companion publishes, under the names of [VALUE_NAMES]:
+//!
+//! ```text
+//! power_uw    power of the virtual machine, in microwatts
+//! energy_uj   energy consumed by the virtual machine, in microjoules (optional)
+//! timestamp   time of the measurement, in seconds since the epoch (optional)
+//! ```
+//!
+//! prefixed with `scaphandre_` for Hyper-V (`scaphandre_power_uw`) and with
+//! `guestinfo.scaphandre.` for VMware (`guestinfo.scaphandre.power_uw`).
+//! The energy counter is used when it is published, otherwise it is computed
+//! from the power.
+use crate::sensors::units::Unit;
+use crate::sensors::utils::{accumulate_power_as_energy, current_system_time_since_epoch};
+use crate::sensors::{Record, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+/// Key of the topology sensor data holding the name of the hypervisor.
+pub const SENSOR_DATA_KEY: &str = "hypervisor";
+
+/// Names of the values published by the companion on the host.
+pub const VALUE_NAMES: [&str; 3] = ["power_uw", "energy_uj", "timestamp"];
+
+/// Age after which a value published by the companion is considered stale,
+/// meaning the companion doesn't run anymore.
+pub const MAX_VALUE_AGE: Duration = Duration::from_secs(300);
+
+/// Pool of the KVP items set by the Hyper-V host, as written by hv_kvp_daemon.
+pub const KVP_EXTERNAL_POOL: &str = "/var/lib/hyperv/.kvp_pool_0";
+
+/// Sizes of the key and of the value of a record of a KVP pool file.
+const KVP_KEY_SIZE: usize = 512;
+const KVP_VALUE_SIZE: usize = 2048;
+
+/// Hypervisors publishing the power of their guests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hypervisor {
+    HyperV,
+    Vmware,
+}
+
+impl Hypervisor {
+    /// Returns the hypervisor named `name` (`hyperv` or `vmware`).
+    pub fn from_name(name: &str) -> Option<Hypervisor> {
+        match name {
+            "hyperv" => Some(Hypervisor::HyperV),
+            "vmware" => Some(Hypervisor::Vmware),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hypervisor::HyperV => "hyperv",
+            Hypervisor::Vmware => "vmware",
+        }
+    }
+
+    /// Returns the key the value `name` is published under.
+    pub fn key(&self, name: &str) -> String {
+        match self {
+            Hypervisor::HyperV => format!("scaphandre_{name}"),
+            Hypervisor::Vmware => format!("guestinfo.scaphandre.{name}"),
+        }
+    }
+
+    /// Reads the value `name` published by the companion, None if it isn't.
+    pub fn read(&self, name: &str) -> Result<Option<String>, String> {
+        let key = self.key(name);
+        match self {
+            Hypervisor::HyperV => read_kvp(&key),
+            Hypervisor::Vmware => read_guestinfo(&key),
+        }
+    }
+}
+
+/// Parses the content of a KVP pool file: records of a key and a value,
+/// both NUL padded.
+pub fn parse_kvp_pool(content: &[u8]) -> HashMap<String, String> {
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).to_string()
+    };
+    content
+        .chunks_exact(KVP_KEY_SIZE + KVP_VALUE_SIZE)
+        .map(|record| {
+            (
+                field(&record[..KVP_KEY_SIZE]),
+                field(&record[KVP_KEY_SIZE..]),
+            )
+        })
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn read_kvp(key: &str) -> Result<Option<String>, String> {
+    let content = std::fs::read(KVP_EXTERNAL_POOL).map_err(|e| {
+        format!("couldn't read {KVP_EXTERNAL_POOL}: {e}, is hv_kvp_daemon running ?")
+    })?;
+    Ok(parse_kvp_pool(&content).remove(key))
+}
+
+#[cfg(target_os = "windows")]
+fn read_kvp(key: &str) -> Result<Option<String>, String> {
+    let output = std::process::Command::new("reg")
+        .args([
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Virtual Machine\External",
+            "/v",
+            key,
+        ])
+        .output()
+        .map_err(|e| format!("couldn't run reg query: {e}"))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    // "    scaphandre_power_uw    REG_SZ    12345000"
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.trim_start().starts_with(key))
+        .and_then(|line| line.split_whitespace().nth(2))
+        .map(String::from))
+}
+
+#[cfg(not(target_os = "windows"))]
+const RPCTOOL: &str = "vmware-rpctool";
+#[cfg(target_os = "windows")]
+const RPCTOOL: &str = r"C:\Program Files\VMware\VMware Tools\rpctool.exe";
+
+fn read_guestinfo(key: &str) -> Result<Option<String>, String> {
+    let output = std::process::Command::new(RPCTOOL)
+        .arg(format!("info-get {key}"))
+        .output()
+        .map_err(|e| format!("couldn't run {RPCTOOL}: {e}, are VMware Tools installed ?"))?;
+    // rpctool fails with "No value found" for variables that aren't set
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(if value.is_empty() { None } else { Some(value) })
+}
+
+/// Returns a new energy Record for a topology whose sensor is a hypervisor.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    let hypervisor = topology
+        ._sensor_data
+        .get(SENSOR_DATA_KEY)
+        .and_then(|name| Hypervisor::from_name(name))
+        .ok_or("no hypervisor in topology sensor data")?;
+    if let Some(timestamp) = hypervisor.read("timestamp")? {
+        let published = Duration::from_secs(timestamp.trim().parse::<u64>()?);
+        let age = current_system_time_since_epoch().saturating_sub(published);
+        if age > MAX_VALUE_AGE {
+            return Err(format!(
+                "the power published by the {} host is {}s old, is its companion running ?",
+                hypervisor.name(),
+                age.as_secs()
+            )
+            .into());
+        }
+    }
+    if let Some(energy) = hypervisor.read("energy_uj")? {
+        return Ok(Record::new(
+            current_system_time_since_epoch(),
+            energy.trim().parse::<u64>()?.to_string(),
+            Unit::MicroJoule,
+        ));
+    }
+    let power = hypervisor.read("power_uw")?.ok_or_else(|| {
+        format!(
+            "{} isn't published by the {} host",
+            hypervisor.key("power_uw"),
+            hypervisor.name()
+        )
+    })?;
+    let watts = power.trim().parse::<f64>()? / 1_000_000.0;
+    Ok(accumulate_power_as_energy(
+        topology.record_buffer.last(),
+        watts,
+    )?)
+}
+
+/// This is a Sensor type that reads the power of the virtual machine it runs
+/// in, as published by a companion on the Hyper-V or VMware host.
+pub struct HypervisorSensor {
+    hypervisor: Hypervisor,
+}
+
+impl HypervisorSensor {
+    /// Instantiates and returns an instance of HypervisorSensor.
+    pub fn new(hypervisor: Hypervisor) -> HypervisorSensor {
+        HypervisorSensor { hypervisor }
+    }
+}
+
+impl Sensor for HypervisorSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(
+            String::from(SENSOR_DATA_KEY),
+            String::from(self.hypervisor.name()),
+        );
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+
+    /// VMware guestinfo variables are read with rpctool, and KVP items with reg
+    /// on Windows.
+    fn runs_commands(&self) -> bool {
+        match self.hypervisor {
+            Hypervisor::Vmware => true,
+            Hypervisor::HyperV => cfg!(target_os = "windows"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kvp_pool() {
+        let mut content = vec![];
+        for (key, value) in [
+            ("scaphandre_power_uw", "12500000"),
+            ("scaphandre_timestamp", "1700000000"),
+        ] {
+            let mut record = vec![0; KVP_KEY_SIZE + KVP_VALUE_SIZE];
+            record[..key.len()].copy_from_slice(key.as_bytes());
+            record[KVP_KEY_SIZE..KVP_KEY_SIZE + value.len()].copy_from_slice(value.as_bytes());
+            content.append(&mut record);
+        }
+        // truncated record, while the daemon writes the file
+        content.extend_from_slice(b"scaphandre_energy_uj");
+        let pool = parse_kvp_pool(&content);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool["scaphandre_power_uw"], "12500000");
+        assert_eq!(Hypervisor::HyperV.key("power_uw"), "scaphandre_power_uw");
+        assert_eq!(
+            Hypervisor::Vmware.key("power_uw"),
+            "guestinfo.scaphandre.power_uw"
+        );
+        assert_eq!(Hypervisor::from_name("vmware"), Some(Hypervisor::Vmware));
+        assert_eq!(Hypervisor::from_name("xen"), None);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,404 @@
+//! # INA sensor: shunt power monitors of single-board computers
+//!
+//! Raspberry Pis and most single-board computers have no energy counter at
+//! all. A Texas Instruments INA219 or INA3221 power monitor on the supply of
+//! the board, or of some of its rails, measures the voltage across a shunt
+//! resistor and the bus voltage, which give the current and the power.
+//!
+//! The chip is read over I2C, through the `/dev/i2c-N` device of the i2c-dev
+//! module of Linux (`dtparam=i2c_arm=on` on a Raspberry Pi), with no kernel
+//! driver bound to it:
+//!
+//! - the INA219 has one channel, its shunt voltage in 10 µV steps and its
+//!   bus voltage in 4 mV steps,
+//! - the INA3221 has three, in 40 µV and 8 mV steps.
+//!
+//! The calibration registers are left alone: the current is computed from the
+//! shunt voltage and the value of the shunt, given on the command line. Each
+//! channel is a domain of a single socket, whose power is the sum of the
+//! channels, and so is the power of the host. The chips give an instant power,
+//! integrated between two measurements into energy counters.
+use crate::sensors::utils::accumulate_power_as_energy;
+use crate::sensors::{raw_reads, Record, RecordSource, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+/// I2C bus of the chip, the one of the GPIO header of a Raspberry Pi.
+pub const DEFAULT_BUS: u8 = 1;
+/// I2C address of the chip, with its address pins to the ground.
+pub const DEFAULT_ADDRESS: &str = "0x40";
+/// Value of the shunt resistor of most INA219 and INA3221 boards, in ohms.
+pub const DEFAULT_SHUNT_OHMS: &str = "0.1";
+
+/// Key of the I2C device in the sensor data of the socket and domains.
+pub const SENSOR_DATA_DEVICE_KEY: &str = "ina_device";
+/// Key of the I2C address of the chip in the sensor data.
+pub const SENSOR_DATA_ADDRESS_KEY: &str = "ina_address";
+/// Key of the chip in the sensor data.
+pub const SENSOR_DATA_CHIP_KEY: &str = "ina_chip";
+/// Key of the channels read, as `channel:shunt ohms` comma separated, in the
+/// sensor data: all of them for the socket, one for each domain.
+pub const SENSOR_DATA_CHANNELS_KEY: &str = "ina_channels";
+
+/// Request of the i2c-dev module setting the address of the chip to talk to.
+const I2C_SLAVE: u64 = 0x0703;
+
+/// Power monitors the sensor can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Ina219,
+    Ina3221,
+}
+
+impl FromStr for Chip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ina219" => Ok(Chip::Ina219),
+            "ina3221" => Ok(Chip::Ina3221),
+            _ => Err(format!("unknown chip {s}, expected ina219 or ina3221")),
+        }
+    }
+}
+
+impl Chip {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chip::Ina219 => "ina219",
+            Chip::Ina3221 => "ina3221",
+        }
+    }
+
+    /// Number of channels of the chip, numbered from 1.
+    pub fn channels(&self) -> u8 {
+        match self {
+            Chip::Ina219 => 1,
+            Chip::Ina3221 => 3,
+        }
+    }
+
+    /// Returns the shunt and bus voltage registers of `channel`.
+    fn registers(&self, channel: u8) -> (u8, u8) {
+        match self {
+            Chip::Ina219 => (0x01, 0x02),
+            Chip::Ina3221 => (2 * channel - 1, 2 * channel),
+        }
+    }
+
+    /// Converts the values of the shunt and bus voltage registers of a channel
+    /// to volts.
+    pub fn volts(&self, shunt_register: u16, bus_register: u16) -> (f64, f64) {
+        match self {
+            // the bus voltage is in bits 15 to 3, bits 1 and 0 being flags
+            Chip::Ina219 => (
+                f64::from(shunt_register as i16) * 10e-6,
+                f64::from(bus_register >> 3) * 4e-3,
+            ),
+            Chip::Ina3221 => (
+                f64::from((shunt_register as i16) >> 3) * 40e-6,
+                f64::from((bus_register as i16) >> 3) * 8e-3,
+            ),
+        }
+    }
+}
+
+/// A channel of the chip, and the value of its shunt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    /// Number of the channel, from 1
+    pub number: u8,
+    /// Name of the domain of the channel
+    pub name: String,
+    pub shunt_ohms: f64,
+}
+
+/// Returns the channels of `chip` to read: one per value of `shunts_ohms`
+/// (comma separated), or all of them with the same shunt if it has a single
+/// value, named after `names` (comma separated) or `channel1`, `channel2`...
+pub fn parse_channels(
+    chip: Chip,
+    shunts_ohms: &str,
+    names: Option<&str>,
+) -> Result<Vec<Channel>, String> {
+    let mut shunts = shunts_ohms
+        .split(',')
+        .map(|ohms| match ohms.trim().parse::<f64>() {
+            Ok(ohms) if ohms > 0.0 => Ok(ohms),
+            _ => Err(format!("invalid shunt value {ohms:?}, expected ohms")),
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+    if shunts.len() == 1 {
+        shunts = vec![shunts[0]; usize::from(chip.channels())];
+    }
+    if shunts.len() > usize::from(chip.channels()) {
+        return Err(format!(
+            "{} shunt values given, the {} has {} channels",
+            shunts.len(),
+            chip.name(),
+            chip.channels()
+        ));
+    }
+    let names: Vec<&str> = names.map_or(vec![], |names| names.split(',').collect());
+    let mut channels = vec![];
+    for (number, shunt_ohms) in (1..).zip(shunts) {
+        let name = match names.get(usize::from(number) - 1) {
+            Some(name) => String::from(name.trim()),
+            None => format!("channel{number}"),
+        };
+        // the host power is the sum of the sockets and of their dram domains
+        if name == "dram" {
+            return Err(String::from("a channel can't be named dram"));
+        }
+        channels.push(Channel {
+            number,
+            name,
+            shunt_ohms,
+        });
+    }
+    Ok(channels)
+}
+
+/// Parses an I2C address, in hexadecimal (`0x40`) or decimal.
+pub fn parse_address(address: &str) -> Result<u16, String> {
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("invalid I2C address {address}"))
+}
+
+/// A chip on an I2C bus, opened through i2c-dev.
+struct I2cDevice {
+    file: File,
+    /// `<device>:<address>`, to name the raw reads
+    name: String,
+}
+
+impl I2cDevice {
+    fn open(device: &str, address: u16) -> Result<I2cDevice, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device)
+            .map_err(|e| {
+                format!(
+                    "couldn't open {device} ({e}), is the i2c-dev module loaded and the user \
+                    of scaphandre in the i2c group?"
+                )
+            })?;
+        // SAFETY: I2C_SLAVE only reads its integer argument, the descriptor is
+        // open until file is dropped.
+        if unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                I2C_SLAVE as _,
+                libc::c_ulong::from(address),
+            )
+        } < 0
+        {
+            return Err(format!(
+                "couldn't talk to the chip at {address:#x} on {device} ({}), \
+                it may be bound to a kernel driver (ina2xx, ina3221)",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(I2cDevice {
+            file,
+            name: format!("{device}:{address:#x}"),
+        })
+    }
+
+    /// Reads a 16 bits register, most significant byte first.
+    fn read_register(&self, register: u8) -> io::Result<u16> {
+        (&self.file).write_all(&[register])?;
+        let mut buffer = [0_u8; 2];
+        (&self.file).read_exact(&mut buffer)?;
+        let value = u16::from_be_bytes(buffer);
+        raw_reads::record(&format!("{}:{register:#x}", self.name), &value.to_string());
+        Ok(value)
+    }
+}
+
+/// Tells whether the sensor data of a socket or a domain is the one of INA
+/// channels.
+pub fn is_ina_counter(sensor_data: &HashMap<String, String>) -> bool {
+    sensor_data.contains_key(SENSOR_DATA_CHANNELS_KEY)
+}
+
+/// Reads the power of the channels in the sensor data of a socket or a domain,
+/// and returns the energy counter following `previous`.
+pub fn read_record(
+    sensor_data: &HashMap<String, String>,
+    previous: Option<&Record>,
+) -> Result<Record, Box<dyn Error>> {
+    let field = |key: &str| {
+        sensor_data
+            .get(key)
+            .ok_or_else(|| format!("no {key} in sensor data"))
+    };
+    let chip = field(SENSOR_DATA_CHIP_KEY)?.parse::<Chip>()?;
+    let device = I2cDevice::open(
+        field(SENSOR_DATA_DEVICE_KEY)?,
+        field(SENSOR_DATA_ADDRESS_KEY)?.parse()?,
+    )?;
+    let mut watts = 0.0;
+    for channel in field(SENSOR_DATA_CHANNELS_KEY)?.split(',') {
+        let (number, shunt_ohms) = channel
+            .split_once(':')
+            .ok_or_else(|| format!("invalid channel {channel}"))?;
+        let (shunt_register, bus_register) = chip.registers(number.parse()?);
+        let (shunt_volts, bus_volts) = chip.volts(
+            device.read_register(shunt_register)?,
+            device.read_register(bus_register)?,
+        );
+        // a shunt wired the other way round gives a negative voltage
+        watts += bus_volts * shunt_volts.abs() / shunt_ohms.parse::<f64>()?;
+    }
+    Ok(accumulate_power_as_energy(previous, watts)?.with_source(Some(RecordSource::Ina)))
+}
+
+/// This is a Sensor type that reads the power of a single-board computer from
+/// an INA219 or INA3221 power monitor.
+pub struct InaSensor {
+    chip: Chip,
+    device: String,
+    address: u16,
+    channels: Vec<Channel>,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl InaSensor {
+    /// Instantiates and returns an instance of InaSensor, reading `chip` at
+    /// `address` on I2C bus `bus`, with a shunt of `shunts_ohms` on each
+    /// channel (see [parse_channels]).
+    pub fn new(
+        chip: Chip,
+        bus: u8,
+        address: &str,
+        shunts_ohms: &str,
+        names: Option<&str>,
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+    ) -> Result<InaSensor, String> {
+        Ok(InaSensor {
+            chip,
+            device: format!("/dev/i2c-{bus}"),
+            address: parse_address(address)?,
+            channels: parse_channels(chip, shunts_ohms, names)?,
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        })
+    }
+
+    fn sensor_data(&self, channels: &[Channel]) -> HashMap<String, String> {
+        let channels = channels
+            .iter()
+            .map(|c| format!("{}:{}", c.number, c.shunt_ohms))
+            .collect::<Vec<_>>()
+            .join(",");
+        HashMap::from([
+            (String::from(SENSOR_DATA_DEVICE_KEY), self.device.clone()),
+            (
+                String::from(SENSOR_DATA_ADDRESS_KEY),
+                self.address.to_string(),
+            ),
+            (String::from(SENSOR_DATA_CHIP_KEY), self.chip.name().into()),
+            (String::from(SENSOR_DATA_CHANNELS_KEY), channels),
+        ])
+    }
+}
+
+impl Sensor for InaSensor {
+    /// Creates a Topology with a single socket, each channel being a domain.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let counter = format!("{}:{:#x}", self.device, self.address);
+        let socket_data = self.sensor_data(&self.channels);
+        // fails early on a wrong bus, address or permissions
+        read_record(&socket_data, None)?;
+        let mut topo = Topology::new(HashMap::new());
+        topo.safe_add_socket(
+            0,
+            vec![],
+            vec![],
+            counter.clone(),
+            self.buffer_per_socket_max_kbytes,
+            socket_data,
+        );
+        for (id, channel) in (0..).zip(&self.channels) {
+            topo.safe_add_domain_to_socket(
+                0,
+                id,
+                &channel.name,
+                &format!("{counter}:{}", channel.number),
+                self.buffer_per_domain_max_kbytes,
+                self.sensor_data(std::slice::from_ref(channel)),
+            );
+        }
+        topo.add_cpu_cores();
+        Ok(topo)
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        match self.generate_topology() {
+            Ok(topology) => Box::new(Some(topology)),
+            Err(e) => panic!("Couldn't generate the topology: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ina_conversions() {
+        // 5.12 V and 32 mV across 0.1 ohm: 0.32 A, 1.6384 W
+        let (shunt, bus) = Chip::Ina219.volts(3200, (1280 << 3) | 0b10);
+        assert!((shunt - 0.032).abs() < 1e-9 && (bus - 5.12).abs() < 1e-9);
+        let (shunt, bus) = Chip::Ina3221.volts(800 << 3, 640 << 3);
+        assert!((shunt - 0.032).abs() < 1e-9 && (bus - 5.12).abs() < 1e-9);
+        let (shunt, _) = Chip::Ina3221.volts((-800_i16 << 3) as u16, 0);
+        assert!((shunt + 0.032).abs() < 1e-9);
+        assert_eq!(Chip::Ina3221.registers(3), (0x05, 0x06));
+
+        assert_eq!(parse_address("0x41"), Ok(0x41));
+        assert_eq!(parse_address("64"), Ok(0x40));
+        assert!(parse_address("0xzz").is_err());
+
+        let channels = parse_channels(Chip::Ina3221, "0.1", Some("soc,usb")).unwrap();
+        assert_eq!(channels.len(), 3);
+        assert_eq!(channels[1].name, "usb");
+        assert_eq!(channels[2].name, "channel3");
+        let channels = parse_channels(Chip::Ina3221, "0.1,0.05", None).unwrap();
+        assert_eq!(channels[1].shunt_ohms, 0.05);
+        assert_eq!(channels.len(), 2);
+        assert!(parse_channels(Chip::Ina219, "0.1,0.1", None).is_err());
+        assert!(parse_channels(Chip::Ina219, "0", None).is_err());
+        assert!(parse_channels(Chip::Ina219, "0.1", Some("dram")).is_err());
+        assert!("ina226".parse::<Chip>().is_err());
+
+        let sensor = InaSensor::new(Chip::Ina219, 250, "0x40", "0.1", None, 1, 1).unwrap();
+        assert!(is_ina_counter(&sensor.sensor_data(&sensor.channels)));
+        assert!(sensor.generate_topology().is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,231 @@
+//! # IPMI sensor: wall power of the server measured by its BMC
+//!
+//! RAPL only covers the CPU packages and the memory, while the baseboard
+//! management controller (BMC) of most servers measures the power drawn by
+//! the power supplies, fans, disks and NICs included. It is read with the DCMI
+//! "Get Power Reading" command, through `ipmitool`:
+//!
+//! ```text
+//! $ ipmitool dcmi power reading
+//!     Instantaneous power reading:                   220 Watts
+//!     Minimum during sampling period:                 96 Watts
+//!     Maximum during sampling period:                364 Watts
+//!     Average power reading over sample period:      218 Watts
+//!     IPMI timestamp:                           Thu Jan  1 00:00:00 2020
+//!     Sampling period:                          00000001 Seconds.
+//!     Power reading state is:                   activated
+//! ```
+//!
+//! The local BMC is reached through the IPMI driver (`/dev/ipmi0`, from the
+//! `ipmi_devintf` and `ipmi_si` modules on Linux), a remote one over the
+//! network with `lanplus`. The instantaneous power is used, the energy being
+//! computed from it between two measurements. BMCs refresh their reading
+//! every second or so, with a precision of a watt.
+use crate::sensors::utils::accumulate_power_as_energy;
+use crate::sensors::{Record, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::process::Command;
+
+/// Key of the topology sensor data holding the BMC to read, `local` for the
+/// one of the host.
+pub const SENSOR_DATA_KEY: &str = "ipmi";
+/// Keys of the topology sensor data holding the credentials of a remote BMC.
+pub const SENSOR_DATA_USER_KEY: &str = "ipmi_user";
+pub const SENSOR_DATA_PASSWORD_FILE_KEY: &str = "ipmi_password_file";
+
+const LOCAL_BMC: &str = "local";
+const IPMITOOL: &str = "ipmitool";
+
+/// A BMC, local or reached over the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bmc {
+    /// Host of a remote BMC, None for the local one
+    pub host: Option<String>,
+    pub user: Option<String>,
+    /// File holding the password of the user, read by ipmitool
+    pub password_file: Option<String>,
+}
+
+impl Bmc {
+    /// Returns the arguments of ipmitool to read the power from this BMC.
+    pub fn ipmitool_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(host) = &self.host {
+            args.extend([String::from("-I"), String::from("lanplus")]);
+            args.extend([String::from("-H"), host.clone()]);
+            if let Some(user) = &self.user {
+                args.extend([String::from("-U"), user.clone()]);
+            }
+            if let Some(password_file) = &self.password_file {
+                args.extend([String::from("-f"), password_file.clone()]);
+            }
+        }
+        args.extend(["dcmi", "power", "reading"].map(String::from));
+        args
+    }
+
+    /// Queries the BMC and returns the instantaneous power it measures, in watts.
+    pub fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+        let output = Command::new(IPMITOOL)
+            .args(self.ipmitool_args())
+            .output()
+            .map_err(|e| format!("couldn't run {IPMITOOL}: {e}, is it installed ?"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{IPMITOOL} dcmi power reading failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        parse_power_reading(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn from_sensor_data(sensor_data: &HashMap<String, String>) -> Option<Bmc> {
+        let bmc = sensor_data.get(SENSOR_DATA_KEY)?;
+        Some(Bmc {
+            host: (bmc != LOCAL_BMC).then(|| bmc.clone()),
+            user: sensor_data.get(SENSOR_DATA_USER_KEY).cloned(),
+            password_file: sensor_data.get(SENSOR_DATA_PASSWORD_FILE_KEY).cloned(),
+        })
+    }
+}
+
+/// Extracts the instantaneous power, in watts, from the output of
+/// `ipmitool dcmi power reading`.
+pub fn parse_power_reading(output: &str) -> Result<f64, Box<dyn Error>> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name))
+            .map(|value| value.trim_start_matches(':').trim())
+    };
+    if field("Power reading state is").is_some_and(|state| state != "activated") {
+        return Err("power reading is deactivated on the BMC".into());
+    }
+    let reading = field("Instantaneous power reading")
+        .ok_or("no instantaneous power reading in the output of ipmitool")?;
+    let watts = reading
+        .strip_suffix("Watts")
+        .ok_or_else(|| format!("unexpected power reading: {reading}"))?
+        .trim()
+        .parse::<f64>()?;
+    Ok(watts)
+}
+
+/// Returns a new energy Record for a topology whose sensor is a BMC.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    let bmc =
+        Bmc::from_sensor_data(&topology._sensor_data).ok_or("no BMC in topology sensor data")?;
+    let watts = bmc.read_power_watts()?;
+    Ok(accumulate_power_as_energy(
+        topology.record_buffer.last(),
+        watts,
+    )?)
+}
+
+/// This is a Sensor type that reads the wall power of the server from its BMC.
+pub struct IpmiSensor {
+    bmc: Bmc,
+}
+
+impl IpmiSensor {
+    /// Instantiates and returns an instance of IpmiSensor, reading the local
+    /// BMC if `host` is None.
+    pub fn new(host: Option<&str>, user: Option<&str>, password_file: Option<&str>) -> IpmiSensor {
+        IpmiSensor {
+            bmc: Bmc {
+                host: host.map(String::from),
+                user: user.map(String::from),
+                password_file: password_file.map(String::from),
+            },
+        }
+    }
+}
+
+impl Sensor for IpmiSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(
+            String::from(SENSOR_DATA_KEY),
+            self.bmc
+                .host
+                .clone()
+                .unwrap_or_else(|| String::from(LOCAL_BMC)),
+        );
+        if let Some(user) = &self.bmc.user {
+            sensor_data.insert(String::from(SENSOR_DATA_USER_KEY), user.clone());
+        }
+        if let Some(password_file) = &self.bmc.password_file {
+            sensor_data.insert(
+                String::from(SENSOR_DATA_PASSWORD_FILE_KEY),
+                password_file.clone(),
+            );
+        }
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+
+    /// Measurements are read with ipmitool.
+    fn runs_commands(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dcmi_power_reading() {
+        let output = "\n    Instantaneous power reading:                   220 Watts\n\
+            Minimum during sampling period:                 96 Watts\n\
+            Average power reading over sample period:      218 Watts\n\
+            Sampling period:                          00000001 Seconds.\n\
+            Power reading state is:                   activated\n";
+        assert_eq!(parse_power_reading(output).unwrap(), 220.0);
+        assert!(parse_power_reading(&output.replace("activated", "deactivated")).is_err());
+        assert!(parse_power_reading("Instantaneous power reading: n/a\n").is_err());
+        assert!(parse_power_reading("").is_err());
+
+        let sensor = IpmiSensor::new(Some("10.0.0.5"), Some("admin"), Some("/etc/bmc.pass"));
+        let topology = sensor.generate_topology().unwrap();
+        assert_eq!(
+            Bmc::from_sensor_data(&topology._sensor_data)
+                .unwrap()
+                .ipmitool_args()
+                .join(" "),
+            "-I lanplus -H 10.0.0.5 -U admin -f /etc/bmc.pass dcmi power reading"
+        );
+        let local = IpmiSensor::new(None, None, None)
+            .generate_topology()
+            .unwrap();
+        assert_eq!(
+            Bmc::from_sensor_data(&local._sensor_data)
+                .unwrap()
+                .ipmitool_args(),
+            vec!["dcmi", "power", "reading"]
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
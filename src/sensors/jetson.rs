@@ -0,0 +1,342 @@
+//! # Jetson sensor: onboard power monitors of NVIDIA Jetson modules
+//!
+//! Jetson modules have no RAPL counter, but their carrier boards carry one or
+//! two INA3221 power monitors, bound to a kernel driver, on the rails of the
+//! module. This is what `tegrastats` reads:
+//!
+//! - up to L4T 32 (JetPack 4), the `ina3221x` IIO driver gives each channel
+//!   `N` of `/sys/bus/i2c/drivers/ina3221x/<bus>-<address>/iio:deviceM` a
+//!   `rail_name_N` and its power in milliwatts, `in_powerN_input`,
+//! - since L4T 34 (JetPack 5), the `ina3221` hwmon driver gives each channel
+//!   `N` of `/sys/bus/i2c/drivers/ina3221/<bus>-<address>/hwmon/hwmonM` an
+//!   `inN_label`, its voltage in millivolts, `inN_input`, and its current in
+//!   milliamperes, `currN_input`.
+//!
+//! The rails depend on the module: `POM_5V_IN`, `POM_5V_CPU` and `POM_5V_GPU`
+//! on a Nano, `VDD_IN`, `VDD_CPU_GPU_CV` and `VDD_SOC` on a Xavier NX or an
+//! Orin, `GPU`, `CPU`, `SOC`... on an AGX Xavier. Each rail is a domain of a
+//! single socket, named after the rail in lower case. The power of the socket
+//! is the one of the input rail of the module, as the other rails are parts
+//! of it, or the sum of the rails when there is none. The monitors give an
+//! instant power, integrated between two measurements into energy counters.
+use crate::sensors::counter_file::CounterFiles;
+use crate::sensors::utils::{accumulate_power_as_energy, read_trimmed};
+use crate::sensors::{Record, RecordSource, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Folder of the drivers of the I2C devices of the host.
+pub const DRIVERS_PATH: &str = "/sys/bus/i2c/drivers";
+
+/// Key of the power files of the rails, comma separated, in the sensor data:
+/// all of them for the socket, one for each domain. See [Rail::spec].
+pub const SENSOR_DATA_RAILS_KEY: &str = "jetson_rails";
+
+/// How the power of a rail is read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RailPower {
+    /// File of the power, in milliwatts (ina3221x)
+    Milliwatts(String),
+    /// Files of the voltage, in millivolts, and of the current, in
+    /// milliamperes (ina3221)
+    VoltsAmps(String, String),
+}
+
+/// A rail measured by a channel of an INA3221.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rail {
+    /// Name of the rail given by the driver, such as VDD_IN
+    pub name: String,
+    pub power: RailPower,
+}
+
+impl Rail {
+    /// Returns the files to read in the sensor data: the power file, or the
+    /// voltage and current files separated by `*`.
+    pub fn spec(&self) -> String {
+        match &self.power {
+            RailPower::Milliwatts(power) => power.clone(),
+            RailPower::VoltsAmps(voltage, current) => format!("{voltage}*{current}"),
+        }
+    }
+
+    /// Tells whether the rail is the input of the module, the other ones
+    /// being parts of it.
+    pub fn is_input(&self) -> bool {
+        self.name.to_uppercase().ends_with("_IN")
+    }
+
+    /// Name of the domain of the rail.
+    pub fn domain_name(&self) -> String {
+        self.name.to_lowercase()
+    }
+}
+
+/// Returns the rails of the INA3221 of the host, read from the drivers in
+/// `drivers_path`, sorted by device and channel.
+pub fn find_rails(drivers_path: &str) -> Vec<Rail> {
+    let base = Path::new(drivers_path);
+    let mut rails = vec![];
+    for device in sorted_entries(&base.join("ina3221x")) {
+        for iio in sorted_entries(&device) {
+            if !file_name(&iio).starts_with("iio:device") {
+                continue;
+            }
+            for channel in 0..3 {
+                let power = iio.join(format!("in_power{channel}_input"));
+                if let Some(name) = read_trimmed(&iio.join(format!("rail_name_{channel}"))) {
+                    if power.exists() {
+                        rails.push(Rail {
+                            name,
+                            power: RailPower::Milliwatts(path_string(&power)),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    for device in sorted_entries(&base.join("ina3221")) {
+        for hwmon in sorted_entries(&device.join("hwmon")) {
+            for channel in 1..=3 {
+                let voltage = hwmon.join(format!("in{channel}_input"));
+                let current = hwmon.join(format!("curr{channel}_input"));
+                // channels with nothing wired have no label
+                if let Some(name) = read_trimmed(&hwmon.join(format!("in{channel}_label"))) {
+                    if voltage.exists() && current.exists() {
+                        rails.push(Rail {
+                            name,
+                            power: RailPower::VoltsAmps(
+                                path_string(&voltage),
+                                path_string(&current),
+                            ),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    rails
+}
+
+/// Tells whether the sensor data of a socket or a domain is the one of
+/// Jetson rails.
+pub fn is_jetson_counter(sensor_data: &HashMap<String, String>) -> bool {
+    sensor_data.contains_key(SENSOR_DATA_RAILS_KEY)
+}
+
+/// Reads the power of the rails in the sensor data of a socket or a domain,
+/// and returns the energy counter following `previous`.
+pub fn read_record(
+    sensor_data: &HashMap<String, String>,
+    counter_files: &CounterFiles,
+    previous: Option<&Record>,
+) -> Result<Record, Box<dyn Error>> {
+    let rails = sensor_data
+        .get(SENSOR_DATA_RAILS_KEY)
+        .ok_or("no rail in sensor data")?;
+    let read = |path: &str| -> Result<f64, Box<dyn Error>> {
+        Ok(counter_files.read(path, path)?.trim().parse::<f64>()?)
+    };
+    let mut watts = 0.0;
+    for rail in rails.split(',') {
+        watts += match rail.split_once('*') {
+            Some((voltage, current)) => read(voltage)? * read(current)? / 1e6,
+            None => read(rail)? / 1e3,
+        };
+    }
+    Ok(accumulate_power_as_energy(previous, watts)?.with_source(Some(RecordSource::Jetson)))
+}
+
+/// This is a Sensor type that reads the power of an NVIDIA Jetson module from
+/// the INA3221 power monitors of its carrier board.
+pub struct JetsonSensor {
+    rails: Vec<Rail>,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl JetsonSensor {
+    /// Instantiates and returns an instance of JetsonSensor, reading the rails
+    /// found in `drivers_path`.
+    pub fn new(
+        drivers_path: &str,
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+    ) -> Result<JetsonSensor, String> {
+        let rails = find_rails(drivers_path);
+        if rails.is_empty() {
+            return Err(format!(
+                "no INA3221 rail in {drivers_path}/ina3221x or {drivers_path}/ina3221, \
+                is this an NVIDIA Jetson?"
+            ));
+        }
+        Ok(JetsonSensor {
+            rails,
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        })
+    }
+
+    fn sensor_data(rails: &[&Rail]) -> HashMap<String, String> {
+        let specs = rails.iter().map(|r| r.spec()).collect::<Vec<_>>();
+        HashMap::from([(String::from(SENSOR_DATA_RAILS_KEY), specs.join(","))])
+    }
+}
+
+impl Sensor for JetsonSensor {
+    /// Creates a Topology with a single socket, each rail being a domain.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let socket_rails: Vec<&Rail> = match self.rails.iter().find(|r| r.is_input()) {
+            Some(input) => vec![input],
+            None => self.rails.iter().collect(),
+        };
+        let socket_data = Self::sensor_data(&socket_rails);
+        // fails early on files readable by root only
+        read_record(&socket_data, &CounterFiles::default(), None)?;
+        let mut topo = Topology::new(HashMap::new());
+        topo.safe_add_socket(
+            0,
+            vec![],
+            vec![],
+            socket_data[SENSOR_DATA_RAILS_KEY].clone(),
+            self.buffer_per_socket_max_kbytes,
+            socket_data,
+        );
+        for (id, rail) in (0..).zip(self.rails.iter().filter(|r| !r.is_input())) {
+            topo.safe_add_domain_to_socket(
+                0,
+                id,
+                &rail.domain_name(),
+                &rail.spec(),
+                self.buffer_per_domain_max_kbytes,
+                Self::sensor_data(&[rail]),
+            );
+        }
+        topo.add_cpu_cores();
+        Ok(topo)
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        match self.generate_topology() {
+            Ok(topology) => Box::new(Some(topology)),
+            Err(e) => panic!("Couldn't generate the topology: {e}"),
+        }
+    }
+}
+
+fn sorted_entries(path: &Path) -> Vec<std::path::PathBuf> {
+    let mut entries: Vec<_> = fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|e| e.path()).collect())
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::utils::write_sysfs_attribute;
+
+    #[test]
+    fn jetson_rails() {
+        let base = std::env::temp_dir().join(format!("scaph-jetson-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        // a Nano, on the ina3221x driver
+        let iio = base.join("ina3221x/6-0040/iio:device0");
+        for (channel, name, milliwatts) in [
+            (0, "POM_5V_IN", 4000),
+            (1, "POM_5V_GPU", 500),
+            (2, "POM_5V_CPU", 1500),
+        ] {
+            write_sysfs_attribute(&iio, &format!("rail_name_{channel}"), name);
+            write_sysfs_attribute(
+                &iio,
+                &format!("in_power{channel}_input"),
+                &milliwatts.to_string(),
+            );
+        }
+        // an Orin, on the ina3221 driver, its third channel wired to nothing
+        let hwmon = base.join("ina3221/1-0040/hwmon/hwmon3");
+        for (channel, name, millivolts, milliamps) in [(1, "VDD_SOC", 5000, 400), (3, "", 5000, 0)]
+        {
+            write_sysfs_attribute(&hwmon, &format!("in{channel}_label"), name);
+            write_sysfs_attribute(
+                &hwmon,
+                &format!("in{channel}_input"),
+                &millivolts.to_string(),
+            );
+            write_sysfs_attribute(
+                &hwmon,
+                &format!("curr{channel}_input"),
+                &milliamps.to_string(),
+            );
+        }
+
+        let rails = find_rails(&base.to_string_lossy());
+        let names: Vec<&str> = rails.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["POM_5V_IN", "POM_5V_GPU", "POM_5V_CPU", "VDD_SOC"]);
+        assert!(rails[0].is_input() && !rails[3].is_input());
+        assert_eq!(rails[3].domain_name(), "vdd_soc");
+        assert!(matches!(rails[3].power, RailPower::VoltsAmps(..)));
+
+        let sensor_data = JetsonSensor::sensor_data(&rails.iter().skip(1).collect::<Vec<_>>());
+        assert!(is_jetson_counter(&sensor_data));
+        let first = read_record(&sensor_data, &CounterFiles::default(), None).unwrap();
+        assert_eq!(first.value, "0");
+        assert_eq!(first.source, Some(RecordSource::Jetson));
+        // 0.5 + 1.5 + 2 W during about 1 second
+        let previous = Record {
+            timestamp: first.timestamp - std::time::Duration::from_secs(1),
+            ..first
+        };
+        let record = read_record(&sensor_data, &CounterFiles::default(), Some(&previous)).unwrap();
+        let microjoules = record.value.parse::<u64>().unwrap();
+        assert!(
+            (4_000_000..4_100_000).contains(&microjoules),
+            "{microjoules}"
+        );
+
+        let sensor = JetsonSensor::new(&base.to_string_lossy(), 1, 1).unwrap();
+        let topo = sensor.generate_topology().unwrap();
+        let socket = &topo.get_sockets_passive()[0];
+        assert_eq!(
+            socket.sensor_data[SENSOR_DATA_RAILS_KEY],
+            format!("{}", iio.join("in_power0_input").display())
+        );
+        let domains: Vec<&str> = socket
+            .get_domains_passive()
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(domains, ["pom_5v_gpu", "pom_5v_cpu", "vdd_soc"]);
+
+        fs::remove_dir_all(&base).unwrap();
+        assert!(JetsonSensor::new(&base.to_string_lossy(), 1, 1).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,149 @@
+//! # LXC: running inside LXC and LXD system containers
+//!
+//! A system container shares the kernel of its host, so the RAPL counters are
+//! the host ones, but `/sys/class/powercap` is usually hidden from it (or its
+//! `energy_uj` files readable by the host root only). The host can pass the
+//! RAPL zones through, by bind-mounting each of them in a folder of the
+//! container, and scaphandre reads them from there with `--powercap-path`:
+//!
+//! ```text
+//! lxc config device add c1 intel-rapl-0 disk source=/sys/class/powercap/intel-rapl:0 path=/mnt/powercap/intel-rapl:0 readonly=true
+//! scaphandre --powercap-path /mnt/powercap prometheus
+//! ```
+//!
+//! LXC sets `container=lxc` in the environment of the init process of the
+//! container, which is how it is detected. Inside LXD, the name of the
+//! container is read from the meta-data of the `/dev/lxd/sock` guest API,
+//! and added to all metrics as the `lxc_container` attribute.
+use crate::sensors::powercap_rapl::PowercapRAPLSensor;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Socket of the LXD guest API, present when `security.devlxd` is enabled.
+pub const DEVLXD_SOCKET: &str = "/dev/lxd/sock";
+
+const INIT_ENVIRON: &str = "/proc/1/environ";
+const SYSTEMD_CONTAINER: &str = "/run/systemd/container";
+const DEVLXD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Returns true if the NUL separated `environ` of the init process sets `container=lxc`.
+pub fn is_lxc_environ(environ: &[u8]) -> bool {
+    environ
+        .split(|b| *b == 0)
+        .any(|var| var == b"container=lxc")
+}
+
+/// Returns true if scaphandre runs inside an LXC (or LXD) container.
+pub fn detect() -> bool {
+    if let Ok(environ) = fs::read(INIT_ENVIRON) {
+        return is_lxc_environ(&environ);
+    }
+    // the environment of init is only readable by root, systemd copies it here
+    fs::read_to_string(SYSTEMD_CONTAINER).is_ok_and(|c| c.trim() == "lxc")
+}
+
+/// Returns the name of the container from the meta-data of the LXD guest API.
+pub fn parse_meta_data(meta_data: &str) -> Option<String> {
+    meta_data.lines().find_map(|line| {
+        let name = line.strip_prefix("local-hostname:")?.trim();
+        (!name.is_empty()).then(|| name.to_string())
+    })
+}
+
+/// Returns the body of a successful HTTP/1.0 `response`.
+fn http_body(response: &str) -> Result<&str, String> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("truncated response")?;
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(body),
+        _ => Err(format!("unexpected response: {status}")),
+    }
+}
+
+/// Gets `uri` from the LXD guest API listening on `socket`.
+fn devlxd_get(socket: &Path, uri: &str) -> Result<String, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(DEVLXD_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    stream
+        .write_all(format!("GET {uri} HTTP/1.0\r\nHost: lxd\r\n\r\n").as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+    http_body(&response).map(String::from)
+}
+
+/// Returns the name of the LXD container scaphandre runs in, None outside of
+/// LXC or if the LXD guest API isn't available.
+pub fn container_name() -> Option<String> {
+    if !detect() {
+        return None;
+    }
+    match devlxd_get(Path::new(DEVLXD_SOCKET), "/1.0/meta-data") {
+        Ok(meta_data) => {
+            let name = parse_meta_data(&meta_data);
+            info!("Running in the LXD container {name:?}");
+            name
+        }
+        Err(e) => {
+            info!("Running in an LXC container, but couldn't query {DEVLXD_SOCKET}: {e}");
+            None
+        }
+    }
+}
+
+/// Warns when scaphandre runs in an LXC container while the powercap tree at
+/// `base_path` has no RAPL zones, which means it wasn't passed through.
+pub fn warn_if_no_passthrough(base_path: &str) {
+    if !PowercapRAPLSensor::has_rapl_zones(base_path) && detect() {
+        warn!(
+            "Running in an LXC container without RAPL zones in {base_path}: pass the powercap \
+            tree of the host through and use --powercap-path (see the LXC/LXD guide)"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lxc_detection_and_meta_data() {
+        assert!(is_lxc_environ(b"PATH=/bin\0container=lxc\0"));
+        assert!(!is_lxc_environ(b"container=docker\0"));
+        assert!(!is_lxc_environ(b"container=lxcfs\0"));
+
+        let meta_data = "#cloud-config\ninstance-id: 6a0e3e1c\nlocal-hostname: web-1\n";
+        assert_eq!(parse_meta_data(meta_data), Some(String::from("web-1")));
+        assert_eq!(parse_meta_data("instance-id: web-1\n"), None);
+
+        assert_eq!(
+            http_body("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nlocal-hostname: a\n"),
+            Ok("local-hostname: a\n")
+        );
+        assert!(http_body("HTTP/1.0 404 Not Found\r\n\r\n").is_err());
+        assert!(devlxd_get(Path::new("/nonexistent/sock"), "/1.0").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
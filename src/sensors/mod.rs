@@ -3,27 +3,149 @@
 //! `Sensor` is the root for all sensors. It defines the [Sensor] trait
 //! needed to implement a sensor.
 
+#[cfg(target_os = "linux")]
+pub mod amd_energy;
+#[cfg(target_os = "linux")]
+pub mod battery;
+pub mod counter_file;
+pub mod cpu_support;
+pub mod diskstats;
+pub mod hypervisor;
+#[cfg(target_os = "linux")]
+pub mod ina;
+pub mod ipmi;
+#[cfg(target_os = "linux")]
+pub mod jetson;
+#[cfg(target_os = "linux")]
+pub mod lxc;
+pub mod modbus;
+#[cfg(target_os = "linux")]
+pub mod msr_linux;
 #[cfg(target_os = "windows")]
 pub mod msr_rapl;
 #[cfg(target_os = "windows")]
 use msr_rapl::get_msr_value;
+pub mod netstats;
+#[cfg(target_os = "linux")]
+pub mod numa;
 #[cfg(target_os = "linux")]
 pub mod powercap_rapl;
+#[cfg(target_os = "macos")]
+pub mod powermetrics;
+pub mod pressure;
+pub mod raw_reads;
+#[cfg(target_os = "linux")]
+pub mod shared;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "smartplug")]
+pub mod smartplug;
+#[cfg(target_os = "linux")]
+pub mod uncore;
 pub mod units;
+pub mod update_period;
 pub mod utils;
+#[cfg(target_os = "windows")]
+pub mod windows_driver;
+#[cfg(target_os = "linux")]
+pub mod wsl;
+use counter_file::CounterFiles;
+use diskstats::{DiskIo, DiskStat};
+use netstats::{NetIo, NetStat};
+use pressure::Pressure;
 #[cfg(target_os = "linux")]
 use procfs::{CpuInfo, CpuTime, KernelStats};
-use std::{collections::HashMap, error::Error, fmt, fs, mem::size_of_val, str, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, fs,
+    mem::size_of_val,
+    str,
+    time::Duration,
+};
 #[allow(unused_imports)]
-use sysinfo::{CpuExt, Pid, System, SystemExt};
+use sysinfo::{ComponentExt, CpuExt, Pid, System, SystemExt};
 use sysinfo::{DiskExt, DiskType};
-use utils::{current_system_time_since_epoch, IProcess, ProcessTracker};
+use utils::{
+    current_system_time_since_epoch, IProcess, ProcessCpuSource, ProcessFilters, ProcessTracker,
+};
 
 // !!!!!!!!!!!!!!!!! Sensor !!!!!!!!!!!!!!!!!!!!!!!
 /// Sensor trait, the Sensor API.
 pub trait Sensor {
     fn get_topology(&self) -> Box<Option<Topology>>;
     fn generate_topology(&self) -> Result<Topology, Box<dyn Error>>;
+
+    /// Returns true if the sensor runs external commands to get its
+    /// measurements, which the seccomp profile must then allow.
+    fn runs_commands(&self) -> bool {
+        false
+    }
+}
+
+/// Wraps another sensor to apply process filters to the topologies it
+/// generates, so that filtered out processes are never tracked, and to choose
+/// where the CPU usage of the tracked ones comes from.
+pub struct FilteredSensor {
+    sensor: Box<dyn Sensor>,
+    filters: ProcessFilters,
+    cpu_source: ProcessCpuSource,
+}
+
+impl FilteredSensor {
+    pub fn new(sensor: Box<dyn Sensor>, filters: ProcessFilters) -> FilteredSensor {
+        FilteredSensor {
+            sensor,
+            filters,
+            cpu_source: ProcessCpuSource::default(),
+        }
+    }
+
+    /// Computes the CPU usage of the processes from `cpu_source`.
+    pub fn with_cpu_source(mut self, cpu_source: ProcessCpuSource) -> FilteredSensor {
+        self.cpu_source = cpu_source;
+        self
+    }
+
+    fn configure(&self, topology: &mut Topology) {
+        topology.proc_tracker.set_filters(self.filters.clone());
+        topology.proc_tracker.cpu_source = self.cpu_source;
+    }
+}
+
+impl Sensor for FilteredSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topology = self.sensor.generate_topology()?;
+        self.configure(&mut topology);
+        Ok(topology)
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let mut topology = self.sensor.get_topology();
+        if let Some(topology) = topology.as_mut() {
+            self.configure(topology);
+        }
+        topology
+    }
+
+    fn runs_commands(&self) -> bool {
+        self.sensor.runs_commands()
+    }
+}
+
+/// Sensor giving copies of a topology built beforehand, for tests.
+#[cfg(test)]
+pub(crate) struct StaticSensor(pub(crate) Topology);
+
+#[cfg(test)]
+impl Sensor for StaticSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        Ok(self.0.clone())
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        Box::new(Some(self.0.clone()))
+    }
 }
 
 /// Defines methods for Record instances creation
@@ -45,7 +167,9 @@ pub trait RecordReader {
 /// Owns a vector of CPUSocket structs representing each socket.
 #[derive(Debug, Clone)]
 pub struct Topology {
-    /// The CPU sockets found on the host, represented as CPUSocket instances attached to this topology
+    /// The CPU sockets found on the host, represented as CPUSocket instances attached to this topology.
+    /// It is empty when the host has no package energy counter (ARM SoCs, VMs without one, platform
+    /// meters), in which case only the records of the host are available
     pub sockets: Vec<CPUSocket>,
     /// ProcessTrack instance that keeps track of processes running on the host and CPU stats associated
     pub proc_tracker: ProcessTracker,
@@ -59,6 +183,112 @@ pub struct Topology {
     pub domains_names: Option<Vec<String>>,
     /// Sensor-specific data needed in the topology
     pub _sensor_data: HashMap<String, String>,
+    /// Energy counter files kept open between reads, if the sensor reads them that way.
+    pub counter_files: CounterFiles,
+    /// Stall information (PSI) of the cpu, io and memory at the last refresh
+    pub pressure: Vec<Pressure>,
+    /// RAPL PSYS (platform) zones found on the host, sorted by name
+    pub psys_zones: Vec<PsysZone>,
+    /// Memory controller counters, opened by sensors exposing a dram domain
+    #[cfg(target_os = "linux")]
+    pub memory_counters: uncore::MemoryCounters,
+    /// Bytes transferred from and to memory by each socket, by socket id, at the last refresh
+    pub memory_traffic: HashMap<u16, u64>,
+    /// I/O counters of the disks at the last refresh, and when they were read
+    pub disk_stats: Option<(Duration, Vec<DiskStat>)>,
+    /// I/O of each disk between the last two refreshes
+    pub disk_io: Vec<DiskIo>,
+    /// Counters of the network interfaces at the last refresh, and when they were read
+    pub net_stats: Option<(Duration, Vec<NetStat>)>,
+    /// Traffic of each network interface between the last two refreshes
+    pub net_io: Vec<NetIo>,
+    /// Time between two updates of the RAPL energy counters by the firmware,
+    /// if it was detected. Host measurements closer than that are skipped.
+    pub counter_update_period: Option<Duration>,
+    /// Degraded modes the sensor fell into while discovering the host, each
+    /// one once
+    pub warnings: Vec<TopologyWarning>,
+}
+
+/// A degraded mode the sensor fell into while discovering the topology of the
+/// host, published as `scaph_topology_warnings` so that hosts measured with
+/// less detail can be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyWarning {
+    /// None of the intel_rapl kernel modules is loaded
+    NoRaplModule,
+    /// Powercap has no domain folders, sockets are read from their package
+    /// folders and have no domain
+    SocketFallback,
+    /// Powercap has neither a package zone nor a PSYS zone
+    NoRaplPackage,
+    /// No socket has a dram domain, the energy of the memory isn't part of
+    /// the one of the host
+    NoDram,
+    /// CPU cores whose physical id matches no socket, as in virtual machines,
+    /// are counted in the first socket
+    CoreSocketMismatch,
+}
+
+impl TopologyWarning {
+    /// Returns the value of the `type` label of the metric.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TopologyWarning::NoRaplModule => "no_rapl_module",
+            TopologyWarning::SocketFallback => "socket_fallback",
+            TopologyWarning::NoRaplPackage => "no_rapl_package",
+            TopologyWarning::NoDram => "no_dram",
+            TopologyWarning::CoreSocketMismatch => "core_socket_mismatch",
+        }
+    }
+
+    /// Explains the warning, as logged.
+    pub fn message(&self) -> &'static str {
+        match self {
+            TopologyWarning::NoRaplModule => "Couldn't find intel_rapl modules.",
+            TopologyWarning::SocketFallback => {
+                "Couldn't find domain folders from powercap. Fallback on socket folders, \
+                Scaphandre will not be able to provide per-domain data."
+            }
+            TopologyWarning::NoRaplPackage => "Couldn't find any RAPL PKG domain (nor psys).",
+            TopologyWarning::NoDram => {
+                "No dram domain, the energy of the memory isn't part of the one of the host."
+            }
+            TopologyWarning::CoreSocketMismatch => {
+                "Some CPU cores have a physical id matching no socket (as in virtual machines), \
+                they are counted in the first socket."
+            }
+        }
+    }
+}
+
+/// A RAPL PSYS zone. Most hosts have at most one, but some multi-socket
+/// platforms expose one per package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsysZone {
+    /// Name of the zone: its powercap folder (`intel-rapl:1`), or `psys` when
+    /// read from the MSR on Windows
+    pub name: String,
+    /// Path to the powercap folder of the zone, empty on Windows
+    pub path: String,
+    /// State of the `enabled` attribute of the zone at the last refresh, None
+    /// if it has none
+    pub enabled: Option<bool>,
+    /// Value after which the energy counter of the zone wraps around to 0, in
+    /// microjoules, if known
+    pub max_energy_range_uj: Option<u64>,
+}
+
+impl PsysZone {
+    /// Key of the zone counter in [CounterFiles].
+    pub fn counter_key(&self) -> String {
+        format!("psys:{}", self.name)
+    }
+
+    /// Tells if the counter of the zone advances, i.e. the zone isn't disabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != Some(false)
+    }
 }
 
 impl RecordGenerator for Topology {
@@ -66,7 +296,7 @@ impl RecordGenerator for Topology {
     /// and returns a clone of this record.
     ///
     fn refresh_record(&mut self) {
-        match self.read_record() {
+        match self.read_sensor_record() {
             Ok(record) => {
                 self.record_buffer.push(record);
             }
@@ -120,16 +350,217 @@ impl RecordGenerator for Topology {
     fn get_records_passive(&self) -> Vec<Record> {
         let mut result = vec![];
         for r in &self.record_buffer {
-            result.push(Record::new(
-                r.timestamp,
-                r.value.clone(),
-                units::Unit::MicroJoule,
-            ));
+            result.push(
+                Record::new(r.timestamp, r.value.clone(), units::Unit::MicroJoule)
+                    .with_source(r.source),
+            );
         }
         result
     }
 }
 
+/// Energy counters of the host, its sockets and their domains, read back to
+/// back and stamped with a single timestamp, so that the host counter and the
+/// sum of its parts are consistent.
+#[derive(Debug, Clone)]
+pub struct EnergySnapshot {
+    pub timestamp: Duration,
+    /// Counter of each socket, in the order of the sockets, None if it couldn't be read
+    pub sockets: Vec<Option<Record>>,
+    /// Counters of the domains of each socket, in the order of their domains
+    pub domains: Vec<Vec<Option<Record>>>,
+    /// Counter of the host: from the remote meter or PSYS if there is one, the
+    /// sum of the PKG and DRAM counters of the snapshot otherwise
+    pub host: Option<Record>,
+}
+
+impl Topology {
+    /// Reads a new host Record from the sensor the topology was generated by:
+    /// a remote meter if the sensor data designates one, RAPL otherwise.
+    fn read_sensor_record(&self) -> Result<Record, Box<dyn Error>> {
+        #[cfg(feature = "smartplug")]
+        if self._sensor_data.contains_key(smartplug::SENSOR_DATA_KEY) {
+            return smartplug::read_topology_record(self)
+                .map(|r| r.with_source(Some(RecordSource::Remote)));
+        }
+        if self
+            ._sensor_data
+            .contains_key(modbus::SENSOR_DATA_ADDRESS_KEY)
+        {
+            return modbus::read_topology_record(self)
+                .map(|r| r.with_source(Some(RecordSource::Remote)));
+        }
+        if self._sensor_data.contains_key(hypervisor::SENSOR_DATA_KEY) {
+            return hypervisor::read_topology_record(self)
+                .map(|r| r.with_source(Some(RecordSource::Hypervisor)));
+        }
+        if self._sensor_data.contains_key(ipmi::SENSOR_DATA_KEY) {
+            return ipmi::read_topology_record(self)
+                .map(|r| r.with_source(Some(RecordSource::Bmc)));
+        }
+        #[cfg(target_os = "linux")]
+        if self._sensor_data.contains_key(battery::SENSOR_DATA_KEY) {
+            return battery::read_topology_record(self)
+                .map(|r| r.with_source(Some(RecordSource::Battery)));
+        }
+        #[cfg(target_os = "linux")]
+        if self.is_wsl() {
+            return wsl::read_topology_record(self);
+        }
+        self.read_record()
+    }
+
+    /// Tells if the host counter comes from a remote meter, the hypervisor
+    /// or the BMC, rather than RAPL.
+    fn has_remote_meter(&self) -> bool {
+        #[cfg(feature = "smartplug")]
+        if self._sensor_data.contains_key(smartplug::SENSOR_DATA_KEY) {
+            return true;
+        }
+        #[cfg(target_os = "linux")]
+        if self.is_wsl() || self._sensor_data.contains_key(battery::SENSOR_DATA_KEY) {
+            return true;
+        }
+        self._sensor_data
+            .contains_key(modbus::SENSOR_DATA_ADDRESS_KEY)
+            || self._sensor_data.contains_key(hypervisor::SENSOR_DATA_KEY)
+            || self._sensor_data.contains_key(ipmi::SENSOR_DATA_KEY)
+    }
+
+    /// Tells if the topology was generated by the WSL sensor.
+    #[cfg(target_os = "linux")]
+    fn is_wsl(&self) -> bool {
+        self._sensor_data
+            .contains_key(wsl::SENSOR_DATA_COMPANION_KEY)
+            || self
+                ._sensor_data
+                .contains_key(wsl::SENSOR_DATA_ESTIMATION_KEY)
+    }
+
+    /// Reads the counters of the PSYS zones, the sockets and their domains in
+    /// a single pass, through the counter files kept open by the sensor.
+    pub fn read_energy_snapshot(&self) -> EnergySnapshot {
+        let timestamp = current_system_time_since_epoch();
+        #[cfg(target_os = "linux")]
+        let psys = self.get_rapl_psys_energy_microjoules();
+        #[cfg(target_os = "windows")]
+        let psys = unsafe { self.get_rapl_psys_energy_microjoules() };
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let psys: Option<Record> = None;
+        let read = |reader: &dyn RecordReader, what: String| match reader.read_record() {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Couldn't read the energy counter of {what}: {e:?}");
+                None
+            }
+        };
+        let mut sockets = Vec::with_capacity(self.sockets.len());
+        let mut domains = Vec::with_capacity(self.sockets.len());
+        for socket in &self.sockets {
+            sockets.push(read(socket, format!("socket {}", socket.id)));
+            domains.push(
+                socket
+                    .domains
+                    .iter()
+                    .map(|d| read(d, format!("domain {} of socket {}", d.name, socket.id)))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        let host = if self.has_remote_meter() {
+            match self.read_sensor_record() {
+                Ok(record) => Some(record),
+                #[cfg(target_os = "linux")]
+                Err(e) if e.is::<battery::OnAcPower>() => {
+                    debug!("No energy counter for the host: {e}");
+                    None
+                }
+                Err(e) => {
+                    warn!("Couldn't read the energy counter of the host: {e:?}");
+                    None
+                }
+            }
+        } else if psys.is_some() {
+            psys
+        } else if self.sockets.is_empty() {
+            None
+        } else {
+            let mut total: i128 = 0;
+            let mut source = None;
+            for (socket, (value, domain_values)) in
+                self.sockets.iter().zip(sockets.iter().zip(&domains))
+            {
+                // the counter of a disabled zone is flat, it isn't part of the host
+                let pkg = Some(value).filter(|_| socket.is_enabled());
+                let drams = socket
+                    .domains
+                    .iter()
+                    .zip(domain_values)
+                    .filter(|(d, _)| d.name == "dram" && d.is_enabled())
+                    .map(|(_, value)| value);
+                for record in pkg.into_iter().chain(drams).flatten() {
+                    source = source.or(record.source);
+                    match record.value.trim().parse::<i128>() {
+                        Ok(value) => total += value,
+                        Err(e) => warn!("Couldn't convert {} to i128: {}", record.value.trim(), e),
+                    }
+                }
+            }
+            Some(
+                Record::new(timestamp, total.to_string(), units::Unit::MicroJoule)
+                    .with_source(source),
+            )
+        };
+        EnergySnapshot {
+            timestamp,
+            sockets,
+            domains,
+            host,
+        }
+    }
+
+    /// Stores the counters of `snapshot` as new records of the host, the
+    /// sockets and the domains.
+    fn store_energy_snapshot(&mut self, snapshot: EnergySnapshot) {
+        let timestamp = snapshot.timestamp;
+        let record = |record: Record| {
+            Record::new(timestamp, record.value, units::Unit::MicroJoule).with_source(record.source)
+        };
+        for (socket, (value, domain_values)) in self
+            .sockets
+            .iter_mut()
+            .zip(snapshot.sockets.into_iter().zip(snapshot.domains))
+        {
+            if let Some(value) = value {
+                socket.record_buffer.push(record(value));
+                socket.clean_old_records();
+            }
+            for (domain, value) in socket.domains.iter_mut().zip(domain_values) {
+                if let Some(value) = value {
+                    domain.record_buffer.push(record(value));
+                    domain.clean_old_records();
+                }
+            }
+        }
+        if let Some(value) = snapshot.host {
+            self.record_buffer.push(record(value));
+            self.clean_old_records();
+        } else if self.is_on_ac_power() {
+            // the records from before would give the power the host had on battery
+            self.record_buffer.clear();
+        }
+    }
+
+    /// Tells if the topology was generated by the battery sensor and the host
+    /// runs on AC power.
+    fn is_on_ac_power(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(path) = self._sensor_data.get(battery::SENSOR_DATA_KEY) {
+            return battery::BatteryReading::read(path).is_ok_and(|reading| !reading.discharging);
+        }
+        false
+    }
+}
+
 impl Default for Topology {
     fn default() -> Self {
         {
@@ -149,9 +580,35 @@ impl Topology {
             buffer_max_kbytes: 1,
             domains_names: None,
             _sensor_data: sensor_data,
+            counter_files: CounterFiles::default(),
+            pressure: vec![],
+            psys_zones: vec![],
+            #[cfg(target_os = "linux")]
+            memory_counters: uncore::MemoryCounters::default(),
+            memory_traffic: HashMap::new(),
+            disk_stats: None,
+            disk_io: vec![],
+            net_stats: None,
+            net_io: vec![],
+            counter_update_period: None,
+            warnings: vec![],
         }
     }
 
+    /// Logs `warning` and keeps it, if it wasn't already.
+    pub fn add_warning(&mut self, warning: TopologyWarning) {
+        if self.warnings.contains(&warning) {
+            return;
+        }
+        // client CPUs have no dram domain, nothing to warn about
+        if warning == TopologyWarning::NoDram {
+            info!("{}", warning.message());
+        } else {
+            warn!("{}", warning.message());
+        }
+        self.warnings.push(warning);
+    }
+
     /// Parses /proc/cpuinfo and creates instances of CPUCore.
     ///
     ///# Examples
@@ -211,6 +668,9 @@ impl Topology {
                 buffer_max_kbytes,
                 sensor_data,
             );
+            for d in &socket.domains {
+                self.add_domain_name(&d.name);
+            }
             let res = socket.clone();
             self.sockets.push(socket);
             Some(res)
@@ -240,23 +700,38 @@ impl Topology {
         &self.sockets
     }
 
-    // Build a sorted list of all domains names from all sockets.
-    fn build_domains_names(&mut self) {
-        let mut names: HashMap<String, ()> = HashMap::new();
-        for s in self.sockets.iter() {
-            for d in s.get_domains_passive() {
-                names.insert(d.name.clone(), ());
-            }
+    /// Adds a domain name to the sorted list of all domains names, if missing.
+    fn add_domain_name(&mut self, name: &str) {
+        let names = self.domains_names.get_or_insert_with(Vec::new);
+        if let Err(position) = names.binary_search_by(|n| n.as_str().cmp(name)) {
+            names.insert(position, String::from(name));
         }
-        let mut domain_names = names.keys().cloned().collect::<Vec<String>>();
-        domain_names.sort();
-        self.domains_names = Some(domain_names);
     }
 
-    pub fn set_domains_names(&mut self, names: Vec<String>) {
+    /// Sets the list of all domains names, which is kept sorted and without duplicates.
+    pub fn set_domains_names(&mut self, mut names: Vec<String>) {
+        names.sort();
+        names.dedup();
         self.domains_names = Some(names);
     }
 
+    /// Flags the uncore domains as measuring the integrated GPU, if the PCI
+    /// devices found in `pci_devices_path` include one.
+    pub fn detect_integrated_gpu(&mut self, pci_devices_path: &str) {
+        if !utils::has_integrated_gpu(pci_devices_path) {
+            return;
+        }
+        for socket in self.sockets.iter_mut() {
+            for domain in socket.domains.iter_mut().filter(|d| d.name == "uncore") {
+                debug!(
+                    "Uncore domain of socket {} measures the integrated GPU",
+                    socket.id
+                );
+                domain.igpu = true;
+            }
+        }
+    }
+
     /// Adds a Domain instance to a given socket, if and only if the domain
     /// id doesn't exist already for the socket.
     pub fn safe_add_domain_to_socket(
@@ -280,7 +755,9 @@ impl Topology {
                 ));
             }
         }
-        self.build_domains_names();
+        if self.sockets.iter().any(|s| s.id == socket_id) {
+            self.add_domain_name(name);
+        }
     }
 
     /// Generates CPUCore instances for the host and adds them
@@ -288,27 +765,37 @@ impl Topology {
     #[cfg(target_os = "linux")]
     pub fn add_cpu_cores(&mut self) {
         if let Some(mut cores) = Topology::generate_cpu_cores() {
+            let mut mismatch = false;
             while let Some(c) = cores.pop() {
+                // ARM processors have no physical id in /proc/cpuinfo
                 let socket_id = &c
                     .attributes
                     .get("physical id")
-                    .unwrap()
-                    .parse::<u16>()
-                    .unwrap();
-                let socket_match = self.sockets.iter_mut().find(|x| &x.id == socket_id);
-
-                //In VMs there might be a missmatch betwen Sockets and Cores - see Issue#133 as a first fix we just map all cores that can't be mapped to the first
-                let socket = match socket_match {
-                    Some(x) => x,
-                    None =>self.sockets.first_mut().expect("Trick: if you are running on a vm, do not forget to use --vm parameter invoking scaphandre at the command line")
+                    .and_then(|id| id.parse::<u16>().ok())
+                    .unwrap_or(0);
+                let socket = match self.sockets.iter().position(|x| &x.id == socket_id) {
+                    Some(i) => &mut self.sockets[i],
+                    // no package energy counter for any core, e.g. on hosts measured by a
+                    // platform meter only, which have no socket at all
+                    None if self.sockets.is_empty() => {
+                        debug!("No socket {} for core {}, leaving it out", socket_id, c.id);
+                        continue;
+                    }
+                    // VMs (see Issue#133) and some SoCs expose packages whose ids don't match
+                    // the physical ids of the cores: map them to the first socket
+                    None => {
+                        warn!(
+                            "No socket {} for core {}, mapping it to socket {}",
+                            socket_id, c.id, self.sockets[0].id
+                        );
+                        mismatch = true;
+                        &mut self.sockets[0]
+                    }
                 };
-
-                if socket_id == &socket.id {
-                    socket.add_cpu_core(c);
-                } else {
-                    socket.add_cpu_core(c);
-                    warn!("coud't not match core to socket - mapping to first socket instead - if you are not using --vm there is something wrong")
-                }
+                socket.add_cpu_core(c);
+            }
+            if mismatch {
+                self.add_warning(TopologyWarning::CoreSocketMismatch);
             }
 
             //#[cfg(target_os = "windows")]
@@ -342,29 +829,110 @@ impl Topology {
     /// and power consumption, CPU stats and cores power comsumption,
     /// CPU sockets stats and power consumption.
     pub fn refresh(&mut self) {
-        let sockets = &mut self.sockets;
-        for s in sockets {
-            // refresh each socket with new record
-            s.refresh_record();
+        // records of a topology built by the shared sensor come from another instance
+        #[cfg(target_os = "linux")]
+        let shared = self._sensor_data.contains_key(shared::SENSOR_DATA_KEY);
+        #[cfg(not(target_os = "linux"))]
+        let shared = false;
+        // all energy counters are read first and together, processes can take
+        // a while to refresh on busy hosts
+        let snapshot = if shared || self.is_within_update_period() {
+            None
+        } else {
+            self.refresh_zones_enabled();
+            Some(self.read_energy_snapshot())
+        };
+        for s in &mut self.sockets {
             s.refresh_stats();
-            let domains = s.get_domains();
-            for d in domains {
-                d.refresh_record();
-            }
-            //let cores = s.get_cores();
-            //for c in cores {
-            //
-            //}
         }
         self.proc_tracker.refresh();
         self.refresh_procs();
-        self.refresh_record();
+        // with a fresh process list, forget the processes that are gone, then
+        // bound what remains in case processes come and go faster than that
+        self.proc_tracker.clean_terminated_process_records_vectors();
+        self.proc_tracker.evict_least_recently_seen();
+        if shared {
+            #[cfg(target_os = "linux")]
+            if let Err(e) = shared::refresh_topology_records(self) {
+                warn!("Couldn't get energy counters from the primary instance: {e}");
+            }
+        } else if let Some(snapshot) = snapshot {
+            self.store_energy_snapshot(snapshot);
+        }
         self.refresh_stats();
+        #[cfg(target_os = "linux")]
+        {
+            self.pressure = pressure::read_pressure();
+            self.refresh_disk_io();
+            self.refresh_net_io();
+            if !self.memory_counters.is_empty() {
+                self.memory_traffic = self.memory_counters.read_bytes();
+            }
+        }
+    }
+
+    /// Reads the `enabled` attribute of the powercap zones. The records of a
+    /// zone are dropped when it is disabled or enabled again, as its counter
+    /// stood still in between, and so are the records of the host if the zone
+    /// is part of its sum.
+    fn refresh_zones_enabled(&mut self) {
+        let mut host_changed = false;
+        for zone in &mut self.psys_zones {
+            if zone.path.is_empty() {
+                continue;
+            }
+            let enabled = read_zone_enabled(&format!("{}/enabled", zone.path));
+            host_changed |= update_zone_enabled(&mut zone.enabled, enabled, &zone.name);
+        }
+        for socket in &mut self.sockets {
+            if let Some(path) = socket.sensor_data.get("enabled_file") {
+                let enabled = read_zone_enabled(path);
+                let name = format!("of socket {}", socket.id);
+                if update_zone_enabled(&mut socket.enabled, enabled, &name) {
+                    socket.record_buffer.clear();
+                    host_changed = true;
+                }
+            }
+            for domain in &mut socket.domains {
+                if let Some(path) = domain.sensor_data.get("enabled_file") {
+                    let enabled = read_zone_enabled(path);
+                    let name = format!("{} of socket {}", domain.name, socket.id);
+                    if update_zone_enabled(&mut domain.enabled, enabled, &name) {
+                        domain.record_buffer.clear();
+                        host_changed |= domain.name == "dram";
+                    }
+                }
+            }
+        }
+        if host_changed && !self.has_remote_meter() {
+            self.record_buffer.clear();
+        }
+    }
+
+    /// Tells if the last host record is more recent than the update period of
+    /// the counters, in which case reading them again would give the same values.
+    fn is_within_update_period(&self) -> bool {
+        match (self.counter_update_period, self.record_buffer.last()) {
+            (Some(period), Some(last)) => {
+                let elapsed = current_system_time_since_epoch().saturating_sub(last.timestamp);
+                if elapsed < period {
+                    debug!(
+                        "Skipping energy measurement: {elapsed:?} since the last one, counters are updated every {period:?}"
+                    );
+                    return true;
+                }
+                false
+            }
+            _ => false,
+        }
     }
 
     /// Gets currently running processes (as procfs::Process instances) and stores
     /// them in self.proc_tracker
     fn refresh_procs(&mut self) {
+        if self.proc_tracker.filters.disabled {
+            return;
+        }
         {
             let pt = &mut self.proc_tracker;
             pt.sysinfo.refresh_processes();
@@ -385,6 +953,40 @@ impl Topology {
         }
     }
 
+    /// Reads the I/O counters of the disks, and computes the I/O since the
+    /// previous read.
+    #[cfg(target_os = "linux")]
+    fn refresh_disk_io(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        let current = diskstats::read_diskstats();
+        self.disk_io = match &self.disk_stats {
+            Some((previous_timestamp, previous)) => diskstats::disks_io(
+                previous,
+                &current,
+                timestamp.saturating_sub(*previous_timestamp),
+            ),
+            None => vec![],
+        };
+        self.disk_stats = Some((timestamp, current));
+    }
+
+    /// Reads the counters of the network interfaces, and computes the traffic
+    /// since the previous read.
+    #[cfg(target_os = "linux")]
+    fn refresh_net_io(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        let current = netstats::read_netstats(std::path::Path::new(netstats::INTERFACES_PATH));
+        self.net_io = match &self.net_stats {
+            Some((previous_timestamp, previous)) => netstats::interfaces_io(
+                previous,
+                &current,
+                timestamp.saturating_sub(*previous_timestamp),
+            ),
+            None => vec![],
+        };
+        self.net_stats = Some((timestamp, current));
+    }
+
     /// Gets currents stats and stores them as a CPUStat instance in self.stat_buffer
     pub fn refresh_stats(&mut self) {
         if let Some(stats) = self.read_stats() {
@@ -435,120 +1037,139 @@ impl Topology {
             let previous_value = previous.value.parse::<u64>().unwrap();
             if previous_value <= last_value {
                 let diff = last_value - previous_value;
-                return Some(Record::new(last.timestamp, diff.to_string(), last.unit));
+                return Some(
+                    Record::new(last.timestamp, diff.to_string(), last.unit)
+                        .with_source(last.source),
+                );
             }
         }
         None
     }
 
+    /// Returns the time between the last two records of the host, over which
+    /// the host power is computed.
+    pub fn get_records_interval(&self) -> Option<Duration> {
+        let len = self.record_buffer.len();
+        if len < 2 {
+            return None;
+        }
+        self.record_buffer[len - 1]
+            .timestamp
+            .checked_sub(self.record_buffer[len - 2].timestamp)
+    }
+
     /// Returns a Record instance containing the power consumed between
     /// last and previous measurement, in microwatts.
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
-        if self.record_buffer.len() > 1 {
-            let last_record = self.record_buffer.last().unwrap();
-            let previous_record = self
-                .record_buffer
-                .get(self.record_buffer.len() - 2)
-                .unwrap();
-            match previous_record.value.trim().parse::<u128>() {
-                Ok(previous_microjoules) => match last_record.value.trim().parse::<u128>() {
-                    Ok(last_microjoules) => {
-                        if previous_microjoules > last_microjoules {
-                            return None;
-                        }
-                        let microjoules = last_microjoules - previous_microjoules;
-                        let time_diff = last_record.timestamp.as_secs_f64()
-                            - previous_record.timestamp.as_secs_f64();
-                        let microwatts = microjoules as f64 / time_diff;
-                        return Some(Record::new(
-                            last_record.timestamp,
-                            (microwatts as u64).to_string(),
-                            units::Unit::MicroWatt,
-                        ));
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Could'nt get previous_microjoules - value : '{}' - error : {:?}",
-                            previous_record.value, e
-                        );
+        EnergyReading::from_records(&self.record_buffer)?.power
+    }
+
+    /// Returns the last energy record and the power computed from it and the
+    /// previous one, which share their timestamp.
+    pub fn get_energy_reading(&self) -> Option<EnergyReading> {
+        EnergyReading::from_records(&self.record_buffer)
+    }
+
+    /// Returns the power of the host as the sum of the PKG and DRAM domains of the
+    /// sockets, which is what the host power is made of when PSYS is not available.
+    /// Returns None if no socket has enough records yet.
+    pub fn get_pkg_dram_power_microwatts(&self) -> Option<Record> {
+        let mut timestamp = None;
+        let mut source = None;
+        let mut microwatts = 0.0;
+        for s in self.sockets.iter().filter(|s| s.is_enabled()) {
+            if let Some(p) = s.get_records_diff_power_microwatts() {
+                microwatts += p.value.parse::<f64>().unwrap_or(0.0);
+                timestamp = timestamp.max(Some(p.timestamp));
+                source = source.or(p.source);
+            }
+            for d in &s.domains {
+                if d.name == "dram" && d.is_enabled() {
+                    if let Some(p) = d.get_records_diff_power_microwatts() {
+                        microwatts += p.value.parse::<f64>().unwrap_or(0.0);
                     }
-                },
-                Err(e) => {
-                    warn!(
-                        "Couldn't parse previous_microjoules - value : '{}' - error : {:?}",
-                        previous_record.value.trim(),
-                        e
-                    );
                 }
             }
         }
-        None
+        Some(
+            Record::new(
+                timestamp?,
+                format!("{}", microwatts as u64),
+                units::Unit::MicroWatt,
+            )
+            .with_source(source),
+        )
+    }
+
+    /// Returns the ratio (between 0 and 1) of the host power that is covered by
+    /// the PKG and DRAM domains of the sockets, compared to the PSYS measurement.
+    /// Returns None when PSYS is not available, as the share of the host power
+    /// that is not measured is then unknown.
+    pub fn get_measurement_coverage_ratio(&self) -> Option<Record> {
+        if !self.has_psys() {
+            return None;
+        }
+        let host_power = self.get_records_diff_power_microwatts()?;
+        let host_microwatts = host_power.value.parse::<f64>().ok()?;
+        if host_microwatts <= 0.0 {
+            return None;
+        }
+        let observed_microwatts = self
+            .get_pkg_dram_power_microwatts()?
+            .value
+            .parse::<f64>()
+            .ok()?;
+        let ratio = (observed_microwatts / host_microwatts).min(1.0);
+        Some(Record::new(
+            host_power.timestamp,
+            format!("{ratio:.4}"),
+            units::Unit::Numeric,
+        ))
     }
 
     /// Returns a CPUStat instance containing the difference between last
     /// and previous stats measurement (from stat_buffer), attribute by attribute.
     pub fn get_stats_diff(&self) -> Option<CPUStat> {
         if self.stat_buffer.len() > 1 {
-            let last = &self.stat_buffer[0];
-            let previous = &self.stat_buffer[1];
-            let mut iowait = None;
-            let mut irq = None;
-            let mut softirq = None;
-            let mut steal = None;
-            let mut guest = None;
-            let mut guest_nice = None;
-            if last.iowait.is_some() && previous.iowait.is_some() {
-                iowait = Some(last.iowait.unwrap() - previous.iowait.unwrap());
-            }
-            if last.irq.is_some() && previous.irq.is_some() {
-                irq = Some(last.irq.unwrap() - previous.irq.unwrap());
-            }
-            if last.softirq.is_some() && previous.softirq.is_some() {
-                softirq = Some(last.softirq.unwrap() - previous.softirq.unwrap());
-            }
-            if last.steal.is_some() && previous.steal.is_some() {
-                steal = Some(last.steal.unwrap() - previous.steal.unwrap());
-            }
-            if last.guest.is_some() && previous.guest.is_some() {
-                guest = Some(last.guest.unwrap() - previous.guest.unwrap());
-            }
-            if last.guest_nice.is_some() && previous.guest_nice.is_some() {
-                guest_nice = Some(last.guest_nice.unwrap() - previous.guest_nice.unwrap());
-            }
-            return Some(CPUStat {
-                user: last.user - previous.user,
-                nice: last.nice - previous.nice,
-                system: last.system - previous.system,
-                idle: last.idle - previous.idle,
-                iowait,
-                irq,
-                softirq,
-                steal,
-                guest,
-                guest_nice,
-            });
+            return self.stat_buffer[0].diff(&self.stat_buffer[1]);
         }
         None
     }
 
+    /// Returns the share of the CPU time of the host, from 0 to 1, spent in each
+    /// mode of /proc/stat between the last two refreshes of the stats. Guest time
+    /// is left out, as it is part of the user and nice time already.
+    pub fn get_cpu_time_ratios(&self) -> Vec<(&'static str, f64)> {
+        let stats = match self.get_stats_diff() {
+            Some(stats) => stats,
+            None => return vec![],
+        };
+        let elapsed = stats.elapsed_time_jiffies();
+        if elapsed == 0 {
+            return vec![];
+        }
+        [
+            ("user", Some(stats.user)),
+            ("nice", Some(stats.nice)),
+            ("system", Some(stats.system)),
+            ("idle", Some(stats.idle)),
+            ("iowait", stats.iowait),
+            ("irq", stats.irq),
+            ("softirq", stats.softirq),
+            ("steal", stats.steal),
+        ]
+        .into_iter()
+        .filter_map(|(mode, jiffies)| Some((mode, jiffies? as f64 / elapsed as f64)))
+        .collect()
+    }
+
     /// Reads content from /proc/stat and extracts the stats of the whole CPU topology.
     pub fn read_stats(&self) -> Option<CPUStat> {
         #[cfg(target_os = "linux")]
         {
             let kernelstats_or_not = KernelStats::new();
             if let Ok(res_cputime) = kernelstats_or_not {
-                return Some(CPUStat {
-                    user: res_cputime.total.user,
-                    guest: res_cputime.total.guest,
-                    guest_nice: res_cputime.total.guest_nice,
-                    idle: res_cputime.total.idle,
-                    iowait: res_cputime.total.iowait,
-                    irq: res_cputime.total.irq,
-                    nice: res_cputime.total.nice,
-                    softirq: res_cputime.total.softirq,
-                    steal: res_cputime.total.steal,
-                    system: res_cputime.total.system,
-                });
+                return Some(CPUStat::from_procfs_cputime(res_cputime.total));
             }
         }
         None
@@ -618,9 +1239,9 @@ impl Topology {
         ])
     }
 
-    pub fn get_disks(&self) -> HashMap<String, (String, HashMap<String, String>, Record)> {
+    pub fn get_disks(&self) -> BTreeMap<String, (String, HashMap<String, String>, Record)> {
         let timestamp = current_system_time_since_epoch();
-        let mut res = HashMap::new();
+        let mut res = BTreeMap::new();
         for d in self.proc_tracker.sysinfo.disks() {
             let mut attributes = HashMap::new();
             if let Ok(file_system) = str::from_utf8(d.file_system()) {
@@ -671,11 +1292,85 @@ impl Topology {
         res
     }
 
+    /// Returns the temperature of the hardware components known by sysinfo (CPU
+    /// packages and cores, chipset, NVMe drives...), in Celsius, keyed by label.
+    pub fn get_components_temperatures(&self) -> BTreeMap<String, Record> {
+        let timestamp = current_system_time_since_epoch();
+        let mut res = BTreeMap::new();
+        for c in self.proc_tracker.sysinfo.components() {
+            let temperature = c.temperature();
+            if temperature.is_finite() {
+                res.insert(
+                    String::from(c.label()),
+                    Record::new(timestamp, temperature.to_string(), units::Unit::Celsius),
+                );
+            }
+        }
+        res
+    }
+
+    /// Returns the speed of the fans exposed through hwmon, in revolutions per minute,
+    /// keyed by fan label (or `<chip>/fanN` when the driver gives no label).
+    /// Returns the name of the battery read by the battery sensor and whether
+    /// the host is plugged into AC power, if it is known.
+    pub fn get_ac_online(&self) -> Option<(String, bool)> {
+        #[cfg(target_os = "linux")]
+        if let Some(path) = self._sensor_data.get(battery::SENSOR_DATA_KEY) {
+            let name = std::path::Path::new(path)
+                .file_name()?
+                .to_string_lossy()
+                .to_string();
+            return Some((name, battery::ac_online(path)?));
+        }
+        None
+    }
+
+    pub fn get_fans_speed(&self) -> BTreeMap<String, Record> {
+        #[allow(unused_mut)]
+        let mut res = BTreeMap::new();
+        #[cfg(target_os = "linux")]
+        if let Ok(chips) = fs::read_dir("/sys/class/hwmon") {
+            let timestamp = current_system_time_since_epoch();
+            for chip in chips.flatten() {
+                let chip_path = chip.path();
+                let chip_name = fs::read_to_string(chip_path.join("name"))
+                    .map(|name| String::from(name.trim()))
+                    .unwrap_or_else(|_| chip.file_name().to_string_lossy().to_string());
+                let files = match fs::read_dir(&chip_path) {
+                    Ok(files) => files,
+                    Err(_) => continue,
+                };
+                for file in files.flatten() {
+                    let file_name = file.file_name().to_string_lossy().to_string();
+                    let fan = match file_name.strip_suffix("_input") {
+                        Some(fan) if fan.starts_with("fan") => fan,
+                        _ => continue,
+                    };
+                    if let Ok(rpm) = fs::read_to_string(file.path()) {
+                        let label = fs::read_to_string(chip_path.join(format!("{fan}_label")))
+                            .map(|label| String::from(label.trim()))
+                            .unwrap_or_else(|_| format!("{chip_name}/{fan}"));
+                        res.insert(
+                            label,
+                            Record::new(
+                                timestamp,
+                                String::from(rpm.trim()),
+                                units::Unit::RevolutionsPerMinute,
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        res
+    }
+
     pub fn get_total_memory_bytes(&self) -> Record {
         Record {
             timestamp: current_system_time_since_epoch(),
             value: self.proc_tracker.sysinfo.total_memory().to_string(),
             unit: units::Unit::Bytes,
+            source: None,
         }
     }
 
@@ -684,6 +1379,7 @@ impl Topology {
             timestamp: current_system_time_since_epoch(),
             value: self.proc_tracker.sysinfo.available_memory().to_string(),
             unit: units::Unit::Bytes,
+            source: None,
         }
     }
 
@@ -692,6 +1388,7 @@ impl Topology {
             timestamp: current_system_time_since_epoch(),
             value: self.proc_tracker.sysinfo.free_memory().to_string(),
             unit: units::Unit::Bytes,
+            source: None,
         }
     }
 
@@ -700,6 +1397,7 @@ impl Topology {
             timestamp: current_system_time_since_epoch(),
             value: self.proc_tracker.sysinfo.total_swap().to_string(),
             unit: units::Unit::Bytes,
+            source: None,
         }
     }
 
@@ -708,6 +1406,7 @@ impl Topology {
             timestamp: current_system_time_since_epoch(),
             value: self.proc_tracker.sysinfo.free_swap().to_string(),
             unit: units::Unit::Bytes,
+            source: None,
         }
     }
 
@@ -720,11 +1419,10 @@ impl Topology {
                 let conso_f64 = conso.value.parse::<f64>().unwrap();
                 let result =
                     (conso_f64 * process_cpu_percentage.value.parse::<f64>().unwrap()) / 100.0_f64;
-                return Some(Record::new(
-                    record.timestamp,
-                    result.to_string(),
-                    units::Unit::MicroWatt,
-                ));
+                return Some(
+                    Record::new(record.timestamp, result.to_string(), units::Unit::MicroWatt)
+                        .with_source(Some(RecordSource::Estimation)),
+                );
             }
         } else {
             trace!("Couldn't find records for PID: {}", pid);
@@ -732,8 +1430,155 @@ impl Topology {
         None
     }
 
-    pub fn get_all_per_process(&self, pid: Pid) -> Option<HashMap<String, (String, Record)>> {
+    /// Returns the power used by the kernel, in microwatts, keyed by activity:
+    /// "irq" and "softirq" are the host power weighted by the share of CPU time
+    /// spent serving interrupts, "kthreads" is the sum of the power attributed to
+    /// kernel threads.
+    pub fn get_kernel_power_microwatts(&self) -> HashMap<String, Record> {
+        let mut res = HashMap::new();
+        let host_power = match self.get_records_diff_power_microwatts() {
+            Some(record) => record,
+            None => return res,
+        };
+        let host_microwatts = host_power.value.parse::<f64>().unwrap_or(0.0);
+
+        if let Some(stats) = self.get_stats_diff() {
+            let elapsed = stats.elapsed_time_jiffies();
+            if elapsed > 0 {
+                for (activity, jiffies) in [("irq", stats.irq), ("softirq", stats.softirq)] {
+                    if let Some(jiffies) = jiffies {
+                        let microwatts = host_microwatts * jiffies as f64 / elapsed as f64;
+                        res.insert(
+                            String::from(activity),
+                            Record::new(
+                                host_power.timestamp,
+                                (microwatts as u64).to_string(),
+                                units::Unit::MicroWatt,
+                            )
+                            .with_source(Some(RecordSource::Estimation)),
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut kthreads_microwatts = 0.0;
+        for pid in self.proc_tracker.get_alive_pids() {
+            if self.proc_tracker.is_kernel_thread(pid) {
+                if let Some(record) = self.get_process_power_consumption_microwatts(pid) {
+                    kthreads_microwatts += record.value.parse::<f64>().unwrap_or(0.0);
+                }
+            }
+        }
+        res.insert(
+            String::from("kthreads"),
+            Record::new(
+                host_power.timestamp,
+                (kthreads_microwatts as u64).to_string(),
+                units::Unit::MicroWatt,
+            )
+            .with_source(Some(RecordSource::Estimation)),
+        );
+        res
+    }
+
+    /// Returns the sum, over all sockets, of the power of the RAPL domains named
+    /// `domain_name` (core, uncore, dram...), in microwatts.
+    pub fn get_domain_power_microwatts(&self, domain_name: &str) -> Option<f64> {
+        let mut total = None;
+        for socket in &self.sockets {
+            for domain in socket.get_domains_passive() {
+                if domain.name == domain_name {
+                    if let Some(record) = domain.get_records_diff_power_microwatts() {
+                        let microwatts = record.value.parse::<f64>().unwrap_or(0.0);
+                        total = Some(total.unwrap_or(0.0) + microwatts);
+                    }
+                }
+            }
+        }
+        total
+    }
+
+    /// Splits the power of the core, uncore and dram domains between the alive processes,
+    /// in microwatts, keyed by pid then by domain name. Core power is attributed on the
+    /// share of CPU time of each process, dram power on its share of resident memory
+    /// and uncore power is split evenly. Domains missing on the host are left out.
+    pub fn get_processes_domain_power_microwatts(&self) -> HashMap<Pid, BTreeMap<String, Record>> {
         let mut res = HashMap::new();
+        let core = self.get_domain_power_microwatts("core");
+        let uncore = self.get_domain_power_microwatts("uncore");
+        let dram = self.get_domain_power_microwatts("dram");
+        if core.is_none() && uncore.is_none() && dram.is_none() {
+            return res;
+        }
+
+        let mut records = vec![];
+        for pid in self.proc_tracker.get_alive_pids() {
+            if let Some(record) = self.proc_tracker.get_process_last_record(pid) {
+                records.push((pid, record));
+            }
+        }
+        let total_memory: u64 = records.iter().map(|(_, r)| r.process.memory).sum();
+        let nb_processes = records.len();
+
+        for (pid, record) in records {
+            let mut domains = BTreeMap::new();
+            let mut insert = |name: &str, microwatts: f64| {
+                domains.insert(
+                    String::from(name),
+                    Record::new(
+                        record.timestamp,
+                        (microwatts as u64).to_string(),
+                        units::Unit::MicroWatt,
+                    ),
+                );
+            };
+            if let Some(power) = core {
+                let cpu_share = record.process.cpu_usage_percentage as f64
+                    / self.proc_tracker.nb_cores as f64
+                    / 100.0;
+                insert("core", power * cpu_share);
+            }
+            if let Some(power) = dram {
+                if total_memory > 0 {
+                    insert(
+                        "dram",
+                        power * record.process.memory as f64 / total_memory as f64,
+                    );
+                }
+            }
+            if let Some(power) = uncore {
+                insert("uncore", power / nb_processes as f64);
+            }
+            res.insert(pid, domains);
+        }
+        res
+    }
+
+    /// Splits `storage_microwatts`, the power of the storage devices of the
+    /// host, between processes: `iowait_weight` of it on the time each process
+    /// spent waiting for block I/O, the rest on the bytes each read and wrote.
+    pub fn get_processes_storage_power_microwatts(
+        &self,
+        storage_microwatts: f64,
+        iowait_weight: f64,
+    ) -> HashMap<Pid, Record> {
+        let mut usages = vec![];
+        for pid in self.proc_tracker.get_alive_pids() {
+            if let Some(record) = self.proc_tracker.get_process_last_record(pid) {
+                usages.push(StorageUsage {
+                    pid,
+                    timestamp: record.timestamp,
+                    blkio_delay_ticks: self.proc_tracker.get_process_blkio_delay_ticks(pid),
+                    bytes: record.process.disk_read + record.process.disk_written,
+                });
+            }
+        }
+        split_storage_power(&usages, storage_microwatts, iowait_weight)
+    }
+
+    pub fn get_all_per_process(&self, pid: Pid) -> Option<BTreeMap<String, (String, Record)>> {
+        let mut res = BTreeMap::new();
         if let Some(record) = self.get_proc_tracker().get_process_last_record(pid) {
             let process_cpu_percentage =
                 record.process.cpu_usage_percentage / self.proc_tracker.nb_cores as f32;
@@ -908,26 +1753,84 @@ impl Topology {
         None
     }
 
+    /// Returns whether the host has at least one RAPL PSYS zone that isn't disabled.
+    pub fn has_psys(&self) -> bool {
+        self.psys_zones.iter().any(|zone| zone.is_enabled())
+    }
+
+    /// Returns the energy counter of each PSYS zone, by zone name. Zones whose
+    /// counter can't be read are left out.
     #[cfg(target_os = "linux")]
-    pub fn get_rapl_psys_energy_microjoules(&self) -> Option<Record> {
-        if let Some(psys) = self._sensor_data.get("psys") {
-            match &fs::read_to_string(format!("{psys}/energy_uj")) {
+    pub fn get_rapl_psys_zones_energy_microjoules(&self) -> Vec<(String, Record)> {
+        let mut res = vec![];
+        for zone in &self.psys_zones {
+            let path = format!("{}/energy_uj", zone.path);
+            match self.counter_files.read(&zone.counter_key(), &path) {
                 Ok(val) => {
-                    debug!("Read PSYS from {psys}/energy_uj: {}", val.to_string());
-                    return Some(Record::new(
-                        current_system_time_since_epoch(),
-                        val.to_string(),
-                        units::Unit::MicroJoule,
+                    debug!("Read PSYS from {path}: {}", val.trim());
+                    res.push((
+                        zone.name.clone(),
+                        Record::new(
+                            current_system_time_since_epoch(),
+                            String::from(val.trim()),
+                            units::Unit::MicroJoule,
+                        )
+                        .with_source(Some(RecordSource::Psys)),
                     ));
                 }
                 Err(e) => {
-                    warn!("PSYS Error: {:?}", e);
+                    warn!("PSYS Error on {}: {:?}", zone.name, e);
                 }
             }
-        } else {
-            debug!("Asked for PSYS but there is no psys entry in sensor_data.");
         }
-        None
+        res
+    }
+
+    /// Returns the energy of the host according to PSYS: the sum of the counters
+    /// of all enabled PSYS zones, as each of them measures the platform domain of
+    /// its own package. Returns None if there is no enabled PSYS zone or one of
+    /// them can't be read, as a partial sum would look like a counter reset.
+    #[cfg(target_os = "linux")]
+    pub fn get_rapl_psys_energy_microjoules(&self) -> Option<Record> {
+        if !self.has_psys() {
+            debug!("Asked for PSYS but no enabled PSYS zone was found.");
+            return None;
+        }
+        let enabled = self
+            .psys_zones
+            .iter()
+            .filter(|zone| zone.is_enabled())
+            .map(|zone| zone.name.as_str())
+            .collect::<Vec<_>>();
+        let zones = self
+            .get_rapl_psys_zones_energy_microjoules()
+            .into_iter()
+            .filter(|(name, _)| enabled.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+        if zones.len() != enabled.len() {
+            return None;
+        }
+        let mut total: u128 = 0;
+        for (name, record) in &zones {
+            match record.value.parse::<u128>() {
+                Ok(val) => total += val,
+                Err(e) => {
+                    warn!(
+                        "Couldn't parse PSYS counter of {name} '{}': {e}",
+                        record.value
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(
+            Record::new(
+                zones.last()?.1.timestamp,
+                total.to_string(),
+                units::Unit::MicroJoule,
+            )
+            .with_source(Some(RecordSource::Psys)),
+        )
     }
 
     /// # Safety
@@ -942,11 +1845,14 @@ impl Topology {
         let msr_addr = msr_rapl::MSR_PLATFORM_ENERGY_STATUS;
         match get_msr_value(0, msr_addr.into(), &self._sensor_data) {
             Ok(res) => {
-                return Some(Record::new(
-                    current_system_time_since_epoch(),
-                    res.value.to_string(),
-                    units::Unit::MicroJoule,
-                ))
+                return Some(
+                    Record::new(
+                        current_system_time_since_epoch(),
+                        res.value.to_string(),
+                        units::Unit::MicroJoule,
+                    )
+                    .with_source(Some(RecordSource::Psys)),
+                )
             }
             Err(e) => {
                 debug!("get_msr_value returned error : {}", e);
@@ -956,6 +1862,32 @@ impl Topology {
     }
 }
 
+/// Reads the `enabled` attribute of a powercap zone at `path`. Returns None if
+/// the zone has none, e.g. in a virtual machine.
+fn read_zone_enabled(path: &str) -> Option<bool> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim() != "0")
+}
+
+/// Stores the `enabled` state of `zone`, warns when it is disabled, and tells
+/// if its counter stopped or started advancing since the last read.
+fn update_zone_enabled(state: &mut Option<bool>, enabled: Option<bool>, zone: &str) -> bool {
+    if *state == enabled {
+        return false;
+    }
+    match enabled {
+        Some(false) => warn!(
+            "RAPL zone {zone} is disabled: its energy counter doesn't advance, it is left out of the host power until it is enabled again."
+        ),
+        Some(true) if *state == Some(false) => info!("RAPL zone {zone} is enabled again."),
+        _ => {}
+    }
+    let changed = state.unwrap_or(true) != enabled.unwrap_or(true);
+    *state = enabled;
+    changed
+}
+
 // !!!!!!!!!!!!!!!!! CPUSocket !!!!!!!!!!!!!!!!!!!!!!!
 /// CPUSocket struct represents a CPU socket (matches physical_id attribute in /proc/cpuinfo),
 /// owning CPU cores (processor in /proc/cpuinfo).
@@ -980,6 +1912,20 @@ pub struct CPUSocket {
     ///
     #[allow(dead_code)]
     pub sensor_data: HashMap<String, String>,
+    /// Energy counter files kept open between reads, if the sensor reads them that way.
+    pub counter_files: CounterFiles,
+    /// State of the `enabled` attribute of the powercap zone of the socket at
+    /// the last refresh, None if it has none
+    pub enabled: Option<bool>,
+    /// Value after which the energy counter of the socket wraps around to 0,
+    /// in microjoules, if known
+    pub max_energy_range_uj: Option<u64>,
+    /// NUMA nodes whose CPUs belong to the socket
+    pub numa_nodes: Vec<u16>,
+    /// Size of the memory of the NUMA nodes of the socket, in bytes, if known
+    pub memory_bytes: Option<u64>,
+    /// Number of memory channels of the socket, if known
+    pub memory_channels: Option<u16>,
 }
 
 impl RecordGenerator for CPUSocket {
@@ -1043,11 +1989,10 @@ impl RecordGenerator for CPUSocket {
     fn get_records_passive(&self) -> Vec<Record> {
         let mut result = vec![];
         for r in &self.record_buffer {
-            result.push(Record::new(
-                r.timestamp,
-                r.value.clone(),
-                units::Unit::MicroJoule,
-            ));
+            result.push(
+                Record::new(r.timestamp, r.value.clone(), units::Unit::MicroJoule)
+                    .with_source(r.source),
+            );
         }
         result
     }
@@ -1073,6 +2018,12 @@ impl CPUSocket {
             cpu_cores: vec![], // cores are instantiated on a later step
             stat_buffer: vec![],
             sensor_data,
+            counter_files: CounterFiles::default(),
+            enabled: None,
+            max_energy_range_uj: None,
+            numa_nodes: vec![],
+            memory_bytes: None,
+            memory_channels: None,
         }
     }
 
@@ -1080,6 +2031,11 @@ impl CPUSocket {
         self.id = id
     }
 
+    /// Tells if the counter of the socket advances, i.e. its zone isn't disabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != Some(false)
+    }
+
     /// Adds a new Domain instance to the domains vector if and only if it doesn't exist in the vector already.
     fn safe_add_domain(&mut self, domain: Domain) {
         if !self.domains.iter().any(|d| d.id == domain.id) {
@@ -1166,6 +2122,7 @@ impl CPUSocket {
     /// Combines stats from all CPU cores owned byu the socket and returns
     /// a CpuStat struct containing stats for the whole socket.
     pub fn read_stats(&self) -> Option<CPUStat> {
+        let cores_stats = read_cores_stats();
         let mut stats = CPUStat {
             user: 0,
             nice: 0,
@@ -1177,9 +2134,11 @@ impl CPUSocket {
             guest: Some(0),
             guest_nice: Some(0),
             steal: Some(0),
+            cpus: 0,
         };
         for c in &self.cpu_cores {
-            if let Some(c_stats) = c.read_stats() {
+            if let Some(c_stats) = cores_stats.get(&c.id) {
+                stats.cpus += 1;
                 stats.user += c_stats.user;
                 stats.nice += c_stats.nice;
                 stats.system += c_stats.system;
@@ -1197,104 +2156,48 @@ impl CPUSocket {
     /// Computes the difference between previous usage statistics record for the socket
     /// and the current one. Returns a CPUStat object containing this difference, field
     /// by field.
-    pub fn get_stats_diff(&mut self) -> Option<CPUStat> {
+    pub fn get_stats_diff(&self) -> Option<CPUStat> {
         if self.stat_buffer.len() > 1 {
-            let last = &self.stat_buffer[0];
-            let previous = &self.stat_buffer[1];
-            let mut iowait = None;
-            let mut irq = None;
-            let mut softirq = None;
-            let mut steal = None;
-            let mut guest = None;
-            let mut guest_nice = None;
-            if last.iowait.is_some() && previous.iowait.is_some() {
-                iowait = Some(last.iowait.unwrap() - previous.iowait.unwrap());
-            }
-            if last.irq.is_some() && previous.irq.is_some() {
-                irq = Some(last.irq.unwrap() - previous.irq.unwrap());
-            }
-            if last.softirq.is_some() && previous.softirq.is_some() {
-                softirq = Some(last.softirq.unwrap() - previous.softirq.unwrap());
-            }
-            if last.steal.is_some() && previous.steal.is_some() {
-                steal = Some(last.steal.unwrap() - previous.steal.unwrap());
-            }
-            if last.guest.is_some() && previous.guest.is_some() {
-                guest = Some(last.guest.unwrap() - previous.guest.unwrap());
-            }
-            if last.guest_nice.is_some() && previous.guest_nice.is_some() {
-                guest_nice = Some(last.guest_nice.unwrap() - previous.guest_nice.unwrap());
-            }
-            return Some(CPUStat {
-                user: last.user - previous.user,
-                nice: last.nice - previous.nice,
-                system: last.system - previous.system,
-                idle: last.idle - previous.idle,
-                iowait,
-                irq,
-                softirq,
-                steal,
-                guest,
-                guest_nice,
-            });
+            return self.stat_buffer[0].diff(&self.stat_buffer[1]);
         }
         None
     }
 
+    /// Returns the share of time, from 0 to 100, the cores of the socket were
+    /// busy between the last two refreshes of its stats.
+    pub fn get_cpu_usage_percentage(&self) -> Option<f64> {
+        let stats = self.get_stats_diff()?;
+        let elapsed = stats.elapsed_time_jiffies();
+        if elapsed == 0 {
+            return None;
+        }
+        Some(stats.total_time_jiffies() as f64 * 100.0 / elapsed as f64)
+    }
+
     /// Returns a Record instance containing the power consumed between last
     /// and previous measurement, for this CPU socket
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
-        if self.record_buffer.len() > 1 {
-            let last_record = self.record_buffer.last().unwrap();
-            let previous_record = self
-                .record_buffer
-                .get(self.record_buffer.len() - 2)
-                .unwrap();
-            debug!(
-                "socket : last_record value: {} previous_record value: {}",
-                &last_record.value, &previous_record.value
-            );
-            let last_rec_val = last_record.value.trim();
-            debug!("socket : l1187 : trying to parse {} as u64", last_rec_val);
-            let prev_rec_val = previous_record.value.trim();
-            debug!("socket : l1189 : trying to parse {} as u64", prev_rec_val);
-            if let (Ok(last_microjoules), Ok(previous_microjoules)) =
-                (last_rec_val.parse::<u64>(), prev_rec_val.parse::<u64>())
-            {
-                let mut microjoules = 0;
-                if last_microjoules >= previous_microjoules {
-                    microjoules = last_microjoules - previous_microjoules;
-                } else {
-                    debug!(
-                        "socket: previous_microjoules ({}) > last_microjoules ({})",
-                        previous_microjoules, last_microjoules
-                    );
-                }
-                let time_diff =
-                    last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
-                let microwatts = microjoules as f64 / time_diff;
-                debug!("socket : l1067: microwatts: {}", microwatts);
-                return Some(Record::new(
-                    last_record.timestamp,
-                    (microwatts as u64).to_string(),
-                    units::Unit::MicroWatt,
-                ));
-            }
-        } else {
-            warn!("Not enough records for socket");
-        }
-        None
+        EnergyReading::from_records(&self.record_buffer)?.power
+    }
+
+    /// Returns the last energy record and the power computed from it and the
+    /// previous one, which share their timestamp.
+    pub fn get_energy_reading(&self) -> Option<EnergyReading> {
+        EnergyReading::from_records(&self.record_buffer)
     }
 
     pub fn get_rapl_mmio_energy_microjoules(&self) -> Option<Record> {
         if let Some(mmio) = self.sensor_data.get("mmio") {
-            match &fs::read_to_string(mmio) {
+            match &self.counter_files.read("mmio", mmio) {
                 Ok(val) => {
-                    return Some(Record::new(
-                        current_system_time_since_epoch(),
-                        val.to_string(),
-                        units::Unit::MicroJoule,
-                    ));
+                    return Some(
+                        Record::new(
+                            current_system_time_since_epoch(),
+                            val.to_string(),
+                            units::Unit::MicroJoule,
+                        )
+                        .with_source(Some(RecordSource::Mmio)),
+                    );
                 }
                 Err(e) => {
                     debug!("MMIO Error: {:?}", e)
@@ -1322,20 +2225,40 @@ impl CPUCore {
         CPUCore { id, attributes }
     }
 
-    /// Reads content from /proc/stat and extracts the stats of the CPU core
+    /// Reads content from /proc/stat and extracts the stats of the CPU core,
+    /// None if it is offline.
+    #[cfg(test)]
     fn read_stats(&self) -> Option<CPUStat> {
-        #[cfg(target_os = "linux")]
-        {
-            if let Ok(mut kernelstats) = KernelStats::new() {
-                return Some(CPUStat::from_procfs_cputime(
-                    kernelstats.cpu_time.remove(self.id as usize),
-                ));
-            }
-        }
-        None
+        read_cores_stats().remove(&self.id)
     }
 }
 
+/// Reads /proc/stat and returns the stats of each online CPU core, by core id.
+fn read_cores_stats() -> HashMap<u16, CPUStat> {
+    #[cfg(target_os = "linux")]
+    if let Ok(content) = fs::read_to_string("/proc/stat") {
+        return parse_cores_stats(&content);
+    }
+    HashMap::new()
+}
+
+/// Parses the `cpuN` lines of /proc/stat, by core id. Offline cores have no line,
+/// so that the position of a line doesn't tell the id of its core.
+fn parse_cores_stats(content: &str) -> HashMap<u16, CPUStat> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let id = line
+                .split_whitespace()
+                .next()?
+                .strip_prefix("cpu")?
+                .parse::<u16>()
+                .ok()?;
+            Some((id, CPUStat::from_proc_stat_line(line)?))
+        })
+        .collect()
+}
+
 // !!!!!!!!!!!!!!!!! Domain !!!!!!!!!!!!!!!!!!!!!!!
 /// Domain struct represents a part of a CPUSocket from the
 /// electricity consumption point of view.
@@ -1354,6 +2277,17 @@ pub struct Domain {
     ///
     #[allow(dead_code)]
     sensor_data: HashMap<String, String>,
+    /// Energy counter files kept open between reads, if the sensor reads them that way.
+    pub counter_files: CounterFiles,
+    /// State of the `enabled` attribute of the powercap zone of the domain at
+    /// the last refresh, None if it has none
+    pub enabled: Option<bool>,
+    /// Value after which the energy counter of the domain wraps around to 0,
+    /// in microjoules, if known
+    pub max_energy_range_uj: Option<u64>,
+    /// True if the domain measures the integrated GPU of the host, which is
+    /// the case of the uncore domain of desktop and laptop CPUs
+    pub igpu: bool,
 }
 impl RecordGenerator for Domain {
     /// Computes a measurement of energy comsumption for this CPU domain,
@@ -1402,11 +2336,10 @@ impl RecordGenerator for Domain {
     fn get_records_passive(&self) -> Vec<Record> {
         let mut result = vec![];
         for r in &self.record_buffer {
-            result.push(Record::new(
-                r.timestamp,
-                r.value.clone(),
-                units::Unit::MicroJoule,
-            ));
+            result.push(
+                Record::new(r.timestamp, r.value.clone(), units::Unit::MicroJoule)
+                    .with_source(r.source),
+            );
         }
         result
     }
@@ -1427,48 +2360,42 @@ impl Domain {
             record_buffer: vec![],
             buffer_max_kbytes,
             sensor_data,
+            counter_files: CounterFiles::default(),
+            enabled: None,
+            max_energy_range_uj: None,
+            igpu: false,
         }
     }
 
+    /// Tells if the counter of the domain advances, i.e. its zone isn't disabled.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != Some(false)
+    }
+
     /// Returns a Record instance containing the power consumed between
     /// last and previous measurement, in microwatts.
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
-        if self.record_buffer.len() > 1 {
-            let last_record = self.record_buffer.last().unwrap();
-            let previous_record = self
-                .record_buffer
-                .get(self.record_buffer.len() - 2)
-                .unwrap();
-            if let (Ok(last_microjoules), Ok(previous_microjoules)) = (
-                last_record.value.trim().parse::<u64>(),
-                previous_record.value.trim().parse::<u64>(),
-            ) {
-                if previous_microjoules > last_microjoules {
-                    return None;
-                }
-                let microjoules = last_microjoules - previous_microjoules;
-                let time_diff =
-                    last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
-                let microwatts = microjoules as f64 / time_diff;
-                return Some(Record::new(
-                    last_record.timestamp,
-                    (microwatts as u64).to_string(),
-                    units::Unit::MicroWatt,
-                ));
-            }
-        }
-        None
+        EnergyReading::from_records(&self.record_buffer)?.power
+    }
+
+    /// Returns the last energy record and the power computed from it and the
+    /// previous one, which share their timestamp.
+    pub fn get_energy_reading(&self) -> Option<EnergyReading> {
+        EnergyReading::from_records(&self.record_buffer)
     }
 
     pub fn get_rapl_mmio_energy_microjoules(&self) -> Option<Record> {
         if let Some(mmio) = self.sensor_data.get("mmio") {
-            match &fs::read_to_string(mmio) {
+            match &self.counter_files.read("mmio", mmio) {
                 Ok(val) => {
-                    return Some(Record::new(
-                        current_system_time_since_epoch(),
-                        val.to_string(),
-                        units::Unit::MicroJoule,
-                    ));
+                    return Some(
+                        Record::new(
+                            current_system_time_since_epoch(),
+                            val.to_string(),
+                            units::Unit::MicroJoule,
+                        )
+                        .with_source(Some(RecordSource::Mmio)),
+                    );
                 }
                 Err(e) => {
                     debug!("MMIO Error in get microjoules: {:?}", e);
@@ -1485,6 +2412,169 @@ impl fmt::Display for Domain {
 }
 
 // !!!!!!!!!!!!!!!!! Record !!!!!!!!!!!!!!!!!!!!!!!
+/// Where the value of an energy or power Record comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordSource {
+    /// RAPL counters read from powercap files
+    Powercap,
+    /// RAPL counters read from the MSRs, through the Windows driver
+    Msr,
+    /// RAPL counters read from the MSRs, through the msr devices of Linux
+    MsrDevice,
+    /// RAPL counters of AMD CPUs read from the amd_energy hwmon driver
+    AmdEnergy,
+    /// RAPL counters read from the MMIO interface of powercap
+    Mmio,
+    /// RAPL PSYS (platform) counters
+    Psys,
+    /// Computed by scaphandre from other measurements, like the power of a process
+    Estimation,
+    /// External meter: Modbus power meter, smart plug, scaphandre on the
+    /// Windows host of WSL2
+    Remote,
+    /// Power of the virtual machine published by its Hyper-V or VMware host
+    Hypervisor,
+    /// Wall power of the server measured by its BMC, read with IPMI DCMI
+    Bmc,
+    /// Power drawn from the battery of a laptop running on battery
+    Battery,
+    /// Power of the SoC or the package of a Mac, sampled by powermetrics
+    Powermetrics,
+    /// Power of a single-board computer measured by an INA219 or INA3221
+    Ina,
+    /// Power of an NVIDIA Jetson module measured by the INA3221 of its
+    /// carrier board
+    Jetson,
+}
+
+impl RecordSource {
+    /// Returns the value of the `value_source` attribute of the metrics made
+    /// from records of this source.
+    pub fn value_source(&self) -> &'static str {
+        match self {
+            RecordSource::Powercap => "powercap_rapl_pkg",
+            RecordSource::Msr => "scaphandredrv_rapl_pkg",
+            RecordSource::MsrDevice => "msr_rapl_pkg",
+            RecordSource::AmdEnergy => "amd_energy_hwmon",
+            RecordSource::Mmio => "powercap_rapl_mmio",
+            RecordSource::Psys => "powercap_rapl_psys",
+            RecordSource::Estimation => "estimation",
+            RecordSource::Remote => "remote_meter",
+            RecordSource::Hypervisor => "hypervisor",
+            RecordSource::Bmc => "ipmi_dcmi",
+            RecordSource::Battery => "battery_discharge",
+            RecordSource::Powermetrics => "powermetrics",
+            RecordSource::Ina => "ina_power_monitor",
+            RecordSource::Jetson => "jetson_ina3221",
+        }
+    }
+}
+
+/// Energy counter of the host, a socket or a domain, and the power computed
+/// from it, taken from the same records so that they always match.
+#[derive(Debug, Clone)]
+pub struct EnergyReading {
+    /// Last record of the counter, in microjoules
+    pub counter: Record,
+    /// Average power since the previous record, in microwatts, None if there is
+    /// no previous record or the counter went backwards
+    pub power: Option<Record>,
+}
+
+impl EnergyReading {
+    /// Returns the last of the energy `records` and the power between it and
+    /// the previous one, or None if there is no record.
+    pub fn from_records(records: &[Record]) -> Option<EnergyReading> {
+        let (previous, counter) = match records {
+            [.., previous, last] => (Some(previous), last),
+            [last] => (None, last),
+            [] => return None,
+        };
+        let power = previous.and_then(|previous| {
+            let parse = |record: &Record| match record.value.trim().parse::<u128>() {
+                Ok(microjoules) => Some(microjoules),
+                Err(e) => {
+                    warn!("Couldn't parse energy record '{}': {e}", record.value);
+                    None
+                }
+            };
+            let (previous_microjoules, last_microjoules) = (parse(previous)?, parse(counter)?);
+            let time_diff = counter.timestamp.checked_sub(previous.timestamp)?;
+            if previous_microjoules > last_microjoules || time_diff.is_zero() {
+                return None;
+            }
+            let microwatts =
+                (last_microjoules - previous_microjoules) as f64 / time_diff.as_secs_f64();
+            Some(
+                Record::new(
+                    counter.timestamp,
+                    (microwatts as u64).to_string(),
+                    units::Unit::MicroWatt,
+                )
+                .with_source(counter.source),
+            )
+        });
+        Some(EnergyReading {
+            counter: counter.clone(),
+            power,
+        })
+    }
+}
+
+/// Storage activity of a process between its last two records.
+#[derive(Debug, Clone)]
+pub struct StorageUsage {
+    pub pid: Pid,
+    pub timestamp: Duration,
+    /// Clock ticks spent waiting for block I/O, None without delay accounting
+    pub blkio_delay_ticks: Option<u64>,
+    /// Bytes read from and written to disk
+    pub bytes: u64,
+}
+
+/// Splits `microwatts` between the processes of `usages`, `iowait_weight` of it
+/// on their share of the block I/O wait and the rest on their share of the
+/// bytes transferred. When nobody waited for I/O, or delay accounting is off,
+/// all of it is split on bytes, and the other way around. Processes that did
+/// no I/O at all aren't returned.
+pub fn split_storage_power(
+    usages: &[StorageUsage],
+    microwatts: f64,
+    iowait_weight: f64,
+) -> HashMap<Pid, Record> {
+    let total_ticks: u64 = usages.iter().filter_map(|u| u.blkio_delay_ticks).sum();
+    let total_bytes: u64 = usages.iter().map(|u| u.bytes).sum();
+    let iowait_weight = match (total_ticks, total_bytes) {
+        (0, 0) => return HashMap::new(),
+        (0, _) => 0.0,
+        (_, 0) => 1.0,
+        _ => iowait_weight.clamp(0.0, 1.0),
+    };
+    let mut res = HashMap::new();
+    for usage in usages {
+        let mut share = 0.0;
+        if total_ticks > 0 {
+            share +=
+                iowait_weight * usage.blkio_delay_ticks.unwrap_or(0) as f64 / total_ticks as f64;
+        }
+        if total_bytes > 0 {
+            share += (1.0 - iowait_weight) * usage.bytes as f64 / total_bytes as f64;
+        }
+        if share > 0.0 {
+            res.insert(
+                usage.pid,
+                Record::new(
+                    usage.timestamp,
+                    ((microwatts * share) as u64).to_string(),
+                    units::Unit::MicroWatt,
+                )
+                .with_source(Some(RecordSource::Estimation)),
+            );
+        }
+    }
+    res
+}
+
 /// Record struct represents an electricity consumption measurement
 /// tied to a domain.
 #[derive(Debug, Clone)]
@@ -1492,6 +2582,8 @@ pub struct Record {
     pub timestamp: Duration,
     pub value: String,
     pub unit: units::Unit,
+    /// Where the value comes from, None for records that aren't energy or power
+    pub source: Option<RecordSource>,
 }
 
 impl Record {
@@ -1501,8 +2593,15 @@ impl Record {
             timestamp,
             value,
             unit,
+            source: None,
         }
     }
+
+    /// Returns the record, coming from `source`.
+    pub fn with_source(mut self, source: Option<RecordSource>) -> Record {
+        self.source = source;
+        self
+    }
 }
 
 impl fmt::Display for Record {
@@ -1529,6 +2628,8 @@ pub struct CPUStat {
     steal: Option<u64>,
     guest: Option<u64>,
     guest_nice: Option<u64>,
+    /// Number of cores summed in the stat, 0 for the stat of the whole host
+    cpus: u16,
 }
 
 impl CPUStat {
@@ -1545,7 +2646,60 @@ impl CPUStat {
             steal: cpu_time.steal,
             guest: cpu_time.guest,
             guest_nice: cpu_time.guest_nice,
-        }
+            cpus: 0,
+        }
+    }
+
+    /// Parses a `cpu` or `cpuN` line of /proc/stat. Counters missing on older
+    /// kernels are None.
+    fn from_proc_stat_line(line: &str) -> Option<CPUStat> {
+        let mut fields = line.split_whitespace().skip(1);
+        let mut next = || fields.next().and_then(|f| f.parse::<u64>().ok());
+        Some(CPUStat {
+            user: next()?,
+            nice: next()?,
+            system: next()?,
+            idle: next()?,
+            iowait: next(),
+            irq: next(),
+            softirq: next(),
+            steal: next(),
+            guest: next(),
+            guest_nice: next(),
+            cpus: 0,
+        })
+    }
+
+    /// Returns the CPU time spent since `previous`, counter by counter.
+    ///
+    /// Returns None when a core was plugged or unplugged in between, as the
+    /// sums wouldn't cover the same cores, or when one of the user, nice, system
+    /// or idle counters went backwards (counter wrap or reset): the time spent
+    /// over the interval is then unknown. The other counters are saturated to 0
+    /// instead, iowait being allowed to decrease by the kernel.
+    pub fn diff(&self, previous: &CPUStat) -> Option<CPUStat> {
+        if self.cpus != previous.cpus {
+            debug!(
+                "CPU cores changed from {} to {}, skipping CPU stats.",
+                previous.cpus, self.cpus
+            );
+            return None;
+        }
+        let optional =
+            |last: Option<u64>, previous: Option<u64>| Some(last?.saturating_sub(previous?));
+        Some(CPUStat {
+            user: self.user.checked_sub(previous.user)?,
+            nice: self.nice.checked_sub(previous.nice)?,
+            system: self.system.checked_sub(previous.system)?,
+            idle: self.idle.checked_sub(previous.idle)?,
+            iowait: optional(self.iowait, previous.iowait),
+            irq: optional(self.irq, previous.irq),
+            softirq: optional(self.softirq, previous.softirq),
+            steal: optional(self.steal, previous.steal),
+            guest: optional(self.guest, previous.guest),
+            guest_nice: optional(self.guest_nice, previous.guest_nice),
+            cpus: self.cpus,
+        })
     }
 
     /// Returns the total of active CPU time spent, for this stat measurement
@@ -1568,6 +2722,19 @@ impl CPUStat {
         );
         user + nice + system + guest_nice + guest
     }
+
+    /// Returns the total CPU time elapsed for this stat measurement, idle included.
+    /// Guest time is not added as it is already accounted in user and nice.
+    pub fn elapsed_time_jiffies(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.irq.unwrap_or_default()
+            + self.iowait.unwrap_or_default()
+            + self.softirq.unwrap_or_default()
+            + self.steal.unwrap_or_default()
+    }
 }
 
 impl Clone for CPUStat {
@@ -1584,6 +2751,7 @@ impl Clone for CPUStat {
             softirq: self.softirq,
             steal: self.steal,
             system: self.system,
+            cpus: self.cpus,
         }
     }
 }
@@ -1612,8 +2780,11 @@ mod tests {
     fn read_topology_stats() {
         #[cfg(target_os = "linux")]
         let sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(target_os = "windows")]
         let sensor = msr_rapl::MsrRAPLSensor::new();
+        #[cfg(target_os = "macos")]
+        let sensor =
+            powermetrics::PowermetricsSensor::new(powermetrics::DEFAULT_SAMPLE_INTERVAL, 8, 8);
         let topo = (*sensor.get_topology()).unwrap();
         println!("{:?}", topo.read_stats());
     }
@@ -1622,8 +2793,11 @@ mod tests {
     fn read_core_stats() {
         #[cfg(target_os = "linux")]
         let sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(target_os = "windows")]
         let sensor = msr_rapl::MsrRAPLSensor::new();
+        #[cfg(target_os = "macos")]
+        let sensor =
+            powermetrics::PowermetricsSensor::new(powermetrics::DEFAULT_SAMPLE_INTERVAL, 8, 8);
         let mut topo = (*sensor.get_topology()).unwrap();
         for s in topo.get_sockets() {
             for c in s.get_cores() {
@@ -1636,13 +2810,388 @@ mod tests {
     fn read_socket_stats() {
         #[cfg(target_os = "linux")]
         let sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
-        #[cfg(not(target_os = "linux"))]
+        #[cfg(target_os = "windows")]
         let sensor = msr_rapl::MsrRAPLSensor::new();
+        #[cfg(target_os = "macos")]
+        let sensor =
+            powermetrics::PowermetricsSensor::new(powermetrics::DEFAULT_SAMPLE_INTERVAL, 8, 8);
         let mut topo = (*sensor.get_topology()).unwrap();
         for s in topo.get_sockets() {
             println!("{:?}", s.read_stats());
         }
     }
+
+    #[test]
+    fn elapsed_time_jiffies() {
+        let stat = CPUStat {
+            user: 100,
+            nice: 10,
+            system: 50,
+            idle: 800,
+            irq: Some(5),
+            iowait: Some(20),
+            softirq: Some(15),
+            steal: None,
+            guest: Some(30),
+            guest_nice: Some(0),
+            cpus: 0,
+        };
+        assert_eq!(stat.elapsed_time_jiffies(), 1000);
+    }
+
+    #[test]
+    fn cpu_stats_diff() {
+        let content = "cpu  300 0 100 1600 40 0 10 0 0 0
+cpu0 100 0 50 800 20 0 5 0 0 0
+cpu2 200 0 50 800 20 0 5 0 0 0
+intr 1234 0
+ctxt 5678
+";
+        let cores = parse_cores_stats(content);
+        assert_eq!(cores.len(), 2);
+        // cpu1 is offline: cpu2 is the second line
+        assert_eq!(cores[&2].user, 200);
+        let stat = |user, idle, iowait, cpus| {
+            let mut stat = CPUStat::from_proc_stat_line(&format!(
+                "cpu {user} 0 100 {idle} {iowait} 0 10 0 0 0"
+            ))
+            .unwrap();
+            stat.cpus = cpus;
+            stat
+        };
+        let diff = stat(400, 1700, 60, 2)
+            .diff(&stat(300, 1600, 40, 2))
+            .unwrap();
+        assert_eq!((diff.user, diff.idle, diff.iowait), (100, 100, Some(20)));
+        assert_eq!(diff.steal, Some(0));
+        // iowait may decrease
+        let diff = stat(400, 1700, 30, 2)
+            .diff(&stat(300, 1600, 40, 2))
+            .unwrap();
+        assert_eq!(diff.iowait, Some(0));
+        // a counter went backwards
+        assert!(stat(200, 1700, 40, 2)
+            .diff(&stat(300, 1600, 40, 2))
+            .is_none());
+        assert!(stat(u64::MAX, 1600, 40, 0)
+            .diff(&stat(u64::MAX - 1, 1599, 40, 0))
+            .is_some());
+        assert!(stat(3, 1600, 40, 0)
+            .diff(&stat(u64::MAX - 1, 1599, 40, 0))
+            .is_none());
+        let mut topo = Topology::new(HashMap::new());
+        topo.stat_buffer = vec![stat(400, 1700, 60, 0), stat(300, 1600, 40, 0)];
+        let ratios = topo.get_cpu_time_ratios();
+        assert_eq!(ratios.len(), 8);
+        assert_eq!(ratios[0], ("user", 100.0 / 220.0));
+        assert_eq!(ratios[4], ("iowait", 20.0 / 220.0));
+        // a core was plugged in between
+        assert!(stat(400, 2500, 40, 3)
+            .diff(&stat(300, 1600, 40, 2))
+            .is_none());
+        // missing counters of older kernels
+        let old = CPUStat::from_proc_stat_line("cpu 300 0 100 1600").unwrap();
+        assert_eq!(old.iowait, None);
+        assert_eq!(old.diff(&old).unwrap().iowait, None);
+        assert!(CPUStat::from_proc_stat_line("cpu 300 0 x 1600").is_none());
+    }
+
+    #[test]
+    fn domains_names_sorted() {
+        let mut topo = Topology::new(HashMap::new());
+        topo.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        for (id, name) in [(0, "uncore"), (1, "core"), (2, "dram"), (3, "core")] {
+            topo.safe_add_domain_to_socket(0, id, name, "", 1, HashMap::new());
+        }
+        // unknown socket
+        topo.safe_add_domain_to_socket(1, 0, "psys", "", 1, HashMap::new());
+        assert_eq!(
+            topo.domains_names,
+            Some(vec![
+                String::from("core"),
+                String::from("dram"),
+                String::from("uncore")
+            ])
+        );
+    }
+
+    #[test]
+    fn topology_warnings() {
+        let mut topo = Topology::new(HashMap::new());
+        topo.add_warning(TopologyWarning::NoDram);
+        topo.add_warning(TopologyWarning::SocketFallback);
+        topo.add_warning(TopologyWarning::NoDram);
+        assert_eq!(
+            topo.warnings,
+            vec![TopologyWarning::NoDram, TopologyWarning::SocketFallback]
+        );
+        assert_eq!(topo.warnings[1].name(), "socket_fallback");
+        // the cores of the host have no socket 7, as in some virtual machines
+        #[cfg(target_os = "linux")]
+        {
+            topo.safe_add_socket(7, vec![], vec![], String::new(), 1, HashMap::new());
+            topo.add_cpu_cores();
+            assert!(!topo.sockets[0].cpu_cores.is_empty());
+            assert!(topo.warnings.contains(&TopologyWarning::CoreSocketMismatch));
+            // nor 8 and 9: they all go to the first socket
+            let mut topo = Topology::new(HashMap::new());
+            for id in [8, 9] {
+                topo.safe_add_socket(id, vec![], vec![], String::new(), 1, HashMap::new());
+            }
+            topo.add_cpu_cores();
+            let cores = Topology::generate_cpu_cores().unwrap();
+            assert_eq!(topo.sockets[0].cpu_cores.len(), cores.len());
+            assert!(topo.sockets[1].cpu_cores.is_empty());
+            // without sockets, the cores are left out quietly
+            let mut topo = Topology::new(HashMap::new());
+            topo.add_cpu_cores();
+            assert!(topo.warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn integrated_gpu_detection() {
+        let dir = std::env::temp_dir().join(format!("scaphandre-test-pci-{}", std::process::id()));
+        let device = |address: &str, class: &str, vendor: &str| {
+            let path = dir.join(address);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(path.join("class"), format!("{class}\n")).unwrap();
+            std::fs::write(path.join("vendor"), format!("{vendor}\n")).unwrap();
+        };
+        let pci_devices_path = dir.to_str().unwrap();
+        let topology = || {
+            let mut topo = Topology::new(HashMap::new());
+            topo.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+            for (id, name) in [(0, "core"), (1, "uncore")] {
+                topo.safe_add_domain_to_socket(0, id, name, "", 1, HashMap::new());
+            }
+            topo
+        };
+        // host bridge and discrete NVIDIA GPU behind a PCIe bridge
+        device("0000:00:00.0", "0x060000", "0x8086");
+        device("0000:01:00.0", "0x030000", "0x10de");
+        assert!(!utils::has_integrated_gpu(pci_devices_path));
+        let mut topo = topology();
+        topo.detect_integrated_gpu(pci_devices_path);
+        assert!(topo.sockets[0].domains.iter().all(|d| !d.igpu));
+
+        // Intel UHD graphics, whatever its slot
+        device("0000:00:04.0", "0x030000", "0x8086");
+        assert!(utils::has_integrated_gpu(pci_devices_path));
+        let mut topo = topology();
+        topo.detect_integrated_gpu(pci_devices_path);
+        let igpu: Vec<&str> = topo.sockets[0]
+            .domains
+            .iter()
+            .filter(|d| d.igpu)
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(igpu, vec!["uncore"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!utils::has_integrated_gpu(pci_devices_path));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn energy_snapshot() {
+        let dir =
+            std::env::temp_dir().join(format!("scaphandre-test-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let counter = |name: &str, value: &str| {
+            let path = dir.join(name).to_str().unwrap().to_string();
+            std::fs::write(&path, value).unwrap();
+            let mut sensor_data = HashMap::new();
+            sensor_data.insert(String::from("source_file"), path.clone());
+            (path, sensor_data)
+        };
+        let mut topo = Topology::new(HashMap::new());
+        let (path, data) = counter("pkg", "1000\n");
+        topo.safe_add_socket(0, vec![], vec![], path, 1, data);
+        for (id, name, value) in [(0, "core", "600\n"), (1, "dram", "250\n")] {
+            let (path, data) = counter(name, value);
+            topo.safe_add_domain_to_socket(0, id, name, &path, 1, data);
+        }
+        let snapshot = topo.read_energy_snapshot();
+        assert_eq!(snapshot.sockets[0].as_ref().unwrap().value, "1000\n");
+        assert_eq!(snapshot.domains[0].len(), 2);
+        let host = snapshot.host.as_ref().unwrap();
+        assert_eq!(host.value, "1250");
+        assert_eq!(host.source, Some(RecordSource::Powercap));
+
+        topo.store_energy_snapshot(snapshot.clone());
+        let socket = &topo.sockets[0];
+        assert_eq!(socket.record_buffer[0].timestamp, snapshot.timestamp);
+        assert_eq!(
+            socket.domains[1].record_buffer[0].timestamp,
+            snapshot.timestamp
+        );
+        assert_eq!(topo.record_buffer[0].value, "1250");
+
+        // a disabled dram zone is left out of the host, and the records it was part of are dropped
+        let enabled = dir.join("dram_enabled").to_str().unwrap().to_string();
+        std::fs::write(&enabled, "0\n").unwrap();
+        topo.sockets[0].domains[1]
+            .sensor_data
+            .insert(String::from("enabled_file"), enabled.clone());
+        topo.refresh_zones_enabled();
+        assert_eq!(topo.sockets[0].domains[1].enabled, Some(false));
+        assert!(topo.sockets[0].domains[1].record_buffer.is_empty());
+        assert!(topo.record_buffer.is_empty());
+        assert_eq!(topo.read_energy_snapshot().host.unwrap().value, "1000");
+        topo.store_energy_snapshot(topo.read_energy_snapshot());
+        // nothing changes while it stays disabled
+        topo.refresh_zones_enabled();
+        assert_eq!(topo.record_buffer.len(), 1);
+        std::fs::write(&enabled, "1\n").unwrap();
+        topo.refresh_zones_enabled();
+        assert!(topo.sockets[0].domains[1].is_enabled());
+        assert!(topo.record_buffer.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // no socket and no host-level counter: nothing to measure, rather than 0
+        let mut topo = Topology::new(HashMap::new());
+        topo.add_cpu_cores();
+        let snapshot = topo.read_energy_snapshot();
+        assert!(snapshot.sockets.is_empty());
+        assert!(snapshot.host.is_none());
+        topo.store_energy_snapshot(snapshot);
+        assert!(topo.record_buffer.is_empty());
+        assert!(topo.get_pkg_dram_power_microwatts().is_none());
+    }
+
+    #[test]
+    fn energy_reading() {
+        let record = |secs, value: &str| {
+            Record::new(
+                Duration::from_secs(secs),
+                String::from(value),
+                units::Unit::MicroJoule,
+            )
+            .with_source(Some(RecordSource::Powercap))
+        };
+        assert!(EnergyReading::from_records(&[]).is_none());
+        let first = EnergyReading::from_records(&[record(10, "1000")]).unwrap();
+        assert_eq!(first.counter.value, "1000");
+        assert!(first.power.is_none());
+
+        let reading =
+            EnergyReading::from_records(&[record(10, "1000"), record(12, "5000\n")]).unwrap();
+        let power = reading.power.unwrap();
+        assert_eq!(power.value, "2000");
+        assert_eq!(power.timestamp, reading.counter.timestamp);
+        assert_eq!(power.source, Some(RecordSource::Powercap));
+
+        // the counter wrapped
+        let wrapped = EnergyReading::from_records(&[record(12, "5000"), record(14, "10")]).unwrap();
+        assert_eq!(wrapped.counter.value, "10");
+        assert!(wrapped.power.is_none());
+    }
+
+    /// Two energy records of `microjoules`, one second apart.
+    fn energy_records(microjoules: [u64; 2]) -> Vec<Record> {
+        microjoules
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Record::new(
+                    Duration::from_secs(1_700_000_000 + i as u64),
+                    value.to_string(),
+                    units::Unit::MicroJoule,
+                )
+            })
+            .collect()
+    }
+
+    /// A topology of one socket with a core and a dram domain, its host,
+    /// socket, core and dram drawing `watts` each.
+    fn measured_topology(watts: [u64; 4]) -> Topology {
+        let mut topo = Topology::new(HashMap::new());
+        topo.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        for (id, name) in [(0, "core"), (1, "dram")] {
+            topo.safe_add_domain_to_socket(0, id, name, "", 1, HashMap::new());
+        }
+        let records =
+            |watts: u64| energy_records([1_000_000_000, 1_000_000_000 + watts * 1_000_000]);
+        topo.record_buffer = records(watts[0]);
+        topo.sockets[0].record_buffer = records(watts[1]);
+        topo.sockets[0].domains[0].record_buffer = records(watts[2]);
+        topo.sockets[0].domains[1].record_buffer = records(watts[3]);
+        topo
+    }
+
+    fn psys_zone(name: &str) -> PsysZone {
+        PsysZone {
+            name: String::from(name),
+            path: String::new(),
+            enabled: None,
+            max_energy_range_uj: None,
+        }
+    }
+
+    #[test]
+    fn measurement_coverage_ratio() {
+        let mut topo = measured_topology([100, 60, 40, 20]);
+        // without PSYS, the share of the host that isn't measured is unknown
+        assert!(topo.get_measurement_coverage_ratio().is_none());
+        topo.psys_zones.push(psys_zone("intel-rapl:1"));
+        let ratio = topo.get_measurement_coverage_ratio().unwrap();
+        assert_eq!(ratio.value, "0.8000");
+        assert_eq!(ratio.timestamp, Duration::from_secs(1_700_000_001));
+        // PSYS lagging behind PKG and DRAM doesn't give more than the whole host
+        topo.record_buffer = energy_records([0, 50_000_000]);
+        assert_eq!(
+            topo.get_measurement_coverage_ratio().unwrap().value,
+            "1.0000"
+        );
+    }
+
+    #[test]
+    fn records_interval() {
+        let mut topo = Topology::new(HashMap::new());
+        let record = |millis| {
+            Record::new(
+                Duration::from_millis(millis),
+                String::from("0"),
+                units::Unit::MicroJoule,
+            )
+        };
+        topo.record_buffer.push(record(10_000));
+        assert_eq!(topo.get_records_interval(), None);
+        topo.record_buffer.push(record(15_020));
+        assert_eq!(
+            topo.get_records_interval(),
+            Some(Duration::from_millis(5020))
+        );
+    }
+
+    #[test]
+    fn storage_power_split() {
+        let usage = |pid, blkio_delay_ticks, bytes| StorageUsage {
+            pid: Pid::from(pid),
+            timestamp: Duration::from_secs(10),
+            blkio_delay_ticks,
+            bytes,
+        };
+        let power =
+            |split: &HashMap<Pid, Record>, pid| split.get(&Pid::from(pid)).map(|r| r.value.clone());
+        let usages = [
+            usage(1, Some(30), 0),
+            usage(2, Some(10), 3000),
+            usage(3, Some(0), 1000),
+            usage(4, Some(0), 0),
+        ];
+        let split = split_storage_power(&usages, 8_000_000.0, 0.5);
+        assert_eq!(power(&split, 1).as_deref(), Some("3000000"));
+        assert_eq!(power(&split, 2).as_deref(), Some("4000000"));
+        assert_eq!(power(&split, 3).as_deref(), Some("1000000"));
+        assert_eq!(power(&split, 4), None);
+
+        // without delay accounting, bytes are all there is
+        let usages = [usage(1, None, 1000), usage(2, None, 3000)];
+        let split = split_storage_power(&usages, 8_000_000.0, 1.0);
+        assert_eq!(power(&split, 2).as_deref(), Some("6000000"));
+        assert!(split_storage_power(&[usage(1, None, 0)], 8_000_000.0, 1.0).is_empty());
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -0,0 +1,487 @@
+//! # Modbus/TCP sensor
+//!
+//! Reads the power (and optionally the energy counter) of a facility meter, PDU
+//! or UPS speaking Modbus/TCP, so that facility-level measurements go through
+//! the same exporters as host-level ones.
+//!
+//! Registers are described in a register map file. Each non-empty line that
+//! doesn't start with `#` describes one quantity with five whitespace-separated
+//! columns:
+//!
+//! ```text
+//! # quantity  register  address  type  scale
+//! power       input     52       f32   1
+//! energy      holding   72       u32   0.1
+//! ```
+//!
+//! - `quantity` is `power` (scaled to watts) or `energy` (scaled to watt-hours).
+//!   `power` is mandatory. When `energy` is missing, the energy counter is
+//!   computed from the power.
+//! - `register` is `holding` (function 0x03) or `input` (function 0x04).
+//! - `address` is the 0-based register address, in decimal or in hexadecimal (`0x34`).
+//! - `type` is one of `u16`, `i16`, `u32`, `i32`, `f32`, `u64`. Multi-registers values
+//!   are read most significant word first, add a `le` suffix (`f32le`) for meters
+//!   sending the least significant word first.
+//! - `scale` is the factor applied to the raw value.
+use crate::sensors::units::Unit;
+use crate::sensors::utils::{accumulate_power_as_energy, current_system_time_since_epoch};
+use crate::sensors::{Record, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Key of the topology sensor data holding the address of the meter.
+pub const SENSOR_DATA_ADDRESS_KEY: &str = "modbus_address";
+/// Key of the topology sensor data holding the Modbus unit identifier.
+const SENSOR_DATA_UNIT_ID_KEY: &str = "modbus_unit_id";
+/// Prefix of the topology sensor data keys holding the register definitions.
+const SENSOR_DATA_REGISTER_PREFIX: &str = "modbus_register_";
+
+/// Default Modbus/TCP port.
+const MODBUS_PORT: u16 = 502;
+
+/// Maximum time to wait for an answer from the meter.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterKind {
+    Holding,
+    Input,
+}
+
+impl RegisterKind {
+    fn function_code(&self) -> u8 {
+        match self {
+            RegisterKind::Holding => 0x03,
+            RegisterKind::Input => 0x04,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    U64,
+}
+
+impl ValueType {
+    /// Number of 16 bits registers holding a value of this type.
+    fn registers_count(&self) -> u16 {
+        match self {
+            ValueType::U16 | ValueType::I16 => 1,
+            ValueType::U32 | ValueType::I32 | ValueType::F32 => 2,
+            ValueType::U64 => 4,
+        }
+    }
+}
+
+/// Location and encoding of a quantity in the meter registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterDefinition {
+    pub kind: RegisterKind,
+    pub address: u16,
+    pub value_type: ValueType,
+    /// Registers are sent least significant word first.
+    pub swapped_words: bool,
+    pub scale: f64,
+}
+
+impl RegisterDefinition {
+    /// Parses the `register address type scale` columns of a register map line.
+    fn parse(columns: &[&str]) -> Result<RegisterDefinition, String> {
+        if columns.len() != 4 {
+            return Err(format!(
+                "expected register, address, type and scale, got {}",
+                columns.join(" ")
+            ));
+        }
+        let kind = match columns[0] {
+            "holding" => RegisterKind::Holding,
+            "input" => RegisterKind::Input,
+            other => {
+                return Err(format!(
+                    "unknown register {other}, expected holding or input"
+                ))
+            }
+        };
+        let address = match columns[1].strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => columns[1].parse::<u16>(),
+        }
+        .map_err(|e| format!("invalid address {}: {e}", columns[1]))?;
+        let (type_name, swapped_words) = match columns[2].strip_suffix("le") {
+            Some(name) => (name, true),
+            None => (columns[2], false),
+        };
+        let value_type = match type_name {
+            "u16" => ValueType::U16,
+            "i16" => ValueType::I16,
+            "u32" => ValueType::U32,
+            "i32" => ValueType::I32,
+            "f32" => ValueType::F32,
+            "u64" => ValueType::U64,
+            other => return Err(format!("unknown type {other}")),
+        };
+        let scale = columns[3]
+            .parse::<f64>()
+            .map_err(|e| format!("invalid scale {}: {e}", columns[3]))?;
+        Ok(RegisterDefinition {
+            kind,
+            address,
+            value_type,
+            swapped_words,
+            scale,
+        })
+    }
+
+    /// Decodes the registers read for this definition and applies the scale.
+    fn decode(&self, registers: &[u16]) -> Result<f64, String> {
+        if registers.len() != self.value_type.registers_count() as usize {
+            return Err(format!(
+                "expected {} registers, got {}",
+                self.value_type.registers_count(),
+                registers.len()
+            ));
+        }
+        let mut words = registers.to_vec();
+        if self.swapped_words {
+            words.reverse();
+        }
+        let raw = words.iter().fold(0u64, |acc, w| (acc << 16) | *w as u64);
+        let value = match self.value_type {
+            ValueType::U16 | ValueType::U32 | ValueType::U64 => raw as f64,
+            ValueType::I16 => raw as u16 as i16 as f64,
+            ValueType::I32 => raw as u32 as i32 as f64,
+            ValueType::F32 => f32::from_bits(raw as u32) as f64,
+        };
+        Ok(value * self.scale)
+    }
+}
+
+impl std::fmt::Display for RegisterDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            RegisterKind::Holding => "holding",
+            RegisterKind::Input => "input",
+        };
+        let value_type = match self.value_type {
+            ValueType::U16 => "u16",
+            ValueType::I16 => "i16",
+            ValueType::U32 => "u32",
+            ValueType::I32 => "i32",
+            ValueType::F32 => "f32",
+            ValueType::U64 => "u64",
+        };
+        let suffix = if self.swapped_words { "le" } else { "" };
+        write!(
+            f,
+            "{kind} {} {value_type}{suffix} {}",
+            self.address, self.scale
+        )
+    }
+}
+
+/// Parses the content of a register map file, returning the register definitions by quantity.
+pub fn parse_register_map(content: &str) -> Result<HashMap<String, RegisterDefinition>, String> {
+    let mut map = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        let quantity = columns[0];
+        if quantity != "power" && quantity != "energy" {
+            return Err(format!(
+                "line {}: unknown quantity {quantity}, expected power or energy",
+                i + 1
+            ));
+        }
+        let definition =
+            RegisterDefinition::parse(&columns[1..]).map_err(|e| format!("line {}: {e}", i + 1))?;
+        map.insert(String::from(quantity), definition);
+    }
+    if !map.contains_key("power") {
+        return Err(String::from("the register map has no power register"));
+    }
+    Ok(map)
+}
+
+/// A Modbus/TCP client, reading registers of a single unit.
+pub struct ModbusClient {
+    address: String,
+    unit_id: u8,
+}
+
+impl ModbusClient {
+    pub fn new(address: &str, unit_id: u8) -> ModbusClient {
+        let address = if address.contains(':') {
+            String::from(address)
+        } else {
+            format!("{address}:{MODBUS_PORT}")
+        };
+        ModbusClient { address, unit_id }
+    }
+
+    /// Reads the registers of `definition` and returns the decoded, scaled, value.
+    pub fn read(&self, definition: &RegisterDefinition) -> Result<f64, Box<dyn Error>> {
+        let registers = self.read_registers(
+            definition.kind,
+            definition.address,
+            definition.value_type.registers_count(),
+        )?;
+        Ok(definition.decode(&registers)?)
+    }
+
+    fn read_registers(
+        &self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, Box<dyn Error>> {
+        let socket_addr = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("couldn't resolve {}", self.address))?;
+        let mut stream = TcpStream::connect_timeout(&socket_addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.write_all(&build_request(self.unit_id, kind, address, count))?;
+        let mut header = [0u8; 7];
+        stream.read_exact(&mut header)?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if length < 2 {
+            return Err(format!("invalid Modbus answer length {length}").into());
+        }
+        let mut pdu = vec![0u8; length - 1];
+        stream.read_exact(&mut pdu)?;
+        Ok(parse_response(kind, count, &pdu)?)
+    }
+}
+
+/// Builds a Modbus/TCP read registers request (MBAP header followed by the PDU).
+fn build_request(unit_id: u8, kind: RegisterKind, address: u16, count: u16) -> Vec<u8> {
+    let mut request = vec![
+        0x00,
+        0x01, // transaction identifier
+        0x00,
+        0x00, // protocol identifier
+        0x00,
+        0x06, // length of the remaining bytes
+        unit_id,
+        kind.function_code(),
+    ];
+    request.extend_from_slice(&address.to_be_bytes());
+    request.extend_from_slice(&count.to_be_bytes());
+    request
+}
+
+/// Extracts the registers from the PDU of a read registers answer.
+fn parse_response(kind: RegisterKind, count: u16, pdu: &[u8]) -> Result<Vec<u16>, String> {
+    match pdu.first() {
+        Some(f) if *f == kind.function_code() | 0x80 => {
+            return Err(format!(
+                "Modbus exception code {}",
+                pdu.get(1).copied().unwrap_or_default()
+            ))
+        }
+        Some(f) if *f == kind.function_code() => {}
+        _ => return Err(String::from("unexpected Modbus function code in answer")),
+    }
+    let byte_count = *pdu.get(1).ok_or("truncated Modbus answer")? as usize;
+    if byte_count != count as usize * 2 || pdu.len() < 2 + byte_count {
+        return Err(String::from("truncated Modbus answer"));
+    }
+    Ok(pdu[2..2 + byte_count]
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Returns a new energy Record for a topology whose sensor is a Modbus meter.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    let data = &topology._sensor_data;
+    let address = data
+        .get(SENSOR_DATA_ADDRESS_KEY)
+        .ok_or("no Modbus address in topology sensor data")?;
+    let unit_id = data
+        .get(SENSOR_DATA_UNIT_ID_KEY)
+        .map_or(Ok(1), |id| id.parse::<u8>())?;
+    let client = ModbusClient::new(address, unit_id);
+    let register = |quantity: &str| {
+        data.get(&format!("{SENSOR_DATA_REGISTER_PREFIX}{quantity}"))
+            .map(|d| RegisterDefinition::parse(&d.split_whitespace().collect::<Vec<_>>()))
+    };
+    if let Some(energy) = register("energy") {
+        let watt_hours = client.read(&energy?)?;
+        // 1 Wh = 3600 J
+        return Ok(Record::new(
+            current_system_time_since_epoch(),
+            ((watt_hours * 3_600_000_000.0) as u64).to_string(),
+            Unit::MicroJoule,
+        ));
+    }
+    let power = register("power").ok_or("no power register in topology sensor data")??;
+    let watts = client.read(&power)?;
+    Ok(accumulate_power_as_energy(
+        topology.record_buffer.last(),
+        watts,
+    )?)
+}
+
+/// This is a Sensor type that reads the power of a facility meter over Modbus/TCP.
+pub struct ModbusSensor {
+    address: String,
+    unit_id: u8,
+    registers: HashMap<String, RegisterDefinition>,
+}
+
+impl ModbusSensor {
+    /// Instantiates and returns an instance of ModbusSensor, reading the meter at
+    /// `address` (host or host:port) with the registers described in `register_map_path`.
+    pub fn new(
+        address: &str,
+        unit_id: u8,
+        register_map_path: &str,
+    ) -> Result<ModbusSensor, String> {
+        let content = fs::read_to_string(register_map_path)
+            .map_err(|e| format!("couldn't read register map {register_map_path}: {e}"))?;
+        let registers =
+            parse_register_map(&content).map_err(|e| format!("{register_map_path}: {e}"))?;
+        Ok(ModbusSensor {
+            address: String::from(address),
+            unit_id,
+            registers,
+        })
+    }
+}
+
+impl Sensor for ModbusSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from(SENSOR_DATA_ADDRESS_KEY), self.address.clone());
+        sensor_data.insert(
+            String::from(SENSOR_DATA_UNIT_ID_KEY),
+            self.unit_id.to_string(),
+        );
+        for (quantity, definition) in &self.registers {
+            sensor_data.insert(
+                format!("{SENSOR_DATA_REGISTER_PREFIX}{quantity}"),
+                definition.to_string(),
+            );
+        }
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_map() {
+        let map = parse_register_map(
+            "# quantity register address type scale\n\
+             power input 0x34 f32le 1\n\
+             energy holding 72 u32 0.1\n",
+        )
+        .unwrap();
+        let power = map.get("power").unwrap();
+        assert_eq!(power.kind, RegisterKind::Input);
+        assert_eq!(power.address, 0x34);
+        assert_eq!(power.value_type, ValueType::F32);
+        assert!(power.swapped_words);
+        // definitions are stored as strings in the topology sensor data
+        let columns = power.to_string();
+        let columns: Vec<&str> = columns.split_whitespace().collect();
+        assert_eq!(&RegisterDefinition::parse(&columns).unwrap(), power);
+
+        assert!(parse_register_map("energy holding 72 u32 0.1").is_err());
+        assert!(parse_register_map("power coil 1 u16 1").is_err());
+        assert!(parse_register_map("power input 1 u8 1").is_err());
+        assert!(parse_register_map("voltage input 1 u16 1").is_err());
+    }
+
+    #[test]
+    fn decode_registers() {
+        let definition = |value_type, swapped_words, scale| RegisterDefinition {
+            kind: RegisterKind::Holding,
+            address: 0,
+            value_type,
+            swapped_words,
+            scale,
+        };
+        let bits = 1234.5f32.to_bits();
+        let (high, low) = ((bits >> 16) as u16, bits as u16);
+        assert_eq!(
+            definition(ValueType::F32, false, 1.0)
+                .decode(&[high, low])
+                .unwrap(),
+            1234.5
+        );
+        assert_eq!(
+            definition(ValueType::F32, true, 1.0)
+                .decode(&[low, high])
+                .unwrap(),
+            1234.5
+        );
+        assert_eq!(
+            definition(ValueType::I16, false, 0.1)
+                .decode(&[0xFFF6])
+                .unwrap(),
+            -1.0
+        );
+        assert_eq!(
+            definition(ValueType::U32, false, 1.0)
+                .decode(&[0x0001, 0x0002])
+                .unwrap(),
+            65538.0
+        );
+        assert!(definition(ValueType::U32, false, 1.0)
+            .decode(&[0x0001])
+            .is_err());
+    }
+
+    #[test]
+    fn modbus_frames() {
+        assert_eq!(
+            build_request(1, RegisterKind::Input, 0x34, 2),
+            vec![0, 1, 0, 0, 0, 6, 1, 0x04, 0x00, 0x34, 0x00, 0x02]
+        );
+        assert_eq!(
+            parse_response(RegisterKind::Input, 2, &[0x04, 4, 0x12, 0x34, 0x56, 0x78]).unwrap(),
+            vec![0x1234, 0x5678]
+        );
+        assert!(parse_response(RegisterKind::Input, 2, &[0x84, 2]).is_err());
+        assert!(parse_response(RegisterKind::Input, 2, &[0x04, 4, 0x12]).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,413 @@
+//! # MsrLinuxSensor
+//!
+//! Sensor reading the RAPL energy counters straight from the model-specific
+//! registers (MSR) of the CPUs, through the `msr` module of Linux, for hosts
+//! whose kernel has no powercap RAPL driver (`intel_rapl_msr`): old or
+//! custom kernels, AMD CPUs before Linux 5.8...
+//!
+//! Each socket is read through the device of its first CPU, `/dev/cpu/N/msr`,
+//! which only root can open (it needs `CAP_SYS_RAWIO`). When it can't be
+//! opened, the `/dev/cpu/N/msr_safe` device of the msr-safe module is used
+//! instead, its allowlist having to include the registers below:
+//!
+//! - the package counter (`MSR_PKG_ENERGY_STATUS`) is the socket,
+//! - the `core` (PP0), `uncore` (PP1) and `dram` counters are its domains,
+//!   when the CPU has them,
+//! - on AMD CPUs, which have a core counter per core rather than per package,
+//!   the `core` domain is the sum of the counters of the cores of the socket,
+//!   each one read through the device of its first thread.
+//!
+//! The counters are 32 bits wide, in the energy unit given by
+//! `MSR_RAPL_POWER_UNIT`, and wrap around every few minutes to hours. The
+//! sensor adds up their increases between reads instead, so that the energy
+//! it gives doesn't wrap around as long as they are read more often.
+use crate::sensors::cpu_support::CpuSupport;
+use crate::sensors::utils::current_system_time_since_epoch;
+use crate::sensors::{
+    counter_file::CounterFiles, numa, update_period, Record, RecordSource, Sensor, Topology,
+    TopologyWarning,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::units::Unit::MicroJoule;
+
+pub const DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES: u16 = 1;
+pub const DEFAULT_BUFFER_PER_DOMAIN_MAX_KBYTES: u16 = 1;
+
+/// Folder of the msr devices of the CPUs.
+pub const MSR_DEVICES_PATH: &str = "/dev/cpu";
+
+/// Key of the msr device in the sensor data of sockets and domains.
+pub const SENSOR_DATA_DEVICE_KEY: &str = "msr_device";
+/// Key of the msr devices of the cores, comma separated, in the sensor data of
+/// the `core` domains of AMD CPUs, read instead of [SENSOR_DATA_DEVICE_KEY].
+pub const SENSOR_DATA_CORE_DEVICES_KEY: &str = "msr_core_devices";
+/// Key of the address of the energy counter in the sensor data of sockets and domains.
+pub const SENSOR_DATA_ADDRESS_KEY: &str = "msr_address";
+/// Key of the energy unit of the counter, in joules, in the sensor data of
+/// sockets and domains.
+pub const SENSOR_DATA_ENERGY_UNIT_KEY: &str = "msr_energy_unit";
+
+// Intel RAPL MSRs
+pub const MSR_RAPL_POWER_UNIT: u64 = 0x606;
+pub const MSR_PKG_ENERGY_STATUS: u64 = 0x611;
+pub const MSR_DRAM_ENERGY_STATUS: u64 = 0x619;
+pub const MSR_PP0_ENERGY_STATUS: u64 = 0x639;
+pub const MSR_PP1_ENERGY_STATUS: u64 = 0x641;
+
+// AMD RAPL MSRs
+pub const MSR_AMD_RAPL_POWER_UNIT: u64 = 0xc0010299;
+pub const MSR_AMD_CORE_ENERGY_STATUS: u64 = 0xc001029a;
+pub const MSR_AMD_PKG_ENERGY_STATUS: u64 = 0xc001029b;
+
+/// Energy unit of the dram counter of Intel server CPUs, which don't use the
+/// one of MSR_RAPL_POWER_UNIT for it (15.3 microjoules).
+const SERVER_DRAM_ENERGY_UNIT: f64 = 15.3e-6;
+
+/// Models of the Intel server CPUs (family 6) using [SERVER_DRAM_ENERGY_UNIT],
+/// as listed by the intel_rapl driver of Linux: Haswell-X, Broadwell-X,
+/// Broadwell-DE, Skylake-X, Xeon Phi, Ice Lake-X/D, Sapphire Rapids.
+const SERVER_DRAM_MODELS: [u32; 9] = [0x3f, 0x4f, 0x56, 0x55, 0x57, 0x85, 0x6a, 0x6c, 0x8f];
+
+/// Last value of each counter read, and its increases added up since the first
+/// read, by `device:address`.
+static COUNTERS: Mutex<Option<HashMap<String, (u32, u64)>>> = Mutex::new(None);
+
+/// Returns the energy unit, in joules, given by the value of MSR_RAPL_POWER_UNIT:
+/// 1 / 2^ESU, ESU being bits 8 to 12.
+pub fn energy_unit(power_unit_register: u64) -> f64 {
+    let esu = (power_unit_register >> 8) & 0x1f;
+    1.0 / (1u64 << esu) as f64
+}
+
+/// Converts an energy in `energy_unit` to microjoules.
+pub fn microjoules(energy: u64, energy_unit: f64) -> u64 {
+    (energy as f64 * energy_unit * 1e6) as u64
+}
+
+/// Adds the increase of the 32 bits counter `key` since its last read to its
+/// total and returns it, in energy units. The counter is taken to have wrapped
+/// around once when it decreased.
+fn accumulate(key: &str, energy_status_register: u64) -> u64 {
+    let value = (energy_status_register & 0xffffffff) as u32;
+    let mut counters = COUNTERS.lock().unwrap();
+    let counters = counters.get_or_insert_with(HashMap::new);
+    let counter = counters
+        .entry(String::from(key))
+        .or_insert((value, u64::from(value)));
+    counter.1 += u64::from(value.wrapping_sub(counter.0));
+    counter.0 = value;
+    counter.1
+}
+
+/// Tells whether the sensor data of a socket or a domain is the one of an
+/// msr counter.
+pub fn is_msr_counter(sensor_data: &HashMap<String, String>) -> bool {
+    sensor_data.contains_key(SENSOR_DATA_ADDRESS_KEY)
+}
+
+/// Reads the energy counter described by the sensor data of a socket or a
+/// domain, through the msr device in `counter_files`.
+pub fn read_record(
+    sensor_data: &HashMap<String, String>,
+    counter_files: &CounterFiles,
+) -> Result<Record, Box<dyn Error>> {
+    let field = |key: &str| {
+        sensor_data
+            .get(key)
+            .ok_or_else(|| format!("no {key} in sensor data"))
+    };
+    let devices = match sensor_data.get(SENSOR_DATA_CORE_DEVICES_KEY) {
+        Some(devices) => devices.split(',').collect(),
+        None => vec![field(SENSOR_DATA_DEVICE_KEY)?.as_str()],
+    };
+    let address = field(SENSOR_DATA_ADDRESS_KEY)?.parse::<u64>()?;
+    let energy_unit = field(SENSOR_DATA_ENERGY_UNIT_KEY)?.parse::<f64>()?;
+    let mut total = 0;
+    for device in devices {
+        let value = counter_files.read_register(device, device, address)?;
+        total += accumulate(&format!("{device}:{address:#x}"), value);
+    }
+    Ok(Record::new(
+        current_system_time_since_epoch(),
+        microjoules(total, energy_unit).to_string(),
+        MicroJoule,
+    )
+    .with_source(Some(RecordSource::MsrDevice)))
+}
+
+/// Returns the path of the msr device of `cpu` that can be opened, the msr
+/// one or else the msr_safe one.
+pub fn open_device(base_path: &str, cpu: u32) -> Result<String, String> {
+    let mut errors = vec![];
+    for name in ["msr", "msr_safe"] {
+        let path = format!("{base_path}/{cpu}/{name}");
+        match File::open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) => errors.push(format!("{path}: {e}")),
+        }
+    }
+    Err(format!(
+        "couldn't open the msr device of CPU {cpu} ({}), is the msr module loaded \
+        (modprobe msr) and scaphandre running as root?",
+        errors.join(", ")
+    ))
+}
+
+/// Tells if the host has msr devices, to fall back on when powercap has no
+/// RAPL zone.
+pub fn has_msr_devices(base_path: &str) -> bool {
+    ["msr", "msr_safe"]
+        .iter()
+        .any(|name| Path::new(base_path).join("0").join(name).exists())
+}
+
+/// A CPU of the host, as listed in /proc/cpuinfo.
+struct Cpu {
+    processor: u32,
+    socket: u16,
+    core: u32,
+}
+
+/// This is a Sensor type that reads the RAPL counters from the msr devices of
+/// Linux, when powercap can't.
+pub struct MsrLinuxSensor {
+    base_path: String,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl MsrLinuxSensor {
+    /// Instantiates and returns an instance of MsrLinuxSensor.
+    pub fn new(
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+    ) -> MsrLinuxSensor {
+        MsrLinuxSensor {
+            base_path: String::from(MSR_DEVICES_PATH),
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        }
+    }
+
+    /// Returns the first CPU (thread) of each core.
+    fn first_threads() -> Result<Vec<Cpu>, Box<dyn Error>> {
+        let cpuinfo = procfs::CpuInfo::new()?;
+        let mut first_threads: Vec<Cpu> = vec![];
+        for i in 0..cpuinfo.num_cores() {
+            let Some(processor) = cpuinfo
+                .get_field(i, "processor")
+                .and_then(|p| p.trim().parse::<u32>().ok())
+            else {
+                continue;
+            };
+            // ARM processors and some virtual machines have no physical id
+            let socket = cpuinfo
+                .get_field(i, "physical id")
+                .and_then(|id| id.trim().parse::<u16>().ok())
+                .unwrap_or(0);
+            let core = cpuinfo
+                .get_field(i, "core id")
+                .and_then(|id| id.trim().parse::<u32>().ok())
+                .unwrap_or(processor);
+            if !first_threads
+                .iter()
+                .any(|cpu| cpu.socket == socket && cpu.core == core)
+            {
+                first_threads.push(Cpu {
+                    processor,
+                    socket,
+                    core,
+                });
+            }
+        }
+        Ok(first_threads)
+    }
+}
+
+impl Sensor for MsrLinuxSensor {
+    /// Creates a Topology instance, with the counters each socket has.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let cpu = CpuSupport::detect().ok_or("couldn't read /proc/cpuinfo")?;
+        let amd = cpu.is_amd();
+        let first_threads = MsrLinuxSensor::first_threads()?;
+        let mut topo = Topology::new(HashMap::new());
+        let mut sockets: Vec<u16> = first_threads.iter().map(|cpu| cpu.socket).collect();
+        sockets.sort();
+        sockets.dedup();
+        for socket_id in sockets {
+            let mut threads = first_threads.iter().filter(|cpu| cpu.socket == socket_id);
+            let Some(first_cpu) = threads.next() else {
+                continue;
+            };
+            let device = open_device(&self.base_path, first_cpu.processor)?;
+            let mut files = CounterFiles::default();
+            files.open(&device, &device);
+            let unit_address = if amd {
+                MSR_AMD_RAPL_POWER_UNIT
+            } else {
+                MSR_RAPL_POWER_UNIT
+            };
+            let unit = energy_unit(
+                files
+                    .read_register(&device, &device, unit_address)
+                    .map_err(|e| format!("couldn't read the RAPL energy unit of {device}: {e}"))?,
+            );
+            let sensor_data = |address: u64, unit: f64| {
+                HashMap::from([
+                    (String::from(SENSOR_DATA_DEVICE_KEY), device.clone()),
+                    (String::from(SENSOR_DATA_ADDRESS_KEY), address.to_string()),
+                    (String::from(SENSOR_DATA_ENERGY_UNIT_KEY), unit.to_string()),
+                ])
+            };
+            let package_address = if amd {
+                MSR_AMD_PKG_ENERGY_STATUS
+            } else {
+                MSR_PKG_ENERGY_STATUS
+            };
+            topo.safe_add_socket(
+                first_cpu.socket,
+                vec![],
+                vec![],
+                format!("{device}:{package_address:#x}"),
+                self.buffer_per_socket_max_kbytes,
+                sensor_data(package_address, unit),
+            );
+            let dram_unit = if cpu.family == 6 && SERVER_DRAM_MODELS.contains(&cpu.model) {
+                SERVER_DRAM_ENERGY_UNIT
+            } else {
+                unit
+            };
+            if amd {
+                // the core counters of the other cores are read through their
+                // own device
+                let mut core_devices = vec![device.clone()];
+                for thread in threads {
+                    let core_device = open_device(&self.base_path, thread.processor)?;
+                    files.open(&core_device, &core_device);
+                    core_devices.push(core_device);
+                }
+                let mut sensor_data = sensor_data(MSR_AMD_CORE_ENERGY_STATUS, unit);
+                sensor_data.insert(
+                    String::from(SENSOR_DATA_CORE_DEVICES_KEY),
+                    core_devices.join(","),
+                );
+                topo.safe_add_domain_to_socket(
+                    first_cpu.socket,
+                    0,
+                    "core",
+                    &format!("{device}:{MSR_AMD_CORE_ENERGY_STATUS:#x}"),
+                    self.buffer_per_domain_max_kbytes,
+                    sensor_data,
+                );
+            }
+            let domains = if amd {
+                vec![]
+            } else {
+                vec![
+                    ("core", MSR_PP0_ENERGY_STATUS, unit),
+                    ("uncore", MSR_PP1_ENERGY_STATUS, unit),
+                    ("dram", MSR_DRAM_ENERGY_STATUS, dram_unit),
+                ]
+            };
+            let mut domain_id = 0;
+            for (name, address, unit) in domains {
+                // registers the CPU doesn't have can't be read, or stay at 0
+                match files.read_register(&device, &device, address) {
+                    Ok(value) if value & 0xffffffff != 0 => {}
+                    _ => {
+                        debug!("No {name} counter in {device}");
+                        continue;
+                    }
+                }
+                topo.safe_add_domain_to_socket(
+                    first_cpu.socket,
+                    domain_id,
+                    name,
+                    &format!("{device}:{address:#x}"),
+                    self.buffer_per_domain_max_kbytes,
+                    sensor_data(address, unit),
+                );
+                domain_id += 1;
+            }
+            for socket in topo.get_sockets() {
+                if socket.id != first_cpu.socket {
+                    continue;
+                }
+                socket.counter_files = files.clone();
+                for domain in socket.get_domains() {
+                    domain.counter_files = files.clone();
+                }
+            }
+        }
+        if topo.sockets.is_empty() {
+            return Err("no CPU found in /proc/cpuinfo".into());
+        }
+        if !topo
+            .sockets
+            .iter()
+            .any(|s| s.domains.iter().any(|d| d.name == "dram"))
+        {
+            topo.add_warning(TopologyWarning::NoDram);
+        }
+        topo.add_cpu_cores();
+        numa::set_sockets_memory(&mut topo.sockets);
+        topo.counter_update_period = topo
+            .sockets
+            .first()
+            .and_then(|socket| update_period::detect_update_period(socket));
+        Ok(topo)
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        match self.generate_topology() {
+            Ok(topology) => Box::new(Some(topology)),
+            Err(e) => panic!("Couldn't generate the topology: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msr_energy_units() {
+        // ESU of 14 on most Intel CPUs, 16 on AMD ones
+        assert_eq!(energy_unit(0x000a0e03), 1.0 / 16384.0);
+        assert_eq!(energy_unit(0x000a1003), 1.0 / 65536.0);
+        assert_eq!(microjoules(16384, energy_unit(0x000a0e03)), 1_000_000);
+        assert_eq!(microjoules(2, SERVER_DRAM_ENERGY_UNIT), 30);
+
+        // only the 32 low bits count, and the total goes on past a wrap around
+        assert_eq!(accumulate("test:0x611", 0x1_ffff_fff0), 0xffff_fff0);
+        assert_eq!(accumulate("test:0x611", 0xffff_fff8), 0xffff_fff8);
+        assert_eq!(accumulate("test:0x611", 0x8), 0x1_0000_0008);
+        assert_eq!(accumulate("test:0x639", 0x8), 0x8);
+
+        let mut sensor_data = HashMap::new();
+        assert!(!is_msr_counter(&sensor_data));
+        sensor_data.insert(String::from(SENSOR_DATA_ADDRESS_KEY), String::from("1553"));
+        assert!(is_msr_counter(&sensor_data));
+        assert!(read_record(&sensor_data, &CounterFiles::default()).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
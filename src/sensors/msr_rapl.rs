@@ -1,5 +1,8 @@
 use crate::sensors::utils::current_system_time_since_epoch;
-use crate::sensors::{CPUCore, CPUSocket, Domain, Record, RecordReader, Sensor, Topology};
+use crate::sensors::{
+    raw_reads, update_period, CPUCore, CPUSocket, Domain, PsysZone, Record, RecordReader,
+    RecordSource, Sensor, Topology,
+};
 use raw_cpuid::{CpuId, TopologyType};
 use std::collections::HashMap;
 use std::error::Error;
@@ -203,6 +206,7 @@ impl RecordReader for Topology {
                 timestamp: current_system_time_since_epoch(),
                 unit: super::units::Unit::MicroJoule,
                 value: res.to_string(),
+                source: Some(RecordSource::Msr),
             })
         }
     }
@@ -293,6 +297,7 @@ impl RecordReader for CPUSocket {
                             timestamp: current_system_time_since_epoch(),
                             value: rec.value,
                             unit: super::units::Unit::MicroJoule,
+                            source: Some(RecordSource::Msr),
                         }),
                         Err(e) => {
                             error!(
@@ -303,6 +308,7 @@ impl RecordReader for CPUSocket {
                                 timestamp: current_system_time_since_epoch(),
                                 value: String::from("0"),
                                 unit: super::units::Unit::MicroJoule,
+                                source: Some(RecordSource::Msr),
                             })
                         }
                     }
@@ -336,6 +342,7 @@ impl RecordReader for Domain {
                             timestamp: current_system_time_since_epoch(),
                             unit: super::units::Unit::MicroJoule,
                             value: rec.value,
+                            source: Some(RecordSource::Msr),
                         }),
                         Err(e) => {
                             error!("Could'nt get MSR value for {}: {}", msr_addr, e);
@@ -343,6 +350,7 @@ impl RecordReader for Domain {
                                 timestamp: current_system_time_since_epoch(),
                                 value: String::from("0"),
                                 unit: super::units::Unit::MicroJoule,
+                                source: Some(RecordSource::Msr),
                             })
                         }
                     }
@@ -694,9 +702,12 @@ impl Sensor for MsrRAPLSensor {
             match get_msr_value(0, MSR_PLATFORM_ENERGY_STATUS as u64, &sensor_data) {
                 Ok(_rec) => {
                     debug!("Adding domain Platform / PSYS !");
-                    topology
-                        ._sensor_data
-                        .insert(String::from("psys"), String::from(""));
+                    topology.psys_zones.push(PsysZone {
+                        name: String::from("psys"),
+                        path: String::new(),
+                        enabled: None,
+                        max_energy_range_uj: None,
+                    });
                 }
                 Err(e) => {
                     warn!("Could'nt add Uncore domain: {}", e);
@@ -705,6 +716,10 @@ impl Sensor for MsrRAPLSensor {
         }
 
         topology.set_domains_names(domains);
+        topology.counter_update_period = topology
+            .sockets
+            .first()
+            .and_then(|socket| update_period::detect_update_period(socket));
         Ok(topology)
     }
 
@@ -784,6 +799,10 @@ pub unsafe fn get_msr_value(
                     ) {
                         Ok(_res) => {
                             close_handle(device);
+                            raw_reads::record(
+                                &format!("msr:{msr_addr:#x}:{core_id}"),
+                                &msr_result.to_string(),
+                            );
 
                             let energy_unit = sensor_data
                                 .get("ENERGY_UNIT")
@@ -798,6 +817,7 @@ pub unsafe fn get_msr_value(
                                 timestamp: current_system_time_since_epoch(),
                                 unit: super::units::Unit::MicroJoule,
                                 value: current_value,
+                                source: Some(RecordSource::Msr),
                             })
                         }
                         Err(e) => {
@@ -0,0 +1,171 @@
+//! # Netstats: traffic and link speed of the network interfaces
+//!
+//! Each network interface has, in `/sys/class/net/<interface>`:
+//!
+//! ```text
+//! statistics/rx_bytes   bytes received since the interface was created
+//! statistics/tx_bytes   bytes transmitted since the interface was created
+//! speed                 speed of the link, in Mb/s (-1, or unreadable, when unknown or down)
+//! device                link to the hardware, missing for virtual interfaces
+//! ```
+//!
+//! Only the interfaces backed by hardware are kept: the loopback, bridges,
+//! veths and tunnels carry traffic that either never leaves the host or is
+//! counted again on a physical interface.
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Folder of the network interfaces.
+pub const INTERFACES_PATH: &str = "/sys/class/net";
+
+/// Cumulated traffic of an interface, and the speed of its link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetStat {
+    pub interface: String,
+    pub received_bytes: u64,
+    pub transmitted_bytes: u64,
+    /// Speed of the link, in Mb/s, None when it is unknown or down
+    pub speed_mbps: Option<u64>,
+}
+
+/// Traffic of an interface between two reads of its counters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetIo {
+    pub interface: String,
+    pub received_bytes: u64,
+    pub transmitted_bytes: u64,
+    pub speed_mbps: Option<u64>,
+    pub interval: Duration,
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse::<u64>().ok()
+}
+
+/// Reads the counters of the physical network interfaces in `path`, sorted
+/// by name.
+pub fn read_netstats(path: &Path) -> Vec<NetStat> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!(
+                "Couldn't list network interfaces in {}: {e}",
+                path.display()
+            );
+            return vec![];
+        }
+    };
+    let mut res = vec![];
+    for entry in entries.flatten() {
+        let interface = entry.path();
+        if !interface.join("device").exists() {
+            continue;
+        }
+        let statistics = interface.join("statistics");
+        let (received_bytes, transmitted_bytes) = match (
+            read_u64(&statistics.join("rx_bytes")),
+            read_u64(&statistics.join("tx_bytes")),
+        ) {
+            (Some(rx), Some(tx)) => (rx, tx),
+            _ => continue,
+        };
+        res.push(NetStat {
+            interface: entry.file_name().to_string_lossy().to_string(),
+            received_bytes,
+            transmitted_bytes,
+            // "-1" doesn't parse as u64
+            speed_mbps: read_u64(&interface.join("speed")).filter(|speed| *speed > 0),
+        });
+    }
+    res.sort_by(|a, b| a.interface.cmp(&b.interface));
+    res
+}
+
+/// Returns the traffic of each interface of `current` between `previous` and
+/// `current`, read `interval` apart. Interfaces missing from `previous`, or
+/// whose counters went backwards (driver reloaded), are skipped.
+pub fn interfaces_io(previous: &[NetStat], current: &[NetStat], interval: Duration) -> Vec<NetIo> {
+    if interval.is_zero() {
+        return vec![];
+    }
+    current
+        .iter()
+        .filter_map(|stat| {
+            let before = previous.iter().find(|p| p.interface == stat.interface)?;
+            Some(NetIo {
+                interface: stat.interface.clone(),
+                received_bytes: stat.received_bytes.checked_sub(before.received_bytes)?,
+                transmitted_bytes: stat
+                    .transmitted_bytes
+                    .checked_sub(before.transmitted_bytes)?,
+                speed_mbps: stat.speed_mbps,
+                interval,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces_traffic() {
+        let dir = std::env::temp_dir().join(format!("scaphandre-net-{}", std::process::id()));
+        for (interface, physical, rx, tx, speed) in [
+            ("eth0", true, "1000", "5000", "10000\n"),
+            ("eth1", true, "10", "20", "-1\n"),
+            ("lo", false, "999", "999", ""),
+        ] {
+            let statistics = dir.join(interface).join("statistics");
+            fs::create_dir_all(&statistics).unwrap();
+            fs::write(statistics.join("rx_bytes"), rx).unwrap();
+            fs::write(statistics.join("tx_bytes"), tx).unwrap();
+            fs::write(dir.join(interface).join("speed"), speed).unwrap();
+            if physical {
+                fs::create_dir_all(dir.join(interface).join("device")).unwrap();
+            }
+        }
+        let previous = read_netstats(&dir);
+        assert_eq!(
+            previous
+                .iter()
+                .map(|s| (s.interface.as_str(), s.speed_mbps))
+                .collect::<Vec<_>>(),
+            vec![("eth0", Some(10000)), ("eth1", None)]
+        );
+
+        fs::write(dir.join("eth0/statistics/rx_bytes"), "3000").unwrap();
+        fs::write(dir.join("eth1/statistics/rx_bytes"), "0").unwrap();
+        let current = read_netstats(&dir);
+        let io = interfaces_io(&previous, &current, Duration::from_secs(10));
+        assert_eq!(
+            io,
+            vec![NetIo {
+                interface: String::from("eth0"),
+                received_bytes: 2000,
+                transmitted_bytes: 0,
+                speed_mbps: Some(10000),
+                interval: Duration::from_secs(10),
+            }]
+        );
+        assert!(read_netstats(&dir.join("missing")).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
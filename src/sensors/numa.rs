@@ -0,0 +1,191 @@
+//! # NUMA: memory attached to each socket
+//!
+//! The energy of the `dram` RAPL domain of a socket depends on how much memory
+//! sits behind it, which differs between hosts and sometimes between the
+//! sockets of a host. The kernel describes each NUMA node in
+//! `/sys/devices/system/node/node<n>`:
+//!
+//! ```text
+//! cpulist     CPUs of the node (0-17,36-53)
+//! meminfo     memory of the node, MemTotal being its size (Node 0 MemTotal: 65536000 kB)
+//! ```
+//!
+//! A socket is attached to the nodes whose CPUs are its own: one node usually,
+//! several when sub-NUMA clustering is enabled. Nodes without CPUs, such as
+//! CXL or high bandwidth memory, aren't attached to any socket.
+//!
+//! The number of memory channels of a socket is the number of memory
+//! controller perf sources (`uncore_imc_<n>`, see [super::uncore]) having one
+//! of its CPUs in their cpumask, which is only known on Intel server CPUs.
+use super::uncore::{parse_cpu_list, EVENT_SOURCES_PATH};
+use super::CPUSocket;
+use std::fs;
+use std::path::Path;
+
+/// Folder of the NUMA nodes.
+pub const NODES_PATH: &str = "/sys/devices/system/node";
+
+/// A NUMA node, as described by sysfs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    pub id: u16,
+    pub cpus: Vec<u16>,
+    /// Size of the memory of the node, in bytes
+    pub memory_bytes: Option<u64>,
+}
+
+/// Returns the size of the memory of a node, in bytes, from its meminfo file.
+pub fn parse_mem_total(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (_, value) = line.split_once("MemTotal:")?;
+        let mut fields = value.split_whitespace();
+        let size = fields.next()?.parse::<u64>().ok()?;
+        match fields.next() {
+            Some("kB") => Some(size * 1024),
+            None => Some(size),
+            Some(_) => None,
+        }
+    })
+}
+
+/// Reads the NUMA nodes of the host from `path`, sorted by id. Returns an
+/// empty list on hosts without NUMA support in the kernel.
+pub fn read_nodes(path: &Path) -> Vec<NumaNode> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Couldn't list NUMA nodes in {}: {e}", path.display());
+            return vec![];
+        }
+    };
+    let mut nodes = vec![];
+    for entry in entries.flatten() {
+        let id = match entry
+            .file_name()
+            .to_string_lossy()
+            .strip_prefix("node")
+            .and_then(|id| id.parse::<u16>().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let node_path = entry.path();
+        nodes.push(NumaNode {
+            id,
+            cpus: fs::read_to_string(node_path.join("cpulist"))
+                .map(|list| parse_cpu_list(&list))
+                .unwrap_or_default(),
+            memory_bytes: fs::read_to_string(node_path.join("meminfo"))
+                .ok()
+                .and_then(|meminfo| parse_mem_total(&meminfo)),
+        });
+    }
+    nodes.sort_by_key(|n| n.id);
+    nodes
+}
+
+/// Returns the number of memory controller sources in `path` having one of
+/// `cpus` in their cpumask, None if there are no such sources at all.
+pub fn count_memory_channels(path: &Path, cpus: &[u16]) -> Option<u16> {
+    let mut sources = 0;
+    let mut channels = 0;
+    for source in fs::read_dir(path).ok()?.flatten() {
+        if !source
+            .file_name()
+            .to_string_lossy()
+            .starts_with("uncore_imc")
+        {
+            continue;
+        }
+        sources += 1;
+        let mask = fs::read_to_string(source.path().join("cpumask")).unwrap_or_default();
+        if parse_cpu_list(&mask).iter().any(|cpu| cpus.contains(cpu)) {
+            channels += 1;
+        }
+    }
+    (sources > 0).then_some(channels)
+}
+
+/// Sets the NUMA nodes, memory size and memory channels of each socket, from
+/// the CPUs its cores run.
+pub fn set_sockets_memory(sockets: &mut [CPUSocket]) {
+    let nodes = read_nodes(Path::new(NODES_PATH));
+    for socket in sockets {
+        let cpus = socket.cpu_cores.iter().map(|c| c.id).collect::<Vec<_>>();
+        let attached = nodes
+            .iter()
+            .filter(|n| n.cpus.iter().any(|cpu| cpus.contains(cpu)))
+            .collect::<Vec<_>>();
+        socket.numa_nodes = attached.iter().map(|n| n.id).collect();
+        socket.memory_bytes = attached
+            .iter()
+            .map(|n| n.memory_bytes)
+            .sum::<Option<u64>>()
+            .filter(|_| !attached.is_empty());
+        socket.memory_channels = count_memory_channels(Path::new(EVENT_SOURCES_PATH), &cpus);
+        debug!(
+            "Socket {} has NUMA nodes {:?}, {:?} bytes of memory and {:?} memory channels",
+            socket.id, socket.numa_nodes, socket.memory_bytes, socket.memory_channels
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_numa_nodes() {
+        let dir = std::env::temp_dir().join(format!("scaphandre-numa-{}", std::process::id()));
+        for (node, cpus, memory) in [("node1", "2-3\n", "8"), ("node0", "0-1,4\n", "16")] {
+            fs::create_dir_all(dir.join(node)).unwrap();
+            fs::write(dir.join(node).join("cpulist"), cpus).unwrap();
+            fs::write(
+                dir.join(node).join("meminfo"),
+                format!("Node 0 MemTotal:       {memory} kB\nNode 0 MemFree:       4 kB\n"),
+            )
+            .unwrap();
+        }
+        fs::create_dir_all(dir.join("power")).unwrap();
+        fs::create_dir_all(dir.join("uncore_imc_0")).unwrap();
+        fs::write(dir.join("uncore_imc_0").join("cpumask"), "0,2\n").unwrap();
+        fs::create_dir_all(dir.join("uncore_imc_1")).unwrap();
+        fs::write(dir.join("uncore_imc_1").join("cpumask"), "2\n").unwrap();
+
+        assert_eq!(
+            read_nodes(&dir),
+            vec![
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1, 4],
+                    memory_bytes: Some(16384)
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![2, 3],
+                    memory_bytes: Some(8192)
+                },
+            ]
+        );
+        assert_eq!(count_memory_channels(&dir, &[0, 1, 4]), Some(1));
+        assert_eq!(count_memory_channels(&dir, &[2, 3]), Some(2));
+        assert_eq!(count_memory_channels(&dir.join("node0"), &[0]), None);
+        assert!(read_nodes(&dir.join("missing")).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -1,6 +1,11 @@
+use crate::sensors::counter_file::ReadMode;
+use crate::sensors::cpu_support::CpuSupport;
 use crate::sensors::units::Unit::MicroJoule;
-use crate::sensors::utils::current_system_time_since_epoch;
-use crate::sensors::{CPUSocket, Domain, Record, RecordReader, Sensor, Topology};
+use crate::sensors::utils::{current_system_time_since_epoch, PCI_DEVICES_PATH};
+use crate::sensors::{
+    amd_energy, ina, jetson, msr_linux, numa, uncore, update_period, CPUSocket, Domain, PsysZone,
+    Record, RecordReader, RecordSource, Sensor, Topology, TopologyWarning,
+};
 use procfs::{modules, KernelModule};
 use regex::Regex;
 use std::collections::HashMap;
@@ -12,6 +17,9 @@ use super::units::Unit;
 pub const DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES: u16 = 1;
 pub const DEFAULT_BUFFER_PER_DOMAIN_MAX_KBYTES: u16 = 1;
 
+/// Default folder of the powercap tree.
+pub const POWERCAP_PATH: &str = "/sys/class/powercap";
+
 /// This is a Sensor type that relies on powercap and rapl linux modules
 /// to collect energy consumption from CPU sockets and RAPL domains
 pub struct PowercapRAPLSensor {
@@ -19,6 +27,7 @@ pub struct PowercapRAPLSensor {
     buffer_per_socket_max_kbytes: u16,
     buffer_per_domain_max_kbytes: u16,
     virtual_machine: bool,
+    read_mode: ReadMode,
 }
 
 impl PowercapRAPLSensor {
@@ -28,7 +37,7 @@ impl PowercapRAPLSensor {
         buffer_per_domain_max_kbytes: u16,
         virtual_machine: bool,
     ) -> PowercapRAPLSensor {
-        let mut powercap_path = String::from("/sys/class/powercap");
+        let mut powercap_path = String::from(POWERCAP_PATH);
         if virtual_machine {
             powercap_path = String::from("/var/scaphandre");
             if let Ok(val) = env::var("SCAPHANDRE_POWERCAP_PATH") {
@@ -43,12 +52,139 @@ impl PowercapRAPLSensor {
             buffer_per_socket_max_kbytes,
             buffer_per_domain_max_kbytes,
             virtual_machine,
+            read_mode: ReadMode::default(),
+        }
+    }
+
+    /// Reads the powercap tree from `path` rather than the default one, such as
+    /// a tree passed through to a container by its host.
+    pub fn set_base_path(&mut self, path: &str) {
+        info!("Powercap_rapl path is: {}", path);
+        self.base_path = String::from(path);
+    }
+
+    /// Returns the folder of the powercap tree the sensor reads.
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    /// Sets how the energy counter files are read (see [ReadMode]).
+    pub fn set_read_mode(&mut self, read_mode: ReadMode) {
+        self.read_mode = read_mode;
+    }
+
+    /// Opens the counter files of the sockets, domains and PSYS of `topo`, to be
+    /// read with positioned reads. Counters that can't be opened are read from sysfs.
+    fn open_counter_files(topo: &mut Topology) {
+        for zone in topo.psys_zones.clone() {
+            topo.counter_files
+                .open(&zone.counter_key(), &format!("{}/energy_uj", zone.path));
+        }
+        for socket in topo.get_sockets() {
+            for key in ["source_file", "mmio"] {
+                if let Some(path) = socket.sensor_data.get(key) {
+                    socket.counter_files.open(key, path);
+                }
+            }
+            for domain in socket.get_domains() {
+                for key in ["source_file", "mmio"] {
+                    if let Some(path) = domain.sensor_data.get(key) {
+                        domain.counter_files.open(key, path);
+                    }
+                }
+                if let Some(files) = domain
+                    .sensor_data
+                    .get(amd_energy::SENSOR_DATA_CORE_FILES_KEY)
+                    .cloned()
+                {
+                    for path in files.split(',') {
+                        domain.counter_files.open(path, path);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the value after which the energy counter of each zone of `topo`
+    /// wraps around, from the max_energy_range_uj file next to it.
+    fn read_max_energy_ranges(topo: &mut Topology) {
+        let read = |counter_path: &str| {
+            let folder = counter_path.strip_suffix("/energy_uj")?;
+            let path = format!("{folder}/max_energy_range_uj");
+            match fs::read_to_string(&path) {
+                Ok(range) => range.trim().parse::<u64>().ok(),
+                Err(e) => {
+                    debug!("Couldn't read {path}: {e}");
+                    None
+                }
+            }
+        };
+        for zone in &mut topo.psys_zones {
+            zone.max_energy_range_uj = read(&format!("{}/energy_uj", zone.path));
+        }
+        for socket in topo.get_sockets() {
+            socket.max_energy_range_uj = read(&socket.counter_uj_path);
+            for domain in socket.get_domains() {
+                domain.max_energy_range_uj = read(&domain.counter_uj_path);
+            }
+        }
+    }
+
+    /// Tells if the powercap tree at `base_path` has RAPL zones.
+    pub fn has_rapl_zones(base_path: &str) -> bool {
+        fs::read_dir(base_path).is_ok_and(|entries| {
+            entries
+                .flatten()
+                .any(|e| e.file_name().to_string_lossy().starts_with("intel-rapl"))
+        })
+    }
+
+    /// Returns the folder of the amd_energy hwmon device to read instead of the
+    /// powercap tree at `base_path`, when it has no RAPL zone and the CPU is
+    /// an AMD one.
+    pub fn amd_energy_device(base_path: &str) -> Option<String> {
+        if PowercapRAPLSensor::has_rapl_zones(base_path) || !CpuSupport::detect()?.is_amd() {
+            return None;
         }
+        amd_energy::find(amd_energy::HWMON_PATH)
+    }
+
+    /// Opens the counters of the sockets and domains of `topo`, then adds its
+    /// CPU cores, memory and counter update period.
+    fn complete_topology(&self, mut topo: Topology) -> Topology {
+        PowercapRAPLSensor::read_max_energy_ranges(&mut topo);
+        if self.read_mode == ReadMode::Pread {
+            PowercapRAPLSensor::open_counter_files(&mut topo);
+        }
+        topo.add_cpu_cores();
+        if !self.virtual_machine {
+            topo.detect_integrated_gpu(PCI_DEVICES_PATH);
+        }
+        numa::set_sockets_memory(&mut topo.sockets);
+        // memory traffic is only reported next to the energy of the dram domain
+        if topo
+            .sockets
+            .iter()
+            .any(|s| s.domains.iter().any(|d| d.name == "dram"))
+        {
+            topo.memory_counters = uncore::MemoryCounters::open(&topo.sockets);
+        } else if !topo.sockets.is_empty() {
+            topo.add_warning(TopologyWarning::NoDram);
+        }
+        // counters of a virtual machine are files written by the qemu exporter
+        // of the hypervisor, at its own pace
+        if !self.virtual_machine {
+            topo.counter_update_period = topo
+                .sockets
+                .first()
+                .and_then(|socket| update_period::detect_update_period(socket));
+        }
+        topo
     }
 
     /// Checks if intel_rapl modules are present and activated.
     pub fn check_module() -> Result<String, String> {
-        let modules = modules().unwrap();
+        let modules = modules().map_err(|e| format!("Couldn't list kernel modules: {e}"))?;
         let rapl_modules = modules
             .iter()
             .filter(|(_, v)| {
@@ -80,9 +216,13 @@ impl RecordReader for Topology {
             Ok(psys_record)
         } else {
             let mut total: i128 = 0;
+            // powercap, the msr devices with the msr_linux sensor, amd_energy
+            // or an INA power monitor
+            let mut source = RecordSource::Powercap;
             debug!("Suming socket PKG and DRAM metrics to get host metric");
-            for s in &self.sockets {
+            for s in self.sockets.iter().filter(|s| s.is_enabled()) {
                 if let Ok(r) = s.read_record() {
+                    source = r.source.unwrap_or(source);
                     match r.value.trim().parse::<i128>() {
                         Ok(val) => {
                             total += val;
@@ -93,7 +233,7 @@ impl RecordReader for Topology {
                     }
                 }
                 for d in &s.domains {
-                    if d.name == "dram" {
+                    if d.name == "dram" && d.is_enabled() {
                         if let Ok(dr) = d.read_record() {
                             match dr.value.trim().parse::<i128>() {
                                 Ok(val) => {
@@ -111,31 +251,66 @@ impl RecordReader for Topology {
                 current_system_time_since_epoch(),
                 total.to_string(),
                 Unit::MicroJoule,
-            ))
+            )
+            .with_source(Some(source)))
         }
     }
 }
 impl RecordReader for CPUSocket {
     fn read_record(&self) -> Result<Record, Box<dyn Error>> {
+        if msr_linux::is_msr_counter(&self.sensor_data) {
+            return msr_linux::read_record(&self.sensor_data, &self.counter_files);
+        }
+        if ina::is_ina_counter(&self.sensor_data) {
+            return ina::read_record(&self.sensor_data, self.record_buffer.last());
+        }
+        if jetson::is_jetson_counter(&self.sensor_data) {
+            return jetson::read_record(
+                &self.sensor_data,
+                &self.counter_files,
+                self.record_buffer.last(),
+            );
+        }
+        let source = if self.sensor_data.contains_key(amd_energy::SENSOR_DATA_KEY) {
+            RecordSource::AmdEnergy
+        } else {
+            RecordSource::Powercap
+        };
         let source_file = self.sensor_data.get("source_file").unwrap();
-        match fs::read_to_string(source_file) {
-            Ok(result) => Ok(Record::new(
-                current_system_time_since_epoch(),
-                result,
-                MicroJoule,
-            )),
+        match self.counter_files.read("source_file", source_file) {
+            Ok(result) => Ok(
+                Record::new(current_system_time_since_epoch(), result, MicroJoule)
+                    .with_source(Some(source)),
+            ),
             Err(error) => Err(Box::new(error)),
         }
     }
 }
 impl RecordReader for Domain {
     fn read_record(&self) -> Result<Record, Box<dyn Error>> {
+        if msr_linux::is_msr_counter(&self.sensor_data) {
+            return msr_linux::read_record(&self.sensor_data, &self.counter_files);
+        }
+        if amd_energy::is_core_sum(&self.sensor_data) {
+            return amd_energy::read_core_record(&self.sensor_data, &self.counter_files);
+        }
+        if ina::is_ina_counter(&self.sensor_data) {
+            return ina::read_record(&self.sensor_data, self.record_buffer.last());
+        }
+        if jetson::is_jetson_counter(&self.sensor_data) {
+            return jetson::read_record(
+                &self.sensor_data,
+                &self.counter_files,
+                self.record_buffer.last(),
+            );
+        }
         let source_file = self.sensor_data.get("source_file").unwrap();
-        match fs::read_to_string(source_file) {
+        match self.counter_files.read("source_file", source_file) {
             Ok(result) => Ok(Record {
                 timestamp: current_system_time_since_epoch(),
                 unit: MicroJoule,
                 value: result,
+                source: Some(RecordSource::Powercap),
             }),
             Err(error) => Err(Box::new(error)),
         }
@@ -145,11 +320,29 @@ impl RecordReader for Domain {
 impl Sensor for PowercapRAPLSensor {
     /// Creates a Topology instance.
     fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topo = Topology::new(HashMap::new());
         let modules_state = PowercapRAPLSensor::check_module();
         if modules_state.is_err() && !self.virtual_machine {
-            warn!("Couldn't find intel_rapl modules.");
+            topo.add_warning(TopologyWarning::NoRaplModule);
+        }
+        let amd_energy_device = if self.virtual_machine {
+            None
+        } else {
+            PowercapRAPLSensor::amd_energy_device(&self.base_path)
+        };
+        if let Some(hwmon_path) = amd_energy_device {
+            info!(
+                "No RAPL zone in {}, reading the AMD energy counters of {hwmon_path}",
+                self.base_path
+            );
+            amd_energy::add_to_topology(
+                &mut topo,
+                &hwmon_path,
+                self.buffer_per_socket_max_kbytes,
+                self.buffer_per_domain_max_kbytes,
+            )?;
+            return Ok(self.complete_topology(topo));
         }
-        let mut topo = Topology::new(HashMap::new());
         let re_socket = Regex::new(r"^.*/intel-rapl:\d+$").unwrap();
         let re_domain = Regex::new(r"^.*/intel-rapl:\d+:\d+$").unwrap();
         let re_socket_mmio = Regex::new(r"^.*/intel-rapl-mmio:\d+$").unwrap();
@@ -171,6 +364,10 @@ impl Sensor for PowercapRAPLSensor {
                     String::from("source_file"),
                     format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
                 );
+                sensor_data_for_socket.insert(
+                    String::from("enabled_file"),
+                    format!("{}/intel-rapl:{}/enabled", self.base_path, socket_id),
+                );
                 topo.safe_add_socket(
                     socket_id,
                     vec![],
@@ -187,6 +384,10 @@ impl Sensor for PowercapRAPLSensor {
                         self.base_path, socket_id, domain_id
                     ),
                 );
+                sensor_data_for_domain.insert(
+                    String::from("enabled_file"),
+                    format!("{folder_name}/enabled"),
+                );
                 if let Ok(domain_name) = &fs::read_to_string(format!("{folder_name}/name")) {
                     topo.safe_add_domain_to_socket(
                         socket_id,
@@ -235,13 +436,12 @@ impl Sensor for PowercapRAPLSensor {
             }
         }
         if !re_domain_matched {
-            warn!("Couldn't find domain folders from powercap. Fallback on socket folders.");
-            warn!("Scaphandre will not be able to provide per-domain data.");
+            topo.add_warning(TopologyWarning::SocketFallback);
             let mut found = false;
             for folder in fs::read_dir(&self.base_path).unwrap() {
                 let folder_name = String::from(folder.unwrap().path().to_str().unwrap());
                 if let Ok(domain_name) = &fs::read_to_string(format!("{folder_name}/name")) {
-                    if domain_name != "psys" && re_socket.is_match(&folder_name) {
+                    if domain_name.trim() != "psys" && re_socket.is_match(&folder_name) {
                         let mut splitted = folder_name.split(':');
                         let _ = splitted.next();
                         let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
@@ -250,6 +450,10 @@ impl Sensor for PowercapRAPLSensor {
                             String::from("source_file"),
                             format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
                         );
+                        sensor_data_for_socket.insert(
+                            String::from("enabled_file"),
+                            format!("{}/intel-rapl:{}/enabled", self.base_path, socket_id),
+                        );
                         topo.safe_add_socket(
                             socket_id,
                             vec![],
@@ -265,7 +469,7 @@ impl Sensor for PowercapRAPLSensor {
                 }
             }
             if !found {
-                warn!("Could'nt find any RAPL PKG domain (nor psys).");
+                topo.add_warning(TopologyWarning::NoRaplPackage);
             }
         }
         for folder in fs::read_dir(&self.base_path).unwrap() {
@@ -274,8 +478,14 @@ impl Sensor for PowercapRAPLSensor {
                 Ok(domain_name) => {
                     let domain_name_trimed = domain_name.trim();
                     if domain_name_trimed == "psys" {
-                        debug!("Found PSYS domain RAPL folder.");
-                        topo._sensor_data.insert(String::from("psys"), folder_name);
+                        debug!("Found PSYS domain RAPL folder: {folder_name}");
+                        let name = folder_name.rsplit('/').next().unwrap_or_default();
+                        topo.psys_zones.push(PsysZone {
+                            name: String::from(name),
+                            path: folder_name.clone(),
+                            enabled: None,
+                            max_energy_range_uj: None,
+                        });
                     }
                 }
                 Err(e) => {
@@ -283,8 +493,9 @@ impl Sensor for PowercapRAPLSensor {
                 }
             }
         }
-        topo.add_cpu_cores();
-        Ok(topo)
+        // some platforms have a PSYS zone per package, read_dir gives them in no order
+        topo.psys_zones.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(self.complete_topology(topo))
     }
 
     /// Instanciates Topology object if not existing and returns it
@@ -0,0 +1,416 @@
+//! # PowermetricsSensor
+//!
+//! Sensor for macOS, on Apple Silicon and Intel Macs. Neither gives access to
+//! RAPL the way Linux and Windows do: Apple Silicon publishes the energy of
+//! its CPU, GPU and neural engine (ANE) through IOReport, Intel Macs the
+//! energy of their package through the SMC. `powermetrics`, which comes with
+//! macOS, reads both. The sensor keeps one `powermetrics --samplers cpu_power`
+//! running in the background and integrates the power of each of its samples
+//! into energy counters:
+//!
+//! - the socket is the SoC on Apple Silicon (`Combined Power`), the package
+//!   on Intel (`Intel energy model derived package power`),
+//! - the domains are `cpu`, `gpu` and `ane` on Apple Silicon, none on Intel,
+//! - the host is the socket.
+//!
+//! powermetrics only runs as root.
+use crate::sensors::units::Unit::MicroJoule;
+use crate::sensors::utils::current_system_time_since_epoch;
+use crate::sensors::{CPUSocket, Domain, Record, RecordReader, RecordSource, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Command sampling the power of the host.
+pub const POWERMETRICS: &str = "powermetrics";
+
+/// Default interval between two samples of powermetrics.
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_millis(1000);
+
+pub const DEFAULT_BUFFER_PER_SOCKET_MAX_KBYTES: u16 = 1;
+pub const DEFAULT_BUFFER_PER_DOMAIN_MAX_KBYTES: u16 = 1;
+
+/// Time to wait before starting powermetrics again when it stopped.
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Energy counters integrated from the samples of powermetrics, in microjoules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Counters {
+    /// Energy of the SoC or the package
+    pub package: f64,
+    /// Energy of each domain, by name, in the order powermetrics gives them
+    pub domains: Vec<(String, f64)>,
+}
+
+impl Counters {
+    /// Adds the energy spent over `sample`.
+    pub fn add(&mut self, sample: &Sample) {
+        let seconds = sample.elapsed.as_secs_f64();
+        self.package += sample.package_watts() * seconds * 1e6;
+        for (name, watts) in &sample.domains_watts {
+            match self.domains.iter_mut().find(|(domain, _)| domain == name) {
+                Some((_, microjoules)) => *microjoules += watts * seconds * 1e6,
+                None => self.domains.push((name.clone(), watts * seconds * 1e6)),
+            }
+        }
+    }
+}
+
+/// State of the powermetrics running in the background.
+struct Sampler {
+    interval: Duration,
+    counters: Option<Counters>,
+    last_sample: Option<Instant>,
+    error: Option<String>,
+}
+
+static SAMPLER: Mutex<Option<Sampler>> = Mutex::new(None);
+
+/// Power measured over a sample of powermetrics.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sample {
+    /// Duration of the sample
+    pub elapsed: Duration,
+    /// Power of the SoC or the package, in watts, if given
+    pub package_watts: Option<f64>,
+    /// Power of each domain, in watts
+    pub domains_watts: Vec<(String, f64)>,
+}
+
+impl Sample {
+    /// Power of the SoC or the package, in watts: the sum of the domains if
+    /// powermetrics doesn't give it.
+    pub fn package_watts(&self) -> f64 {
+        self.package_watts
+            .unwrap_or_else(|| self.domains_watts.iter().map(|(_, watts)| watts).sum())
+    }
+}
+
+/// Parses a value in watts or milliwatts, as `1234 mW` or `3.52W`.
+fn parse_watts(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if let Some(milliwatts) = value.strip_suffix("mW") {
+        milliwatts.trim().parse::<f64>().ok().map(|mw| mw / 1000.0)
+    } else {
+        value.strip_suffix('W')?.trim().parse::<f64>().ok()
+    }
+}
+
+/// Builds the samples from the output of powermetrics, line by line.
+#[derive(Debug, Default)]
+pub struct SampleParser {
+    current: Option<Sample>,
+}
+
+impl SampleParser {
+    /// Reads a line of powermetrics, returns the sample it completes if any.
+    pub fn feed(&mut self, line: &str) -> Option<Sample> {
+        let line = line.trim();
+        // *** Sampled system activity (Sat Oct 18 10:00:00 2026 +0200) (1003.52ms elapsed) ***
+        if line.starts_with("*** Sampled system activity") {
+            let elapsed = line
+                .rsplit_once("ms elapsed)")
+                .and_then(|(start, _)| start.rsplit_once('('))
+                .and_then(|(_, ms)| ms.trim().parse::<f64>().ok())?;
+            let previous = self.current.replace(Sample {
+                elapsed: Duration::from_secs_f64(elapsed / 1000.0),
+                ..Default::default()
+            });
+            // a sample without its package line is complete when the next one starts
+            return previous.filter(|s| !s.domains_watts.is_empty());
+        }
+        let (name, value) = line.split_once(':')?;
+        let watts = parse_watts(value)?;
+        let sample = self.current.as_mut()?;
+        let domain = match name {
+            "CPU Power" => "cpu",
+            "GPU Power" => "gpu",
+            "ANE Power" => "ane",
+            _ if name.starts_with("Combined Power")
+                || name.starts_with("Intel energy model derived package power") =>
+            {
+                sample.package_watts = Some(watts);
+                return self.current.take();
+            }
+            _ => return None,
+        };
+        // the GPU sampler gives the GPU power again
+        if !sample.domains_watts.iter().any(|(d, _)| d == domain) {
+            sample.domains_watts.push((String::from(domain), watts));
+        }
+        None
+    }
+}
+
+/// Runs powermetrics until it stops, adding its samples to the counters.
+fn run_powermetrics(interval: Duration) -> Result<(), String> {
+    let mut child = Command::new(POWERMETRICS)
+        .args(["--samplers", "cpu_power", "--buffer-size", "1", "-i"])
+        .arg(interval.as_millis().to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("couldn't run {POWERMETRICS}: {e}"))?;
+    let stdout = child.stdout.take().ok_or("no output from powermetrics")?;
+    let mut parser = SampleParser::default();
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if let Some(sample) = parser.feed(&line) {
+            trace!("powermetrics sample: {sample:?}");
+            let mut sampler = SAMPLER.lock().unwrap();
+            if let Some(sampler) = sampler.as_mut() {
+                sampler
+                    .counters
+                    .get_or_insert_with(Counters::default)
+                    .add(&sample);
+                sampler.last_sample = Some(Instant::now());
+                sampler.error = None;
+            }
+        }
+    }
+    let mut stderr = String::new();
+    if let Some(mut err) = child.stderr.take() {
+        let _ = err.read_to_string(&mut stderr);
+    }
+    let status = child.wait().map_err(|e| e.to_string())?;
+    Err(format!(
+        "{POWERMETRICS} stopped ({status}): {}",
+        stderr.trim()
+    ))
+}
+
+/// Starts powermetrics in the background, sampling every `interval`, if it
+/// isn't running yet.
+pub fn start_sampler(interval: Duration) {
+    let mut sampler = SAMPLER.lock().unwrap();
+    if sampler.is_some() {
+        return;
+    }
+    *sampler = Some(Sampler {
+        interval,
+        counters: None,
+        last_sample: None,
+        error: None,
+    });
+    std::thread::spawn(move || loop {
+        if let Err(e) = run_powermetrics(interval) {
+            warn!("{e}, running it again in {}s", RESTART_DELAY.as_secs());
+            if let Some(sampler) = SAMPLER.lock().unwrap().as_mut() {
+                sampler.error = Some(e);
+            }
+        }
+        std::thread::sleep(RESTART_DELAY);
+    });
+}
+
+/// Returns the energy counters, or why there are none: powermetrics not
+/// started, or not sampling anymore.
+pub fn read_counters() -> Result<Counters, String> {
+    let sampler = SAMPLER.lock().unwrap();
+    let sampler = sampler.as_ref().ok_or("powermetrics isn't running")?;
+    let max_age = (sampler.interval * 5).max(Duration::from_secs(10));
+    match (&sampler.counters, sampler.last_sample) {
+        (Some(counters), Some(last)) if last.elapsed() <= max_age => Ok(counters.clone()),
+        _ => Err(sampler
+            .error
+            .clone()
+            .unwrap_or_else(|| format!("no sample from {POWERMETRICS} for {max_age:?}"))),
+    }
+}
+
+fn record(microjoules: f64) -> Record {
+    Record::new(
+        current_system_time_since_epoch(),
+        format!("{microjoules:.0}"),
+        MicroJoule,
+    )
+    .with_source(Some(RecordSource::Powermetrics))
+}
+
+impl RecordReader for Topology {
+    fn read_record(&self) -> Result<Record, Box<dyn Error>> {
+        Ok(record(read_counters()?.package))
+    }
+}
+
+impl RecordReader for CPUSocket {
+    fn read_record(&self) -> Result<Record, Box<dyn Error>> {
+        Ok(record(read_counters()?.package))
+    }
+}
+
+impl RecordReader for Domain {
+    fn read_record(&self) -> Result<Record, Box<dyn Error>> {
+        let counters = read_counters()?;
+        let (_, microjoules) = counters
+            .domains
+            .iter()
+            .find(|(name, _)| *name == self.name)
+            .ok_or_else(|| format!("no {} power in the samples of powermetrics", self.name))?;
+        Ok(record(*microjoules))
+    }
+}
+
+/// This is a Sensor type that reads the power of a Mac from powermetrics.
+pub struct PowermetricsSensor {
+    interval: Duration,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl PowermetricsSensor {
+    /// Instantiates and returns an instance of PowermetricsSensor, sampling
+    /// the power every `interval`.
+    pub fn new(
+        interval: Duration,
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+    ) -> PowermetricsSensor {
+        PowermetricsSensor {
+            interval,
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        }
+    }
+}
+
+impl Sensor for PowermetricsSensor {
+    /// Starts powermetrics and waits for its first sample, which tells the
+    /// domains of the host.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        start_sampler(self.interval);
+        let deadline = Instant::now() + self.interval * 3 + Duration::from_secs(2);
+        let counters = loop {
+            match read_counters() {
+                Ok(counters) => break counters,
+                Err(e) if Instant::now() > deadline => {
+                    return Err(format!("{e}, is scaphandre running as root?").into())
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        let mut topology = Topology::new(HashMap::new());
+        topology.safe_add_socket(
+            0,
+            vec![],
+            vec![],
+            String::from(POWERMETRICS),
+            self.buffer_per_socket_max_kbytes,
+            HashMap::new(),
+        );
+        for (id, (name, _)) in (0u16..).zip(&counters.domains) {
+            topology.safe_add_domain_to_socket(
+                0,
+                id,
+                name,
+                POWERMETRICS,
+                self.buffer_per_domain_max_kbytes,
+                HashMap::new(),
+            );
+        }
+        Ok(topology)
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        match self.generate_topology() {
+            Ok(topology) => Box::new(Some(topology)),
+            Err(e) => panic!("Couldn't generate the topology: {e}"),
+        }
+    }
+
+    /// Measurements are read from a powermetrics process.
+    fn runs_commands(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_samples() {
+        let apple_silicon = "\
+*** Sampled system activity (Sat Oct 17 10:00:00 2026 +0200) (1003.52ms elapsed) ***
+
+**** Processor usage ****
+
+E-Cluster HW active frequency: 1020 MHz
+CPU 0 frequency: 1037 MHz
+
+CPU Power: 1200 mW
+GPU Power: 40 mW
+ANE Power: 0 mW
+Combined Power (CPU + GPU + ANE): 1240 mW
+
+**** GPU usage ****
+
+GPU HW active frequency: 389 MHz
+GPU Power: 41 mW
+*** Sampled system activity (Sat Oct 17 10:00:01 2026 +0200) (2000ms elapsed) ***
+CPU Power: 500 mW
+";
+        let mut parser = SampleParser::default();
+        let samples: Vec<Sample> = apple_silicon
+            .lines()
+            .filter_map(|line| parser.feed(line))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![Sample {
+                elapsed: Duration::from_secs_f64(1.00352),
+                package_watts: Some(1.24),
+                domains_watts: vec![
+                    (String::from("cpu"), 1.2),
+                    (String::from("gpu"), 0.04),
+                    (String::from("ane"), 0.0)
+                ],
+            }]
+        );
+        let last = parser
+            .feed(
+                "*** Sampled system activity (Sat Oct 17 10:00:03 2026 +0200) (1000ms elapsed) ***",
+            )
+            .unwrap();
+        assert_eq!(last.package_watts(), 0.5);
+
+        let mut counters = Counters::default();
+        counters.add(&last);
+        counters.add(&samples[0]);
+        assert_eq!(counters.package.round(), 2_244_365.0);
+        assert_eq!(counters.domains[0].0, "cpu");
+        assert_eq!(counters.domains[0].1.round(), 2_204_224.0);
+        assert_eq!(counters.domains.len(), 3);
+
+        let mut parser = SampleParser::default();
+        parser.feed(
+            "*** Sampled system activity (Sat Oct 17 10:00:00 2026 +0200) (500.0ms elapsed) ***",
+        );
+        let intel = parser
+            .feed("Intel energy model derived package power (CPUs+GT+SA): 3.52W")
+            .unwrap();
+        assert_eq!(intel.package_watts, Some(3.52));
+        assert!(intel.domains_watts.is_empty());
+        assert_eq!(parse_watts(" 12 mW"), Some(0.012));
+        assert_eq!(parse_watts("n/a"), None);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,129 @@
+//! # Pressure: stall information of the host (PSI)
+//!
+//! Since Linux 4.20, `/proc/pressure/{cpu,io,memory}` tell the share of time
+//! during which some (or all) non-idle tasks were stalled waiting for a
+//! resource. Read next to the power, they tell a host that is busy doing work
+//! from one that burns energy thrashing on memory or waiting on disks.
+//!
+//! Each file has one line per kind of stall:
+//!
+//! ```text
+//! some avg10=0.31 avg60=0.12 avg300=0.05 total=1234567
+//! full avg10=0.00 avg60=0.00 avg300=0.00 total=7654
+//! ```
+//!
+//! where the averages are percentages over the last 10, 60 and 300 seconds
+//! and `total` is the cumulated stall time, in microseconds.
+use std::fs;
+
+/// Resources the kernel reports stall information for.
+pub const PRESSURE_RESOURCES: [&str; 3] = ["cpu", "io", "memory"];
+
+/// Stall information of one kind (`some` or `full`) for one resource.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pressure {
+    /// `cpu`, `io` or `memory`
+    pub resource: String,
+    /// `some` when at least one task was stalled, `full` when all were
+    pub kind: String,
+    /// Share of time stalled over the last 10, 60 and 300 seconds, from 0 to 1
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    /// Cumulated stall time, in microseconds
+    pub total: u64,
+}
+
+/// Parses the content of a `/proc/pressure/<resource>` file. Malformed lines
+/// are skipped.
+pub fn parse_pressure(resource: &str, content: &str) -> Vec<Pressure> {
+    let mut res = vec![];
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = match fields.next() {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let mut pressure = Pressure {
+            resource: String::from(resource),
+            kind: String::from(kind),
+            avg10: 0.0,
+            avg60: 0.0,
+            avg300: 0.0,
+            total: 0,
+        };
+        let mut valid = true;
+        for (key, value) in fields.filter_map(|f| f.split_once('=')) {
+            if key == "total" {
+                match value.parse() {
+                    Ok(total) => pressure.total = total,
+                    Err(_) => valid = false,
+                }
+                continue;
+            }
+            let avg = match key {
+                "avg10" => &mut pressure.avg10,
+                "avg60" => &mut pressure.avg60,
+                "avg300" => &mut pressure.avg300,
+                _ => continue,
+            };
+            match value.parse::<f64>() {
+                Ok(percent) => *avg = percent / 100.0,
+                Err(_) => valid = false,
+            }
+        }
+        if valid {
+            res.push(pressure);
+        } else {
+            debug!("Skipping malformed line of /proc/pressure/{resource}: {line}");
+        }
+    }
+    res
+}
+
+/// Reads the stall information of all resources. Returns an empty vector on
+/// kernels without PSI, or when it is disabled (`psi=0`).
+pub fn read_pressure() -> Vec<Pressure> {
+    let mut res = vec![];
+    for resource in PRESSURE_RESOURCES {
+        if let Ok(content) = fs::read_to_string(format!("/proc/pressure/{resource}")) {
+            res.append(&mut parse_pressure(resource, &content));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pressure_file() {
+        let content = "some avg10=1.50 avg60=0.25 avg300=0.00 total=1234567\n\
+                       full avg10=0.00 avg60=0.00 avg300=0.00 total=7654\n\
+                       bogus avg10=abc\n";
+        let res = parse_pressure("memory", content);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].resource, "memory");
+        assert_eq!(res[0].kind, "some");
+        assert_eq!(res[0].avg10, 0.015);
+        assert_eq!(res[0].avg60, 0.0025);
+        assert_eq!(res[0].total, 1234567);
+        assert_eq!(res[1].kind, "full");
+        assert_eq!(res[1].total, 7654);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,147 @@
+//! # Raw reads: dump of the energy counters read by the sensors
+//!
+//! When a host reports weird values, the first question is what the hardware
+//! returned. With `--debug-raw DESTINATION`, each read of an energy counter is
+//! written to DESTINATION (`stderr`, or a file appended to) as a line of JSON:
+//!
+//! ```text
+//! {"timestamp":1700000000.123456,"source":"/sys/class/powercap/intel-rapl:0/energy_uj","value":"123456789\n"}
+//! ```
+//!
+//! `source` is the path of the counter file, or `msr:<address>:<core>` for
+//! the MSRs read through the Windows driver, and `value` what was read, before
+//! any parsing. Reads are dumped for a bounded duration only, as there are
+//! several of them at each measurement, after which the sensors read the
+//! counters as usual.
+use super::utils::current_system_time_since_epoch;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Destination writing the reads to the standard error.
+pub const STDERR_DESTINATION: &str = "stderr";
+
+/// Default duration reads are dumped for, in seconds.
+pub const DEFAULT_DURATION_SECONDS: u64 = 300;
+
+struct Dump {
+    writer: Box<dyn Write + Send>,
+    until: Instant,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DUMP: Mutex<Option<Dump>> = Mutex::new(None);
+
+/// Starts dumping the raw reads to `destination` for `duration`.
+pub fn enable(destination: &str, duration: Duration) -> Result<(), String> {
+    let writer: Box<dyn Write + Send> = if destination == STDERR_DESTINATION {
+        Box::new(io::stderr())
+    } else {
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(destination)
+                .map_err(|e| format!("can't open {destination}: {e}"))?,
+        )
+    };
+    *DUMP.lock().unwrap() = Some(Dump {
+        writer,
+        until: Instant::now() + duration,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    info!(
+        "Dumping raw counter reads to {destination} for {} s",
+        duration.as_secs()
+    );
+    Ok(())
+}
+
+/// Appends `text` to `line` as a JSON string.
+fn push_json_string(line: &mut String, text: &str) {
+    line.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            c if (c as u32) < 0x20 => line.push_str(&format!("\\u{:04x}", c as u32)),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// Returns the JSON line describing a read of `source`, ending with a new line.
+pub fn format_read(timestamp: Duration, source: &str, value: &str) -> String {
+    let mut line = format!(
+        "{{\"timestamp\":{}.{:06},\"source\":",
+        timestamp.as_secs(),
+        timestamp.subsec_micros()
+    );
+    push_json_string(&mut line, source);
+    line.push_str(",\"value\":");
+    push_json_string(&mut line, value);
+    line.push_str("}\n");
+    line
+}
+
+/// Dumps a read of `source`, if the dump is enabled.
+pub fn record(source: &str, value: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut dump = DUMP.lock().unwrap();
+    let stop = match dump.as_mut() {
+        Some(dump) if Instant::now() < dump.until => {
+            let line = format_read(current_system_time_since_epoch(), source, value);
+            if let Err(e) = dump.writer.write_all(line.as_bytes()) {
+                warn!("Couldn't dump a raw counter read, stopping: {e}");
+                true
+            } else {
+                false
+            }
+        }
+        _ => true,
+    };
+    if stop {
+        ENABLED.store(false, Ordering::Relaxed);
+        *dump = None;
+        info!("Stopped dumping raw counter reads");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_read_line() {
+        assert_eq!(
+            format_read(
+                Duration::from_micros(1_700_000_000_000_042),
+                "/sys/class/powercap/intel-rapl:0/energy_uj",
+                "123456\n"
+            ),
+            "{\"timestamp\":1700000000.000042,\"source\":\"/sys/class/powercap/intel-rapl:0/energy_uj\",\"value\":\"123456\\n\"}\n"
+        );
+        assert!(format_read(Duration::ZERO, "a\"b", "\u{1}")
+            .contains("\"a\\\"b\",\"value\":\"\\u0001\""));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,435 @@
+//! # Shared sensor
+//!
+//! Lets several scaphandre instances run on the same host while only one of
+//! them reads RAPL. The primary instance publishes the energy counters of its
+//! topology on a unix socket (see [publish]), secondary instances use the
+//! [SharedSensor] to build their topology from these counters instead of
+//! reading the hardware themselves.
+//!
+//! Each connection to the socket gets a snapshot of the counters, one per line:
+//!
+//! ```text
+//! host <timestamp> <microjoules>
+//! socket <socket_id> <timestamp> <microjoules>
+//! domain <socket_id> <domain_id> <name> <timestamp> <microjoules>
+//! ```
+//!
+//! Timestamps are in seconds since the epoch, as read by the primary instance.
+//!
+//! Whoever can write where the socket is, a container it is mounted in for
+//! instance, can also publish forged counters. With the signing feature, the
+//! primary instance can sign each snapshot (see [crate::sensors::signing]), and
+//! secondary instances given trusted keys only accept recent snapshots signed
+//! by one of them.
+#[cfg(feature = "signing")]
+use crate::sensors::signing::{Signer, Verifier};
+use crate::sensors::units::Unit;
+use crate::sensors::{Record, RecordGenerator, RecordReader, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+use std::time::Duration;
+
+/// Key of the topology sensor data holding the path of the socket to read counters from.
+pub const SENSOR_DATA_KEY: &str = "shared_socket";
+
+/// Key of the topology sensor data holding the path of the public keys the
+/// snapshots must be signed with.
+#[cfg(feature = "signing")]
+pub const SENSOR_DATA_TRUSTED_KEYS_KEY: &str = "shared_trusted_keys";
+
+/// Maximum time to wait for the primary instance to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum age of the counters of a signed snapshot, so that a snapshot
+/// captured earlier can't be replayed.
+#[cfg(feature = "signing")]
+const MAX_SIGNED_SNAPSHOT_AGE: Duration = Duration::from_secs(60);
+
+/// Energy counters of a topology, as published by the primary instance.
+struct Snapshot {
+    host: Option<Record>,
+    sockets: Vec<(u16, Record)>,
+    domains: Vec<(u16, u16, String, Record)>,
+}
+
+fn format_record(record: &Record) -> String {
+    format!("{} {}", record.timestamp.as_secs_f64(), record.value.trim())
+}
+
+/// Reads the counters of `topology` and returns them in the snapshot format.
+fn format_snapshot(topology: &Topology) -> String {
+    let mut snapshot = String::new();
+    match topology.read_sensor_record() {
+        Ok(record) => snapshot.push_str(&format!("host {}\n", format_record(&record))),
+        Err(e) => warn!("Couldn't read host record to publish: {e}"),
+    }
+    for socket in topology.get_sockets_passive() {
+        if let Ok(record) = socket.read_record() {
+            snapshot.push_str(&format!(
+                "socket {} {}\n",
+                socket.id,
+                format_record(&record)
+            ));
+        }
+        for domain in socket.get_domains_passive() {
+            if let Ok(record) = domain.read_record() {
+                snapshot.push_str(&format!(
+                    "domain {} {} {} {}\n",
+                    socket.id,
+                    domain.id,
+                    domain.name,
+                    format_record(&record)
+                ));
+            }
+        }
+    }
+    snapshot
+}
+
+fn parse_record(timestamp: &str, value: &str) -> Result<Record, String> {
+    let timestamp = timestamp
+        .parse::<f64>()
+        .map_err(|e| format!("invalid timestamp {timestamp}: {e}"))?;
+    value
+        .parse::<u64>()
+        .map_err(|e| format!("invalid counter {value}: {e}"))?;
+    Ok(Record::new(
+        Duration::from_secs_f64(timestamp),
+        String::from(value),
+        Unit::MicroJoule,
+    ))
+}
+
+fn parse_snapshot(text: &str) -> Result<Snapshot, String> {
+    let mut snapshot = Snapshot {
+        host: None,
+        sockets: vec![],
+        domains: vec![],
+    };
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parse_id = |id: &str| {
+            id.parse::<u16>()
+                .map_err(|e| format!("invalid id {id} in line '{line}': {e}"))
+        };
+        match fields.as_slice() {
+            ["host", timestamp, value] => snapshot.host = Some(parse_record(timestamp, value)?),
+            ["socket", socket_id, timestamp, value] => snapshot
+                .sockets
+                .push((parse_id(socket_id)?, parse_record(timestamp, value)?)),
+            ["domain", socket_id, domain_id, name, timestamp, value] => snapshot.domains.push((
+                parse_id(socket_id)?,
+                parse_id(domain_id)?,
+                String::from(*name),
+                parse_record(timestamp, value)?,
+            )),
+            // checked before parsing, when trusted keys are given
+            ["signature", ..] => {}
+            _ => return Err(format!("invalid line '{line}'")),
+        }
+    }
+    Ok(snapshot)
+}
+
+fn read_snapshot(path: &str) -> Result<String, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut text = String::new();
+    stream.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+fn fetch_snapshot(path: &str) -> Result<Snapshot, Box<dyn Error>> {
+    Ok(parse_snapshot(&read_snapshot(path)?)?)
+}
+
+/// Fetches a snapshot and checks that it is signed by one of the keys of
+/// `trusted_keys` and recent enough.
+#[cfg(feature = "signing")]
+fn fetch_signed_snapshot(path: &str, trusted_keys: &str) -> Result<Snapshot, Box<dyn Error>> {
+    let text = read_snapshot(path)?;
+    let payload = Verifier::load(trusted_keys)?
+        .verify(&text)
+        .map_err(|e| format!("rejected energy counters: {e}"))?;
+    let snapshot = parse_snapshot(payload)?;
+    let now = crate::sensors::utils::current_system_time_since_epoch();
+    let oldest = snapshot
+        .host
+        .iter()
+        .chain(snapshot.sockets.iter().map(|(_, r)| r))
+        .chain(snapshot.domains.iter().map(|(_, _, _, r)| r))
+        .map(|r| r.timestamp)
+        .min();
+    match oldest {
+        Some(timestamp) if now.saturating_sub(timestamp) > MAX_SIGNED_SNAPSHOT_AGE => Err(format!(
+            "rejected energy counters read {}s ago, replayed?",
+            now.saturating_sub(timestamp).as_secs()
+        )
+        .into()),
+        _ => Ok(snapshot),
+    }
+}
+
+/// Fetches a snapshot from the socket of the topology sensor data, checking
+/// its signature if trusted keys are given.
+fn fetch_topology_snapshot(topology: &Topology, path: &str) -> Result<Snapshot, Box<dyn Error>> {
+    #[cfg(feature = "signing")]
+    if let Some(trusted_keys) = topology._sensor_data.get(SENSOR_DATA_TRUSTED_KEYS_KEY) {
+        return fetch_signed_snapshot(path, trusted_keys);
+    }
+    let _ = topology;
+    fetch_snapshot(path)
+}
+
+/// Publishes the counters of `topology` on a unix socket at `path`, from a
+/// background thread, for secondary instances using the [SharedSensor].
+pub fn publish(topology: Topology, path: &str) -> std::io::Result<()> {
+    serve(topology, path, Box::new(|snapshot| snapshot))
+}
+
+/// Publishes the counters of `topology` like [publish], each snapshot being
+/// signed by `signer`.
+#[cfg(feature = "signing")]
+pub fn publish_signed(topology: Topology, path: &str, mut signer: Signer) -> std::io::Result<()> {
+    serve(
+        topology,
+        path,
+        Box::new(move |snapshot| signer.sign(&snapshot)),
+    )
+}
+
+/// Serves snapshots of the counters of `topology`, transformed by `seal`, on a
+/// unix socket at `path`.
+fn serve(
+    topology: Topology,
+    path: &str,
+    mut seal: Box<dyn FnMut(String) -> String + Send>,
+) -> std::io::Result<()> {
+    // a socket file left by a previous run would make bind() fail
+    if fs::metadata(path).is_ok() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Publishing energy counters on {path}");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    let snapshot = seal(format_snapshot(&topology));
+                    if let Err(e) = stream.write_all(snapshot.as_bytes()) {
+                        warn!("Couldn't publish energy counters: {e}");
+                    }
+                }
+                Err(e) => warn!("Connection to the shared socket failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stores new records in the topology, its sockets and domains, from the counters
+/// published by the primary instance.
+pub fn refresh_topology_records(topology: &mut Topology) -> Result<(), Box<dyn Error>> {
+    let path = topology
+        ._sensor_data
+        .get(SENSOR_DATA_KEY)
+        .ok_or("no shared socket in topology sensor data")?
+        .clone();
+    let snapshot = fetch_topology_snapshot(topology, &path)?;
+    for (socket_id, record) in snapshot.sockets {
+        if let Some(socket) = topology.sockets.iter_mut().find(|s| s.id == socket_id) {
+            socket.record_buffer.push(record);
+            socket.clean_old_records();
+        }
+    }
+    for (socket_id, domain_id, _, record) in snapshot.domains {
+        if let Some(socket) = topology.sockets.iter_mut().find(|s| s.id == socket_id) {
+            if let Some(domain) = socket.get_domains().iter_mut().find(|d| d.id == domain_id) {
+                domain.record_buffer.push(record);
+                domain.clean_old_records();
+            }
+        }
+    }
+    if let Some(record) = snapshot.host {
+        topology.record_buffer.push(record);
+        topology.clean_old_records();
+    }
+    Ok(())
+}
+
+/// This is a Sensor type that gets the energy counters of the host from
+/// another scaphandre instance, through the unix socket it publishes them on.
+pub struct SharedSensor {
+    path: String,
+    /// File or directory of the public keys the snapshots must be signed with
+    #[cfg(feature = "signing")]
+    trusted_keys: Option<String>,
+    buffer_per_socket_max_kbytes: u16,
+    buffer_per_domain_max_kbytes: u16,
+}
+
+impl SharedSensor {
+    /// Instantiates and returns an instance of SharedSensor, reading the
+    /// counters published on the unix socket at `path`.
+    pub fn new(
+        path: &str,
+        buffer_per_socket_max_kbytes: u16,
+        buffer_per_domain_max_kbytes: u16,
+    ) -> SharedSensor {
+        SharedSensor {
+            path: String::from(path),
+            #[cfg(feature = "signing")]
+            trusted_keys: None,
+            buffer_per_socket_max_kbytes,
+            buffer_per_domain_max_kbytes,
+        }
+    }
+
+    /// Only accepts snapshots signed by one of the public keys of `path`, a
+    /// .pub file or a directory of them.
+    #[cfg(feature = "signing")]
+    pub fn with_trusted_keys(mut self, path: &str) -> SharedSensor {
+        self.trusted_keys = Some(String::from(path));
+        self
+    }
+}
+
+impl Sensor for SharedSensor {
+    /// Creates a Topology with the sockets and domains published by the primary instance.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from(SENSOR_DATA_KEY), self.path.clone());
+        #[cfg(feature = "signing")]
+        if let Some(trusted_keys) = &self.trusted_keys {
+            sensor_data.insert(
+                String::from(SENSOR_DATA_TRUSTED_KEYS_KEY),
+                trusted_keys.clone(),
+            );
+        }
+        let mut topo = Topology::new(sensor_data);
+        let snapshot = fetch_topology_snapshot(&topo, &self.path)?;
+        for (socket_id, _) in &snapshot.sockets {
+            topo.safe_add_socket(
+                *socket_id,
+                vec![],
+                vec![],
+                String::new(),
+                self.buffer_per_socket_max_kbytes,
+                HashMap::new(),
+            );
+        }
+        for (socket_id, domain_id, name, _) in &snapshot.domains {
+            topo.safe_add_domain_to_socket(
+                *socket_id,
+                *domain_id,
+                name,
+                "",
+                self.buffer_per_domain_max_kbytes,
+                HashMap::new(),
+            );
+        }
+        topo.add_cpu_cores();
+        Ok(topo)
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        match self.generate_topology() {
+            Ok(topology) => Box::new(Some(topology)),
+            Err(e) => panic!("Couldn't get energy counters from {}: {e}", self.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_snapshots() {
+        let snapshot = parse_snapshot(
+            "host 1700000000.5 123456789\n\
+             socket 0 1700000000.5 100000000\n\
+             domain 0 1 dram 1700000000.5 20000000\n",
+        )
+        .unwrap();
+        assert_eq!(
+            snapshot.host.unwrap().timestamp,
+            Duration::from_secs_f64(1700000000.5)
+        );
+        assert_eq!(snapshot.sockets[0].0, 0);
+        assert_eq!(snapshot.sockets[0].1.value, "100000000");
+        assert_eq!(snapshot.domains[0].2, "dram");
+        assert!(parse_snapshot("socket 0 1700000000.5").is_err());
+        assert!(parse_snapshot("host 1700000000.5 -12").is_err());
+        assert!(parse_snapshot("cpu 0 1700000000.5 12").is_err());
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let record = Record::new(
+            Duration::from_secs_f64(1700000000.25),
+            String::from("42000000\n"),
+            Unit::MicroJoule,
+        );
+        let line = format!("host {}", format_record(&record));
+        let snapshot = parse_snapshot(&line).unwrap();
+        let host = snapshot.host.unwrap();
+        assert_eq!(host.timestamp, record.timestamp);
+        assert_eq!(host.value, "42000000");
+    }
+
+    #[test]
+    fn publish_and_fetch() {
+        let path =
+            std::env::temp_dir().join(format!("scaphandre-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        publish(Topology::new(HashMap::new()), path).unwrap();
+        let snapshot = fetch_snapshot(path).unwrap();
+        assert!(snapshot.host.is_some());
+        assert!(snapshot.sockets.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn publish_signed_and_fetch() {
+        let dir = std::env::temp_dir().join(format!("scaphandre-shared-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("primary").to_str().unwrap().to_string();
+        let trusted = dir.join("trusted");
+        fs::create_dir_all(&trusted).unwrap();
+        crate::sensors::signing::generate_key(&key).unwrap();
+
+        let path = dir.join("counters.sock");
+        let path = path.to_str().unwrap();
+        let signer = Signer::load(&key).unwrap();
+        publish_signed(Topology::new(HashMap::new()), path, signer).unwrap();
+        let trusted_keys = trusted.to_str().unwrap();
+        assert!(fetch_signed_snapshot(path, trusted_keys).is_err());
+        fs::copy(format!("{key}.pub"), trusted.join("primary.pub")).unwrap();
+        let snapshot = fetch_signed_snapshot(path, trusted_keys).unwrap();
+        assert!(snapshot.host.is_some());
+        // secondary instances without trusted keys ignore the signature
+        assert!(fetch_snapshot(path).unwrap().host.is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,272 @@
+//! # Signing: ed25519 signatures of the payloads exchanged between instances
+//!
+//! When the energy counters of a host are read by another scaphandre instance
+//! (see [crate::sensors::shared]), whoever can write where the payload is
+//! read from can also forge it. The publishing instance can sign each payload
+//! with its secret key, so that the reading instance only accepts payloads
+//! signed by one of the public keys it trusts.
+//!
+//! A signed payload is the payload followed by a last line:
+//!
+//! ```text
+//! signature <key_id> <signature>
+//! ```
+//!
+//! the signature (hex encoded) covering every byte before that line, and the
+//! key id being the first 16 hex digits of the public key.
+//!
+//! Keys are files holding 32 hex encoded bytes, written by
+//! `scaphandre generate-signing-key`. To rotate a key without losing any
+//! payload, add the new public key to the trusted keys of the readers, replace
+//! the secret key of the publisher (reloaded when its file changes), then
+//! remove the old public key.
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Prefix of the line holding the signature of a payload.
+const SIGNATURE_PREFIX: &str = "signature ";
+
+/// Extension of public key files, in a directory of trusted keys.
+const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N], String> {
+    let hex = hex.trim();
+    if hex.len() != 2 * N || !hex.is_ascii() {
+        return Err(format!("expected {} hex digits", 2 * N));
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|e| format!("invalid hex digits: {e}"))?;
+    }
+    Ok(bytes)
+}
+
+/// Returns the id of a public key: its first 16 hex digits.
+pub fn key_id(key: &VerifyingKey) -> String {
+    to_hex(&key.as_bytes()[..8])
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let hex = fs::read_to_string(path).map_err(|e| format!("can't read {path:?}: {e}"))?;
+    from_hex(&hex)
+        .map(|bytes| SigningKey::from_bytes(&bytes))
+        .map_err(|e| format!("invalid secret key in {path:?}: {e}"))
+}
+
+fn read_verifying_key(path: &Path) -> Result<VerifyingKey, String> {
+    let hex = fs::read_to_string(path).map_err(|e| format!("can't read {path:?}: {e}"))?;
+    from_hex(&hex)
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string()))
+        .map_err(|e| format!("invalid public key in {path:?}: {e}"))
+}
+
+/// Generates a new key pair, writing the secret key to `path` (readable by its
+/// owner only) and the public key to `path` with a .pub extension added.
+/// Returns the id of the key.
+pub fn generate_key(path: &str) -> Result<String, String> {
+    let mut bytes = [0; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let key = SigningKey::from_bytes(&bytes);
+    let public_path = format!("{path}.{PUBLIC_KEY_EXTENSION}");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("can't create {path}: {e}"))?;
+    std::io::Write::write_all(&mut file, format!("{}\n", to_hex(&bytes)).as_bytes())
+        .map_err(|e| format!("can't write {path}: {e}"))?;
+    fs::write(
+        &public_path,
+        format!("{}\n", to_hex(key.verifying_key().as_bytes())),
+    )
+    .map_err(|e| format!("can't write {public_path}: {e}"))?;
+    Ok(key_id(&key.verifying_key()))
+}
+
+/// Signs payloads with the secret key of a file, reloaded when it changes.
+pub struct Signer {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    key: SigningKey,
+}
+
+impl Signer {
+    pub fn load(path: &str) -> Result<Signer, String> {
+        let path = PathBuf::from(path);
+        Ok(Signer {
+            key: read_signing_key(&path)?,
+            modified: modified(&path),
+            path,
+        })
+    }
+
+    /// Reloads the key if its file changed. A file that can't be read, while
+    /// being replaced for instance, keeps the previous key in use.
+    fn reload_if_changed(&mut self) {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return;
+        }
+        match read_signing_key(&self.path) {
+            Ok(key) => {
+                info!(
+                    "Signing with the new key {} of {:?}",
+                    key_id(&key.verifying_key()),
+                    self.path
+                );
+                self.key = key;
+                self.modified = modified;
+            }
+            Err(e) => warn!("Keeping the previous signing key: {e}"),
+        }
+    }
+
+    /// Returns `payload`, ending with a newline, followed by its signature line.
+    pub fn sign(&mut self, payload: &str) -> String {
+        self.reload_if_changed();
+        let mut signed = String::from(payload);
+        if !signed.is_empty() && !signed.ends_with('\n') {
+            signed.push('\n');
+        }
+        let signature = self.key.sign(signed.as_bytes());
+        signed.push_str(&format!(
+            "{SIGNATURE_PREFIX}{} {}\n",
+            key_id(&self.key.verifying_key()),
+            to_hex(&signature.to_bytes())
+        ));
+        signed
+    }
+}
+
+/// Checks payloads against the public keys of a file, or of the .pub files of
+/// a directory.
+pub struct Verifier {
+    keys: HashMap<String, VerifyingKey>,
+}
+
+impl Verifier {
+    /// Reads the trusted keys. Readers load them again for each payload, so
+    /// that keys added or removed are taken into account right away.
+    pub fn load(path: &str) -> Result<Verifier, String> {
+        let files = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| {
+                    path.extension().and_then(|e| e.to_str()) == Some(PUBLIC_KEY_EXTENSION)
+                })
+                .collect::<Vec<_>>(),
+            Err(_) if Path::new(path).is_file() => vec![PathBuf::from(path)],
+            Err(e) => return Err(format!("can't read trusted keys in {path}: {e}")),
+        };
+        let mut keys = HashMap::new();
+        for file in &files {
+            match read_verifying_key(file) {
+                Ok(key) => {
+                    keys.insert(key_id(&key), key);
+                }
+                Err(e) => warn!("Ignoring trusted key: {e}"),
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("no public key found in {path}"));
+        }
+        Ok(Verifier { keys })
+    }
+
+    /// Returns the payload of `signed` if its signature is valid and made by a
+    /// trusted key, an error otherwise.
+    pub fn verify<'a>(&self, signed: &'a str) -> Result<&'a str, String> {
+        let body = signed.strip_suffix('\n').unwrap_or(signed);
+        let (payload, signature_line) = match body.rfind('\n') {
+            Some(i) => (&signed[..=i], &body[i + 1..]),
+            None => ("", body),
+        };
+        let fields = signature_line
+            .strip_prefix(SIGNATURE_PREFIX)
+            .ok_or("payload is not signed")?
+            .split(' ')
+            .collect::<Vec<_>>();
+        let [id, signature] = fields.as_slice() else {
+            return Err(format!("invalid signature line '{signature_line}'"));
+        };
+        let key = self
+            .keys
+            .get(*id)
+            .ok_or_else(|| format!("payload signed by the untrusted key {id}"))?;
+        let signature = Signature::from_bytes(&from_hex(signature)?);
+        key.verify_strict(payload.as_bytes(), &signature)
+            .map_err(|_| format!("invalid signature of the key {id}"))?;
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("scaphandre-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = dir.join("old").to_str().unwrap().to_string();
+        let new = dir.join("new").to_str().unwrap().to_string();
+        let old_id = generate_key(&old).unwrap();
+        assert!(generate_key(&old).is_err(), "keys are never overwritten");
+
+        let mut signer = Signer::load(&old).unwrap();
+        let keys = dir.to_str().unwrap();
+        let signed = signer.sign("host 1700000000.5 42\n");
+        assert!(signed.contains(&old_id));
+        let verifier = Verifier::load(keys).unwrap();
+        assert_eq!(verifier.verify(&signed), Ok("host 1700000000.5 42\n"));
+        let forged = signed.replace(" 42\n", " 41\n");
+        assert!(verifier.verify(&forged).is_err());
+        assert!(verifier.verify("host 1700000000.5 42\n").is_err());
+
+        // the publisher switches to a new key, not trusted yet
+        let new_id = generate_key(&new).unwrap();
+        let public_key = fs::read_to_string(format!("{new}.pub")).unwrap();
+        fs::remove_file(format!("{new}.pub")).unwrap();
+        fs::rename(&new, &old).unwrap();
+        let signed = signer.sign("socket 0 1700000000.5 12");
+        assert!(signed.contains(&new_id));
+        let verifier = Verifier::load(keys).unwrap();
+        assert!(verifier.verify(&signed).unwrap_err().contains(&new_id));
+
+        // trusted once its public key is added
+        fs::write(format!("{new}.pub"), public_key).unwrap();
+        let verifier = Verifier::load(keys).unwrap();
+        assert_eq!(verifier.verify(&signed), Ok("socket 0 1700000000.5 12\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,579 @@
+//! # Smart plug sensor
+//!
+//! Polls the local API of a smart plug for the active power of the host plugged
+//! into it. This gives the wall power of the whole machine, including the
+//! components RAPL doesn't see, and works on hardware without RAPL support.
+//!
+//! Supported plugs are designated as `<kind>://<address>`:
+//!
+//! - `shelly://192.168.1.20`: Shelly Gen2 devices, through the `Switch.GetStatus` RPC
+//! - `tasmota://192.168.1.21`: plugs running the Tasmota firmware, through the `Status 8` command
+//! - `kasa://192.168.1.22`: TP-Link Kasa plugs with energy monitoring (HS110, KP115...), through their local TCP protocol
+//! - `mqtt://192.168.1.5/tele/plug/SENSOR`: any of them publishing on an MQTT broker, the
+//!   messages of the topic being Shelly or Tasmota statuses, or a bare number of watts
+//!
+//! When the plug also gives the energy measured by its meter, the host energy
+//! counter follows it: it starts at the value of the meter, advances with the
+//! instant power between two readings, and catches up with the meter whenever
+//! it falls behind.
+use crate::sensors::utils::accumulate_power_as_energy;
+use crate::sensors::{Record, Sensor, Topology};
+use isahc::config::Configurable;
+use isahc::{ReadResponseExt, RequestExt};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Key of the topology sensor data holding the smart plug designation.
+pub const SENSOR_DATA_KEY: &str = "smartplug";
+
+/// Maximum time to wait for an answer from the plug.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default TCP port of the TP-Link Kasa local protocol.
+const KASA_PORT: u16 = 9999;
+
+/// Default TCP port of MQTT brokers.
+const MQTT_PORT: u16 = 1883;
+
+/// Keep alive of the MQTT connection, a ping being sent when nothing was
+/// received for half of it.
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Age after which the last message of an MQTT topic isn't used anymore.
+/// Tasmota publishes its telemetry every 5 minutes by default.
+const MQTT_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Delay before connecting again to an MQTT broker after an error.
+const MQTT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Reading received in the last message of an MQTT topic, and when.
+type LastReading = Option<(Instant, PlugReading)>;
+
+/// Last reading received on each MQTT subscription, by `<broker>/<topic>`.
+static MQTT_READINGS: Mutex<Vec<(String, LastReading)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmartPlugKind {
+    Shelly,
+    Tasmota,
+    Kasa,
+    Mqtt,
+}
+
+/// What a smart plug measures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlugReading {
+    /// Active power, in watts.
+    pub watts: f64,
+    /// Energy measured by the meter of the plug since it was reset, in watt-hours,
+    /// if the plug gives it.
+    pub total_wh: Option<f64>,
+}
+
+/// A smart plug reachable on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmartPlug {
+    pub kind: SmartPlugKind,
+    /// Host (and optionally port) of the plug, or of the MQTT broker
+    /// followed by the topic.
+    pub address: String,
+}
+
+impl SmartPlug {
+    /// Parses a `<kind>://<address>` smart plug designation.
+    pub fn parse(spec: &str) -> Result<SmartPlug, String> {
+        let (kind, address) = spec
+            .split_once("://")
+            .ok_or_else(|| format!("invalid smart plug {spec}, expected <kind>://<address>"))?;
+        let kind = match kind {
+            "shelly" => SmartPlugKind::Shelly,
+            "tasmota" => SmartPlugKind::Tasmota,
+            "kasa" => SmartPlugKind::Kasa,
+            "mqtt" => SmartPlugKind::Mqtt,
+            other => {
+                return Err(format!(
+                    "unknown smart plug kind {other}, expected shelly, tasmota, kasa or mqtt"
+                ))
+            }
+        };
+        let address = address.trim_end_matches('/');
+        if address.is_empty() {
+            return Err(format!("missing address in smart plug {spec}"));
+        }
+        if kind == SmartPlugKind::Mqtt && !address.contains('/') {
+            return Err(format!(
+                "missing topic in smart plug {spec}, expected mqtt://<broker>/<topic>"
+            ));
+        }
+        Ok(SmartPlug {
+            kind,
+            address: String::from(address),
+        })
+    }
+
+    /// Queries the plug and returns the active power it measures, in watts.
+    pub fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+        Ok(self.read()?.watts)
+    }
+
+    /// Queries the plug and returns what it measures.
+    pub fn read(&self) -> Result<PlugReading, Box<dyn Error>> {
+        match self.kind {
+            SmartPlugKind::Shelly => {
+                let status = self.get_json("rpc/Switch.GetStatus?id=0")?;
+                parse_shelly_reading(&status)
+            }
+            SmartPlugKind::Tasmota => {
+                let status = self.get_json("cm?cmnd=Status%208")?;
+                parse_tasmota_reading(&status["StatusSNS"])
+            }
+            SmartPlugKind::Kasa => {
+                let realtime = self.kasa_query(r#"{"emeter":{"get_realtime":{}}}"#)?;
+                parse_kasa_reading(&realtime)
+            }
+            SmartPlugKind::Mqtt => self.mqtt_last_reading(),
+        }
+    }
+
+    fn get_json(&self, path: &str) -> Result<Value, Box<dyn Error>> {
+        let url = format!("http://{}/{}", self.address, path);
+        debug!("Querying smart plug at {}", url);
+        let mut response = isahc::Request::get(&url)
+            .timeout(TIMEOUT)
+            .body(())?
+            .send()?;
+        if !response.status().is_success() {
+            return Err(format!("{url} answered with status {}", response.status()).into());
+        }
+        Ok(serde_json::from_str(&response.text()?)?)
+    }
+
+    fn kasa_query(&self, command: &str) -> Result<Value, Box<dyn Error>> {
+        let address = if self.address.contains(':') {
+            self.address.clone()
+        } else {
+            format!("{}:{}", self.address, KASA_PORT)
+        };
+        let socket_addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("couldn't resolve {address}"))?;
+        let mut stream = TcpStream::connect_timeout(&socket_addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let payload = kasa_encrypt(command.as_bytes());
+        stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+        stream.write_all(&payload)?;
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let mut answer = vec![0u8; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut answer)?;
+        Ok(serde_json::from_slice(&kasa_decrypt(&answer))?)
+    }
+
+    /// Returns the last reading received on the MQTT topic of the plug,
+    /// subscribing to it from a background thread on the first call.
+    fn mqtt_last_reading(&self) -> Result<PlugReading, Box<dyn Error>> {
+        let mut readings = MQTT_READINGS.lock().unwrap();
+        match readings
+            .iter()
+            .find(|(address, _)| *address == self.address)
+        {
+            Some((_, Some((received, reading)))) if received.elapsed() < MQTT_MAX_AGE => {
+                Ok(*reading)
+            }
+            Some((_, Some((received, _)))) => Err(format!(
+                "no message on mqtt://{} for {} s",
+                self.address,
+                received.elapsed().as_secs()
+            )
+            .into()),
+            Some((_, None)) => {
+                Err(format!("no message received on mqtt://{} yet", self.address).into())
+            }
+            None => {
+                readings.push((self.address.clone(), None));
+                let address = self.address.clone();
+                thread::spawn(move || loop {
+                    if let Err(e) = mqtt_subscribe(&address) {
+                        warn!("Lost subscription to mqtt://{address}: {e}");
+                    }
+                    thread::sleep(MQTT_RETRY_DELAY);
+                });
+                Err(format!("subscribing to mqtt://{}", self.address).into())
+            }
+        }
+    }
+}
+
+/// Subscribes to `<broker>/<topic>` and stores the readings it receives, until
+/// the connection fails.
+fn mqtt_subscribe(address: &str) -> Result<(), Box<dyn Error>> {
+    let (broker, topic) = address
+        .split_once('/')
+        .ok_or_else(|| format!("no topic in {address}"))?;
+    let broker = if broker.contains(':') {
+        String::from(broker)
+    } else {
+        format!("{broker}:{MQTT_PORT}")
+    };
+    let socket_addr = broker
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("couldn't resolve {broker}"))?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(MQTT_KEEP_ALIVE / 2))?;
+
+    let mut connect = vec![0, 4];
+    connect.extend_from_slice(b"MQTT");
+    // protocol level 4 (3.1.1), clean session
+    connect.extend_from_slice(&[4, 0x02]);
+    connect.extend_from_slice(&(MQTT_KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    push_mqtt_string(&mut connect, &format!("scaphandre-{}", std::process::id()));
+    write_mqtt_packet(&mut stream, 0x10, &connect)?;
+    match read_mqtt_packet(&mut stream)? {
+        Some((0x20, body)) if body.get(1) == Some(&0) => {}
+        Some((0x20, body)) => {
+            return Err(format!("{broker} refused the connection (code {:?})", body.get(1)).into())
+        }
+        _ => return Err(format!("{broker} didn't acknowledge the connection").into()),
+    }
+
+    // packet identifier 1, then the topic filter with QoS 0
+    let mut subscribe = vec![0, 1];
+    push_mqtt_string(&mut subscribe, topic);
+    subscribe.push(0);
+    write_mqtt_packet(&mut stream, 0x82, &subscribe)?;
+    info!("Subscribed to {topic} on {broker}");
+
+    loop {
+        match read_mqtt_packet(&mut stream)? {
+            Some((0x30, body)) => {
+                // QoS 0: the topic name is directly followed by the payload
+                let topic_len = body
+                    .get(..2)
+                    .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+                    .ok_or("truncated PUBLISH packet")?;
+                let payload = body
+                    .get(2 + topic_len..)
+                    .ok_or("truncated PUBLISH packet")?;
+                match parse_mqtt_reading(payload) {
+                    Ok(reading) => {
+                        let mut readings = MQTT_READINGS.lock().unwrap();
+                        if let Some(entry) = readings.iter_mut().find(|(a, _)| a == address) {
+                            entry.1 = Some((Instant::now(), reading));
+                        }
+                    }
+                    Err(e) => debug!("Ignoring message on {topic}: {e}"),
+                }
+            }
+            Some((0x90, body)) if body.get(2) == Some(&0x80) => {
+                return Err(format!("{broker} refused the subscription to {topic}").into())
+            }
+            Some(_) => {}
+            // nothing received for a while: ping the broker to keep the connection
+            None => write_mqtt_packet(&mut stream, 0xC0, &[])?,
+        }
+    }
+}
+
+fn push_mqtt_string(buffer: &mut Vec<u8>, string: &str) {
+    buffer.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(string.as_bytes());
+}
+
+fn write_mqtt_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> std::io::Result<()> {
+    let mut packet = vec![header];
+    // remaining length, 7 bits per byte
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet)
+}
+
+/// An MQTT packet, as its type and its body.
+type MqttPacket = (u8, Vec<u8>);
+
+/// Reads an MQTT packet, or None when nothing was received before the read
+/// timeout.
+fn read_mqtt_packet(stream: &mut TcpStream) -> Result<Option<MqttPacket>, Box<dyn Error>> {
+    let mut header = [0u8];
+    match stream.read(&mut header) {
+        Ok(0) => return Err("connection closed by the broker".into()),
+        Ok(_) => {}
+        Err(e)
+            if e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::TimedOut =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let mut len = 0usize;
+    for shift in [0, 7, 14, 21] {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body)?;
+            // the flags only matter for PUBLISH packets, all received with QoS 0
+            return Ok(Some((header[0] & 0xF0, body)));
+        }
+    }
+    Err("malformed remaining length in MQTT packet".into())
+}
+
+/// Parses the payload of an MQTT message: a Shelly status (`<prefix>/status/switch:0`),
+/// a Tasmota telemetry (`tele/<topic>/SENSOR`) or a bare number of watts.
+fn parse_mqtt_reading(payload: &[u8]) -> Result<PlugReading, Box<dyn Error>> {
+    let payload = std::str::from_utf8(payload)?.trim();
+    if let Ok(watts) = payload.parse::<f64>() {
+        return Ok(PlugReading {
+            watts,
+            total_wh: None,
+        });
+    }
+    let message: Value = serde_json::from_str(payload)?;
+    if message.get("apower").is_some() {
+        parse_shelly_reading(&message)
+    } else if message.get("ENERGY").is_some() {
+        parse_tasmota_reading(&message)
+    } else {
+        Err("neither a Shelly status nor a Tasmota telemetry".into())
+    }
+}
+
+/// Extracts the reading from a Shelly `Switch.GetStatus` answer, its energy
+/// being given in watt-hours.
+fn parse_shelly_reading(status: &Value) -> Result<PlugReading, Box<dyn Error>> {
+    Ok(PlugReading {
+        watts: status["apower"]
+            .as_f64()
+            .ok_or("no apower field in Shelly answer")?,
+        total_wh: status["aenergy"]["total"].as_f64(),
+    })
+}
+
+/// Extracts the reading from the sensors of a Tasmota `Status 8` answer
+/// (`StatusSNS`) or telemetry, its energy being given in kilowatt-hours.
+fn parse_tasmota_reading(sensors: &Value) -> Result<PlugReading, Box<dyn Error>> {
+    let energy = &sensors["ENERGY"];
+    Ok(PlugReading {
+        watts: energy["Power"]
+            .as_f64()
+            .ok_or("no ENERGY.Power field in Tasmota answer")?,
+        total_wh: energy["Total"].as_f64().map(|kwh| kwh * 1000.0),
+    })
+}
+
+/// Extracts the reading from a Kasa `get_realtime` answer. Depending on the
+/// hardware version, power and energy are given in milliwatts and watt-hours,
+/// or in watts and kilowatt-hours.
+fn parse_kasa_reading(realtime: &Value) -> Result<PlugReading, Box<dyn Error>> {
+    let emeter = &realtime["emeter"]["get_realtime"];
+    if let Some(milliwatts) = emeter["power_mw"].as_f64() {
+        Ok(PlugReading {
+            watts: milliwatts / 1000.0,
+            total_wh: emeter["total_wh"].as_f64(),
+        })
+    } else {
+        Ok(PlugReading {
+            watts: emeter["power"]
+                .as_f64()
+                .ok_or("no power field in Kasa answer")?,
+            total_wh: emeter["total"].as_f64().map(|kwh| kwh * 1000.0),
+        })
+    }
+}
+
+/// Kasa local protocol obfuscation: each byte is XORed with the previous encrypted byte.
+fn kasa_encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = 171u8;
+    plain
+        .iter()
+        .map(|b| {
+            key ^= b;
+            key
+        })
+        .collect()
+}
+
+fn kasa_decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = 171u8;
+    cipher
+        .iter()
+        .map(|b| {
+            let plain = key ^ b;
+            key = *b;
+            plain
+        })
+        .collect()
+}
+
+/// Returns a new energy Record for a topology whose sensor is a smart plug.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    let spec = topology
+        ._sensor_data
+        .get(SENSOR_DATA_KEY)
+        .ok_or("no smart plug in topology sensor data")?;
+    let reading = SmartPlug::parse(spec)?.read()?;
+    let mut record = accumulate_power_as_energy(topology.record_buffer.last(), reading.watts)?;
+    if let Some(total_wh) = reading.total_wh {
+        // the instant power is only sampled, the meter of the plug isn't
+        let meter_microjoules = (total_wh * 3_600_000_000.0) as u64;
+        if record.value.parse::<u64>()? < meter_microjoules {
+            record.value = meter_microjoules.to_string();
+        }
+    }
+    Ok(record)
+}
+
+/// This is a Sensor type that reads the power of the whole host from the
+/// smart plug it is plugged into.
+pub struct SmartPlugSensor {
+    plug: SmartPlug,
+}
+
+impl SmartPlugSensor {
+    /// Instantiates and returns an instance of SmartPlugSensor, for a plug
+    /// designated as `<kind>://<address>`.
+    pub fn new(spec: &str) -> Result<SmartPlugSensor, String> {
+        Ok(SmartPlugSensor {
+            plug: SmartPlug::parse(spec)?,
+        })
+    }
+}
+
+impl Sensor for SmartPlugSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        let kind = match self.plug.kind {
+            SmartPlugKind::Shelly => "shelly",
+            SmartPlugKind::Tasmota => "tasmota",
+            SmartPlugKind::Kasa => "kasa",
+            SmartPlugKind::Mqtt => "mqtt",
+        };
+        sensor_data.insert(
+            String::from(SENSOR_DATA_KEY),
+            format!("{kind}://{}", self.plug.address),
+        );
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_smartplug() {
+        assert_eq!(
+            SmartPlug::parse("kasa://192.168.1.22:9999/").unwrap(),
+            SmartPlug {
+                kind: SmartPlugKind::Kasa,
+                address: String::from("192.168.1.22:9999"),
+            }
+        );
+        assert!(SmartPlug::parse("192.168.1.22").is_err());
+        assert!(SmartPlug::parse("zigbee://plug").is_err());
+        assert!(SmartPlug::parse("shelly://").is_err());
+        assert_eq!(
+            SmartPlug::parse("mqtt://broker/tele/plug/SENSOR").unwrap(),
+            SmartPlug {
+                kind: SmartPlugKind::Mqtt,
+                address: String::from("broker/tele/plug/SENSOR"),
+            }
+        );
+        assert!(SmartPlug::parse("mqtt://broker").is_err());
+    }
+
+    #[test]
+    fn parse_plug_answers() {
+        let shelly: Value = serde_json::from_str(
+            r#"{"id":0,"output":true,"apower":42.5,"aenergy":{"total":1520.25}}"#,
+        )
+        .unwrap();
+        let reading = parse_shelly_reading(&shelly).unwrap();
+        assert_eq!(reading.watts, 42.5);
+        assert_eq!(reading.total_wh, Some(1520.25));
+        let tasmota: Value = serde_json::from_str(
+            r#"{"StatusSNS":{"ENERGY":{"Power":37,"Voltage":230,"Total":12.5}}}"#,
+        )
+        .unwrap();
+        let reading = parse_tasmota_reading(&tasmota["StatusSNS"]).unwrap();
+        assert_eq!(reading.watts, 37.0);
+        assert_eq!(reading.total_wh, Some(12500.0));
+        let kasa_v2: Value =
+            serde_json::from_str(r#"{"emeter":{"get_realtime":{"power_mw":12500}}}"#).unwrap();
+        assert_eq!(parse_kasa_reading(&kasa_v2).unwrap().watts, 12.5);
+        assert_eq!(parse_kasa_reading(&kasa_v2).unwrap().total_wh, None);
+        let kasa_v1: Value =
+            serde_json::from_str(r#"{"emeter":{"get_realtime":{"power":8.2,"total":0.5}}}"#)
+                .unwrap();
+        assert_eq!(parse_kasa_reading(&kasa_v1).unwrap().watts, 8.2);
+        assert_eq!(parse_kasa_reading(&kasa_v1).unwrap().total_wh, Some(500.0));
+        assert!(parse_shelly_reading(&kasa_v1).is_err());
+    }
+
+    #[test]
+    fn parse_mqtt_messages() {
+        assert_eq!(parse_mqtt_reading(b"41.2\n").unwrap().watts, 41.2);
+        let tele = br#"{"Time":"2024-01-01T00:00:00","ENERGY":{"Total":3.2,"Power":55}}"#;
+        let reading = parse_mqtt_reading(tele).unwrap();
+        assert_eq!(reading.watts, 55.0);
+        assert_eq!(reading.total_wh, Some(3200.0));
+        assert_eq!(
+            parse_mqtt_reading(br#"{"id":0,"apower":12}"#)
+                .unwrap()
+                .watts,
+            12.0
+        );
+        assert!(parse_mqtt_reading(br#"{"online":true}"#).is_err());
+    }
+
+    #[test]
+    fn kasa_obfuscation() {
+        let command = br#"{"system":{"get_sysinfo":{}}}"#;
+        let encrypted = kasa_encrypt(command);
+        assert_eq!(encrypted[0], b'{' ^ 171);
+        assert_eq!(kasa_decrypt(&encrypted), command.to_vec());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
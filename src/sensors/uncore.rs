@@ -0,0 +1,300 @@
+//! # Uncore: memory traffic of each socket from the memory controllers
+//!
+//! Intel server CPUs expose the counters of their integrated memory
+//! controllers (IMC) as `uncore_imc_<n>` perf event sources. Their
+//! `cas_count_read` and `cas_count_write` events count the cache lines read
+//! from and written to memory, which makes a proxy of the memory bandwidth to
+//! read next to the energy of the `dram` RAPL domain.
+//!
+//! Each source describes itself in `/sys/bus/event_source/devices/uncore_imc_<n>`:
+//!
+//! ```text
+//! type                          perf type to open the events with
+//! cpumask                       one CPU per socket, to open the events on
+//! format/event, format/umask    bits of the config holding each term (config:0-7)
+//! events/cas_count_read         terms of the event (event=0x04,umask=0x03)
+//! events/cas_count_read.scale   multiplier to the unit (6.103515625e-5)
+//! events/cas_count_read.unit    unit of the scaled value (MiB)
+//! ```
+//!
+//! Opening uncore events needs `CAP_PERFMON` (or `CAP_SYS_ADMIN`), or
+//! `kernel.perf_event_paranoid` set to 0 or lower. Without them, or on CPUs
+//! without such counters, no memory traffic is reported.
+use super::CPUSocket;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
+
+/// Folder of the perf event sources.
+pub const EVENT_SOURCES_PATH: &str = "/sys/bus/event_source/devices";
+
+/// Events counting the cache lines transferred by a memory controller.
+pub const MEMORY_EVENTS: [&str; 2] = ["cas_count_read", "cas_count_write"];
+
+/// Size of a cache line, when the event has no scale.
+const CACHE_LINE_BYTES: f64 = 64.0;
+
+/// Returns the value of `config` for the terms of an event (`event=0x04,umask=0x03`),
+/// given the format of each term (`config:0-7`). Returns None if a term has no
+/// format or doesn't set `config`.
+pub fn parse_event_config(terms: &str, formats: &HashMap<String, String>) -> Option<u64> {
+    let mut config = 0;
+    for term in terms.trim().split(',').filter(|t| !t.is_empty()) {
+        let (name, value) = term.split_once('=').unwrap_or((term, "1"));
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+            None => value.parse().ok()?,
+        };
+        let bits = formats.get(name)?.trim().strip_prefix("config:")?;
+        let mut value = value;
+        for range in bits.split(',') {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?),
+                None => (range.parse().ok()?, range.parse().ok()?),
+            };
+            let width = end - start + 1;
+            let mask = if width >= 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            config |= (value & mask) << start;
+            value = value.checked_shr(width).unwrap_or(0);
+        }
+    }
+    Some(config)
+}
+
+/// Parses a list of CPUs such as `0,36` or `0-3,8`.
+pub fn parse_cpu_list(list: &str) -> Vec<u16> {
+    let mut res = vec![];
+    for part in list.trim().split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.parse::<u16>(), end.parse::<u16>()) {
+                    res.extend(start..=end);
+                }
+            }
+            None => {
+                if let Ok(cpu) = part.parse() {
+                    res.push(cpu);
+                }
+            }
+        }
+    }
+    res
+}
+
+/// perf_event_attr, as of its first version (PERF_ATTR_SIZE_VER0), which
+/// is enough to count an event.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Opens a counting event of type `kind` on `cpu`, for all processes.
+fn open_event(kind: u32, config: u64, cpu: u16) -> std::io::Result<File> {
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+    let attr = PerfEventAttr {
+        kind,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        ..Default::default()
+    };
+    // SAFETY: attr lives until the call returns, and the descriptor returned
+    // on success is owned by the File only.
+    unsafe {
+        let fd = libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            -1 as libc::pid_t,
+            cpu as libc::c_int,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        );
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(fd as libc::c_int))
+    }
+}
+
+/// A memory event opened on a socket, with the number of bytes a count stands for.
+#[derive(Debug)]
+struct MemoryEvent {
+    socket_id: u16,
+    file: File,
+    bytes_per_count: f64,
+}
+
+/// Memory controller events kept open for each socket. Cloning it shares them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCounters {
+    events: Arc<Vec<MemoryEvent>>,
+}
+
+impl MemoryCounters {
+    /// Opens the memory events of all the memory controllers, on a CPU of each
+    /// socket. Controllers whose events can't be opened are left out.
+    pub fn open(sockets: &[CPUSocket]) -> MemoryCounters {
+        let mut events = vec![];
+        let sources = match fs::read_dir(EVENT_SOURCES_PATH) {
+            Ok(sources) => sources,
+            Err(e) => {
+                debug!("Couldn't list perf event sources: {e}");
+                return MemoryCounters::default();
+            }
+        };
+        for source in sources.flatten() {
+            if !source
+                .file_name()
+                .to_string_lossy()
+                .starts_with("uncore_imc")
+            {
+                continue;
+            }
+            let path = source.path();
+            let kind = match fs::read_to_string(path.join("type")) {
+                Ok(kind) => match kind.trim().parse::<u32>() {
+                    Ok(kind) => kind,
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+            let mut formats = HashMap::new();
+            if let Ok(entries) = fs::read_dir(path.join("format")) {
+                for entry in entries.flatten() {
+                    if let Ok(format) = fs::read_to_string(entry.path()) {
+                        formats.insert(entry.file_name().to_string_lossy().to_string(), format);
+                    }
+                }
+            }
+            let cpus = fs::read_to_string(path.join("cpumask"))
+                .map(|mask| parse_cpu_list(&mask))
+                .unwrap_or_default();
+            for event in MEMORY_EVENTS {
+                let config = match fs::read_to_string(path.join("events").join(event))
+                    .ok()
+                    .and_then(|terms| parse_event_config(&terms, &formats))
+                {
+                    Some(config) => config,
+                    None => continue,
+                };
+                let scale = fs::read_to_string(path.join("events").join(format!("{event}.scale")))
+                    .ok()
+                    .and_then(|scale| scale.trim().parse::<f64>().ok());
+                let unit = fs::read_to_string(path.join("events").join(format!("{event}.unit")))
+                    .unwrap_or_default();
+                let bytes_per_count = match (scale, unit.trim()) {
+                    (Some(scale), "MiB") => scale * 1024.0 * 1024.0,
+                    (Some(scale), "Bytes") => scale,
+                    _ => CACHE_LINE_BYTES,
+                };
+                for cpu in &cpus {
+                    let socket = match sockets
+                        .iter()
+                        .find(|s| s.cpu_cores.iter().any(|c| c.id == *cpu))
+                    {
+                        Some(socket) => socket,
+                        None => continue,
+                    };
+                    match open_event(kind, config, *cpu) {
+                        Ok(file) => events.push(MemoryEvent {
+                            socket_id: socket.id,
+                            file,
+                            bytes_per_count,
+                        }),
+                        Err(e) => debug!(
+                            "Couldn't open {event} of {} on CPU {cpu}: {e}",
+                            path.display()
+                        ),
+                    }
+                }
+            }
+        }
+        if events.is_empty() {
+            debug!("No memory controller counter available, memory traffic won't be reported");
+        } else {
+            info!("Opened {} memory controller counters", events.len());
+        }
+        MemoryCounters {
+            events: Arc::new(events),
+        }
+    }
+
+    /// Returns true if no memory event could be opened.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the bytes read from and written to memory by each socket since
+    /// the counters were opened.
+    pub fn read_bytes(&self) -> HashMap<u16, u64> {
+        let mut res = HashMap::new();
+        for event in self.events.iter() {
+            let mut buf = [0; 8];
+            match (&event.file).read_exact(&mut buf) {
+                Ok(()) => {
+                    let bytes = (u64::from_ne_bytes(buf) as f64 * event.bytes_per_count) as u64;
+                    *res.entry(event.socket_id).or_insert(0) += bytes;
+                }
+                Err(e) => debug!(
+                    "Couldn't read memory counter of socket {}: {e}",
+                    event.socket_id
+                ),
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_uncore_event() {
+        let mut formats = HashMap::new();
+        formats.insert(String::from("event"), String::from("config:0-7\n"));
+        formats.insert(String::from("umask"), String::from("config:8-15\n"));
+        formats.insert(String::from("edge"), String::from("config:18\n"));
+        assert_eq!(
+            parse_event_config("event=0x04,umask=0x03\n", &formats),
+            Some(0x0304)
+        );
+        assert_eq!(
+            parse_event_config("event=0x01,edge", &formats),
+            Some(0x40001)
+        );
+        assert_eq!(parse_event_config("event=0x04,thresh=1", &formats), None);
+
+        assert_eq!(parse_cpu_list("0,36\n"), vec![0, 36]);
+        assert_eq!(parse_cpu_list("0-2,8"), vec![0, 1, 2, 8]);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
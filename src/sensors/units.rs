@@ -1,9 +1,11 @@
-use std::{cmp::Ordering, fmt};
+use std::{fmt, str::FromStr};
 
 // !!!!!!!!!!!!!!!!! Unit !!!!!!!!!!!!!!!!!!!!!!!
 #[derive(Debug)]
 pub enum Unit {
     Numeric,
+    KiloWattHour,
+    WattHour,
     Joule,
     MilliJoule,
     MicroJoule,
@@ -18,48 +20,347 @@ pub enum Unit {
     MegaBytes,
     GigaBytes,
     MegaHertz,
+    Celsius,
+    RevolutionsPerMinute,
 }
 
 impl Unit {
-    /// Converts either an energy measurement (Joule, MilliJoule or MicroJoule) to another energy Unit (Joule, MilliJoule or MicroJoule)
-    /// or a power measurement (MilliWatt, MicroWatt, Watt, KiloWatt) to another power Unit
+    /// Converts either an energy measurement (KiloWattHour, WattHour, Joule, MilliJoule or MicroJoule)
+    /// to another energy Unit, or a power measurement (MegaWatt, KiloWatt, Watt, MilliWatt or MicroWatt)
+    /// to another power Unit. Returns an error for other conversions (such as energy to power,
+    /// which needs a time dimension) or if the result is not a finite number.
     pub fn to(measure: f64, source_unit: &Unit, dest_unit: &Unit) -> Result<f64, String> {
-        let energy_order = [Unit::Joule, Unit::MilliJoule, Unit::MicroJoule];
-        let power_order = [
+        let factors = match (source_unit.microjoules(), dest_unit.microjoules()) {
+            (Some(source), Some(dest)) => Some((source, dest)),
+            _ => match (source_unit.microwatts(), dest_unit.microwatts()) {
+                (Some(source), Some(dest)) => Some((source, dest)),
+                _ => None,
+            },
+        };
+        match factors {
+            // factors are integers, so that conversions between metric prefixes are exact
+            Some((source, dest)) => {
+                let result = measure * source / dest;
+                if result.is_finite() {
+                    Ok(result)
+                } else {
+                    Err(format!("{measure} {source_unit} is out of range in {dest_unit}"))
+                }
+            }
+            None => Err(format!(
+                "can't convert {source_unit:?} to {dest_unit:?}, only energy to energy and power to power conversions are possible"
+            )),
+        }
+    }
+
+    /// Value of one unit of energy, in microjoules.
+    fn microjoules(&self) -> Option<f64> {
+        match self {
+            Unit::KiloWattHour => Some(3.6e12),
+            Unit::WattHour => Some(3.6e9),
+            Unit::Joule => Some(1e6),
+            Unit::MilliJoule => Some(1e3),
+            Unit::MicroJoule => Some(1.0),
+            _ => None,
+        }
+    }
+
+    /// Value of one unit of power, in microwatts.
+    fn microwatts(&self) -> Option<f64> {
+        match self {
+            Unit::MegaWatt => Some(1e12),
+            Unit::KiloWatt => Some(1e9),
+            Unit::Watt => Some(1e6),
+            Unit::MilliWatt => Some(1e3),
+            Unit::MicroWatt => Some(1.0),
+            _ => None,
+        }
+    }
+
+    /// Returns true for the units of energy.
+    pub fn is_energy(&self) -> bool {
+        self.microjoules().is_some()
+    }
+
+    /// Returns true for the units of power.
+    pub fn is_power(&self) -> bool {
+        self.microwatts().is_some()
+    }
+
+    /// Returns the short symbol of the unit, as displayed next to values (kWh, W, MB...).
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::KiloWattHour => "kWh",
+            Unit::WattHour => "Wh",
+            Unit::Joule => "J",
+            Unit::MilliJoule => "mJ",
+            Unit::MicroJoule => "uJ",
+            Unit::MegaWatt => "MW",
+            Unit::KiloWatt => "kW",
+            Unit::Watt => "W",
+            Unit::MilliWatt => "mW",
+            Unit::MicroWatt => "uW",
+            Unit::Percentage => "%",
+            Unit::Bytes => "B",
+            Unit::KiloBytes => "kB",
+            Unit::MegaBytes => "MB",
+            Unit::GigaBytes => "GB",
+            Unit::MegaHertz => "MHz",
+            Unit::Celsius => "°C",
+            Unit::RevolutionsPerMinute => "RPM",
+            Unit::Numeric => "",
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    /// Parses the symbol (case sensitive, as mW and MW differ) or the name
+    /// (case insensitive) of an energy or power unit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let units = [
+            Unit::KiloWattHour,
+            Unit::WattHour,
+            Unit::Joule,
+            Unit::MilliJoule,
+            Unit::MicroJoule,
             Unit::MegaWatt,
             Unit::KiloWatt,
             Unit::Watt,
             Unit::MilliWatt,
             Unit::MicroWatt,
         ];
-        let pos_source_energy = energy_order.iter().position(|x| x == source_unit);
-        let pos_dest_energy = energy_order.iter().position(|x| x == dest_unit);
-        let pos_source_power = power_order.iter().position(|x| x == source_unit);
-        let pos_dest_power = power_order.iter().position(|x| x == dest_unit);
-        if let (Some(pos_source), Some(pos_dest)) = (pos_source_energy, pos_dest_energy) {
-            Ok(measure * Unit::get_mult(pos_source, pos_dest))
-        } else if let (Some(pos_source), Some(pos_dest)) = (pos_source_power, pos_dest_power) {
-            Ok(measure * Unit::get_mult(pos_source, pos_dest))
+        units
+            .into_iter()
+            .find(|unit| unit.symbol() == s || unit.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown unit {s}, expected one of {}",
+                    units.map(|unit| unit.symbol()).join(", ")
+                )
+            })
+    }
+}
+
+/// Parses a unit of power, for command line options.
+pub fn parse_power_unit(s: &str) -> Result<Unit, String> {
+    let unit = s.parse::<Unit>()?;
+    if unit.is_power() {
+        Ok(unit)
+    } else {
+        Err(format!("{s} is not a unit of power (MW, kW, W, mW or uW)"))
+    }
+}
+
+/// Parses a unit of energy, for command line options.
+pub fn parse_energy_unit(s: &str) -> Result<Unit, String> {
+    let unit = s.parse::<Unit>()?;
+    if unit.is_energy() {
+        Ok(unit)
+    } else {
+        Err(format!(
+            "{s} is not a unit of energy (kWh, Wh, J, mJ or uJ)"
+        ))
+    }
+}
+
+/// A unit of power or energy chosen on the command line: either a fixed one,
+/// or the most readable one for each value (see [Measure::auto_scaled]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayUnit {
+    Auto,
+    Fixed(Unit),
+}
+
+/// Number of decimals of the values displayed with --human, unless given with --precision.
+pub const HUMAN_PRECISION: usize = 2;
+
+/// Parses "auto" or a unit of power, for command line options.
+pub fn parse_display_power_unit(s: &str) -> Result<DisplayUnit, String> {
+    match s {
+        "auto" => Ok(DisplayUnit::Auto),
+        _ => parse_power_unit(s).map(DisplayUnit::Fixed),
+    }
+}
+
+/// A value and its unit, displayed with the unit symbol and the precision of
+/// the formatter if any: `format!("{:.2}", Measure::new(1.5, Unit::Watt))` gives "1.50 W".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measure {
+    pub value: f64,
+    pub unit: Unit,
+}
+
+impl Measure {
+    pub fn new(value: f64, unit: Unit) -> Measure {
+        Measure { value, unit }
+    }
+
+    /// Returns the measure converted to `unit`, see [Unit::to].
+    pub fn to(&self, unit: Unit) -> Result<Measure, String> {
+        Ok(Measure::new(Unit::to(self.value, &self.unit, &unit)?, unit))
+    }
+
+    /// Returns the measure in the largest unit of the same kind in which it is
+    /// at least 1 (1.2 kW rather than 1200000000 uW), or unchanged if it isn't
+    /// a power nor an energy.
+    pub fn auto_scaled(&self) -> Measure {
+        let units: &[Unit] = if self.unit.is_power() {
+            &[
+                Unit::MegaWatt,
+                Unit::KiloWatt,
+                Unit::Watt,
+                Unit::MilliWatt,
+                Unit::MicroWatt,
+            ]
+        } else if self.unit.is_energy() {
+            &[
+                Unit::KiloWattHour,
+                Unit::WattHour,
+                Unit::Joule,
+                Unit::MilliJoule,
+                Unit::MicroJoule,
+            ]
         } else {
-            panic!("Unimplemented or impossible conversion (if asked from energy value to power value without time dimension).");
+            return *self;
+        };
+        units
+            .iter()
+            .filter_map(|unit| self.to(*unit).ok())
+            .find(|measure| measure.value.abs() >= 1.0)
+            .unwrap_or_else(|| self.to(units[units.len() - 1]).unwrap_or(*self))
+    }
+
+    /// Formats the measure in its most readable unit, with `precision` decimals
+    /// (HUMAN_PRECISION if None): "1.23 kW" for 1234000000 uW.
+    pub fn human(&self, precision: Option<usize>, number_format: &NumberFormat) -> String {
+        self.auto_scaled()
+            .format(Some(precision.unwrap_or(HUMAN_PRECISION)), number_format)
+    }
+
+    /// Formats the measure like its Display implementation does, with the
+    /// separators of `number_format`.
+    pub fn format(&self, precision: Option<usize>, number_format: &NumberFormat) -> String {
+        let value = number_format.format(self.value, precision);
+        match self.unit.symbol() {
+            "" => value,
+            symbol => format!("{value} {symbol}"),
         }
     }
+}
 
-    /// Helper func to compute the multiplicative factor needed for a conversion
-    fn get_mult(pos_source: usize, pos_dest: usize) -> f64 {
-        let mut mult: f64 = 1.0;
-        match pos_dest.cmp(&pos_source) {
-            Ordering::Greater => mult *= 1000.0_f64.powf((pos_dest - pos_source) as f64),
-            Ordering::Less => mult /= 1000.0_f64.powf((pos_source - pos_dest) as f64),
-            Ordering::Equal => (),
+impl fmt::Display for Measure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match f.precision() {
+            Some(precision) => write!(f, "{:.*}", precision, self.value)?,
+            None => write!(f, "{}", self.value)?,
+        }
+        match self.unit.symbol() {
+            "" => Ok(()),
+            symbol => write!(f, " {symbol}"),
         }
-        mult
+    }
+}
+
+/// Decimal and thousands separators used to display numbers to humans.
+/// The default one is that of the C locale: a dot, and no thousands separator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            decimal_separator: '.',
+            thousands_separator: None,
+        }
+    }
+}
+
+impl NumberFormat {
+    /// Returns the separators of a locale name such as fr_FR.UTF-8, de_CH or
+    /// en, or None if the language of the locale is unknown.
+    pub fn for_locale(locale: &str) -> Option<NumberFormat> {
+        let name = locale.split(['.', '@']).next().unwrap_or_default();
+        let (language, country) = match name.split_once(['_', '-']) {
+            Some((language, country)) => (language, Some(country)),
+            None => (name, None),
+        };
+        let (decimal_separator, thousands_separator) = match (language, country) {
+            ("C" | "POSIX" | "", _) => return Some(NumberFormat::default()),
+            ("de" | "it", Some("CH")) => ('.', Some('\'')),
+            ("en" | "ja" | "zh" | "ko" | "he" | "th" | "hi" | "ms", _) => ('.', Some(',')),
+            ("de" | "es" | "it" | "nl" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "sl", _) => {
+                (',', Some('.'))
+            }
+            // narrow no-break space
+            ("fr", _) => (',', Some('\u{202f}')),
+            (
+                "ru" | "pl" | "cs" | "sk" | "fi" | "sv" | "nb" | "nn" | "no" | "uk" | "hu" | "bg"
+                | "et" | "lt" | "lv",
+                _,
+            ) => (',', Some('\u{a0}')),
+            _ => return None,
+        };
+        Some(NumberFormat {
+            decimal_separator,
+            thousands_separator,
+        })
+    }
+
+    /// Returns the separators of the locale of the environment, from LC_ALL,
+    /// LC_NUMERIC or LANG, or those of the C locale if it is unknown.
+    pub fn from_env() -> NumberFormat {
+        ["LC_ALL", "LC_NUMERIC", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|locale| !locale.is_empty())
+            .and_then(|locale| NumberFormat::for_locale(&locale))
+            .unwrap_or_default()
+    }
+
+    /// Formats `value` with `precision` decimals, or all significant ones if None.
+    pub fn format(&self, value: f64, precision: Option<usize>) -> String {
+        let digits = match precision {
+            Some(precision) => format!("{value:.precision$}"),
+            None => value.to_string(),
+        };
+        let (sign, digits) = match digits.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", digits.as_str()),
+        };
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (digits, None),
+        };
+        let mut result = String::from(sign);
+        match self.thousands_separator {
+            Some(separator) if integer.bytes().all(|b| b.is_ascii_digit()) => {
+                for (i, digit) in integer.chars().enumerate() {
+                    if i > 0 && (integer.len() - i) % 3 == 0 {
+                        result.push(separator);
+                    }
+                    result.push(digit);
+                }
+            }
+            _ => result.push_str(integer),
+        }
+        if let Some(fraction) = fraction {
+            result.push(self.decimal_separator);
+            result.push_str(fraction);
+        }
+        result
     }
 }
 
 impl fmt::Display for Unit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Unit::KiloWattHour => write!(f, "KiloWattHours"),
+            Unit::WattHour => write!(f, "WattHours"),
             Unit::Joule => write!(f, "Joules"),
             Unit::MilliJoule => write!(f, "MilliJoules"),
             Unit::MicroJoule => write!(f, "MicroJoules"),
@@ -74,6 +375,8 @@ impl fmt::Display for Unit {
             Unit::MegaBytes => write!(f, "MegaBytes"),
             Unit::GigaBytes => write!(f, "GigaBytes"),
             Unit::MegaHertz => write!(f, "MegaHertz"),
+            Unit::Celsius => write!(f, "Celsius"),
+            Unit::RevolutionsPerMinute => write!(f, "RPM"),
             Unit::Numeric => write!(f, ""),
         }
     }
@@ -159,6 +462,107 @@ mod tests {
         let dest = Unit::Joule;
         assert_eq!(Unit::to(value, &source, &dest).unwrap(), 4.0);
     }
+
+    #[test]
+    fn kwh_to_joules() {
+        assert_eq!(
+            Unit::to(1.5, &Unit::KiloWattHour, &Unit::Joule).unwrap(),
+            5400000.0
+        );
+        assert_eq!(
+            Unit::to(7200.0, &Unit::Joule, &Unit::WattHour).unwrap(),
+            2.0
+        );
+        assert_eq!(
+            Unit::to(3.6e9, &Unit::MicroJoule, &Unit::KiloWattHour).unwrap(),
+            0.001
+        );
+    }
+
+    #[test]
+    fn impossible_conversions() {
+        assert!(Unit::to(1.0, &Unit::Joule, &Unit::Watt).is_err());
+        assert!(Unit::to(1.0, &Unit::Bytes, &Unit::KiloBytes).is_err());
+        assert!(Unit::to(f64::MAX, &Unit::MegaWatt, &Unit::MicroWatt).is_err());
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!("kWh".parse::<Unit>().unwrap(), Unit::KiloWattHour);
+        assert_eq!("mW".parse::<Unit>().unwrap(), Unit::MilliWatt);
+        assert_eq!("MW".parse::<Unit>().unwrap(), Unit::MegaWatt);
+        assert_eq!("joules".parse::<Unit>().unwrap(), Unit::Joule);
+        assert!("GB".parse::<Unit>().is_err());
+        assert_eq!(parse_power_unit("kW").unwrap(), Unit::KiloWatt);
+        assert!(parse_power_unit("kWh").is_err());
+        assert_eq!(parse_energy_unit("Wh").unwrap(), Unit::WattHour);
+        assert!(parse_energy_unit("W").is_err());
+    }
+
+    #[test]
+    fn display_measures() {
+        let measure = Measure::new(1.5, Unit::Watt);
+        assert_eq!(measure.to_string(), "1.5 W");
+        assert_eq!(format!("{measure:.2}"), "1.50 W");
+        assert_eq!(
+            format!("{:.3}", measure.to(Unit::KiloWatt).unwrap()),
+            "0.002 kW"
+        );
+        assert_eq!(Measure::new(3.0, Unit::Numeric).to_string(), "3");
+    }
+
+    #[test]
+    fn auto_scaled_measures() {
+        let scaled = Measure::new(1_234_000.0, Unit::MicroWatt).auto_scaled();
+        assert_eq!(scaled.unit, Unit::Watt);
+        assert_eq!(
+            Measure::new(12.0, Unit::MicroWatt).auto_scaled().unit,
+            Unit::MicroWatt
+        );
+        assert_eq!(
+            Measure::new(0.0, Unit::Watt).auto_scaled(),
+            Measure::new(0.0, Unit::MicroWatt)
+        );
+        assert_eq!(
+            Measure::new(5.4e9, Unit::MicroJoule).auto_scaled(),
+            Measure::new(1.5, Unit::WattHour)
+        );
+        assert_eq!(
+            Measure::new(50.0, Unit::Percentage).auto_scaled().unit,
+            Unit::Percentage
+        );
+        let c = NumberFormat::default();
+        assert_eq!(
+            Measure::new(1_234_000_000.0, Unit::MicroWatt).human(None, &c),
+            "1.23 kW"
+        );
+        assert_eq!(
+            Measure::new(4.2e12, Unit::MicroJoule).human(Some(1), &c),
+            "1.2 kWh"
+        );
+    }
+
+    #[test]
+    fn number_formats() {
+        let c = NumberFormat::default();
+        assert_eq!(c.format(1234567.891, Some(2)), "1234567.89");
+        let fr = NumberFormat::for_locale("fr_FR.UTF-8").unwrap();
+        assert_eq!(
+            fr.format(1234567.891, Some(2)),
+            "1\u{202f}234\u{202f}567,89"
+        );
+        let de = NumberFormat::for_locale("de_DE").unwrap();
+        assert_eq!(de.format(-1234.5, None), "-1.234,5");
+        assert_eq!(de.format(123.0, Some(0)), "123");
+        let ch = NumberFormat::for_locale("de_CH.UTF-8").unwrap();
+        assert_eq!(ch.format(12345.0, Some(1)), "12'345.0");
+        assert_eq!(NumberFormat::for_locale("C"), Some(c));
+        assert_eq!(NumberFormat::for_locale("tlh_KL"), None);
+        assert_eq!(
+            Measure::new(1500.25, Unit::Watt).format(Some(1), &de),
+            "1.500,2 W"
+        );
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -0,0 +1,116 @@
+//! # Update period: how often the RAPL energy counters actually change
+//!
+//! RAPL counters are updated by the firmware, usually every millisecond, but
+//! some BIOSes only update them about once per second. Read more often than
+//! that, the counter returns the same value twice: the energy consumed between
+//! the two reads is zero and the next one accounts for both intervals, so that
+//! the power computed from them is jagged.
+//!
+//! At startup, the counter of the first socket is polled until it changed a
+//! few times, and the median time between two changes is kept as the update
+//! period of the counters. Measurements of the host closer than that period
+//! are then skipped (see [crate::sensors::Topology::refresh]).
+use crate::sensors::RecordReader;
+use std::time::{Duration, Instant};
+
+/// Maximum time spent polling the counter at startup.
+pub const DETECTION_TIMEOUT: Duration = Duration::from_millis(2500);
+
+/// Time between two reads of the counter while polling it.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Number of changes of the counter after which polling stops.
+const CHANGES_NEEDED: usize = 6;
+
+/// Period from which the counters are considered slow, and a warning is logged.
+pub const SLOW_UPDATE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Returns the median of the intervals between the successive `changes` of a
+/// counter, None if there are less than two of them.
+///
+/// The first change is only a reference: the time between the start of the
+/// polling and the first change doesn't tell anything about the period.
+pub fn estimate_update_period(changes: &[Duration]) -> Option<Duration> {
+    let mut intervals = changes
+        .windows(2)
+        .filter_map(|pair| pair[1].checked_sub(pair[0]))
+        .collect::<Vec<_>>();
+    if intervals.is_empty() {
+        return None;
+    }
+    intervals.sort();
+    Some(intervals[intervals.len() / 2])
+}
+
+/// Polls the energy counter read by `reader` and returns its update period,
+/// None if it couldn't be read or didn't change often enough before
+/// [DETECTION_TIMEOUT].
+pub fn detect_update_period(reader: &dyn RecordReader) -> Option<Duration> {
+    let start = Instant::now();
+    let mut last_value = match reader.read_record() {
+        Ok(record) => record.value,
+        Err(e) => {
+            debug!("Couldn't read the energy counter to detect its update period: {e:?}");
+            return None;
+        }
+    };
+    let mut changes = vec![];
+    while changes.len() < CHANGES_NEEDED && start.elapsed() < DETECTION_TIMEOUT {
+        std::thread::sleep(POLL_INTERVAL);
+        let value = match reader.read_record() {
+            Ok(record) => record.value,
+            Err(_) => continue,
+        };
+        if value != last_value {
+            changes.push(start.elapsed());
+            last_value = value;
+        }
+    }
+    let period = estimate_update_period(&changes);
+    match period {
+        Some(period) if period >= SLOW_UPDATE_PERIOD => warn!(
+            "RAPL energy counters are updated every {}ms on this host, measurements closer than that are skipped.",
+            period.as_millis()
+        ),
+        Some(period) => debug!("RAPL energy counters update period: {period:?}"),
+        None => warn!(
+            "Couldn't detect the update period of the RAPL energy counters within {DETECTION_TIMEOUT:?}."
+        ),
+    }
+    period
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_period() {
+        let ms = Duration::from_millis;
+        assert_eq!(estimate_update_period(&[]), None);
+        assert_eq!(estimate_update_period(&[ms(400)]), None);
+        // a late read doesn't change the median
+        assert_eq!(
+            estimate_update_period(&[ms(400), ms(1400), ms(2400), ms(3450), ms(4400)]),
+            Some(ms(1000))
+        );
+        assert_eq!(
+            estimate_update_period(&[ms(1), ms(2), ms(3), ms(5), ms(6)]),
+            Some(ms(1))
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -3,9 +3,12 @@ use ordered_float::*;
 use procfs;
 use regex::Regex;
 #[allow(unused_imports)]
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+#[cfg(feature = "containers")]
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use sysinfo::{
     get_current_pid, CpuExt, CpuRefreshKind, Pid, Process, ProcessExt, ProcessStatus, System,
@@ -89,6 +92,31 @@ pub struct IProcess {
     pub stime: u64,
     #[cfg(target_os = "linux")]
     pub utime: u64,
+    // Time spent waiting for block I/O, in clock ticks, if delay accounting is enabled
+    #[cfg(target_os = "linux")]
+    pub delayacct_blkio_ticks: Option<u64>,
+    // CPU time of the threads of the process, summed from /proc/PID/task, in clock ticks
+    // (or the one of /proc/PID/stat if higher), read with ProcessCpuSource::Tasks only
+    #[cfg(target_os = "linux")]
+    pub tasks_time_jiffies: Option<u64>,
+    // CPU usage computed by sysinfo, when cpu_usage_percentage was computed from the threads
+    #[cfg(target_os = "linux")]
+    pub sysinfo_cpu_usage_percentage: Option<f32>,
+}
+
+/// Returns the arguments of `process`. Sysinfo skips the arguments that aren't
+/// valid UTF-8, on Linux they are read again from /proc/PID/cmdline with the
+/// invalid sequences replaced.
+pub fn read_cmdline(process: &Process) -> Vec<String> {
+    #[cfg(target_os = "linux")]
+    if let Ok(content) = std::fs::read(format!("/proc/{}/cmdline", process.pid())) {
+        return content
+            .split(|b| *b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).trim().to_string())
+            .filter(|arg| !arg.is_empty())
+            .collect();
+    }
+    process.cmd().to_vec()
 }
 
 impl IProcess {
@@ -98,19 +126,21 @@ impl IProcess {
         {
             let mut stime = 0;
             let mut utime = 0;
+            let mut delayacct_blkio_ticks = None;
             if let Ok(procfs_process) =
                 procfs::process::Process::new(process.pid().to_string().parse::<i32>().unwrap())
             {
                 if let Ok(stat) = procfs_process.stat() {
                     stime += stat.stime;
                     utime += stat.utime;
+                    delayacct_blkio_ticks = stat.delayacct_blkio_ticks;
                 }
             }
             IProcess {
                 pid: process.pid(),
                 owner: 0,
-                comm: String::from(process.exe().to_str().unwrap()),
-                cmdline: process.cmd().to_vec(),
+                comm: process.exe().to_string_lossy().to_string(),
+                cmdline: read_cmdline(process),
                 cpu_usage_percentage: process.cpu_usage(),
                 memory: process.memory(),
                 virtual_memory: process.virtual_memory(),
@@ -120,6 +150,9 @@ impl IProcess {
                 total_disk_written: disk_usage.total_written_bytes,
                 stime,
                 utime,
+                delayacct_blkio_ticks,
+                tasks_time_jiffies: None,
+                sysinfo_cpu_usage_percentage: None,
             }
         }
         #[cfg(not(target_os = "linux"))]
@@ -127,8 +160,8 @@ impl IProcess {
             IProcess {
                 pid: process.pid(),
                 owner: 0,
-                comm: String::from(process.exe().to_str().unwrap()),
-                cmdline: process.cmd().to_vec(),
+                comm: process.exe().to_string_lossy().to_string(),
+                cmdline: read_cmdline(process),
                 cpu_usage_percentage: process.cpu_usage(),
                 memory: process.memory(),
                 virtual_memory: process.virtual_memory(),
@@ -143,7 +176,7 @@ impl IProcess {
     /// Returns the command line of related to the process, as found by sysinfo.
     pub fn cmdline(&self, proc_tracker: &ProcessTracker) -> Result<Vec<String>, Error> {
         if let Some(p) = proc_tracker.sysinfo.process(self.pid) {
-            Ok(p.cmd().to_vec())
+            Ok(read_cmdline(p))
         } else {
             Err(Error::new(
                 ErrorKind::Other,
@@ -155,7 +188,7 @@ impl IProcess {
     /// Returns the executable string related to the process
     pub fn exe(&self, proc_tracker: &ProcessTracker) -> Result<PathBuf, String> {
         if let Some(p) = proc_tracker.sysinfo.process(self.pid) {
-            Ok(PathBuf::from(p.exe().to_str().unwrap()))
+            Ok(p.exe().to_path_buf())
         } else {
             Err(String::from("Couldn't get process."))
         }
@@ -188,13 +221,140 @@ pub fn page_size() -> Result<u64, String> {
     {
         res = Ok(procfs::page_size())
     }
-    #[cfg(target_os = "windows")]
+    #[cfg(not(target_os = "linux"))]
     {
         res = Ok(4096u64)
     }
     res
 }
 
+/// Folder of the PCI devices in sysfs
+pub const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+
+/// Returns true if the host has an integrated GPU, in which case the "uncore"
+/// (PP1) RAPL domain measures the power of that GPU. Integrated GPUs are the
+/// Intel display controllers on the root PCI bus, discrete ones sit behind a
+/// PCIe bridge.
+pub fn has_integrated_gpu(pci_devices_path: &str) -> bool {
+    let Ok(entries) = std::fs::read_dir(pci_devices_path) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name();
+        let read =
+            |file: &str| std::fs::read_to_string(entry.path().join(file)).unwrap_or_default();
+        name.to_string_lossy().starts_with("0000:00:")
+            && read("class").trim().starts_with("0x03")
+            && read("vendor").trim() == "0x8086"
+    })
+}
+
+/// Returns the content of the sysfs attribute at `path` without its trailing
+/// line feed, or None if it can't be read or is empty (some drivers leave their
+/// labels empty).
+pub fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|value| String::from(value.trim()))
+        .filter(|value| !value.is_empty())
+}
+
+/// Writes `value` and a line feed to `file` under `base`, as sysfs shows its
+/// attributes, creating the parent folders. Used by the tests of the sysfs sensors.
+#[cfg(test)]
+pub fn write_sysfs_attribute(base: &Path, file: &str, value: &str) {
+    let path = base.join(file);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, format!("{value}\n")).unwrap();
+}
+
+/// Regular expressions deciding which processes are tracked by a ProcessTracker.
+/// `include` and `exclude` are matched against the executable path and the
+/// command line of the process.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessFilters {
+    /// If set, only the processes matching it are tracked
+    pub include: Option<Regex>,
+    /// If set, the processes matching it are not tracked
+    pub exclude: Option<Regex>,
+    /// If set, only the processes running in a Docker container whose name
+    /// matches it are tracked
+    #[cfg(feature = "containers")]
+    pub container: Option<Regex>,
+    /// If true, no process is tracked at all (--low-memory)
+    pub disabled: bool,
+}
+
+impl ProcessFilters {
+    /// Returns true if a process with this executable path and command line
+    /// passes the include and exclude filters.
+    pub fn accepts(&self, exe: &str, cmdline: &str) -> bool {
+        if self.disabled {
+            return false;
+        }
+        let matches = |regex: &Regex| regex.is_match(exe) || regex.is_match(cmdline);
+        if let Some(include) = &self.include {
+            if !matches(include) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if matches(exclude) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Where the CPU usage of the processes, which their power is computed from,
+/// comes from.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProcessCpuSource {
+    /// CPU usage computed by sysinfo
+    #[default]
+    Sysinfo,
+    /// CPU time of the threads of each process, summed from /proc/PID/task (Linux only)
+    Tasks,
+}
+
+/// Returns the CPU time (user and system) of the threads of the process `pid`,
+/// summed from /proc/PID/task/TID/stat, in clock ticks. Threads that exited
+/// are not in these sums anymore, while /proc/PID/stat keeps counting them.
+#[cfg(target_os = "linux")]
+pub fn read_tasks_time_jiffies(pid: Pid) -> Option<u64> {
+    let procfs_process = procfs::process::Process::new(pid.to_string().parse().ok()?).ok()?;
+    let mut jiffies = 0;
+    for task in procfs_process.tasks().ok()?.flatten() {
+        if let Ok(stat) = task.stat() {
+            jiffies += stat.utime + stat.stime;
+        }
+    }
+    Some(jiffies)
+}
+
+/// Returns the CPU usage, in percents of a core as computed by sysinfo, of a
+/// process that used `jiffies` clock ticks of CPU time during `elapsed`.
+pub fn cpu_usage_from_jiffies(jiffies: u64, elapsed: Duration, ticks_per_second: u64) -> f32 {
+    if elapsed.is_zero() || ticks_per_second == 0 {
+        return 0.0;
+    }
+    (jiffies as f64 / ticks_per_second as f64 / elapsed.as_secs_f64() * 100.0) as f32
+}
+
+/// Returns the sysinfo System used by a ProcessTracker. The minimal build
+/// starts with the memory only, and never reads the components and disks.
+fn new_system() -> System {
+    #[cfg(feature = "minimal")]
+    {
+        let mut system = System::new();
+        system.refresh_memory();
+        system
+    }
+    #[cfg(not(feature = "minimal"))]
+    System::new_all()
+}
+
 #[derive(Debug)]
 /// Manages ProcessRecord instances.
 pub struct ProcessTracker {
@@ -213,6 +373,20 @@ pub struct ProcessTracker {
     pub regex_cgroup_kubernetes: Regex,
     #[cfg(feature = "containers")]
     pub regex_cgroup_containerd: Regex,
+    /// Names of the Docker containers by id, for the container filter
+    #[cfg(feature = "containers")]
+    container_names: HashMap<String, String>,
+    #[cfg(feature = "containers")]
+    regex_container_id: Regex,
+    /// Processes rejected by these filters are not tracked at all
+    pub filters: ProcessFilters,
+    /// Maximum number of processes tracked at once, the least recently seen
+    /// are evicted beyond it
+    pub max_tracked_pids: usize,
+    /// Number of processes evicted because of max_tracked_pids since startup
+    pub evicted_pids: u64,
+    /// Where the CPU usage of the processes comes from
+    pub cpu_source: ProcessCpuSource,
 }
 
 impl Clone for ProcessTracker {
@@ -220,14 +394,26 @@ impl Clone for ProcessTracker {
         ProcessTracker {
             procs: self.procs.clone(),
             max_records_per_process: self.max_records_per_process,
-            sysinfo: System::new_all(),
+            sysinfo: if self.filters.disabled {
+                System::new()
+            } else {
+                new_system()
+            },
             #[cfg(feature = "containers")]
             regex_cgroup_docker: self.regex_cgroup_docker.clone(),
             #[cfg(feature = "containers")]
             regex_cgroup_kubernetes: self.regex_cgroup_kubernetes.clone(),
             #[cfg(feature = "containers")]
             regex_cgroup_containerd: self.regex_cgroup_containerd.clone(),
+            #[cfg(feature = "containers")]
+            container_names: self.container_names.clone(),
+            #[cfg(feature = "containers")]
+            regex_container_id: self.regex_container_id.clone(),
             nb_cores: self.nb_cores,
+            filters: self.filters.clone(),
+            max_tracked_pids: self.max_tracked_pids,
+            evicted_pids: self.evicted_pids,
+            cpu_source: self.cpu_source,
         }
     }
 }
@@ -250,7 +436,7 @@ impl ProcessTracker {
         #[cfg(feature = "containers")]
         let regex_cgroup_containerd = Regex::new("/system.slice/containerd.service/.*$").unwrap();
 
-        let mut system = System::new_all();
+        let mut system = new_system();
         system.refresh_cpu_specifics(CpuRefreshKind::everything());
         let nb_cores = system.cpus().len();
 
@@ -264,19 +450,104 @@ impl ProcessTracker {
             regex_cgroup_kubernetes,
             #[cfg(feature = "containers")]
             regex_cgroup_containerd,
+            #[cfg(feature = "containers")]
+            container_names: HashMap::new(),
+            #[cfg(feature = "containers")]
+            regex_container_id: Regex::new("[[:alnum:]]{12,}").unwrap(),
             nb_cores,
+            filters: ProcessFilters::default(),
+            max_tracked_pids: DEFAULT_MAX_TRACKED_PIDS,
+            evicted_pids: 0,
+            cpu_source: ProcessCpuSource::default(),
         }
     }
 
     pub fn refresh(&mut self) {
-        self.sysinfo.refresh_components();
+        #[cfg(not(feature = "minimal"))]
+        {
+            self.sysinfo.refresh_components();
+            self.sysinfo.refresh_disks();
+            self.sysinfo.refresh_disks_list();
+        }
         self.sysinfo.refresh_memory();
-        self.sysinfo.refresh_disks();
-        self.sysinfo.refresh_disks_list();
         self.sysinfo
             .refresh_cpu_specifics(CpuRefreshKind::everything());
     }
 
+    /// Replaces the process filters, forgetting the processes already known
+    /// when process tracking is disabled.
+    pub fn set_filters(&mut self, filters: ProcessFilters) {
+        if filters.disabled {
+            self.procs.clear();
+            let mut system = System::new();
+            system.refresh_memory();
+            system.refresh_cpu_specifics(CpuRefreshKind::everything());
+            self.sysinfo = system;
+        }
+        self.filters = filters;
+    }
+
+    /// Returns true if `process` passes the filters and should be tracked.
+    pub fn accepts(&self, process: &IProcess) -> bool {
+        if self.filters.disabled {
+            return false;
+        }
+        let exe = process.exe(self).unwrap_or_default();
+        if !self
+            .filters
+            .accepts(exe.to_str().unwrap_or_default(), &process.cmdline.concat())
+        {
+            return false;
+        }
+        #[cfg(feature = "containers")]
+        if let Some(regex) = &self.filters.container {
+            return self
+                .docker_container_id(process.pid)
+                .and_then(|id| self.container_names.get(&id))
+                .is_some_and(|names| regex.is_match(names));
+        }
+        true
+    }
+
+    /// Remembers the names of the running Docker `containers`, which the
+    /// container filter is matched against.
+    #[cfg(feature = "containers")]
+    pub fn set_container_names(&mut self, containers: &[Container]) {
+        self.container_names = containers
+            .iter()
+            .map(|container| {
+                let names = container
+                    .Names
+                    .iter()
+                    .map(|name| name.trim().replace('/', ""))
+                    .collect::<String>();
+                (container.Id.clone(), names)
+            })
+            .collect();
+    }
+
+    /// Returns the id of the Docker container the process `pid` runs in, if any.
+    #[cfg(feature = "containers")]
+    fn docker_container_id(&self, pid: Pid) -> Option<String> {
+        let procfs_process = procfs::process::Process::new(pid.to_string().parse().ok()?).ok()?;
+        procfs_process
+            .cgroups()
+            .ok()?
+            .iter()
+            .find_map(|cgroup| self.docker_container_id_in_cgroup(&cgroup.pathname))
+    }
+
+    /// Returns the id of the Docker container in the cgroup path `pathname`, if any.
+    #[cfg(feature = "containers")]
+    fn docker_container_id_in_cgroup(&self, pathname: &str) -> Option<String> {
+        if !self.regex_cgroup_docker.is_match(pathname) {
+            return None;
+        }
+        self.regex_container_id
+            .find(pathname)
+            .map(|id| id.as_str().to_string())
+    }
+
     pub fn components(&mut self) -> Vec<String> {
         let mut res = vec![];
         for c in self.sysinfo.components() {
@@ -313,6 +584,14 @@ impl ProcessTracker {
     /// }
     /// ```
     pub fn add_process_record(&mut self, process: IProcess) -> Result<String, String> {
+        if !self.accepts(&process) {
+            return Ok(String::from("Process filtered out, not tracked."));
+        }
+        #[cfg(target_os = "linux")]
+        let process = match self.cpu_source {
+            ProcessCpuSource::Tasks => self.with_tasks_cpu_usage(process),
+            ProcessCpuSource::Sysinfo => process,
+        };
         let iterator = self.procs.iter_mut();
         let pid = process.pid;
         // find the vector containing Process instances with the same pid
@@ -339,6 +618,66 @@ impl ProcessTracker {
         Ok(String::from("Successfully added record to process."))
     }
 
+    /// Returns `process` with the CPU usage of its threads since its previous
+    /// record instead of the one of sysinfo. The first record of a process
+    /// keeps the usage of sysinfo.
+    #[cfg(target_os = "linux")]
+    fn with_tasks_cpu_usage(&self, mut process: IProcess) -> IProcess {
+        // the time of the threads that exited is only in /proc/PID/stat
+        process.tasks_time_jiffies = read_tasks_time_jiffies(process.pid)
+            .map(|jiffies| jiffies.max(process.utime + process.stime));
+        let previous = self
+            .get_process_last_record(process.pid)
+            .filter(|previous| previous.process.comm == process.comm);
+        let (Some(jiffies), Some(previous_jiffies), Some(previous)) = (
+            process.tasks_time_jiffies,
+            previous.and_then(|previous| previous.process.tasks_time_jiffies),
+            previous,
+        ) else {
+            return process;
+        };
+        process.sysinfo_cpu_usage_percentage = Some(process.cpu_usage_percentage);
+        process.cpu_usage_percentage = cpu_usage_from_jiffies(
+            jiffies.saturating_sub(previous_jiffies),
+            current_system_time_since_epoch().saturating_sub(previous.timestamp),
+            procfs::ticks_per_second(),
+        );
+        process
+    }
+
+    /// Returns the ratio of the CPU usage of the tracked processes computed
+    /// from their threads to the one computed by sysinfo, with
+    /// ProcessCpuSource::Tasks, to see how much sysinfo misses.
+    #[cfg(target_os = "linux")]
+    pub fn tasks_cpu_usage_ratio(&self) -> Option<f64> {
+        let (mut tasks, mut sysinfo) = (0.0, 0.0);
+        for last in self.procs.iter().filter_map(|records| records.first()) {
+            if let Some(sysinfo_usage) = last.process.sysinfo_cpu_usage_percentage {
+                tasks += f64::from(last.process.cpu_usage_percentage);
+                sysinfo += f64::from(sysinfo_usage);
+            }
+        }
+        (sysinfo > 0.0).then(|| tasks / sysinfo)
+    }
+
+    /// Returns the ratio of the CPU usage of the tracked processes computed
+    /// from their threads to the one computed by sysinfo (always None, threads
+    /// are only read on Linux).
+    #[cfg(not(target_os = "linux"))]
+    pub fn tasks_cpu_usage_ratio(&self) -> Option<f64> {
+        None
+    }
+
+    /// Returns the CPU usage of a process, in percents of a core: the one of
+    /// its last record with ProcessCpuSource::Tasks, the current one of
+    /// sysinfo otherwise.
+    fn process_cpu_usage(&self, last: &ProcessRecord, sysinfo_process: &Process) -> f32 {
+        match self.cpu_source {
+            ProcessCpuSource::Tasks => last.process.cpu_usage_percentage,
+            ProcessCpuSource::Sysinfo => sysinfo_process.cpu_usage(),
+        }
+    }
+
     pub fn get_process_last_record(&self, pid: Pid) -> Option<&ProcessRecord> {
         if let Some(records) = self.find_records(pid) {
             if let Some(last) = records.first() {
@@ -582,6 +921,18 @@ impl ProcessTracker {
                                         pod_namespace.clone(),
                                     );
                                 }
+                                if let Some(labels) = &pod.metadata.labels {
+                                    for (k, v) in labels {
+                                        let key = k.replace(
+                                            |c: char| !c.is_ascii_alphanumeric() && c != '_',
+                                            "_",
+                                        );
+                                        description.insert(
+                                            format!("kubernetes_pod_label_{key}"),
+                                            v.clone(),
+                                        );
+                                    }
+                                }
                                 if let Some(pod_spec) = &pod.spec {
                                     if let Some(node_name) = &pod_spec.node_name {
                                         description.insert(
@@ -622,6 +973,49 @@ impl ProcessTracker {
             .collect()
     }
 
+    /// Returns the time the process started at, in seconds since the epoch.
+    pub fn get_process_start_time(&self, pid: Pid) -> Option<u64> {
+        self.sysinfo.process(pid).map(|p| p.start_time())
+    }
+
+    /// Returns the id of the user the process runs as.
+    #[cfg(target_os = "linux")]
+    pub fn get_process_user_id(&self, pid: Pid) -> Option<u32> {
+        self.sysinfo.process(pid)?.user_id().map(|uid| **uid)
+    }
+
+    /// Returns the id of the user the process runs as (only known on Linux).
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_process_user_id(&self, _pid: Pid) -> Option<u32> {
+        None
+    }
+
+    /// Returns the clock ticks the process spent waiting for block I/O between
+    /// its last two records, if delay accounting is enabled.
+    #[cfg(target_os = "linux")]
+    pub fn get_process_blkio_delay_ticks(&self, pid: Pid) -> Option<u64> {
+        match self.find_records(pid)?.as_slice() {
+            [last, previous, ..] => Some(
+                last.process
+                    .delayacct_blkio_ticks?
+                    .saturating_sub(previous.process.delayacct_blkio_ticks?),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Returns the clock ticks the process spent waiting for block I/O (only
+    /// known on Linux).
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_process_blkio_delay_ticks(&self, _pid: Pid) -> Option<u64> {
+        None
+    }
+
+    /// Returns the time the host booted at, in seconds since the epoch.
+    pub fn get_boot_time(&self) -> u64 {
+        self.sysinfo.boot_time()
+    }
+
     /// Returns the process name associated to a PID
     pub fn get_process_name(&self, pid: Pid) -> String {
         let mut result = self
@@ -659,78 +1053,120 @@ impl ProcessTracker {
         None
     }
 
+    /// Returns the paths of the cgroups a process belongs to, as found in /proc/PID/cgroup
+    #[cfg(target_os = "linux")]
+    pub fn get_process_cgroups(&self, pid: Pid) -> Vec<String> {
+        if let Ok(procfs_process) =
+            procfs::process::Process::new(pid.to_string().parse::<i32>().unwrap())
+        {
+            if let Ok(cgroups) = procfs_process.cgroups() {
+                return cgroups.iter().map(|cg| cg.pathname.clone()).collect();
+            }
+        }
+        vec![]
+    }
+
+    /// Returns the paths of the cgroups a process belongs to (always empty, cgroups are Linux-specific)
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_process_cgroups(&self, _pid: Pid) -> Vec<String> {
+        vec![]
+    }
+
+    /// Returns the voluntary and nonvoluntary context switches of a process,
+    /// summed over its threads as found in /proc/PID/task/TID/status. Threads
+    /// that exited are not counted anymore, so the sums can decrease.
+    #[cfg(target_os = "linux")]
+    pub fn get_process_context_switches(&self, pid: Pid) -> Option<(u64, u64)> {
+        let procfs_process = procfs::process::Process::new(pid.to_string().parse().ok()?).ok()?;
+        let mut voluntary = 0;
+        let mut nonvoluntary = 0;
+        for task in procfs_process.tasks().ok()?.flatten() {
+            if let Ok(status) = task.status() {
+                voluntary += status.voluntary_ctxt_switches.unwrap_or(0);
+                nonvoluntary += status.nonvoluntary_ctxt_switches.unwrap_or(0);
+            }
+        }
+        Some((voluntary, nonvoluntary))
+    }
+
+    /// Returns the context switches of a process (always None, they are only read on Linux)
+    #[cfg(not(target_os = "linux"))]
+    pub fn get_process_context_switches(&self, _pid: Pid) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Tells if a process is a kernel thread: kthreadd (PID 2) or one of its
+    /// children. Those are the processes `ps` displays with brackets.
+    #[cfg(target_os = "linux")]
+    pub fn is_kernel_thread(&self, pid: Pid) -> bool {
+        let kthreadd = Pid::from(2);
+        if pid == kthreadd {
+            return true;
+        }
+        if let Some(p) = self.sysinfo.process(pid) {
+            return p.parent() == Some(kthreadd);
+        }
+        false
+    }
+
+    /// Tells if a process is a kernel thread (always false, kernel threads are only tracked on Linux)
+    #[cfg(not(target_os = "linux"))]
+    pub fn is_kernel_thread(&self, _pid: Pid) -> bool {
+        false
+    }
+
     pub fn get_cpu_usage_percentage(&self, pid: Pid, nb_cores: usize) -> f32 {
         let cpu_current_usage = self.sysinfo.global_cpu_info().cpu_usage();
-        if let Some(p) = self.sysinfo.process(pid) {
-            (cpu_current_usage * p.cpu_usage() / 100.0) / nb_cores as f32
-        } else {
-            0.0
+        match (self.get_process_last_record(pid), self.sysinfo.process(pid)) {
+            (Some(last), Some(p)) => {
+                (cpu_current_usage * self.process_cpu_usage(last, p) / 100.0) / nb_cores as f32
+            }
+            (None, Some(p)) => (cpu_current_usage * p.cpu_usage() / 100.0) / nb_cores as f32,
+            _ => 0.0,
         }
     }
 
+    /// Returns the processes tracked for long enough to have a CPU usage, with
+    /// their last record, their sysinfo process and that usage (as computed by
+    /// get_cpu_usage_percentage). Sysinfo is queried once per process.
+    fn tracked_cpu_usages(&self) -> impl Iterator<Item = (&ProcessRecord, &Process, f64)> {
+        let cpu_current_usage = self.sysinfo.global_cpu_info().cpu_usage();
+        let nb_cores = self.nb_cores as f32;
+        self.procs
+            .iter()
+            .filter(|records| records.len() > 1)
+            .filter_map(move |records| {
+                let first = records.first()?;
+                let sysinfo_process = self.sysinfo.process(first.process.pid)?;
+                let usage = (cpu_current_usage * self.process_cpu_usage(first, sysinfo_process)
+                    / 100.0)
+                    / nb_cores;
+                Some((records.last()?, sysinfo_process, usage as f64))
+            })
+    }
+
     /// Returns processes sorted by the highest consumers in first
     pub fn get_top_consumers(&self, top: u16) -> Vec<(IProcess, f64)> {
-        let mut consumers: Vec<(IProcess, OrderedFloat<f64>)> = vec![];
-        for p in &self.procs {
-            if p.len() > 1 {
-                let diff = self
-                    .get_cpu_usage_percentage(p.first().unwrap().process.pid as _, self.nb_cores);
-                if consumers
-                    .iter()
-                    .filter(|x| {
-                        if let Some(p) = self.sysinfo.process(x.0.pid as _) {
-                            return p.cpu_usage() > diff;
-                        }
-                        false
-                    })
-                    .count()
-                    < top as usize
-                {
-                    let pid = p.first().unwrap().process.pid;
-                    if let Some(sysinfo_process) = self.sysinfo.process(pid as _) {
-                        let new_consumer = IProcess::new(sysinfo_process);
-                        consumers.push((new_consumer, OrderedFloat(diff as f64)));
-                        consumers.sort_by(|x, y| y.1.cmp(&x.1));
-                        if consumers.len() > top as usize {
-                            consumers.pop();
-                        }
-                    } else {
-                        debug!("Couldn't get process info for {}", pid);
-                    }
-                }
-            }
-        }
-        let mut result: Vec<(IProcess, f64)> = vec![];
-        for (p, f) in consumers {
-            result.push((p, f.into_inner()));
-        }
-        result
+        highest_usages(
+            self.tracked_cpu_usages()
+                .map(|(_, sysinfo_process, usage)| (IProcess::new(sysinfo_process), usage)),
+            Some(top as usize),
+        )
     }
 
     /// Returns processes filtered by a regexp
     pub fn get_filtered_processes(&self, regex_filter: &Regex) -> Vec<(IProcess, f64)> {
-        let mut consumers: Vec<(IProcess, OrderedFloat<f64>)> = vec![];
-        for p in &self.procs {
-            if p.len() > 1 {
-                let diff = self
-                    .get_cpu_usage_percentage(p.first().unwrap().process.pid as _, self.nb_cores);
-                let p_record = p.last().unwrap();
-                let process_exe = p_record.process.exe(self).unwrap_or_default();
-                let process_cmdline = p_record.process.cmdline(self).unwrap_or_default();
-                if regex_filter.is_match(process_exe.to_str().unwrap_or_default()) {
-                    consumers.push((p_record.process.clone(), OrderedFloat(diff as f64)));
-                    consumers.sort_by(|x, y| y.1.cmp(&x.1));
-                } else if regex_filter.is_match(&process_cmdline.concat()) {
-                    consumers.push((p_record.process.clone(), OrderedFloat(diff as f64)));
-                    consumers.sort_by(|x, y| y.1.cmp(&x.1));
-                }
-            }
-        }
-        let mut result: Vec<(IProcess, f64)> = vec![];
-        for (p, f) in consumers {
-            result.push((p, f.into_inner()));
-        }
-        result
+        highest_usages(
+            self.tracked_cpu_usages()
+                .filter(|(p_record, _, _)| {
+                    let process_exe = p_record.process.exe(self).unwrap_or_default();
+                    regex_filter.is_match(process_exe.to_str().unwrap_or_default())
+                        || regex_filter
+                            .is_match(&p_record.process.cmdline(self).unwrap_or_default().concat())
+                })
+                .map(|(p_record, _, usage)| (p_record.process.clone(), usage)),
+            None,
+        )
     }
 
     /// Drops a vector of ProcessRecord instances from self.procs
@@ -774,16 +1210,29 @@ impl ProcessTracker {
 
     /// Removes empty Vectors from self.procs
     fn drop_empty_process_records_vectors(&mut self) {
-        let procs = &mut self.procs;
-        if !procs.is_empty() {
-            for i in 0..(procs.len() - 1) {
-                if let Some(v) = procs.get(i) {
-                    if v.is_empty() {
-                        procs.remove(i);
-                    }
-                }
-            }
+        self.procs.retain(|v| !v.is_empty());
+    }
+
+    /// Stops tracking the processes seen the longest time ago, so that no more
+    /// than max_tracked_pids processes are tracked. Processes seen at the same
+    /// time are evicted by decreasing PID, so that the result doesn't depend on
+    /// the order of self.procs. Returns the number of evicted processes.
+    pub fn evict_least_recently_seen(&mut self) -> usize {
+        self.drop_empty_process_records_vectors();
+        if self.procs.len() <= self.max_tracked_pids {
+            return 0;
         }
+        let evicted = self.procs.len() - self.max_tracked_pids;
+        // the most recent record of each process is the first one
+        self.procs.sort_by(|a, b| {
+            b[0].timestamp
+                .cmp(&a[0].timestamp)
+                .then(a[0].process.pid.cmp(&b[0].process.pid))
+        });
+        self.procs.truncate(self.max_tracked_pids);
+        self.evicted_pids += evicted as u64;
+        debug!("Evicted {evicted} processes from the process tracker.");
+        evicted
     }
 }
 
@@ -806,6 +1255,62 @@ impl ProcessRecord {
     }
 }
 
+/// Lock taken by the instance reading the sensors of the host, as a file holding
+/// its pid, so that two instances don't read RAPL (or fight over the Windows
+/// driver) at the same time. The file is removed when the Lockfile is dropped,
+/// and a lockfile left by a process that is not running anymore is taken over.
+pub struct Lockfile {
+    path: PathBuf,
+}
+
+impl Lockfile {
+    /// Creates the lockfile at `path`, or returns an error if another running
+    /// process holds it.
+    pub fn acquire(path: &str) -> Result<Lockfile, String> {
+        use std::io::Write;
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())
+                        .map_err(|e| format!("couldn't write {path}: {e}"))?;
+                    return Ok(Lockfile {
+                        path: PathBuf::from(path),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = std::fs::read_to_string(path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<usize>().ok());
+                    if let Some(pid) = owner {
+                        if System::new().refresh_process(Pid::from(pid)) {
+                            return Err(format!(
+                                "{path} is held by another scaphandre instance (pid {pid})"
+                            ));
+                        }
+                    }
+                    warn!("Taking over stale lockfile {path}");
+                    std::fs::remove_file(path)
+                        .map_err(|e| format!("couldn't remove stale lockfile {path}: {e}"))?;
+                }
+                Err(e) => return Err(format!("couldn't create {path}: {e}")),
+            }
+        }
+        Err(format!("couldn't acquire {path}"))
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Couldn't remove lockfile {:?}: {e}", self.path);
+        }
+    }
+}
+
 /// Returns a Duration instance with the current timestamp
 pub fn current_system_time_since_epoch() -> Duration {
     SystemTime::now()
@@ -813,12 +1318,106 @@ pub fn current_system_time_since_epoch() -> Duration {
         .unwrap()
 }
 
+/// An item ordered by its usage only, to be kept in a BinaryHeap.
+struct ByUsage<T>(OrderedFloat<f64>, T);
+
+impl<T> PartialEq for ByUsage<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for ByUsage<T> {}
+
+impl<T> PartialOrd for ByUsage<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for ByUsage<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Returns the `top` items with the highest usage (all of them if `top` is
+/// None), sorted by decreasing usage. Only `top` items are kept at any time,
+/// in a min-heap, so this is O(n log top) instead of sorting at each insert.
+pub fn highest_usages<T>(
+    items: impl Iterator<Item = (T, f64)>,
+    top: Option<usize>,
+) -> Vec<(T, f64)> {
+    let mut heap = BinaryHeap::new();
+    for (item, usage) in items {
+        heap.push(Reverse(ByUsage(OrderedFloat(usage), item)));
+        if top.is_some_and(|top| heap.len() > top) {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(ByUsage(usage, item))| (item, usage.into_inner()))
+        .collect()
+}
+
+/// Default maximum number of processes tracked at once: the default pid_max of
+/// Linux, which a host rarely exceeds with processes that are alive together.
+pub const DEFAULT_MAX_TRACKED_PIDS: usize = 32768;
+
+/// Parts of a cgroup path that tell a process runs in a container: Docker,
+/// Kubernetes (kubepods), containerd, CRI-O, Podman (libpod) and LXC.
+const CONTAINER_CGROUP_MARKERS: [&str; 6] =
+    ["docker", "kubepods", "containerd", "crio-", "libpod", "lxc"];
+
+/// Tells if a cgroup path, as found in /proc/PID/cgroup, belongs to a container.
+/// This only looks at the path, so it needs neither the containers feature nor
+/// access to the runtime APIs.
+pub fn is_container_cgroup(path: &str) -> bool {
+    CONTAINER_CGROUP_MARKERS
+        .iter()
+        .any(|marker| path.contains(marker))
+}
+
+/// Returns the last component of the most relevant cgroup of a process: the
+/// first one belonging to a container if any, else the first that isn't the
+/// root cgroup.
+pub fn cgroup_leaf(cgroups: &[String]) -> Option<String> {
+    let path = cgroups
+        .iter()
+        .find(|c| is_container_cgroup(c))
+        .or_else(|| cgroups.iter().find(|c| c.as_str() != "/"))?;
+    path.rsplit('/').next().map(String::from)
+}
+
+/// Returns a new energy counter Record, in microjoules, for sensors that only give
+/// an instant power (in watts): the energy consumed since `previous` is added to it,
+/// as if that power had been drawn during the whole interval.
+pub fn accumulate_power_as_energy(
+    previous: Option<&super::Record>,
+    watts: f64,
+) -> Result<super::Record, std::num::ParseIntError> {
+    let now = current_system_time_since_epoch();
+    let mut microjoules = 0;
+    if let Some(previous) = previous {
+        let previous_microjoules = previous.value.trim().parse::<u64>()?;
+        let elapsed = now.saturating_sub(previous.timestamp).as_secs_f64();
+        microjoules = previous_microjoules + (watts * 1_000_000.0 * elapsed) as u64;
+    }
+    Ok(super::Record::new(
+        now,
+        microjoules.to_string(),
+        super::units::Unit::MicroJoule,
+    ))
+}
+
 mod tests {
 
     #[test]
     fn process_cmdline() {
         use super::*;
         use crate::sensors::Topology;
+        use std::collections::HashMap;
         // find the cmdline of current proc thanks to sysinfo
         // do the same with processtracker
         // assert
@@ -844,6 +1443,7 @@ mod tests {
     fn process_records_added() {
         use super::*;
         use crate::sensors::Topology;
+        use std::collections::HashMap;
         let mut topo = Topology::new(HashMap::new());
         topo.refresh();
         let proc = IProcess::myself(&topo.proc_tracker).unwrap();
@@ -855,6 +1455,17 @@ mod tests {
         assert_eq!(tracker.procs[0].len(), 3);
     }
 
+    #[cfg(all(test, target_os = "linux"))]
+    #[test]
+    fn process_context_switches() {
+        use super::*;
+        let tracker = ProcessTracker::new(3);
+        let proc = IProcess::myself(&tracker).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let (voluntary, _) = tracker.get_process_context_switches(proc.pid).unwrap();
+        assert!(voluntary > 0);
+    }
+
     #[cfg(all(test, target_os = "linux"))]
     #[test]
     fn process_records_cleaned() {
@@ -872,6 +1483,159 @@ mod tests {
         assert_eq!(tracker.procs.len(), 1);
         assert_eq!(tracker.procs[0].len(), 3);
     }
+
+    #[test]
+    fn highest_usages_kept_in_order() {
+        use super::*;
+        let items = vec![("a", 1.0), ("b", 5.0), ("c", 3.0), ("d", 4.0)];
+        let top = highest_usages(items.clone().into_iter(), Some(2));
+        assert_eq!(top, vec![("b", 5.0), ("d", 4.0)]);
+        let all = highest_usages(items.into_iter(), None);
+        assert_eq!(all.iter().map(|x| x.0).collect::<String>(), "bdca");
+        assert!(highest_usages(vec![("a", 1.0)].into_iter(), Some(0)).is_empty());
+    }
+
+    #[test]
+    fn container_cgroups() {
+        use super::*;
+        let docker = vec![String::from("/system.slice/docker-4a5c9f1e8b2d.scope")];
+        assert!(is_container_cgroup(&docker[0]));
+        assert_eq!(cgroup_leaf(&docker).unwrap(), "docker-4a5c9f1e8b2d.scope");
+        let pod = String::from("/kubepods/burstable/pod1234/0123abcd");
+        let v1 = vec![String::from("/"), String::from("/user.slice"), pod];
+        assert_eq!(cgroup_leaf(&v1).unwrap(), "0123abcd");
+        let session = vec![String::from("/user.slice/user-1000.slice/session-2.scope")];
+        assert!(!is_container_cgroup(&session[0]));
+        assert_eq!(cgroup_leaf(&session).unwrap(), "session-2.scope");
+        assert_eq!(cgroup_leaf(&[String::from("/")]), None);
+    }
+
+    #[test]
+    fn least_recently_seen_evicted() {
+        use super::*;
+        let mut tracker = ProcessTracker::new(3);
+        tracker.max_tracked_pids = 2;
+        let myself = IProcess::myself(&tracker).unwrap();
+        for (pid, secs) in [(10, 100), (11, 300), (12, 200), (13, 300)] {
+            let mut process = myself.clone();
+            process.pid = Pid::from(pid);
+            let mut record = ProcessRecord::new(process);
+            record.timestamp = Duration::from_secs(secs);
+            tracker.procs.push(vec![record]);
+        }
+        tracker.procs.push(vec![]);
+        assert_eq!(tracker.evict_least_recently_seen(), 2);
+        assert_eq!(tracker.get_all_pids(), vec![Pid::from(11), Pid::from(13)]);
+        assert_eq!(tracker.evicted_pids, 2);
+        assert_eq!(tracker.evict_least_recently_seen(), 0);
+    }
+
+    #[test]
+    fn process_records_filtered() {
+        use super::*;
+        let mut tracker = ProcessTracker::new(3);
+        let mut proc = IProcess::myself(&tracker).unwrap();
+        proc.comm = String::from("/usr/bin/postgres");
+        proc.cmdline = vec![String::from("postgres: checkpointer")];
+        tracker.filters = ProcessFilters {
+            include: Some(Regex::new("postgres").unwrap()),
+            exclude: Some(Regex::new("checkpointer").unwrap()),
+            ..Default::default()
+        };
+        assert!(tracker.add_process_record(proc.clone()).is_ok());
+        assert!(tracker.procs.is_empty());
+        tracker.filters.exclude = None;
+        assert!(tracker.add_process_record(proc.clone()).is_ok());
+        assert_eq!(tracker.procs.len(), 1);
+        tracker.filters.include = Some(Regex::new("^nginx$").unwrap());
+        assert!(!tracker.accepts(&proc));
+        // the executable path is matched, not the name of the process
+        let exe = proc.exe(&tracker).unwrap();
+        let exe_name = exe.file_name().unwrap().to_str().unwrap();
+        tracker.filters.include = Some(Regex::new(&regex::escape(exe_name)).unwrap());
+        assert!(tracker.accepts(&proc));
+        tracker.filters.include = Some(Regex::new("^/usr/bin/postgres$").unwrap());
+        assert!(!tracker.accepts(&proc));
+        tracker.set_filters(ProcessFilters {
+            disabled: true,
+            ..Default::default()
+        });
+        assert!(tracker.procs.is_empty());
+        assert!(!tracker.accepts(&proc));
+        assert!(tracker.sysinfo.processes().is_empty());
+    }
+
+    #[cfg(feature = "containers")]
+    #[test]
+    fn process_records_filtered_by_container() {
+        use super::*;
+        let id = "3f1a0c2b9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a";
+        let mut tracker = ProcessTracker::new(3);
+        assert_eq!(
+            tracker.docker_container_id_in_cgroup(&format!("/system.slice/docker-{id}.scope")),
+            Some(String::from(id))
+        );
+        assert_eq!(
+            tracker.docker_container_id_in_cgroup(&format!("/docker/{id}")),
+            Some(String::from(id))
+        );
+        assert_eq!(
+            tracker.docker_container_id_in_cgroup("/user.slice/user-1000.slice"),
+            None
+        );
+
+        let proc = IProcess::myself(&tracker).unwrap();
+        assert!(tracker.accepts(&proc));
+        // the tests don't run in a known container
+        tracker.filters.container = Some(Regex::new(".*").unwrap());
+        assert!(!tracker.accepts(&proc));
+        assert!(tracker.add_process_record(proc).is_ok());
+        assert!(tracker.procs.is_empty());
+    }
+
+    #[cfg(all(test, target_os = "linux"))]
+    #[test]
+    fn process_tasks_cpu_usage() {
+        use super::*;
+        assert_eq!(
+            cpu_usage_from_jiffies(150, Duration::from_secs(1), 100),
+            150.0
+        );
+        assert_eq!(cpu_usage_from_jiffies(150, Duration::ZERO, 100), 0.0);
+
+        let mut tracker = ProcessTracker::new(3);
+        tracker.cpu_source = ProcessCpuSource::Tasks;
+        let proc = IProcess::myself(&tracker).unwrap();
+        assert!(tracker.add_process_record(proc.clone()).is_ok());
+        assert!(tracker.procs[0][0].process.tasks_time_jiffies.is_some());
+        assert_eq!(tracker.tasks_cpu_usage_ratio(), None);
+        // two threads spinning for 300 ms, that exited at the next record
+        let spinners: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let start = std::time::Instant::now();
+                    while start.elapsed() < Duration::from_millis(300) {
+                        std::hint::black_box(0);
+                    }
+                })
+            })
+            .collect();
+        for spinner in spinners {
+            spinner.join().unwrap();
+        }
+        let proc = IProcess::myself(&tracker).unwrap();
+        assert!(tracker.add_process_record(proc).is_ok());
+        let last = &tracker.procs[0][0].process;
+        assert!(
+            last.cpu_usage_percentage > 20.0,
+            "{}",
+            last.cpu_usage_percentage
+        );
+        assert!(last.sysinfo_cpu_usage_percentage.is_some());
+        let usage = last.cpu_usage_percentage;
+        tracker.procs[0][0].process.sysinfo_cpu_usage_percentage = Some(usage / 2.0);
+        assert_eq!(tracker.tasks_cpu_usage_ratio(), Some(2.0));
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
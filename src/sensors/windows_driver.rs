@@ -0,0 +1,221 @@
+//! # Windows driver management
+//!
+//! The msr_rapl sensor reads RAPL MSRs through the [ScaphandreDriver](https://github.com/hubblo-org/windows-rapl-driver/)
+//! kernel driver. This module installs, inspects and removes the kernel driver
+//! service, so that setting it up doesn't take several manual steps.
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState, ServiceType,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+/// Name of the kernel driver service, as opened by the msr_rapl sensor.
+pub const SERVICE_NAME: &str = "ScaphandreDriver";
+
+/// Subcommands of `scaphandre driver`.
+#[derive(clap::Subcommand, Debug)]
+pub enum DriverCommand {
+    /// Install the driver as a kernel service and start it
+    Install {
+        /// Path to the driver file (ScaphandreDrv.sys)
+        #[arg(value_name = "SYS_FILE")]
+        path: PathBuf,
+
+        /// Install the driver even if its signature is not valid (Windows has to be in test mode)
+        #[arg(long)]
+        allow_unsigned: bool,
+    },
+    /// Display the state, version and signature of the installed driver
+    Status,
+    /// Stop the driver and remove its service
+    Uninstall,
+}
+
+/// Signature and version of a driver file, as reported by Windows.
+#[derive(Debug)]
+pub struct DriverFileInfo {
+    /// Authenticode signature status (Valid, NotSigned, HashMismatch...)
+    pub signature: String,
+    pub version: String,
+}
+
+impl DriverFileInfo {
+    pub fn is_signed(&self) -> bool {
+        self.signature == "Valid"
+    }
+}
+
+/// Reads the signature status and the file version of the driver at `path`.
+pub fn driver_file_info(path: &Path) -> Result<DriverFileInfo, String> {
+    let script = format!(
+        "$f = '{}'; (Get-AuthenticodeSignature -FilePath $f).Status; (Get-Item $f).VersionInfo.FileVersion",
+        path.display()
+    );
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .output()
+        .map_err(|e| format!("couldn't run powershell: {e}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim);
+    Ok(DriverFileInfo {
+        signature: String::from(lines.next().unwrap_or("Unknown")),
+        version: String::from(lines.next().filter(|v| !v.is_empty()).unwrap_or("unknown")),
+    })
+}
+
+/// Installs the driver at `path` as a kernel service started at boot, then starts it.
+pub fn install(path: &Path, allow_unsigned: bool) -> Result<(), String> {
+    let path = path
+        .canonicalize()
+        .map_err(|e| format!("couldn't find {}: {e}", path.display()))?;
+    let info = driver_file_info(&path)?;
+    if !info.is_signed() {
+        if allow_unsigned {
+            warn!(
+                "Driver signature is {}, it will only load with Windows in test mode",
+                info.signature
+            );
+        } else {
+            return Err(format!(
+                "driver signature is {}, use --allow-unsigned with Windows in test mode to install it anyway",
+                info.signature
+            ));
+        }
+    }
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| format!("couldn't connect to the service manager: {e}"))?;
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_NAME),
+        service_type: ServiceType::KERNEL_DRIVER,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: path,
+        launch_arguments: vec![],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(
+            &service_info,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::START,
+        )
+        .map_err(|e| format!("couldn't create the {SERVICE_NAME} service: {e}"))?;
+    service
+        .start(&[] as &[&OsStr])
+        .map_err(|e| format!("couldn't start the {SERVICE_NAME} service: {e}"))?;
+    println!("{SERVICE_NAME} {} installed and started.", info.version);
+    Ok(())
+}
+
+/// Prints the state of the driver service, and the version and signature of its file.
+pub fn status() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| format!("couldn't connect to the service manager: {e}"))?;
+    let service = match manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::QUERY_CONFIG,
+    ) {
+        Ok(service) => service,
+        Err(_) => {
+            println!("{SERVICE_NAME} is not installed.");
+            return Ok(());
+        }
+    };
+    let state = service
+        .query_status()
+        .map_err(|e| format!("couldn't query the {SERVICE_NAME} service: {e}"))?
+        .current_state;
+    let config = service
+        .query_config()
+        .map_err(|e| format!("couldn't query the {SERVICE_NAME} configuration: {e}"))?;
+    println!("{SERVICE_NAME}: {state:?}");
+    println!("File: {}", config.executable_path.display());
+    match driver_file_info(&config.executable_path) {
+        Ok(info) => {
+            println!("Version: {}", info.version);
+            println!("Signature: {}", info.signature);
+        }
+        Err(e) => println!("Couldn't read the driver file: {e}"),
+    }
+    Ok(())
+}
+
+/// Returns true if the driver service is installed and running.
+pub fn is_running() -> bool {
+    ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .and_then(|manager| manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS))
+        .and_then(|service| service.query_status())
+        .is_ok_and(|status| status.current_state == ServiceState::Running)
+}
+
+/// Stops the driver service if it is running, then deletes it.
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| format!("couldn't connect to the service manager: {e}"))?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("couldn't open the {SERVICE_NAME} service: {e}"))?;
+    let state = service
+        .query_status()
+        .map_err(|e| format!("couldn't query the {SERVICE_NAME} service: {e}"))?
+        .current_state;
+    if state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| format!("couldn't stop the {SERVICE_NAME} service: {e}"))?;
+        // the service is only deleted once stopped
+        for _ in 0..10 {
+            match service.query_status() {
+                Ok(status) if status.current_state == ServiceState::Stopped => break,
+                _ => thread::sleep(Duration::from_millis(500)),
+            }
+        }
+    }
+    service
+        .delete()
+        .map_err(|e| format!("couldn't delete the {SERVICE_NAME} service: {e}"))?;
+    println!("{SERVICE_NAME} uninstalled.");
+    Ok(())
+}
+
+/// Runs a `scaphandre driver` subcommand.
+pub fn run(command: DriverCommand) -> Result<(), String> {
+    match command {
+        DriverCommand::Install {
+            path,
+            allow_unsigned,
+        } => install(&path, allow_unsigned),
+        DriverCommand::Status => status(),
+        DriverCommand::Uninstall => uninstall(),
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,259 @@
+//! # WSL sensor: approximate power inside Windows Subsystem for Linux 2
+//!
+//! WSL2 runs Linux in a lightweight Hyper-V virtual machine, which has no
+//! access to RAPL: there is no powercap tree and no way to read the MSRs. The
+//! WSL sensor gets the power from one of two sources, chosen when it starts:
+//!
+//! - a companion: scaphandre running on the Windows host, as a service with
+//!   the prometheus exporter. Its `scaph_host_energy_microjoules` counter is
+//!   read over HTTP, on `localhost:8080` by default, which reaches Windows
+//!   with the mirrored networking mode of WSL2 (use the address of the host
+//!   otherwise). This is the energy of the whole Windows host.
+//! - an estimation, when no companion answers: the power is interpolated
+//!   between an idle and a maximum power from the CPU usage of the virtual
+//!   machine, read in `/proc/stat`. It only follows the Linux workloads and
+//!   its absolute values depend on the two powers given, so it is meant to
+//!   compare runs on a developer machine rather than to be trusted as is.
+use crate::sensors::utils::{accumulate_power_as_energy, current_system_time_since_epoch};
+use crate::sensors::{units::Unit, Record, RecordSource, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Key of the topology sensor data holding the address of the companion.
+pub const SENSOR_DATA_COMPANION_KEY: &str = "wsl_companion";
+/// Key of the topology sensor data holding the idle and maximum powers of the
+/// estimation, in watts, as `<idle>:<max>`.
+pub const SENSOR_DATA_ESTIMATION_KEY: &str = "wsl_estimation";
+
+/// Default address of the prometheus exporter of the companion.
+pub const DEFAULT_COMPANION: &str = "localhost:8080";
+/// Default powers of the estimation, in watts, those of a laptop.
+pub const DEFAULT_IDLE_WATTS: f64 = 5.0;
+pub const DEFAULT_MAX_WATTS: f64 = 45.0;
+
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+const COMPANION_TIMEOUT: Duration = Duration::from_secs(2);
+const HOST_ENERGY_METRIC: &str = "scaph_host_energy_microjoules";
+
+/// Returns true if the kernel release `osrelease` is the one of WSL2
+/// (5.15.90.1-microsoft-standard-WSL2). WSL1 has no Linux kernel, and
+/// scaphandre can't run there.
+pub fn is_wsl2(osrelease: &str) -> bool {
+    let osrelease = osrelease.to_lowercase();
+    osrelease.contains("microsoft-standard") || osrelease.contains("wsl2")
+}
+
+/// Returns true if scaphandre runs inside WSL2.
+pub fn detect() -> bool {
+    fs::read_to_string(OSRELEASE).is_ok_and(|osrelease| is_wsl2(&osrelease))
+}
+
+/// Returns the value of the host energy counter from the prometheus `metrics`
+/// of the companion, in microjoules.
+pub fn parse_host_energy(metrics: &str) -> Option<u64> {
+    metrics.lines().find_map(|line| {
+        let rest = line.strip_prefix(HOST_ENERGY_METRIC)?;
+        let value = match rest.chars().next()? {
+            '{' => &rest[rest.find('}')? + 1..],
+            ' ' => rest,
+            _ => return None,
+        };
+        Some(value.split_whitespace().next()?.parse::<f64>().ok()? as u64)
+    })
+}
+
+/// Returns the power, in watts, interpolated between `idle_watts` and
+/// `max_watts` for a CPU usage from 0 to 1.
+pub fn estimate_watts(idle_watts: f64, max_watts: f64, cpu_usage: f64) -> f64 {
+    idle_watts + (max_watts - idle_watts) * cpu_usage.clamp(0.0, 1.0)
+}
+
+/// Gets the prometheus metrics of the companion at `address`.
+fn get_metrics(address: &str) -> Result<String, Box<dyn Error>> {
+    let socket = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("couldn't resolve {address}"))?;
+    let mut stream = TcpStream::connect_timeout(&socket, COMPANION_TIMEOUT)?;
+    stream.set_read_timeout(Some(COMPANION_TIMEOUT))?;
+    stream.write_all(format!("GET /metrics HTTP/1.0\r\nHost: {address}\r\n\r\n").as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("truncated response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("unexpected response: {status}").into());
+    }
+    Ok(body.to_string())
+}
+
+/// Reads the host energy counter of the companion at `address`, in microjoules.
+fn read_companion_energy(address: &str) -> Result<u64, Box<dyn Error>> {
+    parse_host_energy(&get_metrics(address)?)
+        .ok_or_else(|| format!("no {HOST_ENERGY_METRIC} in the metrics of {address}").into())
+}
+
+/// Returns a new energy Record for a topology whose sensor is the WSL sensor.
+pub fn read_topology_record(topology: &Topology) -> Result<Record, Box<dyn Error>> {
+    if let Some(address) = topology._sensor_data.get(SENSOR_DATA_COMPANION_KEY) {
+        return Ok(Record::new(
+            current_system_time_since_epoch(),
+            read_companion_energy(address)?.to_string(),
+            Unit::MicroJoule,
+        )
+        .with_source(Some(RecordSource::Remote)));
+    }
+    let (idle_watts, max_watts) = topology
+        ._sensor_data
+        .get(SENSOR_DATA_ESTIMATION_KEY)
+        .and_then(|powers| powers.split_once(':'))
+        .and_then(|(idle, max)| Some((idle.parse::<f64>().ok()?, max.parse::<f64>().ok()?)))
+        .ok_or("no WSL companion nor estimation in topology sensor data")?;
+    // usage since the stats were last refreshed, which is when the previous
+    // record was read
+    let cpu_usage = match (topology.read_stats(), topology.stat_buffer.first()) {
+        (Some(now), Some(previous)) => now
+            .diff(previous)
+            .filter(|diff| diff.elapsed_time_jiffies() > 0)
+            .map(|diff| diff.total_time_jiffies() as f64 / diff.elapsed_time_jiffies() as f64)
+            .unwrap_or(0.0),
+        _ => 0.0,
+    };
+    Ok(accumulate_power_as_energy(
+        topology.record_buffer.last(),
+        estimate_watts(idle_watts, max_watts, cpu_usage),
+    )?
+    .with_source(Some(RecordSource::Estimation)))
+}
+
+/// Where the WSL sensor gets the power from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WslSource {
+    /// Address of the prometheus exporter of scaphandre on the Windows host
+    Companion(String),
+    /// Idle and maximum powers of the estimation, in watts
+    Estimation { idle_watts: f64, max_watts: f64 },
+}
+
+/// This is a Sensor type that reads the power of the Windows host of WSL2
+/// from a companion, or estimates it from the CPU usage.
+pub struct WslSensor {
+    source: WslSource,
+}
+
+impl WslSensor {
+    /// Instantiates and returns an instance of WslSensor, reading from the
+    /// companion at `companion` if it answers, estimating the power otherwise.
+    pub fn new(companion: &str, idle_watts: f64, max_watts: f64) -> WslSensor {
+        info!("WSL2 has no access to RAPL, looking for scaphandre on the Windows host");
+        let source = match read_companion_energy(companion) {
+            Ok(_) => {
+                info!("Reading the energy of the Windows host from scaphandre on {companion}");
+                WslSource::Companion(String::from(companion))
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't read the energy of the Windows host from scaphandre on {companion} \
+                    ({e}), estimating the power from the CPU usage between {idle_watts} W and \
+                    {max_watts} W instead"
+                );
+                WslSource::Estimation {
+                    idle_watts,
+                    max_watts,
+                }
+            }
+        };
+        WslSensor { source }
+    }
+
+    pub fn source(&self) -> &WslSource {
+        &self.source
+    }
+}
+
+impl Sensor for WslSensor {
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut sensor_data = HashMap::new();
+        match &self.source {
+            WslSource::Companion(address) => {
+                sensor_data.insert(String::from(SENSOR_DATA_COMPANION_KEY), address.clone())
+            }
+            WslSource::Estimation {
+                idle_watts,
+                max_watts,
+            } => sensor_data.insert(
+                String::from(SENSOR_DATA_ESTIMATION_KEY),
+                format!("{idle_watts}:{max_watts}"),
+            ),
+        };
+        Ok(Topology::new(sensor_data))
+    }
+
+    fn get_topology(&self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wsl_detection_and_sources() {
+        assert!(is_wsl2("5.15.90.1-microsoft-standard-WSL2\n"));
+        assert!(!is_wsl2("4.4.0-19041-Microsoft"));
+        assert!(!is_wsl2("6.1.0-13-amd64"));
+
+        let metrics = "# HELP scaph_host_energy_microjoules Energy measurements for the host.\n\
+            # TYPE scaph_host_energy_microjoules counter\n\
+            scaph_host_energy_microjoules_total 1\n\
+            scaph_host_energy_microjoules{value_source=\"scaphandredrv_rapl_pkg\"} 123456789\n";
+        assert_eq!(parse_host_energy(metrics), Some(123456789));
+        assert_eq!(
+            parse_host_energy("scaph_host_energy_microjoules 42 1700000000000\n"),
+            Some(42)
+        );
+        assert_eq!(parse_host_energy("scaph_host_power_microwatts 42\n"), None);
+
+        assert_eq!(estimate_watts(5.0, 45.0, 0.0), 5.0);
+        assert_eq!(estimate_watts(5.0, 45.0, 0.5), 25.0);
+        assert_eq!(estimate_watts(5.0, 45.0, 2.0), 45.0);
+
+        let sensor = WslSensor::new("127.0.0.1:1", 5.0, 45.0);
+        assert_eq!(
+            sensor.source(),
+            &WslSource::Estimation {
+                idle_watts: 5.0,
+                max_watts: 45.0
+            }
+        );
+        let topology = sensor.generate_topology().unwrap();
+        let record = read_topology_record(&topology).unwrap();
+        assert_eq!(record.value, "0");
+        assert_eq!(record.source, Some(RecordSource::Estimation));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,186 @@
+//! # Windows service configuration
+//!
+//! Creates or updates the Windows service running scaphandre in the background,
+//! with the exporter and options to use, and restarts on failure. This replaces
+//! `sc.exe create` invocations and can be called by installers.
+use std::env;
+use std::ffi::OsString;
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceErrorControl, ServiceFailureActions,
+    ServiceFailureResetPeriod, ServiceInfo, ServiceStartType, ServiceState, ServiceType,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+/// Default name of the scaphandre service.
+pub const DEFAULT_SERVICE_NAME: &str = "Scaphandre";
+
+/// Subcommands of `scaphandre service`.
+#[derive(clap::Subcommand, Debug)]
+pub enum ServiceCommand {
+    /// Create the service running scaphandre with the given exporter, or update it if it exists.
+    /// Arguments after the service options are given to the exporter
+    /// (e.g. `service register --exporter prometheus --port 8080`)
+    Register {
+        /// Exporter run by the service
+        #[arg(long)]
+        exporter: String,
+
+        /// Name of the service
+        #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+        name: String,
+
+        /// Only start the service on demand, instead of at boot
+        #[arg(long)]
+        manual_start: bool,
+
+        /// Delay before restarting the service after a failure, in seconds (0 disables restarts)
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        restart_delay: u64,
+
+        /// Options of the exporter
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        exporter_args: Vec<String>,
+    },
+    /// Stop and delete the service
+    Unregister {
+        /// Name of the service
+        #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+        name: String,
+    },
+}
+
+/// Creates or updates the service `name`, running the current executable with
+/// `exporter` and `exporter_args`, and restarting it `restart_delay` after a failure.
+pub fn register(
+    name: &str,
+    exporter: &str,
+    exporter_args: &[String],
+    manual_start: bool,
+    restart_delay: Duration,
+) -> Result<(), String> {
+    let executable_path =
+        env::current_exe().map_err(|e| format!("couldn't find scaphandre executable: {e}"))?;
+    let mut launch_arguments = vec![OsString::from(exporter)];
+    launch_arguments.extend(exporter_args.iter().map(OsString::from));
+    let service_info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from(name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: if manual_start {
+            ServiceStartType::OnDemand
+        } else {
+            ServiceStartType::AutoStart
+        },
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None, // LocalSystem, needed to talk to the driver
+        account_password: None,
+    };
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| format!("couldn't connect to the service manager: {e}"))?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::CHANGE_CONFIG | ServiceAccess::START;
+    let service = match manager.open_service(name, access) {
+        Ok(service) => {
+            service
+                .change_config(&service_info)
+                .map_err(|e| format!("couldn't update the {name} service: {e}"))?;
+            println!("Service {name} updated, restart it to apply the changes.");
+            service
+        }
+        Err(_) => {
+            let service = manager
+                .create_service(&service_info, access)
+                .map_err(|e| format!("couldn't create the {name} service: {e}"))?;
+            println!("Service {name} created.");
+            service
+        }
+    };
+
+    let actions = if restart_delay.is_zero() {
+        vec![]
+    } else {
+        // first, second and subsequent failures
+        (0..3)
+            .map(|_| ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: restart_delay,
+            })
+            .collect()
+    };
+    service
+        .update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(86400)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(actions),
+        })
+        .map_err(|e| format!("couldn't set the recovery options of the {name} service: {e}"))?;
+    Ok(())
+}
+
+/// Stops the service `name` if it is running, then deletes it.
+pub fn unregister(name: &str) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| format!("couldn't connect to the service manager: {e}"))?;
+    let service = manager
+        .open_service(
+            name,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("couldn't open the {name} service: {e}"))?;
+    let state = service
+        .query_status()
+        .map_err(|e| format!("couldn't query the {name} service: {e}"))?
+        .current_state;
+    if state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| format!("couldn't stop the {name} service: {e}"))?;
+    }
+    service
+        .delete()
+        .map_err(|e| format!("couldn't delete the {name} service: {e}"))?;
+    println!("Service {name} deleted.");
+    Ok(())
+}
+
+/// Runs a `scaphandre service` subcommand.
+pub fn run(command: ServiceCommand) -> Result<(), String> {
+    match command {
+        ServiceCommand::Register {
+            exporter,
+            name,
+            manual_start,
+            restart_delay,
+            exporter_args,
+        } => register(
+            &name,
+            &exporter,
+            &exporter_args,
+            manual_start,
+            Duration::from_secs(restart_delay),
+        ),
+        ServiceCommand::Unregister { name } => unregister(&name),
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,125 @@
+//! # Watchdog: systemd readiness and liveness notifications
+//!
+//! With `--watchdog`, scaphandre tells systemd it is ready once its sensor
+//! topology is built (`READY=1`), then that it is alive (`WATCHDOG=1`) each
+//! time the metrics are generated from a new measurement of the host energy.
+//! A unit with `Type=notify` and `WatchdogSec=` then gets restarted by systemd
+//! when scaphandre hangs or keeps reporting the same stale measurement.
+//!
+//! Notifications are datagrams sent to the socket given by systemd in the
+//! `NOTIFY_SOCKET` environment variable, as `sd_notify(3)` does. They are only
+//! sent on Linux.
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Environment variable holding the path of the notification socket.
+pub const NOTIFY_SOCKET_VARIABLE: &str = "NOTIFY_SOCKET";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Timestamp, in microseconds, of the host record of the last liveness notification
+static LAST_RECORD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the notifications. Returns an error, and leaves them disabled, if
+/// scaphandre isn't run by systemd with a notification socket.
+pub fn enable() -> Result<(), String> {
+    if !cfg!(target_os = "linux") {
+        return Err(String::from(
+            "the systemd watchdog is only available on Linux",
+        ));
+    }
+    match std::env::var_os(NOTIFY_SOCKET_VARIABLE) {
+        Some(path) if !path.is_empty() => {
+            ENABLED.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        _ => Err(format!(
+            "{NOTIFY_SOCKET_VARIABLE} is not set, the service should have Type=notify"
+        )),
+    }
+}
+
+/// Tells systemd that scaphandre has started.
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that scaphandre is alive, if `record_timestamp`, the time of
+/// the last host energy record, is newer than at the previous notification.
+pub fn notify_alive(record_timestamp: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let micros = record_timestamp.as_micros() as u64;
+    if LAST_RECORD_MICROS.fetch_max(micros, Ordering::Relaxed) < micros {
+        notify("WATCHDOG=1");
+    } else {
+        debug!("No new host record since the last watchdog notification");
+    }
+}
+
+fn notify(state: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(path) = std::env::var_os(NOTIFY_SOCKET_VARIABLE) {
+        if let Err(e) = send(&path, state) {
+            warn!("Couldn't notify systemd of {state}: {e}");
+        }
+    }
+}
+
+/// Sends `state` to the notification socket at `path`, which is in the
+/// abstract namespace if it starts with '@'.
+#[cfg(target_os = "linux")]
+fn send(path: &OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    let bytes = path.as_bytes();
+    let addr = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_path: &OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notification_sent() {
+        let path = std::env::temp_dir().join(format!("scaphandre-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,257 @@
+//! End-to-end tests of the exporters, run with `cargo test --features integration`.
+//!
+//! Each test runs the scaphandre binary against a fake powercap tree (the
+//! powercap sensor in `--vm` mode, reading the counters from
+//! `SCAPHANDRE_POWERCAP_PATH`), so that no RAPL is needed, and checks that the
+//! metrics reached the backend. Backends are started with docker, which has to
+//! be usable by the user running the tests.
+#![cfg(all(feature = "integration", target_os = "linux"))]
+
+use isahc::ReadResponseExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time given to a backend to start, and to scaphandre to send its metrics.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Powercap tree with a socket and its core and dram domains, whose counters
+/// are increased every 100ms while it lives.
+struct FakePowercap {
+    path: PathBuf,
+    running: Arc<AtomicBool>,
+}
+
+impl FakePowercap {
+    fn new(name: &str) -> FakePowercap {
+        let path = std::env::temp_dir().join(format!(
+            "scaphandre-integration-{}-{name}",
+            std::process::id()
+        ));
+        let zones = [
+            ("intel-rapl:0", "package-0", 10_000),
+            ("intel-rapl:0:0", "core", 6_000),
+            ("intel-rapl:0:1", "dram", 1_000),
+        ];
+        for (zone, zone_name, _) in zones {
+            std::fs::create_dir_all(path.join(zone)).unwrap();
+            std::fs::write(path.join(zone).join("name"), zone_name).unwrap();
+            std::fs::write(path.join(zone).join("energy_uj"), "0").unwrap();
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let (tree, still_running) = (path.clone(), running.clone());
+        thread::spawn(move || {
+            let mut ticks: u64 = 0;
+            while still_running.load(Ordering::Relaxed) {
+                ticks += 1;
+                // written in place: scaphandre keeps the counter files open
+                for (zone, _, microjoules_per_tick) in zones {
+                    let value = (ticks * microjoules_per_tick).to_string();
+                    let _ = std::fs::write(tree.join(zone).join("energy_uj"), value);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        FakePowercap { path, running }
+    }
+}
+
+impl Drop for FakePowercap {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Docker container of a backend, removed when dropped.
+struct Container {
+    id: String,
+}
+
+impl Container {
+    /// Starts `image`, publishing each (host, container) pair of `ports`.
+    fn start(image: &str, ports: &[(u16, u16)]) -> Container {
+        let mut command = Command::new("docker");
+        command.args(["run", "--detach", "--rm"]);
+        for (host, container) in ports {
+            command.args(["--publish", &format!("127.0.0.1:{host}:{container}")]);
+        }
+        let output = command
+            .arg(image)
+            .output()
+            .expect("docker is needed to run the integration tests");
+        assert!(
+            output.status.success(),
+            "Couldn't start {image}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Container {
+            id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", "--force", &self.id])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// Scaphandre process, killed when dropped.
+struct Scaphandre {
+    child: Child,
+}
+
+impl Scaphandre {
+    /// Runs scaphandre on `powercap` with the exporter and options of `args`.
+    fn run(powercap: &FakePowercap, args: &[&str]) -> Scaphandre {
+        let child = Command::new(env!("CARGO_BIN_EXE_scaphandre"))
+            .args(["--no-header", "--vm"])
+            .args(args)
+            .env("SCAPHANDRE_POWERCAP_PATH", &powercap.path)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Couldn't run scaphandre");
+        Scaphandre { child }
+    }
+}
+
+impl Drop for Scaphandre {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Calls `check` every second until it returns a value, panics with `what`
+/// after [TIMEOUT].
+fn wait_for<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let start = Instant::now();
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        if start.elapsed() > TIMEOUT {
+            panic!("Timed out waiting for {what}");
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Returns the body of `url` if it answers with a success status.
+fn http_get(url: &str) -> Option<String> {
+    let mut response = isahc::get(url).ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.text().ok()
+}
+
+/// Returns the value of the first sample of `metric` in a Prometheus exposition `body`.
+fn sample_value(body: &str, metric: &str) -> Option<f64> {
+    body.lines()
+        .filter(|line| !line.starts_with('#'))
+        .find(|line| {
+            line.strip_prefix(metric)
+                .is_some_and(|rest| rest.starts_with(['{', ' ']))
+        })
+        .and_then(|line| line.rsplit(' ').next())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Checks the host and process metrics of a Prometheus exposition `body`.
+fn assert_exposition(body: &str) {
+    assert!(sample_value(body, "scaph_host_energy_microjoules").is_some());
+    let power = sample_value(body, "scaph_host_power_microwatts")
+        .expect("scaph_host_power_microwatts is missing");
+    // package and dram counters increase by 110mJ each 100ms
+    assert!(power > 0.0, "host power is {power}");
+    assert!(body.contains("scaph_process_power_consumption_microwatts{"));
+}
+
+#[test]
+fn prometheus() {
+    let powercap = FakePowercap::new("prometheus");
+    let _scaphandre = Scaphandre::run(
+        &powercap,
+        &["prometheus", "--address", "127.0.0.1", "--port", "18080"],
+    );
+    // the host power is computed from the previous scrape
+    let body = wait_for("the prometheus exporter", || {
+        http_get("http://127.0.0.1:18080/metrics")
+            .filter(|body| sample_value(body, "scaph_host_power_microwatts").is_some())
+    });
+    assert_exposition(&body);
+}
+
+#[test]
+fn prometheus_push() {
+    let powercap = FakePowercap::new("prometheuspush");
+    let _gateway = Container::start("prom/pushgateway", &[(19091, 9091)]);
+    wait_for("the push gateway", || {
+        http_get("http://127.0.0.1:19091/-/ready")
+    });
+    let _scaphandre = Scaphandre::run(
+        &powercap,
+        &[
+            "prometheus-push",
+            "--host",
+            "127.0.0.1",
+            "--port",
+            "19091",
+            "--step",
+            "2",
+        ],
+    );
+    let body = wait_for("metrics in the push gateway", || {
+        http_get("http://127.0.0.1:19091/metrics")
+            .filter(|body| sample_value(body, "scaph_host_power_microwatts").is_some())
+    });
+    assert_exposition(&body);
+}
+
+#[cfg(feature = "riemann")]
+#[test]
+fn riemann() {
+    use riemann_client::Client;
+
+    let powercap = FakePowercap::new("riemann");
+    let _riemann = Container::start("riemannio/riemann", &[(15555, 5555)]);
+    wait_for("riemann", || Client::connect(&("127.0.0.1", 15555)).ok());
+    let _scaphandre = Scaphandre::run(
+        &powercap,
+        &[
+            "riemann",
+            "--address",
+            "127.0.0.1",
+            "--port",
+            "15555",
+            "--dispatch-interval",
+            "2",
+        ],
+    );
+    let events = wait_for("metrics in riemann", || {
+        let mut client = Client::connect(&("127.0.0.1", 15555)).ok()?;
+        let events = client
+            .query("service = \"scaph_host_power_microwatts\"")
+            .ok()?;
+        if events.is_empty() {
+            None
+        } else {
+            Some(events)
+        }
+    });
+    let power = &events[0];
+    assert!(power.get_metric_d() > 0.0 || power.get_metric_sint64() > 0);
+    assert!(power
+        .get_tags()
+        .iter()
+        .any(|tag| tag.as_str() == "scaphandre"));
+}